@@ -44,6 +44,8 @@ use gdbstub::arch::Arch;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use hypervisor::CpuConfigAArch64;
 use hypervisor::DeviceKind;
+#[cfg(feature = "gdb")]
+use hypervisor::HwWatchpoint;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorCap;
 use hypervisor::ProtectionType;
@@ -418,6 +420,10 @@ impl arch::LinuxArch for AArch64 {
                 }
             }
             VmImage::Kernel(ref mut kernel_image) => {
+                let mut decompressed =
+                    kernel_loader::decompress_kernel(kernel_image, mem.memory_size())
+                        .map_err(Error::KernelLoadFailure)?;
+                let kernel_image = decompressed.as_mut().unwrap_or(kernel_image);
                 let loaded_kernel = if let Ok(elf_kernel) = kernel_loader::load_elf(
                     &mem,
                     get_kernel_addr(),
@@ -889,11 +895,23 @@ impl<T: VcpuAArch64> arch::GdbOps<T> for AArch64 {
         vcpu.get_max_hw_bps().map_err(Error::GetMaxHwBreakPoint)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
+    fn set_hw_breakpoints(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+    ) -> Result<()> {
+        // TODO: Program the DBGWVR/DBGWCR registers to support watchpoints.
+        if !watchpoints.is_empty() {
+            return Err(Error::SetHwBreakpoint(base::Error::new(libc::ENOTSUP)));
+        }
         const SINGLE_STEP: bool = false;
         vcpu.set_guest_debug(breakpoints, SINGLE_STEP)
             .map_err(Error::SetHwBreakpoint)
     }
+
+    fn get_debug_exit_watchpoint(_vcpu: &T) -> Result<Option<usize>> {
+        Ok(None)
+    }
 }
 
 impl AArch64 {
@@ -7,6 +7,7 @@
 pub mod android;
 pub mod pstore;
 pub mod serial;
+pub mod vmcore;
 
 pub mod sys;
 
@@ -58,6 +59,8 @@ use devices::SerialParameters;
 use devices::VirtioMmioDevice;
 #[cfg(feature = "gdb")]
 use gdbstub::arch::Arch;
+#[cfg(feature = "gdb")]
+use hypervisor::HwWatchpoint;
 use hypervisor::IoEventAddress;
 use hypervisor::Vm;
 #[cfg(windows)]
@@ -347,6 +350,9 @@ pub struct VmComponents {
     pub pflash_block_size: u32,
     pub pflash_image: Option<File>,
     pub pstore: Option<Pstore>,
+    /// Start an ELF kernel through its Xen PVH entry point instead of its 64-bit entry point.
+    #[cfg(target_arch = "x86_64")]
+    pub pvh: bool,
     /// A file to load as pVM firmware. Must be `Some` iff
     /// `hv_cfg.protection_type == ProtectionType::UnprotectedWithFirmware`.
     pub pvm_fw: Option<File>,
@@ -547,8 +553,16 @@ pub trait GdbOps<T: VcpuArch> {
     /// Get maximum number of hardware breakpoints.
     fn get_max_hw_breakpoints(vcpu: &T) -> Result<usize, Self::Error>;
 
-    /// Set hardware breakpoints at the given addresses.
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<(), Self::Error>;
+    /// Set hardware breakpoints at the given addresses and hardware watchpoints.
+    fn set_hw_breakpoints(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+    ) -> Result<(), Self::Error>;
+
+    /// Get the index in the watchpoints last passed to `set_hw_breakpoints` of the watchpoint that
+    /// stopped the vCPU, or `None` if the last debug exit wasn't caused by a watchpoint.
+    fn get_debug_exit_watchpoint(vcpu: &T) -> Result<Option<usize>, Self::Error>;
 }
 
 /// Errors for device manager.
@@ -0,0 +1,540 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guest memory dumps in the ELF core file format ("vmcore").
+//!
+//! The dump contains one `PT_LOAD` segment per guest memory region, with both the virtual and the
+//! physical address of the segment set to the guest physical address of the region, and a
+//! `PT_NOTE` segment with an `NT_PRSTATUS` note per vCPU. This is the same layout as the dumps
+//! written by QEMU's `dump-guest-memory`, so the file can be opened with `crash` or gdb.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use base::warn;
+use base::Event;
+use vm_control::VcpuControl;
+use vm_control::VcpuSuspendGuard;
+use vm_control::VmRunMode;
+use vm_memory::GuestMemory;
+
+use crate::VcpuArch;
+
+// How long to wait for each vCPU to report its registers before writing the dump without them.
+const VCPU_REGISTERS_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+// Name of the notes describing the state of a process (or here, a vCPU).
+const CORE_NOTE_NAME: &[u8] = b"CORE\0";
+
+// Offset of `pr_pid` and of the general purpose registers in `struct elf_prstatus`. The fields
+// before the registers are the same on all 64-bit architectures.
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+// Memory regions are copied in chunks of this size.
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+// Memory segments start at a page-aligned offset in the file.
+const SEGMENT_ALIGN: u64 = 4096;
+
+cfg_if::cfg_if! {
+    if #[cfg(any(target_arch = "arm", target_arch = "aarch64"))] {
+        const ELF_MACHINE: u16 = 183; // EM_AARCH64
+
+        // Returns the registers of `vcpu` in the order of `struct user_pt_regs`.
+        fn core_registers(vcpu: &dyn VcpuArch) -> Result<Vec<u64>> {
+            use hypervisor::VcpuRegAArch64;
+
+            let mut regs = (0..31)
+                .map(|i| Ok(vcpu.get_one_reg(VcpuRegAArch64::X(i))?))
+                .collect::<Result<Vec<u64>>>()?;
+            regs.push(vcpu.get_one_reg(VcpuRegAArch64::Sp)?);
+            regs.push(vcpu.get_one_reg(VcpuRegAArch64::Pc)?);
+            regs.push(vcpu.get_one_reg(VcpuRegAArch64::Pstate)?);
+            Ok(regs)
+        }
+    } else if #[cfg(target_arch = "riscv64")] {
+        const ELF_MACHINE: u16 = 243; // EM_RISCV
+
+        // Returns the registers of `vcpu` in the order of `struct user_regs_struct`.
+        fn core_registers(vcpu: &dyn VcpuArch) -> Result<Vec<u64>> {
+            use hypervisor::CoreRegister::*;
+            use hypervisor::VcpuRegister;
+
+            [
+                Pc, Ra, Sp, Gp, Tp, T0, T1, T2, S0, S1, A0, A1, A2, A3, A4, A5, A6, A7, S2, S3, S4,
+                S5, S6, S7, S8, S9, S10, S11, T3, T4, T5, T6,
+            ]
+            .into_iter()
+            .map(|reg| Ok(vcpu.get_one_reg(VcpuRegister::Core(reg))?))
+            .collect()
+        }
+    } else if #[cfg(target_arch = "x86_64")] {
+        const ELF_MACHINE: u16 = 62; // EM_X86_64
+
+        // Returns the registers of `vcpu` in the order of `struct user_regs_struct`.
+        fn core_registers(vcpu: &dyn VcpuArch) -> Result<Vec<u64>> {
+            let regs = vcpu.get_regs()?;
+            let sregs = vcpu.get_sregs()?;
+            Ok(vec![
+                regs.r15,
+                regs.r14,
+                regs.r13,
+                regs.r12,
+                regs.rbp,
+                regs.rbx,
+                regs.r11,
+                regs.r10,
+                regs.r9,
+                regs.r8,
+                regs.rax,
+                regs.rcx,
+                regs.rdx,
+                regs.rsi,
+                regs.rdi,
+                // orig_rax is only meaningful for system calls.
+                u64::MAX,
+                regs.rip,
+                sregs.cs.selector.into(),
+                regs.rflags,
+                regs.rsp,
+                sregs.ss.selector.into(),
+                sregs.fs.base,
+                sregs.gs.base,
+                sregs.ds.selector.into(),
+                sregs.es.selector.into(),
+                sregs.fs.selector.into(),
+                sregs.gs.selector.into(),
+            ])
+        }
+    }
+}
+
+/// Returns the descriptor of the `NT_PRSTATUS` note for `vcpu`, i.e. a `struct elf_prstatus` with
+/// the general purpose registers of the vCPU.
+///
+/// `cpu_id` is reported as the process id `cpu_id + 1`, which gdb shows as the thread id.
+pub fn vcpu_prstatus(vcpu: &dyn VcpuArch, cpu_id: usize) -> Result<Vec<u8>> {
+    let regs = core_registers(vcpu).context("failed to read vcpu registers")?;
+    Ok(prstatus(cpu_id, &regs))
+}
+
+// Builds a `struct elf_prstatus` for `cpu_id` with the registers `regs`.
+fn prstatus(cpu_id: usize, regs: &[u64]) -> Vec<u8> {
+    let pid = u32::try_from(cpu_id + 1).unwrap_or(u32::MAX);
+
+    let mut desc = vec![0u8; PRSTATUS_REGS_OFFSET];
+    desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&pid.to_le_bytes());
+    for reg in regs {
+        desc.extend_from_slice(&reg.to_le_bytes());
+    }
+    // `pr_fpvalid` and the padding at the end of the structure.
+    desc.extend_from_slice(&[0u8; 8]);
+    desc
+}
+
+// Rounds `n` up to a multiple of `align`, which must be a power of two.
+fn align_up(n: u64, align: u64) -> u64 {
+    (n + align - 1) & !(align - 1)
+}
+
+// Appends an ELF note with the name "CORE" to `buf`.
+fn push_note(buf: &mut Vec<u8>, type_: u32, desc: &[u8]) {
+    buf.extend_from_slice(&(CORE_NOTE_NAME.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&type_.to_le_bytes());
+    buf.extend_from_slice(CORE_NOTE_NAME);
+    buf.resize(align_up(buf.len() as u64, 4) as usize, 0);
+    buf.extend_from_slice(desc);
+    buf.resize(align_up(buf.len() as u64, 4) as usize, 0);
+}
+
+// Appends a 64-bit ELF program header to `buf`.
+fn push_program_header(
+    buf: &mut Vec<u8>,
+    type_: u32,
+    flags: u32,
+    offset: u64,
+    addr: u64,
+    size: u64,
+    align: u64,
+) {
+    buf.extend_from_slice(&type_.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    // p_vaddr and p_paddr.
+    buf.extend_from_slice(&addr.to_le_bytes());
+    buf.extend_from_slice(&addr.to_le_bytes());
+    // p_filesz and p_memsz.
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Writes an ELF core dump of `mem` to `w`.
+///
+/// `prstatus` contains the `NT_PRSTATUS` note descriptor of each vCPU, as returned by
+/// `vcpu_prstatus`. Guest memory is read while it is being written, so the vCPUs and devices should
+/// be stopped for the dump to be consistent.
+pub fn write_vmcore<W: Write>(w: &mut W, mem: &GuestMemory, prstatus: &[Vec<u8>]) -> Result<()> {
+    let regions: Vec<(u64, u64)> = mem
+        .regions()
+        .map(|r| (r.guest_addr.offset(), r.size as u64))
+        .collect();
+
+    let mut notes = Vec::new();
+    for desc in prstatus {
+        push_note(&mut notes, NT_PRSTATUS, desc);
+    }
+
+    let num_headers = regions.len() + 1;
+    let notes_offset = (ELF_HEADER_SIZE + num_headers * PROGRAM_HEADER_SIZE) as u64;
+    let data_offset = align_up(notes_offset + notes.len() as u64, SEGMENT_ALIGN);
+
+    let mut headers = Vec::with_capacity(notes_offset as usize);
+    headers.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    headers.resize(16, 0);
+    headers.extend_from_slice(&ET_CORE.to_le_bytes());
+    headers.extend_from_slice(&ELF_MACHINE.to_le_bytes());
+    headers.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    // e_entry, e_phoff and e_shoff.
+    headers.extend_from_slice(&0u64.to_le_bytes());
+    headers.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    headers.extend_from_slice(&0u64.to_le_bytes());
+    // e_flags.
+    headers.extend_from_slice(&0u32.to_le_bytes());
+    headers.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    headers.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    let phnum = u16::try_from(num_headers).context("too many memory regions")?;
+    headers.extend_from_slice(&phnum.to_le_bytes());
+    // e_shentsize, e_shnum and e_shstrndx.
+    headers.extend_from_slice(&[0u8; 6]);
+
+    push_program_header(
+        &mut headers,
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        notes.len() as u64,
+        4,
+    );
+    let mut offset = data_offset;
+    for &(addr, size) in &regions {
+        push_program_header(
+            &mut headers,
+            PT_LOAD,
+            PF_R | PF_W | PF_X,
+            offset,
+            addr,
+            size,
+            SEGMENT_ALIGN,
+        );
+        offset += size;
+    }
+
+    w.write_all(&headers)?;
+    w.write_all(&notes)?;
+    let padding = data_offset - notes_offset - notes.len() as u64;
+    w.write_all(&vec![0u8; padding as usize])?;
+
+    let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+    for (addr, size) in regions {
+        let mut copied = 0;
+        while copied < size {
+            let len = (size - copied).min(COPY_CHUNK_SIZE as u64) as usize;
+            mem.read_exact_at_addr(&mut buf[..len], vm_memory::GuestAddress(addr + copied))
+                .with_context(|| format!("failed to read guest memory at {:#x}", addr + copied))?;
+            w.write_all(&buf[..len])?;
+            copied += len as u64;
+        }
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+// Reads the `NT_PRSTATUS` note descriptor of each vCPU, which must be suspended. vCPUs that fail
+// to report their registers in time are left out of the dump.
+fn vcpu_notes(kick_vcpus: &impl Fn(VcpuControl), vcpu_num: usize) -> Vec<Vec<u8>> {
+    let (send_chan, recv_chan) = mpsc::channel();
+    kick_vcpus(VcpuControl::CoreDumpRegisters(send_chan));
+    let mut notes = Vec::with_capacity(vcpu_num);
+    for _ in 0..vcpu_num {
+        match recv_chan.recv_timeout(VCPU_REGISTERS_TIMEOUT) {
+            Ok((cpu_id, Ok(desc))) => notes.push((cpu_id, desc)),
+            Ok((cpu_id, Err(e))) => warn!("failed to get registers of vcpu {}: {:#}", cpu_id, e),
+            Err(e) => {
+                warn!("failed to get registers of all vcpus: {}", e);
+                break;
+            }
+        }
+    }
+    notes.sort_by_key(|(cpu_id, _)| *cpu_id);
+    notes.into_iter().map(|(_, desc)| desc).collect()
+}
+
+// Creates `dump_path` and writes an ELF core dump of `mem` with the vCPU notes `prstatus` to it.
+fn write_vmcore_file(dump_path: &Path, mem: &GuestMemory, prstatus: &[Vec<u8>]) -> Result<()> {
+    let file = File::create(dump_path)
+        .with_context(|| format!("failed to create {}", dump_path.display()))?;
+    write_vmcore(&mut BufWriter::new(file), mem, prstatus)
+        .with_context(|| format!("failed to write guest dump to {}", dump_path.display()))
+}
+
+/// Suspends the vCPUs and writes an ELF core dump of `mem` and the vCPU registers to `dump_path`.
+///
+/// The vCPUs are restored to their previous run mode once the dump has been written.
+///
+/// # Arguments
+///
+/// * `kick_vcpus` - A function to send a [VcpuControl] message to all the vCPUs and interrupt
+///   them.
+/// * `vcpu_num` - The number of vCPUs.
+pub fn dump_guest(
+    dump_path: &Path,
+    mem: &GuestMemory,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_num: usize,
+) -> Result<()> {
+    let _vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_num)?;
+    let prstatus = vcpu_notes(&kick_vcpus, vcpu_num);
+    write_vmcore_file(dump_path, mem, &prstatus)
+}
+
+/// A guest dump written by a separate thread, so that the caller can keep serving requests while
+/// guest memory is copied. The vCPUs stay suspended until [GuestDumpThread::finish] is called.
+pub struct GuestDumpThread {
+    saved_run_mode: VmRunMode,
+    done_evt: Event,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl GuestDumpThread {
+    /// Suspends the vCPUs, reads their registers and starts writing an ELF core dump of `mem` to
+    /// `dump_path` in a new thread.
+    ///
+    /// See [dump_guest] for the arguments.
+    pub fn start(
+        dump_path: PathBuf,
+        mem: &GuestMemory,
+        kick_vcpus: impl Fn(VcpuControl),
+        vcpu_num: usize,
+    ) -> Result<Self> {
+        let vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_num)?;
+        let prstatus = vcpu_notes(&kick_vcpus, vcpu_num);
+        let done_evt = Event::new().context("failed to create event")?;
+        let thread_done_evt = done_evt.try_clone().context("failed to clone event")?;
+        let mem = mem.clone();
+        let thread = thread::Builder::new()
+            .name("guest_dump".to_owned())
+            .spawn(move || {
+                let result = write_vmcore_file(&dump_path, &mem, &prstatus);
+                if let Err(e) = thread_done_evt.signal() {
+                    warn!("failed to signal the end of the guest dump: {}", e);
+                }
+                result
+            })
+            .context("failed to spawn guest dump thread")?;
+        Ok(GuestDumpThread {
+            saved_run_mode: vcpu_guard.into_saved_run_mode(),
+            done_evt,
+            thread,
+        })
+    }
+
+    /// Returns the event signaled once the dump has been written.
+    pub fn done_event(&self) -> &Event {
+        &self.done_evt
+    }
+
+    /// Waits for the dump to be written, restores the vCPUs to their run mode from before the dump
+    /// and returns the result of the dump.
+    pub fn finish(self, kick_vcpus: impl Fn(VcpuControl)) -> Result<()> {
+        let result = match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("guest dump thread panicked")),
+        };
+        if self.saved_run_mode != VmRunMode::Suspending {
+            kick_vcpus(VcpuControl::RunState(self.saved_run_mode));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use vm_memory::GuestAddress;
+
+    use super::*;
+
+    const NOTE_HEADER_SIZE: usize = 12;
+
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn prstatus_layout() {
+        let desc = prstatus(2, &[0x1111, 0x2222]);
+        assert_eq!(desc.len(), PRSTATUS_REGS_OFFSET + 2 * 8 + 8);
+        assert_eq!(read_u32(&desc, PRSTATUS_PID_OFFSET), 3);
+        assert_eq!(read_u64(&desc, PRSTATUS_REGS_OFFSET), 0x1111);
+        assert_eq!(read_u64(&desc, PRSTATUS_REGS_OFFSET + 8), 0x2222);
+    }
+
+    #[test]
+    fn vmcore_layout() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x2000), (GuestAddress(0x10000), 0x1000)])
+            .unwrap();
+        mem.write_all_at_addr(b"first", GuestAddress(0x10)).unwrap();
+        mem.write_all_at_addr(b"second", GuestAddress(0x10000))
+            .unwrap();
+        let notes = vec![prstatus(0, &[1]), prstatus(1, &[2])];
+
+        let mut core = Vec::new();
+        write_vmcore(&mut core, &mem, &notes).unwrap();
+
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(read_u16(&core, 16), ET_CORE);
+        assert_eq!(read_u16(&core, 18), ELF_MACHINE);
+        assert_eq!(read_u64(&core, 32), ELF_HEADER_SIZE as u64);
+        assert_eq!(read_u16(&core, 56), 3);
+
+        // The note segment contains one NT_PRSTATUS note per vCPU.
+        let phdr = ELF_HEADER_SIZE;
+        assert_eq!(read_u32(&core, phdr), PT_NOTE);
+        let notes_offset = read_u64(&core, phdr + 8) as usize;
+        let notes_size = read_u64(&core, phdr + 32) as usize;
+        let note_size = NOTE_HEADER_SIZE + 8 + notes[0].len();
+        assert_eq!(notes_size, 2 * note_size);
+        for (i, desc) in notes.iter().enumerate() {
+            let note = notes_offset + i * note_size;
+            assert_eq!(read_u32(&core, note), CORE_NOTE_NAME.len() as u32);
+            assert_eq!(read_u32(&core, note + 4), desc.len() as u32);
+            assert_eq!(read_u32(&core, note + 8), NT_PRSTATUS);
+            assert_eq!(&core[note + 12..note + 17], CORE_NOTE_NAME);
+            assert_eq!(&core[note + 20..note + 20 + desc.len()], &desc[..]);
+        }
+
+        // Each memory region has a load segment with its contents.
+        let expected = [
+            (0u64, 0x2000u64, 0x10, &b"first"[..]),
+            (0x10000, 0x1000, 0, b"second"),
+        ];
+        for (i, (addr, size, data_offset, data)) in expected.into_iter().enumerate() {
+            let phdr = ELF_HEADER_SIZE + (i + 1) * PROGRAM_HEADER_SIZE;
+            assert_eq!(read_u32(&core, phdr), PT_LOAD);
+            let offset = read_u64(&core, phdr + 8);
+            assert_eq!(offset % SEGMENT_ALIGN, 0);
+            assert_eq!(read_u64(&core, phdr + 16), addr);
+            assert_eq!(read_u64(&core, phdr + 24), addr);
+            assert_eq!(read_u64(&core, phdr + 32), size);
+            let start = offset as usize + data_offset;
+            assert_eq!(&core[start..start + data.len()], data);
+        }
+        assert_eq!(
+            core.len() as u64,
+            read_u64(&core, ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE + 8) + 0x1000
+        );
+    }
+
+    // Returns a function answering the messages sent to `vcpu_num` vCPUs, which share the run mode
+    // `run_mode`.
+    fn fake_vcpus(vcpu_num: usize, run_mode: &Cell<VmRunMode>) -> impl Fn(VcpuControl) + '_ {
+        move |msg| match msg {
+            VcpuControl::RunState(mode) => run_mode.set(mode),
+            VcpuControl::GetStates(send) => {
+                for _ in 0..vcpu_num {
+                    send.send(run_mode.get()).unwrap();
+                }
+            }
+            VcpuControl::CoreDumpRegisters(send) => {
+                assert_eq!(run_mode.get(), VmRunMode::Suspending);
+                for cpu_id in 0..vcpu_num {
+                    send.send((cpu_id, Ok(prstatus(cpu_id, &[cpu_id as u64]))))
+                        .unwrap();
+                }
+            }
+            msg => panic!("unexpected vcpu message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn dump_guest_file() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        mem.write_all_at_addr(b"guest", GuestAddress(0x100))
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let run_mode = Cell::new(VmRunMode::Running);
+
+        let path = dir.path().join("vmcore");
+        dump_guest(&path, &mem, fake_vcpus(2, &run_mode), 2).unwrap();
+        assert_eq!(run_mode.get(), VmRunMode::Running);
+
+        let core = std::fs::read(&path).unwrap();
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(read_u16(&core, 56), 2);
+        let notes_offset = read_u64(&core, ELF_HEADER_SIZE + 8) as usize;
+        let note_size = NOTE_HEADER_SIZE + 8 + prstatus(0, &[0]).len();
+        assert_eq!(
+            read_u64(&core, ELF_HEADER_SIZE + 32) as usize,
+            2 * note_size
+        );
+        for cpu_id in 0..2 {
+            let desc = notes_offset + cpu_id * note_size + NOTE_HEADER_SIZE + 8;
+            assert_eq!(
+                read_u32(&core, desc + PRSTATUS_PID_OFFSET),
+                cpu_id as u32 + 1
+            );
+        }
+        let data = read_u64(&core, ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE + 8) as usize + 0x100;
+        assert_eq!(&core[data..data + 5], b"guest");
+
+        // Writing the dump from a thread gives the same file, with the vCPUs suspended until the
+        // dump is finished.
+        let thread_path = dir.path().join("vmcore_thread");
+        let dump =
+            GuestDumpThread::start(thread_path.clone(), &mem, fake_vcpus(2, &run_mode), 2).unwrap();
+        assert_eq!(run_mode.get(), VmRunMode::Suspending);
+        dump.done_event().wait().unwrap();
+        dump.finish(fake_vcpus(2, &run_mode)).unwrap();
+        assert_eq!(run_mode.get(), VmRunMode::Running);
+        assert_eq!(std::fs::read(&thread_path).unwrap(), core);
+    }
+}
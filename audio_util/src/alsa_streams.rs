@@ -0,0 +1,228 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audio streams playing to and capturing from ALSA PCM devices.
+//!
+//! Any PCM name known to libasound can be used, including the `pipewire` and `pulse` plugins,
+//! and the `null` device or the `snd-aloop` loopback card for testing.
+
+use alsa::pcm::Access;
+use alsa::pcm::Format;
+use alsa::pcm::Frames;
+use alsa::pcm::HwParams;
+use alsa::Direction;
+use alsa::ValueOr;
+use alsa::PCM;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use thiserror::Error as ThisError;
+
+use crate::blocking_streams::BlockingCaptureStream;
+use crate::blocking_streams::BlockingPlaybackStream;
+use crate::blocking_streams::CaptureDevice;
+use crate::blocking_streams::PlaybackDevice;
+
+// Number of periods held by the ALSA ring buffer.
+const PERIODS_PER_BUFFER: Frames = 4;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to configure ALSA device {0}: {1}")]
+    Configure(String, alsa::Error),
+    #[error("failed to open ALSA device {0}: {1}")]
+    Open(String, alsa::Error),
+    #[error("ALSA streams only support async operation")]
+    Unimplemented,
+}
+
+fn alsa_format(format: SampleFormat) -> Format {
+    match format {
+        SampleFormat::U8 => Format::U8,
+        SampleFormat::S16LE => Format::S16LE,
+        SampleFormat::S24LE => Format::S24LE,
+        SampleFormat::S32LE => Format::S32LE,
+    }
+}
+
+fn open_pcm(
+    name: &str,
+    direction: Direction,
+    num_channels: usize,
+    format: SampleFormat,
+    frame_rate: u32,
+    period_frames: usize,
+) -> Result<PCM, Error> {
+    let pcm = PCM::new(name, direction, false).map_err(|e| Error::Open(name.to_owned(), e))?;
+    let configure = || -> alsa::Result<()> {
+        let hw_params = HwParams::any(&pcm)?;
+        hw_params.set_access(Access::RWInterleaved)?;
+        hw_params.set_format(alsa_format(format))?;
+        hw_params.set_channels(num_channels as u32)?;
+        hw_params.set_rate(frame_rate, ValueOr::Nearest)?;
+        let period_frames =
+            hw_params.set_period_size_near(period_frames as Frames, ValueOr::Nearest)?;
+        hw_params.set_buffer_size_near(period_frames * PERIODS_PER_BUFFER)?;
+        pcm.hw_params(&hw_params)
+    };
+    configure().map_err(|e| Error::Configure(name.to_owned(), e))?;
+    Ok(pcm)
+}
+
+struct AlsaDevice {
+    pcm: PCM,
+    frame_size: usize,
+}
+
+impl PlaybackDevice for AlsaDevice {
+    fn write_period(&mut self, mut data: &[u8]) -> Result<(), BoxError> {
+        let io = self.pcm.io_bytes();
+        while !data.is_empty() {
+            match io.writei(data) {
+                Ok(frames) => data = &data[frames * self.frame_size..],
+                // Restarts the device after an underrun.
+                Err(e) => self.pcm.try_recover(e, true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CaptureDevice for AlsaDevice {
+    fn read_period(&mut self, data: &mut [u8]) -> Result<(), BoxError> {
+        let io = self.pcm.io_bytes();
+        let mut offset = 0;
+        while offset < data.len() {
+            match io.readi(&mut data[offset..]) {
+                Ok(frames) => offset += frames * self.frame_size,
+                // Restarts the device after an overrun.
+                Err(e) => self.pcm.try_recover(e, true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stream source opening the streams it is asked for on an ALSA PCM device.
+struct AlsaStreamSource {
+    device: String,
+}
+
+impl AlsaStreamSource {
+    fn open_device(
+        &self,
+        direction: Direction,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<AlsaDevice, Error> {
+        let pcm = open_pcm(
+            &self.device,
+            direction,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        Ok(AlsaDevice {
+            pcm,
+            frame_size: format.sample_bytes() * num_channels,
+        })
+    }
+}
+
+impl StreamSource for AlsaStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(Error::Unimplemented))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        let device = self.open_device(
+            Direction::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = device.frame_size;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(BlockingPlaybackStream::new(
+                device,
+                frame_size,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let device = self.open_device(
+            Direction::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = device.frame_size;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(BlockingCaptureStream::new(device, frame_size, buffer_size)?),
+        ))
+    }
+}
+
+/// `AlsaStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// for streams on an ALSA PCM device.
+pub struct AlsaStreamSourceGenerator {
+    /// Name of the PCM device, such as `default` or `hw:0,0`.
+    device: String,
+}
+
+impl AlsaStreamSourceGenerator {
+    /// Creates a new `AlsaStreamSourceGenerator` opening streams on the PCM named `device`.
+    pub fn new(device: String) -> Self {
+        AlsaStreamSourceGenerator { device }
+    }
+}
+
+impl StreamSourceGenerator for AlsaStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(AlsaStreamSource {
+            device: self.device.clone(),
+        }))
+    }
+}
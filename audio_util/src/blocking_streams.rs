@@ -0,0 +1,449 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Adapts audio devices with blocking read and write calls to the async streams of
+//! `audio_streams`.
+//!
+//! Each stream runs its device on a dedicated thread and exchanges period sized buffers with it
+//! over channels. The thread signals an `Event` whenever it hands a buffer back, which the async
+//! side waits on through the executor.
+
+use std::io;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::thread::JoinHandle;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use base::error;
+use base::AsRawDescriptor;
+use base::Event;
+use thiserror::Error as ThisError;
+
+// Number of period buffers exchanged between a stream and its device thread.
+const NUM_BUFFERS: usize = 2;
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to create event: {0}")]
+    CreateEvent(base::Error),
+    #[error("audio device thread stopped")]
+    DeviceStopped,
+    #[error("failed to spawn audio device thread: {0}")]
+    SpawnThread(io::Error),
+}
+
+/// An audio output device accepting interleaved frames.
+pub trait PlaybackDevice: Send + 'static {
+    /// Plays `data`, blocking until the device has accepted all of it.
+    fn write_period(&mut self, data: &[u8]) -> Result<(), BoxError>;
+}
+
+/// An audio input device providing interleaved frames.
+pub trait CaptureDevice: Send + 'static {
+    /// Fills `data` with captured frames, blocking until enough of them are available.
+    fn read_period(&mut self, data: &mut [u8]) -> Result<(), BoxError>;
+}
+
+/// Buffers handed back by a device thread, which signals `event` after sending each of them.
+struct ReadyBuffers {
+    receiver: Receiver<Vec<u8>>,
+    event: Event,
+}
+
+impl ReadyBuffers {
+    async fn next(&self, ex: &dyn AudioStreamsExecutor) -> Result<Vec<u8>, BoxError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(buffer) => return Ok(buffer),
+                Err(TryRecvError::Empty) => {
+                    ex.wait_fd_readable(self.event.as_raw_descriptor()).await?;
+                    self.event.wait()?;
+                }
+                Err(TryRecvError::Disconnected) => return Err(Box::new(Error::DeviceStopped)),
+            }
+        }
+    }
+}
+
+/// Records the number of frames of the current buffer that were written or read.
+#[derive(Default)]
+struct PeriodCommit {
+    frames: Option<usize>,
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for PeriodCommit {
+    async fn commit(&mut self, nframes: usize) {
+        self.frames = Some(nframes);
+    }
+}
+
+fn spawn_device_thread<F>(name: &str, event: &Event, f: F) -> Result<JoinHandle<()>, Error>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let event = event.try_clone().map_err(Error::CreateEvent)?;
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            f();
+            // Wake up the stream so that it notices the thread is gone.
+            if let Err(e) = event.signal() {
+                error!("failed to signal audio stream: {}", e);
+            }
+        })
+        .map_err(Error::SpawnThread)
+}
+
+/// Playback stream writing the buffers committed by its user to a `PlaybackDevice`.
+pub struct BlockingPlaybackStream {
+    frame_size: usize,
+    // Buffer last returned by `next_playback_buffer`, sent to the device once committed.
+    buffer: Option<Vec<u8>>,
+    commit: PeriodCommit,
+    free_buffers: ReadyBuffers,
+    filled_sender: Option<Sender<(Vec<u8>, usize)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlockingPlaybackStream {
+    /// Starts playing to `device` periods of `period_frames` frames of `frame_size` bytes.
+    pub fn new<D: PlaybackDevice>(
+        device: D,
+        frame_size: usize,
+        period_frames: usize,
+    ) -> Result<Self, Error> {
+        let (filled_sender, filled_receiver) = channel::<(Vec<u8>, usize)>();
+        let (free_sender, free_receiver) = channel();
+        for _ in 0..NUM_BUFFERS {
+            // The receiver is alive, so sending cannot fail.
+            let _ = free_sender.send(vec![0; frame_size * period_frames]);
+        }
+        let event = Event::new().map_err(Error::CreateEvent)?;
+        let thread = spawn_device_thread("audio_playback", &event, {
+            let event = event.try_clone().map_err(Error::CreateEvent)?;
+            let mut device = device;
+            move || {
+                for (buffer, len) in filled_receiver.iter() {
+                    if let Err(e) = device.write_period(&buffer[..len]) {
+                        error!("failed to play audio: {}", e);
+                        return;
+                    }
+                    if free_sender.send(buffer).is_err() {
+                        return;
+                    }
+                    if let Err(e) = event.signal() {
+                        error!("failed to signal audio stream: {}", e);
+                        return;
+                    }
+                }
+            }
+        })?;
+
+        Ok(BlockingPlaybackStream {
+            frame_size,
+            buffer: None,
+            commit: PeriodCommit::default(),
+            free_buffers: ReadyBuffers {
+                receiver: free_receiver,
+                event,
+            },
+            filled_sender: Some(filled_sender),
+            thread: Some(thread),
+        })
+    }
+
+    // Sends the current buffer to the device if it was committed.
+    fn send_committed(&mut self) -> Result<(), Error> {
+        let frames = match self.commit.frames.take() {
+            Some(frames) => frames,
+            None => return Ok(()),
+        };
+        if let (Some(buffer), Some(sender)) = (self.buffer.take(), &self.filled_sender) {
+            let len = (frames * self.frame_size).min(buffer.len());
+            sender
+                .send((buffer, len))
+                .map_err(|_| Error::DeviceStopped)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for BlockingPlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.send_committed()?;
+        if self.buffer.is_none() {
+            self.buffer = Some(self.free_buffers.next(ex).await?);
+        }
+        // Cannot fail as the buffer was just filled in.
+        let buffer = self.buffer.as_mut().unwrap();
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+impl Drop for BlockingPlaybackStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.send_committed() {
+            error!("failed to play last audio period: {}", e);
+        }
+        // Closing the channel stops the thread once it played the pending buffers.
+        self.filled_sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("audio playback thread panicked");
+            }
+        }
+    }
+}
+
+/// Capture stream reading the buffers it provides to its user from a `CaptureDevice`.
+pub struct BlockingCaptureStream {
+    frame_size: usize,
+    // Buffer last returned by `next_capture_buffer`, reused by the device once committed.
+    buffer: Option<Vec<u8>>,
+    commit: PeriodCommit,
+    filled_buffers: ReadyBuffers,
+    free_sender: Option<Sender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlockingCaptureStream {
+    /// Starts capturing from `device` periods of `period_frames` frames of `frame_size` bytes.
+    pub fn new<D: CaptureDevice>(
+        device: D,
+        frame_size: usize,
+        period_frames: usize,
+    ) -> Result<Self, Error> {
+        let (free_sender, free_receiver) = channel::<Vec<u8>>();
+        let (filled_sender, filled_receiver) = channel();
+        for _ in 0..NUM_BUFFERS {
+            // The receiver is alive, so sending cannot fail.
+            let _ = free_sender.send(vec![0; frame_size * period_frames]);
+        }
+        let event = Event::new().map_err(Error::CreateEvent)?;
+        let thread = spawn_device_thread("audio_capture", &event, {
+            let event = event.try_clone().map_err(Error::CreateEvent)?;
+            let mut device = device;
+            move || {
+                for mut buffer in free_receiver.iter() {
+                    if let Err(e) = device.read_period(&mut buffer) {
+                        error!("failed to capture audio: {}", e);
+                        return;
+                    }
+                    if filled_sender.send(buffer).is_err() {
+                        return;
+                    }
+                    if let Err(e) = event.signal() {
+                        error!("failed to signal audio stream: {}", e);
+                        return;
+                    }
+                }
+            }
+        })?;
+
+        Ok(BlockingCaptureStream {
+            frame_size,
+            buffer: None,
+            commit: PeriodCommit::default(),
+            filled_buffers: ReadyBuffers {
+                receiver: filled_receiver,
+                event,
+            },
+            free_sender: Some(free_sender),
+            thread: Some(thread),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for BlockingCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        if self.commit.frames.take().is_some() {
+            if let (Some(buffer), Some(sender)) = (self.buffer.take(), &self.free_sender) {
+                sender.send(buffer).map_err(|_| Error::DeviceStopped)?;
+            }
+        }
+        if self.buffer.is_none() {
+            self.buffer = Some(self.filled_buffers.next(ex).await?);
+        }
+        // Cannot fail as the buffer was just filled in.
+        let buffer = self.buffer.as_mut().unwrap();
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+impl Drop for BlockingCaptureStream {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once it filled the pending buffers.
+        self.free_sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("audio capture thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use audio_streams::AsyncStream;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    // Executor blocking the current thread until the awaited descriptor is readable.
+    struct BlockingExecutor;
+
+    #[async_trait(?Send)]
+    impl AudioStreamsExecutor for BlockingExecutor {
+        fn async_unix_stream(&self, _f: UnixStream) -> io::Result<AsyncStream> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+
+        async fn delay(&self, dur: Duration) -> io::Result<()> {
+            thread::sleep(dur);
+            Ok(())
+        }
+
+        async fn wait_fd_readable(&self, fd: base::RawDescriptor) -> io::Result<()> {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Safe because `pollfd` is a valid pollfd array of length 1.
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    struct RecordingDevice(Arc<Mutex<Vec<u8>>>);
+
+    impl PlaybackDevice for RecordingDevice {
+        fn write_period(&mut self, data: &[u8]) -> Result<(), BoxError> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    struct CountingDevice(u8);
+
+    impl CaptureDevice for CountingDevice {
+        fn read_period(&mut self, data: &mut [u8]) -> Result<(), BoxError> {
+            for byte in data {
+                *byte = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+            Ok(())
+        }
+    }
+
+    struct FailingDevice;
+
+    impl PlaybackDevice for FailingDevice {
+        fn write_period(&mut self, _data: &[u8]) -> Result<(), BoxError> {
+            Err(Box::new(io::Error::from(io::ErrorKind::BrokenPipe)))
+        }
+    }
+
+    #[test]
+    fn playback_writes_committed_frames() {
+        let played = Arc::new(Mutex::new(Vec::new()));
+        let mut stream =
+            BlockingPlaybackStream::new(RecordingDevice(played.clone()), 2, 4).unwrap();
+        block_on(async {
+            for period in 0..5u8 {
+                let mut buffer = stream
+                    .next_playback_buffer(&BlockingExecutor)
+                    .await
+                    .unwrap();
+                assert_eq!(buffer.frame_capacity(), 4);
+                // The last period is only partially filled.
+                let len = if period == 4 { 4 } else { 8 };
+                buffer.copy_cb(len, |data| data.fill(period)).unwrap();
+                buffer.commit().await;
+            }
+        });
+        drop(stream);
+
+        let mut expected = Vec::new();
+        for period in 0..4u8 {
+            expected.extend_from_slice(&[period; 8]);
+        }
+        expected.extend_from_slice(&[4; 4]);
+        assert_eq!(*played.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn capture_reads_device_periods() {
+        let mut stream = BlockingCaptureStream::new(CountingDevice(0), 4, 2).unwrap();
+        block_on(async {
+            let mut expected = 0u8;
+            for _ in 0..5 {
+                let mut buffer = stream.next_capture_buffer(&BlockingExecutor).await.unwrap();
+                let mut data = [0u8; 8];
+                buffer
+                    .copy_cb(8, |captured| data.copy_from_slice(captured))
+                    .unwrap();
+                for byte in data {
+                    assert_eq!(byte, expected);
+                    expected = expected.wrapping_add(1);
+                }
+                buffer.commit().await;
+            }
+        });
+    }
+
+    #[test]
+    fn playback_reports_device_failure() {
+        let mut stream = BlockingPlaybackStream::new(FailingDevice, 2, 4).unwrap();
+        block_on(async {
+            // Two buffers are available before the device thread has to return one.
+            for _ in 0..NUM_BUFFERS {
+                let mut buffer = stream
+                    .next_playback_buffer(&BlockingExecutor)
+                    .await
+                    .unwrap();
+                buffer.copy_cb(8, |data| data.fill(0)).unwrap();
+                buffer.commit().await;
+            }
+            assert!(stream
+                .next_playback_buffer(&BlockingExecutor)
+                .await
+                .is_err());
+        });
+    }
+}
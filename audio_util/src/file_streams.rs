@@ -4,6 +4,8 @@
 
 use std::fs::File;
 use std::io::Error as IOError;
+use std::io::Write;
+use std::ops::Range;
 use std::slice;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
@@ -12,6 +14,8 @@ use std::time::Duration;
 use std::time::Instant;
 
 use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
 use audio_streams::AsyncBufferCommit;
 use audio_streams::AsyncPlaybackBuffer;
 use audio_streams::AsyncPlaybackBufferStream;
@@ -20,6 +24,7 @@ use audio_streams::BoxError;
 use audio_streams::NoopStreamControl;
 use audio_streams::SampleFormat;
 use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
 use base::warn;
@@ -27,18 +32,67 @@ use base::MappedRegion;
 use base::MemoryMapping;
 use base::MemoryMappingBuilder;
 use base::MmapError;
+use base::Protection;
 use thiserror::Error as ThisError;
 
+use crate::wav::is_wav;
+use crate::wav::parse_wav;
+use crate::wav::wav_header;
+use crate::wav::Error as WavError;
+use crate::wav::WavFormat;
+use crate::wav::WAV_HEADER_SIZE;
+
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("Failed to build memory mapping: {0}")]
     BuildMemoryMapping(MmapError),
     #[error("Failed to clone file descriptor: {0}")]
     Clone(IOError),
+    #[error("Failed to get the size of the capture file: {0}")]
+    Metadata(IOError),
+    #[error("Failed to parse WAV capture file: {0}")]
+    ParseWav(WavError),
     #[error("Not implemented")]
     Unimplemented,
 }
 
+/// Log of the progress of a stream, written as one line per committed buffer holding the
+/// microseconds elapsed since the stream started and the number of frames transferred so far.
+struct TimestampLog {
+    file: File,
+    start_time: Instant,
+    frames: usize,
+}
+
+impl TimestampLog {
+    fn new(mut file: File, num_channels: usize, format: SampleFormat, frame_rate: u32) -> Self {
+        if let Err(e) = writeln!(
+            file,
+            "# start channels={} format={:?} rate={}",
+            num_channels, format, frame_rate
+        ) {
+            warn!("Failed to write timestamp log: {}", e);
+        }
+        TimestampLog {
+            file,
+            start_time: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    fn record(&mut self, nframes: usize) {
+        self.frames += nframes;
+        if let Err(e) = writeln!(
+            self.file,
+            "{} {}",
+            self.start_time.elapsed().as_micros(),
+            self.frames
+        ) {
+            warn!("Failed to write timestamp log: {}", e);
+        }
+    }
+}
+
 /// An Audio Stream that can be used to write playback buffer to a file.
 /// `FileStream` doesn't directly open and write to file. It receives
 /// an mmap of a file instead.
@@ -64,6 +118,8 @@ pub struct FileStream {
     start_time: Option<Instant>,
     /// Type that will be called before the buffer is dropped.
     buffer_drop: FileStreamBufferCommit,
+    /// Format written in the WAV header at the start of the file, if the file is a WAV file.
+    wav_format: Option<WavFormat>,
 }
 
 impl FileStream {
@@ -73,9 +129,11 @@ impl FileStream {
         frame_size: usize,
         buffer_mem_length: usize,
         interval_ms: Duration,
+        wav_format: Option<WavFormat>,
+        timestamp_log: Option<TimestampLog>,
     ) -> Self {
         let max_offset = memory_mapping.size();
-        FileStream {
+        let stream = FileStream {
             memory_mapping,
             offset: offset.clone(),
             frame_size,
@@ -88,11 +146,30 @@ impl FileStream {
                 frame_size,
                 offset,
                 max_offset,
+                timestamp_log,
             },
+            wav_format,
+        };
+        stream.update_wav_header();
+        stream
+    }
+
+    /// Updates the data size in the WAV header to cover everything written so far.
+    fn update_wav_header(&self) {
+        if let Some(wav_format) = &self.wav_format {
+            let data_len = self.offset.load(Ordering::Relaxed) - WAV_HEADER_SIZE;
+            self.memory_mapping
+                .write_slice(&wav_header(wav_format, data_len as u32), 0);
         }
     }
 }
 
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.update_wav_header();
+    }
+}
+
 #[async_trait(?Send)]
 impl AsyncPlaybackBufferStream for FileStream {
     async fn next_playback_buffer<'a>(
@@ -109,6 +186,7 @@ impl AsyncPlaybackBufferStream for FileStream {
             self.start_time = Some(Instant::now());
             self.next_frame = self.interval_ms;
         }
+        self.update_wav_header();
 
         let offset = self.offset.load(Ordering::Relaxed);
         let buffer = self
@@ -127,14 +205,24 @@ struct FileStreamSource {
     file: File,
     file_size: usize,
     offset: Arc<AtomicUsize>,
+    wav: bool,
+    timestamp_log: Option<File>,
 }
 
 impl FileStreamSource {
-    fn new(file: File, file_size: usize, offset: Arc<AtomicUsize>) -> Self {
+    fn new(
+        file: File,
+        file_size: usize,
+        offset: Arc<AtomicUsize>,
+        wav: bool,
+        timestamp_log: Option<File>,
+    ) -> Self {
         FileStreamSource {
             file,
             file_size,
             offset,
+            wav,
+            timestamp_log,
         }
     }
 }
@@ -157,6 +245,13 @@ impl StreamSource for FileStreamSource {
         let buffer_mem_length = buffer_size * frame_size;
         let memory_mapping = AudioMemoryMapping::new(memory_mapping, buffer_mem_length);
         let interval_ms = Duration::from_millis(buffer_size as u64 * 1000 / frame_rate as u64);
+        let wav_format = if self.wav {
+            Some(WavFormat::new(num_channels, format, frame_rate))
+        } else {
+            None
+        };
+        let timestamp_log = try_clone_log(&self.timestamp_log)?
+            .map(|file| TimestampLog::new(file, num_channels, format, frame_rate));
         Ok((
             Box::new(NoopStreamControl::new()),
             Box::new(FileStream::new(
@@ -165,6 +260,8 @@ impl StreamSource for FileStreamSource {
                 frame_size,
                 buffer_mem_length,
                 interval_ms,
+                wav_format,
+                timestamp_log,
             )),
         ))
     }
@@ -195,6 +292,10 @@ pub struct FileStreamSourceGenerator {
     file_size: usize,
     /// Number of bytes that has been written to the file.
     offset: Arc<AtomicUsize>,
+    /// Whether the file starts with a WAV header describing the played samples.
+    wav: bool,
+    /// File where the progress of the streams is logged.
+    timestamp_log: Option<File>,
 }
 
 impl FileStreamSourceGenerator {
@@ -210,8 +311,31 @@ impl FileStreamSourceGenerator {
             file,
             file_size,
             offset: Arc::new(AtomicUsize::new(0)),
+            wav: false,
+            timestamp_log: None,
         }
     }
+
+    /// Writes the samples after a WAV header holding the format of the stream, instead of as raw
+    /// samples.
+    pub fn with_wav_header(mut self) -> Self {
+        self.wav = true;
+        self.offset = Arc::new(AtomicUsize::new(WAV_HEADER_SIZE));
+        self
+    }
+
+    /// Logs the time at which each buffer is committed to `timestamp_log`.
+    pub fn with_timestamp_log(mut self, timestamp_log: File) -> Self {
+        self.timestamp_log = Some(timestamp_log);
+        self
+    }
+}
+
+fn try_clone_log(timestamp_log: &Option<File>) -> Result<Option<File>, Error> {
+    timestamp_log
+        .as_ref()
+        .map(|file| file.try_clone().map_err(Error::Clone))
+        .transpose()
 }
 
 impl StreamSourceGenerator for FileStreamSourceGenerator {
@@ -220,6 +344,8 @@ impl StreamSourceGenerator for FileStreamSourceGenerator {
             self.file.try_clone().map_err(Error::Clone)?,
             self.file_size,
             self.offset.clone(),
+            self.wav,
+            try_clone_log(&self.timestamp_log)?,
         )))
     }
 }
@@ -228,6 +354,7 @@ struct FileStreamBufferCommit {
     frame_size: usize,
     offset: Arc<AtomicUsize>,
     max_offset: usize,
+    timestamp_log: Option<TimestampLog>,
 }
 
 #[async_trait(?Send)]
@@ -237,6 +364,257 @@ impl AsyncBufferCommit for FileStreamBufferCommit {
         if self.offset.load(Ordering::Relaxed) + written_bytes < self.max_offset {
             self.offset.fetch_add(written_bytes, Ordering::Relaxed);
         }
+        if let Some(timestamp_log) = &mut self.timestamp_log {
+            timestamp_log.record(nwritten);
+        }
+    }
+}
+
+/// Samples read by capture streams, taken from the data chunk of a WAV file or from the whole of a
+/// raw file.
+struct CaptureData {
+    /// Mapping of the file, or `None` for an empty file which cannot be mapped.
+    memory_mapping: Option<MemoryMapping>,
+    /// Position of the samples in the file.
+    samples: Range<usize>,
+    /// Format given in the header of a WAV file.
+    wav_format: Option<WavFormat>,
+}
+
+impl CaptureData {
+    fn new(file: &File) -> Result<Self, Error> {
+        let size = file.metadata().map_err(Error::Metadata)?.len() as usize;
+        if size == 0 {
+            return Ok(CaptureData {
+                memory_mapping: None,
+                samples: 0..0,
+                wav_format: None,
+            });
+        }
+        let memory_mapping = MemoryMappingBuilder::new(size)
+            .from_file(file)
+            .protection(Protection::read())
+            .build()
+            .map_err(Error::BuildMemoryMapping)?;
+        // Safe because the mapping is `size` bytes long and outlives the slice, which is only
+        // used to parse the header.
+        let contents = unsafe { slice::from_raw_parts(memory_mapping.as_ptr(), size) };
+        let (wav_format, samples) = if is_wav(contents) {
+            let (wav_format, samples) = parse_wav(contents).map_err(Error::ParseWav)?;
+            (Some(wav_format), samples)
+        } else {
+            (None, 0..size)
+        };
+        Ok(CaptureData {
+            memory_mapping: Some(memory_mapping),
+            samples,
+            wav_format,
+        })
+    }
+}
+
+/// An Audio Stream that reads capture buffers from the samples of a file, at the pace of the
+/// stream. Past the end of the samples, it either starts over or returns silence.
+pub struct FileCaptureStream {
+    data: Arc<CaptureData>,
+    /// Number of bytes of samples that have been read.
+    position: Arc<AtomicUsize>,
+    /// Number of bytes of samples that can be read, a multiple of the frame size.
+    samples_len: usize,
+    looping: bool,
+    buffer: Vec<u8>,
+    frame_size: usize,
+
+    /// Duration of an audio in milliseconds for the current `buffer_size`.
+    interval_ms: Duration,
+    /// Time marker of correct time to return next buffer.
+    next_frame: Duration,
+    /// Timestamp that records when the stream starts.
+    start_time: Option<Instant>,
+    /// Type that will be called before the buffer is dropped.
+    buffer_drop: FileCaptureBufferCommit,
+}
+
+impl FileCaptureStream {
+    /// Fills the capture buffer with the next samples of the file.
+    fn fill_buffer(&mut self) {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            let position = self.position.load(Ordering::Relaxed);
+            let remaining = self.samples_len.saturating_sub(position);
+            if remaining == 0 {
+                if self.looping && self.samples_len > 0 {
+                    self.position.store(0, Ordering::Relaxed);
+                    continue;
+                }
+                self.buffer[filled..].fill(0);
+                break;
+            }
+            let len = remaining.min(self.buffer.len() - filled);
+            if let Some(memory_mapping) = &self.data.memory_mapping {
+                if let Err(e) = memory_mapping.read_slice(
+                    &mut self.buffer[filled..filled + len],
+                    self.data.samples.start + position,
+                ) {
+                    warn!("Failed to read capture file: {}", e);
+                }
+            }
+            self.position.store(position + len, Ordering::Relaxed);
+            filled += len;
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for FileCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        if let Some(start_time) = self.start_time {
+            let elapsed = start_time.elapsed();
+            if elapsed < self.next_frame {
+                ex.delay(self.next_frame - elapsed).await?;
+            }
+            self.next_frame += self.interval_ms;
+        } else {
+            self.start_time = Some(Instant::now());
+            self.next_frame = self.interval_ms;
+        }
+        self.fill_buffer();
+
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+struct FileCaptureBufferCommit {
+    timestamp_log: Option<TimestampLog>,
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for FileCaptureBufferCommit {
+    async fn commit(&mut self, nread: usize) {
+        if let Some(timestamp_log) = &mut self.timestamp_log {
+            timestamp_log.record(nread);
+        }
+    }
+}
+
+struct FileCaptureStreamSource {
+    data: Arc<CaptureData>,
+    position: Arc<AtomicUsize>,
+    looping: bool,
+    timestamp_log: Option<File>,
+}
+
+impl StreamSource for FileCaptureStreamSource {
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let stream_format = WavFormat::new(num_channels, format, frame_rate);
+        if let Some(wav_format) = self.data.wav_format {
+            if wav_format != stream_format {
+                warn!(
+                    "Capture file format {:?} differs from stream format {:?}, samples are not converted",
+                    wav_format, stream_format
+                );
+            }
+        }
+        let frame_size = format.sample_bytes() * num_channels;
+        let samples_len = self.data.samples.len() / frame_size * frame_size;
+        let interval_ms = Duration::from_millis(buffer_size as u64 * 1000 / frame_rate as u64);
+        let timestamp_log = try_clone_log(&self.timestamp_log)?
+            .map(|file| TimestampLog::new(file, num_channels, format, frame_rate));
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(FileCaptureStream {
+                data: self.data.clone(),
+                position: self.position.clone(),
+                samples_len,
+                looping: self.looping,
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                interval_ms,
+                next_frame: interval_ms,
+                start_time: None,
+                buffer_drop: FileCaptureBufferCommit { timestamp_log },
+            }),
+        ))
+    }
+
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<
+        (
+            Box<dyn StreamControl>,
+            Box<dyn audio_streams::PlaybackBufferStream>,
+        ),
+        BoxError,
+    > {
+        Err(Box::new(Error::Unimplemented))
+    }
+}
+
+/// `FileCaptureStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// for capture streams reading the samples of a WAV or raw file.
+pub struct FileCaptureStreamSourceGenerator {
+    data: Arc<CaptureData>,
+    /// Number of bytes of samples that have been read, shared by the streams.
+    position: Arc<AtomicUsize>,
+    /// Whether to start over at the end of the samples instead of returning silence.
+    looping: bool,
+    /// File where the progress of the streams is logged.
+    timestamp_log: Option<File>,
+}
+
+impl FileCaptureStreamSourceGenerator {
+    /// Creates a new `FileCaptureStreamSourceGenerator` reading the samples of `file`.
+    /// Files starting with a RIFF WAVE header are read from their data chunk, other files are
+    /// read as raw samples in the format of the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The file holding the captured samples.
+    /// * `looping` - Whether to start over at the end of the samples instead of returning
+    ///   silence.
+    pub fn new(file: File, looping: bool) -> Result<Self, Error> {
+        Ok(FileCaptureStreamSourceGenerator {
+            data: Arc::new(CaptureData::new(&file)?),
+            position: Arc::new(AtomicUsize::new(0)),
+            looping,
+            timestamp_log: None,
+        })
+    }
+
+    /// Logs the time at which each buffer is committed to `timestamp_log`.
+    pub fn with_timestamp_log(mut self, timestamp_log: File) -> Self {
+        self.timestamp_log = Some(timestamp_log);
+        self
+    }
+}
+
+impl StreamSourceGenerator for FileCaptureStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(FileCaptureStreamSource {
+            data: self.data.clone(),
+            position: self.position.clone(),
+            looping: self.looping,
+            timestamp_log: try_clone_log(&self.timestamp_log)?,
+        }))
     }
 }
 
@@ -265,4 +643,81 @@ impl AudioMemoryMapping {
     fn size(&self) -> usize {
         self.memory_mapping.size()
     }
+
+    fn write_slice(&self, buf: &[u8], offset: usize) {
+        if let Err(e) = self.memory_mapping.write_slice(buf, offset) {
+            warn!("Failed to write to memory mapping: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture_stream(contents: &[u8], looping: bool, buffer_size: usize) -> FileCaptureStream {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        let data = Arc::new(CaptureData::new(&file).unwrap());
+        let frame_size = 2;
+        FileCaptureStream {
+            samples_len: data.samples.len() / frame_size * frame_size,
+            data,
+            position: Arc::new(AtomicUsize::new(0)),
+            looping,
+            buffer: vec![0xff; buffer_size * frame_size],
+            frame_size,
+            interval_ms: Duration::from_millis(1),
+            next_frame: Duration::from_millis(1),
+            start_time: None,
+            buffer_drop: FileCaptureBufferCommit {
+                timestamp_log: None,
+            },
+        }
+    }
+
+    #[test]
+    fn capture_raw_then_silence() {
+        let mut stream = capture_stream(&[1, 2, 3, 4, 5, 6, 7], false, 2);
+
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [1, 2, 3, 4]);
+        // The trailing partial frame is dropped.
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [5, 6, 0, 0]);
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn capture_raw_looping() {
+        let mut stream = capture_stream(&[1, 2, 3, 4, 5, 6], true, 2);
+
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [1, 2, 3, 4]);
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [5, 6, 1, 2]);
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn capture_wav_data_chunk() {
+        let format = WavFormat::new(1, SampleFormat::S16LE, 8000);
+        let mut contents = wav_header(&format, 4).to_vec();
+        contents.extend_from_slice(&[1, 2, 3, 4]);
+        let mut stream = capture_stream(&contents, true, 3);
+
+        assert_eq!(stream.data.wav_format, Some(format));
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [1, 2, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn capture_empty_file() {
+        let mut stream = capture_stream(&[], true, 1);
+
+        stream.fill_buffer();
+        assert_eq!(stream.buffer, [0, 0]);
+    }
 }
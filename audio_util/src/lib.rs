@@ -2,7 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(feature = "alsa")]
+mod alsa_streams;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub mod blocking_streams;
 mod file_streams;
+#[cfg(feature = "pulse")]
+mod pulse_streams;
+mod wav;
 
+#[cfg(feature = "alsa")]
+pub use alsa_streams::AlsaStreamSourceGenerator;
 pub use file_streams::Error;
+pub use file_streams::FileCaptureStreamSourceGenerator;
 pub use file_streams::FileStreamSourceGenerator;
+#[cfg(feature = "pulse")]
+pub use pulse_streams::PulseStreamSourceGenerator;
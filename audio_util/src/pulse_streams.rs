@@ -0,0 +1,290 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audio streams playing to and capturing from a PulseAudio server.
+//!
+//! Streams are opened with the libpulse-simple API, which also works with PipeWire through its
+//! `pipewire-pulse` server.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::raw::c_void;
+use std::ptr::null;
+
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use libpulse_simple_sys::pa_simple;
+use libpulse_simple_sys::pa_simple_free;
+use libpulse_simple_sys::pa_simple_new;
+use libpulse_simple_sys::pa_simple_read;
+use libpulse_simple_sys::pa_simple_write;
+use libpulse_sys::pa_buffer_attr;
+use libpulse_sys::pa_sample_format_t;
+use libpulse_sys::pa_sample_spec;
+use libpulse_sys::pa_stream_direction_t;
+use libpulse_sys::pa_strerror;
+use libpulse_sys::PA_SAMPLE_S16LE;
+use libpulse_sys::PA_SAMPLE_S24_32LE;
+use libpulse_sys::PA_SAMPLE_S32LE;
+use libpulse_sys::PA_SAMPLE_U8;
+use libpulse_sys::PA_STREAM_PLAYBACK;
+use libpulse_sys::PA_STREAM_RECORD;
+use thiserror::Error as ThisError;
+
+use crate::blocking_streams::BlockingCaptureStream;
+use crate::blocking_streams::BlockingPlaybackStream;
+use crate::blocking_streams::CaptureDevice;
+use crate::blocking_streams::PlaybackDevice;
+
+const APPLICATION_NAME: &[u8] = b"crosvm\0";
+
+// Lets the server pick the value of a buffer attribute.
+const PA_BUFFER_ATTR_DEFAULT: u32 = u32::MAX;
+
+fn pulse_error_string(error: i32) -> String {
+    // Safe because pa_strerror returns either null or a pointer to a static string.
+    let message = unsafe { pa_strerror(error) };
+    if message.is_null() {
+        return format!("error {}", error);
+    }
+    // Safe because the string is static and nul-terminated.
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to connect to PulseAudio: {0}")]
+    Connect(String),
+    #[error("invalid PulseAudio device name: {0}")]
+    InvalidDeviceName(String),
+    #[error("failed to read from PulseAudio stream: {0}")]
+    Read(String),
+    #[error("PulseAudio streams only support async operation")]
+    Unimplemented,
+    #[error("failed to write to PulseAudio stream: {0}")]
+    Write(String),
+}
+
+fn pulse_format(format: SampleFormat) -> pa_sample_format_t {
+    match format {
+        SampleFormat::U8 => PA_SAMPLE_U8,
+        SampleFormat::S16LE => PA_SAMPLE_S16LE,
+        SampleFormat::S24LE => PA_SAMPLE_S24_32LE,
+        SampleFormat::S32LE => PA_SAMPLE_S32LE,
+    }
+}
+
+/// A connection to the server carrying a single playback or record stream.
+struct PulseStream {
+    simple: *mut pa_simple,
+}
+
+// Safe because the pa_simple connection is only used by the thread owning the `PulseStream`, and
+// libpulse-simple does not tie connections to the thread that created them.
+unsafe impl Send for PulseStream {}
+
+impl PulseStream {
+    fn new(
+        device: Option<&str>,
+        dir: pa_stream_direction_t,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_bytes: usize,
+    ) -> Result<PulseStream, Error> {
+        let device = device
+            .map(|d| CString::new(d).map_err(|_| Error::InvalidDeviceName(d.to_owned())))
+            .transpose()?;
+        let stream_name: &[u8] = if dir == PA_STREAM_PLAYBACK {
+            b"playback\0"
+        } else {
+            b"capture\0"
+        };
+        let spec = pa_sample_spec {
+            format: pulse_format(format),
+            rate: frame_rate,
+            channels: num_channels as u8,
+        };
+        // Asks the server to keep about one period buffered so that the latency of the guest
+        // matches the one it configured.
+        let attr = pa_buffer_attr {
+            maxlength: PA_BUFFER_ATTR_DEFAULT,
+            tlength: period_bytes as u32,
+            prebuf: PA_BUFFER_ATTR_DEFAULT,
+            minreq: PA_BUFFER_ATTR_DEFAULT,
+            fragsize: period_bytes as u32,
+        };
+        let mut error: i32 = 0;
+        // Safe because all strings are nul-terminated and outlive the call, the spec and attr
+        // pointers refer to valid structures and the returned pointer is checked.
+        let simple = unsafe {
+            pa_simple_new(
+                null(),
+                APPLICATION_NAME.as_ptr() as *const c_char,
+                dir,
+                device.as_ref().map_or(null(), |d| d.as_ptr()),
+                stream_name.as_ptr() as *const c_char,
+                &spec,
+                null(),
+                &attr,
+                &mut error,
+            )
+        };
+        if simple.is_null() {
+            return Err(Error::Connect(pulse_error_string(error)));
+        }
+        Ok(PulseStream { simple })
+    }
+}
+
+impl Drop for PulseStream {
+    fn drop(&mut self) {
+        // Safe because `simple` was returned by pa_simple_new and is not used after this.
+        unsafe { pa_simple_free(self.simple) };
+    }
+}
+
+impl PlaybackDevice for PulseStream {
+    fn write_period(&mut self, data: &[u8]) -> Result<(), BoxError> {
+        let mut error: i32 = 0;
+        // Safe because `simple` is valid and the server only reads `data.len()` bytes of `data`.
+        let ret = unsafe {
+            pa_simple_write(
+                self.simple,
+                data.as_ptr() as *const c_void,
+                data.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(Box::new(Error::Write(pulse_error_string(error))));
+        }
+        Ok(())
+    }
+}
+
+impl CaptureDevice for PulseStream {
+    fn read_period(&mut self, data: &mut [u8]) -> Result<(), BoxError> {
+        let mut error: i32 = 0;
+        // Safe because `simple` is valid and at most `data.len()` bytes are written to `data`.
+        let ret = unsafe {
+            pa_simple_read(
+                self.simple,
+                data.as_mut_ptr() as *mut c_void,
+                data.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(Box::new(Error::Read(pulse_error_string(error))));
+        }
+        Ok(())
+    }
+}
+
+/// Stream source opening a new server connection for each stream it is asked for.
+struct PulseStreamSource {
+    device: Option<String>,
+}
+
+impl StreamSource for PulseStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(Error::Unimplemented))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let stream = PulseStream::new(
+            self.device.as_deref(),
+            PA_STREAM_PLAYBACK,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size * frame_size,
+        )?;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(BlockingPlaybackStream::new(
+                stream,
+                frame_size,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let stream = PulseStream::new(
+            self.device.as_deref(),
+            PA_STREAM_RECORD,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size * frame_size,
+        )?;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(BlockingCaptureStream::new(stream, frame_size, buffer_size)?),
+        ))
+    }
+}
+
+/// `PulseStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// for streams on a PulseAudio or PipeWire server.
+pub struct PulseStreamSourceGenerator {
+    /// Name of the sink or source to use, or `None` for the server default.
+    device: Option<String>,
+}
+
+impl PulseStreamSourceGenerator {
+    /// Creates a new `PulseStreamSourceGenerator` opening streams on the sink or source named
+    /// `device`.
+    pub fn new(device: Option<String>) -> Self {
+        PulseStreamSourceGenerator { device }
+    }
+}
+
+impl StreamSourceGenerator for PulseStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(PulseStreamSource {
+            device: self.device.clone(),
+        }))
+    }
+}
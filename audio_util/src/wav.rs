@@ -0,0 +1,199 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal support for the RIFF WAVE format, enough to write the header of PCM recordings and to
+//! locate the samples of PCM files.
+
+use std::ops::Range;
+
+use audio_streams::SampleFormat;
+use thiserror::Error as ThisError;
+
+/// Size of the header written by [`wav_header`].
+pub const WAV_HEADER_SIZE: usize = 44;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("WAV file has no data chunk")]
+    MissingData,
+    #[error("WAV file has no fmt chunk")]
+    MissingFormat,
+    #[error("not a RIFF WAVE file")]
+    NotWave,
+    #[error("WAV file is truncated")]
+    Truncated,
+    #[error("unsupported WAV encoding {0:#x}")]
+    UnsupportedEncoding(u16),
+}
+
+/// Format of the samples of a PCM WAV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub num_channels: u16,
+    pub frame_rate: u32,
+    /// Size in bytes of one sample. 24-bit samples are stored in 32 bits.
+    pub sample_bytes: u16,
+}
+
+impl WavFormat {
+    /// Returns the format of samples played or captured with `format`.
+    pub fn new(num_channels: usize, format: SampleFormat, frame_rate: u32) -> Self {
+        WavFormat {
+            num_channels: num_channels as u16,
+            frame_rate,
+            sample_bytes: format.sample_bytes() as u16,
+        }
+    }
+
+    /// Returns the size in bytes of one frame.
+    pub fn frame_size(&self) -> u16 {
+        self.num_channels * self.sample_bytes
+    }
+}
+
+/// Returns the header of a WAV file holding `data_len` bytes of samples in `format`.
+pub fn wav_header(format: &WavFormat, data_len: u32) -> [u8; WAV_HEADER_SIZE] {
+    let frame_size = format.frame_size();
+    let mut header = [0u8; WAV_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(data_len.saturating_add(36)).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    header[20..22].copy_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+    header[22..24].copy_from_slice(&format.num_channels.to_le_bytes());
+    header[24..28].copy_from_slice(&format.frame_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&(format.frame_rate * frame_size as u32).to_le_bytes());
+    header[32..34].copy_from_slice(&frame_size.to_le_bytes());
+    header[34..36].copy_from_slice(&(format.sample_bytes * 8).to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(Error::Truncated)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(Error::Truncated)
+}
+
+/// Returns true if `data` starts like a RIFF WAVE file.
+pub fn is_wav(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE"
+}
+
+/// Parses the header of the WAV file held in `data`, returning the format of its samples and the
+/// range of `data` they occupy.
+///
+/// A data chunk extending past the end of `data`, as left by an interrupted recording, is
+/// truncated to the available bytes.
+pub fn parse_wav(data: &[u8]) -> Result<(WavFormat, Range<usize>), Error> {
+    if !is_wav(data) {
+        return Err(Error::NotWave);
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let len = read_u32(data, offset + 4)? as usize;
+        let body = offset + 8;
+        match id {
+            b"fmt " => {
+                let encoding = read_u16(data, body)?;
+                if encoding != WAVE_FORMAT_PCM && encoding != WAVE_FORMAT_EXTENSIBLE {
+                    return Err(Error::UnsupportedEncoding(encoding));
+                }
+                let num_channels = read_u16(data, body + 2)?;
+                let frame_rate = read_u32(data, body + 4)?;
+                let block_align = read_u16(data, body + 12)?;
+                format = Some(WavFormat {
+                    num_channels,
+                    frame_rate,
+                    sample_bytes: block_align / num_channels.max(1),
+                });
+            }
+            b"data" => {
+                let format = format.ok_or(Error::MissingFormat)?;
+                let end = body.saturating_add(len).min(data.len());
+                return Ok((format, body..end));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        offset = body.saturating_add(len).saturating_add(len & 1);
+    }
+    Err(Error::MissingData)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let format = WavFormat::new(2, SampleFormat::S16LE, 48000);
+        let mut file = wav_header(&format, 8).to_vec();
+        file.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert_eq!(read_u32(&file, 4), Ok(44));
+        assert_eq!(read_u32(&file, 28), Ok(192000));
+        assert_eq!(parse_wav(&file), Ok((format, 44..52)));
+    }
+
+    #[test]
+    fn s24_is_stored_in_32_bits() {
+        let format = WavFormat::new(1, SampleFormat::S24LE, 8000);
+        let header = wav_header(&format, 0);
+
+        assert_eq!(read_u16(&header, 32), Ok(4));
+        assert_eq!(read_u16(&header, 34), Ok(32));
+        let (parsed, _) = parse_wav(&header).unwrap();
+        assert_eq!(parsed.sample_bytes, 4);
+    }
+
+    #[test]
+    fn parse_skips_unknown_chunks() {
+        let format = WavFormat::new(1, SampleFormat::U8, 8000);
+        let header = wav_header(&format, 3);
+        let mut file = header[0..36].to_vec();
+        file.extend_from_slice(b"LIST");
+        file.extend_from_slice(&3u32.to_le_bytes());
+        file.extend_from_slice(&[0, 0, 0, 0]);
+        file.extend_from_slice(&header[36..44]);
+        file.extend_from_slice(&[7, 8, 9]);
+
+        assert_eq!(parse_wav(&file), Ok((format, 56..59)));
+    }
+
+    #[test]
+    fn parse_truncated_data() {
+        let format = WavFormat::new(1, SampleFormat::S16LE, 8000);
+        let mut file = wav_header(&format, 1000).to_vec();
+        file.extend_from_slice(&[0; 10]);
+
+        assert_eq!(parse_wav(&file), Ok((format, 44..54)));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_wav(b"not a wav file"), Err(Error::NotWave));
+
+        let format = WavFormat::new(1, SampleFormat::S16LE, 8000);
+        let header = wav_header(&format, 0);
+        assert_eq!(parse_wav(&header[0..36]), Err(Error::MissingData));
+
+        let mut file = header.to_vec();
+        file[20] = 3;
+        assert_eq!(parse_wav(&file), Err(Error::UnsupportedEncoding(3)));
+    }
+}
@@ -662,7 +662,7 @@ impl Bus {
         if let Some(device_index) = device_index {
             self.stats
                 .lock()
-                .end_stat(BusOperation::Write, start, device_index);
+                .end_stat(BusOperation::Read, start, device_index);
             return true;
         }
 
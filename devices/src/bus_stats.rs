@@ -136,6 +136,17 @@ impl BusStatistics {
         BusStatistics::default()
     }
 
+    /// Returns a BusStatistics without any recorded access that shares the device information of
+    /// `self`, so that the stats of several users of the same bus can be recorded separately and
+    /// merged later.
+    pub fn empty_clone(&self) -> BusStatistics {
+        BusStatistics {
+            enabled: self.enabled,
+            device_stats: Vec::new(),
+            device_identifiers: self.device_identifiers.clone(),
+        }
+    }
+
     /// Enable or disable statistics gathering.
     pub fn set_enabled(&mut self, enabled: bool) {
         self.enabled = enabled;
@@ -5,6 +5,10 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::mem;
+use std::net::TcpStream;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -15,15 +19,24 @@ use base::EventType;
 use base::RawDescriptor;
 use base::Tube;
 use sync::Mutex;
+use vm_control::EmulatedUsbDevice;
 use vm_control::UsbControlAttachedDevice;
 use vm_control::UsbControlCommand;
 use vm_control::UsbControlResult;
 use vm_control::USB_CONTROL_MAX_PORTS;
 
 use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::emulated::emulated_device::EmulatedDevice;
+use crate::usb::backend::emulated::emulated_device::FunctionBackend;
+use crate::usb::backend::emulated::hid::Hid;
+use crate::usb::backend::emulated::hid::HidKind;
+use crate::usb::backend::emulated::mass_storage::MassStorage;
+use crate::usb::backend::emulated::UsbFunction;
 use crate::usb::backend::error::*;
 use crate::usb::backend::host_backend::host_backend_device_provider::attach_host_backend_device;
 use crate::usb::backend::host_backend::host_device::HostDevice;
+use crate::usb::backend::usbip_backend::usbip_connection::UsbipConnection;
+use crate::usb::backend::usbip_backend::usbip_device::UsbipDevice;
 use crate::usb::xhci::usb_hub::UsbHub;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
@@ -209,6 +222,115 @@ impl ProviderInner {
         }
     }
 
+    fn create_emulated_function(
+        device: EmulatedUsbDevice,
+        file: File,
+    ) -> anyhow::Result<Box<dyn UsbFunction>> {
+        Ok(match device {
+            EmulatedUsbDevice::MassStorage { read_only } => {
+                let disk =
+                    disk::create_disk_file(file, false, disk::MAX_NESTING_DEPTH, Path::new(""))
+                        .context("failed to open disk image")?;
+                Box::new(MassStorage::new(disk, read_only).context("failed to get disk size")?)
+            }
+            EmulatedUsbDevice::Keyboard | EmulatedUsbDevice::Tablet => {
+                let kind = match device {
+                    EmulatedUsbDevice::Keyboard => HidKind::Keyboard,
+                    _ => HidKind::Tablet,
+                };
+                let source = UnixStream::from(OwnedFd::from(file));
+                Box::new(Hid::new(kind, source).context("failed to set up input event socket")?)
+            }
+        })
+    }
+
+    fn handle_attach_emulated_device(
+        &self,
+        device: EmulatedUsbDevice,
+        file: File,
+    ) -> UsbControlResult {
+        let function = match Self::create_emulated_function(device, file) {
+            Ok(function) => function,
+            Err(e) => {
+                error!("could not create emulated USB device {:?}: {:#}", device, e);
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+
+        let backend = FunctionBackend::new(function, self.job_queue.clone());
+        let event_handler: Arc<dyn EventHandler> = Arc::new(backend.clone());
+        if let Err(e) = backend.add_event_handler(&self.event_loop, &event_handler) {
+            error!("failed to add emulated USB device to event handler: {}", e);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+
+        let device_ctx = DeviceContext {
+            event_handler,
+            device: Arc::new(Mutex::new(backend.clone())),
+        };
+
+        let emulated_device =
+            match EmulatedDevice::new(self.fail_handle.clone(), self.job_queue.clone(), backend) {
+                Ok(emulated_device) => Box::new(emulated_device),
+                Err(e) => {
+                    error!("failed to initialize EmulatedDevice: {}", e);
+                    return UsbControlResult::FailedToInitHostDevice;
+                }
+            };
+
+        match self.usb_hub.connect_backend(emulated_device) {
+            Ok(port) => {
+                self.devices.lock().insert(port, device_ctx);
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
+    fn handle_attach_usbip_device(&self, busid: String, file: File) -> UsbControlResult {
+        let stream = TcpStream::from(OwnedFd::from(file));
+        let (connection, device, descriptors) = match UsbipConnection::new(stream, &busid) {
+            Ok(imported) => imported,
+            Err(e) => {
+                error!("could not import USB/IP device {}: {}", busid, e);
+                return UsbControlResult::NoSuchDevice;
+            }
+        };
+
+        let event_handler: Arc<dyn EventHandler> = Arc::new(connection.clone());
+        if let Err(e) = connection.add_event_handler(&self.event_loop, &event_handler) {
+            error!("failed to add USB/IP device to event handler: {}", e);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+
+        let device_ctx = DeviceContext {
+            event_handler,
+            device: Arc::new(Mutex::new(connection.clone())),
+        };
+
+        let usbip_device = Box::new(UsbipDevice::new(
+            self.fail_handle.clone(),
+            self.job_queue.clone(),
+            connection,
+            device,
+            descriptors,
+        ));
+
+        match self.usb_hub.connect_backend(usbip_device) {
+            Ok(port) => {
+                self.devices.lock().insert(port, device_ctx);
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
@@ -258,6 +380,12 @@ impl ProviderInner {
         let cmd = tube.recv().map_err(Error::ReadControlTube)?;
         let result = match cmd {
             UsbControlCommand::AttachDevice { file } => self.handle_attach_device(file),
+            UsbControlCommand::AttachEmulatedDevice { device, file } => {
+                self.handle_attach_emulated_device(device, file)
+            }
+            UsbControlCommand::AttachUsbipDevice { busid, file } => {
+                self.handle_attach_usbip_device(busid, file)
+            }
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
         };
@@ -0,0 +1,549 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use anyhow::Context;
+use base::debug;
+use base::error;
+use base::warn;
+use base::Descriptor;
+use base::EventType;
+use sync::Mutex;
+use usb_util::parse_usbfs_descriptors;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptorTree;
+use usb_util::DeviceSpeed;
+use usb_util::StandardControlRequest;
+use usb_util::Transfer;
+use usb_util::TransferBuffer;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use usb_util::ENDPOINT_DIRECTION_OFFSET;
+
+use super::string_descriptor;
+use super::UsbFunction;
+use super::DESCRIPTOR_TYPE_STRING;
+use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::endpoint::UsbEndpoint;
+use crate::usb::backend::error::*;
+use crate::usb::backend::host_backend::host_device::ControlEndpointState;
+use crate::usb::backend::transfer::BackendTransferHandle;
+use crate::usb::backend::transfer::GenericTransferHandle;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+use crate::utils::AsyncJobQueue;
+use crate::utils::EventHandler;
+use crate::utils::EventLoop;
+use crate::utils::FailHandle;
+
+// Language ID descriptor advertising US English only.
+const LANGUAGE_ID_DESCRIPTOR: [u8; 4] = [4, DESCRIPTOR_TYPE_STRING, 0x09, 0x04];
+
+type Completion = (Transfer, TransferStatus, usize);
+
+struct PendingTransfer {
+    id: u64,
+    transfer: Transfer,
+}
+
+struct FunctionState {
+    function: Box<dyn UsbFunction>,
+    // IN transfers waiting for the function to have data, in submission order.
+    pending: VecDeque<PendingTransfer>,
+    next_id: u64,
+}
+
+impl FunctionState {
+    // Retries the pending IN transfers and returns the ones that can be completed. Transfers on
+    // an endpoint that still has nothing to send block the later transfers of that endpoint.
+    fn service_pending(&mut self) -> Vec<Completion> {
+        let mut completions = Vec::new();
+        let mut blocked = BTreeSet::new();
+        let mut still_pending = VecDeque::new();
+        while let Some(mut pending) = self.pending.pop_front() {
+            let endpoint = pending.transfer.endpoint();
+            if blocked.contains(&endpoint) {
+                still_pending.push_back(pending);
+                continue;
+            }
+            let result = match &mut pending.transfer.buffer {
+                TransferBuffer::Vector(v) => self.function.data_in(endpoint & 0xf, v),
+                TransferBuffer::Dma(_) => Some((TransferStatus::Error, 0)),
+            };
+            match result {
+                Some((status, len)) => completions.push((pending.transfer, status, len)),
+                None => {
+                    blocked.insert(endpoint);
+                    still_pending.push_back(pending);
+                }
+            }
+        }
+        self.pending = still_pending;
+        completions
+    }
+}
+
+// Completes transfers from the job queue: completion callbacks take the xhci transfer lock, which
+// is held by the callers of `submit_backend_transfer` and of transfer cancellation.
+fn complete_later(job_queue: &AsyncJobQueue, completions: Vec<Completion>) -> Result<()> {
+    for completion in completions {
+        let completion = Mutex::new(Some(completion));
+        job_queue
+            .queue_job(move || {
+                if let Some((transfer, status, len)) = completion.lock().take() {
+                    transfer.complete(status, len);
+                }
+            })
+            .map_err(Error::QueueAsyncJob)?;
+    }
+    Ok(())
+}
+
+/// Backend device through which the data endpoints of an emulated device reach its function.
+#[derive(Clone)]
+pub struct FunctionBackend {
+    state: Arc<Mutex<FunctionState>>,
+    job_queue: Arc<AsyncJobQueue>,
+}
+
+impl FunctionBackend {
+    pub fn new(function: Box<dyn UsbFunction>, job_queue: Arc<AsyncJobQueue>) -> FunctionBackend {
+        FunctionBackend {
+            state: Arc::new(Mutex::new(FunctionState {
+                function,
+                pending: VecDeque::new(),
+                next_id: 0,
+            })),
+            job_queue,
+        }
+    }
+
+    /// Runs `f` on the function, then completes the pending IN transfers it may have unblocked.
+    pub fn with_function<R>(&self, f: impl FnOnce(&mut dyn UsbFunction) -> R) -> Result<R> {
+        let mut state = self.state.lock();
+        let ret = f(state.function.as_mut());
+        let completions = state.service_pending();
+        drop(state);
+        complete_later(&self.job_queue, completions)?;
+        Ok(ret)
+    }
+
+    /// Registers the event descriptor of the function, if it has one, on `event_loop`.
+    pub fn add_event_handler(
+        &self,
+        event_loop: &EventLoop,
+        handler: &Arc<dyn EventHandler>,
+    ) -> Result<()> {
+        if let Some(descriptor) = self.state.lock().function.event_descriptor() {
+            event_loop
+                .add_event(
+                    &Descriptor(descriptor),
+                    EventType::Read,
+                    Arc::downgrade(handler),
+                )
+                .map_err(Error::AddToEventLoop)?;
+        }
+        Ok(())
+    }
+}
+
+struct FunctionTransferHandle {
+    state: Weak<Mutex<FunctionState>>,
+    job_queue: Arc<AsyncJobQueue>,
+    id: u64,
+}
+
+impl GenericTransferHandle for FunctionTransferHandle {
+    fn cancel(&self) -> Result<()> {
+        let state = match self.state.upgrade() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        let mut state = state.lock();
+        let pending = match state.pending.iter().position(|p| p.id == self.id) {
+            Some(index) => state.pending.remove(index),
+            // The transfer has already completed.
+            None => None,
+        };
+        drop(state);
+        match pending {
+            Some(pending) => complete_later(
+                &self.job_queue,
+                vec![(pending.transfer, TransferStatus::Cancelled, 0)],
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BackendDevice for FunctionBackend {
+    fn submit_backend_transfer(&mut self, mut transfer: Transfer) -> Result<BackendTransferHandle> {
+        let endpoint = transfer.endpoint();
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let mut completions = Vec::new();
+        if endpoint >> ENDPOINT_DIRECTION_OFFSET == 0 {
+            let status = match &mut transfer.buffer {
+                TransferBuffer::Vector(v) => state.function.data_out(endpoint & 0xf, v),
+                TransferBuffer::Dma(_) => TransferStatus::Error,
+            };
+            let len = transfer.buffer.size().unwrap_or(0);
+            completions.push((transfer, status, len));
+        } else {
+            state.pending.push_back(PendingTransfer { id, transfer });
+        }
+        completions.extend(state.service_pending());
+        drop(state);
+        complete_later(&self.job_queue, completions)?;
+
+        Ok(BackendTransferHandle::new(FunctionTransferHandle {
+            state: Arc::downgrade(&self.state),
+            job_queue: self.job_queue.clone(),
+            id,
+        }))
+    }
+
+    fn detach_event_handler(&self, event_loop: &Arc<EventLoop>) -> Result<()> {
+        if let Some(descriptor) = self.state.lock().function.event_descriptor() {
+            event_loop
+                .remove_event_for_descriptor(&Descriptor(descriptor))
+                .map_err(Error::RemoveFromEventLoop)?;
+        }
+        Ok(())
+    }
+
+    fn request_transfer_buffer(&mut self, size: usize) -> TransferBuffer {
+        TransferBuffer::Vector(vec![0u8; size])
+    }
+}
+
+impl EventHandler for FunctionBackend {
+    fn on_event(&self) -> anyhow::Result<()> {
+        self.with_function(|function| function.on_event())
+            .context("failed to complete usb transfers")?
+    }
+}
+
+/// Emulated device is a USB device whose function is implemented by crosvm.
+pub struct EmulatedDevice {
+    fail_handle: Arc<dyn FailHandle>,
+    job_queue: Arc<AsyncJobQueue>,
+    backend: FunctionBackend,
+    descriptors: DeviceDescriptorTree,
+    // Data endpoints of the active configuration.
+    endpoints: Vec<UsbEndpoint>,
+    configuration: u8,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+}
+
+impl EmulatedDevice {
+    /// Create a new emulated device exposing the function behind `backend`.
+    pub fn new(
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+        backend: FunctionBackend,
+    ) -> Result<EmulatedDevice> {
+        let descriptors = backend
+            .with_function(|function| parse_usbfs_descriptors(&function.descriptors()))?
+            .map_err(Error::GetDeviceDescriptor)?;
+        Ok(EmulatedDevice {
+            fail_handle,
+            job_queue,
+            backend,
+            descriptors,
+            endpoints: Vec::new(),
+            configuration: 0,
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        })
+    }
+
+    fn create_endpoints(&mut self, config: u8) -> Result<()> {
+        self.endpoints = Vec::new();
+        let config_descriptor = self
+            .descriptors
+            .get_config_descriptor(config)
+            .ok_or(Error::MissingConfigDescriptor(config))?;
+        for i in 0..config_descriptor.num_interfaces() {
+            let interface = config_descriptor
+                .get_interface_descriptor(i, 0)
+                .ok_or(Error::GetInterfaceDescriptor(i, 0))?;
+            for ep_idx in 0..interface.bNumEndpoints {
+                let ep_dp = interface
+                    .get_endpoint_descriptor(ep_idx)
+                    .ok_or(Error::GetEndpointDescriptor(ep_idx))?;
+                let ep_num = ep_dp.get_endpoint_number();
+                if ep_num == 0 {
+                    continue;
+                }
+                let direction = ep_dp.get_direction();
+                let ty = ep_dp.get_endpoint_type().ok_or(Error::GetEndpointType)?;
+                self.endpoints.push(UsbEndpoint::new(
+                    self.fail_handle.clone(),
+                    self.job_queue.clone(),
+                    ep_num,
+                    direction,
+                    ty,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the descriptor requested by a standard GET_DESCRIPTOR device request.
+    fn get_descriptor(&self) -> Option<Vec<u8>> {
+        let descriptor_type = (self.control_request_setup.value >> 8) as u8;
+        let descriptor_index = self.control_request_setup.value as u8;
+        if descriptor_type == DescriptorType::Device as u8 {
+            let len = self.descriptors.raw().first().copied()? as usize;
+            self.descriptors.raw().get(..len).map(|d| d.to_vec())
+        } else if descriptor_type == DescriptorType::Configuration as u8 {
+            let config_descriptor = self
+                .descriptors
+                .get_config_descriptor_by_index(descriptor_index)?;
+            let config_start = config_descriptor.offset();
+            let config_end = config_start + config_descriptor.wTotalLength as usize;
+            self.descriptors
+                .raw()
+                .get(config_start..config_end)
+                .map(|d| d.to_vec())
+        } else if descriptor_type == DESCRIPTOR_TYPE_STRING {
+            if descriptor_index == 0 {
+                Some(LANGUAGE_ID_DESCRIPTOR.to_vec())
+            } else {
+                self.backend
+                    .with_function(|function| function.string(descriptor_index))
+                    .ok()
+                    .flatten()
+                    .map(|s| string_descriptor(&s))
+            }
+        } else {
+            None
+        }
+    }
+
+    fn set_config(&mut self) -> TransferStatus {
+        let config = self.control_request_setup.value as u8;
+        usb_trace!("set_config: {}", config);
+        if config == 0 {
+            self.endpoints = Vec::new();
+            self.configuration = 0;
+            return TransferStatus::Completed;
+        }
+        if let Err(e) = self.create_endpoints(config) {
+            error!("failed to set configuration {}: {}", config, e);
+            return TransferStatus::Stalled;
+        }
+        self.configuration = config;
+        if let Err(e) = self.backend.with_function(|function| function.reset()) {
+            error!("failed to reset usb function: {}", e);
+        }
+        TransferStatus::Completed
+    }
+
+    // Handles a standard request, returning `None` if it should be passed to the function.
+    fn standard_request(&mut self, data: &mut [u8]) -> Option<(TransferStatus, usize)> {
+        let standard_request = self.control_request_setup.get_standard_request()?;
+        let recipient = self.control_request_setup.get_recipient();
+        let response = |data: &mut [u8], response: &[u8]| {
+            let len = response.len().min(data.len());
+            data[..len].copy_from_slice(&response[..len]);
+            (TransferStatus::Completed, len)
+        };
+        let result = match (standard_request, recipient) {
+            (StandardControlRequest::GetDescriptor, ControlRequestRecipient::Device) => {
+                match self.get_descriptor() {
+                    Some(descriptor) => response(data, &descriptor),
+                    None => (TransferStatus::Stalled, 0),
+                }
+            }
+            // The address is assigned by the xHCI Address Device command.
+            (StandardControlRequest::SetAddress, _) => (TransferStatus::Completed, 0),
+            (StandardControlRequest::SetConfiguration, _) => (self.set_config(), 0),
+            (StandardControlRequest::GetConfiguration, _) => response(data, &[self.configuration]),
+            (StandardControlRequest::GetStatus, _) => response(data, &[0, 0]),
+            (StandardControlRequest::GetInterface, _) => response(data, &[0]),
+            (StandardControlRequest::SetInterface, _) => {
+                if self.control_request_setup.value == 0 {
+                    (TransferStatus::Completed, 0)
+                } else {
+                    (TransferStatus::Stalled, 0)
+                }
+            }
+            // Endpoint halt is tracked by the xHCI controller.
+            (StandardControlRequest::ClearFeature, _) | (StandardControlRequest::SetFeature, _) => {
+                (TransferStatus::Completed, 0)
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: &XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let direction = self.control_request_setup.get_direction();
+        let mut data = vec![0u8; self.control_request_setup.length as usize];
+        if direction == ControlRequestDataPhaseTransferDirection::HostToDevice {
+            if let Some(buffer) = &buffer {
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+            }
+        }
+
+        let (status, mut len) = match self.standard_request(&mut data) {
+            Some(result) => result,
+            None => {
+                let setup = self.control_request_setup;
+                self.backend
+                    .with_function(|function| function.control(&setup, &mut data))?
+            }
+        };
+
+        if direction == ControlRequestDataPhaseTransferDirection::DeviceToHost {
+            len = match &buffer {
+                Some(buffer) => buffer.write(&data[..len]).map_err(Error::WriteBuffer)?,
+                None => 0,
+            };
+        }
+        debug!(
+            "emulated control transfer completed with actual length {}",
+            len
+        );
+        xhci_transfer
+            .on_transfer_complete(&status, len as u32)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage => {
+                let setup = xhci_transfer
+                    .create_usb_request_setup()
+                    .map_err(Error::CreateUsbRequestSetup)?;
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_trace!("setup stage: setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                let buffer = xhci_transfer.create_buffer().map_err(Error::CreateBuffer)?;
+                self.execute_control_transfer(&xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(&xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl XhciBackendDevice for EmulatedDevice {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.descriptors.idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.descriptors.idProduct
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            return self.handle_control_transfer(transfer);
+        }
+        for ep in &self.endpoints {
+            if ep.match_ep(transfer.get_endpoint_number(), transfer.get_transfer_dir()) {
+                return ep.handle_transfer(&mut self.backend, transfer);
+            }
+        }
+        warn!("Could not find endpoint for transfer");
+        transfer
+            .on_transfer_complete(&TransferStatus::Error, 0)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn set_address(&mut self, address: UsbDeviceAddress) {
+        debug!("emulated usb device got address {}", address);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.endpoints = Vec::new();
+        self.configuration = 0;
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        self.backend.with_function(|function| function.reset())
+    }
+
+    fn get_speed(&self) -> Option<DeviceSpeed> {
+        self.backend
+            .with_function(|function| function.speed())
+            .map_err(|e| error!("failed to get usb function speed: {}", e))
+            .ok()
+    }
+
+    fn alloc_streams(&self, _ep: u8, _num_streams: u16) -> Result<()> {
+        // Emulated devices do not advertise bulk streams.
+        Ok(())
+    }
+
+    fn free_streams(&self, _ep: u8) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB HID keyboard and tablet functions fed with virtio-input events read from a socket.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::os::unix::net::UnixStream;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::add_fd_flags;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use linux_input_sys::InputEventDecoder;
+use usb_util::ConfigDescriptor;
+use usb_util::ControlRequestType;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::DeviceSpeed;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use super::finish_config_descriptor;
+use super::push_descriptor;
+use super::UsbFunction;
+
+const VENDOR_ID: u16 = 0x18d1;
+const KEYBOARD_PRODUCT_ID: u16 = 0x5f11;
+const TABLET_PRODUCT_ID: u16 = 0x5f12;
+
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+
+const INTERRUPT_IN_ENDPOINT: u8 = 1;
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+// HID class requests.
+const HID_GET_REPORT: u8 = 0x01;
+const HID_GET_IDLE: u8 = 0x02;
+const HID_GET_PROTOCOL: u8 = 0x03;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_SET_IDLE: u8 = 0x0a;
+const HID_SET_PROTOCOL: u8 = 0x0b;
+
+// Reports not yet read by the guest beyond this limit are dropped.
+const MAX_QUEUED_REPORTS: usize = 64;
+
+// Boot keyboard report descriptor from appendix B.1 of the HID specification.
+const KEYBOARD_REPORT_DESCRIPTOR: [u8; 63] = [
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+];
+
+// Three buttons, 16-bit absolute X and Y in [0, 0x7fff] and a relative wheel.
+const TABLET_REPORT_DESCRIPTOR: [u8; 74] = [
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x03,
+    0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05, 0x81, 0x01,
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x00, 0x26, 0xff, 0x7f, 0x35, 0x00, 0x46, 0xff, 0x7f,
+    0x75, 0x10, 0x95, 0x02, 0x81, 0x02, 0x05, 0x01, 0x09, 0x38, 0x15, 0x81, 0x25, 0x7f, 0x35, 0x00,
+    0x45, 0x00, 0x75, 0x08, 0x95, 0x01, 0x81, 0x06, 0xc0, 0xc0,
+];
+
+const TABLET_MAX_COORDINATE: i32 = 0x7fff;
+
+// Linux key codes of the keyboard page usages 0x00 to 0x65 covered by the boot keyboard.
+const USAGE_TO_KEY: [u8; 0x66] = [
+    0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22,
+    47, 17, 45, 21, 44, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26, 27, 43, 43,
+    39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 87, 88, 99, 70, 119, 110,
+    102, 104, 111, 107, 109, 106, 105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77,
+    71, 72, 73, 82, 83, 86, 127,
+];
+
+// Linux key codes of the modifier usages 0xe0 to 0xe7, in report bit order.
+const MODIFIER_KEYS: [u16; 8] = [
+    KEY_LEFTCTRL,
+    KEY_LEFTSHIFT,
+    KEY_LEFTALT,
+    KEY_LEFTMETA,
+    KEY_RIGHTCTRL,
+    KEY_RIGHTSHIFT,
+    KEY_RIGHTALT,
+    KEY_RIGHTMETA,
+];
+
+fn key_to_usage(code: u16) -> Option<u8> {
+    USAGE_TO_KEY
+        .iter()
+        .skip(4)
+        .position(|&key| key as u16 == code)
+        .map(|i| i as u8 + 4)
+}
+
+/// Kind of HID device to emulate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HidKind {
+    Keyboard,
+    Tablet,
+}
+
+#[derive(Default)]
+struct KeyboardState {
+    modifiers: u8,
+    // Usages of the pressed keys, at most 6 as in the boot protocol.
+    keys: Vec<u8>,
+}
+
+#[derive(Default)]
+struct TabletState {
+    buttons: u8,
+    x: u16,
+    y: u16,
+    wheel: i8,
+}
+
+/// USB HID function translating virtio-input events into HID input reports.
+pub struct Hid {
+    kind: HidKind,
+    source: UnixStream,
+    // Trailing bytes of an incomplete event.
+    partial: Vec<u8>,
+    keyboard: KeyboardState,
+    tablet: TabletState,
+    changed: bool,
+    reports: VecDeque<Vec<u8>>,
+    idle: u8,
+    protocol: u8,
+}
+
+impl Hid {
+    pub fn new(kind: HidKind, source: UnixStream) -> base::Result<Hid> {
+        // UnixStream::set_nonblocking uses an ioctl that is not allowed in the device jail.
+        add_fd_flags(source.as_raw_descriptor(), libc::O_NONBLOCK)?;
+        Ok(Hid {
+            kind,
+            source,
+            partial: Vec::new(),
+            keyboard: KeyboardState::default(),
+            tablet: TabletState::default(),
+            changed: false,
+            reports: VecDeque::new(),
+            idle: 0,
+            protocol: 1,
+        })
+    }
+
+    fn report_descriptor(&self) -> &'static [u8] {
+        match self.kind {
+            HidKind::Keyboard => &KEYBOARD_REPORT_DESCRIPTOR,
+            HidKind::Tablet => &TABLET_REPORT_DESCRIPTOR,
+        }
+    }
+
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let len = (self.report_descriptor().len() as u16).to_le_bytes();
+        // HID 1.11, not localized, one report descriptor.
+        let mut descriptor = [0x11, 0x01, 0x00, 0x01, DESCRIPTOR_TYPE_REPORT, 0, 0];
+        descriptor[5..].copy_from_slice(&len);
+        descriptor
+    }
+
+    fn report(&self) -> Vec<u8> {
+        match self.kind {
+            HidKind::Keyboard => {
+                let mut report = vec![0u8; 8];
+                report[0] = self.keyboard.modifiers;
+                for (i, key) in self.keyboard.keys.iter().enumerate() {
+                    report[2 + i] = *key;
+                }
+                report
+            }
+            HidKind::Tablet => {
+                let x = self.tablet.x.to_le_bytes();
+                let y = self.tablet.y.to_le_bytes();
+                vec![
+                    self.tablet.buttons,
+                    x[0],
+                    x[1],
+                    y[0],
+                    y[1],
+                    self.tablet.wheel as u8,
+                ]
+            }
+        }
+    }
+
+    fn handle_key(&mut self, code: u16, pressed: bool) {
+        match self.kind {
+            HidKind::Keyboard => {
+                if let Some(bit) = MODIFIER_KEYS.iter().position(|&key| key == code) {
+                    if pressed {
+                        self.keyboard.modifiers |= 1 << bit;
+                    } else {
+                        self.keyboard.modifiers &= !(1 << bit);
+                    }
+                } else if let Some(usage) = key_to_usage(code) {
+                    let keys = &mut self.keyboard.keys;
+                    keys.retain(|&k| k != usage);
+                    if pressed && keys.len() < 6 {
+                        keys.push(usage);
+                    }
+                } else {
+                    return;
+                }
+            }
+            HidKind::Tablet => {
+                let bit = match code {
+                    BTN_LEFT | BTN_TOUCH => 0,
+                    BTN_RIGHT => 1,
+                    BTN_MIDDLE => 2,
+                    _ => return,
+                };
+                if pressed {
+                    self.tablet.buttons |= 1 << bit;
+                } else {
+                    self.tablet.buttons &= !(1 << bit);
+                }
+            }
+        }
+        self.changed = true;
+    }
+
+    fn handle_event(&mut self, event: virtio_input_event) {
+        let code = event.code.to_native();
+        let value = event.value.to_native();
+        match event.type_.to_native() {
+            // Key repeats are generated by the guest.
+            EV_KEY if value != 2 => self.handle_key(code, value != 0),
+            EV_ABS if self.kind == HidKind::Tablet => {
+                let value = value.clamp(0, TABLET_MAX_COORDINATE) as u16;
+                match code {
+                    ABS_X => self.tablet.x = value,
+                    ABS_Y => self.tablet.y = value,
+                    _ => return,
+                }
+                self.changed = true;
+            }
+            EV_REL if self.kind == HidKind::Tablet && code == REL_WHEEL => {
+                self.tablet.wheel =
+                    (self.tablet.wheel as i32 + value).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+                self.changed = true;
+            }
+            EV_SYN if code == SYN_REPORT && self.changed => {
+                if self.reports.len() == MAX_QUEUED_REPORTS {
+                    self.reports.pop_front();
+                }
+                self.reports.push_back(self.report());
+                self.tablet.wheel = 0;
+                self.changed = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl UsbFunction for Hid {
+    fn descriptors(&self) -> Vec<u8> {
+        let (product_id, subclass, protocol, max_packet_size) = match self.kind {
+            // Boot interface subclass, keyboard protocol.
+            HidKind::Keyboard => (KEYBOARD_PRODUCT_ID, 1, 1, 8),
+            HidKind::Tablet => (TABLET_PRODUCT_ID, 0, 0, 6),
+        };
+        let mut buf = Vec::new();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Device as u8,
+            &DeviceDescriptor {
+                bcdUSB: 0x0200,
+                bMaxPacketSize0: 64,
+                idVendor: VENDOR_ID,
+                idProduct: product_id,
+                bcdDevice: 0x0100,
+                iManufacturer: STRING_MANUFACTURER,
+                iProduct: STRING_PRODUCT,
+                bNumConfigurations: 1,
+                ..Default::default()
+            },
+        );
+        let config_offset = buf.len();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Configuration as u8,
+            &ConfigDescriptor {
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                // Bus powered, remote wakeup.
+                bmAttributes: 0xa0,
+                bMaxPower: 50,
+                ..Default::default()
+            },
+        );
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Interface as u8,
+            &InterfaceDescriptor {
+                bNumEndpoints: 1,
+                bInterfaceClass: 0x03,
+                bInterfaceSubClass: subclass,
+                bInterfaceProtocol: protocol,
+                ..Default::default()
+            },
+        );
+        push_descriptor(&mut buf, DESCRIPTOR_TYPE_HID, &self.hid_descriptor());
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Endpoint as u8,
+            &EndpointDescriptor {
+                bEndpointAddress: 0x80 | INTERRUPT_IN_ENDPOINT,
+                bmAttributes: 0x03,
+                wMaxPacketSize: max_packet_size,
+                bInterval: 10,
+            },
+        );
+        finish_config_descriptor(&mut buf, config_offset);
+        buf
+    }
+
+    fn string(&self, index: u8) -> Option<String> {
+        match (index, self.kind) {
+            (STRING_MANUFACTURER, _) => Some("crosvm".to_string()),
+            (STRING_PRODUCT, HidKind::Keyboard) => Some("USB keyboard".to_string()),
+            (STRING_PRODUCT, HidKind::Tablet) => Some("USB tablet".to_string()),
+            _ => None,
+        }
+    }
+
+    fn speed(&self) -> DeviceSpeed {
+        DeviceSpeed::Full
+    }
+
+    fn control(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> (TransferStatus, usize) {
+        let response: Vec<u8> = match (setup.get_type(), setup.request) {
+            (ControlRequestType::Standard, request)
+                if request == StandardControlRequest::GetDescriptor as u8 =>
+            {
+                match (setup.value >> 8) as u8 {
+                    DESCRIPTOR_TYPE_HID => self.hid_descriptor().to_vec(),
+                    DESCRIPTOR_TYPE_REPORT => self.report_descriptor().to_vec(),
+                    _ => return (TransferStatus::Stalled, 0),
+                }
+            }
+            (ControlRequestType::Class, HID_GET_REPORT) => self.report(),
+            (ControlRequestType::Class, HID_GET_IDLE) => vec![self.idle],
+            (ControlRequestType::Class, HID_GET_PROTOCOL) => vec![self.protocol],
+            // Keyboard LED output reports are ignored.
+            (ControlRequestType::Class, HID_SET_REPORT) => Vec::new(),
+            (ControlRequestType::Class, HID_SET_IDLE) => {
+                self.idle = (setup.value >> 8) as u8;
+                Vec::new()
+            }
+            (ControlRequestType::Class, HID_SET_PROTOCOL) => {
+                self.protocol = setup.value as u8;
+                Vec::new()
+            }
+            _ => return (TransferStatus::Stalled, 0),
+        };
+        let len = response.len().min(data.len());
+        data[..len].copy_from_slice(&response[..len]);
+        (TransferStatus::Completed, len)
+    }
+
+    fn data_out(&mut self, _endpoint: u8, _data: &[u8]) -> TransferStatus {
+        TransferStatus::Stalled
+    }
+
+    fn data_in(&mut self, endpoint: u8, data: &mut [u8]) -> Option<(TransferStatus, usize)> {
+        if endpoint != INTERRUPT_IN_ENDPOINT {
+            return Some((TransferStatus::Stalled, 0));
+        }
+        let report = self.reports.pop_front()?;
+        let len = report.len().min(data.len());
+        data[..len].copy_from_slice(&report[..len]);
+        Some((TransferStatus::Completed, len))
+    }
+
+    fn reset(&mut self) {
+        self.reports.clear();
+        self.idle = 0;
+        self.protocol = 1;
+    }
+
+    fn event_descriptor(&self) -> Option<RawDescriptor> {
+        Some(self.source.as_raw_descriptor())
+    }
+
+    fn on_event(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = match self.source.read(&mut buf) {
+                Ok(0) => bail!("input event source closed"),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read input events"),
+            };
+            self.partial.extend_from_slice(&buf[..len]);
+            let complete = self.partial.len() - self.partial.len() % virtio_input_event::SIZE;
+            let events: Vec<virtio_input_event> = self.partial[..complete]
+                .chunks(virtio_input_event::SIZE)
+                .map(virtio_input_event::decode)
+                .collect();
+            self.partial.drain(..complete);
+            for event in events {
+                self.handle_event(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use usb_util::parse_usbfs_descriptors;
+    use zerocopy::AsBytes;
+
+    use super::*;
+
+    fn send(stream: &mut UnixStream, events: &[virtio_input_event]) {
+        for event in events {
+            stream.write_all(event.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn keyboard_reports() {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let mut hid = Hid::new(HidKind::Keyboard, reader).unwrap();
+        let mut report = [0u8; 8];
+        assert!(hid.data_in(INTERRUPT_IN_ENDPOINT, &mut report).is_none());
+
+        send(
+            &mut writer,
+            &[
+                virtio_input_event::key(KEY_LEFTSHIFT, true),
+                virtio_input_event::key(KEY_A, true),
+                virtio_input_event::syn(),
+                virtio_input_event::key(KEY_A, false),
+                virtio_input_event::syn(),
+            ],
+        );
+        hid.on_event().unwrap();
+
+        hid.data_in(INTERRUPT_IN_ENDPOINT, &mut report).unwrap();
+        assert_eq!(report, [0x02, 0, 0x04, 0, 0, 0, 0, 0]);
+        hid.data_in(INTERRUPT_IN_ENDPOINT, &mut report).unwrap();
+        assert_eq!(report, [0x02, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(hid.data_in(INTERRUPT_IN_ENDPOINT, &mut report).is_none());
+    }
+
+    #[test]
+    fn tablet_reports() {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let mut hid = Hid::new(HidKind::Tablet, reader).unwrap();
+        send(
+            &mut writer,
+            &[
+                virtio_input_event::absolute(ABS_X, 0x1234),
+                virtio_input_event::absolute(ABS_Y, 0x10000),
+                virtio_input_event::key(BTN_LEFT, true),
+                virtio_input_event::syn(),
+            ],
+        );
+        hid.on_event().unwrap();
+
+        let mut report = [0u8; 6];
+        hid.data_in(INTERRUPT_IN_ENDPOINT, &mut report).unwrap();
+        assert_eq!(report, [0x01, 0x34, 0x12, 0xff, 0x7f, 0]);
+    }
+
+    #[test]
+    fn source_closed() {
+        let (writer, reader) = UnixStream::pair().unwrap();
+        let mut hid = Hid::new(HidKind::Keyboard, reader).unwrap();
+        drop(writer);
+        assert!(hid.on_event().is_err());
+    }
+
+    #[test]
+    fn descriptors_parse() {
+        let (_writer, reader) = UnixStream::pair().unwrap();
+        let hid = Hid::new(HidKind::Keyboard, reader).unwrap();
+        let tree = parse_usbfs_descriptors(&hid.descriptors()).unwrap();
+        let interface = tree
+            .get_config_descriptor(1)
+            .unwrap()
+            .get_interface_descriptor(0, 0)
+            .unwrap();
+        let endpoint = interface.get_endpoint_descriptor(0).unwrap();
+        assert_eq!(endpoint.bEndpointAddress, 0x81);
+    }
+}
@@ -0,0 +1,678 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB mass storage function implementing the Bulk-Only Transport with a minimal SCSI
+//! direct-access block device on top of a disk image.
+
+use std::io;
+
+use base::error;
+use data_model::VolatileSlice;
+use disk::DiskFile;
+use usb_util::ConfigDescriptor;
+use usb_util::ControlRequestType;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::DeviceSpeed;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use super::finish_config_descriptor;
+use super::push_descriptor;
+use super::UsbFunction;
+
+const VENDOR_ID: u16 = 0x18d1;
+const PRODUCT_ID: u16 = 0x5f10;
+
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+const STRING_SERIAL: u8 = 3;
+
+const BULK_IN_ENDPOINT: u8 = 1;
+const BULK_OUT_ENDPOINT: u8 = 2;
+const BULK_MAX_PACKET_SIZE: u16 = 512;
+
+// Class requests of the Bulk-Only Transport.
+const BOT_GET_MAX_LUN: u8 = 0xfe;
+const BOT_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CBW_FLAGS_DATA_IN: u8 = 0x80;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+const BLOCK_SIZE: u64 = 512;
+// Largest amount of data read from the disk at once.
+const MAX_READ_LEN: usize = 64 * 1024;
+
+// SCSI operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const READ_CAPACITY_16: u8 = 0x10;
+
+/// SCSI sense data reported by REQUEST SENSE after a failed command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+const SENSE_NONE: Sense = Sense {
+    key: 0x00,
+    asc: 0x00,
+};
+const SENSE_READ_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x11,
+};
+const SENSE_WRITE_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x0c,
+};
+const SENSE_INVALID_OPCODE: Sense = Sense {
+    key: 0x05,
+    asc: 0x20,
+};
+const SENSE_LBA_OUT_OF_RANGE: Sense = Sense {
+    key: 0x05,
+    asc: 0x21,
+};
+const SENSE_INVALID_FIELD: Sense = Sense {
+    key: 0x05,
+    asc: 0x24,
+};
+const SENSE_WRITE_PROTECTED: Sense = Sense {
+    key: 0x07,
+    asc: 0x27,
+};
+
+/// Data phase of a SCSI command.
+enum ScsiData {
+    None,
+    In(Vec<u8>),
+    Read { offset: u64, len: usize },
+    Out { offset: u64, len: usize },
+}
+
+enum BotState {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Sending the data of the current command.
+    DataIn { data: Vec<u8>, pos: usize },
+    /// Sending `remaining` bytes read from the disk at `offset`.
+    ReadIn { offset: u64, remaining: usize },
+    /// Receiving the data of the current command, written to the disk at `offset` until
+    /// `write_len` bytes have been received and discarded after that.
+    DataOut {
+        offset: u64,
+        write_len: usize,
+        remaining: usize,
+    },
+    /// Waiting to send the command status wrapper.
+    Status,
+}
+
+/// USB mass storage device exposing a disk image as a single logical unit.
+pub struct MassStorage {
+    disk: Box<dyn DiskFile>,
+    read_only: bool,
+    block_count: u64,
+    state: BotState,
+    tag: u32,
+    residue: u32,
+    status: u8,
+    sense: Sense,
+}
+
+impl MassStorage {
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> io::Result<MassStorage> {
+        let block_count = disk.get_len()? / BLOCK_SIZE;
+        Ok(MassStorage {
+            disk,
+            read_only,
+            block_count,
+            state: BotState::Command,
+            tag: 0,
+            residue: 0,
+            status: CSW_STATUS_PASSED,
+            sense: SENSE_NONE,
+        })
+    }
+
+    // Returns the byte offset and length of `blocks` blocks starting at `lba`.
+    fn block_range(&self, lba: u64, blocks: u64) -> Result<(u64, usize), Sense> {
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.block_count => {
+                Ok((lba * BLOCK_SIZE, (blocks * BLOCK_SIZE) as usize))
+            }
+            _ => Err(SENSE_LBA_OUT_OF_RANGE),
+        }
+    }
+
+    fn read(&self, lba: u64, blocks: u64) -> Result<ScsiData, Sense> {
+        let (offset, len) = self.block_range(lba, blocks)?;
+        Ok(ScsiData::Read { offset, len })
+    }
+
+    fn write(&mut self, lba: u64, blocks: u64) -> Result<ScsiData, Sense> {
+        if self.read_only {
+            return Err(SENSE_WRITE_PROTECTED);
+        }
+        let (offset, len) = self.block_range(lba, blocks)?;
+        Ok(ScsiData::Out { offset, len })
+    }
+
+    fn inquiry(&self, cb: &[u8]) -> Result<ScsiData, Sense> {
+        let evpd = cb[1] & 1 != 0;
+        let data = if evpd {
+            match cb[2] {
+                // Supported vital product data pages.
+                0x00 => vec![0, 0x00, 0, 1, 0x00],
+                _ => return Err(SENSE_INVALID_FIELD),
+            }
+        } else {
+            let mut data = vec![
+                0x00, // Direct access block device.
+                0x80, // Removable medium.
+                0x06, // SPC-4.
+                0x02, // Response data format.
+                31,   // Additional length.
+                0, 0, 0,
+            ];
+            data.extend_from_slice(b"CROSVM  ");
+            data.extend_from_slice(b"USB DISK        ");
+            data.extend_from_slice(b"1.0 ");
+            data
+        };
+        Ok(ScsiData::In(data))
+    }
+
+    fn mode_sense(&self, ten: bool) -> ScsiData {
+        let device_specific = if self.read_only { 0x80 } else { 0 };
+        // Mode parameter header without any block descriptor or mode page.
+        ScsiData::In(if ten {
+            vec![0, 6, 0, device_specific, 0, 0, 0, 0]
+        } else {
+            vec![3, 0, device_specific, 0]
+        })
+    }
+
+    fn read_capacity(&self, sixteen: bool) -> ScsiData {
+        let last_lba = self.block_count.saturating_sub(1);
+        let mut data = Vec::new();
+        if sixteen {
+            data.extend_from_slice(&last_lba.to_be_bytes());
+            data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+            data.resize(32, 0);
+        } else {
+            let last_lba = u32::try_from(last_lba).unwrap_or(u32::MAX);
+            data.extend_from_slice(&last_lba.to_be_bytes());
+            data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+        }
+        ScsiData::In(data)
+    }
+
+    fn read_format_capacities(&self) -> ScsiData {
+        let blocks = u32::try_from(self.block_count).unwrap_or(u32::MAX);
+        let mut data = vec![0, 0, 0, 8];
+        data.extend_from_slice(&blocks.to_be_bytes());
+        // Formatted media, followed by the 24-bit block length.
+        data.push(0x02);
+        data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+        ScsiData::In(data)
+    }
+
+    fn request_sense(&mut self) -> ScsiData {
+        let sense = std::mem::take(&mut self.sense);
+        let mut data = vec![0u8; 18];
+        data[0] = 0x70; // Current error, fixed format.
+        data[2] = sense.key;
+        data[7] = 10; // Additional sense length.
+        data[12] = sense.asc;
+        ScsiData::In(data)
+    }
+
+    fn execute(&mut self, cb: &[u8; 16]) -> Result<ScsiData, Sense> {
+        let be16 = |i: usize| u16::from_be_bytes([cb[i], cb[i + 1]]) as u64;
+        let be32 = |i: usize| u32::from_be_bytes([cb[i], cb[i + 1], cb[i + 2], cb[i + 3]]) as u64;
+        let be64 = |i: usize| (be32(i) << 32) | be32(i + 4);
+        match cb[0] {
+            TEST_UNIT_READY
+            | START_STOP_UNIT
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | VERIFY_10
+            // Writes are not cached by the device.
+            | SYNCHRONIZE_CACHE_10 => Ok(ScsiData::None),
+            REQUEST_SENSE => Ok(self.request_sense()),
+            INQUIRY => self.inquiry(cb),
+            MODE_SENSE_6 => Ok(self.mode_sense(false)),
+            MODE_SENSE_10 => Ok(self.mode_sense(true)),
+            READ_FORMAT_CAPACITIES => Ok(self.read_format_capacities()),
+            READ_CAPACITY_10 => Ok(self.read_capacity(false)),
+            SERVICE_ACTION_IN_16 if cb[1] & 0x1f == READ_CAPACITY_16 => {
+                Ok(self.read_capacity(true))
+            }
+            READ_10 => self.read(be32(2), be16(7)),
+            READ_16 => self.read(be64(2), be32(10)),
+            WRITE_10 => self.write(be32(2), be16(7)),
+            WRITE_16 => self.write(be64(2), be32(10)),
+            _ => Err(SENSE_INVALID_OPCODE),
+        }
+    }
+
+    fn handle_cbw(&mut self, cbw: &[u8]) -> TransferStatus {
+        if cbw.len() != CBW_LEN
+            || u32::from_le_bytes([cbw[0], cbw[1], cbw[2], cbw[3]]) != CBW_SIGNATURE
+        {
+            error!("usb mass storage: invalid command block wrapper");
+            return TransferStatus::Stalled;
+        }
+        self.tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+        let data_len = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]);
+        let data_in = cbw[12] & CBW_FLAGS_DATA_IN != 0;
+        let mut cb = [0u8; 16];
+        cb.copy_from_slice(&cbw[15..31]);
+
+        self.residue = data_len;
+        self.status = CSW_STATUS_PASSED;
+        let data = match self.execute(&cb) {
+            Ok(data) => data,
+            Err(sense) => {
+                self.sense = sense;
+                self.status = CSW_STATUS_FAILED;
+                ScsiData::None
+            }
+        };
+
+        self.state = if data_len == 0 {
+            BotState::Status
+        } else if data_in {
+            match data {
+                ScsiData::In(mut data) => {
+                    data.truncate(data_len as usize);
+                    BotState::DataIn { data, pos: 0 }
+                }
+                ScsiData::Read { offset, len } => BotState::ReadIn {
+                    offset,
+                    remaining: len.min(data_len as usize),
+                },
+                _ => BotState::DataIn {
+                    data: Vec::new(),
+                    pos: 0,
+                },
+            }
+        } else {
+            let (offset, write_len) = match data {
+                ScsiData::Out { offset, len } => (offset, len.min(data_len as usize)),
+                _ => (0, 0),
+            };
+            BotState::DataOut {
+                offset,
+                write_len,
+                remaining: data_len as usize,
+            }
+        };
+        TransferStatus::Completed
+    }
+
+    fn handle_data_out(&mut self, data: &[u8]) -> TransferStatus {
+        let (offset, write_len, remaining) = match &mut self.state {
+            BotState::DataOut {
+                offset,
+                write_len,
+                remaining,
+            } => (offset, write_len, remaining),
+            _ => return TransferStatus::Stalled,
+        };
+        let len = data.len().min(*remaining);
+        let to_write = len.min(*write_len);
+        if to_write > 0 {
+            let mut buf = data[..to_write].to_vec();
+            if let Err(e) = self
+                .disk
+                .write_all_at_volatile(VolatileSlice::new(&mut buf), *offset)
+            {
+                error!("usb mass storage: failed to write disk: {}", e);
+                self.sense = SENSE_WRITE_ERROR;
+                self.status = CSW_STATUS_FAILED;
+                *write_len = 0;
+            } else {
+                *offset += to_write as u64;
+                *write_len -= to_write;
+            }
+        }
+        *remaining -= len;
+        self.residue -= len as u32;
+        if *remaining == 0 {
+            self.state = BotState::Status;
+        }
+        TransferStatus::Completed
+    }
+
+    // Fills `buf` with the data of the current read command, reading at most `MAX_READ_LEN` bytes
+    // from the disk at once. Returns the number of bytes filled.
+    fn handle_read_in(&mut self, buf: &mut [u8]) -> usize {
+        let (offset, remaining) = match &mut self.state {
+            BotState::ReadIn { offset, remaining } => (offset, remaining),
+            _ => return 0,
+        };
+        let len = buf.len().min(*remaining);
+        let mut filled = 0;
+        let mut failed = false;
+        for chunk in buf[..len].chunks_mut(MAX_READ_LEN) {
+            if let Err(e) = self
+                .disk
+                .read_exact_at_volatile(VolatileSlice::new(chunk), *offset)
+            {
+                error!("usb mass storage: failed to read disk: {}", e);
+                failed = true;
+                break;
+            }
+            *offset += chunk.len() as u64;
+            filled += chunk.len();
+        }
+        *remaining -= filled;
+        self.residue -= filled as u32;
+        // A short transfer ends the data phase.
+        if failed {
+            self.sense = SENSE_READ_ERROR;
+            self.status = CSW_STATUS_FAILED;
+            self.state = BotState::Status;
+        } else if *remaining == 0 {
+            self.state = BotState::Status;
+        }
+        filled
+    }
+
+    fn csw(&self) -> [u8; CSW_LEN] {
+        let mut csw = [0u8; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&self.residue.to_le_bytes());
+        csw[12] = self.status;
+        csw
+    }
+}
+
+impl UsbFunction for MassStorage {
+    fn descriptors(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Device as u8,
+            &DeviceDescriptor {
+                bcdUSB: 0x0200,
+                bMaxPacketSize0: 64,
+                idVendor: VENDOR_ID,
+                idProduct: PRODUCT_ID,
+                bcdDevice: 0x0100,
+                iManufacturer: STRING_MANUFACTURER,
+                iProduct: STRING_PRODUCT,
+                iSerialNumber: STRING_SERIAL,
+                bNumConfigurations: 1,
+                ..Default::default()
+            },
+        );
+        let config_offset = buf.len();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Configuration as u8,
+            &ConfigDescriptor {
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                // Bus powered.
+                bmAttributes: 0x80,
+                bMaxPower: 50,
+                ..Default::default()
+            },
+        );
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Interface as u8,
+            &InterfaceDescriptor {
+                bNumEndpoints: 2,
+                // Mass storage, SCSI transparent command set, Bulk-Only Transport.
+                bInterfaceClass: 0x08,
+                bInterfaceSubClass: 0x06,
+                bInterfaceProtocol: 0x50,
+                ..Default::default()
+            },
+        );
+        for address in [0x80 | BULK_IN_ENDPOINT, BULK_OUT_ENDPOINT] {
+            push_descriptor(
+                &mut buf,
+                DescriptorType::Endpoint as u8,
+                &EndpointDescriptor {
+                    bEndpointAddress: address,
+                    bmAttributes: 0x02,
+                    wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+                    bInterval: 0,
+                },
+            );
+        }
+        finish_config_descriptor(&mut buf, config_offset);
+        buf
+    }
+
+    fn string(&self, index: u8) -> Option<String> {
+        match index {
+            STRING_MANUFACTURER => Some("crosvm".to_string()),
+            STRING_PRODUCT => Some("USB mass storage".to_string()),
+            // Serial numbers of mass storage devices must be at least 12 hexadecimal digits.
+            STRING_SERIAL => Some("000000000001".to_string()),
+            _ => None,
+        }
+    }
+
+    fn speed(&self) -> DeviceSpeed {
+        DeviceSpeed::High
+    }
+
+    fn control(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> (TransferStatus, usize) {
+        if setup.get_type() != ControlRequestType::Class {
+            return (TransferStatus::Stalled, 0);
+        }
+        match setup.request {
+            BOT_RESET => {
+                self.state = BotState::Command;
+                (TransferStatus::Completed, 0)
+            }
+            BOT_GET_MAX_LUN if !data.is_empty() => {
+                data[0] = 0;
+                (TransferStatus::Completed, 1)
+            }
+            _ => (TransferStatus::Stalled, 0),
+        }
+    }
+
+    fn data_out(&mut self, endpoint: u8, data: &[u8]) -> TransferStatus {
+        if endpoint != BULK_OUT_ENDPOINT {
+            return TransferStatus::Stalled;
+        }
+        match self.state {
+            BotState::Command => self.handle_cbw(data),
+            _ => self.handle_data_out(data),
+        }
+    }
+
+    fn data_in(&mut self, endpoint: u8, buf: &mut [u8]) -> Option<(TransferStatus, usize)> {
+        if endpoint != BULK_IN_ENDPOINT {
+            return Some((TransferStatus::Stalled, 0));
+        }
+        match &mut self.state {
+            BotState::Command => None,
+            BotState::DataIn { data, pos } => {
+                let len = (data.len() - *pos).min(buf.len());
+                buf[..len].copy_from_slice(&data[*pos..*pos + len]);
+                *pos += len;
+                self.residue -= len as u32;
+                // A short transfer ends the data phase even if less than requested was sent.
+                if *pos == data.len() {
+                    self.state = BotState::Status;
+                }
+                Some((TransferStatus::Completed, len))
+            }
+            BotState::ReadIn { .. } => Some((TransferStatus::Completed, self.handle_read_in(buf))),
+            BotState::DataOut { .. } => Some((TransferStatus::Stalled, 0)),
+            BotState::Status => {
+                let csw = self.csw();
+                let len = csw.len().min(buf.len());
+                buf[..len].copy_from_slice(&csw[..len]);
+                self.state = BotState::Command;
+                Some((TransferStatus::Completed, len))
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = BotState::Command;
+        self.sense = SENSE_NONE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn cbw(tag: u32, data_len: u32, data_in: bool, cb: &[u8]) -> Vec<u8> {
+        let mut cbw = vec![0u8; CBW_LEN];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&data_len.to_le_bytes());
+        cbw[12] = if data_in { CBW_FLAGS_DATA_IN } else { 0 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        cbw
+    }
+
+    fn read_csw(storage: &mut MassStorage) -> (u32, u32, u8) {
+        let mut buf = [0u8; CSW_LEN];
+        let (status, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert!(status == TransferStatus::Completed);
+        assert_eq!(len, CSW_LEN);
+        assert_eq!(buf[0..4], CSW_SIGNATURE.to_le_bytes());
+        (
+            u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            buf[12],
+        )
+    }
+
+    fn storage(blocks: u64, read_only: bool) -> MassStorage {
+        let file = tempfile().unwrap();
+        file.set_len(blocks * BLOCK_SIZE).unwrap();
+        MassStorage::new(Box::new(file), read_only).unwrap()
+    }
+
+    #[test]
+    fn read_capacity() {
+        let mut storage = storage(8, false);
+        assert!(
+            storage.data_out(
+                BULK_OUT_ENDPOINT,
+                &cbw(7, 8, true, &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            ) == TransferStatus::Completed
+        );
+        let mut buf = [0u8; 8];
+        let (_, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(buf, [0, 0, 0, 7, 0, 0, 2, 0]);
+        assert_eq!(read_csw(&mut storage), (7, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut storage = storage(8, false);
+        let write = [WRITE_10, 0, 0, 0, 0, 2, 0, 0, 1, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(1, 512, false, &write));
+        storage.data_out(BULK_OUT_ENDPOINT, &[0xab; 512]);
+        assert_eq!(read_csw(&mut storage), (1, 0, CSW_STATUS_PASSED));
+
+        let read = [READ_10, 0, 0, 0, 0, 2, 0, 0, 1, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(2, 512, true, &read));
+        let mut buf = [0u8; 512];
+        let (_, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(len, 512);
+        assert!(buf.iter().all(|&b| b == 0xab));
+        assert_eq!(read_csw(&mut storage), (2, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn read_in_several_transfers() {
+        let mut storage = storage(8, false);
+        let write = [WRITE_10, 0, 0, 0, 0, 0, 0, 0, 8, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(1, 4096, false, &write));
+        let data: Vec<u8> = (0..4096).map(|i| (i / 512) as u8).collect();
+        storage.data_out(BULK_OUT_ENDPOINT, &data);
+        assert_eq!(read_csw(&mut storage), (1, 0, CSW_STATUS_PASSED));
+
+        let read = [READ_10, 0, 0, 0, 0, 0, 0, 0, 8, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(2, 4096, true, &read));
+        let mut buf = vec![0u8; 3072];
+        let (_, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(len, 3072);
+        assert_eq!(buf, data[..3072]);
+        let (_, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(len, 1024);
+        assert_eq!(buf[..1024], data[3072..]);
+        assert_eq!(read_csw(&mut storage), (2, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn write_protected() {
+        let mut storage = storage(8, true);
+        let write = [WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(3, 512, false, &write));
+        storage.data_out(BULK_OUT_ENDPOINT, &[0xab; 512]);
+        assert_eq!(read_csw(&mut storage), (3, 0, CSW_STATUS_FAILED));
+
+        storage.data_out(
+            BULK_OUT_ENDPOINT,
+            &cbw(4, 18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]),
+        );
+        let mut buf = [0u8; 18];
+        storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(buf[2], SENSE_WRITE_PROTECTED.key);
+        assert_eq!(buf[12], SENSE_WRITE_PROTECTED.asc);
+    }
+
+    #[test]
+    fn out_of_range_read_reports_residue() {
+        let mut storage = storage(8, false);
+        let read = [READ_10, 0, 0, 0, 0, 8, 0, 0, 1, 0];
+        storage.data_out(BULK_OUT_ENDPOINT, &cbw(5, 512, true, &read));
+        let mut buf = [0u8; 512];
+        let (_, len) = storage.data_in(BULK_IN_ENDPOINT, &mut buf).unwrap();
+        assert_eq!(len, 0);
+        assert_eq!(read_csw(&mut storage), (5, 512, CSW_STATUS_FAILED));
+    }
+
+    #[test]
+    fn status_waits_for_command() {
+        let mut storage = storage(8, false);
+        let mut buf = [0u8; CSW_LEN];
+        assert!(storage.data_in(BULK_IN_ENDPOINT, &mut buf).is_none());
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB devices emulated by crosvm instead of being passed through from the host.
+//!
+//! An emulated device is split in two parts: `EmulatedDevice` implements the xHCI facing side,
+//! the standard control requests and the data endpoints, while a `UsbFunction` implements the
+//! class specific behavior of the device.
+
+pub mod emulated_device;
+pub mod hid;
+pub mod mass_storage;
+
+use std::mem::size_of;
+
+use base::RawDescriptor;
+use usb_util::DescriptorHeader;
+use usb_util::DeviceSpeed;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use zerocopy::AsBytes;
+
+/// Descriptor type of string descriptors.
+pub const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+
+/// Class specific behavior of an emulated USB device.
+pub trait UsbFunction: Send {
+    /// Returns the device descriptor followed by the configuration descriptors, laid out like
+    /// the usbfs `descriptors` file.
+    fn descriptors(&self) -> Vec<u8>;
+    /// Returns the string referenced by string descriptor `index`, if any.
+    fn string(&self, index: u8) -> Option<String>;
+    /// Returns the speed at which the device is operating.
+    fn speed(&self) -> DeviceSpeed;
+    /// Handles a control request that is not a standard device request. Device to host responses
+    /// are written to `data` and the number of bytes written is returned.
+    fn control(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> (TransferStatus, usize);
+    /// Consumes data sent by the host to OUT endpoint `endpoint`.
+    fn data_out(&mut self, endpoint: u8, data: &[u8]) -> TransferStatus;
+    /// Fills `data` for a transfer on IN endpoint `endpoint`. Returns `None` if the function has
+    /// nothing to send yet, in which case the transfer stays pending and is retried later.
+    fn data_in(&mut self, endpoint: u8, data: &mut [u8]) -> Option<(TransferStatus, usize)>;
+    /// Returns the function to its initial state.
+    fn reset(&mut self);
+    /// Returns a descriptor to poll for readability on behalf of the function, if it needs one.
+    fn event_descriptor(&self) -> Option<RawDescriptor> {
+        None
+    }
+    /// Called when the descriptor returned by `event_descriptor` is readable.
+    fn on_event(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends `descriptor` to `buf`, preceded by the standard descriptor header.
+pub fn push_descriptor<T: AsBytes>(buf: &mut Vec<u8>, descriptor_type: u8, descriptor: &T) {
+    let header = DescriptorHeader {
+        bLength: (size_of::<DescriptorHeader>() + size_of::<T>()) as u8,
+        bDescriptorType: descriptor_type,
+    };
+    buf.extend_from_slice(header.as_bytes());
+    buf.extend_from_slice(descriptor.as_bytes());
+}
+
+/// Sets the wTotalLength field of the configuration descriptor starting at `config_offset`, once
+/// all of its interface, class and endpoint descriptors have been appended to `buf`.
+pub fn finish_config_descriptor(buf: &mut [u8], config_offset: usize) {
+    let total_length = (buf.len() - config_offset) as u16;
+    let start = config_offset + size_of::<DescriptorHeader>();
+    buf[start..start + 2].copy_from_slice(&total_length.to_le_bytes());
+}
+
+/// Encodes `s` as a string descriptor.
+pub fn string_descriptor(s: &str) -> Vec<u8> {
+    let mut buf = vec![0, DESCRIPTOR_TYPE_STRING];
+    for c in s.encode_utf16().take(126) {
+        buf.extend_from_slice(&c.to_le_bytes());
+    }
+    buf[0] = buf.len() as u8;
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use usb_util::parse_usbfs_descriptors;
+    use usb_util::ConfigDescriptor;
+    use usb_util::DescriptorType;
+    use usb_util::DeviceDescriptor;
+
+    use super::*;
+
+    #[test]
+    fn built_descriptors_parse() {
+        let mut buf = Vec::new();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Device as u8,
+            &DeviceDescriptor {
+                bcdUSB: 0x0200,
+                bMaxPacketSize0: 64,
+                bNumConfigurations: 1,
+                ..Default::default()
+            },
+        );
+        let config_offset = buf.len();
+        push_descriptor(
+            &mut buf,
+            DescriptorType::Configuration as u8,
+            &ConfigDescriptor {
+                bConfigurationValue: 1,
+                ..Default::default()
+            },
+        );
+        finish_config_descriptor(&mut buf, config_offset);
+
+        let tree = parse_usbfs_descriptors(&buf).unwrap();
+        let config = tree.get_config_descriptor(1).unwrap();
+        assert_eq!({ config.wTotalLength }, 9);
+    }
+
+    #[test]
+    fn string_descriptor_encoding() {
+        assert_eq!(string_descriptor("ab"), vec![6, 3, b'a', 0, b'b', 0]);
+    }
+}
@@ -57,6 +57,8 @@ pub enum Error {
     GetInterfaceDescriptor(u8, u8),
     #[error("failed to get xhci transfer type: {0}")]
     GetXhciTransferType(XhciTransferError),
+    #[error("no descriptor for configuration {0}")]
+    MissingConfigDescriptor(u8),
     #[error("request missing required data buffer")]
     MissingRequiredBuffer,
     #[error("failed to queue async job: {0}")]
@@ -83,6 +85,24 @@ pub enum Error {
     TransferComplete(XhciTransferError),
     #[error("failed to cancel transfer: {0}")]
     TransferHandle(UsbUtilError),
+    #[error("USB/IP server closed the connection")]
+    UsbipDisconnected,
+    #[error("USB/IP server failed to get descriptor: status {0}")]
+    UsbipGetDescriptor(i32),
+    #[error("failed to import USB/IP device: {0}")]
+    UsbipImport(UsbUtilError),
+    #[error("USB/IP server returned {0} bytes for a shorter transfer")]
+    UsbipInvalidLength(i32),
+    #[error("failed to read from USB/IP server: {0}")]
+    UsbipRead(std::io::Error),
+    #[error("invalid USB/IP reply: {0}")]
+    UsbipReply(UsbUtilError),
+    #[error("failed to set up USB/IP connection: {0}")]
+    UsbipSetup(std::io::Error),
+    #[error("USB/IP reply for unknown transfer {0}")]
+    UsbipUnknownSeqnum(u32),
+    #[error("failed to write to USB/IP server: {0}")]
+    UsbipWrite(std::io::Error),
     #[error("failed to write buffer: {0}")]
     WriteBuffer(BufferError),
     #[error("failed to write control tube: {0}")]
@@ -4,8 +4,10 @@
 
 pub mod device;
 pub mod device_provider;
+pub mod emulated;
 pub mod endpoint;
 pub mod error;
 pub mod host_backend;
 pub mod transfer;
+pub mod usbip_backend;
 pub mod utils;
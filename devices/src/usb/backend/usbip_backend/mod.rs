@@ -0,0 +1,8 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Devices imported from a USB/IP server, such as the Linux usbipd.
+
+pub mod usbip_connection;
+pub mod usbip_device;
@@ -0,0 +1,441 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::time::Duration;
+
+use anyhow::Context;
+use base::AsRawDescriptor;
+use base::EventType;
+use libc::c_void;
+use libc::ECONNRESET;
+use libc::ENODEV;
+use libc::ENOENT;
+use libc::EPIPE;
+use libc::ESHUTDOWN;
+use libc::MSG_DONTWAIT;
+use sync::Mutex;
+use usb_util::parse_usbfs_descriptors;
+use usb_util::usbip;
+use usb_util::usbip::CmdSubmit;
+use usb_util::usbip::CmdUnlink;
+use usb_util::usbip::Reply;
+use usb_util::usbip::RetSubmit;
+use usb_util::usbip::RetUnlink;
+use usb_util::usbip::UsbipUsbDevice;
+use usb_util::usbip::USBIP_DIR_IN;
+use usb_util::usbip::USBIP_DIR_OUT;
+use usb_util::ConfigDescriptor;
+use usb_util::DescriptorHeader;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::DeviceDescriptorTree;
+use usb_util::StandardControlRequest;
+use usb_util::Transfer;
+use usb_util::TransferBuffer;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+
+use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::error::*;
+use crate::usb::backend::transfer::BackendTransferHandle;
+use crate::usb::backend::transfer::GenericTransferHandle;
+use crate::utils::EventHandler;
+use crate::utils::EventLoop;
+
+// Time allowed for each reply of the server while the device is being imported.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SETUP_SIZE: usize = size_of::<UsbRequestSetup>();
+const REPLY_HEADER_SIZE: usize = size_of::<RetSubmit>();
+
+type Completion = (Transfer, TransferStatus, usize);
+
+struct PendingTransfer {
+    transfer: Transfer,
+    direction: u32,
+}
+
+struct UsbipState {
+    stream: TcpStream,
+    devid: u32,
+    next_seqnum: u32,
+    // Submitted transfers by sequence number, until the server replies to them.
+    pending: HashMap<u32, PendingTransfer>,
+    // Sequence number of the transfer targeted by each unlink request in flight.
+    unlinks: HashMap<u32, u32>,
+    // Received bytes that do not form a complete reply yet.
+    rx: Vec<u8>,
+}
+
+impl UsbipState {
+    fn alloc_seqnum(&mut self) -> u32 {
+        let seqnum = self.next_seqnum;
+        // Sequence number 0 is never used.
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        seqnum
+    }
+
+    // Synchronously reads a descriptor from the device. Only used before the connection is added
+    // to the event loop, when no other transfer can be in flight.
+    fn get_descriptor(
+        &mut self,
+        descriptor_type: DescriptorType,
+        index: u8,
+        length: u16,
+    ) -> Result<Vec<u8>> {
+        let setup = UsbRequestSetup::new(
+            0x80,
+            StandardControlRequest::GetDescriptor as u8,
+            ((descriptor_type as u16) << 8) | index as u16,
+            0,
+            length,
+        );
+        let seqnum = self.alloc_seqnum();
+        let cmd = CmdSubmit::new(
+            seqnum,
+            self.devid,
+            0,
+            USBIP_DIR_IN,
+            length as i32,
+            setup_bytes(&setup),
+        );
+        self.stream
+            .write_all(cmd.as_bytes())
+            .map_err(Error::UsbipWrite)?;
+        let ret = match usbip::read_reply(&mut self.stream).map_err(Error::UsbipReply)? {
+            Reply::Submit(ret) if ret.header.seqnum.to_native() == seqnum => ret,
+            Reply::Submit(RetSubmit { header, .. }) | Reply::Unlink(RetUnlink { header, .. }) => {
+                return Err(Error::UsbipUnknownSeqnum(header.seqnum.to_native()))
+            }
+        };
+        let actual_length = ret.actual_length.to_native();
+        if !(0..=length as i32).contains(&actual_length) {
+            return Err(Error::UsbipInvalidLength(actual_length));
+        }
+        let mut data = vec![0u8; actual_length as usize];
+        self.stream
+            .read_exact(&mut data)
+            .map_err(Error::UsbipRead)?;
+        if ret.status.to_native() != 0 {
+            return Err(Error::UsbipGetDescriptor(ret.status.to_native()));
+        }
+        Ok(data)
+    }
+
+    // Reads the device descriptor and all the configuration descriptors, in the layout of the
+    // usbfs descriptors file.
+    fn read_descriptors(&mut self) -> Result<DeviceDescriptorTree> {
+        let device_descriptor_size =
+            (size_of::<DescriptorHeader>() + size_of::<DeviceDescriptor>()) as u16;
+        let mut raw = self.get_descriptor(DescriptorType::Device, 0, device_descriptor_size)?;
+        let num_configurations = DeviceDescriptor::read_from_prefix(
+            raw.get(size_of::<DescriptorHeader>()..).unwrap_or_default(),
+        )
+        .map(|d| d.bNumConfigurations)
+        .unwrap_or(0);
+
+        let config_header_size =
+            (size_of::<DescriptorHeader>() + size_of::<ConfigDescriptor>()) as u16;
+        for index in 0..num_configurations {
+            let header =
+                self.get_descriptor(DescriptorType::Configuration, index, config_header_size)?;
+            let total_length = ConfigDescriptor::read_from_prefix(
+                header
+                    .get(size_of::<DescriptorHeader>()..)
+                    .unwrap_or_default(),
+            )
+            .map(|c| c.wTotalLength)
+            .unwrap_or(config_header_size);
+            raw.extend(self.get_descriptor(DescriptorType::Configuration, index, total_length)?);
+        }
+        parse_usbfs_descriptors(&raw).map_err(Error::GetDeviceDescriptor)
+    }
+
+    fn submit(&mut self, transfer: Transfer) -> Result<u32> {
+        let endpoint = transfer.endpoint();
+        let buffer = match &transfer.buffer {
+            TransferBuffer::Vector(v) => v,
+            // request_transfer_buffer() never hands out DMA buffers.
+            TransferBuffer::Dma(_) => return Err(Error::GetDmaBuffer),
+        };
+        let ep = endpoint & 0x7f;
+        let (direction, setup, length, out_data) = if ep == 0 {
+            let setup = UsbRequestSetup::read_from_prefix(&buffer[..])
+                .ok_or(Error::MissingRequiredBuffer)?;
+            let length = setup.length as usize;
+            let data = buffer.get(SETUP_SIZE..SETUP_SIZE + length);
+            let data = data.ok_or(Error::MissingRequiredBuffer)?;
+            if setup.request_type & 0x80 != 0 {
+                (USBIP_DIR_IN, setup_bytes(&setup), length, &[][..])
+            } else {
+                (USBIP_DIR_OUT, setup_bytes(&setup), length, data)
+            }
+        } else if endpoint & 0x80 != 0 {
+            (USBIP_DIR_IN, [0; 8], buffer.len(), &[][..])
+        } else {
+            (USBIP_DIR_OUT, [0; 8], buffer.len(), &buffer[..])
+        };
+
+        let seqnum = self.alloc_seqnum();
+        let cmd = CmdSubmit::new(seqnum, self.devid, ep, direction, length as i32, setup);
+        let mut message = cmd.as_bytes().to_vec();
+        message.extend_from_slice(out_data);
+        self.stream.write_all(&message).map_err(Error::UsbipWrite)?;
+        self.pending.insert(
+            seqnum,
+            PendingTransfer {
+                transfer,
+                direction,
+            },
+        );
+        Ok(seqnum)
+    }
+
+    fn unlink(&mut self, seqnum: u32) -> Result<()> {
+        // Cancelling a transfer that already completed or is being cancelled is a no-op.
+        if !self.pending.contains_key(&seqnum) || self.unlinks.values().any(|&s| s == seqnum) {
+            return Ok(());
+        }
+        let unlink_seqnum = self.alloc_seqnum();
+        let cmd = CmdUnlink::new(unlink_seqnum, self.devid, seqnum);
+        self.stream
+            .write_all(cmd.as_bytes())
+            .map_err(Error::UsbipWrite)?;
+        self.unlinks.insert(unlink_seqnum, seqnum);
+        Ok(())
+    }
+
+    // Reads everything the server has sent so far without blocking.
+    fn receive(&mut self) -> Result<()> {
+        let mut buf = [0u8; 16384];
+        loop {
+            // Safe because `buf` is valid for writes of `buf.len()` bytes and recv does not keep
+            // the pointer. The return value is checked.
+            let ret = unsafe {
+                libc::recv(
+                    self.stream.as_raw_descriptor(),
+                    buf.as_mut_ptr() as *mut c_void,
+                    buf.len(),
+                    MSG_DONTWAIT,
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(Error::UsbipRead(e)),
+                }
+            }
+            if ret == 0 {
+                return Err(Error::UsbipDisconnected);
+            }
+            self.rx.extend_from_slice(&buf[..ret as usize]);
+        }
+    }
+
+    // Returns the next transfer completed by the replies received so far, if any.
+    fn next_completion(&mut self) -> Result<Option<Completion>> {
+        while self.rx.len() >= REPLY_HEADER_SIZE {
+            match usbip::read_reply(&mut &self.rx[..REPLY_HEADER_SIZE])
+                .map_err(Error::UsbipReply)?
+            {
+                Reply::Submit(ret) => {
+                    let seqnum = ret.header.seqnum.to_native();
+                    let direction = self
+                        .pending
+                        .get(&seqnum)
+                        .ok_or(Error::UsbipUnknownSeqnum(seqnum))?
+                        .direction;
+                    let data_len = if direction == USBIP_DIR_IN {
+                        ret.actual_length.to_native().max(0) as usize
+                    } else {
+                        0
+                    };
+                    if self.rx.len() < REPLY_HEADER_SIZE + data_len {
+                        return Ok(None);
+                    }
+                    let mut pending = self.pending.remove(&seqnum).unwrap();
+                    let data = &self.rx[REPLY_HEADER_SIZE..REPLY_HEADER_SIZE + data_len];
+                    let actual_length = if direction == USBIP_DIR_IN {
+                        copy_in_data(&mut pending.transfer, data)
+                    } else {
+                        ret.actual_length.to_native().max(0) as usize
+                    };
+                    self.rx.drain(..REPLY_HEADER_SIZE + data_len);
+                    let status = transfer_status(ret.status.to_native());
+                    return Ok(Some((pending.transfer, status, actual_length)));
+                }
+                Reply::Unlink(ret) => {
+                    self.rx.drain(..REPLY_HEADER_SIZE);
+                    // If the transfer completed before it could be unlinked, its RET_SUBMIT was
+                    // received first and it is no longer pending.
+                    if let Some(target) = self.unlinks.remove(&ret.header.seqnum.to_native()) {
+                        if let Some(pending) = self.pending.remove(&target) {
+                            return Ok(Some((pending.transfer, TransferStatus::Cancelled, 0)));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn setup_bytes(setup: &UsbRequestSetup) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(setup.as_bytes());
+    bytes
+}
+
+// Copies the data of an IN transfer to its buffer, after the setup packet for control transfers,
+// and returns the number of bytes copied.
+fn copy_in_data(transfer: &mut Transfer, data: &[u8]) -> usize {
+    let offset = if transfer.endpoint() & 0x7f == 0 {
+        SETUP_SIZE
+    } else {
+        0
+    };
+    match &mut transfer.buffer {
+        TransferBuffer::Vector(v) => {
+            let dest = v.get_mut(offset..).unwrap_or_default();
+            let len = dest.len().min(data.len());
+            dest[..len].copy_from_slice(&data[..len]);
+            len
+        }
+        TransferBuffer::Dma(_) => 0,
+    }
+}
+
+fn transfer_status(status: i32) -> TransferStatus {
+    match -status {
+        0 => TransferStatus::Completed,
+        EPIPE => TransferStatus::Stalled,
+        ENOENT | ECONNRESET => TransferStatus::Cancelled,
+        ENODEV | ESHUTDOWN => TransferStatus::NoDevice,
+        _ => TransferStatus::Error,
+    }
+}
+
+/// Connection to a USB/IP server exporting a single device. It submits the transfers of the
+/// device to the server, and completes them as the server replies when used as the event handler
+/// of the connection.
+#[derive(Clone)]
+pub struct UsbipConnection {
+    state: Arc<Mutex<UsbipState>>,
+}
+
+impl UsbipConnection {
+    /// Imports device `busid` from the USB/IP server at the other end of `stream`. Returns the
+    /// connection along with the description and the descriptors of the device.
+    pub fn new(
+        mut stream: TcpStream,
+        busid: &str,
+    ) -> Result<(UsbipConnection, UsbipUsbDevice, DeviceDescriptorTree)> {
+        stream
+            .set_read_timeout(Some(SETUP_TIMEOUT))
+            .map_err(Error::UsbipSetup)?;
+        let device = usbip::import_device(&mut stream, busid).map_err(Error::UsbipImport)?;
+        let mut state = UsbipState {
+            stream,
+            devid: device.devid(),
+            next_seqnum: 1,
+            pending: HashMap::new(),
+            unlinks: HashMap::new(),
+            rx: Vec::new(),
+        };
+        let descriptors = state.read_descriptors()?;
+        state
+            .stream
+            .set_read_timeout(None)
+            .map_err(Error::UsbipSetup)?;
+        let connection = UsbipConnection {
+            state: Arc::new(Mutex::new(state)),
+        };
+        Ok((connection, device, descriptors))
+    }
+
+    /// Registers the connection as its own event handler on `event_loop`.
+    pub fn add_event_handler(
+        &self,
+        event_loop: &EventLoop,
+        handler: &Arc<dyn EventHandler>,
+    ) -> Result<()> {
+        event_loop
+            .add_event(
+                &self.state.lock().stream,
+                EventType::Read,
+                Arc::downgrade(handler),
+            )
+            .map_err(Error::AddToEventLoop)
+    }
+}
+
+struct UsbipTransferHandle {
+    state: Weak<Mutex<UsbipState>>,
+    seqnum: u32,
+}
+
+impl GenericTransferHandle for UsbipTransferHandle {
+    fn cancel(&self) -> Result<()> {
+        match self.state.upgrade() {
+            Some(state) => state.lock().unlink(self.seqnum),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BackendDevice for UsbipConnection {
+    fn submit_backend_transfer(&mut self, transfer: Transfer) -> Result<BackendTransferHandle> {
+        let seqnum = self.state.lock().submit(transfer)?;
+        Ok(BackendTransferHandle::new(UsbipTransferHandle {
+            state: Arc::downgrade(&self.state),
+            seqnum,
+        }))
+    }
+
+    fn detach_event_handler(&self, event_loop: &Arc<EventLoop>) -> Result<()> {
+        event_loop
+            .remove_event_for_descriptor(&self.state.lock().stream)
+            .map_err(Error::RemoveFromEventLoop)
+    }
+
+    fn request_transfer_buffer(&mut self, size: usize) -> TransferBuffer {
+        TransferBuffer::Vector(vec![0u8; size])
+    }
+}
+
+impl EventHandler for UsbipConnection {
+    fn on_event(&self) -> anyhow::Result<()> {
+        let received = self.state.lock().receive();
+        loop {
+            // Transfers are completed without holding the state lock, since their callbacks take
+            // the xhci transfer state lock, which is held while transfers are submitted.
+            let completion = self.state.lock().next_completion();
+            match completion.context("failed to handle USB/IP reply")? {
+                Some((transfer, status, actual_length)) => transfer.complete(status, actual_length),
+                None => break,
+            }
+        }
+        if let Err(e) = received {
+            let pending: Vec<PendingTransfer> =
+                self.state.lock().pending.drain().map(|(_, p)| p).collect();
+            for p in pending {
+                p.transfer.complete(TransferStatus::NoDevice, 0);
+            }
+            return Err(e).context("USB/IP connection failed");
+        }
+        Ok(())
+    }
+}
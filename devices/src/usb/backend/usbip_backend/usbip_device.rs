@@ -0,0 +1,358 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+
+use base::debug;
+use base::error;
+use base::warn;
+use usb_util::usbip::UsbipUsbDevice;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::DeviceDescriptorTree;
+use usb_util::DeviceSpeed;
+use usb_util::StandardControlRequest;
+use usb_util::Transfer;
+use usb_util::TransferBuffer;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use zerocopy::AsBytes;
+
+use crate::usb::backend::endpoint::UsbEndpoint;
+use crate::usb::backend::error::*;
+use crate::usb::backend::host_backend::host_device::ControlEndpointState;
+use crate::usb::backend::usbip_backend::usbip_connection::UsbipConnection;
+use crate::usb::backend::utils::submit_transfer;
+use crate::usb::backend::utils::update_transfer_state;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferState;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+use crate::utils::AsyncJobQueue;
+use crate::utils::FailHandle;
+
+/// USB/IP device is a device exported by a USB/IP server. All its requests except SET_ADDRESS
+/// are forwarded to the server.
+pub struct UsbipDevice {
+    fail_handle: Arc<dyn FailHandle>,
+    job_queue: Arc<AsyncJobQueue>,
+    connection: UsbipConnection,
+    device: UsbipUsbDevice,
+    descriptors: DeviceDescriptorTree,
+    // Data endpoints of the active configuration and alternate settings.
+    endpoints: Vec<UsbEndpoint>,
+    configuration: u8,
+    alt_settings: HashMap<u8, u8>,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+}
+
+impl UsbipDevice {
+    /// Create a new device for the imported `device`, whose descriptors are `descriptors`.
+    pub fn new(
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+        connection: UsbipConnection,
+        device: UsbipUsbDevice,
+        descriptors: DeviceDescriptorTree,
+    ) -> UsbipDevice {
+        UsbipDevice {
+            fail_handle,
+            job_queue,
+            connection,
+            device,
+            descriptors,
+            endpoints: Vec::new(),
+            configuration: 0,
+            alt_settings: HashMap::new(),
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        }
+    }
+
+    fn create_endpoints(&mut self) -> Result<()> {
+        self.endpoints = Vec::new();
+        if self.configuration == 0 {
+            return Ok(());
+        }
+        let config_descriptor = self
+            .descriptors
+            .get_config_descriptor(self.configuration)
+            .ok_or(Error::MissingConfigDescriptor(self.configuration))?;
+        for i in 0..config_descriptor.num_interfaces() {
+            let alt_setting = *self.alt_settings.get(&i).unwrap_or(&0);
+            let interface = config_descriptor
+                .get_interface_descriptor(i, alt_setting)
+                .ok_or(Error::GetInterfaceDescriptor(i, alt_setting))?;
+            for ep_idx in 0..interface.bNumEndpoints {
+                let ep_dp = interface
+                    .get_endpoint_descriptor(ep_idx)
+                    .ok_or(Error::GetEndpointDescriptor(ep_idx))?;
+                let ep_num = ep_dp.get_endpoint_number();
+                if ep_num == 0 {
+                    continue;
+                }
+                let direction = ep_dp.get_direction();
+                let ty = ep_dp.get_endpoint_type().ok_or(Error::GetEndpointType)?;
+                self.endpoints.push(UsbEndpoint::new(
+                    self.fail_handle.clone(),
+                    self.job_queue.clone(),
+                    ep_num,
+                    direction,
+                    ty,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Handles the standard requests that are emulated or that change the endpoints of the device.
+    // Returns true if the request has been completed and must not be forwarded to the server.
+    fn intercepted_control_transfer(&mut self, xhci_transfer: &XhciTransfer) -> Result<bool> {
+        let setup = self.control_request_setup;
+        if setup.get_direction() != ControlRequestDataPhaseTransferDirection::HostToDevice {
+            return Ok(false);
+        }
+        // Copies of the packed fields.
+        let value = setup.value;
+        let index = setup.index;
+        match (setup.get_standard_request(), setup.get_recipient()) {
+            (Some(StandardControlRequest::SetAddress), ControlRequestRecipient::Device) => {
+                // The address is assigned by the xHCI Address Device command.
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                return Ok(true);
+            }
+            (Some(StandardControlRequest::SetConfiguration), ControlRequestRecipient::Device) => {
+                usb_trace!("set_config: {}", value);
+                self.configuration = value as u8;
+                self.alt_settings.clear();
+                self.create_endpoints()?;
+            }
+            (Some(StandardControlRequest::SetInterface), ControlRequestRecipient::Interface) => {
+                usb_trace!("set_interface: {} {}", index, value);
+                self.alt_settings.insert(index as u8, value as u8);
+                self.create_endpoints()?;
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: Arc<XhciTransfer>,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        if self.intercepted_control_transfer(&xhci_transfer)? {
+            return Ok(());
+        }
+
+        // The control transfer buffer holds a UsbRequestSetup struct followed by the data.
+        let control_buffer_len =
+            mem::size_of::<UsbRequestSetup>() + self.control_request_setup.length as usize;
+        let mut control_buffer = vec![0u8; control_buffer_len];
+        control_buffer[..mem::size_of::<UsbRequestSetup>()]
+            .copy_from_slice(self.control_request_setup.as_bytes());
+
+        let direction = self.control_request_setup.get_direction();
+        let buffer = if direction == ControlRequestDataPhaseTransferDirection::HostToDevice {
+            if let Some(buffer) = buffer {
+                buffer
+                    .read(&mut control_buffer[mem::size_of::<UsbRequestSetup>()..])
+                    .map_err(Error::ReadBuffer)?;
+            }
+            None
+        } else {
+            buffer
+        };
+
+        let mut control_transfer = Transfer::new_control(TransferBuffer::Vector(control_buffer))
+            .map_err(Error::CreateTransfer)?;
+
+        let tmp_transfer = xhci_transfer.clone();
+        let callback = move |t: Transfer| {
+            update_transfer_state(&xhci_transfer, &t)?;
+            let state = xhci_transfer.state().lock();
+            match *state {
+                XhciTransferState::Cancelled => {
+                    drop(state);
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                        .map_err(Error::TransferComplete)?;
+                }
+                XhciTransferState::Completed => {
+                    let status = t.status();
+                    let mut actual_length = t.actual_length();
+                    if direction == ControlRequestDataPhaseTransferDirection::DeviceToHost {
+                        if let (TransferBuffer::Vector(v), Some(buffer)) = (&t.buffer, &buffer) {
+                            let data = &v[mem::size_of::<UsbRequestSetup>()..];
+                            actual_length = buffer
+                                .write(&data[..actual_length.min(data.len())])
+                                .map_err(Error::WriteBuffer)?;
+                        }
+                    }
+                    drop(state);
+                    debug!(
+                        "usbip control transfer completed with actual length {}",
+                        actual_length
+                    );
+                    xhci_transfer
+                        .on_transfer_complete(&status, actual_length as u32)
+                        .map_err(Error::TransferComplete)?;
+                }
+                _ => {
+                    error!("should not take this branch");
+                    return Err(Error::BadXhciTransferState);
+                }
+            }
+            Ok(())
+        };
+
+        let fail_handle = self.fail_handle.clone();
+        control_transfer.set_callback(move |t: Transfer| match callback(t) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("control transfer callback failed {:?}", e);
+                fail_handle.fail();
+            }
+        });
+        submit_transfer(
+            self.fail_handle.clone(),
+            &self.job_queue,
+            tmp_transfer,
+            &mut self.connection,
+            control_transfer,
+        )
+    }
+
+    fn handle_control_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let xhci_transfer = Arc::new(transfer);
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage => {
+                let setup = xhci_transfer
+                    .create_usb_request_setup()
+                    .map_err(Error::CreateUsbRequestSetup)?;
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_trace!("setup stage: setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                let buffer = xhci_transfer.create_buffer().map_err(Error::CreateBuffer)?;
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl XhciBackendDevice for UsbipDevice {
+    fn get_backend_type(&self) -> BackendType {
+        // See definition of bcdUsb.
+        const USB3_MASK: u16 = 0x0300;
+        match self.descriptors.bcdUSB & USB3_MASK {
+            USB3_MASK => BackendType::Usb3,
+            _ => BackendType::Usb2,
+        }
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.device.idVendor.to_native()
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.device.idProduct.to_native()
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            return self.handle_control_transfer(transfer);
+        }
+        for ep in &self.endpoints {
+            if ep.match_ep(transfer.get_endpoint_number(), transfer.get_transfer_dir()) {
+                return ep.handle_transfer(&mut self.connection, transfer);
+            }
+        }
+        warn!("Could not find endpoint for transfer");
+        transfer
+            .on_transfer_complete(&TransferStatus::Error, 0)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn set_address(&mut self, address: UsbDeviceAddress) {
+        debug!("usbip device got address {}", address);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // USB/IP has no port reset request, the device returns to the default state on the next
+        // SET_CONFIGURATION.
+        self.endpoints = Vec::new();
+        self.configuration = 0;
+        self.alt_settings.clear();
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        Ok(())
+    }
+
+    fn get_speed(&self) -> Option<DeviceSpeed> {
+        self.device.speed()
+    }
+
+    fn alloc_streams(&self, _ep: u8, _num_streams: u16) -> Result<()> {
+        // USB/IP transfers carry no stream id, so streams cannot be forwarded.
+        Ok(())
+    }
+
+    fn free_streams(&self, _ep: u8) -> Result<()> {
+        Ok(())
+    }
+}
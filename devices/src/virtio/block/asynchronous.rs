@@ -56,6 +56,7 @@ use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskStats;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 
@@ -233,6 +234,34 @@ pub struct DiskState {
 /// Disk state which can be modified by other worker threads
 struct WorkerSharedState {
     disk_size: Arc<AtomicU64>,
+    counters: DiskCounters,
+}
+
+/// Counters of the requests completed by the workers of a disk, reported by
+/// `DiskControlCommand::Stats`.
+#[derive(Default)]
+struct DiskCounters {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    write_bytes: AtomicU64,
+    flushes: AtomicU64,
+    discards: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl DiskCounters {
+    fn stats(&self) -> DiskStats {
+        DiskStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            write_bytes: self.write_bytes.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            discards: self.discards.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl DiskState {
@@ -249,7 +278,10 @@ impl DiskState {
             read_only,
             sparse,
             id,
-            worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState { disk_size })),
+            worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
+                disk_size,
+                counters: DiskCounters::default(),
+            })),
         }
     }
 }
@@ -287,6 +319,12 @@ async fn process_one_request(
                 LogLevel::Debug => debug!("failed executing disk request: {:#}", e),
                 LogLevel::Error => error!("failed executing disk request: {:#}", e),
             }
+            let disk_state = disk_state.read_lock().await;
+            let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+            worker_shared_state
+                .counters
+                .errors
+                .fetch_add(1, Ordering::Relaxed);
             e.status()
         }
     };
@@ -394,6 +432,11 @@ async fn handle_command_tube(
             Ok(command) => {
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => resize(&disk_state, new_size).await,
+                    DiskControlCommand::Stats => {
+                        let disk_state = disk_state.read_lock().await;
+                        let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+                        DiskControlResult::Stats(worker_shared_state.counters.stats())
+                    }
                 };
 
                 let resp_clone = resp.clone();
@@ -800,6 +843,7 @@ impl BlockAsync {
         }
 
         let disk_size = worker_shared_state.disk_size.load(Ordering::Relaxed);
+        let counters = &worker_shared_state.counters;
         match req_type {
             VIRTIO_BLK_T_IN => {
                 let data_len = writer.available_bytes();
@@ -819,6 +863,10 @@ impl BlockAsync {
                         sector,
                         desc_error,
                     })?;
+                counters.reads.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .read_bytes
+                    .fetch_add(data_len as u64, Ordering::Relaxed);
             }
             VIRTIO_BLK_T_OUT => {
                 let data_len = reader.available_bytes();
@@ -838,6 +886,10 @@ impl BlockAsync {
                         sector,
                         desc_error,
                     })?;
+                counters.writes.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .write_bytes
+                    .fetch_add(data_len as u64, Ordering::Relaxed);
 
                 if !*flush_timer_armed.borrow() {
                     *flush_timer_armed.borrow_mut() = true;
@@ -903,6 +955,7 @@ impl BlockAsync {
                             })?;
                     }
                 }
+                counters.discards.fetch_add(1, Ordering::Relaxed);
             }
             VIRTIO_BLK_T_FLUSH => {
                 disk_state
@@ -910,6 +963,7 @@ impl BlockAsync {
                     .fdatasync()
                     .await
                     .map_err(ExecuteError::Flush)?;
+                counters.flushes.fetch_add(1, Ordering::Relaxed);
 
                 if *flush_timer_armed.borrow() {
                     flush_timer
@@ -1033,6 +1087,7 @@ impl VirtioDevice for BlockAsync {
 
         let shared_state = Arc::new(AsyncRwLock::new(WorkerSharedState {
             disk_size: self.disk_size.clone(),
+            counters: DiskCounters::default(),
         }));
 
         let mut worker_threads = vec![];
@@ -1387,6 +1442,7 @@ mod tests {
             id: None,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                counters: DiskCounters::default(),
             })),
         }));
 
@@ -1404,6 +1460,17 @@ mod tests {
         let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512) as u64);
         let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
         assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        let stats = ex
+            .run_until(disk_stats(&disk_state))
+            .expect("running executor failed");
+        assert_eq!((stats.reads, stats.read_bytes, stats.errors), (1, 512, 0));
+    }
+
+    async fn disk_stats(disk_state: &AsyncRwLock<DiskState>) -> DiskStats {
+        let disk_state = disk_state.read_lock().await;
+        let worker_shared_state = disk_state.worker_shared_state.read_lock().await;
+        worker_shared_state.counters.stats()
     }
 
     #[test]
@@ -1455,6 +1522,7 @@ mod tests {
             id: None,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                counters: DiskCounters::default(),
             })),
         }));
 
@@ -1472,6 +1540,11 @@ mod tests {
         let status_offset = GuestAddress((0x1000 + size_of_val(&req_hdr) + 512 * 2) as u64);
         let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
         assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let stats = ex
+            .run_until(disk_stats(&disk_state))
+            .expect("running executor failed");
+        assert_eq!((stats.reads, stats.errors), (0, 1));
     }
 
     #[test]
@@ -1525,6 +1598,7 @@ mod tests {
             id: Some(*id),
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
+                counters: DiskCounters::default(),
             })),
         }));
 
@@ -146,6 +146,7 @@ pub mod snd {
         pub jacks: Le32,
         pub streams: Le32,
         pub chmaps: Le32,
+        pub controls: Le32,
     }
 }
 
@@ -183,6 +184,8 @@ pub mod video {
         Ffmpeg,
         #[cfg(feature = "vaapi")]
         Vaapi,
+        #[cfg(feature = "swcodec")]
+        Swcodec,
     }
 
     #[derive(Debug, Serialize, Deserialize, FromKeyValues)]
@@ -204,6 +207,13 @@ pub mod video {
             | 1u64 << VIRTIO_VIDEO_F_RESOURCE_VIRTIO_OBJECT
     }
 
+    /// The same set of virtio features is supported by the swcodec decoder and encoder.
+    pub fn swcodec_supported_virtio_features() -> u64 {
+        1u64 << VIRTIO_VIDEO_F_RESOURCE_GUEST_PAGES
+            | 1u64 << VIRTIO_VIDEO_F_RESOURCE_NON_CONTIG
+            | 1u64 << VIRTIO_VIDEO_F_RESOURCE_VIRTIO_OBJECT
+    }
+
     /// The same set of virtio features is supported by the vda decoder and encoder.
     pub fn vda_supported_virtio_features() -> u64 {
         1u64 << VIRTIO_VIDEO_F_RESOURCE_NON_CONTIG | 1u64 << VIRTIO_VIDEO_F_RESOURCE_VIRTIO_OBJECT
@@ -214,6 +224,7 @@ pub mod video {
         ffmpeg_supported_virtio_features()
             | vaapi_supported_virtio_features()
             | vda_supported_virtio_features()
+            | swcodec_supported_virtio_features()
     }
 
     pub fn backend_supported_virtio_features(backend: VideoBackendType) -> u64 {
@@ -226,6 +237,8 @@ pub mod video {
             VideoBackendType::Ffmpeg => ffmpeg_supported_virtio_features(),
             #[cfg(feature = "vaapi")]
             VideoBackendType::Vaapi => vaapi_supported_virtio_features(),
+            #[cfg(feature = "swcodec")]
+            VideoBackendType::Swcodec => swcodec_supported_virtio_features(),
         }
     }
 
@@ -2,13 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fmt;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
-#[cfg(feature = "arc_quota")]
 use serde::de::Error;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
 use serde_keyvalue::FromKeyValues;
 
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
@@ -34,6 +38,147 @@ pub enum CachePolicy {
     Always,
 }
 
+/// A range of ids in an `IdMap`. The `count` ids starting at `guest` in the VM correspond to the
+/// `count` ids starting at `host` on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapRange {
+    pub guest: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl IdMapRange {
+    fn contains_guest(&self, id: u32) -> bool {
+        id >= self.guest && u64::from(id) < u64::from(self.guest) + u64::from(self.count)
+    }
+
+    fn contains_host(&self, id: u32) -> bool {
+        id >= self.host && u64::from(id) < u64::from(self.host) + u64::from(self.count)
+    }
+}
+
+/// Mapping between the uids (or gids) seen by the VM and the ones stored on the host.
+///
+/// The map is written in the same format as the `uidmap` and `gidmap` options of minijail:
+/// `"guest host count[,guest host count]"`. An empty map is the identity mapping. Otherwise ids
+/// that are not covered by any range cannot be used by the VM, and host ids without a mapping are
+/// reported to the VM as the overflow id (65534).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap(Vec<IdMapRange>);
+
+impl IdMap {
+    /// Returns true if this is the identity mapping.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the ranges of this map.
+    pub fn ranges(&self) -> &[IdMapRange] {
+        &self.0
+    }
+
+    /// Returns the host id corresponding to the id `id` in the VM, or `None` if `id` is not
+    /// mapped.
+    pub fn to_host(&self, id: u32) -> Option<u32> {
+        if self.is_empty() {
+            return Some(id);
+        }
+
+        self.0
+            .iter()
+            .find(|r| r.contains_guest(id))
+            .map(|r| id - r.guest + r.host)
+    }
+
+    /// Returns the id in the VM corresponding to the host id `id`, or `None` if `id` is not
+    /// mapped.
+    pub fn to_guest(&self, id: u32) -> Option<u32> {
+        if self.is_empty() {
+            return Some(id);
+        }
+
+        self.0
+            .iter()
+            .find(|r| r.contains_host(id))
+            .map(|r| id - r.host + r.guest)
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges: Vec<IdMapRange> = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let ids = entry
+                .split_whitespace()
+                .map(|id| {
+                    id.parse::<u32>()
+                        .map_err(|e| format!("invalid id `{id}` in id map: {e}"))
+                })
+                .collect::<Result<Vec<u32>, String>>()?;
+            let range = match ids[..] {
+                [guest, host, count] => IdMapRange { guest, host, count },
+                _ => {
+                    return Err(format!(
+                        "id map entry `{entry}` must be of the form `guest host count`"
+                    ))
+                }
+            };
+
+            if range.count == 0
+                || u64::from(range.guest) + u64::from(range.count) > 1 << 32
+                || u64::from(range.host) + u64::from(range.count) > 1 << 32
+            {
+                return Err(format!("id map entry `{entry}` is out of range"));
+            }
+
+            let last_guest = range.guest + (range.count - 1);
+            let last_host = range.host + (range.count - 1);
+            if ranges.iter().any(|r| {
+                r.contains_guest(range.guest)
+                    || r.contains_guest(last_guest)
+                    || range.contains_guest(r.guest)
+                    || r.contains_host(range.host)
+                    || r.contains_host(last_host)
+                    || range.contains_host(r.host)
+            }) {
+                return Err(format!("id map entry `{entry}` overlaps another entry"));
+            }
+
+            ranges.push(range);
+        }
+
+        Ok(IdMap(ranges))
+    }
+}
+
+impl Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, r) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{} {} {}", r.guest, r.host, r.count)?;
+        }
+        Ok(())
+    }
+}
+
+// Id maps are (de)serialized in their string form.
+impl Serialize for IdMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IdMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(<D as Deserializer>::Error::custom)
+    }
+}
+
 const fn config_default_timeout() -> Duration {
     Duration::from_secs(5)
 }
@@ -46,6 +191,23 @@ const fn config_default_posix_acl() -> bool {
     true
 }
 
+const fn config_default_num_queues() -> usize {
+    1
+}
+
+const fn config_default_num_threads() -> usize {
+    1
+}
+
+fn deserialize_nonzero<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    match usize::deserialize(deserializer)? {
+        0 => Err(<D as Deserializer>::Error::custom(
+            "value must be at least 1",
+        )),
+        n => Ok(n),
+    }
+}
+
 fn deserialize_timeout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
     let secs = u64::deserialize(deserializer)?;
 
@@ -168,6 +330,61 @@ pub struct Config {
     /// The default value for this option is `true`.
     #[serde(default = "config_default_posix_acl")]
     pub posix_acl: bool,
+
+    /// Read-only "lower" directory to overlay below the shared directory.
+    ///
+    /// When set, the shared directory becomes the writable "upper" layer of a copy-on-write
+    /// overlay: files are served from the upper directory if they exist there and from the lower
+    /// directory otherwise. Files from the lower directory are copied up before they are modified
+    /// and deleted entries are hidden with whiteouts, so the lower directory is never written to
+    /// and can be shared between several VMs.
+    ///
+    /// This option cannot be combined with `ascii_casefold`.
+    ///
+    /// The default value for this option is `None` (no overlay).
+    #[serde(default)]
+    pub lower: Option<PathBuf>,
+
+    /// Mapping between the uids of the VM and the host uids that own the shared files.
+    ///
+    /// The file system converts the uids of the VM to host uids when it creates or changes the
+    /// owner of files, and converts host uids back when it reports file attributes. This allows
+    /// an unprivileged host user to present files owned by root in the VM without setting up a
+    /// user namespace. See `IdMap` for the format.
+    ///
+    /// The default value for this option is the identity mapping.
+    #[serde(default)]
+    pub uid_map: IdMap,
+
+    /// Mapping between the gids of the VM and the host gids that own the shared files. This works
+    /// like `uid_map`.
+    ///
+    /// The default value for this option is the identity mapping.
+    #[serde(default)]
+    pub gid_map: IdMap,
+
+    /// The number of request queues advertised to the VM. Each request queue is serviced by its
+    /// own worker, so the guest can submit requests from several vCPUs without contention.
+    ///
+    /// The default value for this option is 1.
+    #[serde(
+        default = "config_default_num_queues",
+        deserialize_with = "deserialize_nonzero"
+    )]
+    pub num_queues: usize,
+
+    /// The number of threads that execute the requests of each request queue.
+    ///
+    /// With more than one thread, a slow operation on the host file system no longer delays the
+    /// other requests of the queue. Requests that modify an inode are still executed in the order
+    /// in which the VM sent them, relative to the other requests for the same inode.
+    ///
+    /// The default value for this option is 1 (requests are executed one at a time).
+    #[serde(
+        default = "config_default_num_threads",
+        deserialize_with = "deserialize_nonzero"
+    )]
+    pub num_threads: usize,
 }
 
 impl Default for Config {
@@ -183,6 +400,90 @@ impl Default for Config {
             privileged_quota_uids: Default::default(),
             use_dax: false,
             posix_acl: config_default_posix_acl(),
+            lower: None,
+            uid_map: Default::default(),
+            gid_map: Default::default(),
+            num_queues: config_default_num_queues(),
+            num_threads: config_default_num_threads(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_id_map() {
+        let map: IdMap = "0 1000 1,1 100000 1000".parse().unwrap();
+        assert_eq!(
+            map.ranges(),
+            [
+                IdMapRange {
+                    guest: 0,
+                    host: 1000,
+                    count: 1,
+                },
+                IdMapRange {
+                    guest: 1,
+                    host: 100000,
+                    count: 1000,
+                },
+            ]
+        );
+        assert_eq!(map.to_host(0), Some(1000));
+        assert_eq!(map.to_host(10), Some(100009));
+        assert_eq!(map.to_host(1001), None);
+        assert_eq!(map.to_guest(1000), Some(0));
+        assert_eq!(map.to_guest(100999), Some(1000));
+        assert_eq!(map.to_guest(0), None);
+
+        let identity: IdMap = "".parse().unwrap();
+        assert!(identity.is_empty());
+        assert_eq!(identity.to_host(1234), Some(1234));
+        assert_eq!(identity.to_guest(1234), Some(1234));
+    }
+
+    #[test]
+    fn parse_id_map_invalid() {
+        assert!("0 1000".parse::<IdMap>().is_err());
+        assert!("0 1000 1 2".parse::<IdMap>().is_err());
+        assert!("0 a 1".parse::<IdMap>().is_err());
+        assert!("0 1000 0".parse::<IdMap>().is_err());
+        assert!("1 4294967295 2".parse::<IdMap>().is_err());
+        assert!("0 1000 10,5 2000 1".parse::<IdMap>().is_err());
+        assert!("0 1000 10,20 995 10".parse::<IdMap>().is_err());
+    }
+
+    #[test]
+    fn config_id_map() {
+        let cfg: Config =
+            serde_keyvalue::from_key_values("uid_map=0 1000 1,gid_map=0 1001 1").unwrap();
+        assert_eq!(cfg.uid_map.to_host(0), Some(1000));
+        assert_eq!(cfg.gid_map.to_host(0), Some(1001));
+        assert!(Config::default().uid_map.is_empty());
+    }
+
+    #[test]
+    fn id_map_round_trip() {
+        let map: IdMap = "0 1000 1,1 100000 1000".parse().unwrap();
+        let serialized = serde_json::to_string(&map).unwrap();
+        assert_eq!(serialized, "\"0 1000 1,1 100000 1000\"");
+        assert_eq!(serde_json::from_str::<IdMap>(&serialized).unwrap(), map);
+
+        let identity = serde_json::to_string(&IdMap::default()).unwrap();
+        assert!(serde_json::from_str::<IdMap>(&identity).unwrap().is_empty());
+    }
+
+    #[test]
+    fn config_queues_and_threads() {
+        let cfg = Config::default();
+        assert_eq!((cfg.num_queues, cfg.num_threads), (1, 1));
+
+        let cfg: Config = serde_keyvalue::from_key_values("num_queues=4,num_threads=8").unwrap();
+        assert_eq!((cfg.num_queues, cfg.num_threads), (4, 8));
+
+        assert!(serde_keyvalue::from_key_values::<Config>("num_queues=0").is_err());
+        assert!(serde_keyvalue::from_key_values::<Config>("num_threads=0").is_err());
+    }
+}
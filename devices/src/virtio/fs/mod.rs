@@ -50,6 +50,8 @@ mod worker;
 
 pub use config::CachePolicy;
 pub use config::Config;
+pub use config::IdMap;
+pub use config::IdMapRange;
 use fuse::Server;
 use passthrough::PassthroughFs;
 pub use worker::process_fs_queue;
@@ -65,9 +67,15 @@ const FS_BAR_SIZE: u64 = 1 << 33;
 #[sorted]
 #[derive(Error, Debug)]
 pub enum Error {
+    /// Failed to clone the event of a virtio queue.
+    #[error("failed to clone virtio queue Event: {0}")]
+    CloneQueueEvent(SysError),
     /// Failed to create the file system.
     #[error("failed to create file system: {0}")]
     CreateFs(io::Error),
+    /// Failed to spawn a thread for executing requests.
+    #[error("failed to spawn request thread: {0}")]
+    CreateRequestThread(io::Error),
     /// Creating WaitContext failed.
     #[error("failed to create WaitContext: {0}")]
     CreateWaitContext(SysError),
@@ -86,6 +94,9 @@ pub enum Error {
     /// Error while reading from the virtio queue's Event.
     #[error("failed to read from virtio queue Event: {0}")]
     ReadQueueEvent(SysError),
+    /// A thread executing requests exited unexpectedly.
+    #[error("request thread exited unexpectedly")]
+    RequestThreadExited,
     /// Failed to set the securebits for the worker thread.
     #[error("failed to set securebits for the worker thread: {0}")]
     SetSecurebits(SysError),
@@ -220,6 +231,7 @@ impl VirtioDevice for Fs {
 
         let fs = self.fs.take().expect("missing file system implementation");
         let use_dax = fs.cfg().use_dax;
+        let num_threads = fs.cfg().num_threads;
 
         let server = Arc::new(Server::new(fs));
         let socket = self.tube.take().expect("missing mapping socket");
@@ -255,9 +267,13 @@ impl VirtioDevice for Fs {
                 let irq = interrupt.clone();
                 let socket = Arc::clone(&socket);
 
+                // The high priority queue only carries requests like FORGET and INTERRUPT that are
+                // cheap to execute, so a single thread is enough.
+                let num_threads = if idx == 0 { 1 } else { num_threads };
+
                 let worker =
                     WorkerThread::start(format!("v_fs:{}:{}", self.tag, idx), move |kill_evt| {
-                        let mut worker = Worker::new(queue, server, irq, socket, slot);
+                        let mut worker = Worker::new(queue, server, irq, socket, slot, num_threads);
                         worker.run(kill_evt, watch_resample_event)
                     });
 
@@ -102,6 +102,11 @@ where
         })
     }
 
+    /// Returns an iterator over the values of the map, in the order of their main keys.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.main.values().map(|(_, v)| v)
+    }
+
     /// Clears the map, removing all values.
     pub fn clear(&mut self) {
         self.alt.clear();
@@ -37,6 +37,7 @@ use base::FromRawDescriptor;
 use base::RawDescriptor;
 use data_model::zerocopy_from_reader;
 use fuse::filesystem::Context;
+use fuse::filesystem::DirEntry;
 use fuse::filesystem::DirectoryIterator;
 use fuse::filesystem::Entry;
 use fuse::filesystem::FileSystem;
@@ -74,8 +75,14 @@ use crate::virtio::fs::config::CachePolicy;
 use crate::virtio::fs::config::Config;
 use crate::virtio::fs::expiring_map::ExpiringMap;
 use crate::virtio::fs::multikey::MultikeyBTreeMap;
+use crate::virtio::fs::passthrough::overlay::Layer;
+use crate::virtio::fs::passthrough::overlay::Overlay;
+use crate::virtio::fs::passthrough::overlay::OverlayDirIter;
+use crate::virtio::fs::passthrough::overlay::OverlayNode;
 use crate::virtio::fs::read_dir::ReadDir;
 
+mod overlay;
+
 const EMPTY_CSTR: &[u8] = b"\0";
 const ROOT_CSTR: &[u8] = b"/\0";
 const PROC_CSTR: &[u8] = b"/proc\0";
@@ -84,6 +91,19 @@ const USER_VIRTIOFS_XATTR: &[u8] = b"user.virtiofs.";
 const SECURITY_XATTR: &[u8] = b"security.";
 const SELINUX_XATTR: &[u8] = b"security.selinux";
 
+// The id reported to the VM for host uids and gids that are not covered by `uid_map`/`gid_map`.
+// This matches the default of `/proc/sys/kernel/overflowuid` and `overflowgid`.
+const OVERFLOW_ID: u32 = 65534;
+
+// POSIX ACLs are stored in these xattrs as a 4 byte version header followed by 8 byte entries of
+// a 16-bit tag, 16-bit permissions and a 32-bit id. Only named user and group entries carry an id.
+const POSIX_ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+const POSIX_ACL_DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+const POSIX_ACL_HEADER_SIZE: usize = 4;
+const POSIX_ACL_ENTRY_SIZE: usize = 8;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP: u16 = 0x08;
+
 const FSCRYPT_KEY_DESCRIPTOR_SIZE: usize = 8;
 const FSCRYPT_KEY_IDENTIFIER_SIZE: usize = 16;
 
@@ -235,6 +255,8 @@ struct InodeData {
     refcount: AtomicU64,
     filetype: FileType,
     path: String,
+    // Overlay state of the inode. Only set when a lower directory is configured.
+    overlay: Option<Mutex<OverlayNode>>,
 }
 
 impl AsRawDescriptor for InodeData {
@@ -403,6 +425,10 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
 fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -424,7 +450,7 @@ fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     Ok(unsafe { st.assume_init() })
 }
 
-fn statat<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<libc::stat64> {
+fn statat<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
     // Safe because the kernel will only write data in `st` and we check the return
@@ -611,6 +637,10 @@ pub struct PassthroughFs {
     // if we use PassthroughFs in multi-threaded environments.
     expiring_casefold_lookup_caches: Option<Mutex<ExpiringCasefoldLookupCaches>>,
 
+    // Read-only lower layer when the file system serves a copy-on-write overlay. See the
+    // `overlay` module for details.
+    overlay: Option<Overlay>,
+
     cfg: Config,
 }
 
@@ -631,6 +661,13 @@ impl std::fmt::Debug for PassthroughFs {
 
 impl PassthroughFs {
     pub fn new(tag: &str, cfg: Config) -> io::Result<PassthroughFs> {
+        if cfg.lower.is_some() && cfg.ascii_casefold {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ascii_casefold cannot be used with a lower directory",
+            ));
+        }
+
         // Safe because this is a constant value and a valid C string.
         let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_CSTR) };
 
@@ -668,6 +705,9 @@ impl PassthroughFs {
             None
         };
 
+        // The lower directory must be opened now as it is usually outside of the jail.
+        let overlay = cfg.lower.as_deref().map(Overlay::new).transpose()?;
+
         let passthroughfs = PassthroughFs {
             process_lock: Mutex::new(()),
             tag: tag.to_string(),
@@ -688,6 +728,7 @@ impl PassthroughFs {
             #[cfg(feature = "arc_quota")]
             dbus_fd,
             expiring_casefold_lookup_caches,
+            overlay,
             cfg,
         };
 
@@ -704,12 +745,14 @@ impl PassthroughFs {
     }
 
     pub fn keep_rds(&self) -> Vec<RawDescriptor> {
-        #[cfg_attr(not(feature = "arc_quota"), allow(unused_mut))]
         let mut keep_rds = vec![self.proc.as_raw_descriptor()];
         #[cfg(feature = "arc_quota")]
         if let Some(fd) = self.dbus_fd {
             keep_rds.push(fd);
         }
+        if let Some(overlay) = &self.overlay {
+            keep_rds.push(overlay.as_raw_descriptor());
+        }
         keep_rds
     }
 
@@ -802,7 +845,14 @@ impl PassthroughFs {
     // Creates a new entry for `f` or increases the refcount of the existing entry for `f`.
     // The inodes mutex lock must not be already taken by the same thread otherwise this
     // will deadlock.
-    fn add_entry(&self, f: File, st: libc::stat64, open_flags: libc::c_int, path: String) -> Entry {
+    fn add_entry(
+        &self,
+        f: File,
+        st: libc::stat64,
+        open_flags: libc::c_int,
+        path: String,
+        overlay: Option<OverlayNode>,
+    ) -> Entry {
         let mut inodes = self.inodes.lock();
 
         let altkey = InodeAltKey {
@@ -823,6 +873,7 @@ impl PassthroughFs {
                     refcount: AtomicU64::new(1),
                     filetype: st.st_mode.into(),
                     path,
+                    overlay: overlay.map(Mutex::new),
                 }),
             );
 
@@ -832,7 +883,7 @@ impl PassthroughFs {
         Entry {
             inode,
             generation: 0,
-            attr: st,
+            attr: self.guest_attr(st),
             // We use the same timeout for the attribute and the entry.
             attr_timeout: self.cfg.timeout,
             entry_timeout: self.cfg.timeout,
@@ -878,8 +929,25 @@ impl PassthroughFs {
     }
 
     fn do_lookup(&self, parent: &InodeData, name: &CStr) -> io::Result<Entry> {
+        if self.overlay.is_some() {
+            return self.overlay_lookup(parent, name);
+        }
+
         let st = statat(parent, name)?;
+        self.open_entry(parent, parent, name, st, None)
+    }
 
+    // Returns an entry for `name` in `dir`, whose attributes are `st`, opening it if it isn't
+    // known yet. `parent` is the inode of the directory and is only used to build the path of the
+    // new inode.
+    fn open_entry<D: AsRawDescriptor + ?Sized>(
+        &self,
+        dir: &D,
+        parent: &InodeData,
+        name: &CStr,
+        st: libc::stat64,
+        overlay: Option<OverlayNode>,
+    ) -> io::Result<Entry> {
         let altkey = InodeAltKey {
             ino: st.st_ino,
             dev: st.st_dev,
@@ -891,7 +959,7 @@ impl PassthroughFs {
             return Ok(Entry {
                 inode: self.increase_inode_refcount(data),
                 generation: 0,
-                attr: st,
+                attr: self.guest_attr(st),
                 // We use the same timeout for the attribute and the entry.
                 attr_timeout: self.cfg.timeout,
                 entry_timeout: self.cfg.timeout,
@@ -911,7 +979,7 @@ impl PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let fd = match unsafe {
             syscall!(libc::openat64(
-                parent.as_raw_descriptor(),
+                dir.as_raw_descriptor(),
                 name.as_ptr(),
                 flags
             ))
@@ -929,7 +997,7 @@ impl PassthroughFs {
                 // Safe because this doesn't modify any memory and we check the return value.
                 unsafe {
                     syscall!(libc::openat64(
-                        parent.as_raw_descriptor(),
+                        dir.as_raw_descriptor(),
                         name.as_ptr(),
                         flags
                     ))
@@ -950,12 +1018,18 @@ impl PassthroughFs {
         // We made sure the lock acquired for `self.inodes` is released automatically when
         // the if block above is exited, so a call to `self.add_entry()` should not cause a deadlock
         // here. This would not be the case if this were executed in an else block instead.
-        Ok(self.add_entry(f, st, flags, path))
+        Ok(self.add_entry(f, st, flags, path, overlay))
     }
 
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
         let inode_data = self.find_inode(inode)?;
 
+        // Lower files must be copied up before they can be modified.
+        let open_flags = flags as i32;
+        if open_flags & libc::O_ACCMODE != libc::O_RDONLY || open_flags & libc::O_TRUNC != 0 {
+            self.copy_up(&inode_data)?;
+        }
+
         let file = Mutex::new(self.open_inode(&inode_data, flags as i32)?);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
@@ -998,10 +1072,68 @@ impl PassthroughFs {
         Err(ebadf())
     }
 
+    // Returns the host uid and gid corresponding to the ids of the caller in `ctx`.
+    fn host_ids(&self, ctx: &Context) -> io::Result<(libc::uid_t, libc::gid_t)> {
+        let uid = self.cfg.uid_map.to_host(ctx.uid).ok_or_else(einval)?;
+        let gid = self.cfg.gid_map.to_host(ctx.gid).ok_or_else(einval)?;
+        Ok((uid, gid))
+    }
+
+    // Changes the credentials of the current thread to the host ids of the caller in `ctx`.
+    fn set_ctx_creds(&self, ctx: &Context) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        let (uid, gid) = self.host_ids(ctx)?;
+        set_creds(uid, gid)
+    }
+
+    // Converts the owner of `st` to the ids seen by the VM.
+    fn guest_attr(&self, mut st: libc::stat64) -> libc::stat64 {
+        st.st_uid = self.cfg.uid_map.to_guest(st.st_uid).unwrap_or(OVERFLOW_ID);
+        st.st_gid = self.cfg.gid_map.to_guest(st.st_gid).unwrap_or(OVERFLOW_ID);
+        st
+    }
+
+    // Translates the ids of the named user and group entries of a POSIX ACL xattr `value`, either
+    // from the VM to the host (`to_host`) or back. Ids the VM uses that have no mapping are rejected
+    // while host ids without a mapping are reported as `OVERFLOW_ID`. Other xattrs are unchanged.
+    fn map_acl_ids<'a>(
+        &self,
+        name: &CStr,
+        value: &'a [u8],
+        to_host: bool,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        let name = name.to_bytes();
+        if (name != POSIX_ACL_ACCESS_XATTR && name != POSIX_ACL_DEFAULT_XATTR)
+            || (self.cfg.uid_map.is_empty() && self.cfg.gid_map.is_empty())
+            || value.len() < POSIX_ACL_HEADER_SIZE
+        {
+            return Ok(Cow::Borrowed(value));
+        }
+        if (value.len() - POSIX_ACL_HEADER_SIZE) % POSIX_ACL_ENTRY_SIZE != 0 {
+            return Err(einval());
+        }
+
+        let mut mapped = value.to_vec();
+        for entry in mapped[POSIX_ACL_HEADER_SIZE..].chunks_exact_mut(POSIX_ACL_ENTRY_SIZE) {
+            let map = match u16::from_le_bytes([entry[0], entry[1]]) {
+                ACL_USER => &self.cfg.uid_map,
+                ACL_GROUP => &self.cfg.gid_map,
+                _ => continue,
+            };
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let id = if to_host {
+                map.to_host(id).ok_or_else(einval)?
+            } else {
+                map.to_guest(id).unwrap_or(OVERFLOW_ID)
+            };
+            entry[4..].copy_from_slice(&id.to_le_bytes());
+        }
+        Ok(Cow::Owned(mapped))
+    }
+
     fn do_getattr(&self, inode: &InodeData) -> io::Result<(libc::stat64, Duration)> {
         let st = stat(inode)?;
 
-        Ok((st, self.cfg.timeout))
+        Ok((self.guest_attr(st), self.cfg.timeout))
     }
 
     fn do_unlink(&self, parent: &InodeData, name: &CStr, flags: libc::c_int) -> io::Result<()> {
@@ -1010,6 +1142,30 @@ impl PassthroughFs {
         Ok(())
     }
 
+    fn do_rename(
+        &self,
+        olddir: &InodeData,
+        oldname: &CStr,
+        newdir: &InodeData,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
+        // and we have glibc 2.28.
+        syscall!(unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                olddir.as_raw_descriptor(),
+                oldname.as_ptr(),
+                newdir.as_raw_descriptor(),
+                newname.as_ptr(),
+                flags,
+            )
+        })?;
+        Ok(())
+    }
+
     fn do_fsync<F: AsRawDescriptor>(&self, file: &F, datasync: bool) -> io::Result<()> {
         // Safe because this doesn't modify any memory and we check the return value.
         syscall!(unsafe {
@@ -1168,7 +1324,7 @@ impl PassthroughFs {
         let in_attr: fsxattr = zerocopy_from_reader(r)?;
 
         #[cfg(feature = "arc_quota")]
-        let st = stat(&*data)?;
+        let st = self.guest_attr(stat(&*data)?);
 
         // Changing quota project ID requires CAP_FOWNER or being file owner.
         // Here we use privileged_quota_uids because we cannot perform a CAP_FOWNER check.
@@ -1261,7 +1417,7 @@ impl PassthroughFs {
         let in_flags: c_int = zerocopy_from_reader(r)?;
 
         #[cfg(feature = "arc_quota")]
-        let st = stat(&*data)?;
+        let st = self.guest_attr(stat(&*data)?);
 
         // Only privleged uid can perform FS_IOC_SETFLAGS through cryptohome.
         #[cfg(feature = "arc_quota")]
@@ -1552,10 +1708,25 @@ fn strip_xattr_prefix(buf: &mut Vec<u8>) {
     }
 }
 
+/// Directory iterator returned by `PassthroughFs::readdir`.
+pub enum PassthroughDirIter {
+    ReadDir(ReadDir<Box<[u8]>>),
+    Overlay(OverlayDirIter),
+}
+
+impl DirectoryIterator for PassthroughDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        match self {
+            PassthroughDirIter::ReadDir(read_dir) => read_dir.next(),
+            PassthroughDirIter::Overlay(overlay) => overlay.next(),
+        }
+    }
+}
+
 impl FileSystem for PassthroughFs {
     type Inode = Inode;
     type Handle = Handle;
-    type DirIter = ReadDir<Box<[u8]>>;
+    type DirIter = PassthroughDirIter;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
         // Safe because this is a constant value and a valid C string.
@@ -1593,6 +1764,10 @@ impl FileSystem for PassthroughFs {
                 refcount: AtomicU64::new(2),
                 filetype: st.st_mode.into(),
                 path: "".to_string(),
+                overlay: self
+                    .overlay
+                    .as_ref()
+                    .map(|_| Mutex::new(OverlayNode::root())),
             }),
         );
 
@@ -1608,7 +1783,9 @@ impl FileSystem for PassthroughFs {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
         }
-        if self.cfg.cache_policy == CachePolicy::Always {
+        // Lower files are copied up when they are opened for writing, so the overlay needs to see
+        // every open request.
+        if self.cfg.cache_policy == CachePolicy::Always && self.overlay.is_none() {
             if capable.contains(FsOptions::ZERO_MESSAGE_OPEN) {
                 opts |= FsOptions::ZERO_MESSAGE_OPEN;
                 self.zero_message_open.store(true, Ordering::Relaxed);
@@ -1738,8 +1915,9 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let _trace = fs_trace!(self.tag, "mkdir", parent, name, mode, umask);
         let data = self.find_inode(parent)?;
+        let whiteout = self.prepare_upper_name(&data, name)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let creds = self.set_ctx_creds(&ctx)?;
         {
             let casefold_cache = self.lock_casefold_lookup_caches();
             let _scoped_umask = ScopedUmask::new(umask);
@@ -1750,12 +1928,23 @@ impl FileSystem for PassthroughFs {
                 c.insert(data.inode, name);
             }
         }
+        // A directory replacing a deleted lower entry must hide the lower contents. The marker is
+        // created with the device's credentials since the guest may not be allowed to write to the
+        // new directory.
+        drop(creds);
+        if whiteout {
+            self.set_opaque_at(&data, name)?;
+            self.remove_whiteout_at(&data, name)?;
+        }
         self.do_lookup(&data, name)
     }
 
     fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
         let _trace = fs_trace!(self.tag, "rmdir", parent, name);
         let data = self.find_inode(parent)?;
+        if self.overlay.is_some() {
+            return self.overlay_remove(&data, name, libc::AT_REMOVEDIR);
+        }
         let casefold_cache = self.lock_casefold_lookup_caches();
         // TODO(b/278691962): If ascii_casefold is enabled, we need to call
         // `lookup_case_unfolded_name()` to get the actual name to be unlinked.
@@ -1775,17 +1964,24 @@ impl FileSystem for PassthroughFs {
         offset: u64,
     ) -> io::Result<Self::DirIter> {
         let _trace = fs_trace!(self.tag, "readdir", inode, handle, size, offset);
+        if self.overlay.is_some() {
+            let data = self.find_inode(inode)?;
+            return self
+                .overlay_readdir(&data, offset)
+                .map(PassthroughDirIter::Overlay);
+        }
+
         let buf = vec![0; size as usize].into_boxed_slice();
 
         if self.zero_message_opendir.load(Ordering::Relaxed) {
             let data = self.find_inode(inode)?;
-            ReadDir::new(&*data, offset as libc::off64_t, buf)
+            ReadDir::new(&*data, offset as libc::off64_t, buf).map(PassthroughDirIter::ReadDir)
         } else {
             let data = self.find_handle(handle, inode)?;
 
             let dir = data.file.lock();
 
-            ReadDir::new(&*dir, offset as libc::off64_t, buf)
+            ReadDir::new(&*dir, offset as libc::off64_t, buf).map(PassthroughDirIter::ReadDir)
         }
     }
 
@@ -1832,8 +2028,9 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let _trace = fs_trace!(self.tag, "chromeos_tempfile", parent, mode, umask);
         let data = self.find_inode(parent)?;
+        self.copy_up(&data)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let (_uid, _gid) = self.set_ctx_creds(&ctx)?;
 
         let tmpflags = libc::O_RDWR | libc::O_TMPFILE | libc::O_CLOEXEC | libc::O_NOFOLLOW;
 
@@ -1864,7 +2061,8 @@ impl FileSystem for PassthroughFs {
             data.path.clone(),
             current_dir.to_str().unwrap_or("<non UTF-8 str>")
         );
-        Ok(self.add_entry(tmpfile, st, tmpflags, path))
+        let overlay = self.overlay_child(&data, Layer::Upper, current_dir);
+        Ok(self.add_entry(tmpfile, st, tmpflags, path, overlay))
     }
 
     fn create(
@@ -1878,7 +2076,8 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         let _trace = fs_trace!(self.tag, "create", parent, name, mode, flags, umask);
         let data = self.find_inode(parent)?;
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let whiteout = self.prepare_upper_name(&data, name)?;
+        let creds = self.set_ctx_creds(&ctx)?;
 
         let create_flags =
             (flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW) & !libc::O_DIRECT;
@@ -1904,13 +2103,19 @@ impl FileSystem for PassthroughFs {
         // Safe because we just opened this fd.
         let file = unsafe { File::from_raw_descriptor(fd) };
 
+        drop(creds);
+        if whiteout {
+            self.remove_whiteout_at(&data, name)?;
+        }
+
         let st = stat(&file)?;
         let path = format!(
             "{}/{}",
             data.path.clone(),
             name.to_str().unwrap_or("<non UTF-8 str>")
         );
-        let entry = self.add_entry(file, st, create_flags, path);
+        let overlay = self.overlay_child(&data, Layer::Upper, name);
+        let entry = self.add_entry(file, st, create_flags, path, overlay);
 
         let (handle, opts) = if self.zero_message_open.load(Ordering::Relaxed) {
             (None, OpenOptions::KEEP_CACHE)
@@ -1931,6 +2136,9 @@ impl FileSystem for PassthroughFs {
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
         let _trace = fs_trace!(self.tag, "unlink", parent, name);
         let data = self.find_inode(parent)?;
+        if self.overlay.is_some() {
+            return self.overlay_remove(&data, name, 0);
+        }
         let casefold_cache = self.lock_casefold_lookup_caches();
         // TODO(b/278691962): If ascii_casefold is enabled, we need to call
         // `lookup_case_unfolded_name()` to get the actual name to be unlinked.
@@ -2061,6 +2269,7 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<(libc::stat64, Duration)> {
         let _trace = fs_trace!(self.tag, "setattr", inode, handle);
         let inode_data = self.find_inode(inode)?;
+        self.copy_up(&inode_data)?;
 
         enum Data {
             Handle(Arc<HandleData>, RawDescriptor),
@@ -2093,13 +2302,13 @@ impl FileSystem for PassthroughFs {
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                self.cfg.uid_map.to_host(attr.st_uid).ok_or_else(einval)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                self.cfg.gid_map.to_host(attr.st_gid).ok_or_else(einval)?
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
@@ -2185,22 +2394,13 @@ impl FileSystem for PassthroughFs {
 
         let old_inode = self.find_inode(olddir)?;
         let new_inode = self.find_inode(newdir)?;
+        if self.overlay.is_some() {
+            return self.overlay_rename(&old_inode, oldname, &new_inode, newname, flags);
+        }
         {
             let casefold_cache = self.lock_casefold_lookup_caches();
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
-            // and we have glibc 2.28.
-            syscall!(unsafe {
-                libc::syscall(
-                    libc::SYS_renameat2,
-                    old_inode.as_raw_descriptor(),
-                    oldname.as_ptr(),
-                    new_inode.as_raw_descriptor(),
-                    newname.as_ptr(),
-                    flags,
-                )
-            })?;
+            self.do_rename(&old_inode, oldname, &new_inode, newname, flags)?;
             if let Some(mut c) = casefold_cache {
                 c.remove(olddir, oldname);
                 c.insert(newdir, newname);
@@ -2221,8 +2421,9 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let _trace = fs_trace!(self.tag, "mknod", parent, name, mode, rdev, umask);
         let data = self.find_inode(parent)?;
+        let whiteout = self.prepare_upper_name(&data, name)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let creds = self.set_ctx_creds(&ctx)?;
         {
             let _scoped_umask = ScopedUmask::new(umask);
             let casefold_cache = self.lock_casefold_lookup_caches();
@@ -2240,6 +2441,10 @@ impl FileSystem for PassthroughFs {
                 c.insert(parent, name);
             }
         }
+        drop(creds);
+        if whiteout {
+            self.remove_whiteout_at(&data, name)?;
+        }
 
         self.do_lookup(&data, name)
     }
@@ -2254,6 +2459,8 @@ impl FileSystem for PassthroughFs {
         let _trace = fs_trace!(self.tag, "link", inode, newparent, newname);
         let data = self.find_inode(inode)?;
         let new_inode = self.find_inode(newparent)?;
+        self.copy_up(&data)?;
+        let whiteout = self.prepare_upper_name(&new_inode, newname)?;
 
         let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -2274,6 +2481,9 @@ impl FileSystem for PassthroughFs {
                 c.insert(newparent, newname);
             }
         }
+        if whiteout {
+            self.remove_whiteout_at(&new_inode, newname)?;
+        }
 
         self.do_lookup(&new_inode, newname)
     }
@@ -2287,8 +2497,9 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let _trace = fs_trace!(self.tag, "symlink", parent, linkname, name);
         let data = self.find_inode(parent)?;
+        let whiteout = self.prepare_upper_name(&data, name)?;
 
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        let creds = self.set_ctx_creds(&ctx)?;
         {
             let casefold_cache = self.lock_casefold_lookup_caches();
             // Safe because this doesn't modify any memory and we check the return value.
@@ -2299,6 +2510,10 @@ impl FileSystem for PassthroughFs {
                 c.insert(parent, name);
             }
         }
+        drop(creds);
+        if whiteout {
+            self.remove_whiteout_at(&data, name)?;
+        }
 
         self.do_lookup(&data, name)
     }
@@ -2393,7 +2608,7 @@ impl FileSystem for PassthroughFs {
         let _trace = fs_trace!(self.tag, "access", inode, mask);
         let data = self.find_inode(inode)?;
 
-        let st = stat(&*data)?;
+        let st = self.guest_attr(stat(&*data)?);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -2451,7 +2666,9 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::EPERM));
         }
 
+        let value = self.map_acl_ids(name, value, true)?;
         let data = self.find_inode(inode)?;
+        self.copy_up(&data)?;
         let name = self.rewrite_xattr_name(name);
         let file = data.file.lock();
         let o_path_file = (file.1 & libc::O_PATH) != 0;
@@ -2506,16 +2723,18 @@ impl FileSystem for PassthroughFs {
         }
 
         let data = self.find_inode(inode)?;
-        let name = self.rewrite_xattr_name(name);
+        let xattr_name = self.rewrite_xattr_name(name);
         let mut buf = vec![0u8; size as usize];
 
         // Safe because this will only modify the contents of `buf`.
-        let res = self.do_getxattr(&data, &name, &mut buf[..])?;
+        let res = self.do_getxattr(&data, &xattr_name, &mut buf[..])?;
         if size == 0 {
             Ok(GetxattrReply::Count(res as u32))
         } else {
             buf.truncate(res);
-            Ok(GetxattrReply::Value(buf))
+            Ok(GetxattrReply::Value(
+                self.map_acl_ids(name, &buf, false)?.into_owned(),
+            ))
         }
     }
 
@@ -2575,6 +2794,7 @@ impl FileSystem for PassthroughFs {
         }
 
         let data = self.find_inode(inode)?;
+        self.copy_up(&data)?;
         let name = self.rewrite_xattr_name(name);
 
         let file = data.file.lock();
@@ -2609,6 +2829,7 @@ impl FileSystem for PassthroughFs {
         length: u64,
     ) -> io::Result<()> {
         let _trace = fs_trace!(self.tag, "fallocate", inode, handle, mode, offset, length);
+        self.copy_up_inode(inode)?;
 
         let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
             let data = self.find_inode(inode)?;
@@ -2688,6 +2909,7 @@ impl FileSystem for PassthroughFs {
                 if in_size < size_of::<fsxattr>() as u32 {
                     Err(io::Error::from_raw_os_error(libc::EINVAL))
                 } else {
+                    self.copy_up_inode(inode)?;
                     self.set_fsxattr(ctx, inode, handle, r)
                 }
             }
@@ -2702,6 +2924,7 @@ impl FileSystem for PassthroughFs {
                 if in_size < size_of::<c_int>() as u32 {
                     Err(io::Error::from_raw_os_error(libc::ENOMEM))
                 } else {
+                    self.copy_up_inode(inode)?;
                     self.set_flags(ctx, inode, handle, r)
                 }
             }
@@ -2709,6 +2932,7 @@ impl FileSystem for PassthroughFs {
                 if in_size < size_of::<fsverity_enable_arg>() as u32 {
                     Err(io::Error::from_raw_os_error(libc::ENOMEM))
                 } else {
+                    self.copy_up_inode(inode)?;
                     self.enable_verity(inode, handle, r)
                 }
             }
@@ -2751,7 +2975,8 @@ impl FileSystem for PassthroughFs {
         );
         // We need to change credentials during a write so that the kernel will remove setuid or
         // setgid bits from the file if it was written to by someone other than the owner.
-        let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        self.copy_up_inode(inode_dst)?;
+        let (_uid, _gid) = self.set_ctx_creds(&ctx)?;
         let (src_data, dst_data): (Arc<dyn AsRawDescriptor>, Arc<dyn AsRawDescriptor>) =
             if self.zero_message_open.load(Ordering::Relaxed) {
                 (self.find_inode(inode_src)?, self.find_inode(inode_dst)?)
@@ -2812,6 +3037,9 @@ impl FileSystem for PassthroughFs {
         };
 
         let data = self.find_inode(inode)?;
+        if write {
+            self.copy_up(&data)?;
+        }
 
         if self.zero_message_open.load(Ordering::Relaxed) {
             let mut file = data.file.lock();
@@ -2857,6 +3085,7 @@ impl FileSystem for PassthroughFs {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::MetadataExt;
     use std::path::Path;
 
     use named_lock::NamedLock;
@@ -2894,7 +3123,8 @@ mod tests {
     fn lookup(fs: &PassthroughFs, path: &Path) -> io::Result<Inode> {
         let mut inode = 1;
         let ctx = get_context();
-        for name in path.iter() {
+        // The root inode is `/` in tests.
+        for name in path.strip_prefix("/").unwrap_or(path).iter() {
             let name = CString::new(name.to_str().unwrap()).unwrap();
             let ent = match fs.lookup(ctx, inode, &name) {
                 Ok(ent) => ent,
@@ -3292,4 +3522,390 @@ mod tests {
             "Entry with inode=0 is expected for the removed file 'a.txt'"
         );
     }
+
+    #[test]
+    fn id_map() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        let host_ctx = get_context();
+        let cfg = Config {
+            uid_map: format!("1000 {} 1", host_ctx.uid).parse().unwrap(),
+            gid_map: format!("2000 {} 1", host_ctx.gid).parse().unwrap(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new("tag", cfg).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+
+        let ctx = Context {
+            uid: 1000,
+            gid: 2000,
+            pid: host_ctx.pid,
+        };
+        let parent = lookup(&fs, temp_dir.path()).expect("lookup temp dir");
+        let name = CString::new("a.txt").unwrap();
+        let (entry, _, _) = fs
+            .create(ctx, parent, &name, 0o666, libc::O_RDWR as u32, 0)
+            .expect("create a.txt");
+
+        // The file is owned by the host ids but the VM sees the mapped ids.
+        let metadata = std::fs::metadata(temp_dir.path().join("a.txt")).unwrap();
+        assert_eq!(metadata.uid(), host_ctx.uid);
+        assert_eq!(metadata.gid(), host_ctx.gid);
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (1000, 2000));
+        let (st, _) = fs.getattr(ctx, entry.inode, None).expect("getattr");
+        assert_eq!((st.st_uid, st.st_gid), (1000, 2000));
+
+        // Changing the owner to the mapped ids works, but unmapped ids are rejected.
+        // Safe because zero-initialized `stat64` is a valid value.
+        let mut attr = unsafe { MaybeUninit::<libc::stat64>::zeroed().assume_init() };
+        attr.st_uid = 1000;
+        attr.st_gid = 2000;
+        let (st, _) = fs
+            .setattr(
+                ctx,
+                entry.inode,
+                attr,
+                None,
+                SetattrValid::UID | SetattrValid::GID,
+            )
+            .expect("chown a.txt");
+        assert_eq!((st.st_uid, st.st_gid), (1000, 2000));
+        attr.st_uid = 1001;
+        assert_eq!(
+            fs.setattr(ctx, entry.inode, attr, None, SetattrValid::UID)
+                .expect_err("uid 1001 is not mapped")
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+
+        // Callers with unmapped ids cannot create files.
+        let unmapped_ctx = Context { uid: 0, ..ctx };
+        let name = CString::new("b.txt").unwrap();
+        assert_eq!(
+            fs.create(unmapped_ctx, parent, &name, 0o666, libc::O_RDWR as u32, 0)
+                .map(|_| ())
+                .expect_err("uid 0 is not mapped")
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn id_map_acl_xattrs() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let cfg = Config {
+            uid_map: "1000 5000 1".parse().unwrap(),
+            gid_map: "2000 6000 1".parse().unwrap(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new("tag", cfg).unwrap();
+
+        // Builds an ACL xattr value from (tag, id) entries.
+        let acl = |entries: &[(u16, u32)]| {
+            let mut value = 2u32.to_le_bytes().to_vec();
+            for (tag, id) in entries {
+                value.extend_from_slice(&tag.to_le_bytes());
+                value.extend_from_slice(&6u16.to_le_bytes());
+                value.extend_from_slice(&id.to_le_bytes());
+            }
+            value
+        };
+        let access = CStr::from_bytes_with_nul(b"system.posix_acl_access\0").unwrap();
+        let default = CStr::from_bytes_with_nul(b"system.posix_acl_default\0").unwrap();
+        let user = CStr::from_bytes_with_nul(b"user.foobar\0").unwrap();
+
+        // Only the ids of the named user and group entries are translated.
+        let guest = acl(&[(0x01, 7), (ACL_USER, 1000), (ACL_GROUP, 2000), (0x20, 7)]);
+        let host = acl(&[(0x01, 7), (ACL_USER, 5000), (ACL_GROUP, 6000), (0x20, 7)]);
+        assert_eq!(fs.map_acl_ids(access, &guest, true).unwrap(), host);
+        assert_eq!(fs.map_acl_ids(default, &host, false).unwrap(), guest);
+
+        // Unmapped ids are rejected from the VM and reported as the overflow id to it.
+        assert_eq!(
+            fs.map_acl_ids(access, &acl(&[(ACL_USER, 1001)]), true)
+                .expect_err("uid 1001 is not mapped")
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(
+            fs.map_acl_ids(access, &acl(&[(ACL_GROUP, 1)]), false)
+                .unwrap(),
+            acl(&[(ACL_GROUP, OVERFLOW_ID)])
+        );
+
+        // Other xattrs are passed through unchanged.
+        assert_eq!(fs.map_acl_ids(user, &guest, true).unwrap(), guest);
+    }
+
+    /// Creates the given directories and files in `lower`, below the path of `upper`. The root of
+    /// the file system is `/` in tests, so this is where the overlay finds the lower entries of
+    /// `upper`.
+    fn create_lower_data(lower: &TempDir, upper: &TempDir, dirs: &[&str], files: &[(&str, &str)]) {
+        let path = lower.path().join(upper.path().strip_prefix("/").unwrap());
+        std::fs::create_dir_all(&path).unwrap();
+
+        for d in dirs {
+            std::fs::create_dir_all(path.join(d)).unwrap();
+        }
+
+        for (f, contents) in files {
+            std::fs::write(path.join(f), contents).unwrap();
+        }
+    }
+
+    /// Returns the path of `path` in `lower`.
+    fn lower_path(lower: &TempDir, path: &Path) -> std::path::PathBuf {
+        lower.path().join(path.strip_prefix("/").unwrap())
+    }
+
+    /// Creates a `PassthroughFs` with `lower` as its lower directory.
+    fn create_overlay_fs(lower: &TempDir) -> PassthroughFs {
+        let cfg = Config {
+            lower: Some(lower.path().to_path_buf()),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new("tag", cfg).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        fs
+    }
+
+    /// Returns the names in the directory at `path`, except for `.` and `..`.
+    fn read_dir_names(fs: &PassthroughFs, path: &Path) -> Vec<String> {
+        let ctx = get_context();
+        let inode = lookup(fs, path).expect("lookup directory");
+        let (handle, _) = fs.opendir(ctx, inode, 0).expect("opendir");
+        let mut entries = fs
+            .readdir(ctx, inode, handle.unwrap_or(0), 4096, 0)
+            .expect("readdir");
+
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next() {
+            let name = entry.name.to_str().unwrap();
+            if name != "." && name != ".." {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn overlay_copy_up() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        create_lower_data(&lower, &upper, &["dir"], &[("dir/a.txt", "lower")]);
+        let fs = create_overlay_fs(&lower);
+        let ctx = get_context();
+
+        let a_path = upper.path().join("dir/a.txt");
+        let inode = lookup(&fs, &a_path).expect("lookup a.txt");
+        fs.open(ctx, inode, libc::O_RDONLY as u32)
+            .expect("open a.txt");
+        assert!(
+            !upper.path().join("dir").exists(),
+            "reading must not copy up"
+        );
+
+        // Truncating the file copies it and its parent directory up.
+        // Safe because zero-initialized `stat64` is a valid value.
+        let attr = unsafe { MaybeUninit::<libc::stat64>::zeroed().assume_init() };
+        let (st, _) = fs
+            .setattr(ctx, inode, attr, None, SetattrValid::SIZE)
+            .expect("truncate a.txt");
+        assert_eq!(st.st_size, 0);
+        assert_eq!(std::fs::read_to_string(&a_path).unwrap(), "");
+        assert_eq!(
+            std::fs::read_to_string(lower_path(&lower, &a_path)).unwrap(),
+            "lower",
+            "the lower file must not be modified"
+        );
+        assert_eq!(lookup(&fs, &a_path).expect("lookup a.txt"), inode);
+    }
+
+    #[test]
+    fn overlay_whiteout() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        create_test_data(&upper, &[], &["c.txt"]);
+        create_lower_data(&lower, &upper, &[], &[("a.txt", "a"), ("b.txt", "b")]);
+        let fs = create_overlay_fs(&lower);
+
+        assert_eq!(
+            read_dir_names(&fs, upper.path()),
+            ["a.txt", "b.txt", "c.txt"]
+        );
+
+        // Removing a lower file hides it without touching the lower directory.
+        let a_path = upper.path().join("a.txt");
+        unlink(&fs, &a_path).expect("remove a.txt");
+        assert_eq!(
+            lookup(&fs, &a_path)
+                .expect_err("file must not exist")
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(lower_path(&lower, &a_path).exists());
+        assert_eq!(read_dir_names(&fs, upper.path()), ["b.txt", "c.txt"]);
+
+        // A failed creation keeps the file hidden.
+        let parent = lookup(&fs, upper.path()).expect("lookup upper");
+        let a = CString::new("a.txt").unwrap();
+        fs.mknod(get_context(), parent, &a, libc::S_IFDIR | 0o755, 0, 0)
+            .map(|_| ())
+            .expect_err("mknod cannot create directories");
+        assert_eq!(
+            lookup(&fs, &a_path)
+                .expect_err("file must not exist")
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // A new file can replace the removed one.
+        create(&fs, &a_path).expect("create a.txt");
+        assert_eq!(
+            read_dir_names(&fs, upper.path()),
+            ["a.txt", "b.txt", "c.txt"]
+        );
+        assert_eq!(std::fs::read_to_string(&a_path).unwrap(), "");
+
+        // Lower files cannot be created again.
+        assert_eq!(
+            create(&fs, &upper.path().join("b.txt"))
+                .map(|_| ())
+                .expect_err("b.txt exists")
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn overlay_opaque_dir() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        create_lower_data(
+            &lower,
+            &upper,
+            &["d", "d/sub"],
+            &[("d/x.txt", "x"), ("d/sub/y.txt", "y")],
+        );
+        let fs = create_overlay_fs(&lower);
+        let ctx = get_context();
+
+        let parent = lookup(&fs, upper.path()).expect("lookup upper");
+        let d = CString::new("d").unwrap();
+        let sub = CString::new("sub").unwrap();
+        assert_eq!(
+            fs.rmdir(ctx, parent, &d)
+                .expect_err("d is not empty")
+                .raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+
+        unlink(&fs, &upper.path().join("d/x.txt")).expect("remove x.txt");
+        unlink(&fs, &upper.path().join("d/sub/y.txt")).expect("remove y.txt");
+        let d_inode = lookup(&fs, &upper.path().join("d")).expect("lookup d");
+        fs.rmdir(ctx, d_inode, &sub).expect("remove sub");
+        fs.rmdir(ctx, parent, &d).expect("remove d");
+        assert!(read_dir_names(&fs, upper.path()).is_empty());
+
+        // A new directory with the same name doesn't show the lower contents.
+        let d_entry = fs.mkdir(ctx, parent, &d, 0o755, 0).expect("create d");
+        assert!(read_dir_names(&fs, &upper.path().join("d")).is_empty());
+        assert_eq!(
+            lookup(&fs, &upper.path().join("d/x.txt"))
+                .expect_err("file must not exist")
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Neither do the new directories below it.
+        fs.mkdir(ctx, d_entry.inode, &sub, 0o755, 0)
+            .expect("create sub");
+        assert!(read_dir_names(&fs, &upper.path().join("d/sub")).is_empty());
+
+        // The contents stay hidden after the directory is renamed.
+        let e = CString::new("e").unwrap();
+        fs.rename(ctx, parent, &d, parent, &e, 0).expect("rename d");
+        assert_eq!(read_dir_names(&fs, upper.path()), ["e"]);
+        assert!(read_dir_names(&fs, &upper.path().join("e/sub")).is_empty());
+    }
+
+    #[test]
+    fn overlay_rename() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        create_lower_data(&lower, &upper, &["d"], &[("a.txt", "a")]);
+        let fs = create_overlay_fs(&lower);
+        let ctx = get_context();
+
+        let parent = lookup(&fs, upper.path()).expect("lookup upper");
+        let a = CString::new("a.txt").unwrap();
+        let b = CString::new("b.txt").unwrap();
+        let d = CString::new("d").unwrap();
+        let e = CString::new("e").unwrap();
+
+        // Directories with lower contents cannot be renamed.
+        assert_eq!(
+            fs.rename(ctx, parent, &d, parent, &e, 0)
+                .expect_err("d is a lower directory")
+                .raw_os_error(),
+            Some(libc::EXDEV)
+        );
+
+        fs.rename(ctx, parent, &a, parent, &b, 0)
+            .expect("rename a.txt");
+        assert_eq!(read_dir_names(&fs, upper.path()), ["b.txt", "d"]);
+        assert_eq!(
+            std::fs::read_to_string(upper.path().join("b.txt")).unwrap(),
+            "a"
+        );
+        assert!(lower_path(&lower, &upper.path().join("a.txt")).exists());
+    }
+
+    #[test]
+    fn overlay_lookup_parent() {
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let upper = TempDir::new().unwrap();
+        let lower = TempDir::new().unwrap();
+        create_lower_data(&lower, &upper, &["d"], &[]);
+        let fs = create_overlay_fs(&lower);
+        let ctx = get_context();
+
+        // `..` must not lead out of the lower directory, from its root or from a lower-only
+        // directory.
+        let dir = lookup(&fs, &upper.path().join("d")).expect("lookup d");
+        for (parent, name) in [
+            (ROOT_ID, ".."),
+            (dir, ".."),
+            (dir, "."),
+            (ROOT_ID, "tmp/.."),
+        ] {
+            let name = CString::new(name).unwrap();
+            assert_eq!(
+                fs.lookup(ctx, parent, &name)
+                    .map(|_| ())
+                    .expect_err("lookup must fail")
+                    .raw_os_error(),
+                Some(libc::EINVAL)
+            );
+        }
+    }
 }
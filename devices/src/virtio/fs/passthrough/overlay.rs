@@ -0,0 +1,1147 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Copy-on-write overlay support for `PassthroughFs`.
+//!
+//! When a lower directory is configured, the directory served by `PassthroughFs` becomes the
+//! writable "upper" layer of an overlay and the lower directory is never modified:
+//!
+//! * Lookups return the upper entry if there is one and fall back to the lower directory
+//!   otherwise, unless the name was deleted or the upper directory is opaque.
+//! * Lower entries are copied up to the upper directory (contents, owner, mode, timestamps and
+//!   extended attributes) the first time they are modified. Missing parent directories are copied
+//!   up first.
+//! * Deleting an entry that exists in the lower directory leaves a whiteout in the upper
+//!   directory. Whiteouts are `.wh.<name>` files and directories that hide the lower contents are
+//!   marked with a `.wh..wh..opq` file. This is the on-disk format used by aufs and
+//!   fuse-overlayfs and, unlike the character devices used by the kernel's overlayfs, it doesn't
+//!   require any privilege to create. 0/0 character devices are still recognized as whiteouts.
+//! * Reading a directory returns a snapshot of the merged contents of both layers.
+//!
+//! Like the kernel's overlayfs without `redirect_dir`, renaming a directory that has contents in
+//! the lower layer fails with `EXDEV` and user space is expected to fall back to copying it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::ptr;
+
+use base::syscall;
+use base::warn;
+use base::AsRawDescriptor;
+use base::FromRawDescriptor;
+use base::RawDescriptor;
+use fuse::filesystem::DirEntry;
+use fuse::filesystem::DirectoryIterator;
+use fuse::filesystem::Entry;
+use fuse::filesystem::ROOT_ID;
+use sync::Mutex;
+
+use super::forget_one;
+use super::stat;
+use super::statat;
+use super::FileType;
+use super::Inode;
+use super::InodeAltKey;
+use super::InodeData;
+use super::PassthroughFs;
+use crate::virtio::fs::read_dir::ReadDir;
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq\0";
+const CURRENT_DIR: &[u8] = b".\0";
+
+const READ_DIR_BUF_SIZE: usize = 4096;
+
+fn opaque_marker() -> &'static CStr {
+    // Safe because this is a constant value and a valid C string.
+    unsafe { CStr::from_bytes_with_nul_unchecked(OPAQUE_MARKER) }
+}
+
+fn current_dir() -> &'static CStr {
+    // Safe because this is a constant value and a valid C string.
+    unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR) }
+}
+
+fn errno(e: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(e)
+}
+
+/// The layer an overlay inode currently lives in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Upper,
+    Lower,
+}
+
+/// Per-inode overlay state.
+#[derive(Debug)]
+pub struct OverlayNode {
+    layer: Layer,
+    // Path of the inode relative to the root of both layers, used to find the matching lower entry.
+    // Renaming a directory updates the paths of the inodes below it.
+    path: CString,
+}
+
+impl OverlayNode {
+    /// Returns the node for the root directory, which always exists in the upper layer.
+    pub fn root() -> OverlayNode {
+        OverlayNode {
+            layer: Layer::Upper,
+            path: current_dir().to_owned(),
+        }
+    }
+
+    /// Returns the node for the entry `name` of this directory, living in `layer`.
+    pub fn child(&self, layer: Layer, name: &CStr) -> OverlayNode {
+        let path = if self.path.as_bytes() == b"." {
+            name.to_bytes().to_vec()
+        } else {
+            [self.path.as_bytes(), b"/", name.to_bytes()].concat()
+        };
+
+        OverlayNode {
+            layer,
+            // Neither part contains nul bytes so the unwrap is safe.
+            path: CString::new(path).expect("overlay path contains a nul byte"),
+        }
+    }
+
+    /// Moves this node below `new_path` if it is `old_path` or one of its descendants.
+    fn rebase(&mut self, old_path: &CStr, new_path: &CStr) {
+        let suffix = match self.path.as_bytes().strip_prefix(old_path.to_bytes()) {
+            Some(suffix) if suffix.is_empty() || suffix.starts_with(b"/") => suffix,
+            _ => return,
+        };
+        // Neither part contains nul bytes so the unwrap is safe.
+        self.path = CString::new([new_path.to_bytes(), suffix].concat())
+            .expect("overlay path contains a nul byte");
+    }
+}
+
+/// The read-only lower layer of a `PassthroughFs`.
+pub struct Overlay {
+    // Root of the lower directory, opened with `O_PATH`.
+    lower: File,
+    // Serializes copy-ups so that the same entry is never copied twice.
+    copy_up_lock: Mutex<()>,
+}
+
+impl Overlay {
+    pub fn new(lower: &Path) -> io::Result<Overlay> {
+        let lower = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(lower)?;
+
+        Ok(Overlay {
+            lower,
+            copy_up_lock: Mutex::new(()),
+        })
+    }
+
+    /// Opens the lower directory at `path`. Returns `None` if there is no directory at `path` in
+    /// the lower layer.
+    fn lower_dir(&self, path: &CStr) -> io::Result<Option<File>> {
+        match open_dir_path(&self.lower, path) {
+            Ok(dir) => Ok(Some(dir)),
+            Err(e) => match e.raw_os_error() {
+                Some(libc::ENOENT) | Some(libc::ENOTDIR) | Some(libc::ELOOP) => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+impl AsRawDescriptor for Overlay {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.lower.as_raw_descriptor()
+    }
+}
+
+/// An entry of a merged directory snapshot.
+struct OverlayDirEntry {
+    ino: libc::ino64_t,
+    type_: u32,
+    name: CString,
+}
+
+/// Iterates over a snapshot of the merged contents of an overlay directory. The offset of each
+/// entry is its position in the snapshot.
+pub struct OverlayDirIter {
+    entries: Vec<OverlayDirEntry>,
+    next: usize,
+}
+
+impl OverlayDirIter {
+    fn new(entries: Vec<OverlayDirEntry>, offset: u64) -> OverlayDirIter {
+        OverlayDirIter {
+            entries,
+            next: usize::try_from(offset).unwrap_or(usize::MAX),
+        }
+    }
+}
+
+impl DirectoryIterator for OverlayDirIter {
+    fn next(&mut self) -> Option<DirEntry> {
+        let index = self.next;
+        let entry = self.entries.get(index)?;
+        self.next = index + 1;
+
+        Some(DirEntry {
+            ino: entry.ino,
+            offset: self.next as u64,
+            type_: entry.type_,
+            name: &entry.name,
+        })
+    }
+}
+
+/// Returns true if `name` is reserved for whiteouts and other overlay metadata.
+pub fn is_reserved_name(name: &CStr) -> bool {
+    name.to_bytes().starts_with(WHITEOUT_PREFIX)
+}
+
+/// Returns an error unless `name` is a single path component other than `.` and `..`. Names
+/// from the guest are resolved relative to the lower directory, which is opened before the device
+/// is jailed, so `..` would reach the host file system above it.
+fn check_name(name: &CStr) -> io::Result<()> {
+    match name.to_bytes() {
+        b"" | b"." | b".." => Err(errno(libc::EINVAL)),
+        bytes if bytes.contains(&b'/') => Err(errno(libc::EINVAL)),
+        _ => Ok(()),
+    }
+}
+
+fn whiteout_name(name: &CStr) -> CString {
+    // Neither part contains nul bytes so the unwrap is safe.
+    CString::new([WHITEOUT_PREFIX, name.to_bytes()].concat())
+        .expect("whiteout name contains a nul byte")
+}
+
+fn is_whiteout(st: &libc::stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFCHR && st.st_rdev == 0
+}
+
+fn openat<D: AsRawDescriptor + ?Sized>(
+    dir: &D,
+    name: &CStr,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = syscall!(unsafe {
+        libc::openat64(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode,
+        )
+    })?;
+
+    // Safe because we just opened this descriptor.
+    Ok(unsafe { File::from_raw_descriptor(fd) })
+}
+
+fn unlinkat<D: AsRawDescriptor + ?Sized>(
+    dir: &D,
+    name: &CStr,
+    flags: libc::c_int,
+) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe { libc::unlinkat(dir.as_raw_descriptor(), name.as_ptr(), flags) })?;
+    Ok(())
+}
+
+fn exists_at<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<bool> {
+    match statat(dir, name) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Returns the path components of `path`, skipping empty and `.` components.
+fn components(path: &CStr) -> impl Iterator<Item = CString> + '_ {
+    path.to_bytes()
+        .split(|&b| b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        // The components of a C string don't contain nul bytes so the unwrap is safe.
+        .map(|c| CString::new(c).expect("path component contains a nul byte"))
+}
+
+// Splits `path` into its parent directory and its last component.
+fn split_path(path: &CStr) -> (CString, CString) {
+    let bytes = path.to_bytes();
+    let (parent, name) = match bytes.iter().rposition(|&b| b == b'/') {
+        Some(pos) => (&bytes[..pos], &bytes[pos + 1..]),
+        None => (&b"."[..], bytes),
+    };
+
+    // Both parts come from a C string so they don't contain nul bytes.
+    (
+        CString::new(parent).expect("path contains a nul byte"),
+        CString::new(name).expect("path contains a nul byte"),
+    )
+}
+
+// Opens the directory at `path` relative to `root` with `O_PATH`, without following symlinks.
+fn open_dir_path(root: &File, path: &CStr) -> io::Result<File> {
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+    let mut dir = openat(root, current_dir(), flags, 0)?;
+    for component in components(path) {
+        check_name(&component)?;
+        dir = openat(&dir, &component, flags, 0)?;
+    }
+    Ok(dir)
+}
+
+// Opens `dir` again for reading its entries.
+fn reopen_dir<D: AsRawDescriptor + ?Sized>(dir: &D) -> io::Result<File> {
+    openat(dir, current_dir(), libc::O_RDONLY | libc::O_DIRECTORY, 0)
+}
+
+/// Returns true if `name` was deleted from the upper directory `dir`.
+fn is_whited_out<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<bool> {
+    match statat(dir, name) {
+        Ok(st) => return Ok(is_whiteout(&st)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+        Err(e) => return Err(e),
+    }
+    exists_at(dir, &whiteout_name(name))
+}
+
+/// Returns true if the upper directory `dir` hides the contents of the matching lower directory.
+fn is_opaque<D: AsRawDescriptor + ?Sized>(dir: &D) -> io::Result<bool> {
+    exists_at(dir, opaque_marker())
+}
+
+fn create_whiteout<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<()> {
+    openat(
+        dir,
+        &whiteout_name(name),
+        libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW,
+        0o600,
+    )?;
+    Ok(())
+}
+
+// Frees `name` in the upper directory `dir` for a new entry while keeping it deleted, by replacing a
+// character device whiteout with a `.wh.` file. Returns true if `name` is whited out.
+fn set_whiteout_aside<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<bool> {
+    match statat(dir, name) {
+        Ok(st) if is_whiteout(&st) => {
+            create_whiteout(dir, name)?;
+            unlinkat(dir, name, 0)?;
+            Ok(true)
+        }
+        Ok(_) => Ok(false),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => exists_at(dir, &whiteout_name(name)),
+        Err(e) => Err(e),
+    }
+}
+
+fn set_opaque<D: AsRawDescriptor + ?Sized>(dir: &D) -> io::Result<()> {
+    openat(
+        dir,
+        opaque_marker(),
+        libc::O_WRONLY | libc::O_CREAT | libc::O_NOFOLLOW,
+        0o600,
+    )?;
+    Ok(())
+}
+
+// Returns true if `entry` of the upper directory `dir` is a character device whiteout.
+fn is_whiteout_entry(dir: &File, entry: &OverlayDirEntry) -> bool {
+    let type_ = entry.type_ as u8;
+    (type_ == libc::DT_CHR || type_ == libc::DT_UNKNOWN)
+        && statat(dir, &entry.name).map_or(false, |st| is_whiteout(&st))
+}
+
+// Removes the whiteouts and the opaque marker of the upper directory `dir`.
+fn clear_whiteouts(dir: &File) -> io::Result<()> {
+    for entry in read_entries(dir)? {
+        if is_reserved_name(&entry.name) || is_whiteout_entry(dir, &entry) {
+            unlinkat(dir, &entry.name, 0)?;
+        }
+    }
+    Ok(())
+}
+
+// Reads all the entries of `dir`, which must have been opened for reading.
+fn read_entries(dir: &File) -> io::Result<Vec<OverlayDirEntry>> {
+    let mut entries = Vec::new();
+    let mut buf = vec![0u8; READ_DIR_BUF_SIZE];
+    let mut offset = 0;
+    loop {
+        let mut read_dir = ReadDir::new(dir, offset, &mut buf[..])?;
+        let mut done = true;
+        while let Some(entry) = read_dir.next() {
+            done = false;
+            offset = entry.offset as libc::off64_t;
+            entries.push(OverlayDirEntry {
+                ino: entry.ino,
+                type_: entry.type_,
+                name: entry.name.to_owned(),
+            });
+        }
+        if done {
+            return Ok(entries);
+        }
+    }
+}
+
+// Merges the entries of the upper and lower directories, hiding whiteouts, overlay metadata and
+// lower entries that are shadowed or deleted. Entries are sorted by name.
+fn merge_entries(upper: Option<&File>, lower: Option<&File>) -> io::Result<Vec<OverlayDirEntry>> {
+    let mut merged = BTreeMap::new();
+    let mut whiteouts = BTreeSet::new();
+
+    if let Some(upper) = upper {
+        for entry in read_entries(upper)? {
+            let name = entry.name.to_bytes();
+            if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                whiteouts.insert(hidden.to_vec());
+            } else if is_whiteout_entry(upper, &entry) {
+                whiteouts.insert(name.to_vec());
+            } else {
+                merged.insert(name.to_vec(), entry);
+            }
+        }
+    }
+
+    if let Some(lower) = lower {
+        for entry in read_entries(lower)? {
+            let name = entry.name.to_bytes();
+            if is_reserved_name(&entry.name)
+                || whiteouts.contains(name)
+                || merged.contains_key(name)
+            {
+                continue;
+            }
+            merged.insert(name.to_vec(), entry);
+        }
+    }
+
+    Ok(merged.into_values().collect())
+}
+
+fn readlinkat<D: AsRawDescriptor + ?Sized>(dir: &D, name: &CStr) -> io::Result<CString> {
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    // Safe because the kernel will only write to `buf`, which is large enough for any link
+    // target, and we check the return value.
+    let len = syscall!(unsafe {
+        libc::readlinkat(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    })?;
+    buf.truncate(len as usize);
+
+    CString::new(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Copies the extended attributes of `src` to `dst`. Attributes in namespaces that cannot be set
+// from the device's user namespace are skipped.
+fn copy_xattrs(src: &File, dst: &File) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let len =
+        match syscall!(unsafe { libc::flistxattr(src.as_raw_descriptor(), ptr::null_mut(), 0) }) {
+            Ok(len) => len as usize,
+            Err(e) if e.errno() == libc::ENOTSUP => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+    if len == 0 {
+        return Ok(());
+    }
+
+    let mut names = vec![0u8; len];
+    // Safe because the kernel will only write to `names` and we check the return value.
+    let len = syscall!(unsafe {
+        libc::flistxattr(
+            src.as_raw_descriptor(),
+            names.as_mut_ptr() as *mut libc::c_char,
+            names.len(),
+        )
+    })?;
+    names.truncate(len as usize);
+
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        // The names are separated by nul bytes so they don't contain any.
+        let name = CString::new(name).expect("xattr name contains a nul byte");
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let size = syscall!(unsafe {
+            libc::fgetxattr(src.as_raw_descriptor(), name.as_ptr(), ptr::null_mut(), 0)
+        })?;
+        let mut value = vec![0u8; size as usize];
+        // Safe because the kernel will only write to `value` and we check the return value.
+        let size = syscall!(unsafe {
+            libc::fgetxattr(
+                src.as_raw_descriptor(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        })?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        match syscall!(unsafe {
+            libc::fsetxattr(
+                dst.as_raw_descriptor(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                size as usize,
+                0,
+            )
+        }) {
+            Ok(_) => {}
+            Err(e) if e.errno() == libc::EPERM || e.errno() == libc::ENOTSUP => {
+                warn!("failed to copy up xattr {:?}: {}", name, e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+// Gives the upper entry `name` of `dir` the owner, mode and timestamps described by `st`.
+fn copy_metadata<D: AsRawDescriptor + ?Sized>(
+    dir: &D,
+    name: &CStr,
+    st: &libc::stat64,
+) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe {
+        libc::fchownat(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            st.st_uid,
+            st.st_gid,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+
+    // The mode of a symlink cannot be changed. This must happen after the ownership change since
+    // that clears the setuid and setgid bits.
+    if st.st_mode & libc::S_IFMT != libc::S_IFLNK {
+        // Safe because this doesn't modify any memory and we check the return value.
+        syscall!(unsafe {
+            libc::fchmodat(
+                dir.as_raw_descriptor(),
+                name.as_ptr(),
+                st.st_mode & 0o7777,
+                0,
+            )
+        })?;
+    }
+
+    let times = [
+        libc::timespec {
+            tv_sec: st.st_atime,
+            tv_nsec: st.st_atime_nsec,
+        },
+        libc::timespec {
+            tv_sec: st.st_mtime,
+            tv_nsec: st.st_mtime_nsec,
+        },
+    ];
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe {
+        libc::utimensat(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+
+    Ok(())
+}
+
+// Creates a copy of the lower non-directory entry `name` of `lower_dir` as `tmpname` in
+// `upper_dir`.
+fn copy_file(
+    lower_dir: &File,
+    upper_dir: &File,
+    name: &CStr,
+    st: &libc::stat64,
+    tmpname: &CStr,
+) -> io::Result<()> {
+    match st.st_mode & libc::S_IFMT {
+        libc::S_IFREG => {
+            let mut src = openat(lower_dir, name, libc::O_RDONLY | libc::O_NOFOLLOW, 0)?;
+            let mut dst = openat(
+                upper_dir,
+                tmpname,
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW,
+                0o600,
+            )?;
+            io::copy(&mut src, &mut dst)?;
+            copy_xattrs(&src, &dst)
+        }
+        libc::S_IFLNK => {
+            let target = readlinkat(lower_dir, name)?;
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::symlinkat(
+                    target.as_ptr(),
+                    upper_dir.as_raw_descriptor(),
+                    tmpname.as_ptr(),
+                )
+            })?;
+            Ok(())
+        }
+        _ => {
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::mknodat(
+                    upper_dir.as_raw_descriptor(),
+                    tmpname.as_ptr(),
+                    st.st_mode,
+                    st.st_rdev,
+                )
+            })?;
+            Ok(())
+        }
+    }
+}
+
+// Copies the lower directory `name` of `lower_dir`, described by `st`, to `upper_dir` without its
+// contents.
+fn copy_up_dir(
+    lower_dir: &File,
+    upper_dir: &File,
+    name: &CStr,
+    st: &libc::stat64,
+) -> io::Result<()> {
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe { libc::mkdirat(upper_dir.as_raw_descriptor(), name.as_ptr(), 0o700) })?;
+    let res = openat(lower_dir, name, flags, 0).and_then(|src| {
+        copy_xattrs(&src, &openat(upper_dir, name, flags, 0)?)?;
+        copy_metadata(upper_dir, name, st)
+    });
+    if res.is_err() {
+        let _ = unlinkat(upper_dir, name, libc::AT_REMOVEDIR);
+    }
+    res
+}
+
+// Copies the lower entry `name` of `lower_dir`, described by `st`, to `upper_dir`. Entries other
+// than directories are first created as `tmpname` and then renamed so that a partial copy is never
+// visible.
+fn copy_up_entry(
+    lower_dir: &File,
+    upper_dir: &File,
+    name: &CStr,
+    st: &libc::stat64,
+    tmpname: &CStr,
+) -> io::Result<()> {
+    if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        return copy_up_dir(lower_dir, upper_dir, name, st);
+    }
+
+    let res = copy_file(lower_dir, upper_dir, name, st, tmpname)
+        .and_then(|()| copy_metadata(upper_dir, tmpname, st))
+        .and_then(|()| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::renameat(
+                    upper_dir.as_raw_descriptor(),
+                    tmpname.as_ptr(),
+                    upper_dir.as_raw_descriptor(),
+                    name.as_ptr(),
+                )
+            })?;
+            Ok(())
+        });
+    if res.is_err() {
+        let _ = unlinkat(upper_dir, tmpname, 0);
+    }
+    res
+}
+
+impl PassthroughFs {
+    fn lower_layer(&self) -> &Overlay {
+        self.overlay.as_ref().expect("overlay is not enabled")
+    }
+
+    // Returns the layer and path of an inode. Panics if the overlay is not enabled.
+    fn overlay_state(data: &InodeData) -> (Layer, CString) {
+        let node = data
+            .overlay
+            .as_ref()
+            .expect("inode has no overlay state")
+            .lock();
+        (node.layer, node.path.clone())
+    }
+
+    /// Returns the overlay state of the entry `name` of `parent` in `layer`, or `None` if the
+    /// overlay is not enabled.
+    pub(super) fn overlay_child(
+        &self,
+        parent: &InodeData,
+        layer: Layer,
+        name: &CStr,
+    ) -> Option<OverlayNode> {
+        parent
+            .overlay
+            .as_ref()
+            .map(|node| node.lock().child(layer, name))
+    }
+
+    // Opens the upper and lower directories at `path` with `O_PATH`, copying up any directory
+    // that is missing from the upper layer.
+    fn overlay_dirs(&self, path: &CStr) -> io::Result<(File, File)> {
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+        let root = self.find_inode(ROOT_ID)?;
+        let mut upper = openat(&*root, current_dir(), flags, 0)?;
+        let mut lower = openat(&self.lower_layer().lower, current_dir(), flags, 0)?;
+
+        for component in components(path) {
+            check_name(&component)?;
+            // Lower entries below an opaque directory are no longer visible.
+            if is_opaque(&upper)? {
+                return Err(errno(libc::ESTALE));
+            }
+
+            let next_lower = openat(&lower, &component, flags, 0)?;
+            let next_upper = match openat(&upper, &component, flags, 0) {
+                Ok(dir) => dir,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                    if is_whited_out(&upper, &component)? {
+                        return Err(errno(libc::ESTALE));
+                    }
+                    let st = statat(&lower, &component)?;
+                    copy_up_dir(&lower, &upper, &component, &st)?;
+                    openat(&upper, &component, flags, 0)?
+                }
+                // The directory was replaced in the upper layer.
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTDIR) | Some(libc::ELOOP)) => {
+                    return Err(errno(libc::ESTALE));
+                }
+                Err(e) => return Err(e),
+            };
+
+            upper = next_upper;
+            lower = next_lower;
+        }
+
+        Ok((upper, lower))
+    }
+
+    // Returns true if the lower directory at `path` is merged with the upper directory at `path`:
+    // neither of them nor any of their ancestors was deleted or made opaque in the upper layer.
+    // Directories created in place of a deleted lower entry are opaque, so this also hides the
+    // lower contents below their new subdirectories.
+    fn lower_visible(&self, path: &CStr) -> io::Result<bool> {
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+        let root = self.find_inode(ROOT_ID)?;
+        let mut upper = openat(&*root, current_dir(), flags, 0)?;
+
+        for component in components(path) {
+            check_name(&component)?;
+            if is_opaque(&upper)? {
+                return Ok(false);
+            }
+            upper = match openat(&upper, &component, flags, 0) {
+                Ok(dir) => dir,
+                // The rest of the path only exists in the lower layer.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                    return Ok(!is_whited_out(&upper, &component)?);
+                }
+                // The directory was replaced in the upper layer.
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTDIR) | Some(libc::ELOOP)) => {
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(!is_opaque(&upper)?)
+    }
+
+    // Returns the lower directory containing the entry `name` of `parent` and the attributes of
+    // that entry, if it exists and hasn't been deleted or hidden by an opaque directory. This
+    // doesn't check whether the entry is shadowed by an upper entry.
+    fn lower_entry(
+        &self,
+        parent: &InodeData,
+        name: &CStr,
+    ) -> io::Result<Option<(File, libc::stat64)>> {
+        check_name(name)?;
+        let (layer, path) = Self::overlay_state(parent);
+        let dir = match layer {
+            Layer::Lower => openat(parent, current_dir(), libc::O_PATH | libc::O_DIRECTORY, 0)?,
+            Layer::Upper => {
+                if is_whited_out(parent, name)? || !self.lower_visible(&path)? {
+                    return Ok(None);
+                }
+                match self.lower_layer().lower_dir(&path)? {
+                    Some(dir) => dir,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        match statat(&dir, name) {
+            Ok(st) => Ok(Some((dir, st))),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Returns true if the directory `data` has contents in the lower layer.
+    fn has_lower_dir(&self, data: &InodeData) -> io::Result<bool> {
+        let (layer, path) = Self::overlay_state(data);
+        match layer {
+            Layer::Lower => Ok(true),
+            Layer::Upper => {
+                Ok(self.lower_visible(&path)? && self.lower_layer().lower_dir(&path)?.is_some())
+            }
+        }
+    }
+
+    // Looks up `name` in `parent` and calls `f` with the resulting inode, which is forgotten
+    // again afterwards.
+    fn with_entry<T, F>(&self, parent: &InodeData, name: &CStr, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&InodeData) -> io::Result<T>,
+    {
+        let entry = self.do_lookup(parent, name)?;
+        let res = self.find_inode(entry.inode).and_then(|data| f(&data));
+        forget_one(&mut self.inodes.lock(), entry.inode, 1);
+        res
+    }
+
+    /// Looks up `name` in the merged directory `parent`.
+    pub(super) fn overlay_lookup(&self, parent: &InodeData, name: &CStr) -> io::Result<Entry> {
+        check_name(name)?;
+        if is_reserved_name(name) {
+            return Err(errno(libc::ENOENT));
+        }
+
+        let (layer, _) = Self::overlay_state(parent);
+        if layer == Layer::Upper {
+            match statat(parent, name) {
+                Ok(st) if !is_whiteout(&st) => {
+                    let node = self.overlay_child(parent, Layer::Upper, name);
+                    return self.open_entry(parent, parent, name, st, node);
+                }
+                Ok(_) => return Err(errno(libc::ENOENT)),
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.lower_entry(parent, name)? {
+            Some((dir, st)) => {
+                let node = self.overlay_child(parent, Layer::Lower, name);
+                self.open_entry(&dir, parent, name, st, node)
+            }
+            None => Err(errno(libc::ENOENT)),
+        }
+    }
+
+    /// Copies `data` up to the upper layer if it still lives in the lower layer. Existing handles
+    /// are reopened from the upper layer so that they observe the changes. This is a no-op if the
+    /// overlay is not enabled.
+    pub(super) fn copy_up(&self, data: &InodeData) -> io::Result<()> {
+        let (overlay, node) = match (&self.overlay, &data.overlay) {
+            (Some(overlay), Some(node)) => (overlay, node),
+            _ => return Ok(()),
+        };
+        if node.lock().layer == Layer::Upper {
+            return Ok(());
+        }
+
+        let _copy_up = overlay.copy_up_lock.lock();
+        let path = {
+            let node = node.lock();
+            if node.layer == Layer::Upper {
+                return Ok(());
+            }
+            node.path.clone()
+        };
+
+        let (parent_path, name) = split_path(&path);
+        let (upper_dir, lower_dir) = self.overlay_dirs(&parent_path)?;
+        if is_opaque(&upper_dir)? || is_whited_out(&upper_dir, &name)? {
+            return Err(errno(libc::ESTALE));
+        }
+
+        let st = statat(&lower_dir, &name)?;
+        match statat(&upper_dir, &name) {
+            // Directories may already have been copied up as the parent of another entry.
+            Ok(upper_st)
+                if FileType::from(st.st_mode) == FileType::Directory
+                    && FileType::from(upper_st.st_mode) == FileType::Directory => {}
+            Ok(_) => return Err(errno(libc::ESTALE)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                let tmpname = CString::new(format!(".wh..wh.copyup.{}", data.inode))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                copy_up_entry(&lower_dir, &upper_dir, &name, &st, &tmpname)?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Reopen the inode from the upper layer with the same flags, falling back to `O_PATH` like
+        // `do_lookup` does.
+        let mut flags = data.file.lock().1;
+        let file = match openat(&upper_dir, &name, flags, 0) {
+            Ok(file) => file,
+            Err(e) if e.raw_os_error() == Some(libc::EACCES) && flags & libc::O_PATH == 0 => {
+                flags |= libc::O_PATH;
+                openat(&upper_dir, &name, flags, 0)?
+            }
+            Err(e) => return Err(e),
+        };
+        let st = stat(&file)?;
+
+        {
+            let mut inodes = self.inodes.lock();
+            if let Some(inode_data) = inodes.get(&data.inode).cloned() {
+                let altkey = InodeAltKey {
+                    ino: st.st_ino,
+                    dev: st.st_dev,
+                };
+                inodes.insert(data.inode, altkey, inode_data);
+            }
+        }
+        *data.file.lock() = (file, flags);
+        node.lock().layer = Layer::Upper;
+
+        let handles = self.handles.lock();
+        for handle in handles.values().filter(|hd| hd.inode == data.inode) {
+            let mut file = handle.file.lock();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let flags = syscall!(unsafe { libc::fcntl(file.as_raw_descriptor(), libc::F_GETFL) })?;
+            *file = self.open_fd(data.as_raw_descriptor(), flags)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the inode `inode` up to the upper layer if the overlay is enabled.
+    pub(super) fn copy_up_inode(&self, inode: Inode) -> io::Result<()> {
+        if self.overlay.is_none() {
+            return Ok(());
+        }
+        let data = self.find_inode(inode)?;
+        self.copy_up(&data)
+    }
+
+    /// Prepares the creation of the entry `name` in `parent`: `parent` is copied up and a whiteout
+    /// occupying `name` is moved aside. Returns true if a deleted lower entry had that name, in
+    /// which case new directories must be made opaque and `remove_whiteout_at` must be called once
+    /// the entry is created. The whiteout is kept until then so that the deleted entry doesn't
+    /// reappear if the creation fails. This is a no-op if the overlay is not enabled.
+    pub(super) fn prepare_upper_name(&self, parent: &InodeData, name: &CStr) -> io::Result<bool> {
+        if self.overlay.is_none() {
+            return Ok(false);
+        }
+        check_name(name)?;
+        if is_reserved_name(name) {
+            return Err(errno(libc::EINVAL));
+        }
+
+        self.copy_up(parent)?;
+        if self.lower_entry(parent, name)?.is_some() {
+            return Err(errno(libc::EEXIST));
+        }
+        set_whiteout_aside(parent, name)
+    }
+
+    /// Removes the whiteout of `name` from `parent` once a new upper entry replaced it.
+    pub(super) fn remove_whiteout_at(&self, parent: &InodeData, name: &CStr) -> io::Result<()> {
+        match unlinkat(parent, &whiteout_name(name), 0) {
+            Err(e) if e.raw_os_error() != Some(libc::ENOENT) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks the new upper directory `name` of `parent` as opaque.
+    pub(super) fn set_opaque_at(&self, parent: &InodeData, name: &CStr) -> io::Result<()> {
+        set_opaque(&openat(
+            parent,
+            name,
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+            0,
+        )?)
+    }
+
+    // Returns the merged entries of the directory `data`.
+    fn overlay_dir_entries(&self, data: &InodeData) -> io::Result<Vec<OverlayDirEntry>> {
+        let (layer, path) = Self::overlay_state(data);
+        let dir = self.open_fd(data.as_raw_descriptor(), libc::O_RDONLY | libc::O_DIRECTORY)?;
+        match layer {
+            Layer::Lower => merge_entries(None, Some(&dir)),
+            Layer::Upper => {
+                let lower = if !self.lower_visible(&path)? {
+                    None
+                } else {
+                    self.lower_layer()
+                        .lower_dir(&path)?
+                        .map(|lower| reopen_dir(&lower))
+                        .transpose()?
+                };
+                merge_entries(Some(&dir), lower.as_ref())
+            }
+        }
+    }
+
+    /// Returns a snapshot of the merged contents of the directory `data`, starting at `offset`.
+    pub(super) fn overlay_readdir(
+        &self,
+        data: &InodeData,
+        offset: u64,
+    ) -> io::Result<OverlayDirIter> {
+        Ok(OverlayDirIter::new(self.overlay_dir_entries(data)?, offset))
+    }
+
+    /// Removes the entry `name` of `parent` from the merged view, leaving a whiteout if it exists
+    /// in the lower layer. `flags` is passed to `unlinkat`.
+    pub(super) fn overlay_remove(
+        &self,
+        parent: &InodeData,
+        name: &CStr,
+        flags: libc::c_int,
+    ) -> io::Result<()> {
+        check_name(name)?;
+        if is_reserved_name(name) {
+            return Err(errno(libc::ENOENT));
+        }
+
+        self.with_entry(parent, name, |child| {
+            let is_dir = child.filetype == FileType::Directory;
+            if flags & libc::AT_REMOVEDIR != 0 {
+                if !is_dir {
+                    return Err(errno(libc::ENOTDIR));
+                }
+                let not_empty = self
+                    .overlay_dir_entries(child)?
+                    .iter()
+                    .any(|entry| !matches!(entry.name.to_bytes(), b"." | b".."));
+                if not_empty {
+                    return Err(errno(libc::ENOTEMPTY));
+                }
+            } else if is_dir {
+                return Err(errno(libc::EISDIR));
+            }
+
+            let (layer, _) = Self::overlay_state(child);
+            let in_lower = self.lower_entry(parent, name)?.is_some();
+            self.copy_up(parent)?;
+
+            // Create the whiteout first: it has no effect while the upper entry exists.
+            if in_lower {
+                create_whiteout(parent, name)?;
+            }
+            if layer == Layer::Upper {
+                if is_dir {
+                    clear_whiteouts(&self.open_fd(
+                        child.as_raw_descriptor(),
+                        libc::O_RDONLY | libc::O_DIRECTORY,
+                    )?)?;
+                }
+                self.do_unlink(parent, name, flags)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Renames `oldname` in `olddir` to `newname` in `newdir` in the merged view. Entries are
+    /// copied up first and a whiteout is left behind if the old name exists in the lower layer.
+    pub(super) fn overlay_rename(
+        &self,
+        olddir: &InodeData,
+        oldname: &CStr,
+        newdir: &InodeData,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        check_name(oldname)?;
+        check_name(newname)?;
+        if is_reserved_name(oldname) {
+            return Err(errno(libc::ENOENT));
+        }
+        if is_reserved_name(newname) {
+            return Err(errno(libc::EINVAL));
+        }
+        // Exchanging entries or leaving a whiteout would need to update both layers atomically.
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(errno(libc::EXDEV));
+        }
+
+        self.with_entry(olddir, oldname, |src| {
+            let is_dir = src.filetype == FileType::Directory;
+            if is_dir && self.has_lower_dir(src)? {
+                return Err(errno(libc::EXDEV));
+            }
+
+            match self.with_entry(newdir, newname, |dst| {
+                if dst.filetype == FileType::Directory && self.has_lower_dir(dst)? {
+                    Err(errno(libc::EXDEV))
+                } else if flags & libc::RENAME_NOREPLACE != 0 {
+                    Err(errno(libc::EEXIST))
+                } else {
+                    Ok(())
+                }
+            }) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+
+            let in_lower = self.lower_entry(olddir, oldname)?.is_some();
+            self.copy_up(olddir)?;
+            self.copy_up(src)?;
+            self.copy_up(newdir)?;
+            let whiteout = set_whiteout_aside(newdir, newname)?;
+
+            // Create the whiteout first: it has no effect while the upper entry exists.
+            if in_lower {
+                create_whiteout(olddir, oldname)?;
+            }
+            self.do_rename(olddir, oldname, newdir, newname, flags)?;
+
+            // The moved directory must not be merged with a lower directory at its new path.
+            if is_dir {
+                self.set_opaque_at(newdir, newname)?;
+            }
+            if whiteout {
+                self.remove_whiteout_at(newdir, newname)?;
+            }
+
+            // Move the renamed entry and, for directories, the inodes below it to the new path.
+            if let (Some(node), Some(new_node)) = (
+                src.overlay.as_ref(),
+                self.overlay_child(newdir, Layer::Upper, newname),
+            ) {
+                let old_path = node.lock().path.clone();
+                if is_dir {
+                    for data in self.inodes.lock().values() {
+                        if let Some(node) = data.overlay.as_ref() {
+                            node.lock().rebase(&old_path, &new_node.path);
+                        }
+                    }
+                } else {
+                    node.lock().path = new_node.path;
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -7,7 +7,13 @@ use std::convert::TryInto;
 use std::fs::File;
 use std::io;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
 
 use base::error;
 use base::syscall;
@@ -20,12 +26,16 @@ use base::WaitContext;
 use fuse::filesystem::FileSystem;
 use fuse::filesystem::ZeroCopyReader;
 use fuse::filesystem::ZeroCopyWriter;
+use fuse::sys::InHeader;
+use fuse::sys::Opcode;
+use sync::Condvar;
 use sync::Mutex;
 use vm_control::FsMappingRequest;
 use vm_control::VmResponse;
 
 use crate::virtio::fs::Error;
 use crate::virtio::fs::Result;
+use crate::virtio::DescriptorChain;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 use crate::virtio::Reader;
@@ -139,11 +149,12 @@ impl fuse::Mapper for Mapper {
 }
 
 pub struct Worker<F: FileSystem + Sync> {
-    queue: Queue,
+    queue: Arc<Mutex<Queue>>,
     server: Arc<fuse::Server<F>>,
     irq: Interrupt,
     tube: Arc<Mutex<Tube>>,
     slot: u32,
+    num_threads: usize,
 }
 
 pub fn process_fs_queue<F: FileSystem + Sync>(
@@ -165,43 +176,236 @@ pub fn process_fs_queue<F: FileSystem + Sync>(
     Ok(())
 }
 
-impl<F: FileSystem + Sync> Worker<F> {
+// Executes the request in `avail_desc` and returns it to the guest.
+fn handle_request<F: FileSystem + Sync>(
+    server: &fuse::Server<F>,
+    mapper: &Mapper,
+    queue: &Mutex<Queue>,
+    irq: &Interrupt,
+    mut avail_desc: DescriptorChain,
+) -> Result<()> {
+    let total = server.handle_message(&mut avail_desc.reader, &mut avail_desc.writer, mapper)?;
+
+    let mut queue = queue.lock();
+    queue.add_used(avail_desc, total as u32);
+    queue.trigger_interrupt(irq);
+    Ok(())
+}
+
+// Prepares the current thread for executing FUSE requests.
+fn set_up_request_thread() -> Result<()> {
+    // We need to set the no setuid fixup secure bit so that we don't drop capabilities when
+    // changing the thread uid/gid. Without this, creating new entries can fail in some corner
+    // cases.
+    const SECBIT_NO_SETUID_FIXUP: i32 = 1 << 2;
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let mut securebits =
+        syscall!(unsafe { libc::prctl(libc::PR_GET_SECUREBITS) }).map_err(Error::GetSecurebits)?;
+
+    securebits |= SECBIT_NO_SETUID_FIXUP;
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe { libc::prctl(libc::PR_SET_SECUREBITS, securebits) })
+        .map_err(Error::SetSecurebits)?;
+
+    // To avoid extra locking, unshare filesystem attributes from parent. This includes the
+    // current working directory and umask.
+    // Safe because this doesn't modify any memory and we check the return value.
+    syscall!(unsafe { libc::unshare(libc::CLONE_FS) }).map_err(Error::UnshareFromParent)?;
+
+    Ok(())
+}
+
+// Returns true if the guest may rely on `opcode` being executed after the requests for the same
+// inode that it sent earlier. The guest kernel serializes conflicting operations itself by waiting
+// for their replies, so this is only needed for requests that it doesn't wait for (e.g. the
+// asynchronous `RELEASE` at the end of `close(2)`) and for requests that modify the inode.
+fn needs_ordering(opcode: u32) -> bool {
+    !matches!(
+        Opcode::n(opcode),
+        Some(
+            Opcode::Lookup
+                | Opcode::Getattr
+                | Opcode::Readlink
+                | Opcode::Open
+                | Opcode::Read
+                | Opcode::Statfs
+                | Opcode::Getxattr
+                | Opcode::Listxattr
+                | Opcode::Access
+                | Opcode::Opendir
+                | Opcode::Readdir
+                | Opcode::Readdirplus
+                | Opcode::Lseek
+        )
+    )
+}
+
+// Returns true if `opcode` operates on other inodes than the one in its header, like the new
+// parent directory of `RENAME` or the entry removed by `UNLINK`. These requests can't be ordered by
+// a single inode.
+fn touches_multiple_inodes(opcode: u32) -> bool {
+    matches!(
+        Opcode::n(opcode),
+        Some(Opcode::Unlink | Opcode::Rmdir | Opcode::Rename | Opcode::Link | Opcode::Rename2)
+    )
+}
+
+// Progress of the requests handed to a `RequestPool`.
+#[derive(Default)]
+struct PoolState {
+    // Number of requests that the threads haven't completed yet.
+    in_flight: usize,
+    // Set when a thread stopped executing requests.
+    exited: bool,
+}
+
+/// A pool of threads that execute the FUSE requests of one queue concurrently.
+///
+/// Requests that need ordering are always sent to the thread selected by their inode, so that
+/// requests for the same inode are executed in the order in which they were received. Other
+/// requests are distributed among all the threads. Requests that touch several inodes are not
+/// handed to the pool: the caller waits for it to be idle and executes them itself.
+struct RequestPool {
+    senders: Vec<Sender<DescriptorChain>>,
+    threads: Vec<JoinHandle<()>>,
+    next: AtomicUsize,
+    state: Arc<(Mutex<PoolState>, Condvar)>,
+}
+
+impl RequestPool {
+    fn new<F: FileSystem + Sync + Send + 'static>(
+        num_threads: usize,
+        queue: &Arc<Mutex<Queue>>,
+        server: &Arc<fuse::Server<F>>,
+        irq: &Interrupt,
+        tube: &Arc<Mutex<Tube>>,
+        slot: u32,
+    ) -> Result<RequestPool> {
+        let name = thread::current().name().unwrap_or("v_fs").to_string();
+        let mut pool = RequestPool {
+            senders: Vec::with_capacity(num_threads),
+            threads: Vec::with_capacity(num_threads),
+            next: AtomicUsize::new(0),
+            state: Arc::new((Mutex::new(PoolState::default()), Condvar::new())),
+        };
+
+        for i in 0..num_threads {
+            let (sender, receiver) = channel::<DescriptorChain>();
+            let queue = Arc::clone(queue);
+            let server = Arc::clone(server);
+            let irq = irq.clone();
+            let mapper = Mapper::new(Arc::clone(tube), slot);
+            let state = Arc::clone(&pool.state);
+
+            let thread = thread::Builder::new()
+                .name(format!("{}:{}", name, i))
+                .spawn(move || {
+                    let (state, idle) = &*state;
+                    match set_up_request_thread() {
+                        Ok(()) => {
+                            for avail_desc in receiver {
+                                if let Err(e) =
+                                    handle_request(&server, &mapper, &queue, &irq, avail_desc)
+                                {
+                                    error!("virtio-fs transport error: {}", e);
+                                    break;
+                                }
+                                state.lock().in_flight -= 1;
+                                idle.notify_all();
+                            }
+                        }
+                        Err(e) => error!("failed to set up virtio-fs request thread: {}", e),
+                    }
+
+                    // Don't leave `wait_idle` waiting for requests that will never complete.
+                    state.lock().exited = true;
+                    idle.notify_all();
+                })
+                .map_err(Error::CreateRequestThread)?;
+
+            pool.senders.push(sender);
+            pool.threads.push(thread);
+        }
+
+        Ok(pool)
+    }
+
+    // Sends `avail_desc` to the thread that should execute it. If `nodeid` is set, the request is
+    // ordered after the previous requests for that inode.
+    fn dispatch(&self, avail_desc: DescriptorChain, nodeid: Option<u64>) -> Result<()> {
+        let num_threads = self.senders.len();
+        let idx = match nodeid {
+            Some(nodeid) => (nodeid % num_threads as u64) as usize,
+            None => self.next.fetch_add(1, Ordering::Relaxed) % num_threads,
+        };
+
+        self.state.0.lock().in_flight += 1;
+        self.senders[idx]
+            .send(avail_desc)
+            .map_err(|_| Error::RequestThreadExited)
+    }
+
+    // Waits until the threads have completed all the requests handed to them.
+    fn wait_idle(&self) -> Result<()> {
+        let (state, idle) = &*self.state;
+        let state = idle.wait_while(state.lock(), |s| s.in_flight > 0 && !s.exited);
+        if state.exited {
+            return Err(Error::RequestThreadExited);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RequestPool {
+    fn drop(&mut self) {
+        // Closing the channels makes the threads exit once they have executed the pending
+        // requests.
+        self.senders.clear();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("virtio-fs request thread panicked");
+            }
+        }
+    }
+}
+
+impl<F: FileSystem + Sync + Send + 'static> Worker<F> {
     pub fn new(
         queue: Queue,
         server: Arc<fuse::Server<F>>,
         irq: Interrupt,
         tube: Arc<Mutex<Tube>>,
         slot: u32,
+        num_threads: usize,
     ) -> Worker<F> {
         Worker {
-            queue,
+            queue: Arc::new(Mutex::new(queue)),
             server,
             irq,
             tube,
             slot,
+            num_threads,
         }
     }
 
     pub fn run(&mut self, kill_evt: Event, watch_resample_event: bool) -> Result<()> {
-        // We need to set the no setuid fixup secure bit so that we don't drop capabilities when
-        // changing the thread uid/gid. Without this, creating new entries can fail in some corner
-        // cases.
-        const SECBIT_NO_SETUID_FIXUP: i32 = 1 << 2;
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let mut securebits = syscall!(unsafe { libc::prctl(libc::PR_GET_SECUREBITS) })
-            .map_err(Error::GetSecurebits)?;
-
-        securebits |= SECBIT_NO_SETUID_FIXUP;
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe { libc::prctl(libc::PR_SET_SECUREBITS, securebits) })
-            .map_err(Error::SetSecurebits)?;
-
-        // To avoid extra locking, unshare filesystem attributes from parent. This includes the
-        // current working directory and umask.
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe { libc::unshare(libc::CLONE_FS) }).map_err(Error::UnshareFromParent)?;
+        set_up_request_thread()?;
+
+        // With a single thread, requests are executed directly on this thread.
+        let pool = if self.num_threads > 1 {
+            Some(RequestPool::new(
+                self.num_threads,
+                &self.queue,
+                &self.server,
+                &self.irq,
+                &self.tube,
+                self.slot,
+            )?)
+        } else {
+            None
+        };
 
         #[derive(EventToken)]
         enum Token {
@@ -213,11 +417,15 @@ impl<F: FileSystem + Sync> Worker<F> {
             Kill,
         }
 
-        let wait_ctx = WaitContext::build_with(&[
-            (self.queue.event(), Token::QueueReady),
-            (&kill_evt, Token::Kill),
-        ])
-        .map_err(Error::CreateWaitContext)?;
+        let queue_evt = self
+            .queue
+            .lock()
+            .event()
+            .try_clone()
+            .map_err(Error::CloneQueueEvent)?;
+        let wait_ctx =
+            WaitContext::build_with(&[(&queue_evt, Token::QueueReady), (&kill_evt, Token::Kill)])
+                .map_err(Error::CreateWaitContext)?;
 
         if watch_resample_event {
             if let Some(resample_evt) = self.irq.get_resample_evt() {
@@ -232,14 +440,18 @@ impl<F: FileSystem + Sync> Worker<F> {
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
                     Token::QueueReady => {
-                        self.queue.event().wait().map_err(Error::ReadQueueEvent)?;
-                        if let Err(e) = process_fs_queue(
-                            &self.irq,
-                            &mut self.queue,
-                            &self.server,
-                            &self.tube,
-                            self.slot,
-                        ) {
+                        queue_evt.wait().map_err(Error::ReadQueueEvent)?;
+                        let res = match &pool {
+                            Some(pool) => self.dispatch_requests(pool),
+                            None => process_fs_queue(
+                                &self.irq,
+                                &mut self.queue.lock(),
+                                &self.server,
+                                &self.tube,
+                                self.slot,
+                            ),
+                        };
+                        if let Err(e) = res {
                             error!("virtio-fs transport error: {}", e);
                             return Err(e);
                         }
@@ -252,4 +464,31 @@ impl<F: FileSystem + Sync> Worker<F> {
             }
         }
     }
+
+    // Hands all the available requests to `pool`.
+    fn dispatch_requests(&self, pool: &RequestPool) -> Result<()> {
+        let mapper = Mapper::new(Arc::clone(&self.tube), self.slot);
+        loop {
+            // Don't hold the queue lock while dispatching since the request threads need it to
+            // return the used descriptors.
+            let avail_desc = match self.queue.lock().pop() {
+                Some(avail_desc) => avail_desc,
+                None => return Ok(()),
+            };
+
+            match avail_desc.reader.peek_obj::<InHeader>() {
+                Ok(header) if touches_multiple_inodes(header.opcode) => {
+                    // Execute the request once every earlier request is complete, and before
+                    // handing out any later one, so that it's ordered for all the inodes.
+                    pool.wait_idle()?;
+                    handle_request(&self.server, &mapper, &self.queue, &self.irq, avail_desc)?;
+                }
+                Ok(header) if needs_ordering(header.opcode) => {
+                    pool.dispatch(avail_desc, Some(header.nodeid))?
+                }
+                // Malformed requests are rejected by whichever thread receives them.
+                _ => pool.dispatch(avail_desc, None)?,
+            }
+        }
+    }
 }
@@ -10,6 +10,8 @@ mod virtio_gpu;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::io::Read;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
@@ -1116,6 +1118,10 @@ pub enum DisplayBackend {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    /// Serve the display to VNC clients connecting to the given listener, which is bound before
+    /// the device is sandboxed.
+    Vnc(Arc<TcpListener>),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1134,6 +1140,8 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(any(target_os = "android", target_os = "linux"))]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_deref()),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            DisplayBackend::Vnc(listener) => GpuDisplay::open_vnc(listener.try_clone()?),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi(display_properties) => match wndproc_thread.take() {
@@ -1671,6 +1679,13 @@ impl VirtioDevice for Gpu {
             keep_rds.push(event_device.as_raw_descriptor());
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listener) = display_backend {
+                keep_rds.push(listener.as_raw_descriptor());
+            }
+        }
+
         keep_rds
     }
 
@@ -5,7 +5,9 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::fs::File;
 use std::io::IoSliceMut;
+use std::io::Write;
 use std::num::NonZeroU32;
 use std::rc::Rc;
 use std::result::Result;
@@ -22,6 +24,7 @@ use base::SafeDescriptor;
 use data_model::VolatileSlice;
 use gpu_display::*;
 use libc::c_void;
+use rutabaga_gfx::DrmFormat;
 use rutabaga_gfx::ResourceCreate3D;
 use rutabaga_gfx::ResourceCreateBlob;
 use rutabaga_gfx::Rutabaga;
@@ -59,6 +62,8 @@ use super::protocol::GpuResponsePlaneInfo;
 use super::protocol::VirtioGpuResult;
 use super::protocol::VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE;
 use super::protocol::VIRTIO_GPU_BLOB_MEM_HOST3D;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM;
 use super::VirtioScanoutBlobData;
 use crate::virtio::gpu::edid::DisplayInfo;
 use crate::virtio::gpu::edid::EdidBytes;
@@ -89,6 +94,8 @@ struct VirtioGpuResource {
     scanout_data: Option<VirtioScanoutBlobData>,
     display_import: Option<u32>,
     rutabaga_external_mapping: bool,
+    // The virtio-gpu format of resources created by `resource_create_3d`.
+    format: Option<u32>,
 
     // Only saved for snapshotting, so that we can re-attach backing iovecs with the correct new
     // host addresses.
@@ -101,6 +108,7 @@ struct VirtioGpuResourceSnapshot {
     width: u32,
     height: u32,
     size: u64,
+    format: Option<u32>,
 
     backing_iovecs: Option<Vec<(GuestAddress, usize)>>,
 }
@@ -118,6 +126,7 @@ impl VirtioGpuResource {
             scanout_data: None,
             display_import: None,
             rutabaga_external_mapping: false,
+            format: None,
             backing_iovecs: None,
         }
     }
@@ -133,12 +142,14 @@ impl VirtioGpuResource {
             width: self.width,
             height: self.height,
             size: self.size,
+            format: self.format,
             backing_iovecs: self.backing_iovecs.clone(),
         }
     }
 
     fn restore(s: VirtioGpuResourceSnapshot) -> Self {
         let mut resource = VirtioGpuResource::new(s.resource_id, s.width, s.height, s.size);
+        resource.format = s.format;
         resource.backing_iovecs = s.backing_iovecs;
         resource
     }
@@ -630,12 +641,71 @@ impl VirtioGpu {
             })
     }
 
+    /// Reads back the resource shown on the given display and writes its pixels to `file`.
+    fn screenshot(&mut self, display_id: u32, mut file: File) -> GpuControlResult {
+        let scanout = match self.scanouts.get(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let resource = match scanout
+            .resource_id
+            .and_then(|resource_id| self.resources.get(&resource_id.get()))
+        {
+            Some(resource) => resource,
+            None => return GpuControlResult::NoScanoutResource { display_id },
+        };
+
+        // Screenshots are XRGB8888, so only resources with that layout in memory can be read back.
+        let is_xrgb = match (&resource.scanout_data, resource.format) {
+            (Some(data), _) => {
+                data.drm_format == DrmFormat::new(b'X', b'R', b'2', b'4')
+                    || data.drm_format == DrmFormat::new(b'A', b'R', b'2', b'4')
+            }
+            (None, Some(format)) => matches!(
+                format,
+                VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
+            ),
+            (None, None) => false,
+        };
+        if !is_xrgb {
+            return GpuControlResult::ScreenshotFailed(format!(
+                "unsupported format of resource {}",
+                resource.resource_id
+            ));
+        }
+
+        // The resource may be larger than the display when the guest pans across it.
+        let width = scanout.width.min(resource.width);
+        let height = scanout.height.min(resource.height);
+        let mut pixels = vec![0u8; width as usize * height as usize * 4];
+        let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+        transfer.stride = width * 4;
+        if let Err(e) = self.rutabaga.transfer_read(
+            0,
+            resource.resource_id,
+            transfer,
+            Some(IoSliceMut::new(&mut pixels)),
+        ) {
+            return GpuControlResult::ScreenshotFailed(format!(
+                "failed to read back resource {}: {}",
+                resource.resource_id, e
+            ));
+        }
+
+        if let Err(e) = file.write_all(&pixels) {
+            return GpuControlResult::ScreenshotFailed(format!("failed to write pixels: {}", e));
+        }
+
+        GpuControlResult::Screenshot { width, height }
+    }
+
     /// Performs the given command to interact with or modify the device.
     pub fn process_gpu_control_command(&mut self, cmd: GpuControlCommand) -> GpuControlResult {
         match cmd {
             GpuControlCommand::AddDisplays { displays } => self.add_displays(displays),
             GpuControlCommand::ListDisplays => self.list_displays(),
             GpuControlCommand::RemoveDisplays { display_ids } => self.remove_displays(display_ids),
+            GpuControlCommand::Screenshot { display_id, file } => self.screenshot(display_id, file),
         }
     }
 
@@ -846,12 +916,13 @@ impl VirtioGpu {
         self.rutabaga
             .resource_create_3d(resource_id, resource_create_3d)?;
 
-        let resource = VirtioGpuResource::new(
+        let mut resource = VirtioGpuResource::new(
             resource_id,
             resource_create_3d.width,
             resource_create_3d.height,
             0,
         );
+        resource.format = Some(resource_create_3d.format);
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
@@ -0,0 +1,309 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Virtio input devices whose capabilities are described by a JSON file, for devices like
+//! gamepads or pen tablets that have no hardcoded configuration in `defaults`.
+//!
+//! Example of a descriptor file for a gamepad with one stick and two buttons:
+//!
+//! ```json
+//! {
+//!     "name": "Crosvm Virtio Gamepad",
+//!     "serial_name": "virtio-gamepad",
+//!     "device_ids": { "bustype": 6, "vendor": 0, "product": 0, "version": 0 },
+//!     "events": [
+//!         {
+//!             "event_type": "EV_KEY",
+//!             "event_type_code": 1,
+//!             "supported_events": { "BTN_SOUTH": 304, "BTN_EAST": 305 }
+//!         },
+//!         {
+//!             "event_type": "EV_ABS",
+//!             "event_type_code": 3,
+//!             "supported_events": { "ABS_X": 0, "ABS_Y": 1 }
+//!         }
+//!     ],
+//!     "axis_info": [
+//!         { "axis_code": 0, "min": -32768, "max": 32767, "fuzz": 16, "flat": 128 },
+//!         { "axis_code": 1, "min": -32768, "max": 32767, "fuzz": 16, "flat": 128 }
+//!     ]
+//! }
+//! ```
+//!
+//! The names of the event types and codes are only labels for the reader of the file, the device
+//! uses the numeric codes.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use linux_input_sys::constants::*;
+use serde::Deserialize;
+
+use super::virtio_input_absinfo;
+use super::virtio_input_bitmap;
+use super::virtio_input_device_ids;
+use super::InputError;
+use super::Result;
+use super::VirtioInputConfig;
+
+/// Number of codes that fit in a `virtio_input_bitmap`.
+const BITMAP_CODES: u16 = 128 * 8;
+
+/// Maximum length of the name and serial name of the device, the size of the config payload.
+const NAME_MAX: usize = 128;
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+struct InputConfigFileDeviceIds {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct InputConfigFileEvent {
+    /// Name of the event type, e.g. "EV_KEY".
+    #[serde(default)]
+    event_type: String,
+    /// Code of the event type, e.g. 1 for EV_KEY.
+    event_type_code: u16,
+    /// Codes of the events of that type the device sends, keyed by their name.
+    supported_events: BTreeMap<String, u16>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct InputConfigFileAxis {
+    /// Name of the axis, e.g. "ABS_PRESSURE".
+    #[serde(default)]
+    axis: String,
+    /// Code of the axis, e.g. 0x18 for ABS_PRESSURE.
+    axis_code: u16,
+    min: i32,
+    max: i32,
+    #[serde(default)]
+    fuzz: i32,
+    #[serde(default)]
+    flat: i32,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct InputConfigFile {
+    name: Option<String>,
+    serial_name: Option<String>,
+    #[serde(default)]
+    device_ids: InputConfigFileDeviceIds,
+    /// INPUT_PROP_* codes of the device, e.g. 0 (INPUT_PROP_POINTER) for a tablet.
+    #[serde(default)]
+    properties: Vec<u16>,
+    events: Vec<InputConfigFileEvent>,
+    #[serde(default)]
+    axis_info: Vec<InputConfigFileAxis>,
+}
+
+fn invalid(msg: String) -> InputError {
+    InputError::InvalidInputConfig(msg)
+}
+
+impl InputConfigFile {
+    fn into_config(self, idx: u32) -> Result<VirtioInputConfig> {
+        let name = self
+            .name
+            .unwrap_or_else(|| format!("Crosvm Virtio Custom Input {}", idx));
+        let serial_name = self
+            .serial_name
+            .unwrap_or_else(|| format!("virtio-custom-input-{}", idx));
+        for value in [&name, &serial_name] {
+            if value.len() > NAME_MAX {
+                return Err(invalid(format!(
+                    "{} is longer than {} bytes",
+                    value, NAME_MAX
+                )));
+            }
+        }
+
+        if let Some(prop) = self.properties.iter().find(|p| **p >= INPUT_PROP_CNT) {
+            return Err(invalid(format!("unknown property {}", prop)));
+        }
+
+        let mut supported_events = BTreeMap::new();
+        for event in &self.events {
+            let ev_type = event.event_type_code;
+            if ev_type == EV_SYN || ev_type >= EV_CNT {
+                return Err(invalid(format!(
+                    "invalid event type {} ({})",
+                    ev_type, event.event_type
+                )));
+            }
+            let codes: Vec<u16> = event.supported_events.values().cloned().collect();
+            if let Some(code) = codes.iter().find(|c| **c >= BITMAP_CODES) {
+                return Err(invalid(format!(
+                    "event code {} of type {} does not fit in the bitmap",
+                    code, event.event_type
+                )));
+            }
+            if supported_events
+                .insert(ev_type, virtio_input_bitmap::from_bits(&codes))
+                .is_some()
+            {
+                return Err(invalid(format!("event type {} listed twice", ev_type)));
+            }
+        }
+
+        let abs_codes: Vec<u16> = self
+            .events
+            .iter()
+            .filter(|e| e.event_type_code == EV_ABS)
+            .flat_map(|e| e.supported_events.values().cloned())
+            .collect();
+        let mut axis_info = BTreeMap::new();
+        for axis in &self.axis_info {
+            if !abs_codes.contains(&axis.axis_code) {
+                return Err(invalid(format!(
+                    "axis {} ({}) is not a supported EV_ABS event",
+                    axis.axis_code, axis.axis
+                )));
+            }
+            if axis.min > axis.max {
+                return Err(invalid(format!(
+                    "axis {} ({}) has a minimum above its maximum",
+                    axis.axis_code, axis.axis
+                )));
+            }
+            // The guest reads the fields as signed 32-bit values.
+            axis_info.insert(
+                axis.axis_code,
+                virtio_input_absinfo::new(
+                    axis.min as u32,
+                    axis.max as u32,
+                    axis.fuzz as u32,
+                    axis.flat as u32,
+                ),
+            );
+        }
+        if let Some(code) = abs_codes.iter().find(|c| !axis_info.contains_key(c)) {
+            return Err(invalid(format!("EV_ABS event {} has no axis_info", code)));
+        }
+
+        Ok(VirtioInputConfig::new(
+            virtio_input_device_ids::new(
+                self.device_ids.bustype,
+                self.device_ids.product,
+                self.device_ids.vendor,
+                self.device_ids.version,
+            ),
+            name.into_bytes(),
+            serial_name.into_bytes(),
+            virtio_input_bitmap::from_bits(&self.properties),
+            supported_events,
+            axis_info,
+        ))
+    }
+}
+
+/// Instantiates a VirtioInputConfig object from the descriptor file at `config_path`. The name
+/// and serial name default to ones including `idx` when the file does not set them.
+pub fn new_custom_config(idx: u32, config_path: &Path) -> Result<VirtioInputConfig> {
+    let file = File::open(config_path).map_err(InputError::ReadInputConfig)?;
+    let config_file: InputConfigFile =
+        serde_json::from_reader(BufReader::new(file)).map_err(InputError::ParseInputConfig)?;
+    config_file.into_config(idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn config_from_str(json: &str) -> Result<VirtioInputConfig> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(json.as_bytes()).unwrap();
+        new_custom_config(3, file.path())
+    }
+
+    #[test]
+    fn tablet_config() {
+        let config = config_from_str(
+            r#"{
+                "name": "Crosvm Virtio Tablet",
+                "device_ids": { "bustype": 6, "vendor": 1 },
+                "properties": [0],
+                "events": [
+                    {
+                        "event_type": "EV_KEY",
+                        "event_type_code": 1,
+                        "supported_events": { "BTN_TOOL_PEN": 320, "BTN_TOUCH": 330 }
+                    },
+                    {
+                        "event_type": "EV_ABS",
+                        "event_type_code": 3,
+                        "supported_events": { "ABS_X": 0, "ABS_PRESSURE": 24 }
+                    }
+                ],
+                "axis_info": [
+                    { "axis": "ABS_X", "axis_code": 0, "min": 0, "max": 4095 },
+                    { "axis": "ABS_PRESSURE", "axis_code": 24, "min": -1, "max": 1023 }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.name, b"Crosvm Virtio Tablet".to_vec());
+        assert_eq!(config.serial_name, b"virtio-custom-input-3".to_vec());
+        assert_eq!(config.device_ids.bustype.to_native(), 6);
+        assert_eq!(config.device_ids.vendor.to_native(), 1);
+        assert_eq!(config.properties.bitmap[0], 1 << INPUT_PROP_POINTER);
+
+        let events = &config.supported_events;
+        assert_eq!(
+            events.keys().cloned().collect::<Vec<_>>(),
+            vec![EV_KEY, EV_ABS]
+        );
+        assert_eq!(
+            events[&EV_KEY].bitmap[(BTN_TOUCH / 8) as usize],
+            1 << (BTN_TOUCH % 8)
+        );
+        assert_eq!(events[&EV_ABS].bitmap[0], 1 << ABS_X);
+        assert_eq!(events[&EV_ABS].bitmap[3], 1 << (ABS_PRESSURE % 8));
+
+        assert_eq!(config.axis_info[&ABS_X].max.to_native(), 4095);
+        assert_eq!(config.axis_info[&ABS_PRESSURE].min.to_native() as i32, -1);
+    }
+
+    #[test]
+    fn axis_without_info() {
+        let err = config_from_str(
+            r#"{
+                "events": [
+                    {
+                        "event_type_code": 3,
+                        "supported_events": { "ABS_X": 0, "ABS_Y": 1 }
+                    }
+                ],
+                "axis_info": [{ "axis_code": 0, "min": 0, "max": 255 }]
+            }"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, InputError::InvalidInputConfig(_)));
+    }
+
+    #[test]
+    fn invalid_event_type() {
+        let err =
+            config_from_str(r#"{ "events": [{ "event_type_code": 32, "supported_events": {} }] }"#)
+                .unwrap_err();
+        assert!(matches!(err, InputError::InvalidInputConfig(_)));
+
+        let err = config_from_str(r#"{ "events": [], "unknown": 1 }"#).unwrap_err();
+        assert!(matches!(err, InputError::ParseInputConfig(_)));
+    }
+}
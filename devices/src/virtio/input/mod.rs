@@ -2,14 +2,20 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod custom;
 #[allow(dead_code)]
 mod defaults;
 mod evdev;
 mod event_source;
 
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::bail;
@@ -22,10 +28,14 @@ use base::AsRawDescriptor;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
+use base::ReadNotifier;
+use base::Tube;
+use base::TubeError;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
 use data_model::Le32;
+use data_model::SLe32;
 use linux_input_sys::constants::*;
 use linux_input_sys::virtio_input_event;
 use linux_input_sys::InputEventDecoder;
@@ -33,6 +43,10 @@ use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
+use vm_control::input::InputEvent;
+use vm_control::input::ScriptEvent;
+use vm_control::InputControlCommand;
+use vm_control::InputControlResult;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -85,6 +99,15 @@ pub enum InputError {
     // Detected error on guest side
     #[error("detected error on guest side: {0}")]
     GuestError(String),
+    /// The input device configuration file describes an invalid device
+    #[error("invalid input device configuration: {0}")]
+    InvalidInputConfig(String),
+    /// Failed to parse the input device configuration file
+    #[error("failed to parse input device configuration: {0}")]
+    ParseInputConfig(serde_json::Error),
+    /// Failed to read the input device configuration file
+    #[error("failed to read input device configuration: {0}")]
+    ReadInputConfig(std::io::Error),
     // Error while reading from virtqueue
     #[error("failed to read from virtqueue: {0}")]
     ReadQueue(std::io::Error),
@@ -342,24 +365,75 @@ impl VirtioInputConfig {
     }
 }
 
+/// Writes the events sent to the guest to a file, as an event script starting at the first
+/// event.
+struct EventRecorder {
+    writer: BufWriter<File>,
+    start: Option<Instant>,
+}
+
+impl EventRecorder {
+    fn new(file: File) -> EventRecorder {
+        EventRecorder {
+            writer: BufWriter::new(file),
+            start: None,
+        }
+    }
+
+    fn record(&mut self, evt: &virtio_input_event) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let script_event = ScriptEvent {
+            time: start.elapsed(),
+            event: InputEvent {
+                type_: evt.type_.into(),
+                code: evt.code.into(),
+                value: evt.value.into(),
+            },
+        };
+        if let Err(e) = write!(self.writer, "{}", script_event) {
+            error!("failed to record input event: {}", e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("failed to write recorded input events: {}", e);
+        }
+    }
+}
+
 struct Worker<T: EventSource> {
     interrupt: Interrupt,
     event_source: T,
     event_queue: Queue,
     status_queue: Queue,
+    control_tube: Option<Tube>,
+    // Events injected through the control tube, sent after the events of the source.
+    injected_events: VecDeque<virtio_input_event>,
+    recorder: Option<EventRecorder>,
 }
 
 impl<T: EventSource> Worker<T> {
+    fn available_events_count(&self) -> usize {
+        self.event_source.available_events_count() + self.injected_events.len()
+    }
+
+    fn pop_available_event(&mut self) -> Option<virtio_input_event> {
+        self.event_source
+            .pop_available_event()
+            .or_else(|| self.injected_events.pop_front())
+    }
+
     // Fills a virtqueue with events from the source.  Returns the number of bytes written.
-    fn fill_event_virtqueue(
-        event_source: &mut T,
-        avail_desc: &mut DescriptorChain,
-    ) -> Result<usize> {
+    fn fill_event_virtqueue(&mut self, avail_desc: &mut DescriptorChain) -> Result<usize> {
         let writer = &mut avail_desc.writer;
 
         while writer.available_bytes() >= virtio_input_event::SIZE {
-            if let Some(evt) = event_source.pop_available_event() {
+            if let Some(evt) = self.pop_available_event() {
                 writer.write_obj(evt).map_err(InputError::WriteQueue)?;
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(&evt);
+                }
             } else {
                 break;
             }
@@ -373,31 +447,54 @@ impl<T: EventSource> Worker<T> {
         let mut needs_interrupt = false;
 
         // Only consume from the queue iterator if we know we have events to send
-        while self.event_source.available_events_count() > 0 {
+        while self.available_events_count() > 0 {
             match self.event_queue.pop() {
                 None => {
                     break;
                 }
                 Some(mut avail_desc) => {
-                    let bytes_written =
-                        match Worker::fill_event_virtqueue(&mut self.event_source, &mut avail_desc)
-                        {
-                            Ok(count) => count,
-                            Err(e) => {
-                                error!("Input: failed to send events to guest: {}", e);
-                                break;
-                            }
-                        };
+                    let bytes_written = match self.fill_event_virtqueue(&mut avail_desc) {
+                        Ok(count) => count,
+                        Err(e) => {
+                            error!("Input: failed to send events to guest: {}", e);
+                            break;
+                        }
+                    };
 
                     self.event_queue.add_used(avail_desc, bytes_written as u32);
                     needs_interrupt = true;
                 }
             }
         }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush();
+        }
 
         needs_interrupt
     }
 
+    fn handle_control_command(&mut self, command: InputControlCommand) -> InputControlResult {
+        match command {
+            InputControlCommand::Inject { events } => {
+                self.injected_events
+                    .extend(events.iter().map(|event| virtio_input_event {
+                        type_: Le16::from(event.type_),
+                        code: Le16::from(event.code),
+                        value: SLe32::from(event.value),
+                    }));
+            }
+            InputControlCommand::StartRecording { file } => {
+                self.recorder = Some(EventRecorder::new(file));
+            }
+            InputControlCommand::StopRecording => {
+                if let Some(mut recorder) = self.recorder.take() {
+                    recorder.flush();
+                }
+            }
+        }
+        InputControlResult::Ok
+    }
+
     // Sends events from the guest to the source.  Returns the number of bytes read.
     fn read_event_virtqueue(
         avail_desc: &mut DescriptorChain,
@@ -445,6 +542,7 @@ impl<T: EventSource> Worker<T> {
             StatusQAvailable,
             InputEventsAvailable,
             InterruptResample,
+            ControlTube,
             Kill,
         }
         let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
@@ -468,6 +566,15 @@ impl<T: EventSource> Worker<T> {
                 return;
             }
         }
+        if let Some(control_tube) = &self.control_tube {
+            if wait_ctx
+                .add(control_tube.get_read_notifier(), Token::ControlTube)
+                .is_err()
+            {
+                error!("failed adding control tube to WaitContext.");
+                return;
+            }
+        }
 
         'wait: loop {
             let wait_events = match wait_ctx.wait() {
@@ -506,6 +613,30 @@ impl<T: EventSource> Worker<T> {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
+                    Token::ControlTube => {
+                        let control_tube = match &self.control_tube {
+                            Some(control_tube) => control_tube,
+                            None => continue,
+                        };
+                        let command = match control_tube.recv::<InputControlCommand>() {
+                            Ok(command) => command,
+                            Err(TubeError::Disconnected) => {
+                                let _ = wait_ctx.delete(control_tube.get_read_notifier());
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("failed to receive input control command: {}", e);
+                                continue;
+                            }
+                        };
+                        let result = self.handle_control_command(command);
+                        if let Some(control_tube) = &self.control_tube {
+                            if let Err(e) = control_tube.send(&result) {
+                                error!("failed to send input control result: {}", e);
+                            }
+                        }
+                        eventq_needs_interrupt |= self.send_events();
+                    }
                     Token::Kill => {
                         let _ = kill_evt.wait();
                         break 'wait;
@@ -534,6 +665,25 @@ pub struct Input<T: EventSource + Send + 'static> {
     config: VirtioInputConfig,
     source: Option<T>,
     virtio_features: u64,
+    control_tube: Option<Tube>,
+    recorder: Option<EventRecorder>,
+}
+
+impl<T: EventSource + Send + 'static> Input<T> {
+    /// Sets the tube on which the device receives `InputControlCommand`s to inject and record
+    /// events.
+    pub fn set_control_tube(&mut self, control_tube: Tube) {
+        self.control_tube = Some(control_tube);
+    }
+
+    // Stops the worker and takes back the source, control tube and recorder. Returns the queues.
+    fn stop_worker(&mut self) -> Option<(Queue, Queue)> {
+        let worker = self.worker_thread.take()?.stop();
+        self.source = Some(worker.event_source);
+        self.control_tube = worker.control_tube;
+        self.recorder = worker.recorder;
+        Some((worker.event_queue, worker.status_queue))
+    }
 }
 
 /// Snapshot of [Input]'s state.
@@ -548,10 +698,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            keep_rds.push(source.as_raw_descriptor());
+        }
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
-        Vec::new()
+        keep_rds
     }
 
     fn device_type(&self) -> DeviceType {
@@ -590,12 +744,17 @@ where
             .source
             .take()
             .context("tried to activate device without a source for events")?;
+        let control_tube = self.control_tube.take();
+        let recorder = self.recorder.take();
         self.worker_thread = Some(WorkerThread::start("v_input", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 event_source: source,
                 event_queue,
                 status_queue,
+                control_tube,
+                injected_events: VecDeque::new(),
+                recorder,
             };
             worker.run(kill_evt);
             worker
@@ -605,19 +764,12 @@ where
     }
 
     fn reset(&mut self) -> bool {
-        if let Some(worker_thread) = self.worker_thread.take() {
-            let worker = worker_thread.stop();
-            self.source = Some(worker.event_source);
-            return true;
-        }
-        false
+        self.stop_worker().is_some()
     }
 
     fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
-        if let Some(worker_thread) = self.worker_thread.take() {
-            let worker = worker_thread.stop();
-            self.source = Some(worker.event_source);
-            let queues = BTreeMap::from([(0, worker.event_queue), (1, worker.status_queue)]);
+        if let Some((event_queue, status_queue)) = self.stop_worker() {
+            let queues = BTreeMap::from([(0, event_queue), (1, status_queue)]);
             Ok(Some(queues))
         } else {
             Ok(None)
@@ -666,6 +818,8 @@ where
         config: VirtioInputConfig::from_evdev(&source)?,
         source: Some(EvdevEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -686,6 +840,8 @@ where
         config: defaults::new_single_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -706,6 +862,8 @@ where
         config: defaults::new_multi_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -727,6 +885,8 @@ where
         config: defaults::new_trackpad_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -744,6 +904,8 @@ where
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -761,6 +923,8 @@ where
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -778,6 +942,8 @@ where
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
 
@@ -795,5 +961,28 @@ where
         config: defaults::new_rotary_config(idx),
         source: Some(SocketEventSource::new(source)),
         virtio_features,
+        control_tube: None,
+        recorder: None,
+    })
+}
+
+/// Creates a new virtio input device whose name, supported events and axes are read from the
+/// descriptor file at `config_path`.
+pub fn new_custom<T>(
+    idx: u32,
+    source: T,
+    config_path: &Path,
+    virtio_features: u64,
+) -> Result<Input<SocketEventSource<T>>>
+where
+    T: Read + Write + AsRawDescriptor + Send + 'static,
+{
+    Ok(Input {
+        worker_thread: None,
+        config: custom::new_custom_config(idx, config_path)?,
+        source: Some(SocketEventSource::new(source)),
+        virtio_features,
+        control_tube: None,
+        recorder: None,
     })
 }
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::Cell;
 use std::fmt;
 use std::io;
 use std::io::Read;
@@ -13,10 +14,13 @@ use async_trait::async_trait;
 use audio_streams::capture::AsyncCaptureBuffer;
 use audio_streams::AsyncPlaybackBuffer;
 use audio_streams::BoxError;
+use audio_streams::SampleFormat;
 use base::debug;
 use base::error;
+use base::Error as SysError;
 use cros_async::sync::Condvar;
 use cros_async::sync::RwLock as AsyncRwLock;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::TimerAsync;
@@ -28,8 +32,13 @@ use futures::FutureExt;
 use futures::SinkExt;
 use futures::StreamExt;
 use thiserror::Error as ThisError;
+use vm_control::SndControlCommand;
+use vm_control::SndControlResult;
 use zerocopy::AsBytes;
+use zerocopy::FromZeroes;
 
+use super::controls::apply_gain;
+use super::controls::SndState;
 use super::Error;
 use super::SndData;
 use super::WorkerStatus;
@@ -215,9 +224,17 @@ async fn write_data(
     mut dst_buf: AsyncPlaybackBuffer<'_>,
     reader: Option<&mut Reader>,
     buffer_writer: &mut Box<dyn PlaybackBufferWriter>,
+    format: SampleFormat,
+    gain: f64,
 ) -> Result<u32, Error> {
     let transferred = match reader {
-        Some(reader) => buffer_writer.copy_to_buffer(&mut dst_buf, reader)?,
+        Some(reader) if gain == 1.0 => buffer_writer.copy_to_buffer(&mut dst_buf, reader)?,
+        Some(reader) => {
+            let mut samples = vec![0; buffer_writer.endpoint_period_bytes()];
+            let len = reader.read(&mut samples).map_err(Error::Io)?;
+            apply_gain(format, gain, &mut samples[..len]);
+            dst_buf.copy_from(&mut &samples[..len]).map_err(Error::Io)?
+        }
         None => dst_buf
             .copy_from(&mut io::repeat(0).take(buffer_writer.endpoint_period_bytes() as u64))
             .map_err(Error::Io)?,
@@ -240,9 +257,18 @@ async fn read_data<'a>(
     mut src_buf: AsyncCaptureBuffer<'a>,
     writer: Option<&mut Writer>,
     period_bytes: usize,
+    format: SampleFormat,
+    gain: f64,
 ) -> Result<u32, Error> {
     let transferred = match writer {
-        Some(writer) => src_buf.copy_to(writer),
+        Some(writer) if gain == 1.0 => src_buf.copy_to(writer),
+        Some(writer) => {
+            let mut samples = vec![0; period_bytes];
+            src_buf.read(&mut samples).and_then(|len| {
+                apply_gain(format, gain, &mut samples[..len]);
+                writer.write(&samples[..len])
+            })
+        }
         None => src_buf.copy_to(&mut io::sink()),
     }
     .map_err(Error::Io)?;
@@ -319,6 +345,8 @@ pub async fn start_pcm_worker(
     status_mutex: Rc<AsyncRwLock<WorkerStatus>>,
     mut sender: mpsc::UnboundedSender<PcmResponse>,
     period_dur: Duration,
+    format: SampleFormat,
+    gain: Rc<Cell<f64>>,
     release_signal: Rc<(AsyncRwLock<bool>, Condvar)>,
 ) -> Result<(), Error> {
     let res = pcm_worker_loop(
@@ -328,6 +356,8 @@ pub async fn start_pcm_worker(
         &status_mutex,
         &mut sender,
         period_dur,
+        format,
+        gain,
         release_signal,
     )
     .await;
@@ -351,6 +381,8 @@ async fn pcm_worker_loop(
     status_mutex: &Rc<AsyncRwLock<WorkerStatus>>,
     sender: &mut mpsc::UnboundedSender<PcmResponse>,
     period_dur: Duration,
+    format: SampleFormat,
+    gain: Rc<Cell<f64>>,
     release_signal: Rc<(AsyncRwLock<bool>, Condvar)>,
 ) -> Result<(), Error> {
     let on_release = async {
@@ -396,30 +428,37 @@ async fn pcm_worker_loop(
             match *worker_status {
                 WorkerStatus::Quit => {
                     drain_desc_receiver(desc_receiver, sender).await?;
-                    if let Err(e) = write_data(dst_buf, None, buffer_writer).await {
+                    if let Err(e) =
+                        write_data(dst_buf, None, buffer_writer, format, gain.get()).await
+                    {
                         error!("Error on write_data after worker quit: {}", e)
                     }
                     break Ok(());
                 }
                 WorkerStatus::Pause => {
-                    write_data(dst_buf, None, buffer_writer).await?;
+                    write_data(dst_buf, None, buffer_writer, format, gain.get()).await?;
                 }
                 WorkerStatus::Running => match desc_receiver.try_next() {
                     Err(e) => {
                         error!("Underrun. No new DescriptorChain while running: {}", e);
-                        write_data(dst_buf, None, buffer_writer).await?;
+                        write_data(dst_buf, None, buffer_writer, format, gain.get()).await?;
                     }
                     Ok(None) => {
                         error!("Unreachable. status should be Quit when the channel is closed");
-                        write_data(dst_buf, None, buffer_writer).await?;
+                        write_data(dst_buf, None, buffer_writer, format, gain.get()).await?;
                         return Err(Error::InvalidPCMWorkerState);
                     }
                     Ok(Some(mut desc_chain)) => {
                         // stream_id was already read in handle_pcm_queue
-                        let status =
-                            write_data(dst_buf, Some(&mut desc_chain.reader), buffer_writer)
-                                .await
-                                .into();
+                        let status = write_data(
+                            dst_buf,
+                            Some(&mut desc_chain.reader),
+                            buffer_writer,
+                            format,
+                            gain.get(),
+                        )
+                        .await
+                        .into();
                         sender
                             .send(PcmResponse {
                                 desc_chain,
@@ -448,28 +487,35 @@ async fn pcm_worker_loop(
             match *worker_status {
                 WorkerStatus::Quit => {
                     drain_desc_receiver(desc_receiver, sender).await?;
-                    if let Err(e) = read_data(src_buf, None, period_bytes).await {
+                    if let Err(e) = read_data(src_buf, None, period_bytes, format, gain.get()).await
+                    {
                         error!("Error on read_data after worker quit: {}", e)
                     }
                     break Ok(());
                 }
                 WorkerStatus::Pause => {
-                    read_data(src_buf, None, period_bytes).await?;
+                    read_data(src_buf, None, period_bytes, format, gain.get()).await?;
                 }
                 WorkerStatus::Running => match desc_receiver.try_next() {
                     Err(e) => {
                         error!("Overrun. No new DescriptorChain while running: {}", e);
-                        read_data(src_buf, None, period_bytes).await?;
+                        read_data(src_buf, None, period_bytes, format, gain.get()).await?;
                     }
                     Ok(None) => {
                         error!("Unreachable. status should be Quit when the channel is closed");
-                        read_data(src_buf, None, period_bytes).await?;
+                        read_data(src_buf, None, period_bytes, format, gain.get()).await?;
                         return Err(Error::InvalidPCMWorkerState);
                     }
                     Ok(Some(mut desc_chain)) => {
-                        let status = read_data(src_buf, Some(&mut desc_chain.writer), period_bytes)
-                            .await
-                            .into();
+                        let status = read_data(
+                            src_buf,
+                            Some(&mut desc_chain.writer),
+                            period_bytes,
+                            format,
+                            gain.get(),
+                        )
+                        .await
+                        .into();
                         sender
                             .send(PcmResponse {
                                 desc_chain,
@@ -655,6 +701,7 @@ pub async fn handle_ctrl_queue(
     ex: &Executor,
     streams: &Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
     snd_data: &SndData,
+    snd_state: &Rc<AsyncRwLock<SndState>>,
     queue: Rc<AsyncRwLock<Queue>>,
     queue_event: &mut EventAsync,
     interrupt: Interrupt,
@@ -711,9 +758,12 @@ pub async fn handle_ctrl_queue(
                     writer
                         .write_obj(VIRTIO_SND_S_OK)
                         .map_err(Error::WriteResponse)?;
+                    let snd_state = snd_state.read_lock().await;
                     for i in start_id..(start_id + count) {
+                        let mut jack_info = snd_data.jack_info[i];
+                        jack_info.connected = snd_state.jacks_connected[i] as u8;
                         writer
-                            .write_all(snd_data.jack_info[i].as_bytes())
+                            .write_all(jack_info.as_bytes())
                             .map_err(Error::WriteResponse)?;
                     }
                     Ok(())
@@ -778,8 +828,98 @@ pub async fn handle_ctrl_queue(
                     }
                     Ok(())
                 }
-                VIRTIO_SND_R_JACK_REMAP => {
-                    unreachable!("remap is unsupported");
+                VIRTIO_SND_R_CTL_INFO => {
+                    let query_info: virtio_snd_query_info =
+                        reader.read_obj().map_err(Error::ReadMessage)?;
+                    let start_id: usize = u32::from(query_info.start_id) as usize;
+                    let count: usize = u32::from(query_info.count) as usize;
+                    if start_id + count > snd_data.ctl_info.len() {
+                        error!(
+                            "start_id({}) + count({}) must be smaller than \
+                            the number of controls ({})",
+                            start_id,
+                            count,
+                            snd_data.ctl_info.len()
+                        );
+                        return writer
+                            .write_obj(VIRTIO_SND_S_BAD_MSG)
+                            .map_err(Error::WriteResponse);
+                    }
+                    writer
+                        .write_obj(VIRTIO_SND_S_OK)
+                        .map_err(Error::WriteResponse)?;
+                    for i in start_id..(start_id + count) {
+                        writer
+                            .write_all(snd_data.ctl_info[i].virtio_info().as_bytes())
+                            .map_err(Error::WriteResponse)?;
+                    }
+                    Ok(())
+                }
+                VIRTIO_SND_R_CTL_READ => {
+                    let hdr: virtio_snd_ctl_hdr = reader.read_obj().map_err(Error::ReadMessage)?;
+                    let control_id = u32::from(hdr.control_id) as usize;
+                    let value = match snd_state.read_lock().await.control_values.get(control_id) {
+                        Some(value) => *value,
+                        None => {
+                            error!(
+                                "control_id {} < controls {}",
+                                control_id,
+                                snd_data.ctl_info.len()
+                            );
+                            return writer
+                                .write_obj(VIRTIO_SND_S_BAD_MSG)
+                                .map_err(Error::WriteResponse);
+                        }
+                    };
+                    writer
+                        .write_obj(VIRTIO_SND_S_OK)
+                        .map_err(Error::WriteResponse)?;
+                    let mut ctl_value = virtio_snd_ctl_value::new_zeroed();
+                    ctl_value.value[0] = value.into();
+                    writer
+                        .write_all(ctl_value.as_bytes())
+                        .map_err(Error::WriteResponse)
+                }
+                VIRTIO_SND_R_CTL_WRITE => {
+                    let hdr: virtio_snd_ctl_hdr = reader.read_obj().map_err(Error::ReadMessage)?;
+                    let ctl_value: virtio_snd_ctl_value =
+                        reader.read_obj().map_err(Error::ReadMessage)?;
+                    let control_id = u32::from(hdr.control_id) as usize;
+                    let value = u32::from(ctl_value.value[0]);
+                    match snd_data.ctl_info.get(control_id) {
+                        Some(control) if control.is_valid_value(value) => {}
+                        Some(_) => {
+                            error!("Invalid value {} for control {}", value, control_id);
+                            return writer
+                                .write_obj(VIRTIO_SND_S_BAD_MSG)
+                                .map_err(Error::WriteResponse);
+                        }
+                        None => {
+                            error!(
+                                "control_id {} < controls {}",
+                                control_id,
+                                snd_data.ctl_info.len()
+                            );
+                            return writer
+                                .write_obj(VIRTIO_SND_S_BAD_MSG)
+                                .map_err(Error::WriteResponse);
+                        }
+                    }
+                    set_control_value(streams, snd_data, snd_state, control_id, value).await;
+                    writer
+                        .write_obj(VIRTIO_SND_S_OK)
+                        .map_err(Error::WriteResponse)
+                }
+                VIRTIO_SND_R_JACK_REMAP
+                | VIRTIO_SND_R_CTL_ENUM_ITEMS
+                | VIRTIO_SND_R_CTL_TLV_READ
+                | VIRTIO_SND_R_CTL_TLV_WRITE
+                | VIRTIO_SND_R_CTL_TLV_COMMAND => {
+                    // No jack supports remapping, and no control is enumerated or has TLV data.
+                    error!("Unsupported request: {}", code);
+                    return writer
+                        .write_obj(VIRTIO_SND_S_NOT_SUPP)
+                        .map_err(Error::WriteResponse);
                 }
                 VIRTIO_SND_R_PCM_SET_PARAMS => {
                     // Raise VIRTIO_SND_S_BAD_MSG or IO error?
@@ -896,20 +1036,131 @@ pub async fn handle_ctrl_queue(
     Ok(())
 }
 
+/// Sets the value of a control and forwards the resulting volume and mute state to the streams
+/// the control applies to.
+async fn set_control_value(
+    streams: &Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
+    snd_data: &SndData,
+    snd_state: &Rc<AsyncRwLock<SndState>>,
+    control_id: usize,
+    value: u32,
+) {
+    let mut snd_state = snd_state.lock().await;
+    snd_state.control_values[control_id] = value;
+    let control = &snd_data.ctl_info[control_id];
+    let streams = streams.read_lock().await;
+    for (stream, pcm_info) in streams.iter().zip(snd_data.pcm_info_iter()) {
+        if control.applies_to(pcm_info) {
+            let (volume, muted) = snd_state.stream_volume(snd_data, pcm_info);
+            stream.lock().await.set_volume(volume, muted);
+        }
+    }
+}
+
 /// Send events to the audio driver.
+///
+/// Events received from `event_recv` are written to the buffers of the event queue as the driver
+/// makes them available.
 pub async fn handle_event_queue(
-    mut queue: Queue,
-    mut queue_event: EventAsync,
+    queue: Rc<AsyncRwLock<Queue>>,
+    queue_event: &mut EventAsync,
     interrupt: Interrupt,
+    event_recv: &mut mpsc::UnboundedReceiver<virtio_snd_event>,
+    reset_signal: Option<&(AsyncRwLock<bool>, Condvar)>,
 ) -> Result<(), Error> {
+    let on_reset = await_reset_signal(reset_signal).fuse();
+    pin_mut!(on_reset);
+
+    let mut queue = queue.lock().await;
     loop {
-        let desc_chain = queue
-            .next_async(&mut queue_event)
-            .await
-            .map_err(Error::Async)?;
+        let event = select! {
+            _ = on_reset => break,
+            event = event_recv.next() => match event {
+                Some(event) => event,
+                // All the senders are gone, no more events will be sent.
+                None => break,
+            },
+        };
+        let mut desc_chain = {
+            let next_async = queue.next_async(queue_event).fuse();
+            pin_mut!(next_async);
 
-        // TODO(woodychow): Poll and forward events from cras asynchronously (API to be added)
-        queue.add_used(desc_chain, 0);
+            select! {
+                _ = on_reset => break,
+                res = next_async => res.map_err(Error::Async)?,
+            }
+        };
+
+        desc_chain
+            .writer
+            .write_obj(event)
+            .map_err(Error::WriteResponse)?;
+        let len = desc_chain.writer.bytes_written() as u32;
+        queue.add_used(desc_chain, len);
         queue.trigger_interrupt(&interrupt);
     }
+    Ok(())
+}
+
+/// Handles the commands received from the control tube of the device, sending the resulting jack
+/// events to `event_send`.
+pub async fn handle_control_tube(
+    control_tube: &Option<AsyncTube>,
+    snd_data: &SndData,
+    snd_state: &Rc<AsyncRwLock<SndState>>,
+    event_send: mpsc::UnboundedSender<virtio_snd_event>,
+    reset_signal: Option<&(AsyncRwLock<bool>, Condvar)>,
+) -> Result<(), Error> {
+    let control_tube = match control_tube {
+        Some(c) => c,
+        None => {
+            await_reset_signal(reset_signal).await;
+            return Ok(());
+        }
+    };
+    let on_reset = await_reset_signal(reset_signal).fuse();
+    pin_mut!(on_reset);
+
+    loop {
+        let command = {
+            let next = control_tube.next::<SndControlCommand>().fuse();
+            pin_mut!(next);
+
+            select! {
+                _ = on_reset => break,
+                res = next => res.map_err(Error::ControlTube)?,
+            }
+        };
+        let result = match command {
+            SndControlCommand::SetJack { jack_id, connected } => {
+                let jack_id = jack_id as usize;
+                if jack_id < snd_data.jack_info.len() {
+                    let mut snd_state = snd_state.lock().await;
+                    if snd_state.jacks_connected[jack_id] != connected {
+                        snd_state.jacks_connected[jack_id] = connected;
+                        let code = if connected {
+                            VIRTIO_SND_EVT_JACK_CONNECTED
+                        } else {
+                            VIRTIO_SND_EVT_JACK_DISCONNECTED
+                        };
+                        event_send
+                            .unbounded_send(virtio_snd_event {
+                                hdr: virtio_snd_hdr { code: code.into() },
+                                data: (jack_id as u32).into(),
+                            })
+                            .map_err(|e| Error::MpscSend(e.into_send_error()))?;
+                    }
+                    SndControlResult::Ok
+                } else {
+                    error!("jack_id {} < jacks {}", jack_id, snd_data.jack_info.len());
+                    SndControlResult::Err(SysError::new(libc::EINVAL))
+                }
+            }
+        };
+        control_tube
+            .send(result)
+            .await
+            .map_err(Error::ControlTube)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,255 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Control elements of the PCM devices and state of the jacks and controls, which change while the
+//! device runs.
+
+use audio_streams::SampleFormat;
+use serde::Deserialize;
+use serde::Serialize;
+use zerocopy::FromZeroes;
+
+use super::SndData;
+use crate::virtio::snd::constants::*;
+use crate::virtio::snd::layout::*;
+
+/// Maximum value of the volume controls, for a gain of 1.0.
+pub const VOLUME_MAX: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlKind {
+    /// Volume of the streams, from 0 to [`VOLUME_MAX`].
+    Volume,
+    /// Switch turning the streams on (1) or muting them (0).
+    Switch,
+}
+
+/// A control element applying to all the streams of a PCM device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SndControl {
+    pub hda_fn_nid: u32,
+    pub direction: u8, // VIRTIO_SND_D_*
+    pub kind: ControlKind,
+}
+
+impl SndControl {
+    /// Returns the value of the control when the device starts.
+    pub fn default_value(&self) -> u32 {
+        match self.kind {
+            ControlKind::Volume => VOLUME_MAX,
+            ControlKind::Switch => 1,
+        }
+    }
+
+    /// Returns true if `value` is in the range of the control.
+    pub fn is_valid_value(&self, value: u32) -> bool {
+        match self.kind {
+            ControlKind::Volume => value <= VOLUME_MAX,
+            ControlKind::Switch => value <= 1,
+        }
+    }
+
+    /// Returns true if the control applies to the stream described by `pcm_info`.
+    pub fn applies_to(&self, pcm_info: &virtio_snd_pcm_info) -> bool {
+        u32::from(pcm_info.hdr.hda_fn_nid) == self.hda_fn_nid
+            && pcm_info.direction == self.direction
+    }
+
+    /// Returns the name of the control, following the ALSA naming conventions so that mixers
+    /// pick it up.
+    pub fn name(&self) -> &'static str {
+        match (self.direction, self.kind) {
+            (VIRTIO_SND_D_OUTPUT, ControlKind::Volume) => "PCM Playback Volume",
+            (VIRTIO_SND_D_OUTPUT, ControlKind::Switch) => "PCM Playback Switch",
+            (_, ControlKind::Volume) => "Capture Volume",
+            (_, ControlKind::Switch) => "Capture Switch",
+        }
+    }
+
+    /// Returns the information structure of the control sent to the driver.
+    pub fn virtio_info(&self) -> virtio_snd_ctl_info {
+        let mut info = virtio_snd_ctl_info::new_zeroed();
+        info.hdr.hda_fn_nid = self.hda_fn_nid.into();
+        let (role, type_) = match self.kind {
+            ControlKind::Volume => {
+                info.value[0] = 0.into();
+                info.value[1] = VOLUME_MAX.into();
+                info.value[2] = 1.into();
+                (VIRTIO_SND_CTL_ROLE_VOLUME, VIRTIO_SND_CTL_TYPE_INTEGER)
+            }
+            ControlKind::Switch => (VIRTIO_SND_CTL_ROLE_MUTE, VIRTIO_SND_CTL_TYPE_BOOLEAN),
+        };
+        info.role = role.into();
+        info.type_ = type_.into();
+        info.access = (1 << VIRTIO_SND_CTL_ACCESS_READ | 1 << VIRTIO_SND_CTL_ACCESS_WRITE).into();
+        info.count = 1.into();
+        // Devices have one control of each name, told apart by their index.
+        info.index = self.hda_fn_nid.into();
+        let name = self.name().as_bytes();
+        info.name[..name.len()].copy_from_slice(name);
+        info
+    }
+}
+
+/// Connection state of the jacks and values of the controls, indexed like `jack_info` and
+/// `ctl_info` in [`SndData`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SndState {
+    pub(crate) jacks_connected: Vec<bool>,
+    pub(crate) control_values: Vec<u32>,
+}
+
+impl SndState {
+    /// Creates the state of a device starting with the jack states and control values of
+    /// `snd_data`.
+    pub fn new(snd_data: &SndData) -> Self {
+        SndState {
+            jacks_connected: snd_data
+                .jack_info
+                .iter()
+                .map(|jack_info| jack_info.connected != 0)
+                .collect(),
+            control_values: snd_data
+                .ctl_info
+                .iter()
+                .map(SndControl::default_value)
+                .collect(),
+        }
+    }
+
+    /// Returns the volume and mute state of the streams described by `pcm_info`.
+    pub fn stream_volume(&self, snd_data: &SndData, pcm_info: &virtio_snd_pcm_info) -> (f64, bool) {
+        let mut volume = 1.0;
+        let mut muted = false;
+        for (control, value) in snd_data.ctl_info.iter().zip(&self.control_values) {
+            if !control.applies_to(pcm_info) {
+                continue;
+            }
+            match control.kind {
+                ControlKind::Volume => volume = *value as f64 / VOLUME_MAX as f64,
+                ControlKind::Switch => muted = *value == 0,
+            }
+        }
+        (volume, muted)
+    }
+}
+
+/// Scales the little-endian samples of `format` in `samples` by `gain`, from 0.0 (silence) to 1.0.
+///
+/// None of the audio backends apply the volume of their streams, so the volume and switch controls
+/// are applied to the samples exchanged with the driver.
+pub fn apply_gain(format: SampleFormat, gain: f64, samples: &mut [u8]) {
+    match format {
+        SampleFormat::U8 => {
+            for sample in samples {
+                *sample = ((*sample as f64 - 128.0) * gain + 128.0) as u8;
+            }
+        }
+        SampleFormat::S16LE => {
+            for sample in samples.chunks_exact_mut(2) {
+                let value = i16::from_le_bytes([sample[0], sample[1]]);
+                sample.copy_from_slice(&((value as f64 * gain) as i16).to_le_bytes());
+            }
+        }
+        SampleFormat::S24LE | SampleFormat::S32LE => {
+            for sample in samples.chunks_exact_mut(4) {
+                let mut value = i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]);
+                if format == SampleFormat::S24LE {
+                    // Sign-extend the 24 bits of the sample, ignoring the padding byte.
+                    value = value << 8 >> 8;
+                }
+                sample.copy_from_slice(&((value as f64 * gain) as i32).to_le_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm_info(hda_fn_nid: u32, direction: u8) -> virtio_snd_pcm_info {
+        virtio_snd_pcm_info {
+            hdr: virtio_snd_info {
+                hda_fn_nid: hda_fn_nid.into(),
+            },
+            direction,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn control_info() {
+        let control = SndControl {
+            hda_fn_nid: 1,
+            direction: VIRTIO_SND_D_OUTPUT,
+            kind: ControlKind::Volume,
+        };
+        let info = control.virtio_info();
+        assert_eq!(info.role.to_native(), VIRTIO_SND_CTL_ROLE_VOLUME);
+        assert_eq!(info.type_.to_native(), VIRTIO_SND_CTL_TYPE_INTEGER);
+        assert_eq!(info.index.to_native(), 1);
+        assert_eq!(info.value[1].to_native(), VOLUME_MAX);
+        assert_eq!(&info.name[..20], b"PCM Playback Volume\0");
+
+        assert!(control.applies_to(&pcm_info(1, VIRTIO_SND_D_OUTPUT)));
+        assert!(!control.applies_to(&pcm_info(1, VIRTIO_SND_D_INPUT)));
+        assert!(!control.applies_to(&pcm_info(0, VIRTIO_SND_D_OUTPUT)));
+        assert!(control.is_valid_value(VOLUME_MAX));
+        assert!(!control.is_valid_value(VOLUME_MAX + 1));
+    }
+
+    #[test]
+    fn stream_volume() {
+        let snd_data = SndData {
+            jack_info: vec![],
+            pcm_info: vec![],
+            chmap_info: vec![],
+            ctl_info: vec![
+                SndControl {
+                    hda_fn_nid: 0,
+                    direction: VIRTIO_SND_D_OUTPUT,
+                    kind: ControlKind::Volume,
+                },
+                SndControl {
+                    hda_fn_nid: 0,
+                    direction: VIRTIO_SND_D_OUTPUT,
+                    kind: ControlKind::Switch,
+                },
+            ],
+        };
+        let mut state = SndState::new(&snd_data);
+        let output = pcm_info(0, VIRTIO_SND_D_OUTPUT);
+        assert_eq!(state.stream_volume(&snd_data, &output), (1.0, false));
+
+        state.control_values = vec![25, 0];
+        assert_eq!(state.stream_volume(&snd_data, &output), (0.25, true));
+        // Streams of other devices are not affected.
+        let input = pcm_info(0, VIRTIO_SND_D_INPUT);
+        assert_eq!(state.stream_volume(&snd_data, &input), (1.0, false));
+    }
+
+    #[test]
+    fn gain() {
+        let mut samples = [0, 64, 128, 255];
+        apply_gain(SampleFormat::U8, 0.5, &mut samples);
+        assert_eq!(samples, [64, 96, 128, 191]);
+
+        let mut samples = [0u8; 4];
+        samples[..2].copy_from_slice(&1000i16.to_le_bytes());
+        samples[2..].copy_from_slice(&(-1000i16).to_le_bytes());
+        apply_gain(SampleFormat::S16LE, 0.25, &mut samples);
+        assert_eq!(i16::from_le_bytes([samples[0], samples[1]]), 250);
+        assert_eq!(i16::from_le_bytes([samples[2], samples[3]]), -250);
+
+        // -2 in 24 bits, with a padding byte of 0.
+        let mut samples = [0xfe, 0xff, 0xff, 0x00];
+        apply_gain(SampleFormat::S24LE, 0.5, &mut samples);
+        assert_eq!(i32::from_le_bytes(samples), -1);
+
+        let mut samples = i32::MIN.to_le_bytes();
+        apply_gain(SampleFormat::S32LE, 0.0, &mut samples);
+        assert_eq!(samples, [0; 4]);
+    }
+}
@@ -20,11 +20,14 @@ use base::Descriptor;
 use base::Error as SysError;
 use base::Event;
 use base::RawDescriptor;
+use base::Tube;
+use base::TubeError;
 use base::WorkerThread;
 use cros_async::block_on;
 use cros_async::sync::Condvar;
 use cros_async::sync::RwLock as AsyncRwLock;
 use cros_async::AsyncError;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use futures::channel::mpsc;
@@ -46,6 +49,9 @@ use crate::virtio::async_utils;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::snd::virtio_snd_config;
 use crate::virtio::snd::common_backend::async_funcs::*;
+use crate::virtio::snd::common_backend::controls::ControlKind;
+use crate::virtio::snd::common_backend::controls::SndControl;
+use crate::virtio::snd::common_backend::controls::SndState;
 use crate::virtio::snd::common_backend::stream_info::StreamInfo;
 use crate::virtio::snd::common_backend::stream_info::StreamInfoBuilder;
 use crate::virtio::snd::common_backend::stream_info::StreamInfoSnapshot;
@@ -54,6 +60,8 @@ use crate::virtio::snd::file_backend::create_file_stream_source_generators;
 use crate::virtio::snd::file_backend::Error as FileError;
 use crate::virtio::snd::layout::*;
 use crate::virtio::snd::null_backend::create_null_stream_source_generators;
+use crate::virtio::snd::parameters::ChannelLayout;
+use crate::virtio::snd::parameters::JackState;
 use crate::virtio::snd::parameters::Parameters;
 use crate::virtio::snd::parameters::StreamSourceBackend;
 use crate::virtio::snd::sys::create_stream_source_generators as sys_create_stream_source_generators;
@@ -68,6 +76,7 @@ use crate::virtio::Queue;
 use crate::virtio::VirtioDevice;
 
 pub mod async_funcs;
+pub mod controls;
 pub mod stream_info;
 
 // control + event + tx + rx queue
@@ -138,6 +147,9 @@ pub enum Error {
     // Failed to generate StreamSource
     #[error("Failed to generate stream source: {0}")]
     GenerateStreamSource(BoxError),
+    /// Error on the control tube of the device.
+    #[error("Failed to communicate on the control tube: {0}")]
+    ControlTube(TubeError),
     // PCM worker unexpectedly quitted.
     #[error("PCM worker quitted unexpectedly")]
     PCMWorkerQuittedUnexpectedly,
@@ -164,6 +176,7 @@ pub struct SndData {
     pub(crate) jack_info: Vec<virtio_snd_jack_info>,
     pub(crate) pcm_info: Vec<virtio_snd_pcm_info>,
     pub(crate) chmap_info: Vec<virtio_snd_chmap_info>,
+    pub(crate) ctl_info: Vec<SndControl>,
 }
 
 impl SndData {
@@ -198,6 +211,8 @@ pub struct PcmResponse {
 pub struct VirtioSnd {
     cfg: virtio_snd_config,
     snd_data: SndData,
+    snd_state: SndState,
+    control_tube: Option<Tube>,
     stream_info_builders: Vec<StreamInfoBuilder>,
     avail_features: u64,
     acked_features: u64,
@@ -214,21 +229,34 @@ struct VirtioSndSnapshot {
     queue_sizes: Vec<u16>,
     streams_state: Option<Vec<StreamInfoSnapshot>>,
     snd_data: SndData,
+    snd_state: SndState,
 }
 
 impl VirtioSnd {
-    pub fn new(base_features: u64, params: Parameters) -> Result<VirtioSnd, Error> {
+    /// Creates a new sound device. Jacks of the device are plugged and unplugged with the
+    /// commands received from `control_tube`.
+    pub fn new(
+        base_features: u64,
+        params: Parameters,
+        control_tube: Option<Tube>,
+    ) -> Result<VirtioSnd, Error> {
         let params = resize_parameters_pcm_device_config(params);
         let cfg = hardcoded_virtio_snd_config(&params);
         let snd_data = hardcoded_snd_data(&params);
-        let avail_features = base_features;
+        let snd_state = SndState::new(&snd_data);
+        let avail_features = base_features | 1 << VIRTIO_SND_F_CTLS;
         let mut keep_rds: Vec<RawDescriptor> = Vec::new();
+        if let Some(control_tube) = &control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
 
         let stream_info_builders = create_stream_info_builders(&params, &snd_data, &mut keep_rds)?;
 
         Ok(VirtioSnd {
             cfg,
             snd_data,
+            snd_state,
+            control_tube,
             stream_info_builders,
             avail_features,
             acked_features: 0,
@@ -279,106 +307,122 @@ pub(crate) fn create_stream_info_builders(
 
 // To be used with hardcoded_snd_data
 pub fn hardcoded_virtio_snd_config(params: &Parameters) -> virtio_snd_config {
+    let snd_data = hardcoded_snd_data(params);
     virtio_snd_config {
-        jacks: 0.into(),
+        jacks: (snd_data.jack_info.len() as u32).into(),
         streams: params.get_total_streams().into(),
-        chmaps: (params.num_output_devices * 3 + params.num_input_devices).into(),
+        chmaps: (snd_data.chmap_info.len() as u32).into(),
+        controls: (snd_data.ctl_info.len() as u32).into(),
     }
 }
 
 // To be used with hardcoded_virtio_snd_config
 pub fn hardcoded_snd_data(params: &Parameters) -> SndData {
-    let jack_info: Vec<virtio_snd_jack_info> = Vec::new();
+    let mut jack_info: Vec<virtio_snd_jack_info> = Vec::new();
     let mut pcm_info: Vec<virtio_snd_pcm_info> = Vec::new();
     let mut chmap_info: Vec<virtio_snd_chmap_info> = Vec::new();
+    let mut ctl_info: Vec<SndControl> = Vec::new();
+
+    // (direction, number of devices, number of streams per device)
+    let directions = [
+        (
+            VIRTIO_SND_D_OUTPUT,
+            params.num_output_devices,
+            params.num_output_streams,
+        ),
+        (
+            VIRTIO_SND_D_INPUT,
+            params.num_input_devices,
+            params.num_input_streams,
+        ),
+    ];
+    // (hda_fn_nid, direction, device parameters, channel maps) of every device.
+    let mut devices = Vec::new();
+    for (direction, num_devices, _) in directions {
+        for dev in 0..num_devices {
+            let device_params = params.get_device_params_or_default(direction, dev);
+            let chmaps = device_params.chmaps_or_default(direction);
+            devices.push((dev, direction, device_params, chmaps));
+        }
+    }
 
-    for dev in 0..params.num_output_devices {
-        for _ in 0..params.num_output_streams {
-            pcm_info.push(virtio_snd_pcm_info {
-                hdr: virtio_snd_info {
-                    hda_fn_nid: dev.into(),
-                },
-                features: 0.into(), /* 1 << VIRTIO_SND_PCM_F_XXX */
-                formats: SUPPORTED_FORMATS.into(),
-                rates: SUPPORTED_FRAME_RATES.into(),
-                direction: VIRTIO_SND_D_OUTPUT,
-                channels_min: 1,
-                channels_max: 6,
-                padding: [0; 5],
-            });
+    for (direction, _, num_streams) in directions {
+        for (dev, _, _, chmaps) in devices.iter().filter(|d| d.1 == direction) {
+            let channels_max = chmaps
+                .iter()
+                .map(ChannelLayout::channels)
+                .max()
+                .unwrap_or(2);
+            for _ in 0..num_streams {
+                pcm_info.push(virtio_snd_pcm_info {
+                    hdr: virtio_snd_info {
+                        hda_fn_nid: (*dev).into(),
+                    },
+                    features: 0.into(), /* 1 << VIRTIO_SND_PCM_F_XXX */
+                    formats: SUPPORTED_FORMATS.into(),
+                    rates: SUPPORTED_FRAME_RATES.into(),
+                    direction,
+                    channels_min: 1,
+                    channels_max,
+                    padding: [0; 5],
+                });
+            }
+        }
+    }
+
+    // Lists the first channel map of every device, then the second one, and so on.
+    let num_slots = devices.iter().map(|d| d.3.len()).max().unwrap_or(0);
+    for slot in 0..num_slots {
+        for (dev, direction, _, chmaps) in &devices {
+            if let Some(layout) = chmaps.get(slot) {
+                let mut positions = [VIRTIO_SND_CHMAP_NONE; VIRTIO_SND_CHMAP_MAX_SIZE];
+                positions[..layout.positions().len()].copy_from_slice(layout.positions());
+                chmap_info.push(virtio_snd_chmap_info {
+                    hdr: virtio_snd_info {
+                        hda_fn_nid: (*dev).into(),
+                    },
+                    direction: *direction,
+                    channels: layout.channels(),
+                    positions,
+                });
+            }
         }
     }
-    for dev in 0..params.num_input_devices {
-        for _ in 0..params.num_input_streams {
-            pcm_info.push(virtio_snd_pcm_info {
+
+    for (dev, direction, device_params, _) in &devices {
+        if let Some(jack) = device_params.jack {
+            let jack_device = if *direction == VIRTIO_SND_D_OUTPUT {
+                HDA_JACK_HP_OUT
+            } else {
+                HDA_JACK_MIC_IN
+            };
+            jack_info.push(virtio_snd_jack_info {
                 hdr: virtio_snd_info {
-                    hda_fn_nid: dev.into(),
+                    hda_fn_nid: (*dev).into(),
                 },
-                features: 0.into(), /* 1 << VIRTIO_SND_PCM_F_XXX */
-                formats: SUPPORTED_FORMATS.into(),
-                rates: SUPPORTED_FRAME_RATES.into(),
-                direction: VIRTIO_SND_D_INPUT,
-                channels_min: 1,
-                channels_max: 2,
-                padding: [0; 5],
+                features: 0.into(), /* 1 << VIRTIO_SND_JACK_F_XXX */
+                hda_reg_defconf: (jack_device << HDA_DEFCONF_DEVICE_SHIFT
+                    | HDA_JACK_CONN_1_8 << HDA_DEFCONF_CONN_TYPE_SHIFT)
+                    .into(),
+                hda_reg_caps: HDA_PINCAP_PRES_DETECT.into(),
+                connected: (jack == JackState::Connected) as u8,
+                padding: [0; 7],
+            });
+        }
+        for kind in [ControlKind::Volume, ControlKind::Switch] {
+            ctl_info.push(SndControl {
+                hda_fn_nid: *dev,
+                direction: *direction,
+                kind,
             });
         }
-    }
-    // Use stereo channel map.
-    let mut positions = [VIRTIO_SND_CHMAP_NONE; VIRTIO_SND_CHMAP_MAX_SIZE];
-    positions[0] = VIRTIO_SND_CHMAP_FL;
-    positions[1] = VIRTIO_SND_CHMAP_FR;
-    for dev in 0..params.num_output_devices {
-        chmap_info.push(virtio_snd_chmap_info {
-            hdr: virtio_snd_info {
-                hda_fn_nid: dev.into(),
-            },
-            direction: VIRTIO_SND_D_OUTPUT,
-            channels: 2,
-            positions,
-        });
-    }
-    for dev in 0..params.num_input_devices {
-        chmap_info.push(virtio_snd_chmap_info {
-            hdr: virtio_snd_info {
-                hda_fn_nid: dev.into(),
-            },
-            direction: VIRTIO_SND_D_INPUT,
-            channels: 2,
-            positions,
-        });
-    }
-    positions[2] = VIRTIO_SND_CHMAP_RL;
-    positions[3] = VIRTIO_SND_CHMAP_RR;
-    for dev in 0..params.num_output_devices {
-        chmap_info.push(virtio_snd_chmap_info {
-            hdr: virtio_snd_info {
-                hda_fn_nid: dev.into(),
-            },
-            direction: VIRTIO_SND_D_OUTPUT,
-            channels: 4,
-            positions,
-        });
-    }
-    positions[2] = VIRTIO_SND_CHMAP_FC;
-    positions[3] = VIRTIO_SND_CHMAP_LFE;
-    positions[4] = VIRTIO_SND_CHMAP_RL;
-    positions[5] = VIRTIO_SND_CHMAP_RR;
-    for dev in 0..params.num_output_devices {
-        chmap_info.push(virtio_snd_chmap_info {
-            hdr: virtio_snd_info {
-                hda_fn_nid: dev.into(),
-            },
-            direction: VIRTIO_SND_D_OUTPUT,
-            channels: 6,
-            positions,
-        });
     }
 
     SndData {
         jack_info,
         pcm_info,
         chmap_info,
+        ctl_info,
     }
 }
 
@@ -451,6 +495,8 @@ impl VirtioDevice for VirtioSnd {
         }
 
         let snd_data = self.snd_data.clone();
+        let snd_state = self.snd_state.clone();
+        let control_tube = self.control_tube.take();
         let stream_info_builders = self.stream_info_builders.to_vec();
         let streams_state = self.streams_state.take();
         self.worker_thread = Some(WorkerThread::start("v_snd_common", move |kill_evt| {
@@ -462,6 +508,8 @@ impl VirtioDevice for VirtioSnd {
                 interrupt,
                 queues,
                 snd_data,
+                snd_state,
+                control_tube,
                 kill_evt,
                 stream_info_builders,
                 streams_state,
@@ -473,7 +521,9 @@ impl VirtioDevice for VirtioSnd {
 
     fn reset(&mut self) -> bool {
         if let Some(worker_thread) = self.worker_thread.take() {
-            let _ = worker_thread.stop();
+            if let Ok(worker) = worker_thread.stop() {
+                self.control_tube = worker.control_tube;
+            }
         }
 
         true
@@ -483,6 +533,8 @@ impl VirtioDevice for VirtioSnd {
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop().unwrap();
             self.snd_data = worker.snd_data;
+            self.snd_state = worker.snd_state;
+            self.control_tube = worker.control_tube;
             self.streams_state = Some(worker.streams_state);
             return Ok(Some(BTreeMap::from_iter(
                 worker.queues.into_iter().enumerate(),
@@ -523,6 +575,7 @@ impl VirtioDevice for VirtioSnd {
             queue_sizes: self.queue_sizes.to_vec(),
             streams_state,
             snd_data: self.snd_data.clone(),
+            snd_state: self.snd_state.clone(),
         })
         .context("failed to Serialize Sound device")
     }
@@ -549,7 +602,15 @@ impl VirtioDevice for VirtioSnd {
             deser.snd_data,
             self.snd_data
         );
+        anyhow::ensure!(
+            deser.snd_state.jacks_connected.len() == self.snd_state.jacks_connected.len()
+                && deser.snd_state.control_values.len() == self.snd_state.control_values.len(),
+            "snd state doesn't match on restore: expected: {:?}, got: {:?}",
+            deser.snd_state,
+            self.snd_state
+        );
         self.acked_features = deser.acked_features;
+        self.snd_state = deser.snd_state;
         self.streams_state = deser.streams_state.take();
         Ok(())
     }
@@ -565,6 +626,8 @@ fn run_worker(
     interrupt: Interrupt,
     queues: BTreeMap<usize, Queue>,
     snd_data: SndData,
+    snd_state: SndState,
+    control_tube: Option<Tube>,
     kill_evt: Event,
     stream_info_builders: Vec<StreamInfoBuilder>,
     streams_state: Option<Vec<StreamInfoSnapshot>>,
@@ -581,11 +644,21 @@ fn run_worker(
     let streams: Vec<AsyncRwLock<StreamInfo>> = stream_info_builders
         .into_iter()
         .map(StreamInfoBuilder::build)
+        .zip(snd_data.pcm_info_iter())
+        .map(|(mut stream, pcm_info)| {
+            let (volume, muted) = snd_state.stream_volume(&snd_data, pcm_info);
+            stream.set_volume(volume, muted);
+            stream
+        })
         .map(AsyncRwLock::new)
         .collect();
+    let snd_state = Rc::new(AsyncRwLock::new(snd_state));
+    let control_tube =
+        control_tube.map(|t| AsyncTube::new(&ex, t).expect("failed to create async tube"));
 
     let (tx_send, mut tx_recv) = mpsc::unbounded();
     let (rx_send, mut rx_recv) = mpsc::unbounded();
+    let (event_send, mut event_recv) = mpsc::unbounded();
     let tx_send_clone = tx_send.clone();
     let rx_send_clone = rx_send.clone();
     let restore_task = ex.spawn_local(async move {
@@ -632,7 +705,8 @@ fn run_worker(
 
     let (ctrl_queue, mut ctrl_queue_evt) = queues.remove(0);
     let ctrl_queue = Rc::new(AsyncRwLock::new(ctrl_queue));
-    let (_event_queue, _event_queue_evt) = queues.remove(0);
+    let (event_queue, mut event_queue_evt) = queues.remove(0);
+    let event_queue = Rc::new(AsyncRwLock::new(event_queue));
     let (tx_queue, tx_queue_evt) = queues.remove(0);
     let (rx_queue, rx_queue_evt) = queues.remove(0);
 
@@ -652,10 +726,16 @@ fn run_worker(
             &streams,
             interrupt.clone(),
             &snd_data,
+            &snd_state,
+            &control_tube,
             &mut f_kill,
             &mut f_resample,
             ctrl_queue.clone(),
             &mut ctrl_queue_evt,
+            event_queue.clone(),
+            &mut event_queue_evt,
+            event_send.clone(),
+            &mut event_recv,
             tx_queue.clone(),
             &tx_queue_evt,
             tx_send.clone(),
@@ -696,6 +776,14 @@ fn run_worker(
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to ctrl_queue"),
     };
+    let event_queue = match Rc::try_unwrap(event_queue) {
+        Ok(q) => q.into_inner(),
+        Err(_) => panic!("Too many refs to event_queue"),
+    };
+    let snd_state = match Rc::try_unwrap(snd_state) {
+        Ok(s) => s.into_inner(),
+        Err(_) => panic!("Too many refs to snd_state"),
+    };
     let tx_queue = match Rc::try_unwrap(tx_queue) {
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to tx_queue"),
@@ -704,11 +792,13 @@ fn run_worker(
         Ok(q) => q.into_inner(),
         Err(_) => panic!("Too many refs to rx_queue"),
     };
-    let queues = vec![ctrl_queue, _event_queue, tx_queue, rx_queue];
+    let queues = vec![ctrl_queue, event_queue, tx_queue, rx_queue];
 
     Ok(WorkerReturn {
         queues,
         snd_data,
+        snd_state,
+        control_tube: control_tube.map(Tube::from),
         streams_state,
     })
 }
@@ -716,6 +806,8 @@ fn run_worker(
 struct WorkerReturn {
     queues: Vec<Queue>,
     snd_data: SndData,
+    snd_state: SndState,
+    control_tube: Option<Tube>,
     streams_state: Vec<StreamInfoSnapshot>,
 }
 
@@ -737,10 +829,16 @@ fn run_worker_once(
     streams: &Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
     interrupt: Interrupt,
     snd_data: &SndData,
+    snd_state: &Rc<AsyncRwLock<SndState>>,
+    control_tube: &Option<AsyncTube>,
     mut f_kill: &mut (impl Future<Output = anyhow::Result<()>> + FusedFuture + Unpin),
     mut f_resample: &mut (impl Future<Output = anyhow::Result<()>> + FusedFuture + Unpin),
     ctrl_queue: Rc<AsyncRwLock<Queue>>,
     ctrl_queue_evt: &mut EventAsync,
+    event_queue: Rc<AsyncRwLock<Queue>>,
+    event_queue_evt: &mut EventAsync,
+    event_send: mpsc::UnboundedSender<virtio_snd_event>,
+    event_recv: &mut mpsc::UnboundedReceiver<virtio_snd_event>,
     tx_queue: Rc<AsyncRwLock<Queue>>,
     tx_queue_evt: &EventAsync,
     tx_send: mpsc::UnboundedSender<PcmResponse>,
@@ -759,6 +857,7 @@ fn run_worker_once(
         ex,
         streams,
         snd_data,
+        snd_state,
         ctrl_queue,
         ctrl_queue_evt,
         interrupt.clone(),
@@ -768,13 +867,22 @@ fn run_worker_once(
     )
     .fuse();
 
-    // TODO(woodychow): Enable this when libcras sends jack connect/disconnect evts
-    // let f_event = handle_event_queue(
-    //     snd_state,
-    //     event_queue,
-    //     event_queue_evt,
-    //     interrupt,
-    // );
+    let f_event = handle_event_queue(
+        event_queue,
+        event_queue_evt,
+        interrupt.clone(),
+        event_recv,
+        Some(&reset_signal),
+    )
+    .fuse();
+    let f_control = handle_control_tube(
+        control_tube,
+        snd_data,
+        snd_state,
+        event_send,
+        Some(&reset_signal),
+    )
+    .fuse();
     let f_tx = handle_pcm_queue(
         streams,
         tx_send2,
@@ -796,11 +904,21 @@ fn run_worker_once(
     let f_rx_response =
         send_pcm_response_worker(rx_queue, interrupt, rx_recv, Some(&reset_signal)).fuse();
 
-    pin_mut!(f_ctrl, f_tx, f_tx_response, f_rx, f_rx_response);
+    pin_mut!(
+        f_ctrl,
+        f_event,
+        f_control,
+        f_tx,
+        f_tx_response,
+        f_rx,
+        f_rx_response
+    );
 
     let done = async {
         select! {
             res = f_ctrl => (res.context("error in handling ctrl queue"), LoopState::Continue),
+            res = f_event => (res.context("error in handling event queue"), LoopState::Continue),
+            res = f_control => (res.context("error in handling control tube"), LoopState::Continue),
             res = f_tx => (res.context("error in handling tx queue"), LoopState::Continue),
             res = f_tx_response => (res.context("error in handling tx response"), LoopState::Continue),
             res = f_rx => (res.context("error in handling rx queue"), LoopState::Continue),
@@ -833,6 +951,8 @@ fn run_worker_once(
         loop {
             let (res, worker_name) = select!(
                 res = f_ctrl => (res, "f_ctrl"),
+                res = f_event => (res, "f_event"),
+                res = f_control => (res, "f_control"),
                 res = f_tx => (res, "f_tx"),
                 res = f_tx_response => (res, "f_tx_response"),
                 res = f_rx => (res, "f_rx"),
@@ -925,6 +1045,7 @@ mod tests {
     use audio_streams::StreamEffect;
 
     use super::*;
+    use crate::virtio::snd::common_backend::controls::VOLUME_MAX;
     use crate::virtio::snd::parameters::PCMDeviceParameters;
 
     #[test]
@@ -945,17 +1066,19 @@ mod tests {
             ..Default::default()
         };
 
-        let res = VirtioSnd::new(123, params).unwrap();
+        let res = VirtioSnd::new(122, params, None).unwrap();
 
         // Default values
         assert_eq!(res.snd_data.jack_info.len(), 0);
         assert_eq!(res.acked_features, 0);
         assert_eq!(res.worker_thread.is_none(), true);
 
-        assert_eq!(res.avail_features, 123); // avail_features must be equal to the input
+        // avail_features must be the input with control elements
+        assert_eq!(res.avail_features, 122 | 1 << VIRTIO_SND_F_CTLS);
         assert_eq!(res.cfg.jacks.to_native(), 0);
         assert_eq!(res.cfg.streams.to_native(), 13); // (Output = 3*3) + (Input = 2*2)
         assert_eq!(res.cfg.chmaps.to_native(), 11); // (Output = 3*3) + (Input = 2*1)
+        assert_eq!(res.cfg.controls.to_native(), 10); // (Output = 3*2) + (Input = 2*2)
 
         // Check snd_data.pcm_info
         assert_eq!(res.snd_data.pcm_info.len(), 13);
@@ -998,6 +1121,86 @@ mod tests {
                 i
             );
         }
+        // Output devices support up to 5.1 surround, input devices stereo.
+        assert_eq!(res.snd_data.pcm_info[0].channels_max, 6);
+        assert_eq!(res.snd_data.pcm_info[9].channels_max, 2);
+
+        // Check snd_state
+        assert_eq!(res.snd_state.jacks_connected.len(), 0);
+        assert_eq!(
+            res.snd_state.control_values,
+            vec![VOLUME_MAX, 1, VOLUME_MAX, 1, VOLUME_MAX, 1, VOLUME_MAX, 1, VOLUME_MAX, 1]
+        );
+    }
+
+    #[test]
+    fn test_hardcoded_snd_data_jacks_and_chmaps() {
+        let params = Parameters {
+            num_output_devices: 2,
+            num_input_devices: 1,
+            output_device_config: vec![
+                PCMDeviceParameters {
+                    chmaps: Some(vec![ChannelLayout::Stereo, ChannelLayout::Surround71]),
+                    jack: Some(JackState::Connected),
+                    ..PCMDeviceParameters::default()
+                },
+                PCMDeviceParameters {
+                    chmaps: Some(vec![ChannelLayout::Mono]),
+                    ..PCMDeviceParameters::default()
+                },
+            ],
+            input_device_config: vec![PCMDeviceParameters {
+                jack: Some(JackState::Disconnected),
+                ..PCMDeviceParameters::default()
+            }],
+            ..Default::default()
+        };
+
+        let snd_data = hardcoded_snd_data(&params);
+        let cfg = hardcoded_virtio_snd_config(&params);
+        assert_eq!(cfg.jacks.to_native(), 2);
+        assert_eq!(cfg.chmaps.to_native(), 4);
+        assert_eq!(cfg.controls.to_native(), 6);
+
+        let channels_max: Vec<u8> = snd_data.pcm_info.iter().map(|p| p.channels_max).collect();
+        assert_eq!(channels_max, vec![8, 1, 2]);
+
+        // First channel map of every device, then the second one.
+        let chmaps: Vec<(u32, u8, u8)> = snd_data
+            .chmap_info
+            .iter()
+            .map(|c| (c.hdr.hda_fn_nid.to_native(), c.direction, c.channels))
+            .collect();
+        assert_eq!(
+            chmaps,
+            vec![
+                (0, VIRTIO_SND_D_OUTPUT, 2),
+                (1, VIRTIO_SND_D_OUTPUT, 1),
+                (0, VIRTIO_SND_D_INPUT, 2),
+                (0, VIRTIO_SND_D_OUTPUT, 8),
+            ]
+        );
+        assert_eq!(
+            snd_data.chmap_info[3].positions[6..9],
+            [
+                VIRTIO_SND_CHMAP_SL,
+                VIRTIO_SND_CHMAP_SR,
+                VIRTIO_SND_CHMAP_NONE
+            ]
+        );
+
+        assert_eq!(snd_data.jack_info[0].hdr.hda_fn_nid.to_native(), 0);
+        assert_eq!(snd_data.jack_info[0].connected, 1);
+        assert_eq!(
+            snd_data.jack_info[0].hda_reg_defconf.to_native() >> HDA_DEFCONF_DEVICE_SHIFT & 0xf,
+            HDA_JACK_HP_OUT
+        );
+        assert_eq!(snd_data.jack_info[1].connected, 0);
+        assert_eq!(
+            snd_data.jack_info[1].hda_reg_defconf.to_native() >> HDA_DEFCONF_DEVICE_SHIFT & 0xf,
+            HDA_JACK_MIC_IN
+        );
+        assert_eq!(SndState::new(&snd_data).jacks_connected, vec![true, false]);
     }
 
     #[test]
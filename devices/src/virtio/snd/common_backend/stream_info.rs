@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::Cell;
 use std::fmt;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -92,6 +93,8 @@ pub struct StreamInfo {
     pub state: u32, // VIRTIO_SND_R_PCM_SET_PARAMS -> VIRTIO_SND_R_PCM_STOP, or 0 (uninitialized)
     // Stream effects to use when creating a new stream on [`prepare()`].
     pub(crate) effects: Vec<StreamEffect>,
+    // Gain set by the control elements, shared with the PCM worker which applies it to the samples.
+    gain: Rc<Cell<f64>>,
 
     // just_reset set to true after reset. Make invalid state transition return Ok. Set to false
     // after a valid state transition to SET_PARAMS or PREPARE.
@@ -172,6 +175,7 @@ impl From<StreamInfoBuilder> for StreamInfo {
             direction: 0,
             state: 0,
             effects: builder.effects,
+            gain: Rc::new(Cell::new(1.0)),
             just_reset: false,
             status_mutex: Rc::new(AsyncRwLock::new(WorkerStatus::Pause)),
             sender: None,
@@ -295,6 +299,8 @@ impl StreamInfo {
             self.status_mutex.clone(),
             stream_objects.pcm_sender,
             period_dur,
+            self.format,
+            self.gain.clone(),
             release_signal,
         );
         self.worker_future = Some(Box::new(ex.spawn_local(f).into_future()));
@@ -384,6 +390,11 @@ impl StreamInfo {
         self.ex.take(); // Remove ex as the worker is finished
     }
 
+    /// Sets the volume and mute state of the stream, which apply from the next period.
+    pub fn set_volume(&mut self, volume: f64, muted: bool) {
+        self.gain.set(if muted { 0.0 } else { volume });
+    }
+
     pub fn snapshot(&self) -> StreamInfoSnapshot {
         StreamInfoSnapshot {
             channels: self.channels,
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+/* device features */
+pub const VIRTIO_SND_F_CTLS: u32 = 0;
+
 pub const VIRTIO_SND_R_JACK_INFO: u32 = 1;
 pub const VIRTIO_SND_R_JACK_REMAP: u32 = 2;
 
@@ -16,6 +19,15 @@ pub const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
 /* channel map control request types */
 pub const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
 
+/* control element request types */
+pub const VIRTIO_SND_R_CTL_INFO: u32 = 0x0300;
+pub const VIRTIO_SND_R_CTL_ENUM_ITEMS: u32 = 0x0301;
+pub const VIRTIO_SND_R_CTL_READ: u32 = 0x0302;
+pub const VIRTIO_SND_R_CTL_WRITE: u32 = 0x0303;
+pub const VIRTIO_SND_R_CTL_TLV_READ: u32 = 0x0304;
+pub const VIRTIO_SND_R_CTL_TLV_WRITE: u32 = 0x0305;
+pub const VIRTIO_SND_R_CTL_TLV_COMMAND: u32 = 0x0306;
+
 /* jack event types */
 pub const VIRTIO_SND_EVT_JACK_CONNECTED: u32 = 0x1000;
 pub const VIRTIO_SND_EVT_JACK_DISCONNECTED: u32 = 0x1001;
@@ -24,6 +36,9 @@ pub const VIRTIO_SND_EVT_JACK_DISCONNECTED: u32 = 0x1001;
 pub const VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
 pub const VIRTIO_SND_EVT_PCM_XRUN: u32 = 0x1101;
 
+/* control element event types */
+pub const VIRTIO_SND_EVT_CTL_NOTIFY: u32 = 0x1200;
+
 /* common status codes */
 pub const VIRTIO_SND_S_OK: u32 = 0x8000;
 pub const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
@@ -42,6 +57,16 @@ pub const VIRTIO_SND_D_INPUT: u8 = 1;
 /* supported jack features */
 pub const VIRTIO_SND_JACK_F_REMAP: u32 = 0;
 
+/* HDA pin default configuration of jacks */
+pub const HDA_DEFCONF_DEVICE_SHIFT: u32 = 20;
+pub const HDA_DEFCONF_CONN_TYPE_SHIFT: u32 = 16;
+pub const HDA_JACK_HP_OUT: u32 = 0x2;
+pub const HDA_JACK_MIC_IN: u32 = 0xa;
+pub const HDA_JACK_CONN_1_8: u32 = 0x1; /* 1/8" stereo/mono jack */
+
+/* HDA pin capabilities of jacks */
+pub const HDA_PINCAP_PRES_DETECT: u32 = 1 << 2;
+
 /* supported PCM stream features */
 pub const VIRTIO_SND_PCM_F_SHMEM_HOST: u8 = 0;
 pub const VIRTIO_SND_PCM_F_SHMEM_GUEST: u8 = 1;
@@ -133,3 +158,29 @@ pub const VIRTIO_SND_CHMAP_BLC: u8 = 39; /* bottom left center */
 pub const VIRTIO_SND_CHMAP_BRC: u8 = 40; /* bottom right center */
 
 pub const VIRTIO_SND_CHMAP_MAX_SIZE: usize = 18;
+
+/* control element roles */
+pub const VIRTIO_SND_CTL_ROLE_UNDEFINED: u32 = 0;
+pub const VIRTIO_SND_CTL_ROLE_VOLUME: u32 = 1;
+pub const VIRTIO_SND_CTL_ROLE_MUTE: u32 = 2;
+pub const VIRTIO_SND_CTL_ROLE_GAIN: u32 = 3;
+
+/* control element value types */
+pub const VIRTIO_SND_CTL_TYPE_BOOLEAN: u32 = 0;
+pub const VIRTIO_SND_CTL_TYPE_INTEGER: u32 = 1;
+pub const VIRTIO_SND_CTL_TYPE_INTEGER64: u32 = 2;
+pub const VIRTIO_SND_CTL_TYPE_ENUMERATED: u32 = 3;
+pub const VIRTIO_SND_CTL_TYPE_BYTES: u32 = 4;
+pub const VIRTIO_SND_CTL_TYPE_IEC958: u32 = 5;
+
+/* control element access rights */
+pub const VIRTIO_SND_CTL_ACCESS_READ: u32 = 0;
+pub const VIRTIO_SND_CTL_ACCESS_WRITE: u32 = 1;
+pub const VIRTIO_SND_CTL_ACCESS_VOLATILE: u32 = 2;
+pub const VIRTIO_SND_CTL_ACCESS_INACTIVE: u32 = 3;
+pub const VIRTIO_SND_CTL_ACCESS_TLV_READ: u32 = 4;
+pub const VIRTIO_SND_CTL_ACCESS_TLV_WRITE: u32 = 5;
+pub const VIRTIO_SND_CTL_ACCESS_TLV_COMMAND: u32 = 6;
+
+pub const VIRTIO_SND_CTL_NAME_MAX: usize = 44;
+pub const VIRTIO_SND_CTL_VALUE_MAX: usize = 128;
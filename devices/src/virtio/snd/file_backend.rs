@@ -11,9 +11,12 @@ use std::io::Write;
 use std::path::Path;
 
 use audio_streams::NoopStreamSourceGenerator;
+use audio_util::Error as AudioUtilError;
+use audio_util::FileCaptureStreamSourceGenerator;
 use audio_util::FileStreamSourceGenerator;
 use base::error;
 use base::open_file_or_duplicate;
+use base::warn;
 use base::AsRawDescriptor;
 use base::RawDescriptor;
 use thiserror::Error as ThisError;
@@ -21,12 +24,15 @@ use thiserror::Error as ThisError;
 use crate::virtio::snd::common_backend::SndData;
 use crate::virtio::snd::constants::VIRTIO_SND_D_OUTPUT;
 use crate::virtio::snd::parameters::Parameters;
+use crate::virtio::snd::parameters::PlaybackFormat;
 use crate::virtio::snd::sys::SysAudioStreamSourceGenerator;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("Failed to allocate space: {0}")]
     AllocateSpace(IOError),
+    #[error("Failed to read capture file: {0}")]
+    CaptureFile(AudioUtilError),
     #[error("Failed to open file: {0}")]
     OpenFile(base::Error),
 }
@@ -40,8 +46,15 @@ fn allocate_space(mut file: &File, size: usize) -> Result<(), Error> {
     Ok(())
 }
 
-fn open_playback_file(dir_path: &String, stream_id: usize) -> Result<File, Error> {
-    let file_name = format!("stream-{}.out", stream_id);
+fn open_playback_file(
+    dir_path: &String,
+    stream_id: usize,
+    format: PlaybackFormat,
+) -> Result<File, Error> {
+    let file_name = match format {
+        PlaybackFormat::Raw => format!("stream-{}.out", stream_id),
+        PlaybackFormat::Wav => format!("stream-{}.wav", stream_id),
+    };
     let file_path = Path::new(dir_path).join(file_name);
     let file = open_file_or_duplicate(
         file_path,
@@ -51,6 +64,57 @@ fn open_playback_file(dir_path: &String, stream_id: usize) -> Result<File, Error
     Ok(file)
 }
 
+/// Opens `stream-N.wav`, or else `stream-N.raw`, in `dir_path`.
+fn open_capture_file(dir_path: &String, stream_id: usize) -> Result<Option<File>, Error> {
+    for extension in ["wav", "raw"] {
+        let file_path = Path::new(dir_path).join(format!("stream-{}.{}", stream_id, extension));
+        if file_path.exists() {
+            let file = open_file_or_duplicate(file_path, OpenOptions::new().read(true))
+                .map_err(Error::OpenFile)?;
+            return Ok(Some(file));
+        }
+    }
+    Ok(None)
+}
+
+fn open_timestamp_log(dir_path: &String, stream_id: usize) -> Result<File, Error> {
+    let file_path = Path::new(dir_path).join(format!("stream-{}.log", stream_id));
+    open_file_or_duplicate(
+        file_path,
+        OpenOptions::new().write(true).create(true).truncate(true),
+    )
+    .map_err(Error::OpenFile)
+}
+
+fn create_capture_stream_source_generator(
+    params: &Parameters,
+    stream: usize,
+    timestamp_log: Option<File>,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> Result<SysAudioStreamSourceGenerator, Error> {
+    if params.capture_path.is_empty() {
+        return Ok(Box::new(NoopStreamSourceGenerator::new()));
+    }
+    let file = match open_capture_file(&params.capture_path, stream)? {
+        Some(file) => file,
+        None => {
+            warn!(
+                "No capture file for stream {} in {}, capturing silence",
+                stream, params.capture_path
+            );
+            return Ok(Box::new(NoopStreamSourceGenerator::new()));
+        }
+    };
+    keep_rds.push(file.as_raw_descriptor());
+
+    let mut generator = FileCaptureStreamSourceGenerator::new(file, params.capture_loop)
+        .map_err(Error::CaptureFile)?;
+    if let Some(timestamp_log) = timestamp_log {
+        generator = generator.with_timestamp_log(timestamp_log);
+    }
+    Ok(Box::new(generator))
+}
+
 pub(crate) fn create_file_stream_source_generators(
     params: &Parameters,
     snd_data: &SndData,
@@ -59,16 +123,30 @@ pub(crate) fn create_file_stream_source_generators(
     let mut generators = Vec::new();
 
     for (stream, pcm_info) in snd_data.pcm_info.iter().enumerate() {
+        let timestamp_log = if params.timestamp_path.is_empty() {
+            None
+        } else {
+            let file = open_timestamp_log(&params.timestamp_path, stream)?;
+            keep_rds.push(file.as_raw_descriptor());
+            Some(file)
+        };
+
         let generator: SysAudioStreamSourceGenerator = if pcm_info.direction == VIRTIO_SND_D_OUTPUT
         {
-            let file = open_playback_file(&params.playback_path, stream)?;
+            let file = open_playback_file(&params.playback_path, stream, params.playback_format)?;
             allocate_space(&file, params.playback_size)?;
             keep_rds.push(file.as_raw_descriptor());
 
-            Box::new(FileStreamSourceGenerator::new(file, params.playback_size))
+            let mut generator = FileStreamSourceGenerator::new(file, params.playback_size);
+            if params.playback_format == PlaybackFormat::Wav {
+                generator = generator.with_wav_header();
+            }
+            if let Some(timestamp_log) = timestamp_log {
+                generator = generator.with_timestamp_log(timestamp_log);
+            }
+            Box::new(generator)
         } else {
-            // Capture is not supported yet
-            Box::new(NoopStreamSourceGenerator::new())
+            create_capture_stream_source_generator(params, stream, timestamp_log, keep_rds)?
         };
 
         generators.push(generator);
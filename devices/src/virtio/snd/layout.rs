@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use data_model::Le16;
 use data_model::Le32;
 use data_model::Le64;
 use serde::Deserialize;
@@ -12,6 +13,8 @@ use zerocopy::FromZeroes;
 
 use crate::virtio::snd::constants::StatusCode;
 use crate::virtio::snd::constants::VIRTIO_SND_CHMAP_MAX_SIZE;
+use crate::virtio::snd::constants::VIRTIO_SND_CTL_NAME_MAX;
+use crate::virtio::snd::constants::VIRTIO_SND_CTL_VALUE_MAX;
 
 #[derive(Copy, Clone, Default, AsBytes, FromZeroes, FromBytes)]
 #[repr(C)]
@@ -179,3 +182,40 @@ pub struct virtio_snd_chmap_info {
     pub channels: u8,
     pub positions: [u8; VIRTIO_SND_CHMAP_MAX_SIZE],
 }
+
+#[derive(Copy, Clone, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_hdr {
+    pub hdr: virtio_snd_hdr,
+    pub control_id: Le32,
+}
+
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_info {
+    pub hdr: virtio_snd_info,
+    pub role: Le32,   /* VIRTIO_SND_CTL_ROLE_XXX */
+    pub type_: Le32,  /* VIRTIO_SND_CTL_TYPE_XXX */
+    pub access: Le32, /* 1 << VIRTIO_SND_CTL_ACCESS_XXX */
+    pub count: Le32,
+    pub index: Le32,
+    pub name: [u8; VIRTIO_SND_CTL_NAME_MAX],
+    pub padding: [u8; 4],
+    /* integer: min, max, step; enumerated: items */
+    pub value: [Le32; 6],
+}
+
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_value {
+    /* integer, boolean or enumerated values */
+    pub value: [Le32; VIRTIO_SND_CTL_VALUE_MAX],
+}
+
+#[derive(Copy, Clone, Default, AsBytes, FromZeroes, FromBytes)]
+#[repr(C)]
+pub struct virtio_snd_ctl_event {
+    pub hdr: virtio_snd_hdr, /* .code = VIRTIO_SND_EVT_CTL_NOTIFY */
+    pub control_id: Le16,
+    pub mask: Le16,
+}
@@ -77,6 +77,78 @@ impl TryFrom<&str> for StreamSourceBackend {
     }
 }
 
+/// Format of the files written by the file backend for playback streams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackFormat {
+    /// Raw samples, written to `stream-N.out`.
+    #[default]
+    Raw,
+    /// Samples after a WAV header holding the format of the stream, written to `stream-N.wav`.
+    Wav,
+}
+
+/// Channel layout of a channel map advertised for a PCM device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    /// Front and rear pairs.
+    Quad,
+    /// 5.1 surround: front pair, center, LFE and rear pair.
+    Surround51,
+    /// 7.1 surround: 5.1 surround and side pair.
+    Surround71,
+}
+
+impl ChannelLayout {
+    /// Returns the positions of the channels of the layout, in order.
+    pub fn positions(&self) -> &'static [u8] {
+        match self {
+            ChannelLayout::Mono => &[VIRTIO_SND_CHMAP_MONO],
+            ChannelLayout::Stereo => &[VIRTIO_SND_CHMAP_FL, VIRTIO_SND_CHMAP_FR],
+            ChannelLayout::Quad => &[
+                VIRTIO_SND_CHMAP_FL,
+                VIRTIO_SND_CHMAP_FR,
+                VIRTIO_SND_CHMAP_RL,
+                VIRTIO_SND_CHMAP_RR,
+            ],
+            ChannelLayout::Surround51 => &[
+                VIRTIO_SND_CHMAP_FL,
+                VIRTIO_SND_CHMAP_FR,
+                VIRTIO_SND_CHMAP_FC,
+                VIRTIO_SND_CHMAP_LFE,
+                VIRTIO_SND_CHMAP_RL,
+                VIRTIO_SND_CHMAP_RR,
+            ],
+            ChannelLayout::Surround71 => &[
+                VIRTIO_SND_CHMAP_FL,
+                VIRTIO_SND_CHMAP_FR,
+                VIRTIO_SND_CHMAP_FC,
+                VIRTIO_SND_CHMAP_LFE,
+                VIRTIO_SND_CHMAP_RL,
+                VIRTIO_SND_CHMAP_RR,
+                VIRTIO_SND_CHMAP_SL,
+                VIRTIO_SND_CHMAP_SR,
+            ],
+        }
+    }
+
+    /// Returns the number of channels of the layout.
+    pub fn channels(&self) -> u8 {
+        self.positions().len() as u8
+    }
+}
+
+/// State of the jack of a PCM device when the device starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JackState {
+    Connected,
+    Disconnected,
+}
+
 /// Holds the parameters for each PCM device
 #[derive(Debug, Clone, Default, Deserialize, Serialize, FromKeyValues, PartialEq, Eq)]
 #[serde(deny_unknown_fields, default)]
@@ -86,6 +158,31 @@ pub struct PCMDeviceParameters {
     #[cfg(all(unix, feature = "audio_cras"))]
     pub stream_type: Option<CrasStreamType>,
     pub effects: Option<Vec<StreamEffect>>,
+    /// Host device used by the `alsa` and `pulse` backends: an ALSA PCM name, or the name of a
+    /// PulseAudio sink or source. The default device of the backend is used if unset.
+    pub device: Option<String>,
+    /// Channel maps advertised for the device. Output devices default to stereo, quad and 5.1
+    /// surround, and input devices to stereo. The maximum number of channels of the device is the
+    /// number of channels of its largest channel map.
+    pub chmaps: Option<Vec<ChannelLayout>>,
+    /// Adds a jack to the device, in the given initial state. Jacks can then be plugged and
+    /// unplugged from the control socket.
+    pub jack: Option<JackState>,
+}
+
+impl PCMDeviceParameters {
+    /// Returns the channel maps of the device, falling back to the defaults for `direction`.
+    pub(crate) fn chmaps_or_default(&self, direction: u8) -> Vec<ChannelLayout> {
+        match &self.chmaps {
+            Some(chmaps) if !chmaps.is_empty() => chmaps.clone(),
+            _ if direction == VIRTIO_SND_D_OUTPUT => vec![
+                ChannelLayout::Stereo,
+                ChannelLayout::Quad,
+                ChannelLayout::Surround51,
+            ],
+            _ => vec![ChannelLayout::Stereo],
+        }
+    }
 }
 
 /// Holds the parameters for a cras sound device
@@ -100,6 +197,10 @@ pub struct Parameters {
     pub num_input_streams: u32,
     pub playback_path: String,
     pub playback_size: usize,
+    pub playback_format: PlaybackFormat,
+    pub capture_path: String,
+    pub capture_loop: bool,
+    pub timestamp_path: String,
     #[cfg(all(unix, feature = "audio_cras"))]
     #[serde(deserialize_with = "libcras::deserialize_cras_client_type")]
     pub client_type: CrasClientType,
@@ -120,6 +221,10 @@ impl Default for Parameters {
             num_input_streams: 1,
             playback_path: "".to_string(),
             playback_size: 0,
+            playback_format: PlaybackFormat::Raw,
+            capture_path: "".to_string(),
+            capture_loop: false,
+            timestamp_path: "".to_string(),
             #[cfg(all(unix, feature = "audio_cras"))]
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
             #[cfg(all(unix, feature = "audio_cras"))]
@@ -143,6 +248,23 @@ impl Parameters {
         self.get_total_output_streams() + self.get_total_input_streams()
     }
 
+    /// Returns the parameters of device `device_idx` in `direction`, or the default parameters if
+    /// the device has no config.
+    pub(crate) fn get_device_params_or_default(
+        &self,
+        direction: u8,
+        device_idx: u32,
+    ) -> PCMDeviceParameters {
+        let device_config = match direction {
+            VIRTIO_SND_D_OUTPUT => &self.output_device_config,
+            _ => &self.input_device_config,
+        };
+        device_config
+            .get(device_idx as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     #[allow(dead_code)]
     pub(crate) fn get_device_params(
         &self,
@@ -288,8 +410,101 @@ mod tests {
             ],
         );
 
+        check_success(
+            "output_device_config=[[device=hw:0]],input_device_config=[[effects=[aec],device=mic]]",
+            false,
+            StreamSourceBackend::NULL,
+            1,
+            1,
+            1,
+            1,
+            vec![PCMDeviceParameters {
+                device: Some("hw:0".to_string()),
+                ..Default::default()
+            }],
+            vec![PCMDeviceParameters {
+                effects: Some(vec![StreamEffect::EchoCancellation]),
+                device: Some("mic".to_string()),
+                ..Default::default()
+            }],
+        );
+
+        check_success(
+            "output_device_config=[[chmaps=[stereo,surround71],jack=connected]],\
+            input_device_config=[[chmaps=[mono],jack=disconnected]]",
+            false,
+            StreamSourceBackend::NULL,
+            1,
+            1,
+            1,
+            1,
+            vec![PCMDeviceParameters {
+                chmaps: Some(vec![ChannelLayout::Stereo, ChannelLayout::Surround71]),
+                jack: Some(JackState::Connected),
+                ..Default::default()
+            }],
+            vec![PCMDeviceParameters {
+                chmaps: Some(vec![ChannelLayout::Mono]),
+                jack: Some(JackState::Disconnected),
+                ..Default::default()
+            }],
+        );
+
         // Invalid effect in device config
         check_failure("output_device_config=[[effects=[none]]]");
+        // Invalid channel layout or jack state in device config
+        check_failure("output_device_config=[[chmaps=[hexaphonic]]]");
+        check_failure("output_device_config=[[jack=maybe]]");
+    }
+
+    #[test]
+    fn device_chmaps_default() {
+        let device_params = PCMDeviceParameters::default();
+        assert_eq!(
+            device_params.chmaps_or_default(VIRTIO_SND_D_OUTPUT),
+            vec![
+                ChannelLayout::Stereo,
+                ChannelLayout::Quad,
+                ChannelLayout::Surround51
+            ]
+        );
+        assert_eq!(
+            device_params.chmaps_or_default(VIRTIO_SND_D_INPUT),
+            vec![ChannelLayout::Stereo]
+        );
+
+        let device_params = PCMDeviceParameters {
+            chmaps: Some(vec![ChannelLayout::Surround71]),
+            ..Default::default()
+        };
+        assert_eq!(
+            device_params.chmaps_or_default(VIRTIO_SND_D_OUTPUT),
+            vec![ChannelLayout::Surround71]
+        );
+        assert_eq!(ChannelLayout::Surround71.channels(), 8);
+    }
+
+    #[test]
+    fn file_parameters_fromstr() {
+        let params: Parameters = serde_keyvalue::from_key_values(
+            "backend=file,playback_path=/tmp/out,playback_size=4096,playback_format=wav,\
+            capture_path=/tmp/in,capture_loop=true,timestamp_path=/tmp/log",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(params.backend, StreamSourceBackend::FILE);
+        assert_eq!(params.playback_path, "/tmp/out");
+        assert_eq!(params.playback_size, 4096);
+        assert_eq!(params.playback_format, PlaybackFormat::Wav);
+        assert_eq!(params.capture_path, "/tmp/in");
+        assert!(params.capture_loop);
+        assert_eq!(params.timestamp_path, "/tmp/log");
+
+        let params: Parameters =
+            serde_keyvalue::from_key_values("backend=file").expect("parse should have succeded");
+        assert_eq!(params.playback_format, PlaybackFormat::Raw);
+        assert!(!params.capture_loop);
+
+        check_failure("playback_format=mp3");
     }
 
     #[test]
@@ -363,11 +578,13 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    device: None,
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: None,
+                    device: None,
                 },
                 Default::default(),
                 ],
@@ -384,16 +601,19 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    device: None,
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    device: None,
                 },
                 PCMDeviceParameters{
                     client_type: None,
                     stream_type: None,
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    device: None,
                 },
                 Default::default(),
                 ],
@@ -9,7 +9,15 @@ use audio_streams::AsyncPlaybackBufferStream;
 use audio_streams::BoxError;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
-#[cfg(feature = "audio_cras")]
+#[cfg(feature = "audio_alsa")]
+use audio_util::AlsaStreamSourceGenerator;
+#[cfg(feature = "audio_pulse")]
+use audio_util::PulseStreamSourceGenerator;
+#[cfg(any(
+    feature = "audio_alsa",
+    feature = "audio_cras",
+    feature = "audio_pulse"
+))]
 use base::error;
 use base::set_rt_prio_limit;
 use base::set_rt_round_robin;
@@ -50,16 +58,24 @@ pub(crate) struct SysAsyncStreamObjects {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum StreamSourceBackend {
+    #[cfg(feature = "audio_alsa")]
+    ALSA,
     #[cfg(feature = "audio_cras")]
     CRAS,
+    #[cfg(feature = "audio_pulse")]
+    PULSE,
 }
 
 // Implemented to make backend serialization possible, since we deserialize from str.
 impl From<StreamSourceBackend> for String {
     fn from(backend: StreamSourceBackend) -> Self {
         match backend {
+            #[cfg(feature = "audio_alsa")]
+            StreamSourceBackend::ALSA => "alsa".to_owned(),
             #[cfg(feature = "audio_cras")]
             StreamSourceBackend::CRAS => "cras".to_owned(),
+            #[cfg(feature = "audio_pulse")]
+            StreamSourceBackend::PULSE => "pulse".to_owned(),
         }
     }
 }
@@ -69,8 +85,12 @@ impl TryFrom<&str> for StreamSourceBackend {
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s {
+            #[cfg(feature = "audio_alsa")]
+            "alsa" => Ok(StreamSourceBackend::ALSA),
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(StreamSourceBackend::CRAS),
+            #[cfg(feature = "audio_pulse")]
+            "pulse" | "pipewire" => Ok(StreamSourceBackend::PULSE),
             _ => Err(ParametersError::InvalidBackend),
         }
     }
@@ -100,6 +120,44 @@ pub(crate) fn create_cras_stream_source_generators(
     generators
 }
 
+#[cfg(feature = "audio_alsa")]
+pub(crate) fn create_alsa_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> =
+        Vec::with_capacity(snd_data.pcm_info_len());
+    for pcm_info in snd_data.pcm_info_iter() {
+        let device_params = params.get_device_params(pcm_info).unwrap_or_else(|err| {
+            error!("Create alsa stream source generator error: {}", err);
+            Default::default()
+        });
+        generators.push(Box::new(AlsaStreamSourceGenerator::new(
+            device_params.device.unwrap_or_else(|| "default".to_owned()),
+        )));
+    }
+    generators
+}
+
+#[cfg(feature = "audio_pulse")]
+pub(crate) fn create_pulse_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> =
+        Vec::with_capacity(snd_data.pcm_info_len());
+    for pcm_info in snd_data.pcm_info_iter() {
+        let device_params = params.get_device_params(pcm_info).unwrap_or_else(|err| {
+            error!("Create pulse stream source generator error: {}", err);
+            Default::default()
+        });
+        generators.push(Box::new(PulseStreamSourceGenerator::new(
+            device_params.device,
+        )));
+    }
+    generators
+}
+
 #[allow(unused_variables)]
 pub(crate) fn create_stream_source_generators(
     backend: StreamSourceBackend,
@@ -107,8 +165,12 @@ pub(crate) fn create_stream_source_generators(
     snd_data: &SndData,
 ) -> Vec<Box<dyn StreamSourceGenerator>> {
     match backend {
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::ALSA => create_alsa_stream_source_generators(params, snd_data),
         #[cfg(feature = "audio_cras")]
         StreamSourceBackend::CRAS => create_cras_stream_source_generators(params, snd_data),
+        #[cfg(feature = "audio_pulse")]
+        StreamSourceBackend::PULSE => create_pulse_stream_source_generators(params, snd_data),
     }
 }
 
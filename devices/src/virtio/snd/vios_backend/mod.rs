@@ -267,6 +267,7 @@ pub fn new_sound<P: AsRef<Path>>(path: P, virtio_features: u64) -> Result<Sound>
             jacks: Le32::from(vios_client.num_jacks()),
             streams: Le32::from(vios_client.num_streams()),
             chmaps: Le32::from(vios_client.num_chmaps()),
+            controls: Le32::from(0),
         },
         virtio_features,
         worker_thread: None,
@@ -43,8 +43,6 @@ use crate::virtio::vhost::user::device::handler::WorkerState;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 
-const MAX_QUEUE_NUM: usize = 2; /* worker queue and high priority queue */
-
 async fn handle_fs_queue(
     queue: Rc<RefCell<virtio::Queue>>,
     doorbell: Interrupt,
@@ -71,10 +69,11 @@ struct FsBackend {
     ex: Executor,
     server: Arc<fuse::Server<PassthroughFs>>,
     tag: [u8; FS_MAX_TAG_LEN],
+    num_request_queues: usize,
     avail_features: u64,
     acked_features: u64,
     acked_protocol_features: VhostUserProtocolFeatures,
-    workers: [Option<WorkerState<Rc<RefCell<Queue>>, ()>>; MAX_QUEUE_NUM],
+    workers: Vec<Option<WorkerState<Rc<RefCell<Queue>>, ()>>>,
     keep_rds: Vec<RawDescriptor>,
 }
 
@@ -94,7 +93,13 @@ impl FsBackend {
             | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
 
         // Use default passthroughfs config
-        let fs = PassthroughFs::new(tag, cfg.unwrap_or_default())?;
+        let cfg = cfg.unwrap_or_default();
+        // Requests are executed on the executor thread, one at a time.
+        if cfg.num_threads != 1 {
+            bail!("num_threads is not supported by the vhost-user fs device");
+        }
+        let num_request_queues = cfg.num_queues;
+        let fs = PassthroughFs::new(tag, cfg)?;
 
         let mut keep_rds: Vec<RawDescriptor> = [0, 1, 2].to_vec();
         keep_rds.append(&mut fs.keep_rds());
@@ -108,10 +113,12 @@ impl FsBackend {
             ex,
             server,
             tag: fs_tag,
+            num_request_queues,
             avail_features,
             acked_features: 0,
             acked_protocol_features: VhostUserProtocolFeatures::empty(),
-            workers: Default::default(),
+            // The request queues and the high priority queue.
+            workers: (0..num_request_queues + 1).map(|_| None).collect(),
             keep_rds,
         })
     }
@@ -119,7 +126,7 @@ impl FsBackend {
 
 impl VhostUserBackend for FsBackend {
     fn max_queue_num(&self) -> usize {
-        MAX_QUEUE_NUM
+        self.workers.len()
     }
 
     fn features(&self) -> u64 {
@@ -160,7 +167,7 @@ impl VhostUserBackend for FsBackend {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config = virtio_fs_config {
             tag: self.tag,
-            num_request_queues: Le32::from(1),
+            num_request_queues: Le32::from(self.num_request_queues as u32),
         };
         copy_config(data, 0, config.as_bytes(), offset);
     }
@@ -30,6 +30,7 @@ use crate::virtio::device_constants::snd::virtio_snd_config;
 use crate::virtio::snd::common_backend::async_funcs::handle_ctrl_queue;
 use crate::virtio::snd::common_backend::async_funcs::handle_pcm_queue;
 use crate::virtio::snd::common_backend::async_funcs::send_pcm_response_worker;
+use crate::virtio::snd::common_backend::controls::SndState;
 use crate::virtio::snd::common_backend::create_stream_info_builders;
 use crate::virtio::snd::common_backend::hardcoded_snd_data;
 use crate::virtio::snd::common_backend::hardcoded_virtio_snd_config;
@@ -39,6 +40,7 @@ use crate::virtio::snd::common_backend::Error;
 use crate::virtio::snd::common_backend::PcmResponse;
 use crate::virtio::snd::common_backend::SndData;
 use crate::virtio::snd::common_backend::MAX_QUEUE_NUM;
+use crate::virtio::snd::constants::VIRTIO_SND_F_CTLS;
 use crate::virtio::snd::parameters::Parameters;
 use crate::virtio::vhost::user::device::handler::DeviceRequestHandler;
 use crate::virtio::vhost::user::device::handler::Error as DeviceError;
@@ -65,6 +67,7 @@ struct SndBackend {
     // tx and rx
     response_workers: [Option<WorkerState<Rc<AsyncRwLock<Queue>>, Result<(), Error>>>; 2],
     snd_data: Rc<SndData>,
+    snd_state: Rc<AsyncRwLock<SndState>>,
     streams: Rc<AsyncRwLock<Vec<AsyncRwLock<StreamInfo>>>>,
     tx_send: mpsc::UnboundedSender<PcmResponse>,
     rx_send: mpsc::UnboundedSender<PcmResponse>,
@@ -74,11 +77,24 @@ struct SndBackend {
 
 impl SndBackend {
     pub fn new(params: Parameters) -> anyhow::Result<Self> {
+        // Jacks are plugged from the control socket of the VM, which the frontend doesn't forward,
+        // so no jack event could ever reach the driver.
+        if params
+            .output_device_config
+            .iter()
+            .chain(&params.input_device_config)
+            .any(|device_params| device_params.jack.is_some())
+        {
+            bail!("jacks are not supported by the vhost-user snd device");
+        }
+
         let cfg = hardcoded_virtio_snd_config(&params);
         let avail_features = virtio::base_features(ProtectionType::Unprotected)
+            | 1 << VIRTIO_SND_F_CTLS
             | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
 
         let snd_data = hardcoded_snd_data(&params);
+        let snd_state = SndState::new(&snd_data);
         let mut keep_rds = Vec::new();
         let builders = create_stream_info_builders(&params, &snd_data, &mut keep_rds)?;
 
@@ -108,6 +124,7 @@ impl SndBackend {
             workers: Default::default(),
             response_workers: Default::default(),
             snd_data: Rc::new(snd_data),
+            snd_state: Rc::new(AsyncRwLock::new(snd_state)),
             streams,
             tx_send,
             rx_send,
@@ -210,6 +227,7 @@ impl VhostUserBackend for SndBackend {
                 // ctrl queue
                 let streams = self.streams.clone();
                 let snd_data = self.snd_data.clone();
+                let snd_state = self.snd_state.clone();
                 let tx_send = self.tx_send.clone();
                 let rx_send = self.rx_send.clone();
                 let ctrl_queue = queue.clone();
@@ -218,6 +236,7 @@ impl VhostUserBackend for SndBackend {
                         ex,
                         &streams,
                         &snd_data,
+                        &snd_state,
                         ctrl_queue,
                         &mut kick_evt,
                         doorbell,
@@ -12,14 +12,18 @@ use std::path::Path;
 use std::str;
 use std::sync::Mutex as StdMutex;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use argh::FromArgs;
+use base::error;
 use base::AsRawDescriptor;
 use base::Event;
 use base::FromRawDescriptor;
 use base::IntoRawDescriptor;
 use cros_async::Executor;
 use data_model::Le64;
+use hypervisor::ProtectionType;
 use vhost::Vhost;
 use vhost::Vsock;
 use vm_memory::GuestMemory;
@@ -39,15 +43,21 @@ use vmm_vhost::VhostUserSlaveReqHandlerMut;
 use vmm_vhost::VHOST_USER_F_PROTOCOL_FEATURES;
 use zerocopy::AsBytes;
 
+use crate::virtio::base_features;
 use crate::virtio::device_constants::vsock::NUM_QUEUES;
 use crate::virtio::vhost::user::device::handler::vmm_va_to_gpa;
+use crate::virtio::vhost::user::device::handler::DeviceRequestHandler;
 use crate::virtio::vhost::user::device::handler::MappingInfo;
+use crate::virtio::vhost::user::device::handler::VhostUserBackend;
 use crate::virtio::vhost::user::device::handler::VhostUserPlatformOps;
 use crate::virtio::vhost::user::VhostUserDevice;
 use crate::virtio::vhost::user::VhostUserListener;
 use crate::virtio::vhost::user::VhostUserListenerTrait;
+use crate::virtio::vsock::UnixVsock;
+use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 use crate::virtio::QueueConfig;
+use crate::virtio::VirtioDevice;
 
 const EVENT_QUEUE: usize = NUM_QUEUES - 1;
 
@@ -122,6 +132,134 @@ impl VhostUserDevice for VhostUserVsockDevice {
     }
 }
 
+impl VhostUserDevice for UnixVsock {
+    fn max_queue_num(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn into_req_handler(
+        self: Box<Self>,
+        ops: Box<dyn VhostUserPlatformOps>,
+        _ex: &Executor,
+    ) -> anyhow::Result<Box<dyn vmm_vhost::VhostUserSlaveReqHandler>> {
+        let backend = UnixVsockBackend {
+            device: *self,
+            acked_features: 0,
+            acked_protocol_features: VhostUserProtocolFeatures::empty(),
+            queues: Default::default(),
+            doorbells: Default::default(),
+        };
+        let handler = DeviceRequestHandler::new(Box::new(backend), ops);
+        Ok(Box::new(StdMutex::new(handler)))
+    }
+}
+
+/// Runs the userspace vsock device over vhost-user. The device needs all of its queues, so it is
+/// only started once every queue has been, and stopped as soon as one of them is.
+struct UnixVsockBackend {
+    device: UnixVsock,
+    acked_features: u64,
+    acked_protocol_features: VhostUserProtocolFeatures,
+    // Queues started by the frontend but not handed to the device, and the interrupts of all
+    // started queues.
+    queues: [Option<Queue>; NUM_QUEUES],
+    doorbells: [Option<Interrupt>; NUM_QUEUES],
+}
+
+impl VhostUserBackend for UnixVsockBackend {
+    fn max_queue_num(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    fn ack_features(&mut self, value: u64) -> anyhow::Result<()> {
+        let unrequested_features = value & !self.features();
+        if unrequested_features != 0 {
+            bail!("invalid features are given: {:#x}", unrequested_features);
+        }
+
+        self.acked_features |= value;
+        self.device.ack_features(value);
+
+        Ok(())
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
+        let features = VhostUserProtocolFeatures::from_bits(features)
+            .ok_or_else(|| anyhow!("invalid protocol features are given: {:#x}", features))?;
+        let supported = self.protocol_features();
+        self.acked_protocol_features = features & supported;
+        Ok(())
+    }
+
+    fn acked_protocol_features(&self) -> u64 {
+        self.acked_protocol_features.bits()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.read_config(offset, data)
+    }
+
+    fn start_queue(
+        &mut self,
+        idx: usize,
+        queue: Queue,
+        _mem: GuestMemory,
+        doorbell: Interrupt,
+    ) -> anyhow::Result<()> {
+        if idx >= NUM_QUEUES {
+            bail!("attempted to start unknown queue: {}", idx);
+        }
+        self.queues[idx] = Some(queue);
+        self.doorbells[idx] = Some(doorbell);
+        if self.queues.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let [rx_queue, tx_queue, event_queue] = std::mem::take(&mut self.queues);
+        let [rx_doorbell, tx_doorbell, _] = &self.doorbells;
+        self.device.start_worker(
+            rx_queue.unwrap(),
+            tx_queue.unwrap(),
+            event_queue.unwrap(),
+            rx_doorbell.clone().unwrap(),
+            tx_doorbell.clone().unwrap(),
+        )
+    }
+
+    fn stop_queue(&mut self, idx: usize) -> anyhow::Result<Queue> {
+        if let Some(queues) = self.device.stop_worker()? {
+            for (slot, queue) in self.queues.iter_mut().zip(queues) {
+                *slot = Some(queue);
+            }
+        }
+        self.doorbells
+            .get_mut(idx)
+            .and_then(Option::take)
+            .ok_or_else(|| anyhow!("attempted to stop queue {} that was not started", idx))?;
+        Ok(self.queues[idx].take().unwrap())
+    }
+
+    fn reset(&mut self) {
+        if let Err(e) = self.device.stop_worker() {
+            error!("vsock worker failed: {:#}", e);
+        }
+        self.queues = Default::default();
+        self.doorbells = Default::default();
+    }
+}
+
 fn convert_vhost_error(err: vhost::Error) -> Error {
     use vhost::Error::*;
     match err {
@@ -468,6 +606,10 @@ pub struct Options {
     )]
     /// path to the vhost-vsock control socket
     vhost_socket: String,
+    #[argh(option, arg_name = "PATH")]
+    /// bridge the guest to unix sockets at PATH with a userspace device instead of using the
+    /// vhost-vsock one
+    uds_path: Option<String>,
 }
 
 /// Returns an error if the given `args` is invalid or the device fails to run.
@@ -476,7 +618,14 @@ pub fn run_vsock_device(opts: Options) -> anyhow::Result<()> {
 
     let listener = VhostUserListener::new_socket(&opts.socket, None)?;
 
-    let vsock_device = Box::new(VhostUserVsockDevice::new(opts.cid, opts.vhost_socket)?);
+    let vsock_device: Box<dyn VhostUserDevice> = match opts.uds_path {
+        Some(uds_path) => Box::new(UnixVsock::new(
+            opts.cid,
+            Path::new(&uds_path),
+            base_features(ProtectionType::Unprotected),
+        )?),
+        None => Box::new(VhostUserVsockDevice::new(opts.cid, opts.vhost_socket)?),
+    };
 
     listener.run_device(ex, vsock_device)
 }
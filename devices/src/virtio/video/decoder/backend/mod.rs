@@ -18,6 +18,8 @@ use crate::virtio::video::resource::GuestResourceHandle;
 #[cfg(feature = "ffmpeg")]
 pub mod ffmpeg;
 
+#[cfg(feature = "swcodec")]
+pub mod swcodec;
 #[cfg(feature = "vaapi")]
 pub mod vaapi;
 #[cfg(feature = "libvda")]
@@ -0,0 +1,577 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A pure-software decoder backend for the intra-only bitstream of the `swcodec` module.
+//!
+//! This backend does not depend on any host library or hardware and its output is fully
+//! deterministic, which makes it suitable for testing the decoder device and guest drivers in
+//! environments where neither libvda, VA-API nor ffmpeg are available.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::MappedRegion;
+
+use crate::virtio::video::decoder::backend::*;
+use crate::virtio::video::format::FormatDesc;
+use crate::virtio::video::format::Profile;
+use crate::virtio::video::resource::BufferHandle;
+use crate::virtio::video::swcodec::decode_frame;
+use crate::virtio::video::swcodec::swcodec_frame_formats;
+use crate::virtio::video::swcodec::write_nv12_frame;
+use crate::virtio::video::swcodec::RawFrame;
+use crate::virtio::video::swcodec::SWCODEC_FORMAT;
+use crate::virtio::video::utils::EventQueue;
+use crate::virtio::video::utils::OutputQueue;
+
+/// Number of output buffers needed to decode a stream. Frames do not reference each other, so a
+/// single one is enough.
+const MIN_NUM_OUTPUT_BUFFERS: u32 = 1;
+
+/// Types of input job we can receive from the crosvm decoder code.
+enum CodecJob {
+    Frame { timestamp: u64, frame: RawFrame },
+    Flush,
+}
+
+/// State of a session, following the same stages as the other decoder backends.
+enum SessionState {
+    /// Waiting for the first frame to know the resolution of the stream.
+    AwaitingInitialResolution,
+    /// Waiting for the client to call `set_output_parameters`.
+    AwaitingBufferCount,
+    /// Decoding and producing frames.
+    Decoding { output_queue: OutputQueue },
+    /// Dynamic Resolution Change - we can still accept buffers in the old
+    /// format, but are waiting for new parameters before doing any decoding.
+    Drc,
+}
+
+/// A decoder session for the swcodec backend.
+pub struct SwcodecDecoderSession {
+    /// Queue of events waiting to be read by the client.
+    event_queue: EventQueue<DecoderEvent>,
+    /// FIFO of decoded frames and flush requests waiting to be sent to the client.
+    codec_jobs: VecDeque<CodecJob>,
+    /// Current state of the session.
+    state: SessionState,
+    /// Size of the decoded frames (width, height).
+    current_visible_res: (u32, u32),
+}
+
+impl SwcodecDecoderSession {
+    fn queue_event(&mut self, event: DecoderEvent) -> VideoResult<()> {
+        self.event_queue
+            .queue_event(event)
+            .context("while queueing decoder event")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    /// Start the resolution change process, buffers will now be of size `new_visible_res`.
+    fn change_resolution(&mut self, new_visible_res: (u32, u32)) -> VideoResult<()> {
+        self.queue_event(DecoderEvent::ProvidePictureBuffers {
+            min_num_buffers: MIN_NUM_OUTPUT_BUFFERS,
+            width: new_visible_res.0 as i32,
+            height: new_visible_res.1 as i32,
+            visible_rect: Rect {
+                left: 0,
+                top: 0,
+                right: new_visible_res.0 as i32,
+                bottom: new_visible_res.1 as i32,
+            },
+        })?;
+
+        self.current_visible_res = new_visible_res;
+
+        self.state = match self.state {
+            SessionState::AwaitingInitialResolution => SessionState::AwaitingBufferCount,
+            SessionState::Decoding { .. } => SessionState::Drc,
+            _ => {
+                return Err(VideoError::BackendFailure(anyhow!(
+                    "unexpected state during resolution change"
+                )))
+            }
+        };
+
+        Ok(())
+    }
+
+    /// Try to run the next job, if any.
+    ///
+    /// Returns `true` if the job has been completed, `false` if there is no job to run or the next
+    /// frame cannot be sent until the client provides an output buffer.
+    fn try_run_job(&mut self) -> VideoResult<bool> {
+        let (timestamp, frame) = match self.codec_jobs.pop_front() {
+            None => return Ok(false),
+            Some(CodecJob::Flush) => {
+                self.queue_event(DecoderEvent::FlushCompleted(Ok(())))?;
+                return Ok(true);
+            }
+            Some(CodecJob::Frame { timestamp, frame }) => (timestamp, frame),
+        };
+
+        let frame_res = (frame.width, frame.height);
+        if frame_res != self.current_visible_res {
+            // Keep the frame until the client provides buffers of the new resolution.
+            self.codec_jobs
+                .push_front(CodecJob::Frame { timestamp, frame });
+            self.change_resolution(frame_res)?;
+            return Ok(false);
+        }
+
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            _ => {
+                self.codec_jobs
+                    .push_front(CodecJob::Frame { timestamp, frame });
+                return Ok(false);
+            }
+        };
+
+        let (picture_buffer_id, target_buffer) = match output_queue.try_get_ready_buffer() {
+            None => {
+                self.codec_jobs
+                    .push_front(CodecJob::Frame { timestamp, frame });
+                return Ok(false);
+            }
+            Some(buffer) => buffer,
+        };
+
+        write_nv12_frame(target_buffer, &frame)
+            .context("while writing decoded frame")
+            .map_err(VideoError::BackendFailure)?;
+        self.queue_event(DecoderEvent::PictureReady {
+            picture_buffer_id: picture_buffer_id as i32,
+            timestamp,
+            visible_rect: Rect {
+                left: 0,
+                top: 0,
+                right: frame.width as i32,
+                bottom: frame.height as i32,
+            },
+        })?;
+
+        Ok(true)
+    }
+
+    /// Send as many decoded frames as possible to the client.
+    fn try_decode(&mut self) -> VideoResult<()> {
+        while self.try_run_job()? {}
+
+        Ok(())
+    }
+}
+
+impl DecoderSession for SwcodecDecoderSession {
+    fn set_output_parameters(&mut self, buffer_count: usize, format: Format) -> VideoResult<()> {
+        match self.state {
+            // It is valid to set an output format before the initial DRC, but we won't do
+            // anything with it.
+            SessionState::AwaitingInitialResolution => Ok(()),
+            SessionState::AwaitingBufferCount | SessionState::Drc => {
+                if format != Format::NV12 {
+                    return Err(VideoError::InvalidFormat);
+                }
+                self.state = SessionState::Decoding {
+                    output_queue: OutputQueue::new(buffer_count),
+                };
+                Ok(())
+            }
+            _ => Err(VideoError::BackendFailure(anyhow!(
+                "invalid state while calling set_output_parameters"
+            ))),
+        }
+    }
+
+    fn decode(
+        &mut self,
+        resource_id: u32,
+        timestamp: u64,
+        resource: GuestResourceHandle,
+        offset: u32,
+        bytes_used: u32,
+    ) -> VideoResult<()> {
+        let mapping = resource
+            .get_mapping(offset as usize, bytes_used as usize)
+            .context("while mapping input buffer")
+            .map_err(VideoError::BackendFailure)?;
+        let mut bitstream = vec![0u8; mapping.size()];
+        // Safe because the mapping is linear and we own it, so it will not be unmapped while we
+        // copy from it.
+        bitstream.copy_from_slice(unsafe {
+            std::slice::from_raw_parts(mapping.as_ptr(), mapping.size())
+        });
+        // The whole input has been consumed, so the buffer can be reused right away.
+        drop(mapping);
+        self.queue_event(DecoderEvent::NotifyEndOfBitstreamBuffer(resource_id))?;
+
+        match decode_frame(&bitstream) {
+            Ok(frame) => self
+                .codec_jobs
+                .push_back(CodecJob::Frame { timestamp, frame }),
+            // This is a decoding error, so signal it using a `NotifyError` event to reflect the
+            // same asynchronous flow as a hardware decoder would.
+            Err(e) => self.queue_event(DecoderEvent::NotifyError(VideoError::BackendFailure(
+                anyhow!(e),
+            )))?,
+        }
+
+        self.try_decode()
+    }
+
+    fn flush(&mut self) -> VideoResult<()> {
+        if self
+            .codec_jobs
+            .iter()
+            .any(|job| matches!(job, CodecJob::Flush))
+        {
+            return Err(VideoError::BackendFailure(anyhow!(
+                "flush is already in progress"
+            )));
+        }
+        self.codec_jobs.push_back(CodecJob::Flush);
+        self.try_decode()
+    }
+
+    fn reset(&mut self) -> VideoResult<()> {
+        // Drop all currently pending jobs.
+        self.codec_jobs.clear();
+
+        // Drop the queued output buffers.
+        self.clear_output_buffers()?;
+
+        self.queue_event(DecoderEvent::ResetCompleted(Ok(())))
+    }
+
+    fn clear_output_buffers(&mut self) -> VideoResult<()> {
+        // Cancel any ongoing flush.
+        self.codec_jobs
+            .retain(|job| !matches!(job, CodecJob::Flush));
+
+        // Drop all output buffers we currently hold.
+        if let SessionState::Decoding { output_queue } = &mut self.state {
+            output_queue.clear_ready_buffers();
+        }
+
+        // Drop all decoded frames signaled as ready and cancel any reported flush.
+        self.event_queue.retain(|event| {
+            !matches!(
+                event,
+                DecoderEvent::PictureReady { .. } | DecoderEvent::FlushCompleted(_)
+            )
+        });
+
+        Ok(())
+    }
+
+    fn event_pipe(&self) -> &dyn AsRawDescriptor {
+        &self.event_queue
+    }
+
+    fn use_output_buffer(
+        &mut self,
+        picture_buffer_id: i32,
+        resource: GuestResource,
+    ) -> VideoResult<()> {
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            // Receiving buffers before the initial DRC or during a DRC is valid, but we won't use
+            // them and can just drop them.
+            SessionState::AwaitingInitialResolution | SessionState::Drc => return Ok(()),
+            SessionState::AwaitingBufferCount => {
+                return Err(VideoError::BackendFailure(anyhow!(
+                    "invalid state while calling use_output_buffer"
+                )))
+            }
+        };
+
+        output_queue
+            .import_buffer(picture_buffer_id as u32, resource)
+            .context("while importing output buffer")
+            .map_err(VideoError::BackendFailure)?;
+        self.try_decode()
+    }
+
+    fn reuse_output_buffer(&mut self, picture_buffer_id: i32) -> VideoResult<()> {
+        let output_queue = match &mut self.state {
+            SessionState::Decoding { output_queue } => output_queue,
+            // Reusing buffers before the initial DRC or during a DRC is valid, but we won't use
+            // them and can just drop them.
+            SessionState::AwaitingInitialResolution | SessionState::Drc => return Ok(()),
+            SessionState::AwaitingBufferCount => {
+                return Err(VideoError::BackendFailure(anyhow!(
+                    "invalid state while calling reuse_output_buffer"
+                )))
+            }
+        };
+
+        output_queue
+            .reuse_buffer(picture_buffer_id as u32)
+            .context("while reusing output buffer")
+            .map_err(VideoError::BackendFailure)?;
+        self.try_decode()
+    }
+
+    fn read_event(&mut self) -> VideoResult<DecoderEvent> {
+        self.event_queue
+            .dequeue_event()
+            .context("while reading decoder event")
+            .map_err(VideoError::BackendFailure)
+    }
+}
+
+#[derive(Default)]
+pub struct SwcodecDecoder;
+
+impl SwcodecDecoder {
+    /// Create a new swcodec decoder backend instance.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl DecoderBackend for SwcodecDecoder {
+    type Session = SwcodecDecoderSession;
+
+    fn get_capabilities(&self) -> Capability {
+        let in_formats = vec![FormatDesc {
+            mask: 1,
+            format: SWCODEC_FORMAT,
+            frame_formats: swcodec_frame_formats(),
+            plane_align: 1,
+        }];
+        let out_formats = vec![FormatDesc {
+            mask: 1,
+            format: Format::NV12,
+            frame_formats: swcodec_frame_formats(),
+            plane_align: 1,
+        }];
+        let profiles = BTreeMap::from([(SWCODEC_FORMAT, vec![Profile::VP8Profile0])]);
+
+        Capability::new(in_formats, out_formats, profiles, Default::default())
+    }
+
+    fn new_session(&mut self, format: Format) -> VideoResult<Self::Session> {
+        if format != SWCODEC_FORMAT {
+            return Err(VideoError::InvalidFormat);
+        }
+        Ok(SwcodecDecoderSession {
+            event_queue: EventQueue::new()
+                .context("while creating decoder session")
+                .map_err(VideoError::BackendFailure)?,
+            codec_jobs: Default::default(),
+            state: SessionState::AwaitingInitialResolution,
+            current_visible_res: (0, 0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use base::MemoryMappingBuilder;
+    use base::SharedMemory;
+    use base::WaitContext;
+
+    use super::super::tests::*;
+    use super::*;
+    use crate::virtio::video::format::FramePlane;
+    use crate::virtio::video::swcodec::encode_frame;
+    use crate::virtio::video::swcodec::nv12_frame_size;
+    use crate::virtio::video::swcodec::tests::test_frame;
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+    /// Stride of the output buffers, larger than the width to check that padding is respected.
+    const STRIDE: u32 = 80;
+    const NUM_FRAMES: usize = 10;
+    const NUM_OUTPUT_BUFFERS: usize = 2;
+
+    fn read_events(
+        session: &mut SwcodecDecoderSession,
+        wait_ctx: &WaitContext<u8>,
+    ) -> Vec<DecoderEvent> {
+        let mut events = Vec::new();
+        while !wait_ctx.wait_timeout(Duration::ZERO).unwrap().is_empty() {
+            events.push(session.read_event().unwrap());
+        }
+        events
+    }
+
+    /// Decodes a stream of test frames and checks that the decoded frames are identical to the
+    /// encoded ones.
+    fn decode_generic<I, O>(input_resource_builder: I, output_resource_builder: O)
+    where
+        I: Fn(&SharedMemory) -> GuestResourceHandle,
+        O: Fn(&SharedMemory) -> GuestResourceHandle,
+    {
+        let mut decoder = SwcodecDecoder::new();
+        let mut session = decoder.new_session(SWCODEC_FORMAT).unwrap();
+        let wait_ctx = WaitContext::new().unwrap();
+        wait_ctx.add(session.event_pipe(), 0u8).unwrap();
+
+        let output_buffer_size = (STRIDE * (HEIGHT + HEIGHT / 2)) as u64;
+        let output_buffers = (0..NUM_OUTPUT_BUFFERS)
+            .map(|i| {
+                SharedMemory::new(format!("video-output-buffer-{}", i), output_buffer_size).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let input_shm = SharedMemory::new("video-input-buffer", 0x10000).unwrap();
+        let input_mapping = MemoryMappingBuilder::new(input_shm.size() as usize)
+            .from_shared_memory(&input_shm)
+            .build()
+            .unwrap();
+
+        let mut decoded_frames = Vec::new();
+        let mut on_frame_decoded =
+            |session: &mut SwcodecDecoderSession, event: DecoderEvent| match event {
+                DecoderEvent::PictureReady {
+                    picture_buffer_id,
+                    timestamp,
+                    visible_rect,
+                } => {
+                    assert_eq!(visible_rect.right, WIDTH as i32);
+                    assert_eq!(visible_rect.bottom, HEIGHT as i32);
+                    let mapping = MemoryMappingBuilder::new(output_buffer_size as usize)
+                        .from_shared_memory(&output_buffers[picture_buffer_id as usize])
+                        .build()
+                        .unwrap();
+                    let mut data = Vec::with_capacity(nv12_frame_size(WIDTH, HEIGHT));
+                    for row in 0..(HEIGHT + HEIGHT / 2) {
+                        let mut row_data = vec![0u8; WIDTH as usize];
+                        mapping
+                            .read_slice(&mut row_data, (row * STRIDE) as usize)
+                            .unwrap();
+                        data.extend(row_data);
+                    }
+                    decoded_frames.push((timestamp, data));
+                    session.reuse_output_buffer(picture_buffer_id).unwrap();
+                }
+                e => panic!("Unexpected event: {:?}", e),
+            };
+
+        for index in 0..NUM_FRAMES {
+            let bitstream = encode_frame(&test_frame(WIDTH, HEIGHT, index)).unwrap();
+            input_mapping.write_slice(&bitstream, 0).unwrap();
+            session
+                .decode(
+                    index as u32,
+                    index as u64 * 1000,
+                    input_resource_builder(&input_shm),
+                    0,
+                    bitstream.len() as u32,
+                )
+                .unwrap();
+
+            let mut events = read_events(&mut session, &wait_ctx);
+            assert!(matches!(
+                events.remove(0),
+                DecoderEvent::NotifyEndOfBitstreamBuffer(id) if id == index as u32
+            ));
+
+            if index == 0 {
+                assert!(matches!(
+                    events.remove(0),
+                    DecoderEvent::ProvidePictureBuffers {
+                        min_num_buffers: MIN_NUM_OUTPUT_BUFFERS,
+                        width: 64,
+                        height: 48,
+                        ..
+                    }
+                ));
+                session
+                    .set_output_parameters(NUM_OUTPUT_BUFFERS, Format::NV12)
+                    .unwrap();
+                for (picture_buffer_id, buffer) in output_buffers.iter().enumerate() {
+                    session
+                        .use_output_buffer(
+                            picture_buffer_id as i32,
+                            GuestResource {
+                                handle: output_resource_builder(buffer),
+                                planes: vec![
+                                    FramePlane {
+                                        offset: 0,
+                                        stride: STRIDE as usize,
+                                        size: (STRIDE * HEIGHT) as usize,
+                                    },
+                                    FramePlane {
+                                        offset: (STRIDE * HEIGHT) as usize,
+                                        stride: STRIDE as usize,
+                                        size: (STRIDE * HEIGHT / 2) as usize,
+                                    },
+                                ],
+                                width: WIDTH,
+                                height: HEIGHT,
+                                format: Format::NV12,
+                                guest_cpu_mappable: false,
+                            },
+                        )
+                        .unwrap();
+                }
+                events.extend(read_events(&mut session, &wait_ctx));
+            }
+
+            for event in events {
+                on_frame_decoded(&mut session, event);
+            }
+        }
+
+        session.flush().unwrap();
+        let mut received_flush_completed = false;
+        for event in read_events(&mut session, &wait_ctx) {
+            match event {
+                DecoderEvent::FlushCompleted(Ok(())) => received_flush_completed = true,
+                event => on_frame_decoded(&mut session, event),
+            }
+        }
+        assert!(received_flush_completed);
+
+        assert_eq!(decoded_frames.len(), NUM_FRAMES);
+        for (index, (timestamp, data)) in decoded_frames.into_iter().enumerate() {
+            assert_eq!(timestamp, index as u64 * 1000);
+            assert_eq!(data, test_frame(WIDTH, HEIGHT, index).data);
+        }
+    }
+
+    #[test]
+    fn test_get_capabilities() {
+        let caps = SwcodecDecoder::new().get_capabilities();
+        assert_eq!(caps.input_formats().len(), 1);
+        assert_eq!(caps.input_formats()[0].format, SWCODEC_FORMAT);
+        assert_eq!(caps.output_formats()[0].format, Format::NV12);
+    }
+
+    #[test]
+    fn test_decode_guestmem_to_guestmem() {
+        decode_generic(build_guest_mem_handle, build_guest_mem_handle);
+    }
+
+    #[test]
+    fn test_decode_object_to_object() {
+        decode_generic(build_object_handle, build_object_handle);
+    }
+
+    #[test]
+    fn test_decode_invalid_frame() {
+        let mut session = SwcodecDecoder::new().new_session(SWCODEC_FORMAT).unwrap();
+        let wait_ctx = WaitContext::new().unwrap();
+        wait_ctx.add(session.event_pipe(), 0u8).unwrap();
+
+        let input_shm = SharedMemory::new("video-input-buffer", 0x1000).unwrap();
+        session
+            .decode(3, 0, build_guest_mem_handle(&input_shm), 0, 64)
+            .unwrap();
+
+        let events = read_events(&mut session, &wait_ctx);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            DecoderEvent::NotifyEndOfBitstreamBuffer(3)
+        ));
+        assert!(matches!(events[1], DecoderEvent::NotifyError(_)));
+    }
+}
@@ -4,6 +4,8 @@
 
 #[cfg(feature = "ffmpeg")]
 pub mod ffmpeg;
+#[cfg(feature = "swcodec")]
+pub mod swcodec;
 #[cfg(feature = "libvda")]
 pub mod vda;
 
@@ -0,0 +1,437 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A pure-software encoder backend producing the intra-only bitstream of the `swcodec` module.
+//!
+//! Like its decoder counterpart, this backend has no host dependency and produces deterministic
+//! output, and is meant for testing the encoder device.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::AsRawDescriptor;
+use base::MappedRegion;
+use base::MemoryMappingArena;
+
+use crate::virtio::video::encoder::backend::Encoder;
+use crate::virtio::video::encoder::backend::EncoderSession;
+use crate::virtio::video::encoder::EncoderCapabilities;
+use crate::virtio::video::encoder::EncoderEvent;
+use crate::virtio::video::encoder::InputBufferId;
+use crate::virtio::video::encoder::OutputBufferId;
+use crate::virtio::video::encoder::SessionConfig;
+use crate::virtio::video::error::VideoError;
+use crate::virtio::video::error::VideoResult;
+use crate::virtio::video::format::Bitrate;
+use crate::virtio::video::format::Format;
+use crate::virtio::video::format::FormatDesc;
+use crate::virtio::video::format::Profile;
+use crate::virtio::video::resource::BufferHandle;
+use crate::virtio::video::resource::GuestResource;
+use crate::virtio::video::resource::GuestResourceHandle;
+use crate::virtio::video::swcodec::encode_frame;
+use crate::virtio::video::swcodec::max_encoded_size;
+use crate::virtio::video::swcodec::read_nv12_frame;
+use crate::virtio::video::swcodec::swcodec_frame_formats;
+use crate::virtio::video::swcodec::SWCODEC_FORMAT;
+use crate::virtio::video::utils::EventQueue;
+
+/// Number of input buffers requested from the client. Frames are encoded as soon as they are
+/// received, so this does not need to be large.
+const NUM_INPUT_BUFFERS: u32 = 4;
+
+enum CodecJob {
+    /// An encoded frame, waiting for an output buffer.
+    Packet {
+        timestamp: u64,
+        data: Vec<u8>,
+    },
+    Flush,
+}
+
+pub struct SwcodecEncoderSession {
+    /// Queue of events waiting to be read by the client.
+    event_queue: EventQueue<EncoderEvent>,
+
+    /// FIFO of encoded frames and flush requests waiting to be sent to the client.
+    codec_jobs: VecDeque<CodecJob>,
+    /// Queue of (unfilled) output buffers to fill with upcoming encoder output.
+    output_queue: VecDeque<(OutputBufferId, MemoryMappingArena)>,
+
+    next_input_buffer_id: InputBufferId,
+    next_output_buffer_id: OutputBufferId,
+}
+
+impl SwcodecEncoderSession {
+    fn queue_event(&mut self, event: EncoderEvent) -> VideoResult<()> {
+        self.event_queue
+            .queue_event(event)
+            .context("while queueing encoder event")
+            .map_err(VideoError::BackendFailure)
+    }
+
+    /// Try to run the next job, if any.
+    ///
+    /// Returns `true` if the job has been completed, `false` if there is no job to run or the next
+    /// packet cannot be sent until the client provides an output buffer.
+    fn try_run_job(&mut self) -> VideoResult<bool> {
+        match self.codec_jobs.front() {
+            None => Ok(false),
+            Some(CodecJob::Flush) => {
+                self.codec_jobs.pop_front();
+                self.queue_event(EncoderEvent::FlushResponse { flush_done: true })?;
+                Ok(true)
+            }
+            Some(CodecJob::Packet { timestamp, data }) => {
+                let (buffer_id, out_buf) = match self.output_queue.front() {
+                    Some(p) => p,
+                    None => return Ok(false),
+                };
+                if data.len() > out_buf.size() {
+                    return Err(VideoError::BackendFailure(anyhow!(
+                        "encoded packet does not fit in output buffer"
+                    )));
+                }
+                // Safe because the mapping is linear and we own it, so it will not be unmapped
+                // during the lifetime of this slice, and we checked that the data fits.
+                unsafe { std::slice::from_raw_parts_mut(out_buf.as_ptr(), data.len()) }
+                    .copy_from_slice(data);
+                let event = EncoderEvent::ProcessedOutputBuffer {
+                    id: *buffer_id,
+                    bytesused: data.len() as u32,
+                    // All frames are intra-coded.
+                    keyframe: true,
+                    timestamp: *timestamp,
+                };
+                self.queue_event(event)?;
+                self.output_queue.pop_front();
+                self.codec_jobs.pop_front();
+                Ok(true)
+            }
+        }
+    }
+
+    /// Send as many encoded packets as possible to the client.
+    fn try_encode(&mut self) -> VideoResult<()> {
+        while self.try_run_job()? {}
+
+        Ok(())
+    }
+}
+
+impl EncoderSession for SwcodecEncoderSession {
+    fn encode(
+        &mut self,
+        resource: GuestResource,
+        timestamp: u64,
+        _force_keyframe: bool,
+    ) -> VideoResult<InputBufferId> {
+        let buffer_id = self.next_input_buffer_id;
+        self.next_input_buffer_id = buffer_id.wrapping_add(1);
+
+        let frame = read_nv12_frame(&resource)
+            .context("while reading input frame")
+            .map_err(VideoError::BackendFailure)?;
+        // The frame has been copied, so the input buffer can be reused right away.
+        drop(resource);
+        self.queue_event(EncoderEvent::ProcessedInputBuffer { id: buffer_id })?;
+
+        let data = encode_frame(&frame)
+            .context("while encoding frame")
+            .map_err(VideoError::BackendFailure)?;
+        self.codec_jobs
+            .push_back(CodecJob::Packet { timestamp, data });
+        self.try_encode()?;
+
+        Ok(buffer_id)
+    }
+
+    fn use_output_buffer(
+        &mut self,
+        resource: GuestResourceHandle,
+        offset: u32,
+        size: u32,
+    ) -> VideoResult<OutputBufferId> {
+        let buffer_id = self.next_output_buffer_id;
+        self.next_output_buffer_id = buffer_id.wrapping_add(1);
+
+        let mapping = resource
+            .get_mapping(offset as usize, size as usize)
+            .context("while mapping output buffer")
+            .map_err(VideoError::BackendFailure)?;
+
+        self.output_queue.push_back((buffer_id, mapping));
+        self.try_encode()?;
+        Ok(buffer_id)
+    }
+
+    fn flush(&mut self) -> VideoResult<()> {
+        if self
+            .codec_jobs
+            .iter()
+            .any(|job| matches!(job, CodecJob::Flush))
+        {
+            return Err(VideoError::BackendFailure(anyhow!(
+                "flush is already in progress"
+            )));
+        }
+        self.codec_jobs.push_back(CodecJob::Flush);
+        self.try_encode()
+    }
+
+    fn request_encoding_params_change(
+        &mut self,
+        _bitrate: Bitrate,
+        _framerate: u32,
+    ) -> VideoResult<()> {
+        // The encoding is lossless and does not depend on the bitrate or the framerate.
+        Ok(())
+    }
+
+    fn event_pipe(&self) -> &dyn AsRawDescriptor {
+        &self.event_queue
+    }
+
+    fn read_event(&mut self) -> VideoResult<EncoderEvent> {
+        self.event_queue
+            .dequeue_event()
+            .context("while reading encoder event")
+            .map_err(VideoError::BackendFailure)
+    }
+}
+
+#[derive(Default)]
+pub struct SwcodecEncoder;
+
+impl SwcodecEncoder {
+    /// Create a new swcodec encoder backend instance.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encoder for SwcodecEncoder {
+    type Session = SwcodecEncoderSession;
+
+    fn query_capabilities(&self) -> VideoResult<EncoderCapabilities> {
+        Ok(EncoderCapabilities {
+            input_format_descs: vec![FormatDesc {
+                mask: 1,
+                format: Format::NV12,
+                frame_formats: swcodec_frame_formats(),
+                plane_align: 1,
+            }],
+            output_format_descs: vec![FormatDesc {
+                mask: 1,
+                format: SWCODEC_FORMAT,
+                frame_formats: swcodec_frame_formats(),
+                plane_align: 1,
+            }],
+            coded_format_profiles: BTreeMap::from([(SWCODEC_FORMAT, vec![Profile::VP8Profile0])]),
+        })
+    }
+
+    fn start_session(&mut self, config: SessionConfig) -> VideoResult<Self::Session> {
+        if config.dst_params.format != Some(SWCODEC_FORMAT)
+            || config.src_params.format != Some(Format::NV12)
+        {
+            return Err(VideoError::InvalidFormat);
+        }
+        let mut session = SwcodecEncoderSession {
+            event_queue: EventQueue::new()
+                .context("while creating encoder session")
+                .map_err(VideoError::BackendFailure)?,
+            codec_jobs: Default::default(),
+            output_queue: Default::default(),
+            next_input_buffer_id: 0,
+            next_output_buffer_id: 0,
+        };
+        session.queue_event(EncoderEvent::RequireInputBuffers {
+            input_count: NUM_INPUT_BUFFERS,
+            input_frame_height: config.src_params.frame_height,
+            input_frame_width: config.src_params.frame_width,
+            output_buffer_size: max_encoded_size(
+                config.src_params.frame_width,
+                config.src_params.frame_height,
+            ) as u32,
+        })?;
+        Ok(session)
+    }
+
+    fn stop_session(&mut self, _session: Self::Session) -> VideoResult<()> {
+        // Just Drop.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use base::FromRawDescriptor;
+    use base::MemoryMappingBuilder;
+    use base::SafeDescriptor;
+    use base::SharedMemory;
+    use base::WaitContext;
+
+    use super::*;
+    use crate::virtio::video::format::FramePlane;
+    use crate::virtio::video::params::Params;
+    use crate::virtio::video::resource::GuestMemArea;
+    use crate::virtio::video::resource::GuestMemHandle;
+    use crate::virtio::video::swcodec::decode_frame;
+    use crate::virtio::video::swcodec::tests::test_frame;
+
+    const WIDTH: u32 = 32;
+    const HEIGHT: u32 = 16;
+    const NUM_FRAMES: usize = 5;
+
+    fn build_guest_mem_handle(mem: &SharedMemory) -> GuestResourceHandle {
+        GuestResourceHandle::GuestPages(GuestMemHandle {
+            // Safe because we are taking ownership of a just-duplicated FD.
+            desc: unsafe {
+                SafeDescriptor::from_raw_descriptor(base::clone_descriptor(mem).unwrap())
+            },
+            mem_areas: vec![GuestMemArea {
+                offset: 0,
+                length: mem.size() as usize,
+            }],
+        })
+    }
+
+    fn read_events(
+        session: &mut SwcodecEncoderSession,
+        wait_ctx: &WaitContext<u8>,
+    ) -> Vec<EncoderEvent> {
+        let mut events = Vec::new();
+        while !wait_ctx.wait_timeout(Duration::ZERO).unwrap().is_empty() {
+            events.push(session.read_event().unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut encoder = SwcodecEncoder::new();
+        let mut session = encoder
+            .start_session(SessionConfig {
+                src_params: Params {
+                    format: Some(Format::NV12),
+                    frame_width: WIDTH,
+                    frame_height: HEIGHT,
+                    ..Default::default()
+                },
+                dst_params: Params {
+                    format: Some(SWCODEC_FORMAT),
+                    ..Default::default()
+                },
+                dst_profile: Profile::VP8Profile0,
+                dst_bitrate: Bitrate::Cbr { target: 0 },
+                dst_h264_level: None,
+                frame_rate: 30,
+            })
+            .unwrap();
+        let wait_ctx = WaitContext::new().unwrap();
+        wait_ctx.add(session.event_pipe(), 0u8).unwrap();
+
+        let output_buffer_size = match read_events(&mut session, &wait_ctx).as_slice() {
+            [EncoderEvent::RequireInputBuffers {
+                output_buffer_size, ..
+            }] => *output_buffer_size,
+            events => panic!("Unexpected events: {:?}", events),
+        };
+
+        let input_shm = SharedMemory::new("video-input-buffer", 0x1000).unwrap();
+        let input_mapping = MemoryMappingBuilder::new(input_shm.size() as usize)
+            .from_shared_memory(&input_shm)
+            .build()
+            .unwrap();
+        let output_shm = SharedMemory::new(
+            "video-output-buffer",
+            output_buffer_size as u64 * NUM_FRAMES as u64,
+        )
+        .unwrap();
+        let output_mapping = MemoryMappingBuilder::new(output_shm.size() as usize)
+            .from_shared_memory(&output_shm)
+            .build()
+            .unwrap();
+
+        // Queue all the frames before the output buffers, so the encoded packets have to wait.
+        for index in 0..NUM_FRAMES {
+            let frame = test_frame(WIDTH, HEIGHT, index);
+            input_mapping.write_slice(&frame.data, 0).unwrap();
+            let id = session
+                .encode(
+                    GuestResource {
+                        handle: build_guest_mem_handle(&input_shm),
+                        planes: vec![
+                            FramePlane {
+                                offset: 0,
+                                stride: WIDTH as usize,
+                                size: (WIDTH * HEIGHT) as usize,
+                            },
+                            FramePlane {
+                                offset: (WIDTH * HEIGHT) as usize,
+                                stride: WIDTH as usize,
+                                size: (WIDTH * HEIGHT / 2) as usize,
+                            },
+                        ],
+                        width: WIDTH,
+                        height: HEIGHT,
+                        format: Format::NV12,
+                        guest_cpu_mappable: false,
+                    },
+                    index as u64,
+                    false,
+                )
+                .unwrap();
+            assert!(matches!(
+                read_events(&mut session, &wait_ctx).as_slice(),
+                [EncoderEvent::ProcessedInputBuffer { id: event_id }] if *event_id == id
+            ));
+        }
+        session.flush().unwrap();
+        assert!(read_events(&mut session, &wait_ctx).is_empty());
+
+        let mut remaining_events = Vec::new();
+        for index in 0..NUM_FRAMES {
+            let offset = output_buffer_size * index as u32;
+            session
+                .use_output_buffer(
+                    build_guest_mem_handle(&output_shm),
+                    offset,
+                    output_buffer_size,
+                )
+                .unwrap();
+            let mut events = read_events(&mut session, &wait_ctx);
+            let bytesused = match events.remove(0) {
+                EncoderEvent::ProcessedOutputBuffer {
+                    bytesused,
+                    keyframe: true,
+                    timestamp,
+                    ..
+                } if timestamp == index as u64 => bytesused,
+                event => panic!("Unexpected event: {:?}", event),
+            };
+            remaining_events.extend(events);
+
+            let mut packet = vec![0u8; bytesused as usize];
+            output_mapping
+                .read_slice(&mut packet, offset as usize)
+                .unwrap();
+            assert_eq!(
+                decode_frame(&packet).unwrap(),
+                test_frame(WIDTH, HEIGHT, index)
+            );
+        }
+
+        // The flush completes once the last packet has been sent.
+        assert!(matches!(
+            remaining_events.as_slice(),
+            [EncoderEvent::FlushResponse { flush_done: true }]
+        ));
+    }
+}
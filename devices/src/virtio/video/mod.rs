@@ -54,18 +54,25 @@ pub mod worker;
 
 #[cfg(all(
     feature = "video-decoder",
-    not(any(feature = "libvda", feature = "ffmpeg", feature = "vaapi"))
+    not(any(
+        feature = "libvda",
+        feature = "ffmpeg",
+        feature = "vaapi",
+        feature = "swcodec"
+    ))
 ))]
-compile_error!("The \"video-decoder\" feature requires at least one of \"ffmpeg\", \"libvda\" or \"vaapi\" to also be enabled.");
+compile_error!("The \"video-decoder\" feature requires at least one of \"ffmpeg\", \"libvda\", \"vaapi\" or \"swcodec\" to also be enabled.");
 
 #[cfg(all(
     feature = "video-encoder",
-    not(any(feature = "libvda", feature = "ffmpeg"))
+    not(any(feature = "libvda", feature = "ffmpeg", feature = "swcodec"))
 ))]
-compile_error!("The \"video-encoder\" feature requires at least one of \"ffmpeg\" or \"libvda\" to also be enabled.");
+compile_error!("The \"video-encoder\" feature requires at least one of \"ffmpeg\", \"libvda\" or \"swcodec\" to also be enabled.");
 
 #[cfg(feature = "ffmpeg")]
 mod ffmpeg;
+#[cfg(feature = "swcodec")]
+mod swcodec;
 #[cfg(feature = "libvda")]
 mod vda;
 
@@ -173,6 +180,8 @@ pub fn build_config(backend: VideoBackendType) -> virtio_video_config {
         VideoBackendType::Ffmpeg => device_name[0..6].copy_from_slice("ffmpeg".as_bytes()),
         #[cfg(feature = "vaapi")]
         VideoBackendType::Vaapi => device_name[0..5].copy_from_slice("vaapi".as_bytes()),
+        #[cfg(feature = "swcodec")]
+        VideoBackendType::Swcodec => device_name[0..7].copy_from_slice("swcodec".as_bytes()),
     };
     virtio_video_config {
         version: Le32::from(0),
@@ -303,6 +312,18 @@ impl VirtioDevice for VideoDevice {
                             error!("The VA-API encoder is not supported yet");
                             return;
                         }
+                        #[cfg(feature = "swcodec")]
+                        VideoBackendType::Swcodec => {
+                            let swcodec = encoder::backend::swcodec::SwcodecEncoder::new();
+
+                            match encoder::EncoderDevice::new(swcodec, resource_bridge, mem) {
+                                Ok(encoder) => Box::new(encoder),
+                                Err(e) => {
+                                    error!("Failed to create encoder device: {}", e);
+                                    return;
+                                }
+                            }
+                        }
                     };
 
                     if let Err(e) = worker.run(device, &kill_evt) {
@@ -367,6 +388,11 @@ pub fn create_decoder_device(
             })?;
             Box::new(decoder::Decoder::new(va, resource_bridge, mem))
         }
+        #[cfg(feature = "swcodec")]
+        VideoBackendType::Swcodec => {
+            let swcodec = decoder::backend::swcodec::SwcodecDecoder::new();
+            Box::new(decoder::Decoder::new(swcodec, resource_bridge, mem))
+        }
     })
 }
 
@@ -0,0 +1,427 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Bitstream format and buffer helpers shared by the `swcodec` decoder and encoder backends.
+//!
+//! The `swcodec` backends implement a trivial, deterministic, intra-only codec in pure Rust. They
+//! do not depend on any host media library, which makes them suitable to exercise the virtio-video
+//! device (command handling, resource management, event flow) in tests and CI, but they are of no
+//! use to decode real-world streams.
+//!
+//! Each encoded frame is self-contained, in the spirit of MJPEG, and must be submitted as a single
+//! input buffer. It starts with a 16-byte header:
+//!
+//! | offset | size | content                           |
+//! |--------|------|-----------------------------------|
+//! | 0      | 4    | magic, `CRVF`                     |
+//! | 4      | 1    | version, currently 1              |
+//! | 5      | 1    | flags, bit 0 is set on keyframes  |
+//! | 6      | 2    | reserved, must be zero            |
+//! | 8      | 4    | frame width, little-endian        |
+//! | 12     | 4    | frame height, little-endian       |
+//!
+//! and is followed by the tightly packed NV12 planes of the frame, compressed with a PackBits
+//! run-length encoding: a control byte `n` below 128 is followed by `n + 1` literal bytes, while a
+//! control byte of 128 or above is followed by a single byte repeated `n - 126` times.
+//!
+//! virtio-video has no format code for private bitstreams, so the backends advertise this format
+//! as [`SWCODEC_FORMAT`].
+
+// The encoder and decoder backends each use only a part of this module.
+#![allow(dead_code)]
+
+use std::convert::TryInto;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::MappedRegion;
+use base::MemoryMappingArena;
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+use crate::virtio::video::format::Format;
+use crate::virtio::video::format::FormatRange;
+use crate::virtio::video::format::FrameFormat;
+use crate::virtio::video::resource::BufferHandle;
+use crate::virtio::video::resource::GuestResource;
+
+/// Format under which the `swcodec` bitstream is advertised to the guest.
+pub const SWCODEC_FORMAT: Format = Format::VP8;
+
+const FRAME_MAGIC: [u8; 4] = *b"CRVF";
+const FRAME_VERSION: u8 = 1;
+const FRAME_FLAG_KEYFRAME: u8 = 1 << 0;
+const FRAME_HEADER_SIZE: usize = 16;
+
+/// Largest width and height of the frames supported by the backends.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// Longest sequence of literal bytes a control byte can describe.
+const MAX_LITERAL_LEN: usize = 128;
+/// Longest run of identical bytes a control byte can describe.
+const MAX_RUN_LEN: usize = 129;
+
+#[sorted]
+#[derive(Debug, ThisError)]
+pub enum BitstreamError {
+    #[error("frame data is {0} bytes but should be {1} bytes")]
+    BadFrameSize(usize, usize),
+    #[error("invalid frame dimensions {0}x{1}")]
+    InvalidDimensions(u32, u32),
+    #[error("invalid frame header")]
+    InvalidHeader,
+    #[error("unsupported bitstream version {0}")]
+    UnsupportedVersion(u8),
+}
+
+/// A decoded frame, with its NV12 planes tightly packed in `data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Returns the size of the tightly packed NV12 planes of a `width`x`height` frame.
+pub fn nv12_frame_size(width: u32, height: u32) -> usize {
+    let width = width as usize;
+    let height = height as usize;
+    width * height + width * (height / 2)
+}
+
+/// Returns the largest possible size of an encoded `width`x`height` frame, i.e. the size of a
+/// frame made only of literal sequences.
+pub fn max_encoded_size(width: u32, height: u32) -> usize {
+    let frame_size = nv12_frame_size(width, height);
+    FRAME_HEADER_SIZE + frame_size + (frame_size + MAX_LITERAL_LEN - 1) / MAX_LITERAL_LEN
+}
+
+fn check_dimensions(width: u32, height: u32) -> Result<(), BitstreamError> {
+    // NV12 subsamples chroma by 2 in both directions, so only even sizes are supported.
+    if width == 0
+        || height == 0
+        || width > MAX_DIMENSION
+        || height > MAX_DIMENSION
+        || width % 2 != 0
+        || height % 2 != 0
+    {
+        return Err(BitstreamError::InvalidDimensions(width, height));
+    }
+    Ok(())
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL_LEN) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Encodes `frame` as a keyframe.
+pub fn encode_frame(frame: &RawFrame) -> Result<Vec<u8>, BitstreamError> {
+    check_dimensions(frame.width, frame.height)?;
+    let expected_size = nv12_frame_size(frame.width, frame.height);
+    if frame.data.len() != expected_size {
+        return Err(BitstreamError::BadFrameSize(
+            frame.data.len(),
+            expected_size,
+        ));
+    }
+
+    let mut out = Vec::with_capacity(max_encoded_size(frame.width, frame.height));
+    out.extend_from_slice(&FRAME_MAGIC);
+    out.push(FRAME_VERSION);
+    out.push(FRAME_FLAG_KEYFRAME);
+    out.extend_from_slice(&[0u8; 2]);
+    out.extend_from_slice(&frame.width.to_le_bytes());
+    out.extend_from_slice(&frame.height.to_le_bytes());
+
+    let data = &frame.data;
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let run_len = data[pos..]
+            .iter()
+            .take(MAX_RUN_LEN)
+            .take_while(|&&b| b == data[pos])
+            .count();
+        // Runs of two bytes take as much space as literals, so only encode longer ones as runs.
+        if run_len >= 3 {
+            flush_literals(&mut out, &data[literal_start..pos]);
+            out.push((run_len + 126) as u8);
+            out.push(data[pos]);
+            pos += run_len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+
+    Ok(out)
+}
+
+/// Decodes the encoded frame contained in `bitstream`.
+pub fn decode_frame(bitstream: &[u8]) -> Result<RawFrame, BitstreamError> {
+    if bitstream.len() < FRAME_HEADER_SIZE
+        || bitstream[0..4] != FRAME_MAGIC
+        || bitstream[6..8] != [0u8; 2]
+    {
+        return Err(BitstreamError::InvalidHeader);
+    }
+    if bitstream[4] != FRAME_VERSION {
+        return Err(BitstreamError::UnsupportedVersion(bitstream[4]));
+    }
+    // The header size check above guarantees that these conversions succeed.
+    let width = u32::from_le_bytes(bitstream[8..12].try_into().unwrap());
+    let height = u32::from_le_bytes(bitstream[12..16].try_into().unwrap());
+    check_dimensions(width, height)?;
+
+    let expected_size = nv12_frame_size(width, height);
+    let mut data = Vec::with_capacity(expected_size);
+    let mut payload = bitstream[FRAME_HEADER_SIZE..].iter();
+    while let Some(&control) = payload.next() {
+        if control < 128 {
+            let len = control as usize + 1;
+            let literals = payload.as_slice();
+            if literals.len() < len {
+                return Err(BitstreamError::BadFrameSize(
+                    data.len() + literals.len(),
+                    expected_size,
+                ));
+            }
+            data.extend_from_slice(&literals[..len]);
+            payload = literals[len..].iter();
+        } else {
+            let value = *payload
+                .next()
+                .ok_or(BitstreamError::BadFrameSize(data.len(), expected_size))?;
+            data.resize(data.len() + control as usize - 126, value);
+        }
+        if data.len() > expected_size {
+            return Err(BitstreamError::BadFrameSize(data.len(), expected_size));
+        }
+    }
+    if data.len() != expected_size {
+        return Err(BitstreamError::BadFrameSize(data.len(), expected_size));
+    }
+
+    Ok(RawFrame {
+        width,
+        height,
+        data,
+    })
+}
+
+/// Linear mapping of the NV12 planes of a frame resource.
+struct Nv12Mapping {
+    mapping: MemoryMappingArena,
+    /// (offset within `mapping`, stride) of the luma and chroma planes.
+    planes: [(usize, usize); 2],
+}
+
+impl Nv12Mapping {
+    fn new(resource: &GuestResource) -> anyhow::Result<Self> {
+        if resource.format != Format::NV12 || resource.planes.len() != 2 {
+            return Err(anyhow!(
+                "unsupported frame format {} with {} planes",
+                resource.format,
+                resource.planes.len()
+            ));
+        }
+        let width = resource.width as usize;
+        let rows = [resource.height as usize, resource.height as usize / 2];
+        if resource.planes.iter().any(|plane| plane.stride < width) {
+            return Err(anyhow!(
+                "frame planes are too small for {}x{}",
+                width,
+                rows[0]
+            ));
+        }
+
+        // Map the smallest range covering both planes.
+        let start = resource.planes.iter().map(|p| p.offset).min().unwrap();
+        let end = resource
+            .planes
+            .iter()
+            .zip(rows)
+            .map(|(p, rows)| p.offset + p.stride * (rows - 1) + width)
+            .max()
+            .unwrap();
+        let mapping = resource
+            .handle
+            .get_mapping(start, end - start)
+            .context("while mapping frame")?;
+
+        Ok(Self {
+            mapping,
+            planes: [
+                (resource.planes[0].offset - start, resource.planes[0].stride),
+                (resource.planes[1].offset - start, resource.planes[1].stride),
+            ],
+        })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the mapping is linear and we own it, so it will not be unmapped during
+        // the lifetime of this slice.
+        unsafe { std::slice::from_raw_parts_mut(self.mapping.as_ptr(), self.mapping.size()) }
+    }
+
+    /// Returns the (offset in the mapping, offset in a packed frame) of the start of each row of
+    /// a `width`x`height` frame.
+    fn rows(&self, width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+        let [(luma_offset, luma_stride), (chroma_offset, chroma_stride)] = self.planes;
+        let luma_rows = (0..height).map(move |row| (luma_offset + row * luma_stride, row * width));
+        let chroma_rows = (0..height / 2)
+            .map(move |row| (chroma_offset + row * chroma_stride, (height + row) * width));
+        luma_rows.chain(chroma_rows)
+    }
+}
+
+/// Reads the NV12 frame stored in `resource`.
+pub fn read_nv12_frame(resource: &GuestResource) -> anyhow::Result<RawFrame> {
+    let (width, height) = (resource.width, resource.height);
+    check_dimensions(width, height)?;
+    let mut mapping = Nv12Mapping::new(resource)?;
+    let mut data = vec![0u8; nv12_frame_size(width, height)];
+    let rows = mapping
+        .rows(width as usize, height as usize)
+        .collect::<Vec<_>>();
+    let src = mapping.as_mut_slice();
+    for (src_offset, dst_offset) in rows {
+        data[dst_offset..dst_offset + width as usize]
+            .copy_from_slice(&src[src_offset..src_offset + width as usize]);
+    }
+
+    Ok(RawFrame {
+        width,
+        height,
+        data,
+    })
+}
+
+/// Writes `frame` into the NV12 frame `resource`, which must have the same dimensions.
+pub fn write_nv12_frame(resource: &GuestResource, frame: &RawFrame) -> anyhow::Result<()> {
+    if (resource.width, resource.height) != (frame.width, frame.height) {
+        return Err(anyhow!(
+            "cannot write {}x{} frame into {}x{} buffer",
+            frame.width,
+            frame.height,
+            resource.width,
+            resource.height
+        ));
+    }
+    let width = frame.width as usize;
+    let mut mapping = Nv12Mapping::new(resource)?;
+    let rows = mapping
+        .rows(width, frame.height as usize)
+        .collect::<Vec<_>>();
+    let dst = mapping.as_mut_slice();
+    for (dst_offset, src_offset) in rows {
+        dst[dst_offset..dst_offset + width]
+            .copy_from_slice(&frame.data[src_offset..src_offset + width]);
+    }
+
+    Ok(())
+}
+
+/// Frame sizes supported by the swcodec backends. Only even sizes are supported since NV12
+/// subsamples chroma by 2.
+pub fn swcodec_frame_formats() -> Vec<FrameFormat> {
+    vec![FrameFormat {
+        width: FormatRange {
+            min: 2,
+            max: MAX_DIMENSION,
+            step: 2,
+        },
+        height: FormatRange {
+            min: 2,
+            max: MAX_DIMENSION,
+            step: 2,
+        },
+        bitrates: Default::default(),
+    }]
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Returns a deterministic test frame, different for each `index`, with both flat areas and
+    /// gradients so that runs and literals are exercised.
+    pub fn test_frame(width: u32, height: u32, index: usize) -> RawFrame {
+        let data = (0..nv12_frame_size(width, height))
+            .map(|i| {
+                let row = i / width as usize;
+                if row % 4 < 2 {
+                    (row + index) as u8
+                } else {
+                    (i * 7 + index) as u8
+                }
+            })
+            .collect();
+        RawFrame {
+            width,
+            height,
+            data,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for index in 0..4 {
+            let frame = test_frame(64, 48, index);
+            let encoded = encode_frame(&frame).unwrap();
+            assert!(encoded.len() <= max_encoded_size(64, 48));
+            assert_eq!(decode_frame(&encoded).unwrap(), frame);
+        }
+
+        // A flat frame only needs runs.
+        let frame = RawFrame {
+            width: 16,
+            height: 16,
+            data: vec![0x80; nv12_frame_size(16, 16)],
+        };
+        let encoded = encode_frame(&frame).unwrap();
+        assert!(encoded.len() < FRAME_HEADER_SIZE + 8);
+        assert_eq!(decode_frame(&encoded).unwrap(), frame);
+    }
+
+    #[test]
+    fn encoding_is_deterministic() {
+        let frame = test_frame(32, 32, 1);
+        assert_eq!(encode_frame(&frame).unwrap(), encode_frame(&frame).unwrap());
+    }
+
+    #[test]
+    fn invalid_bitstream() {
+        let mut encoded = encode_frame(&test_frame(16, 16, 0)).unwrap();
+        assert!(matches!(
+            decode_frame(&encoded[..encoded.len() - 1]),
+            Err(BitstreamError::BadFrameSize(_, _))
+        ));
+
+        encoded[4] = 2;
+        assert!(matches!(
+            decode_frame(&encoded),
+            Err(BitstreamError::UnsupportedVersion(2))
+        ));
+
+        encoded[0] = b'X';
+        assert!(matches!(
+            decode_frame(&encoded),
+            Err(BitstreamError::InvalidHeader)
+        ));
+
+        assert!(matches!(
+            encode_frame(&RawFrame {
+                width: 15,
+                height: 16,
+                data: vec![],
+            }),
+            Err(BitstreamError::InvalidDimensions(15, 16))
+        ));
+    }
+}
@@ -4,10 +4,13 @@
 
 //! This module implements the virtio vsock device.
 //!
-//! Currently, this is only implemented for Windows.
-//! For Linux, please use the vhost-vsock device, which delegates the vsock
-//! implementation to the kernel.
+//! On Windows, guest connections are bridged to named pipes. On Linux, `Vsock` is the vhost-vsock
+//! device, which delegates the vsock implementation to the kernel, while `UnixVsock` bridges guest
+//! connections to unix sockets for hosts where vhost-vsock is not available.
 
+pub mod protocol;
 mod sys;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::UnixVsock;
 pub use sys::Vsock;
 pub use sys::VsockConfig;
@@ -11,6 +11,13 @@ use zerocopy::FromZeroes;
 
 pub const TYPE_STREAM_SOCKET: u16 = 1;
 
+/// The CID of the host, to which the guest addresses its connections.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+/// Flags of a VIRTIO_VSOCK_OP_SHUTDOWN packet.
+pub const VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_F_SEND: u32 = 2;
+
 /// virtio_vsock_config is the vsock device configuration space defined by the virtio spec.
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes)]
 #[repr(C)]
@@ -7,6 +7,7 @@ cfg_if::cfg_if! {
         mod linux;
         use linux as platform;
         pub use crate::virtio::vhost::Vsock;
+        pub use linux::UnixVsock;
     } else if #[cfg(windows)] {
         mod windows;
         use windows as platform;
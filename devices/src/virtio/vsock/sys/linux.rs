@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod vsock;
+
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -9,6 +11,8 @@ use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 
+pub use self::vsock::UnixVsock;
+
 static VHOST_VSOCK_DEFAULT_PATH: &str = "/dev/vhost-vsock";
 
 fn default_vsock_path() -> PathBuf {
@@ -25,6 +29,10 @@ pub struct VsockConfig {
     /// Path to the vhost-vsock device.
     #[serde(default = "default_vsock_path", rename = "device")]
     pub vhost_device: PathBuf,
+    /// Unix socket bridging the guest to host programs. When set, a userspace vsock device is
+    /// used instead of the vhost-vsock one.
+    #[serde(default, rename = "uds-path")]
+    pub uds_path: Option<PathBuf>,
 }
 
 impl VsockConfig {
@@ -37,6 +45,7 @@ impl VsockConfig {
             vhost_device: vhost_device
                 .map(|p| PathBuf::from(p.as_ref()))
                 .unwrap_or_else(|| PathBuf::from(VHOST_VSOCK_DEFAULT_PATH)),
+            uds_path: None,
         }
     }
 }
@@ -61,6 +70,7 @@ mod tests {
             VsockConfig {
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -80,6 +90,7 @@ mod tests {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 78,
+                uds_path: None,
             }
         );
 
@@ -97,7 +108,7 @@ mod tests {
             from_vsock_arg("invalid=foo").unwrap_err(),
             ParseError {
                 kind: ErrorKind::SerdeError(
-                    "unknown field `invalid`, expected `cid` or `device`".into()
+                    "unknown field `invalid`, expected one of `cid`, `device`, `uds-path`".into()
                 ),
                 pos: 0,
             }
@@ -109,6 +120,7 @@ mod tests {
             VsockConfig {
                 vhost_device: "/some/path".into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -118,6 +130,7 @@ mod tests {
             VsockConfig {
                 vhost_device: "/some/path".into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -130,6 +143,16 @@ mod tests {
             }
         );
 
+        // Userspace device
+        assert_eq!(
+            from_vsock_arg("cid=56,uds-path=/run/vm/vsock").unwrap(),
+            VsockConfig {
+                vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
+                cid: 56,
+                uds_path: Some("/run/vm/vsock".into()),
+            }
+        );
+
         // Device passed twice
         assert_eq!(
             from_vsock_arg("cid=56,device=42,device=/some/path").unwrap_err(),
@@ -0,0 +1,1015 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Userspace virtio-vsock device bridging guest stream sockets to host unix sockets.
+//!
+//! Connections from the guest to port `P` of the host are forwarded to the unix socket listening
+//! at `<uds_path>_<P>`. Host programs reach port `P` of the guest by connecting to the unix socket
+//! bound by the device at `<uds_path>` and writing `CONNECT <P>\n`. Once the guest accepts the
+//! connection, the device answers `OK <host port>\n` and data flows in both directions.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+use base::WorkerThread;
+use vm_memory::GuestMemory;
+use zerocopy::AsBytes;
+
+use crate::virtio::copy_config;
+use crate::virtio::device_constants::vsock::NUM_QUEUES;
+use crate::virtio::vsock::protocol::virtio_vsock_config;
+use crate::virtio::vsock::protocol::virtio_vsock_hdr;
+use crate::virtio::vsock::protocol::vsock_op;
+use crate::virtio::vsock::protocol::TYPE_STREAM_SOCKET;
+use crate::virtio::vsock::protocol::VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE;
+use crate::virtio::vsock::protocol::VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+use crate::virtio::vsock::protocol::VMADDR_CID_HOST;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::VirtioDevice;
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+
+const HDR_SIZE: usize = size_of::<virtio_vsock_hdr>();
+
+// Guest data buffered for each connection while its unix socket is not writable.
+const BUF_ALLOC: u32 = 256 * 1024;
+
+// Once this much buffer space has been freed, the guest is told about it right away instead of
+// waiting for the next packet to the guest to carry the update.
+const CREDIT_UPDATE_THRESHOLD: u32 = BUF_ALLOC / 4;
+
+// Ports used on the host side of connections initiated by the host are allocated from here.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+// Longest `CONNECT <port>\n` line accepted from host programs.
+const MAX_CONNECT_LINE: usize = 32;
+
+/// Returns the unix socket that connections from the guest to `port` are forwarded to.
+fn port_socket_path(uds_path: &Path, port: u32) -> PathBuf {
+    let mut path = OsString::from(uds_path);
+    path.push(format!("_{}", port));
+    PathBuf::from(path)
+}
+
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    std::str::from_utf8(line)
+        .ok()?
+        .trim_end()
+        .strip_prefix("CONNECT ")?
+        .parse()
+        .ok()
+}
+
+enum ConnectionState {
+    // A host program connected to the listening socket and has not sent its `CONNECT` line yet.
+    Handshake(Vec<u8>),
+    // The guest was asked to accept a connection from the host and has not answered yet.
+    Connecting,
+    Connected,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnectionState,
+    host_port: u32,
+    guest_port: u32,
+    // Whether `stream` is in the wait context. Streams are removed once their peer hangs up, since
+    // the hang up would be reported by every wait otherwise.
+    watched: bool,
+    events: EventType,
+    // Guest data not written to `stream` yet.
+    tx_buf: VecDeque<u8>,
+    // Guest data written to `stream`, and the amount last reported to the guest.
+    fwd_cnt: Wrapping<u32>,
+    reported_fwd_cnt: Wrapping<u32>,
+    // Data sent to the guest, and the guest's view of its receive buffer.
+    rx_cnt: Wrapping<u32>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    credit_requested: bool,
+    // `stream` may have data, or may have reached its end, that has not been sent to the guest.
+    readable: bool,
+    // Nothing more will be read from `stream`, because it ended or the guest stopped receiving.
+    host_done: bool,
+    // The guest will not send anything more.
+    guest_done: bool,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnectionState, host_port: u32, guest_port: u32) -> Self {
+        Connection {
+            stream,
+            state,
+            host_port,
+            guest_port,
+            watched: false,
+            events: EventType::None,
+            tx_buf: VecDeque::new(),
+            fwd_cnt: Wrapping(0),
+            reported_fwd_cnt: Wrapping(0),
+            rx_cnt: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            credit_requested: false,
+            readable: false,
+            host_done: false,
+            guest_done: false,
+        }
+    }
+
+    fn wanted_events(&self) -> EventType {
+        let read = match self.state {
+            ConnectionState::Handshake(_) => true,
+            ConnectionState::Connecting => false,
+            ConnectionState::Connected => !self.readable && !self.host_done,
+        };
+        match (read, !self.tx_buf.is_empty()) {
+            (true, true) => EventType::ReadWrite,
+            (true, false) => EventType::Read,
+            (false, true) => EventType::Write,
+            (false, false) => EventType::None,
+        }
+    }
+
+    // Bytes that can be sent to the guest without overflowing its receive buffer.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub((self.rx_cnt - self.peer_fwd_cnt).0)
+    }
+
+    fn header(&mut self, guest_cid: u64, op: u16, len: u32, flags: u32) -> virtio_vsock_hdr {
+        self.reported_fwd_cnt = self.fwd_cnt;
+        virtio_vsock_hdr {
+            src_cid: VMADDR_CID_HOST.into(),
+            dst_cid: guest_cid.into(),
+            src_port: self.host_port.into(),
+            dst_port: self.guest_port.into(),
+            len: len.into(),
+            r#type: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            flags: flags.into(),
+            buf_alloc: BUF_ALLOC.into(),
+            fwd_cnt: self.fwd_cnt.0.into(),
+        }
+    }
+
+    // Writes as much buffered guest data as `stream` accepts.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            let (data, _) = self.tx_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.tx_buf.drain(..n);
+                    self.fwd_cnt += Wrapping(n as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_done {
+            self.stream.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+}
+
+fn reset_header(guest_cid: u64, host_port: u32, guest_port: u32) -> virtio_vsock_hdr {
+    virtio_vsock_hdr {
+        src_cid: VMADDR_CID_HOST.into(),
+        dst_cid: guest_cid.into(),
+        src_port: host_port.into(),
+        dst_port: guest_port.into(),
+        r#type: TYPE_STREAM_SOCKET.into(),
+        op: vsock_op::VIRTIO_VSOCK_OP_RST.into(),
+        ..Default::default()
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    RxQueue,
+    TxQueue,
+    Listener,
+    Connection { id: u32 },
+    InterruptResample,
+    Kill,
+}
+
+struct Worker {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    // The queues are signaled separately as vhost-user gives each its own interrupt.
+    rx_interrupt: Interrupt,
+    tx_interrupt: Interrupt,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    event_queue: Queue,
+    wait_ctx: WaitContext<Token>,
+    connections: BTreeMap<u32, Connection>,
+    // Maps the (host port, guest port) pair of established connections to their id.
+    ports: HashMap<(u32, u32), u32>,
+    next_id: u32,
+    next_host_port: u32,
+    // Packets without payload waiting for a buffer in the rx queue.
+    control_packets: VecDeque<virtio_vsock_hdr>,
+    rx_used: bool,
+    tx_used: bool,
+}
+
+impl Worker {
+    fn add_connection(&mut self, mut connection: Connection) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if !matches!(connection.state, ConnectionState::Handshake(_)) {
+            self.ports
+                .insert((connection.host_port, connection.guest_port), id);
+        }
+        let events = connection.wanted_events();
+        match self
+            .wait_ctx
+            .add_for_event(&connection.stream, events, Token::Connection { id })
+        {
+            Ok(()) => {
+                connection.watched = true;
+                connection.events = events;
+            }
+            Err(e) => error!("vsock: failed to watch connection: {}", e),
+        }
+        self.connections.insert(id, connection);
+    }
+
+    // Drops a connection, telling the guest about it unless it is the one that reset it.
+    fn remove_connection(&mut self, id: u32, notify_guest: bool) {
+        let connection = match self.connections.remove(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if connection.watched {
+            if let Err(e) = self.wait_ctx.delete(&connection.stream) {
+                error!("vsock: failed to stop watching connection: {}", e);
+            }
+        }
+        if matches!(connection.state, ConnectionState::Handshake(_)) {
+            return;
+        }
+        self.ports
+            .remove(&(connection.host_port, connection.guest_port));
+        if notify_guest {
+            self.control_packets.push_back(reset_header(
+                self.guest_cid,
+                connection.host_port,
+                connection.guest_port,
+            ));
+        }
+    }
+
+    fn update_events(&mut self, id: u32) {
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let events = connection.wanted_events();
+        if !connection.watched || events == connection.events {
+            return;
+        }
+        match self
+            .wait_ctx
+            .modify(&connection.stream, events, Token::Connection { id })
+        {
+            Ok(()) => connection.events = events,
+            Err(e) => error!("vsock: failed to update connection events: {}", e),
+        }
+    }
+
+    fn allocate_host_port(&mut self, guest_port: u32) -> u32 {
+        loop {
+            let port = self.next_host_port;
+            self.next_host_port = self
+                .next_host_port
+                .checked_add(1)
+                .unwrap_or(FIRST_HOST_PORT);
+            if !self.ports.contains_key(&(port, guest_port)) {
+                return port;
+            }
+        }
+    }
+
+    fn accept_connection(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("vsock: failed to accept connection: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("vsock: failed to set connection non-blocking: {}", e);
+            return;
+        }
+        self.add_connection(Connection::new(
+            stream,
+            ConnectionState::Handshake(Vec::new()),
+            0,
+            0,
+        ));
+    }
+
+    // Reads the `CONNECT <port>` line of a host program, one byte at a time so that data sent
+    // right after it stays in the socket until the guest accepts the connection.
+    fn read_connect_line(&mut self, id: u32) {
+        let guest_port = {
+            let connection = match self.connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return,
+            };
+            let line = match &mut connection.state {
+                ConnectionState::Handshake(line) => line,
+                _ => return,
+            };
+            let mut byte = [0u8];
+            loop {
+                match connection.stream.read(&mut byte) {
+                    Ok(0) => break None,
+                    Ok(_) if byte[0] == b'\n' => break parse_connect_line(line),
+                    Ok(_) if line.len() < MAX_CONNECT_LINE => line.push(byte[0]),
+                    Ok(_) => break None,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break None,
+                }
+            }
+        };
+
+        let guest_port = match guest_port {
+            Some(port) => port,
+            None => {
+                warn!("vsock: dropping host connection without a valid CONNECT request");
+                self.remove_connection(id, false);
+                return;
+            }
+        };
+        let host_port = self.allocate_host_port(guest_port);
+        let guest_cid = self.guest_cid;
+        let connection = self.connections.get_mut(&id).unwrap();
+        connection.state = ConnectionState::Connecting;
+        connection.host_port = host_port;
+        connection.guest_port = guest_port;
+        let request = connection.header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0, 0);
+        self.ports.insert((host_port, guest_port), id);
+        self.control_packets.push_back(request);
+    }
+
+    fn handle_connection_event(&mut self, id: u32, readable: bool, writable: bool, hungup: bool) {
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        match connection.state {
+            ConnectionState::Handshake(_) => {
+                self.read_connect_line(id);
+            }
+            ConnectionState::Connecting => {
+                if hungup {
+                    self.remove_connection(id, true);
+                }
+            }
+            ConnectionState::Connected => {
+                if writable {
+                    if let Err(e) = connection.flush() {
+                        warn!("vsock: failed to write to host connection: {}", e);
+                        self.remove_connection(id, true);
+                        return;
+                    }
+                    self.maybe_update_credit(id);
+                }
+                let connection = self.connections.get_mut(&id).unwrap();
+                if readable || hungup {
+                    connection.readable = true;
+                }
+                if hungup && connection.watched {
+                    if let Err(e) = self.wait_ctx.delete(&connection.stream) {
+                        error!("vsock: failed to stop watching connection: {}", e);
+                    }
+                    connection.watched = false;
+                }
+            }
+        }
+        self.update_events(id);
+    }
+
+    // Tells the guest about freed buffer space if it is running low.
+    fn maybe_update_credit(&mut self, id: u32) {
+        let guest_cid = self.guest_cid;
+        if let Some(connection) = self.connections.get_mut(&id) {
+            if (connection.fwd_cnt - connection.reported_fwd_cnt).0 >= CREDIT_UPDATE_THRESHOLD {
+                let update =
+                    connection.header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, 0);
+                self.control_packets.push_back(update);
+            }
+        }
+    }
+
+    // Handles a guest request to connect to the unix socket of a host port.
+    fn connect_to_host(&mut self, host_port: u32, guest_port: u32, header: &virtio_vsock_hdr) {
+        let path = port_socket_path(&self.uds_path, host_port);
+        let stream = match UnixStream::connect(&path).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(
+                    "vsock: failed to connect guest port {} to {}: {}",
+                    guest_port,
+                    path.display(),
+                    e
+                );
+                self.control_packets
+                    .push_back(reset_header(self.guest_cid, host_port, guest_port));
+                return;
+            }
+        };
+
+        let mut connection =
+            Connection::new(stream, ConnectionState::Connected, host_port, guest_port);
+        connection.peer_buf_alloc = header.buf_alloc.to_native();
+        connection.peer_fwd_cnt = Wrapping(header.fwd_cnt.to_native());
+        let response = connection.header(self.guest_cid, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, 0, 0);
+        self.add_connection(connection);
+        self.control_packets.push_back(response);
+    }
+
+    fn handle_connection_packet(&mut self, id: u32, header: &virtio_vsock_hdr, data: &[u8]) {
+        let guest_cid = self.guest_cid;
+        let connection = self.connections.get_mut(&id).unwrap();
+        connection.peer_buf_alloc = header.buf_alloc.to_native();
+        connection.peer_fwd_cnt = Wrapping(header.fwd_cnt.to_native());
+        connection.credit_requested = false;
+        let connected = matches!(connection.state, ConnectionState::Connected);
+
+        match (header.op.to_native(), connected) {
+            (vsock_op::VIRTIO_VSOCK_OP_RESPONSE, false) => {
+                connection.state = ConnectionState::Connected;
+                let reply = format!("OK {}\n", connection.host_port);
+                // The reply is the first thing written to the socket, so it fits in its buffer.
+                connection.stream.set_nonblocking(false).ok();
+                let result = connection.stream.write_all(reply.as_bytes());
+                connection.stream.set_nonblocking(true).ok();
+                if let Err(e) = result {
+                    warn!("vsock: failed to accept host connection: {}", e);
+                    self.remove_connection(id, true);
+                    return;
+                }
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_RW, true) => {
+                if connection.guest_done
+                    || connection.tx_buf.len() + data.len() > BUF_ALLOC as usize
+                {
+                    error!(
+                        "vsock: guest port {} sent data it has no credit for",
+                        connection.guest_port
+                    );
+                    self.remove_connection(id, true);
+                    return;
+                }
+                connection.tx_buf.extend(data);
+                if let Err(e) = connection.flush() {
+                    warn!("vsock: failed to write to host connection: {}", e);
+                    self.remove_connection(id, true);
+                    return;
+                }
+                self.maybe_update_credit(id);
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, true) => {}
+            (vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST, true) => {
+                let update =
+                    connection.header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, 0);
+                self.control_packets.push_back(update);
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN, true) => {
+                let flags = header.flags.to_native();
+                if flags & VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE != 0 {
+                    connection.host_done = true;
+                    connection.readable = false;
+                }
+                if flags & VIRTIO_VSOCK_SHUTDOWN_F_SEND != 0 {
+                    connection.guest_done = true;
+                    if let Err(e) = connection.flush() {
+                        warn!("vsock: failed to write to host connection: {}", e);
+                        self.remove_connection(id, true);
+                        return;
+                    }
+                }
+                // Like other vsock transports, reply to a full shutdown with a reset.
+                if connection.host_done && connection.guest_done && connection.tx_buf.is_empty() {
+                    self.remove_connection(id, true);
+                    return;
+                }
+            }
+            (op, _) => {
+                warn!(
+                    "vsock: unexpected operation {} on guest port {}",
+                    op, connection.guest_port
+                );
+                self.remove_connection(id, true);
+                return;
+            }
+        }
+        self.update_events(id);
+    }
+
+    fn handle_tx_packet(&mut self, header: &virtio_vsock_hdr, data: &[u8]) {
+        let op = header.op.to_native();
+        let host_port = header.dst_port.to_native();
+        let guest_port = header.src_port.to_native();
+
+        if header.src_cid.to_native() != self.guest_cid {
+            warn!(
+                "vsock: dropping packet from cid {}",
+                header.src_cid.to_native()
+            );
+            return;
+        }
+        if header.dst_cid.to_native() != VMADDR_CID_HOST
+            || header.r#type.to_native() != TYPE_STREAM_SOCKET
+        {
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                self.control_packets
+                    .push_back(reset_header(self.guest_cid, host_port, guest_port));
+            }
+            return;
+        }
+
+        match (op, self.ports.get(&(host_port, guest_port)).copied()) {
+            (vsock_op::VIRTIO_VSOCK_OP_REQUEST, None) => {
+                self.connect_to_host(host_port, guest_port, header)
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_RST, Some(id)) => self.remove_connection(id, false),
+            (vsock_op::VIRTIO_VSOCK_OP_RST, None) => {}
+            (_, Some(id)) => self.handle_connection_packet(id, header, data),
+            (_, None) => {
+                self.control_packets
+                    .push_back(reset_header(self.guest_cid, host_port, guest_port))
+            }
+        }
+    }
+
+    fn process_tx_queue(&mut self) {
+        while let Some(mut desc) = self.tx_queue.pop() {
+            match desc.reader.read_obj::<virtio_vsock_hdr>() {
+                Ok(header) => {
+                    let len = (header.len.to_native() as usize).min(desc.reader.available_bytes());
+                    let mut data = vec![0u8; len];
+                    match desc.reader.read_exact(&mut data) {
+                        Ok(()) => self.handle_tx_packet(&header, &data),
+                        Err(e) => error!("vsock: failed to read packet data: {}", e),
+                    }
+                }
+                Err(e) => error!("vsock: failed to read packet header: {}", e),
+            }
+            self.tx_queue.add_used(desc, 0);
+            self.tx_used = true;
+        }
+    }
+
+    // Sends the queued control packets, returning false if the rx queue ran out of buffers.
+    fn send_control_packets(&mut self) -> bool {
+        while let Some(header) = self.control_packets.front() {
+            let mut desc = match self.rx_queue.pop() {
+                Some(desc) => desc,
+                None => return false,
+            };
+            if let Err(e) = desc.writer.write_all(header.as_bytes()) {
+                error!("vsock: failed to write packet to the guest: {}", e);
+            }
+            let len = desc.writer.bytes_written() as u32;
+            self.rx_queue.add_used(desc, len);
+            self.rx_used = true;
+            self.control_packets.pop_front();
+        }
+        true
+    }
+
+    // Forwards data from the unix socket of a connection to the guest, returning false if the rx
+    // queue ran out of buffers.
+    fn forward_to_guest(&mut self, id: u32) -> bool {
+        let guest_cid = self.guest_cid;
+        loop {
+            let connection = match self.connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => return true,
+            };
+            if connection.host_done {
+                connection.readable = false;
+                return true;
+            }
+            let credit = connection.peer_credit();
+            if credit == 0 {
+                // Keep the data in the socket until the guest makes room for it.
+                if !connection.credit_requested {
+                    connection.credit_requested = true;
+                    let request = connection.header(
+                        guest_cid,
+                        vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST,
+                        0,
+                        0,
+                    );
+                    self.control_packets.push_back(request);
+                }
+                return true;
+            }
+
+            let desc = match self.rx_queue.peek() {
+                Some(desc) => desc,
+                None => return false,
+            };
+            let room = desc.writer.available_bytes().saturating_sub(HDR_SIZE);
+            let mut data = vec![0u8; room.min(credit as usize)];
+            let result = if data.is_empty() {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "rx buffer too small",
+                ))
+            } else {
+                connection.stream.read(&mut data)
+            };
+            match result {
+                Ok(0) => {
+                    // The host will not send anything more.
+                    connection.host_done = true;
+                    connection.readable = false;
+                    let shutdown = connection.header(
+                        guest_cid,
+                        vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN,
+                        0,
+                        VIRTIO_VSOCK_SHUTDOWN_F_SEND,
+                    );
+                    self.control_packets.push_back(shutdown);
+                    return true;
+                }
+                Ok(n) => {
+                    let mut desc = desc.pop();
+                    let header =
+                        connection.header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_RW, n as u32, 0);
+                    connection.rx_cnt += Wrapping(n as u32);
+                    if let Err(e) = desc
+                        .writer
+                        .write_all(header.as_bytes())
+                        .and_then(|()| desc.writer.write_all(&data[..n]))
+                    {
+                        error!("vsock: failed to write packet to the guest: {}", e);
+                    }
+                    let len = desc.writer.bytes_written() as u32;
+                    self.rx_queue.add_used(desc, len);
+                    self.rx_used = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    connection.readable = false;
+                    return true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("vsock: failed to read from host connection: {}", e);
+                    self.remove_connection(id, true);
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn process_rx_queue(&mut self) {
+        if !self.send_control_packets() {
+            return;
+        }
+        let readable: Vec<u32> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.readable)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in readable {
+            let has_buffers = self.forward_to_guest(id);
+            self.update_events(id);
+            if !has_buffers {
+                break;
+            }
+        }
+        self.send_control_packets();
+    }
+
+    fn run(mut self, kill_evt: Event) -> anyhow::Result<Vec<Queue>> {
+        self.wait_ctx
+            .add_many(&[
+                (self.rx_queue.event(), Token::RxQueue),
+                (self.tx_queue.event(), Token::TxQueue),
+                (&self.listener, Token::Listener),
+                (&kill_evt, Token::Kill),
+            ])
+            .context("failed creating WaitContext")?;
+        if let Some(resample_evt) = self.rx_interrupt.get_resample_evt() {
+            self.wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .context("failed adding resample event to WaitContext")?;
+        }
+
+        'wait: loop {
+            let events = match self.wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            for event in events.iter() {
+                match event.token {
+                    Token::RxQueue => {
+                        if let Err(e) = self.rx_queue.event().wait() {
+                            error!("failed reading rx queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::TxQueue => {
+                        if let Err(e) = self.tx_queue.event().wait() {
+                            error!("failed reading tx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        self.process_tx_queue();
+                    }
+                    Token::Listener => self.accept_connection(),
+                    Token::Connection { id } => self.handle_connection_event(
+                        id,
+                        event.is_readable,
+                        event.is_writable,
+                        event.is_hungup,
+                    ),
+                    Token::InterruptResample => {
+                        self.rx_interrupt.interrupt_resample();
+                    }
+                    Token::Kill => break 'wait,
+                }
+            }
+
+            self.process_rx_queue();
+            if self.rx_used {
+                self.rx_queue.trigger_interrupt(&self.rx_interrupt);
+                self.rx_used = false;
+            }
+            if self.tx_used {
+                self.tx_queue.trigger_interrupt(&self.tx_interrupt);
+                self.tx_used = false;
+            }
+        }
+
+        Ok(vec![self.rx_queue, self.tx_queue, self.event_queue])
+    }
+}
+
+/// Virtio vsock device forwarding the stream connections of the guest to host unix sockets.
+pub struct UnixVsock {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    avail_features: u64,
+    acked_features: u64,
+    worker_thread: Option<WorkerThread<anyhow::Result<Vec<Queue>>>>,
+}
+
+impl UnixVsock {
+    /// Creates a device for the guest `guest_cid`, binding the socket host programs connect to
+    /// at `uds_path`.
+    pub fn new(guest_cid: u64, uds_path: &Path, base_features: u64) -> anyhow::Result<UnixVsock> {
+        let listener = UnixListener::bind(uds_path)
+            .with_context(|| format!("failed to bind vsock socket {}", uds_path.display()))?;
+        Ok(UnixVsock {
+            guest_cid,
+            uds_path: uds_path.to_owned(),
+            listener,
+            avail_features: base_features,
+            acked_features: 0,
+            worker_thread: None,
+        })
+    }
+
+    /// Starts forwarding connections over the given queues.
+    pub(crate) fn start_worker(
+        &mut self,
+        rx_queue: Queue,
+        tx_queue: Queue,
+        event_queue: Queue,
+        rx_interrupt: Interrupt,
+        tx_interrupt: Interrupt,
+    ) -> anyhow::Result<()> {
+        let worker = Worker {
+            guest_cid: self.guest_cid,
+            uds_path: self.uds_path.clone(),
+            listener: self
+                .listener
+                .try_clone()
+                .context("failed to clone vsock listener")?,
+            rx_interrupt,
+            tx_interrupt,
+            rx_queue,
+            tx_queue,
+            event_queue,
+            wait_ctx: WaitContext::new().context("failed creating WaitContext")?,
+            connections: BTreeMap::new(),
+            ports: HashMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+            control_packets: VecDeque::new(),
+            rx_used: false,
+            tx_used: false,
+        };
+        self.worker_thread = Some(WorkerThread::start("v_vsock", move |kill_evt| {
+            worker.run(kill_evt)
+        }));
+        Ok(())
+    }
+
+    /// Stops the worker, dropping all connections, and returns the rx, tx and event queues.
+    pub(crate) fn stop_worker(&mut self) -> anyhow::Result<Option<Vec<Queue>>> {
+        match self.worker_thread.take() {
+            Some(worker_thread) => worker_thread.stop().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl VirtioDevice for UnixVsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.listener.as_raw_descriptor()]
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.avail_features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_vsock_config {
+            guest_cid: self.guest_cid.into(),
+        };
+        copy_config(data, 0, config.as_bytes(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: BTreeMap<usize, Queue>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != NUM_QUEUES {
+            return Err(anyhow!(
+                "expected {} queues, got {}",
+                NUM_QUEUES,
+                queues.len()
+            ));
+        }
+
+        self.start_worker(
+            queues.remove(&RX_QUEUE).unwrap(),
+            queues.remove(&TX_QUEUE).unwrap(),
+            queues.remove(&EVENT_QUEUE).unwrap(),
+            interrupt.clone(),
+            interrupt,
+        )
+    }
+
+    fn reset(&mut self) -> bool {
+        match self.stop_worker() {
+            Ok(queues) => queues.is_some(),
+            Err(e) => {
+                error!("vsock worker failed: {:#}", e);
+                false
+            }
+        }
+    }
+
+    // Connections are not kept across sleep: they are dropped along with the worker.
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        Ok(self
+            .stop_worker()?
+            .map(|queues| BTreeMap::from_iter(queues.into_iter().enumerate())))
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&self) -> anyhow::Result<serde_json::Value> {
+        // `virtio_sleep` drops the connections, so only the `Queue`s, which are handled at a
+        // higher layer, hold state.
+        Ok(serde_json::Value::Null)
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            data == serde_json::Value::Null,
+            "unexpected snapshot data: should be null, got {}",
+            data,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_line() {
+        assert_eq!(parse_connect_line(b"CONNECT 1234"), Some(1234));
+        assert_eq!(parse_connect_line(b"CONNECT 52\r"), Some(52));
+        assert_eq!(parse_connect_line(b"CONNECT"), None);
+        assert_eq!(parse_connect_line(b"CONNECT -1"), None);
+        assert_eq!(parse_connect_line(b"LISTEN 1234"), None);
+    }
+
+    #[test]
+    fn port_socket() {
+        assert_eq!(
+            port_socket_path(Path::new("/run/vm/vsock"), 5000),
+            PathBuf::from("/run/vm/vsock_5000")
+        );
+    }
+
+    #[test]
+    fn credit() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new(stream, ConnectionState::Connected, 1, 2);
+        connection.peer_buf_alloc = 100;
+        assert_eq!(connection.peer_credit(), 100);
+        connection.rx_cnt = Wrapping(u32::MAX - 9);
+        connection.peer_fwd_cnt = Wrapping(u32::MAX - 49);
+        assert_eq!(connection.peer_credit(), 60);
+        connection.rx_cnt += Wrapping(60);
+        assert_eq!(connection.peer_credit(), 0);
+    }
+
+    #[test]
+    fn flush_shuts_down_after_guest_is_done() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut connection = Connection::new(stream, ConnectionState::Connected, 1, 2);
+        connection.tx_buf.extend(b"hello");
+        connection.guest_done = true;
+        connection.flush().unwrap();
+        assert_eq!(connection.fwd_cnt, Wrapping(5));
+        assert_eq!(connection.wanted_events(), EventType::Read);
+
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"hello");
+    }
+}
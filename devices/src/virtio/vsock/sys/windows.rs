@@ -2,10 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-pub mod protocol;
 pub mod vsock;
 
-pub(crate) use protocol::*;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -0,0 +1,67 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use anyhow::Context;
+use anyhow::Result;
+use resources::address_allocator::AddressAllocator;
+use resources::AddressRange;
+use resources::Alloc;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+/// Hands out zeroed ranges of guest memory for rings and buffers.
+pub(crate) struct GuestAllocator {
+    mem: GuestMemory,
+    allocator: AddressAllocator,
+    next_alloc: usize,
+}
+
+impl GuestAllocator {
+    /// Creates an allocator managing all of `mem`.
+    pub fn new(mem: &GuestMemory) -> Result<GuestAllocator> {
+        let pools = mem
+            .guest_memory_regions()
+            .into_iter()
+            .map(|(start, size)| {
+                AddressRange::from_start_and_size(start.offset(), size as u64)
+                    .context("guest memory region out of range")
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(GuestAllocator {
+            mem: mem.clone(),
+            allocator: AddressAllocator::new_from_list(pools, None, None)
+                .context("failed to create guest memory allocator")?,
+            next_alloc: 0,
+        })
+    }
+
+    /// Allocates `size` bytes aligned to `align` and fills them with zeroes.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Result<GuestAddress> {
+        // Zero-length buffers still need a distinct address to be released later.
+        let size = size.max(1);
+        self.next_alloc += 1;
+        let addr = self
+            .allocator
+            .allocate_with_align(
+                size,
+                Alloc::Anon(self.next_alloc),
+                "test".to_string(),
+                align,
+            )
+            .with_context(|| format!("failed to allocate {} bytes of guest memory", size))?;
+        let addr = GuestAddress(addr);
+        self.mem
+            .write_all_at_addr(&vec![0; size as usize], addr)
+            .context("failed to clear guest memory")?;
+        Ok(addr)
+    }
+
+    /// Releases the allocation starting at `addr`.
+    pub fn release(&mut self, addr: GuestAddress) -> Result<()> {
+        self.allocator
+            .release_containing(addr.offset())
+            .with_context(|| format!("no allocation at {}", addr))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base::EventWaitResult;
+use devices::virtio::Interrupt;
+use devices::virtio::VirtioDevice;
+use devices::IrqLevelEvent;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use crate::allocator::GuestAllocator;
+use crate::queue::DriverQueue;
+use crate::queue::UsedBuffer;
+
+/// Size of the guest memory created by `VirtioDriver::new`.
+pub const DEFAULT_MEMORY_SIZE: u64 = 16 << 20;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Drives a `VirtioDevice` the way a guest driver would, through a PCI-like transport using a
+/// level-triggered interrupt.
+///
+/// The usual sequence is `negotiate_features`, `activate`, then adding buffers to `queue(n)`,
+/// kicking it and collecting the results with `wait_used`.
+pub struct VirtioDriver {
+    device: Box<dyn VirtioDevice>,
+    mem: GuestMemory,
+    allocator: Arc<Mutex<GuestAllocator>>,
+    acked_features: u64,
+    interrupt: Option<Interrupt>,
+    queues: Vec<DriverQueue>,
+}
+
+impl VirtioDriver {
+    /// Creates a driver for `device` with `DEFAULT_MEMORY_SIZE` bytes of guest memory.
+    pub fn new(device: Box<dyn VirtioDevice>) -> Result<VirtioDriver> {
+        let mem = GuestMemory::new(&[(GuestAddress(0), DEFAULT_MEMORY_SIZE)])
+            .context("failed to create guest memory")?;
+        Self::with_memory(device, mem)
+    }
+
+    /// Creates a driver for `device` that places rings and buffers anywhere in `mem`.
+    pub fn with_memory(device: Box<dyn VirtioDevice>, mem: GuestMemory) -> Result<VirtioDriver> {
+        let allocator = Arc::new(Mutex::new(GuestAllocator::new(&mem)?));
+        Ok(VirtioDriver {
+            device,
+            mem,
+            allocator,
+            acked_features: 0,
+            interrupt: None,
+            queues: Vec::new(),
+        })
+    }
+
+    /// Returns the guest memory shared with the device.
+    pub fn memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Returns the device under test.
+    pub fn device(&self) -> &dyn VirtioDevice {
+        self.device.as_ref()
+    }
+
+    /// Returns the device under test.
+    pub fn device_mut(&mut self) -> &mut dyn VirtioDevice {
+        self.device.as_mut()
+    }
+
+    /// Accepts the features in `driver_features` that the device offers and returns them.
+    pub fn negotiate_features(&mut self, driver_features: u64) -> u64 {
+        self.acked_features = self.device.features() & driver_features;
+        self.device.ack_features(self.acked_features);
+        self.acked_features
+    }
+
+    /// Returns the features accepted by `negotiate_features`.
+    pub fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    /// Reads `data.len()` bytes of the device configuration space at `offset`.
+    pub fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.read_config(offset, data)
+    }
+
+    /// Writes `data` to the device configuration space at `offset`.
+    pub fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.device.write_config(offset, data)
+    }
+
+    /// Sets up all the queues of the device, with their maximum size, and activates the device.
+    pub fn activate(&mut self) -> Result<()> {
+        if self.interrupt.is_some() {
+            bail!("{} is already active", self.device.debug_label());
+        }
+        let interrupt = Interrupt::new(
+            IrqLevelEvent::new().context("failed to create interrupt event")?,
+            None,
+            VIRTIO_MSI_NO_VECTOR,
+        );
+        let device_features = self.device.features();
+        let mut queues = Vec::new();
+        let mut device_queues = BTreeMap::new();
+        for (index, &size) in self.device.queue_max_sizes().iter().enumerate() {
+            let queue =
+                DriverQueue::new(&self.mem, self.allocator.clone(), size, self.acked_features)
+                    .with_context(|| format!("failed to set up queue {}", index))?;
+            device_queues.insert(
+                index,
+                queue
+                    .activate(device_features)
+                    .with_context(|| format!("failed to activate queue {}", index))?,
+            );
+            queues.push(queue);
+        }
+        self.device
+            .activate(self.mem.clone(), interrupt.clone(), device_queues)
+            .with_context(|| format!("failed to activate {}", self.device.debug_label()))?;
+        self.interrupt = Some(interrupt);
+        self.queues = queues;
+        Ok(())
+    }
+
+    /// Resets the device, dropping the queues. Returns false if the device doesn't support reset.
+    pub fn reset(&mut self) -> bool {
+        self.interrupt = None;
+        self.queues.clear();
+        self.acked_features = 0;
+        self.device.reset()
+    }
+
+    /// Returns the number of queues set up by `activate`.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Returns the driver side of queue `index`.
+    ///
+    /// Panics if the device isn't active or has no such queue.
+    pub fn queue(&mut self, index: usize) -> &mut DriverQueue {
+        &mut self.queues[index]
+    }
+
+    /// Waits until the device raises its interrupt and returns the interrupt status register,
+    /// acknowledging it.
+    pub fn wait_interrupt(&mut self, timeout: Duration) -> Result<u8> {
+        let interrupt = self.interrupt.as_ref().context("device is not active")?;
+        match interrupt
+            .get_interrupt_evt()
+            .wait_timeout(timeout)
+            .context("failed to wait for interrupt")?
+        {
+            EventWaitResult::Signaled => Ok(interrupt.read_and_reset_interrupt_status()),
+            EventWaitResult::TimedOut => bail!("timed out waiting for an interrupt"),
+        }
+    }
+
+    /// Waits for the device to use a buffer of queue `index`, handling its interrupts.
+    pub fn wait_used(&mut self, index: usize, timeout: Duration) -> Result<UsedBuffer> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(used) = self.queue(index).pop_used()? {
+                return Ok(used);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            self.wait_interrupt(timeout)
+                .with_context(|| format!("no used buffer in queue {}", index))?;
+        }
+    }
+
+    /// Adds a buffer to queue `index`, kicks the device and waits for the buffer to be used.
+    pub fn transfer(
+        &mut self,
+        index: usize,
+        readable: &[&[u8]],
+        writable: &[u32],
+        timeout: Duration,
+    ) -> Result<UsedBuffer> {
+        let queue = self.queue(index);
+        let id = queue.add_buffer(readable, writable)?;
+        queue.kick()?;
+        let used = self.wait_used(index, timeout)?;
+        if used.id != id {
+            bail!("device used buffer {} instead of {}", used.id, id);
+        }
+        Ok(used)
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base::Error;
+use base::Event;
+use base::EventWaitResult;
+use base::Result;
+use devices::Bus;
+use devices::IrqChip;
+use devices::IrqChipCap;
+use devices::IrqEdgeEvent;
+use devices::IrqEventIndex;
+use devices::IrqEventSource;
+use devices::IrqLevelEvent;
+use devices::VcpuRunState;
+use hypervisor::IrqRoute;
+use hypervisor::MPState;
+use hypervisor::Vcpu;
+use libc::ENOENT;
+use resources::SystemAllocator;
+use sync::Mutex;
+
+struct IrqEvent {
+    irq: u32,
+    event: Event,
+    resample_event: Option<Event>,
+    source: IrqEventSource,
+}
+
+#[derive(Default)]
+struct FakeIrqChipState {
+    irq_events: Vec<Option<IrqEvent>>,
+    routes: Vec<IrqRoute>,
+    /// Level of the interrupt lines that have been serviced.
+    levels: BTreeMap<u32, bool>,
+    /// Interrupt lines asserted since the last `take_asserted`, in order.
+    asserted: Vec<u32>,
+    mp_states: BTreeMap<usize, MPState>,
+}
+
+impl FakeIrqChipState {
+    fn assert_irq(&mut self, irq: u32, level_triggered: bool) {
+        self.asserted.push(irq);
+        self.levels.insert(irq, level_triggered);
+    }
+}
+
+/// An `IrqChip` that doesn't deliver interrupts to vCPUs, but records which interrupt lines the
+/// devices assert.
+///
+/// Clones made with `try_clone` share the same state.
+#[derive(Default)]
+pub struct FakeIrqChip {
+    state: Arc<Mutex<FakeIrqChipState>>,
+}
+
+impl FakeIrqChip {
+    pub fn new() -> FakeIrqChip {
+        Default::default()
+    }
+
+    /// Returns the interrupt lines asserted since the last call, in order, and forgets them.
+    pub fn take_asserted(&self) -> Vec<u32> {
+        std::mem::take(&mut self.state.lock().asserted)
+    }
+
+    /// Returns true if the level-triggered interrupt `irq` is asserted.
+    pub fn irq_level(&self, irq: u32) -> bool {
+        self.state.lock().levels.get(&irq).copied().unwrap_or(false)
+    }
+
+    /// Returns the current GSI routes.
+    pub fn routes(&self) -> Vec<IrqRoute> {
+        self.state.lock().routes.clone()
+    }
+
+    /// Services every registered irq event that is signaled, without blocking.
+    pub fn poll_irq_events(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        let mut signaled = Vec::new();
+        for evt in state.irq_events.iter().flatten() {
+            if evt.event.wait_timeout(Duration::ZERO)? == EventWaitResult::Signaled {
+                signaled.push((evt.irq, evt.resample_event.is_some()));
+            }
+        }
+        for (irq, level_triggered) in signaled {
+            state.assert_irq(irq, level_triggered);
+        }
+        Ok(())
+    }
+
+    /// Deasserts `irq` as if the guest had acknowledged it, and signals the resample events of
+    /// level-triggered irq events on that line so their devices can trigger them again.
+    pub fn end_of_interrupt(&self, irq: u32) -> Result<()> {
+        let mut state = self.state.lock();
+        state.levels.insert(irq, false);
+        for evt in state.irq_events.iter().flatten() {
+            if evt.irq == irq {
+                if let Some(resample_event) = &evt.resample_event {
+                    resample_event.signal()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register_irq_event(
+        &mut self,
+        irq: u32,
+        event: &Event,
+        resample_event: Option<&Event>,
+        source: IrqEventSource,
+    ) -> Result<Option<IrqEventIndex>> {
+        let mut state = self.state.lock();
+        state.irq_events.push(Some(IrqEvent {
+            irq,
+            event: event.try_clone()?,
+            resample_event: resample_event.map(Event::try_clone).transpose()?,
+            source,
+        }));
+        Ok(Some(state.irq_events.len() - 1))
+    }
+
+    fn unregister_irq_event(&mut self, irq: u32, event: &Event) -> Result<()> {
+        let mut state = self.state.lock();
+        let evt = state
+            .irq_events
+            .iter_mut()
+            .find(|evt| matches!(evt, Some(evt) if evt.irq == irq && &evt.event == event))
+            .ok_or(Error::new(ENOENT))?;
+        *evt = None;
+        Ok(())
+    }
+}
+
+impl IrqChip for FakeIrqChip {
+    fn add_vcpu(&mut self, vcpu_id: usize, _vcpu: &dyn Vcpu) -> Result<()> {
+        self.state
+            .lock()
+            .mp_states
+            .insert(vcpu_id, MPState::Runnable);
+        Ok(())
+    }
+
+    fn register_edge_irq_event(
+        &mut self,
+        irq: u32,
+        irq_event: &IrqEdgeEvent,
+        source: IrqEventSource,
+    ) -> Result<Option<IrqEventIndex>> {
+        self.register_irq_event(irq, irq_event.get_trigger(), None, source)
+    }
+
+    fn unregister_edge_irq_event(&mut self, irq: u32, irq_event: &IrqEdgeEvent) -> Result<()> {
+        self.unregister_irq_event(irq, irq_event.get_trigger())
+    }
+
+    fn register_level_irq_event(
+        &mut self,
+        irq: u32,
+        irq_event: &IrqLevelEvent,
+        source: IrqEventSource,
+    ) -> Result<Option<IrqEventIndex>> {
+        self.register_irq_event(
+            irq,
+            irq_event.get_trigger(),
+            Some(irq_event.get_resample()),
+            source,
+        )
+    }
+
+    fn unregister_level_irq_event(&mut self, irq: u32, irq_event: &IrqLevelEvent) -> Result<()> {
+        self.unregister_irq_event(irq, irq_event.get_trigger())
+    }
+
+    fn route_irq(&mut self, route: IrqRoute) -> Result<()> {
+        let mut state = self.state.lock();
+        state.routes.retain(|r| r.gsi != route.gsi);
+        state.routes.push(route);
+        Ok(())
+    }
+
+    fn set_irq_routes(&mut self, routes: &[IrqRoute]) -> Result<()> {
+        self.state.lock().routes = routes.to_vec();
+        Ok(())
+    }
+
+    fn irq_event_tokens(&self) -> Result<Vec<(IrqEventIndex, IrqEventSource, Event)>> {
+        let state = self.state.lock();
+        let mut tokens = Vec::new();
+        for (index, evt) in state.irq_events.iter().enumerate() {
+            if let Some(evt) = evt {
+                tokens.push((index, evt.source.clone(), evt.event.try_clone()?));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn service_irq(&mut self, irq: u32, level: bool) -> Result<()> {
+        let mut state = self.state.lock();
+        if level {
+            state.assert_irq(irq, true);
+        } else {
+            state.levels.insert(irq, false);
+        }
+        Ok(())
+    }
+
+    fn service_irq_event(&mut self, event_index: IrqEventIndex) -> Result<()> {
+        let mut state = self.state.lock();
+        let (irq, level_triggered) = match state.irq_events.get(event_index) {
+            Some(Some(evt)) => {
+                evt.event.wait()?;
+                (evt.irq, evt.resample_event.is_some())
+            }
+            _ => return Ok(()),
+        };
+        state.assert_irq(irq, level_triggered);
+        Ok(())
+    }
+
+    fn broadcast_eoi(&self, _vector: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_interrupts(&self, _vcpu: &dyn Vcpu) -> Result<()> {
+        Ok(())
+    }
+
+    fn halted(&self, _vcpu_id: usize) {}
+
+    fn wait_until_runnable(&self, _vcpu: &dyn Vcpu) -> Result<VcpuRunState> {
+        Ok(VcpuRunState::Runnable)
+    }
+
+    fn kick_halted_vcpus(&self) {}
+
+    fn get_mp_state(&self, vcpu_id: usize) -> Result<MPState> {
+        self.state
+            .lock()
+            .mp_states
+            .get(&vcpu_id)
+            .copied()
+            .ok_or(Error::new(ENOENT))
+    }
+
+    fn set_mp_state(&mut self, vcpu_id: usize, state: &MPState) -> Result<()> {
+        self.state.lock().mp_states.insert(vcpu_id, *state);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeIrqChip {
+            state: self.state.clone(),
+        })
+    }
+
+    fn finalize_devices(
+        &mut self,
+        _resources: &mut SystemAllocator,
+        _io_bus: &Bus,
+        _mmio_bus: &Bus,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn process_delayed_irq_events(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn irq_delayed_event_token(&self) -> Result<Option<Event>> {
+        Ok(None)
+    }
+
+    fn check_capability(&self, _c: IrqChipCap) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::CrosvmDeviceId;
+    use devices::DeviceId;
+
+    use super::*;
+
+    fn source() -> IrqEventSource {
+        IrqEventSource {
+            device_id: DeviceId::PlatformDeviceId(CrosvmDeviceId::Serial),
+            queue_id: 0,
+            device_name: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn level_irq_event() {
+        let mut chip = FakeIrqChip::new();
+        let evt = IrqLevelEvent::new().unwrap();
+        let index = chip
+            .register_level_irq_event(5, &evt, source())
+            .unwrap()
+            .unwrap();
+        assert_eq!(chip.irq_event_tokens().unwrap().len(), 1);
+
+        evt.trigger().unwrap();
+        chip.service_irq_event(index).unwrap();
+        assert_eq!(chip.take_asserted(), [5]);
+        assert!(chip.irq_level(5));
+
+        chip.end_of_interrupt(5).unwrap();
+        assert!(!chip.irq_level(5));
+        assert_eq!(
+            evt.get_resample().wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::Signaled
+        );
+
+        chip.unregister_level_irq_event(5, &evt).unwrap();
+        evt.trigger().unwrap();
+        chip.poll_irq_events().unwrap();
+        assert!(chip.take_asserted().is_empty());
+    }
+
+    #[test]
+    fn edge_irq_event() {
+        let mut chip = FakeIrqChip::new();
+        let evt = IrqEdgeEvent::new().unwrap();
+        chip.register_edge_irq_event(3, &evt, source()).unwrap();
+        chip.poll_irq_events().unwrap();
+        assert!(chip.take_asserted().is_empty());
+        evt.trigger().unwrap();
+        chip.poll_irq_events().unwrap();
+        assert_eq!(chip.take_asserted(), [3]);
+        assert!(!chip.irq_level(3));
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Helpers for testing devices end-to-end in the test process, without a hypervisor or a guest.
+//!
+//! [`VirtioDriver`] plays the role of the guest driver of a `VirtioDevice`: it negotiates features,
+//! sets up split or packed virtqueues in guest memory, adds buffers, kicks the device and collects
+//! the used buffers and interrupts. [`FakeVm`] and [`FakeIrqChip`] stand in for the hypervisor
+//! objects that some devices and buses need, and record what was done with them.
+
+mod allocator;
+mod driver;
+mod irqchip;
+mod queue;
+mod vm;
+
+pub use driver::VirtioDriver;
+pub use driver::DEFAULT_MEMORY_SIZE;
+pub use irqchip::FakeIrqChip;
+pub use queue::DriverQueue;
+pub use queue::UsedBuffer;
+pub use vm::FakeVm;
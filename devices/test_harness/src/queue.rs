@@ -0,0 +1,532 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The driver side of split and packed virtqueues.
+
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::num::Wrapping;
+use std::sync::atomic::fence;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base::Event;
+use data_model::Le16;
+use data_model::Le32;
+use data_model::Le64;
+use devices::virtio::Desc;
+use devices::virtio::Queue;
+use devices::virtio::QueueConfig;
+use sync::Mutex;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
+use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use virtio_sys::virtio_ring::VRING_DESC_F_NEXT;
+use virtio_sys::virtio_ring::VRING_DESC_F_WRITE;
+use virtio_sys::virtio_ring::VRING_PACKED_DESC_F_AVAIL;
+use virtio_sys::virtio_ring::VRING_PACKED_DESC_F_USED;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use crate::allocator::GuestAllocator;
+
+/// A packed virtqueue descriptor (`struct pvirtq_desc` in the spec).
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+struct PackedDesc {
+    addr: Le64,
+    len: Le32,
+    id: Le16,
+    flags: Le16,
+}
+
+/// A buffer that the device has placed in the used ring.
+#[derive(Debug)]
+pub struct UsedBuffer {
+    /// The id returned by `DriverQueue::add_buffer` for this buffer.
+    pub id: u16,
+    /// Number of bytes the device reported writing.
+    pub len: u32,
+    /// Contents of the device-writable part of the buffer, truncated to `len`.
+    pub data: Vec<u8>,
+}
+
+/// A buffer segment in guest memory.
+struct Segment {
+    addr: GuestAddress,
+    len: u32,
+    writable: bool,
+}
+
+/// A buffer that was made available to the device and hasn't been used yet.
+struct InFlight {
+    segments: Vec<Segment>,
+    /// Descriptor table entries of a split queue buffer, in chain order.
+    desc_indices: Vec<u16>,
+}
+
+enum Ring {
+    Split {
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+        free_descs: Vec<u16>,
+        next_avail: Wrapping<u16>,
+        last_used: Wrapping<u16>,
+    },
+    Packed {
+        driver_area: GuestAddress,
+        device_area: GuestAddress,
+        free_ids: Vec<u16>,
+        free_slots: u16,
+        next_avail: u16,
+        avail_wrap_counter: bool,
+        next_used: u16,
+        used_wrap_counter: bool,
+    },
+}
+
+/// The driver's view of a virtqueue: adds buffers to the available ring or descriptor ring and
+/// collects them once the device has used them.
+pub struct DriverQueue {
+    mem: GuestMemory,
+    allocator: Arc<Mutex<GuestAllocator>>,
+    size: u16,
+    features: u64,
+    desc_table: GuestAddress,
+    ring: Ring,
+    in_flight: BTreeMap<u16, InFlight>,
+    kick_evt: Event,
+}
+
+impl DriverQueue {
+    /// Allocates the rings of a queue with `size` entries. The ring layout is packed if
+    /// `features` contains `VIRTIO_F_RING_PACKED`.
+    pub(crate) fn new(
+        mem: &GuestMemory,
+        allocator: Arc<Mutex<GuestAllocator>>,
+        size: u16,
+        features: u64,
+    ) -> Result<DriverQueue> {
+        let (desc_table, ring) = {
+            let mut allocator = allocator.lock();
+            let desc_table = allocator.allocate(16 * size as u64, 16)?;
+            let ring = if features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+                Ring::Packed {
+                    driver_area: allocator.allocate(4, 4)?,
+                    device_area: allocator.allocate(4, 4)?,
+                    free_ids: (0..size).rev().collect(),
+                    free_slots: size,
+                    next_avail: 0,
+                    avail_wrap_counter: true,
+                    next_used: 0,
+                    used_wrap_counter: true,
+                }
+            } else {
+                if !size.is_power_of_two() {
+                    bail!("split queue size {} is not a power of 2", size);
+                }
+                Ring::Split {
+                    avail_ring: allocator.allocate(6 + 2 * size as u64, 2)?,
+                    used_ring: allocator.allocate(6 + 8 * size as u64, 4)?,
+                    free_descs: (0..size).rev().collect(),
+                    next_avail: Wrapping(0),
+                    last_used: Wrapping(0),
+                }
+            };
+            (desc_table, ring)
+        };
+        Ok(DriverQueue {
+            mem: mem.clone(),
+            allocator,
+            size,
+            features,
+            desc_table,
+            ring,
+            in_flight: BTreeMap::new(),
+            kick_evt: Event::new().context("failed to create queue event")?,
+        })
+    }
+
+    /// Creates the device side of this queue, as a transport would once the driver marks the
+    /// queue ready. `device_features` are the features offered by the device.
+    pub(crate) fn activate(&self, device_features: u64) -> Result<Queue> {
+        let mut config = QueueConfig::new(self.size, device_features);
+        config.set_desc_table(self.desc_table);
+        match &self.ring {
+            Ring::Split {
+                avail_ring,
+                used_ring,
+                ..
+            } => {
+                config.set_avail_ring(*avail_ring);
+                config.set_used_ring(*used_ring);
+            }
+            Ring::Packed {
+                driver_area,
+                device_area,
+                ..
+            } => {
+                config.set_avail_ring(*driver_area);
+                config.set_used_ring(*device_area);
+            }
+        }
+        config.ack_features(self.features);
+        config.set_ready(true);
+        let kick_evt = self
+            .kick_evt
+            .try_clone()
+            .context("failed to clone queue event")?;
+        config.activate(&self.mem, kick_evt)
+    }
+
+    /// Returns the number of entries in the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the number of buffers that the device hasn't used yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Makes a buffer available to the device. The buffer is made of one device-readable
+    /// descriptor for each element of `readable`, holding its contents, followed by one
+    /// device-writable descriptor for each length in `writable`.
+    ///
+    /// Returns the id that identifies the buffer in the `UsedBuffer` returned by `pop_used`.
+    pub fn add_buffer(&mut self, readable: &[&[u8]], writable: &[u32]) -> Result<u16> {
+        let count = readable.len() + writable.len();
+        if count == 0 {
+            bail!("a buffer needs at least one descriptor");
+        }
+        let free = match &self.ring {
+            Ring::Split { free_descs, .. } => free_descs.len(),
+            Ring::Packed { free_slots, .. } => *free_slots as usize,
+        };
+        if count > free {
+            bail!(
+                "queue has room for {} descriptors, {} requested",
+                free,
+                count
+            );
+        }
+
+        let mut segments = Vec::with_capacity(count);
+        {
+            let mut allocator = self.allocator.lock();
+            for data in readable {
+                let addr = allocator.allocate(data.len() as u64, 8)?;
+                self.mem
+                    .write_all_at_addr(data, addr)
+                    .context("failed to write buffer")?;
+                segments.push(Segment {
+                    addr,
+                    len: data.len() as u32,
+                    writable: false,
+                });
+            }
+            for &len in writable {
+                segments.push(Segment {
+                    addr: allocator.allocate(len as u64, 8)?,
+                    len,
+                    writable: true,
+                });
+            }
+        }
+
+        let (id, desc_indices) = match &mut self.ring {
+            Ring::Split {
+                avail_ring,
+                free_descs,
+                next_avail,
+                ..
+            } => {
+                let desc_indices: Vec<u16> =
+                    (0..count).map(|_| free_descs.pop().unwrap()).collect();
+                for (i, segment) in segments.iter().enumerate() {
+                    let mut flags = 0;
+                    if segment.writable {
+                        flags |= VRING_DESC_F_WRITE as u16;
+                    }
+                    let next = desc_indices.get(i + 1).copied();
+                    if next.is_some() {
+                        flags |= VRING_DESC_F_NEXT as u16;
+                    }
+                    let desc = Desc {
+                        addr: segment.addr.offset().into(),
+                        len: segment.len.into(),
+                        flags: flags.into(),
+                        next: next.unwrap_or(0).into(),
+                    };
+                    self.mem
+                        .write_obj_at_addr(
+                            desc,
+                            self.desc_table
+                                .unchecked_add(desc_indices[i] as u64 * size_of::<Desc>() as u64),
+                        )
+                        .context("failed to write descriptor")?;
+                }
+
+                let head = desc_indices[0];
+                let slot = next_avail.0 % self.size;
+                self.mem
+                    .write_obj_at_addr(
+                        Le16::from(head),
+                        avail_ring.unchecked_add(4 + 2 * slot as u64),
+                    )
+                    .context("failed to write available ring")?;
+                *next_avail += Wrapping(1);
+                // The device must see the ring entry before the new index.
+                fence(Ordering::SeqCst);
+                self.mem
+                    .write_obj_at_addr_volatile(
+                        Le16::from(next_avail.0),
+                        avail_ring.unchecked_add(2),
+                    )
+                    .context("failed to write available index")?;
+                (head, desc_indices)
+            }
+            Ring::Packed {
+                free_ids,
+                free_slots,
+                next_avail,
+                avail_wrap_counter,
+                ..
+            } => {
+                let id = free_ids.pop().unwrap();
+                let head_slot = *next_avail;
+                let mut head_flags = 0;
+                for (i, segment) in segments.iter().enumerate() {
+                    let mut flags = 0;
+                    if segment.writable {
+                        flags |= VRING_DESC_F_WRITE as u16;
+                    }
+                    if i + 1 < count {
+                        flags |= VRING_DESC_F_NEXT as u16;
+                    }
+                    if *avail_wrap_counter {
+                        flags |= 1 << VRING_PACKED_DESC_F_AVAIL;
+                    } else {
+                        flags |= 1 << VRING_PACKED_DESC_F_USED;
+                    }
+                    let desc_addr = self.desc_table.unchecked_add(*next_avail as u64 * 16);
+                    // The head descriptor is made available last, once the whole chain is in
+                    // place.
+                    if i == 0 {
+                        head_flags = flags;
+                        flags = 0;
+                    }
+                    let desc = PackedDesc {
+                        addr: segment.addr.offset().into(),
+                        len: segment.len.into(),
+                        id: id.into(),
+                        flags: flags.into(),
+                    };
+                    self.mem
+                        .write_obj_at_addr(desc, desc_addr)
+                        .context("failed to write descriptor")?;
+
+                    *next_avail += 1;
+                    if *next_avail == self.size {
+                        *next_avail = 0;
+                        *avail_wrap_counter = !*avail_wrap_counter;
+                    }
+                }
+                *free_slots -= count as u16;
+                fence(Ordering::SeqCst);
+                self.mem
+                    .write_obj_at_addr_volatile(
+                        Le16::from(head_flags),
+                        self.desc_table.unchecked_add(head_slot as u64 * 16 + 14),
+                    )
+                    .context("failed to write descriptor flags")?;
+                (id, Vec::new())
+            }
+        };
+
+        self.in_flight.insert(
+            id,
+            InFlight {
+                segments,
+                desc_indices,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Notifies the device that buffers were made available.
+    pub fn kick(&self) -> Result<()> {
+        self.kick_evt.signal().context("failed to kick queue")
+    }
+
+    /// Takes the next buffer out of the used ring, if the device has used one.
+    pub fn pop_used(&mut self) -> Result<Option<UsedBuffer>> {
+        let (id, len) = match &mut self.ring {
+            Ring::Split {
+                avail_ring,
+                used_ring,
+                last_used,
+                ..
+            } => {
+                let used_idx: Le16 = self
+                    .mem
+                    .read_obj_from_addr_volatile(used_ring.unchecked_add(2))
+                    .context("failed to read used index")?;
+                if u16::from(used_idx) == last_used.0 {
+                    return Ok(None);
+                }
+                // The used element must not be read before the index that covers it.
+                fence(Ordering::SeqCst);
+                let elem_addr = used_ring.unchecked_add(4 + 8 * (last_used.0 % self.size) as u64);
+                let id: Le32 = self
+                    .mem
+                    .read_obj_from_addr(elem_addr)
+                    .context("failed to read used element")?;
+                let len: Le32 = self
+                    .mem
+                    .read_obj_from_addr(elem_addr.unchecked_add(4))
+                    .context("failed to read used element")?;
+                *last_used += Wrapping(1);
+                if self.features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0 {
+                    // Ask for an interrupt as soon as the device uses another buffer.
+                    self.mem
+                        .write_obj_at_addr(
+                            Le16::from(last_used.0),
+                            avail_ring.unchecked_add(4 + 2 * self.size as u64),
+                        )
+                        .context("failed to write used event")?;
+                }
+                (u32::from(id) as u16, u32::from(len))
+            }
+            Ring::Packed {
+                next_used,
+                used_wrap_counter,
+                ..
+            } => {
+                let desc_addr = self.desc_table.unchecked_add(*next_used as u64 * 16);
+                let flags: Le16 = self
+                    .mem
+                    .read_obj_from_addr_volatile(desc_addr.unchecked_add(14))
+                    .context("failed to read descriptor flags")?;
+                let flags = u16::from(flags);
+                let avail = flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0;
+                let used = flags & (1 << VRING_PACKED_DESC_F_USED) != 0;
+                if avail != used || used != *used_wrap_counter {
+                    return Ok(None);
+                }
+                fence(Ordering::SeqCst);
+                let desc: PackedDesc = self
+                    .mem
+                    .read_obj_from_addr(desc_addr)
+                    .context("failed to read used descriptor")?;
+                (u16::from(desc.id), u32::from(desc.len))
+            }
+        };
+
+        let buffer = match self.in_flight.remove(&id) {
+            Some(buffer) => buffer,
+            None => bail!("device used unknown buffer {}", id),
+        };
+        match &mut self.ring {
+            Ring::Split { free_descs, .. } => {
+                free_descs.extend(buffer.desc_indices.iter().rev());
+            }
+            Ring::Packed {
+                free_ids,
+                free_slots,
+                next_used,
+                used_wrap_counter,
+                ..
+            } => {
+                let count = buffer.segments.len() as u16;
+                *next_used += count;
+                if *next_used >= self.size {
+                    *next_used -= self.size;
+                    *used_wrap_counter = !*used_wrap_counter;
+                }
+                *free_slots += count;
+                free_ids.push(id);
+            }
+        }
+
+        let mut data = Vec::new();
+        let mut remaining = len as usize;
+        let mut allocator = self.allocator.lock();
+        for segment in buffer.segments {
+            if segment.writable && remaining > 0 {
+                let mut buf = vec![0; remaining.min(segment.len as usize)];
+                self.mem
+                    .read_exact_at_addr(&mut buf, segment.addr)
+                    .context("failed to read buffer")?;
+                remaining -= buf.len();
+                data.extend(buf);
+            }
+            allocator.release(segment.addr)?;
+        }
+        Ok(Some(UsedBuffer { id, len, data }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Write;
+
+    use super::*;
+
+    fn round_trip(features: u64) {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let allocator = Arc::new(Mutex::new(GuestAllocator::new(&mem).unwrap()));
+        let mut driver_queue = DriverQueue::new(&mem, allocator, 4, features).unwrap();
+        let mut queue = driver_queue.activate(features).unwrap();
+
+        // Go around the ring a few times to exercise index and wrap counter wrapping.
+        for i in 0..10u8 {
+            let id = driver_queue
+                .add_buffer(&[&[i; 3], &[i + 1; 5]], &[4, 16])
+                .unwrap();
+            assert!(driver_queue.pop_used().unwrap().is_none());
+
+            let mut chain = queue.pop().expect("no available buffer");
+            let mut request = Vec::new();
+            chain.reader.read_to_end(&mut request).unwrap();
+            assert_eq!(request, [&[i; 3][..], &[i + 1; 5]].concat());
+            chain.writer.write_all(&[0xaa; 6]).unwrap();
+            queue.add_used(chain, 6);
+            assert!(queue.pop().is_none());
+
+            let used = driver_queue.pop_used().unwrap().expect("no used buffer");
+            assert_eq!(used.id, id);
+            assert_eq!(used.len, 6);
+            assert_eq!(used.data, [0xaa; 6]);
+            assert_eq!(driver_queue.in_flight(), 0);
+        }
+    }
+
+    #[test]
+    fn split_queue_round_trip() {
+        round_trip(1 << VIRTIO_RING_F_EVENT_IDX);
+    }
+
+    #[test]
+    fn packed_queue_round_trip() {
+        round_trip(1 << VIRTIO_F_RING_PACKED);
+    }
+
+    #[test]
+    fn queue_full() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let allocator = Arc::new(Mutex::new(GuestAllocator::new(&mem).unwrap()));
+        let mut driver_queue = DriverQueue::new(&mem, allocator, 4, 0).unwrap();
+        driver_queue.add_buffer(&[&[0; 8]], &[8, 8]).unwrap();
+        assert!(driver_queue.add_buffer(&[], &[8, 8]).is_err());
+        driver_queue.add_buffer(&[], &[8]).unwrap();
+        assert_eq!(driver_queue.in_flight(), 2);
+    }
+}
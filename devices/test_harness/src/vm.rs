@@ -0,0 +1,320 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use base::AsRawDescriptor;
+use base::Error;
+use base::Event;
+use base::MappedRegion;
+use base::Protection;
+use base::Result;
+use base::SafeDescriptor;
+use hypervisor::BalloonEvent;
+use hypervisor::ClockState;
+use hypervisor::Datamatch;
+use hypervisor::DeviceKind;
+use hypervisor::IoEventAddress;
+use hypervisor::MemSlot;
+use hypervisor::Vm;
+use hypervisor::VmCap;
+use libc::EEXIST;
+use libc::EINVAL;
+use libc::ENODEV;
+use libc::ENOENT;
+use libc::ENOSPC;
+use libc::ENXIO;
+use libc::EOVERFLOW;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+const GUEST_PHYS_ADDR_BITS: u8 = 40;
+
+struct MemoryRegion {
+    guest_addr: GuestAddress,
+    mem: Box<dyn MappedRegion>,
+}
+
+struct IoEvent {
+    evt: Event,
+    addr: IoEventAddress,
+    datamatch: Datamatch,
+}
+
+#[derive(Default)]
+struct FakeVmState {
+    regions: BTreeMap<MemSlot, MemoryRegion>,
+    ioevents: Vec<IoEvent>,
+    /// Sizes of the file mappings added with `add_fd_mapping`, by slot and offset.
+    fd_mappings: BTreeMap<(u32, usize), usize>,
+}
+
+/// A `Vm` that has no vCPUs and only keeps track of the memory regions, mappings and ioevents
+/// that devices register with it.
+///
+/// Clones made with `try_clone` share the same state.
+pub struct FakeVm {
+    guest_mem: GuestMemory,
+    state: Arc<Mutex<FakeVmState>>,
+}
+
+impl FakeVm {
+    /// Creates a `FakeVm` using the given guest memory.
+    pub fn new(guest_mem: GuestMemory) -> FakeVm {
+        FakeVm {
+            guest_mem,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns the slot, guest address and size of each region added with `add_memory_region`.
+    pub fn memory_regions(&self) -> Vec<(MemSlot, GuestAddress, usize)> {
+        self.state
+            .lock()
+            .regions
+            .iter()
+            .map(|(&slot, region)| (slot, region.guest_addr, region.mem.size()))
+            .collect()
+    }
+
+    /// Returns the addresses of the registered ioevents.
+    pub fn ioevents(&self) -> Vec<IoEventAddress> {
+        self.state.lock().ioevents.iter().map(|e| e.addr).collect()
+    }
+
+    /// Returns the offset and size of each file mapping added to `slot`.
+    pub fn fd_mappings(&self, slot: u32) -> Vec<(usize, usize)> {
+        self.state
+            .lock()
+            .fd_mappings
+            .range((slot, 0)..=(slot, usize::MAX))
+            .map(|(&(_, offset), &size)| (offset, size))
+            .collect()
+    }
+}
+
+impl Vm for FakeVm {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeVm {
+            guest_mem: self.guest_mem.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    fn check_capability(&self, _c: VmCap) -> bool {
+        false
+    }
+
+    fn get_guest_phys_addr_bits(&self) -> u8 {
+        GUEST_PHYS_ADDR_BITS
+    }
+
+    fn get_memory(&self) -> &GuestMemory {
+        &self.guest_mem
+    }
+
+    fn add_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        _read_only: bool,
+        _log_dirty_pages: bool,
+    ) -> Result<MemSlot> {
+        let end_addr = guest_addr
+            .checked_add(mem.size() as u64)
+            .ok_or(Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        let mut state = self.state.lock();
+        let overlaps = state.regions.values().any(|region| {
+            guest_addr < region.guest_addr.unchecked_add(region.mem.size() as u64)
+                && region.guest_addr < end_addr
+        });
+        if overlaps {
+            return Err(Error::new(ENOSPC));
+        }
+        // Slots below the number of guest memory regions are taken by the guest memory.
+        let mut slot = self.guest_mem.num_regions() as MemSlot;
+        while state.regions.contains_key(&slot) {
+            slot += 1;
+        }
+        state.regions.insert(slot, MemoryRegion { guest_addr, mem });
+        Ok(slot)
+    }
+
+    fn msync_memory_region(&mut self, slot: MemSlot, _offset: usize, _size: usize) -> Result<()> {
+        if !self.state.lock().regions.contains_key(&slot) {
+            return Err(Error::new(ENOENT));
+        }
+        Ok(())
+    }
+
+    fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
+        let mut state = self.state.lock();
+        let region = state.regions.remove(&slot).ok_or(Error::new(ENOENT))?;
+        state.fd_mappings.retain(|&(s, _), _| s != slot);
+        Ok(region.mem)
+    }
+
+    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_dirty_log(&self, _slot: MemSlot, _dirty_log: &mut [u8]) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn register_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        if state
+            .ioevents
+            .iter()
+            .any(|e| e.addr == addr && e.datamatch == datamatch)
+        {
+            return Err(Error::new(EEXIST));
+        }
+        state.ioevents.push(IoEvent {
+            evt: evt.try_clone()?,
+            addr,
+            datamatch,
+        });
+        Ok(())
+    }
+
+    fn unregister_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        let index = state
+            .ioevents
+            .iter()
+            .position(|e| e.addr == addr && e.datamatch == datamatch && &e.evt == evt)
+            .ok_or(Error::new(ENOENT))?;
+        state.ioevents.remove(index);
+        Ok(())
+    }
+
+    /// Signals the ioevents matching a write of `data` at `addr`.
+    fn handle_io_events(&self, addr: IoEventAddress, data: &[u8]) -> Result<()> {
+        let mut value = [0u8; 8];
+        let len = data.len().min(value.len());
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u64::from_le_bytes(value);
+        let matches = |datamatch: Datamatch| match datamatch {
+            Datamatch::AnyLength => true,
+            Datamatch::U8(v) => data.len() == 1 && v.map_or(true, |v| v as u64 == value),
+            Datamatch::U16(v) => data.len() == 2 && v.map_or(true, |v| v as u64 == value),
+            Datamatch::U32(v) => data.len() == 4 && v.map_or(true, |v| v as u64 == value),
+            Datamatch::U64(v) => data.len() == 8 && v.map_or(true, |v| v == value),
+        };
+        for ioevent in self
+            .state
+            .lock()
+            .ioevents
+            .iter()
+            .filter(|e| e.addr == addr && matches(e.datamatch))
+        {
+            ioevent.evt.signal()?;
+        }
+        Ok(())
+    }
+
+    fn get_pvclock(&self) -> Result<ClockState> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn set_pvclock(&self, _state: &ClockState) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn add_fd_mapping(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        size: usize,
+        _fd: &dyn AsRawDescriptor,
+        _fd_offset: u64,
+        _prot: Protection,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        let region = state.regions.get(&slot).ok_or(Error::new(EINVAL))?;
+        if offset
+            .checked_add(size)
+            .map_or(true, |end| end > region.mem.size())
+        {
+            return Err(Error::new(EINVAL));
+        }
+        state.fd_mappings.insert((slot, offset), size);
+        Ok(())
+    }
+
+    fn remove_mapping(&mut self, slot: u32, offset: usize, _size: usize) -> Result<()> {
+        match self.state.lock().fd_mappings.remove(&(slot, offset)) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn handle_balloon_event(&mut self, _event: BalloonEvent) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use base::EventWaitResult;
+    use base::MemoryMappingBuilder;
+
+    use super::*;
+
+    #[test]
+    fn memory_regions_and_ioevents() {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = FakeVm::new(guest_mem);
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        let slot = vm
+            .add_memory_region(GuestAddress(0x1000), Box::new(mem), false, false)
+            .unwrap();
+        assert_eq!(slot, 1);
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        assert!(vm
+            .add_memory_region(GuestAddress(0x1800), Box::new(mem), false, false)
+            .is_err());
+        assert_eq!(vm.memory_regions(), [(1, GuestAddress(0x1000), 0x1000)]);
+
+        let evt = Event::new().unwrap();
+        let addr = IoEventAddress::Mmio(0x1000);
+        vm.try_clone()
+            .unwrap()
+            .register_ioevent(&evt, addr, Datamatch::U32(Some(3)))
+            .unwrap();
+        vm.handle_io_events(addr, &4u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            evt.wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::TimedOut
+        );
+        vm.handle_io_events(addr, &3u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            evt.wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::Signaled
+        );
+        assert_eq!(vm.ioevents(), [addr]);
+
+        vm.remove_memory_region(slot).unwrap();
+        assert!(vm.memory_regions().is_empty());
+    }
+}
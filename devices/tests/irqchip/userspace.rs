@@ -38,6 +38,7 @@ use hypervisor::DebugRegs;
 use hypervisor::DeliveryMode;
 use hypervisor::DestinationMode;
 use hypervisor::Fpu;
+use hypervisor::HwWatchpoint;
 use hypervisor::HypervHypercall;
 use hypervisor::IoParams;
 use hypervisor::IoapicRedirectionTableEntry;
@@ -776,7 +777,15 @@ impl VcpuX86_64 for FakeVcpu {
     fn get_hyperv_cpuid(&self) -> Result<CpuId> {
         unimplemented!()
     }
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
+        unimplemented!()
+    }
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>> {
         unimplemented!()
     }
     fn get_tsc_offset(&self) -> Result<u64> {
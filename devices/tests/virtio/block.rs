@@ -0,0 +1,91 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// TODO(b/270225199): enable these tests on Windows once IoSource::into_source is implemented.
+#![cfg(any(target_os = "android", target_os = "linux"))]
+
+use std::time::Duration;
+
+use device_test_harness::VirtioDriver;
+use devices::virtio::base_features;
+use devices::virtio::block::DiskOption;
+use devices::virtio::device_constants::block::VIRTIO_BLK_S_OK;
+use devices::virtio::device_constants::block::VIRTIO_BLK_T_IN;
+use devices::virtio::device_constants::block::VIRTIO_BLK_T_OUT;
+use devices::virtio::BlockAsync;
+use hypervisor::ProtectionType;
+use tempfile::tempfile;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const DISK_SIZE: u64 = 0x10000;
+const SECTOR_SIZE: usize = 512;
+
+fn request_header(req_type: u32, sector: u64) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend(req_type.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend(sector.to_le_bytes());
+    header
+}
+
+fn read_write(packed_queue: bool) {
+    let disk_image = tempfile().unwrap();
+    disk_image.set_len(DISK_SIZE).unwrap();
+    let disk_option = DiskOption {
+        packed_queue,
+        ..Default::default()
+    };
+    let block = BlockAsync::new(
+        base_features(ProtectionType::Unprotected),
+        Box::new(disk_image),
+        &disk_option,
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let mut driver = VirtioDriver::new(Box::new(block)).unwrap();
+    // Accept everything the device offers.
+    let features = driver.negotiate_features(u64::MAX);
+    assert_eq!(features & (1 << VIRTIO_F_RING_PACKED) != 0, packed_queue);
+
+    let mut capacity = [0u8; 8];
+    driver.read_config(0, &mut capacity);
+    assert_eq!(u64::from_le_bytes(capacity), DISK_SIZE / SECTOR_SIZE as u64);
+
+    driver.activate().unwrap();
+    let data: Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+    let used = driver
+        .transfer(
+            0,
+            &[&request_header(VIRTIO_BLK_T_OUT, 3), &data],
+            &[1],
+            TIMEOUT,
+        )
+        .unwrap();
+    assert_eq!(used.data, [VIRTIO_BLK_S_OK]);
+
+    let used = driver
+        .transfer(
+            0,
+            &[&request_header(VIRTIO_BLK_T_IN, 3)],
+            &[SECTOR_SIZE as u32, 1],
+            TIMEOUT,
+        )
+        .unwrap();
+    assert_eq!(used.len as usize, SECTOR_SIZE + 1);
+    assert_eq!(used.data[..SECTOR_SIZE], data);
+    assert_eq!(used.data[SECTOR_SIZE], VIRTIO_BLK_S_OK);
+}
+
+#[test]
+fn block_read_write_split_queue() {
+    read_write(false);
+}
+
+#[test]
+fn block_read_write_packed_queue() {
+    read_write(true);
+}
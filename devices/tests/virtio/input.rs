@@ -0,0 +1,76 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![cfg(any(target_os = "android", target_os = "linux"))]
+
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use device_test_harness::VirtioDriver;
+use devices::virtio::base_features;
+use devices::virtio::input::new_keyboard;
+use hypervisor::ProtectionType;
+use linux_input_sys::constants::EV_LED;
+use linux_input_sys::constants::KEY_A;
+use linux_input_sys::constants::LED_CAPSL;
+use linux_input_sys::virtio_input_event;
+use linux_input_sys::InputEventDecoder;
+use zerocopy::AsBytes;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 1;
+// Offsets of the fields of `virtio_input_config`.
+const CONFIG_SIZE: u64 = 2;
+const CONFIG_DATA: u64 = 8;
+
+#[test]
+fn keyboard_events() {
+    let (source, mut host) = UnixStream::pair().unwrap();
+    let keyboard = new_keyboard(0, source, base_features(ProtectionType::Unprotected)).unwrap();
+    let mut driver = VirtioDriver::new(Box::new(keyboard)).unwrap();
+
+    driver.write_config(0, &[VIRTIO_INPUT_CFG_ID_NAME, 0]);
+    let mut size = [0u8];
+    driver.read_config(CONFIG_SIZE, &mut size);
+    let mut name = vec![0u8; size[0] as usize];
+    driver.read_config(CONFIG_DATA, &mut name);
+    assert_eq!(name, b"Crosvm Virtio Keyboard 0");
+
+    driver.negotiate_features(u64::MAX);
+    driver.activate().unwrap();
+
+    // Events written to the source are delivered to the VM, one per buffer here.
+    let queue = driver.queue(EVENT_QUEUE);
+    for _ in 0..2 {
+        queue
+            .add_buffer(&[], &[virtio_input_event::SIZE as u32])
+            .unwrap();
+    }
+    queue.kick().unwrap();
+    let events = [
+        virtio_input_event::key(KEY_A, true),
+        virtio_input_event::syn(),
+    ];
+    host.write_all(events.as_bytes()).unwrap();
+    let mut delivered = driver.wait_used(EVENT_QUEUE, TIMEOUT).unwrap().data;
+    delivered.extend(driver.wait_used(EVENT_QUEUE, TIMEOUT).unwrap().data);
+    assert_eq!(delivered, events.as_bytes());
+
+    // Status events from the VM, such as LED changes, are written back to the source.
+    let led = virtio_input_event {
+        type_: EV_LED.into(),
+        code: LED_CAPSL.into(),
+        value: 1.into(),
+    };
+    driver
+        .transfer(STATUS_QUEUE, &[led.as_bytes()], &[], TIMEOUT)
+        .unwrap();
+    let mut status = [0u8; virtio_input_event::SIZE];
+    host.read_exact(&mut status).unwrap();
+    assert_eq!(status, led.as_bytes());
+}
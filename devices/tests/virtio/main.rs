@@ -0,0 +1,10 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tests of virtio devices driven by `device_test_harness`, without a guest.
+
+mod block;
+mod input;
+mod net;
+mod rng;
@@ -0,0 +1,42 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::time::Duration;
+
+use device_test_harness::VirtioDriver;
+use devices::virtio::base_features;
+use devices::virtio::Rng;
+use hypervisor::ProtectionType;
+use virtio_sys::virtio_config::VIRTIO_F_VERSION_1;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn rng_fills_buffers() {
+    let rng = Rng::new(base_features(ProtectionType::Unprotected)).unwrap();
+    let mut driver = VirtioDriver::new(Box::new(rng)).unwrap();
+    assert_ne!(driver.negotiate_features(1 << VIRTIO_F_VERSION_1), 0);
+    driver.activate().unwrap();
+
+    let first = driver.transfer(0, &[], &[64], TIMEOUT).unwrap();
+    assert_eq!(first.len, 64);
+    assert_eq!(first.data.len(), 64);
+
+    // Several buffers can be in flight at once, and they are split across descriptors.
+    let queue = driver.queue(0);
+    queue.add_buffer(&[], &[16, 16]).unwrap();
+    queue.add_buffer(&[], &[8]).unwrap();
+    queue.kick().unwrap();
+    let mut lens = vec![
+        driver.wait_used(0, TIMEOUT).unwrap().len,
+        driver.wait_used(0, TIMEOUT).unwrap().len,
+    ];
+    lens.sort();
+    assert_eq!(lens, [8, 32]);
+    assert_eq!(driver.queue(0).in_flight(), 0);
+    assert_ne!(
+        first.data,
+        driver.transfer(0, &[], &[64], TIMEOUT).unwrap().data
+    );
+}
@@ -52,6 +52,63 @@ fn do_playback() {
     ));
 }
 
+/// Tests audio capture on virtio-snd with file backend
+///
+/// 1. Create a temporal directory with a WAV file for the capture stream.
+/// 2. Start a VM with a virtiofs device for the temporal directory
+///    and a virtio-snd device with file backend reading the WAV file.
+/// 3. Do capture with arecord.
+/// 4. Compare the samples of the WAV file and the captured audio.
+#[test]
+fn do_capture() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let temp_dir_path_str = temp_dir.path().to_str().unwrap();
+
+    // 1 second of 16 bit stereo samples at 48000 Hz. The capture stream is stream 1, after the
+    // playback stream.
+    let samples: Vec<u8> = (0..2 * 2 * 48000).map(|i| (i % 251) as u8).collect();
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&48000u32.to_le_bytes());
+    wav.extend_from_slice(&(48000u32 * 4).to_le_bytes());
+    wav.extend_from_slice(&4u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&samples);
+    fs::write(temp_dir.path().join("stream-1.wav"), &wav).unwrap();
+
+    let config = Config::new().extra_args(vec![
+        "--shared-dir".to_string(),
+        format!("{}:tmp2:type=fs:cache=always", temp_dir_path_str),
+        "--virtio-snd".to_string(),
+        format!(
+            "backend=file,playback_path={0},playback_size=400000,capture_path={0}",
+            temp_dir_path_str
+        ),
+    ]);
+
+    let mut vm = TestVm::new(config).unwrap();
+    vm.exec_in_guest("mount -t virtiofs tmp2 /mnt").unwrap();
+    vm.exec_in_guest(
+        "arecord --buffer-size=48000 --period-size=12000 \
+        -d 1 -f dat -t raw -Dhw:0,0 /mnt/captured.raw",
+    )
+    .unwrap();
+
+    let captured = fs::read(temp_dir.path().join("captured.raw")).unwrap();
+    assert!(captured.len() >= samples.len());
+    assert!(
+        captured[..samples.len()] == samples[..],
+        "Captured audio differs"
+    );
+}
+
 fn compare_files(temp_dir: TempDir, golden_file_name: &str, output_file_name: &str) -> bool {
     // 1 second, 2 channels, 16 bit (2 byte) format, 48000 frame rate.
     const BYTES_TO_COMPARE: usize = 1 * 2 * 2 * 48000;
@@ -0,0 +1,314 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encodings of framebuffer rectangles sent to VNC clients.
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+
+use super::protocol::PixelFormat;
+use super::protocol::Rect;
+use super::protocol::ENCODING_RAW;
+use super::protocol::ENCODING_TIGHT;
+use super::protocol::ENCODING_ZLIB;
+
+// Tight rectangles are split in tiles so that clients never need large decompression buffers.
+const TIGHT_TILE_SIZE: u16 = 128;
+// Tight data shorter than this is sent without compression.
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+const TIGHT_FILL: u8 = 0x80;
+// Basic compression on zlib stream 0, without filter.
+const TIGHT_BASIC_STREAM0: u8 = 0x00;
+
+/// The encoding used for framebuffer updates, picked from the encodings a client supports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Raw,
+    Zlib,
+    Tight,
+}
+
+impl Encoding {
+    /// Returns the first encoding of `encodings`, in the client's order of preference, that is
+    /// supported by the server.
+    pub fn choose(encodings: &[i32]) -> Encoding {
+        encodings
+            .iter()
+            .find_map(|e| match *e {
+                ENCODING_RAW => Some(Encoding::Raw),
+                ENCODING_ZLIB => Some(Encoding::Zlib),
+                ENCODING_TIGHT => Some(Encoding::Tight),
+                _ => None,
+            })
+            .unwrap_or(Encoding::Raw)
+    }
+}
+
+/// An XRGB8888 framebuffer.
+pub struct Screen<'a> {
+    pub pixels: &'a [u8],
+    pub width: u16,
+}
+
+impl<'a> Screen<'a> {
+    fn pixel(&self, x: u16, y: u16) -> u32 {
+        let offset = (y as usize * self.width as usize + x as usize) * 4;
+        u32::from_le_bytes([
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+            self.pixels[offset + 3],
+        ])
+    }
+
+    fn pixels(&self, rect: Rect) -> impl Iterator<Item = u32> + '_ {
+        (rect.y..rect.y + rect.height)
+            .flat_map(move |y| (rect.x..rect.x + rect.width).map(move |x| self.pixel(x, y)))
+    }
+}
+
+/// Encodes framebuffer updates for a single client. The zlib streams of the zlib and tight
+/// encodings live as long as the connection, so there must be one encoder per client.
+pub struct Encoder {
+    zlib: Compress,
+    tight: Compress,
+    scratch: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            zlib: new_stream(),
+            tight: new_stream(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Appends `rect` of `screen` to `out` as one or more rectangles, and returns how many.
+    pub fn encode(
+        &mut self,
+        encoding: Encoding,
+        format: &PixelFormat,
+        screen: &Screen,
+        rect: Rect,
+        out: &mut Vec<u8>,
+    ) -> u16 {
+        match encoding {
+            Encoding::Raw => {
+                rect.write_header(ENCODING_RAW, out);
+                for pixel in screen.pixels(rect) {
+                    format.write_pixel(pixel, out);
+                }
+                1
+            }
+            Encoding::Zlib => {
+                rect.write_header(ENCODING_ZLIB, out);
+                self.scratch.clear();
+                for pixel in screen.pixels(rect) {
+                    format.write_pixel(pixel, &mut self.scratch);
+                }
+                let len_offset = out.len();
+                out.extend_from_slice(&[0; 4]);
+                compress(&mut self.zlib, &self.scratch, out);
+                let len = (out.len() - len_offset - 4) as u32;
+                out[len_offset..len_offset + 4].copy_from_slice(&len.to_be_bytes());
+                1
+            }
+            Encoding::Tight => {
+                let mut count = 0;
+                for y in (rect.y..rect.y + rect.height).step_by(TIGHT_TILE_SIZE as usize) {
+                    for x in (rect.x..rect.x + rect.width).step_by(TIGHT_TILE_SIZE as usize) {
+                        let tile = Rect {
+                            x,
+                            y,
+                            width: TIGHT_TILE_SIZE.min(rect.x + rect.width - x),
+                            height: TIGHT_TILE_SIZE.min(rect.y + rect.height - y),
+                        };
+                        self.encode_tight_tile(format, screen, tile, out);
+                        count += 1;
+                    }
+                }
+                count
+            }
+        }
+    }
+
+    fn encode_tight_tile(
+        &mut self,
+        format: &PixelFormat,
+        screen: &Screen,
+        tile: Rect,
+        out: &mut Vec<u8>,
+    ) {
+        let write_pixel = |pixel: u32, out: &mut Vec<u8>| {
+            if format.is_tight_compact() {
+                out.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+            } else {
+                format.write_pixel(pixel, out);
+            }
+        };
+
+        tile.write_header(ENCODING_TIGHT, out);
+        let first = screen.pixel(tile.x, tile.y);
+        if screen
+            .pixels(tile)
+            .all(|p| p & 0xffffff == first & 0xffffff)
+        {
+            out.push(TIGHT_FILL);
+            write_pixel(first, out);
+            return;
+        }
+
+        out.push(TIGHT_BASIC_STREAM0);
+        self.scratch.clear();
+        for pixel in screen.pixels(tile) {
+            write_pixel(pixel, &mut self.scratch);
+        }
+        if self.scratch.len() < TIGHT_MIN_TO_COMPRESS {
+            out.extend_from_slice(&self.scratch);
+            return;
+        }
+
+        let mut compressed = Vec::new();
+        compress(&mut self.tight, &self.scratch, &mut compressed);
+        // The length is sent in a compact form of 7 bits per byte.
+        let mut len = compressed.len();
+        loop {
+            let byte = (len & 0x7f) as u8;
+            len >>= 7;
+            if len == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+        out.extend_from_slice(&compressed);
+    }
+}
+
+// Returns a new zlib stream, favoring speed over compression ratio.
+fn new_stream() -> Compress {
+    Compress::new(Compression::fast(), true)
+}
+
+/// Appends `data` compressed on `stream` to `out`, followed by a sync flush so that the client
+/// can decode everything sent so far.
+fn compress(stream: &mut Compress, data: &[u8], out: &mut Vec<u8>) {
+    let start = stream.total_in();
+    loop {
+        let consumed = (stream.total_in() - start) as usize;
+        out.reserve(data.len() - consumed + 64);
+        stream
+            .compress_vec(&data[consumed..], out, FlushCompress::Sync)
+            .expect("deflate stream error");
+        // The flush is complete once all the input is consumed without filling the output.
+        if (stream.total_in() - start) as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::Decompress;
+    use flate2::FlushDecompress;
+
+    use super::*;
+
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut decompress = Decompress::new(true);
+        let mut output = Vec::with_capacity(1 << 20);
+        decompress
+            .decompress_vec(stream, &mut output, FlushDecompress::Sync)
+            .unwrap();
+        assert_eq!(decompress.total_in() as usize, stream.len());
+        output
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut data: Vec<u8> = (0..1000u32).map(|i| (i * i % 251) as u8).collect();
+        data.extend(std::iter::repeat(0x42).take(5000));
+        data.extend((0..300u32).flat_map(|i| [0, 0xff, i as u8, 0x80]));
+
+        let mut deflater = new_stream();
+        let mut stream = Vec::new();
+        compress(&mut deflater, &data, &mut stream);
+        assert!(stream.len() < data.len() / 2);
+        assert_eq!(&stream[stream.len() - 4..], &[0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(inflate(&stream), data);
+
+        // Later rectangles continue the same stream.
+        compress(&mut deflater, b"crosvm", &mut stream);
+        data.extend_from_slice(b"crosvm");
+        assert_eq!(inflate(&stream), data);
+    }
+
+    #[test]
+    fn tight_fill() {
+        let pixels: Vec<u8> = std::iter::repeat([0x30, 0x20, 0x10, 0x00])
+            .take(16)
+            .flatten()
+            .collect();
+        let screen = Screen {
+            pixels: &pixels,
+            width: 4,
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 4,
+        };
+        let mut out = Vec::new();
+        let count = Encoder::new().encode(
+            Encoding::Tight,
+            &PixelFormat::XRGB8888,
+            &screen,
+            rect,
+            &mut out,
+        );
+        assert_eq!(count, 1);
+        assert_eq!(&out[12..], &[TIGHT_FILL, 0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn tight_tiles() {
+        let width = 300u16;
+        let height = 2u16;
+        let pixels: Vec<u8> = (0..width as u32 * height as u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let screen = Screen {
+            pixels: &pixels,
+            width,
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut out = Vec::new();
+        let count = Encoder::new().encode(
+            Encoding::Tight,
+            &PixelFormat::XRGB8888,
+            &screen,
+            rect,
+            &mut out,
+        );
+        assert_eq!(count, 3);
+        // The first tile is 128 pixels wide.
+        assert_eq!(&out[..12], &[0, 0, 0, 0, 0, 128, 0, 2, 0, 0, 0, 7]);
+        assert_eq!(out[12], TIGHT_BASIC_STREAM0);
+    }
+
+    #[test]
+    fn choose_encoding() {
+        assert_eq!(Encoding::choose(&[-223, 7, 6, 0]), Encoding::Tight);
+        assert_eq!(Encoding::choose(&[16, 6, 7]), Encoding::Zlib);
+        assert_eq!(Encoding::choose(&[]), Encoding::Raw);
+    }
+}
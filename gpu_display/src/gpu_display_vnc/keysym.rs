@@ -0,0 +1,133 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translation of the X keysyms sent by VNC clients into Linux keycodes.
+
+use linux_input_sys::constants::*;
+
+/// Returns the key of a US keyboard layout that produces `keysym`. Shifted symbols map to the same
+/// key as their unshifted counterpart, since clients also send the state of the shift keys.
+pub fn keysym_to_linux_keycode(keysym: u32) -> Option<u16> {
+    // Latin-1 keysyms are the same as the characters they produce.
+    if let Some(c) = char::from_u32(keysym).filter(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return ascii_to_linux_keycode(c.to_ascii_lowercase());
+    }
+
+    let keycode = match keysym {
+        0xff08 => KEY_BACKSPACE,
+        0xff09 | 0xfe20 => KEY_TAB,
+        0xff0d => KEY_ENTER,
+        0xff13 => KEY_PAUSE,
+        0xff14 => KEY_SCROLLLOCK,
+        0xff15 => KEY_SYSRQ,
+        0xff1b => KEY_ESC,
+        0xff50 => KEY_HOME,
+        0xff51 => KEY_LEFT,
+        0xff52 => KEY_UP,
+        0xff53 => KEY_RIGHT,
+        0xff54 => KEY_DOWN,
+        0xff55 => KEY_PAGEUP,
+        0xff56 => KEY_PAGEDOWN,
+        0xff57 => KEY_END,
+        0xff61 => KEY_PRINT,
+        0xff63 => KEY_INSERT,
+        0xff67 => KEY_MENU,
+        0xff7f => KEY_NUMLOCK,
+        0xff8d => KEY_KPENTER,
+        0xff95 | 0xffb7 => KEY_KP7,
+        0xff96 | 0xffb4 => KEY_KP4,
+        0xff97 | 0xffb8 => KEY_KP8,
+        0xff98 | 0xffb6 => KEY_KP6,
+        0xff99 | 0xffb2 => KEY_KP2,
+        0xff9a | 0xffb9 => KEY_KP9,
+        0xff9b | 0xffb3 => KEY_KP3,
+        0xff9c | 0xffb1 => KEY_KP1,
+        0xff9d | 0xffb5 => KEY_KP5,
+        0xff9e | 0xffb0 => KEY_KP0,
+        0xff9f | 0xffae => KEY_KPDOT,
+        0xffaa => KEY_KPASTERISK,
+        0xffab => KEY_KPPLUS,
+        0xffad => KEY_KPMINUS,
+        0xffaf => KEY_KPSLASH,
+        0xffbe => KEY_F1,
+        0xffbf => KEY_F2,
+        0xffc0 => KEY_F3,
+        0xffc1 => KEY_F4,
+        0xffc2 => KEY_F5,
+        0xffc3 => KEY_F6,
+        0xffc4 => KEY_F7,
+        0xffc5 => KEY_F8,
+        0xffc6 => KEY_F9,
+        0xffc7 => KEY_F10,
+        0xffc8 => KEY_F11,
+        0xffc9 => KEY_F12,
+        0xffe1 => KEY_LEFTSHIFT,
+        0xffe2 => KEY_RIGHTSHIFT,
+        0xffe3 => KEY_LEFTCTRL,
+        0xffe4 => KEY_RIGHTCTRL,
+        0xffe5 => KEY_CAPSLOCK,
+        0xffe7 | 0xffeb => KEY_LEFTMETA,
+        0xffe8 | 0xffec => KEY_RIGHTMETA,
+        0xffe9 => KEY_LEFTALT,
+        0xffea | 0xfe03 => KEY_RIGHTALT,
+        0xffff => KEY_DELETE,
+        _ => return None,
+    };
+    Some(keycode)
+}
+
+fn ascii_to_linux_keycode(c: char) -> Option<u16> {
+    let keycode = match c {
+        ' ' => KEY_SPACE,
+        '1' | '!' => KEY_1,
+        '2' | '@' => KEY_2,
+        '3' | '#' => KEY_3,
+        '4' | '$' => KEY_4,
+        '5' | '%' => KEY_5,
+        '6' | '^' => KEY_6,
+        '7' | '&' => KEY_7,
+        '8' | '*' => KEY_8,
+        '9' | '(' => KEY_9,
+        '0' | ')' => KEY_0,
+        '-' | '_' => KEY_MINUS,
+        '=' | '+' => KEY_EQUAL,
+        '[' | '{' => KEY_LEFTBRACE,
+        ']' | '}' => KEY_RIGHTBRACE,
+        ';' | ':' => KEY_SEMICOLON,
+        '\'' | '"' => KEY_APOSTROPHE,
+        '`' | '~' => KEY_GRAVE,
+        '\\' | '|' => KEY_BACKSLASH,
+        ',' | '<' => KEY_COMMA,
+        '.' | '>' => KEY_DOT,
+        '/' | '?' => KEY_SLASH,
+        'a' => KEY_A,
+        'b' => KEY_B,
+        'c' => KEY_C,
+        'd' => KEY_D,
+        'e' => KEY_E,
+        'f' => KEY_F,
+        'g' => KEY_G,
+        'h' => KEY_H,
+        'i' => KEY_I,
+        'j' => KEY_J,
+        'k' => KEY_K,
+        'l' => KEY_L,
+        'm' => KEY_M,
+        'n' => KEY_N,
+        'o' => KEY_O,
+        'p' => KEY_P,
+        'q' => KEY_Q,
+        'r' => KEY_R,
+        's' => KEY_S,
+        't' => KEY_T,
+        'u' => KEY_U,
+        'v' => KEY_V,
+        'w' => KEY_W,
+        'x' => KEY_X,
+        'y' => KEY_Y,
+        'z' => KEY_Z,
+        _ => return None,
+    };
+    Some(keycode)
+}
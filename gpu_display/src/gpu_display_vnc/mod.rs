@@ -0,0 +1,662 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display that serves its scanout to VNC clients over the Remote Framebuffer protocol, and
+//! forwards their keyboard and pointer input to the guest.
+//!
+//! The server is driven by the display's wait context like the other backends: clients are
+//! serviced from `DisplayT::flush`, and framebuffer updates are queued when the scanout surface is
+//! flipped. Sockets are non-blocking so a slow client never stalls the GPU device.
+
+mod encoding;
+mod keysym;
+mod protocol;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+use data_model::VolatileSlice;
+use linux_input_sys::virtio_input_event;
+
+use self::encoding::Encoder;
+use self::encoding::Encoding;
+use self::encoding::Screen;
+use self::keysym::keysym_to_linux_keycode;
+use self::protocol::parse_client_message;
+use self::protocol::parse_version;
+use self::protocol::write_server_init;
+use self::protocol::write_update_header;
+use self::protocol::ClientMessage;
+use self::protocol::PixelFormat;
+use self::protocol::Rect;
+use self::protocol::Version;
+use self::protocol::ENCODING_DESKTOP_SIZE;
+use self::protocol::SECURITY_TYPE_NONE;
+use self::protocol::SERVER_VERSION;
+use self::protocol::VERSION_LEN;
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayError;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+const DESKTOP_NAME: &str = "crosvm";
+const BYTES_PER_PIXEL: u32 = 4;
+// Size of the screen shown to clients until the guest sets up a scanout.
+const DEFAULT_WIDTH: u16 = 640;
+const DEFAULT_HEIGHT: u16 = 480;
+
+#[derive(EventToken)]
+enum VncToken {
+    Listener,
+    Client { id: u32 },
+}
+
+/// The last frame flipped on the scanout surface.
+struct Framebuffer {
+    width: u16,
+    height: u16,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    fn new(width: u16, height: u16) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * BYTES_PER_PIXEL as usize],
+        }
+    }
+
+    /// Replaces the contents of the framebuffer with `pixels`, and returns the area that changed.
+    fn update(&mut self, pixels: &[u8]) -> Rect {
+        let stride = self.width as usize * BYTES_PER_PIXEL as usize;
+        let mut damage = Rect::default();
+        for (y, (old, new)) in self
+            .pixels
+            .chunks_exact(stride)
+            .zip(pixels.chunks_exact(stride))
+            .enumerate()
+        {
+            if old == new {
+                continue;
+            }
+            let pixel_differs = |x: &usize| {
+                let offset = x * BYTES_PER_PIXEL as usize;
+                old[offset..offset + BYTES_PER_PIXEL as usize]
+                    != new[offset..offset + BYTES_PER_PIXEL as usize]
+            };
+            let first = (0..self.width as usize).find(pixel_differs).unwrap_or(0);
+            let last = (0..self.width as usize)
+                .rev()
+                .find(pixel_differs)
+                .unwrap_or(first);
+            damage = damage.union(&Rect {
+                x: first as u16,
+                y: y as u16,
+                width: (last - first + 1) as u16,
+                height: 1,
+            });
+        }
+        self.pixels.copy_from_slice(pixels);
+        damage
+    }
+
+    fn full(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+enum ClientState {
+    Version,
+    SecurityType,
+    ClientInit,
+    Running,
+}
+
+struct Client {
+    stream: TcpStream,
+    state: ClientState,
+    version: Version,
+    rx: Vec<u8>,
+    tx: Vec<u8>,
+    watching_writes: bool,
+    format: PixelFormat,
+    encoding: Encoding,
+    encoder: Encoder,
+    desktop_size: bool,
+    // Size of the screen as known by the client.
+    width: u16,
+    height: u16,
+    update_requested: bool,
+    damage: Rect,
+}
+
+impl Client {
+    fn new(stream: TcpStream) -> Client {
+        Client {
+            stream,
+            state: ClientState::Version,
+            version: Version::V3_8,
+            rx: Vec::new(),
+            tx: SERVER_VERSION.to_vec(),
+            watching_writes: false,
+            format: PixelFormat::XRGB8888,
+            encoding: Encoding::Raw,
+            encoder: Encoder::new(),
+            desktop_size: false,
+            width: 0,
+            height: 0,
+            update_requested: false,
+            damage: Rect::default(),
+        }
+    }
+
+    /// Reads everything the client has sent so far.
+    fn read(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => bail!("connection closed"),
+                Ok(len) => self.rx.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to read from client"),
+            }
+        }
+    }
+
+    /// Writes as much of the queued output as the socket accepts.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        while !self.tx.is_empty() {
+            match self.stream.write(&self.tx) {
+                Ok(len) => {
+                    self.tx.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context("failed to write to client"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the messages received from the client, appending its input events to `input`.
+    fn process(
+        &mut self,
+        framebuffer: &Framebuffer,
+        input: &mut Vec<ClientMessage>,
+    ) -> anyhow::Result<()> {
+        loop {
+            match self.state {
+                ClientState::Version => {
+                    let version = match self.rx.get(..VERSION_LEN) {
+                        Some(v) => parse_version(v.try_into().unwrap())?,
+                        None => return Ok(()),
+                    };
+                    self.rx.drain(..VERSION_LEN);
+                    self.version = version;
+                    if version == Version::V3_3 {
+                        // The server picks the security type in version 3.3.
+                        self.tx
+                            .extend_from_slice(&(SECURITY_TYPE_NONE as u32).to_be_bytes());
+                        self.state = ClientState::ClientInit;
+                    } else {
+                        self.tx.extend_from_slice(&[1, SECURITY_TYPE_NONE]);
+                        self.state = ClientState::SecurityType;
+                    }
+                }
+                ClientState::SecurityType => {
+                    let security_type = match self.rx.first() {
+                        Some(t) => *t,
+                        None => return Ok(()),
+                    };
+                    self.rx.drain(..1);
+                    if security_type != SECURITY_TYPE_NONE {
+                        bail!("unsupported security type {}", security_type);
+                    }
+                    if self.version == Version::V3_8 {
+                        // SecurityResult: OK.
+                        self.tx.extend_from_slice(&0u32.to_be_bytes());
+                    }
+                    self.state = ClientState::ClientInit;
+                }
+                ClientState::ClientInit => {
+                    // The shared flag is ignored, all clients share the display.
+                    if self.rx.is_empty() {
+                        return Ok(());
+                    }
+                    self.rx.drain(..1);
+                    write_server_init(
+                        framebuffer.width,
+                        framebuffer.height,
+                        DESKTOP_NAME,
+                        &mut self.tx,
+                    );
+                    self.width = framebuffer.width;
+                    self.height = framebuffer.height;
+                    self.state = ClientState::Running;
+                }
+                ClientState::Running => {
+                    let (message, len) = match parse_client_message(&self.rx)? {
+                        Some(m) => m,
+                        None => return Ok(()),
+                    };
+                    self.rx.drain(..len);
+                    match message {
+                        ClientMessage::SetPixelFormat(format) => self.format = format,
+                        ClientMessage::SetEncodings(encodings) => {
+                            self.encoding = Encoding::choose(&encodings);
+                            self.desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE);
+                        }
+                        ClientMessage::FramebufferUpdateRequest { incremental, rect } => {
+                            self.update_requested = true;
+                            if !incremental {
+                                self.damage = self
+                                    .damage
+                                    .union(&rect.clip(framebuffer.width, framebuffer.height));
+                            }
+                        }
+                        ClientMessage::CutText => {}
+                        m => input.push(m),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues a framebuffer update if the client asked for one and something changed since the
+    /// last one.
+    fn queue_update(&mut self, framebuffer: &Framebuffer) {
+        if !matches!(self.state, ClientState::Running) || !self.update_requested {
+            return;
+        }
+        // Only one update is queued at a time, so slow clients skip frames instead of falling
+        // behind.
+        if !self.tx.is_empty() {
+            return;
+        }
+
+        let mut num_rects = 0;
+        let mut rects = Vec::new();
+        if (self.width, self.height) != (framebuffer.width, framebuffer.height) && self.desktop_size
+        {
+            framebuffer
+                .full()
+                .write_header(ENCODING_DESKTOP_SIZE, &mut rects);
+            num_rects += 1;
+            self.width = framebuffer.width;
+            self.height = framebuffer.height;
+            self.damage = framebuffer.full();
+        }
+
+        // Clients that cannot be resized only get the part of the screen they know about.
+        let damage = self.damage.clip(
+            self.width.min(framebuffer.width),
+            self.height.min(framebuffer.height),
+        );
+        if !damage.is_empty() {
+            let screen = Screen {
+                pixels: &framebuffer.pixels,
+                width: framebuffer.width,
+            };
+            num_rects +=
+                self.encoder
+                    .encode(self.encoding, &self.format, &screen, damage, &mut rects);
+        }
+        if num_rects == 0 {
+            return;
+        }
+
+        write_update_header(num_rects, &mut self.tx);
+        self.tx.extend_from_slice(&rects);
+        self.update_requested = false;
+        self.damage = Rect::default();
+    }
+}
+
+struct VncServer {
+    wait_ctx: WaitContext<VncToken>,
+    listener: TcpListener,
+    clients: BTreeMap<u32, Client>,
+    next_client_id: u32,
+    framebuffer: Framebuffer,
+    scanout_surface_id: Option<u32>,
+    input: VecDeque<GpuDisplayEvents>,
+    touching: bool,
+    tracking_id: i32,
+}
+
+impl VncServer {
+    fn accept_clients(&mut self) {
+        loop {
+            let (stream, address) = match self.listener.accept() {
+                Ok(client) => client,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("failed to accept VNC client: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("failed to set up VNC client {}: {}", address, e);
+                continue;
+            }
+            let id = self.next_client_id;
+            if let Err(e) = self.wait_ctx.add(&stream, VncToken::Client { id }) {
+                error!(
+                    "failed to add VNC client {} to wait context: {}",
+                    address, e
+                );
+                continue;
+            }
+            self.next_client_id += 1;
+            info!("VNC client {} connected from {}", id, address);
+            self.clients.insert(id, Client::new(stream));
+            if let Err(e) = self.flush_client(id) {
+                info!("VNC client {} disconnected: {:#}", id, e);
+                self.disconnect(id);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, id: u32) {
+        if let Some(client) = self.clients.remove(&id) {
+            if let Err(e) = self.wait_ctx.delete(&client.stream) {
+                error!("failed to remove VNC client from wait context: {}", e);
+            }
+        }
+    }
+
+    /// Sends the pending output of client `id`, waiting for the socket to become writable if it
+    /// does not fit.
+    fn flush_client(&mut self, id: u32) -> anyhow::Result<()> {
+        let client = match self.clients.get_mut(&id) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        client.queue_update(&self.framebuffer);
+        client.flush()?;
+        let watch_writes = !client.tx.is_empty();
+        if watch_writes != client.watching_writes {
+            let event_type = if watch_writes {
+                EventType::ReadWrite
+            } else {
+                EventType::Read
+            };
+            self.wait_ctx
+                .modify(&client.stream, event_type, VncToken::Client { id })
+                .context("failed to modify wait context")?;
+            client.watching_writes = watch_writes;
+        }
+        Ok(())
+    }
+
+    fn service_client(&mut self, id: u32, readable: bool) -> anyhow::Result<()> {
+        let mut messages = Vec::new();
+        if let Some(client) = self.clients.get_mut(&id) {
+            if readable {
+                client.read()?;
+            }
+            client.process(&self.framebuffer, &mut messages)?;
+        }
+        self.flush_client(id)?;
+        for message in messages {
+            self.queue_input(message);
+        }
+        Ok(())
+    }
+
+    /// Services the listener and all clients that are ready.
+    fn service(&mut self) {
+        let events = match self.wait_ctx.wait_timeout(Duration::ZERO) {
+            Ok(events) => events,
+            Err(e) => {
+                error!("failed to wait for VNC clients: {}", e);
+                return;
+            }
+        };
+        for event in events {
+            match event.token {
+                VncToken::Listener => self.accept_clients(),
+                VncToken::Client { id } => {
+                    let readable = event.is_readable || event.is_hungup;
+                    if let Err(e) = self.service_client(id, readable) {
+                        info!("VNC client {} disconnected: {:#}", id, e);
+                        self.disconnect(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Translates client input into events for the keyboard and touchscreen event devices. The
+    /// pointer is reported as a single touch while its first button is pressed.
+    fn queue_input(&mut self, message: ClientMessage) {
+        let (events, device_type) = match message {
+            ClientMessage::KeyEvent { down, keysym } => match keysym_to_linux_keycode(keysym) {
+                Some(keycode) => (
+                    vec![virtio_input_event::key(keycode, down)],
+                    EventDeviceKind::Keyboard,
+                ),
+                None => {
+                    info!("ignoring unknown VNC keysym {:#x}", keysym);
+                    return;
+                }
+            },
+            ClientMessage::PointerEvent { buttons, x, y } => {
+                let pressed = buttons & 1 != 0;
+                let mut events = vec![virtio_input_event::multitouch_slot(0)];
+                match (self.touching, pressed) {
+                    (false, false) => return,
+                    (true, false) => events.push(virtio_input_event::multitouch_tracking_id(-1)),
+                    (touching, true) => {
+                        if !touching {
+                            events
+                                .push(virtio_input_event::multitouch_tracking_id(self.tracking_id));
+                            self.tracking_id = self.tracking_id.wrapping_add(1) & i32::MAX;
+                        }
+                        events.push(virtio_input_event::multitouch_absolute_x(x as i32));
+                        events.push(virtio_input_event::multitouch_absolute_y(y as i32));
+                    }
+                }
+                self.touching = pressed;
+                (events, EventDeviceKind::Touchscreen)
+            }
+            _ => return,
+        };
+        self.input.push_back(GpuDisplayEvents {
+            events,
+            device_type,
+        });
+    }
+
+    /// Makes surface `surface_id` the one shown to clients.
+    fn set_scanout(&mut self, surface_id: u32, width: u16, height: u16) {
+        self.scanout_surface_id = Some(surface_id);
+        if (width, height) != (self.framebuffer.width, self.framebuffer.height) {
+            self.framebuffer = Framebuffer::new(width, height);
+        }
+        self.damage_clients(self.framebuffer.full());
+    }
+
+    fn update_screen(&mut self, surface_id: u32, pixels: &[u8]) {
+        if self.scanout_surface_id != Some(surface_id) {
+            return;
+        }
+        let damage = self.framebuffer.update(pixels);
+        if !damage.is_empty() {
+            self.damage_clients(damage);
+        }
+    }
+
+    fn damage_clients(&mut self, damage: Rect) {
+        let ids: Vec<u32> = self.clients.keys().copied().collect();
+        for id in ids {
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.damage = client.damage.union(&damage);
+            }
+            if let Err(e) = self.flush_client(id) {
+                info!("VNC client {} disconnected: {:#}", id, e);
+                self.disconnect(id);
+            }
+        }
+    }
+}
+
+struct VncSurface {
+    surface_id: u32,
+    width: u32,
+    buffer: Vec<u8>,
+    server: Rc<RefCell<VncServer>>,
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(&mut self.buffer),
+            self.width * BYTES_PER_PIXEL,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn flip(&mut self) {
+        self.server
+            .borrow_mut()
+            .update_screen(self.surface_id, &self.buffer);
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        let mut server = self.server.borrow_mut();
+        if server.scanout_surface_id == Some(self.surface_id) {
+            // Clients keep seeing the last frame until another scanout is set up.
+            server.scanout_surface_id = None;
+        }
+    }
+}
+
+/// A display backend serving the first scanout to VNC clients.
+pub struct DisplayVnc {
+    server: Rc<RefCell<VncServer>>,
+    current_event: Option<GpuDisplayEvents>,
+}
+
+impl DisplayVnc {
+    /// Serves VNC clients connecting to `listener`.
+    pub fn new(listener: TcpListener) -> GpuDisplayResult<DisplayVnc> {
+        listener.set_nonblocking(true)?;
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&listener, VncToken::Listener)?;
+
+        let server = VncServer {
+            wait_ctx,
+            listener,
+            clients: BTreeMap::new(),
+            next_client_id: 1,
+            framebuffer: Framebuffer::new(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            scanout_surface_id: None,
+            input: VecDeque::new(),
+            touching: false,
+            tracking_id: 0,
+        };
+        Ok(DisplayVnc {
+            server: Rc::new(RefCell::new(server)),
+            current_event: None,
+        })
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        !self.server.borrow().input.is_empty()
+    }
+
+    // Clients are serviced once each time the display is dispatched, which queues their input.
+    fn flush(&self) {
+        self.server.borrow_mut().service();
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        let mut server = self.server.borrow_mut();
+        self.current_event = server.input.pop_front();
+        // Input is only delivered while there is a scanout surface to match it with.
+        Ok(server.scanout_surface_id.unwrap_or(0) as u64)
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        self.current_event.take()
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+        let (screen_width, screen_height) = match (u16::try_from(width), u16::try_from(height)) {
+            (Ok(w), Ok(h)) => (w, h),
+            _ => return Err(GpuDisplayError::Unsupported),
+        };
+
+        let mut server = self.server.borrow_mut();
+        if surf_type == SurfaceType::Scanout && server.scanout_surface_id.is_none() {
+            server.set_scanout(surface_id, screen_width, screen_height);
+        }
+
+        Ok(Box::new(VncSurface {
+            surface_id,
+            width,
+            buffer: vec![0; width as usize * height as usize * BYTES_PER_PIXEL as usize],
+            server: self.server.clone(),
+        }))
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.server.borrow().wait_ctx.as_raw_descriptor()
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Messages of the Remote Framebuffer protocol (RFC 6143).
+
+use remain::sorted;
+use thiserror::Error;
+
+/// Version sent by the server at the start of the handshake.
+pub const SERVER_VERSION: &[u8; 12] = b"RFB 003.008\n";
+/// Length of the version sent back by the client.
+pub const VERSION_LEN: usize = 12;
+
+pub const SECURITY_TYPE_NONE: u8 = 1;
+
+pub const ENCODING_RAW: i32 = 0;
+pub const ENCODING_ZLIB: i32 = 6;
+pub const ENCODING_TIGHT: i32 = 7;
+pub const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+const CLIENT_SET_PIXEL_FORMAT: u8 = 0;
+const CLIENT_SET_ENCODINGS: u8 = 2;
+const CLIENT_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const CLIENT_KEY_EVENT: u8 = 4;
+const CLIENT_POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+const SERVER_FRAMEBUFFER_UPDATE: u8 = 0;
+
+// Clipboard contents are not used, so only put a bound on how much of it is buffered.
+const MAX_CUT_TEXT_LEN: u32 = 1 << 20;
+
+#[sorted]
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("color maps are not supported")]
+    ColorMapUnsupported,
+    #[error("cut text of {0} bytes is too long")]
+    CutTextTooLong(u32),
+    #[error("unsupported bits per pixel: {0}")]
+    InvalidBitsPerPixel(u8),
+    #[error("unknown client message type {0}")]
+    UnknownMessage(u8),
+    #[error("unsupported protocol version {0:?}")]
+    UnsupportedVersion(String),
+}
+
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
+/// Minor version of the protocol negotiated with a client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Version {
+    V3_3,
+    V3_7,
+    V3_8,
+}
+
+/// Parses the `ProtocolVersion` message sent by the client.
+pub fn parse_version(buf: &[u8; VERSION_LEN]) -> Result<Version> {
+    match buf {
+        b"RFB 003.003\n" => Ok(Version::V3_3),
+        b"RFB 003.007\n" => Ok(Version::V3_7),
+        b"RFB 003.008\n" => Ok(Version::V3_8),
+        // Some clients announce non-standard minor versions, which must be treated as 3.3.
+        _ if buf.starts_with(b"RFB 003.") => Ok(Version::V3_3),
+        _ => Err(ProtocolError::UnsupportedVersion(
+            String::from_utf8_lossy(buf).trim_end().to_string(),
+        )),
+    }
+}
+
+/// Describes how pixel values are laid out on the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The format of the XRGB8888 framebuffers of the display, which clients get by default.
+    pub const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(b: &[u8]) -> Result<PixelFormat> {
+        let true_color = b[3] != 0;
+        if !true_color {
+            return Err(ProtocolError::ColorMapUnsupported);
+        }
+        let format = PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        };
+        match format.bits_per_pixel {
+            8 | 16 | 32 => Ok(format),
+            bpp => Err(ProtocolError::InvalidBitsPerPixel(bpp)),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian as u8,
+            1, // true color
+        ]);
+        out.extend_from_slice(&self.red_max.to_be_bytes());
+        out.extend_from_slice(&self.green_max.to_be_bytes());
+        out.extend_from_slice(&self.blue_max.to_be_bytes());
+        out.extend_from_slice(&[self.red_shift, self.green_shift, self.blue_shift, 0, 0, 0]);
+    }
+
+    /// Whether pixels can be sent as 3 byte TPIXELs in the tight encoding.
+    pub fn is_tight_compact(&self) -> bool {
+        self.bits_per_pixel == 32
+            && self.depth == 24
+            && self.red_max == 255
+            && self.green_max == 255
+            && self.blue_max == 255
+    }
+
+    /// Appends the framebuffer pixel `xrgb` to `out` in this format.
+    pub fn write_pixel(&self, xrgb: u32, out: &mut Vec<u8>) {
+        let scale = |component: u32, max: u16| (component * max as u32 + 127) / 255;
+        let value = scale((xrgb >> 16) & 0xff, self.red_max) << self.red_shift
+            | scale((xrgb >> 8) & 0xff, self.green_max) << self.green_shift
+            | scale(xrgb & 0xff, self.blue_max) << self.blue_shift;
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(value as u8),
+            (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+/// A rectangle of the framebuffer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns the smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+
+    /// Returns the part of `self` that is inside a `width` by `height` screen.
+    pub fn clip(&self, width: u16, height: u16) -> Rect {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Rect {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+
+    /// Appends a rectangle header with the given `encoding` to `out`.
+    pub fn write_header(&self, encoding: i32, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.x.to_be_bytes());
+        out.extend_from_slice(&self.y.to_be_bytes());
+        out.extend_from_slice(&self.width.to_be_bytes());
+        out.extend_from_slice(&self.height.to_be_bytes());
+        out.extend_from_slice(&encoding.to_be_bytes());
+    }
+}
+
+/// A message sent by the client once the handshake is done.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest { incremental: bool, rect: Rect },
+    KeyEvent { down: bool, keysym: u32 },
+    PointerEvent { buttons: u8, x: u16, y: u16 },
+    CutText,
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// Parses the client message at the start of `buf`. Returns the message and its length, or `None`
+/// if `buf` does not hold a complete message yet.
+pub fn parse_client_message(buf: &[u8]) -> Result<Option<(ClientMessage, usize)>> {
+    let message_type = match buf.first() {
+        Some(t) => *t,
+        None => return Ok(None),
+    };
+    let len = match message_type {
+        CLIENT_SET_PIXEL_FORMAT => 20,
+        CLIENT_SET_ENCODINGS if buf.len() >= 4 => 4 + 4 * be16(buf, 2) as usize,
+        CLIENT_FRAMEBUFFER_UPDATE_REQUEST => 10,
+        CLIENT_KEY_EVENT => 8,
+        CLIENT_POINTER_EVENT => 6,
+        CLIENT_CUT_TEXT if buf.len() >= 8 => {
+            let text_len = be32(buf, 4);
+            if text_len > MAX_CUT_TEXT_LEN {
+                return Err(ProtocolError::CutTextTooLong(text_len));
+            }
+            8 + text_len as usize
+        }
+        CLIENT_SET_ENCODINGS | CLIENT_CUT_TEXT => return Ok(None),
+        t => return Err(ProtocolError::UnknownMessage(t)),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let message = match message_type {
+        CLIENT_SET_PIXEL_FORMAT => {
+            ClientMessage::SetPixelFormat(PixelFormat::from_bytes(&buf[4..])?)
+        }
+        CLIENT_SET_ENCODINGS => ClientMessage::SetEncodings(
+            buf[4..len]
+                .chunks_exact(4)
+                .map(|e| i32::from_be_bytes([e[0], e[1], e[2], e[3]]))
+                .collect(),
+        ),
+        CLIENT_FRAMEBUFFER_UPDATE_REQUEST => ClientMessage::FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            rect: Rect {
+                x: be16(buf, 2),
+                y: be16(buf, 4),
+                width: be16(buf, 6),
+                height: be16(buf, 8),
+            },
+        },
+        CLIENT_KEY_EVENT => ClientMessage::KeyEvent {
+            down: buf[1] != 0,
+            keysym: be32(buf, 4),
+        },
+        CLIENT_POINTER_EVENT => ClientMessage::PointerEvent {
+            buttons: buf[1],
+            x: be16(buf, 2),
+            y: be16(buf, 4),
+        },
+        _ => ClientMessage::CutText,
+    };
+    Ok(Some((message, len)))
+}
+
+/// Appends the `ServerInit` message to `out`.
+pub fn write_server_init(width: u16, height: u16, name: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&width.to_be_bytes());
+    out.extend_from_slice(&height.to_be_bytes());
+    PixelFormat::XRGB8888.write(out);
+    out.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+/// Appends the header of a `FramebufferUpdate` message with `num_rects` rectangles to `out`.
+pub fn write_update_header(num_rects: u16, out: &mut Vec<u8>) {
+    out.extend_from_slice(&[SERVER_FRAMEBUFFER_UPDATE, 0]);
+    out.extend_from_slice(&num_rects.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version(b"RFB 003.008\n"), Ok(Version::V3_8));
+        assert_eq!(parse_version(b"RFB 003.005\n"), Ok(Version::V3_3));
+        assert!(parse_version(b"HTTP/1.1 200").is_err());
+    }
+
+    #[test]
+    fn partial_messages() {
+        let request = [3, 1, 0, 0, 0, 0, 0, 4, 0, 2];
+        for len in 0..request.len() {
+            assert_eq!(parse_client_message(&request[..len]), Ok(None));
+        }
+        assert_eq!(
+            parse_client_message(&request),
+            Ok(Some((
+                ClientMessage::FramebufferUpdateRequest {
+                    incremental: true,
+                    rect: Rect {
+                        x: 0,
+                        y: 0,
+                        width: 4,
+                        height: 2
+                    },
+                },
+                10
+            )))
+        );
+    }
+
+    #[test]
+    fn set_encodings() {
+        let message = [2, 0, 0, 2, 0, 0, 0, 7, 0xff, 0xff, 0xff, 0x21, 5];
+        assert_eq!(
+            parse_client_message(&message),
+            Ok(Some((ClientMessage::SetEncodings(vec![7, -223]), 12)))
+        );
+    }
+
+    #[test]
+    fn rgb565() {
+        let mut message = vec![
+            0, 0, 0, 0, 16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0,
+        ];
+        let format = match parse_client_message(&message) {
+            Ok(Some((ClientMessage::SetPixelFormat(format), 20))) => format,
+            r => panic!("unexpected result {:?}", r),
+        };
+        let mut pixel = Vec::new();
+        format.write_pixel(0x00ff8000, &mut pixel);
+        assert_eq!(u16::from_le_bytes([pixel[0], pixel[1]]), 0xfc00);
+
+        // Color maps are rejected.
+        message[7] = 0;
+        assert_eq!(
+            parse_client_message(&message),
+            Err(ProtocolError::ColorMapUnsupported)
+        );
+    }
+}
@@ -43,6 +43,8 @@ pub enum HypervisorCap {
 pub enum VmCap {
     /// Track dirty pages
     DirtyLog,
+    /// Track dirty pages through per-vcpu rings rather than a bitmap
+    DirtyLogRing,
     /// Paravirtualized clock device
     PvClock,
     /// VM can be run in protected mode, where the host does not have access to its memory.
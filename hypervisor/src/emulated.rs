@@ -0,0 +1,142 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A hypervisor backend executing x86_64 guests with an instruction interpreter.
+//!
+//! It doesn't need any support from the host kernel, so it can run the hypervisor and boot tests
+//! on machines without /dev/kvm. It is orders of magnitude slower than hardware virtualization and
+//! only supports the general purpose instructions, which is enough for small bare-metal guests.
+
+use std::arch::x86_64::CpuidResult;
+use std::arch::x86_64::__cpuid;
+
+use base::Result;
+
+use crate::CpuId;
+use crate::CpuIdEntry;
+use crate::Hypervisor;
+use crate::HypervisorCap;
+use crate::HypervisorX86_64;
+use crate::MSR_IA32_TSC;
+
+mod interpreter;
+mod vcpu;
+pub use vcpu::*;
+mod vm;
+pub use vm::*;
+
+/// Number of guest physical address bits reported to the guest.
+const GUEST_PHYS_ADDR_BITS: u8 = 40;
+
+const MSR_IA32_APICBASE: u32 = 0x0000_001b;
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_FS_BASE: u32 = 0xc000_0100;
+const MSR_GS_BASE: u32 = 0xc000_0101;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+const MSR_TSC_AUX: u32 = 0xc000_0103;
+
+/// MSRs whose values are part of the vCPU state reported by `get_all_msrs`.
+const MSR_INDEX_LIST: &[u32] = &[
+    MSR_IA32_TSC,
+    MSR_IA32_APICBASE,
+    MSR_EFER,
+    MSR_FS_BASE,
+    MSR_GS_BASE,
+    MSR_KERNEL_GS_BASE,
+    MSR_TSC_AUX,
+];
+
+// CPUID leaf 1 EDX features implemented by the interpreter.
+const CPUID_1_EDX_PSE: u32 = 1 << 3;
+const CPUID_1_EDX_TSC: u32 = 1 << 4;
+const CPUID_1_EDX_MSR: u32 = 1 << 5;
+const CPUID_1_EDX_PAE: u32 = 1 << 6;
+const CPUID_1_EDX_PGE: u32 = 1 << 13;
+const CPUID_1_EDX_CMOV: u32 = 1 << 15;
+// CPUID leaf 0x80000001 EDX features implemented by the interpreter.
+const CPUID_80000001_EDX_NX: u32 = 1 << 20;
+const CPUID_80000001_EDX_LM: u32 = 1 << 29;
+
+/// A hypervisor that runs guests in the VMM process, without hardware virtualization.
+#[derive(Default)]
+pub struct Emulated {}
+
+impl Emulated {
+    pub fn new() -> Emulated {
+        Emulated {}
+    }
+}
+
+impl Hypervisor for Emulated {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Emulated {})
+    }
+
+    fn check_capability(&self, cap: HypervisorCap) -> bool {
+        // The guest reads the host TSC, it can't calibrate it against emulated timers.
+        matches!(
+            cap,
+            HypervisorCap::UserMemory
+                | HypervisorCap::ImmediateExit
+                | HypervisorCap::CalibratedTscLeafRequired
+        )
+    }
+}
+
+fn cpuid_entry(function: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdEntry {
+    CpuIdEntry {
+        function,
+        index: 0,
+        flags: 0,
+        cpuid: CpuidResult { eax, ebx, ecx, edx },
+    }
+}
+
+impl HypervisorX86_64 for Emulated {
+    fn get_supported_cpuid(&self) -> Result<CpuId> {
+        // Safe because cpuid has no side effects.
+        let (vendor, signature) = unsafe { (__cpuid(0), __cpuid(1)) };
+        let mut cpuid = CpuId::new(5);
+        cpuid.cpu_id_entries = vec![
+            cpuid_entry(0, 1, vendor.ebx, vendor.ecx, vendor.edx),
+            cpuid_entry(
+                1,
+                signature.eax,
+                0,
+                0,
+                CPUID_1_EDX_PSE
+                    | CPUID_1_EDX_TSC
+                    | CPUID_1_EDX_MSR
+                    | CPUID_1_EDX_PAE
+                    | CPUID_1_EDX_PGE
+                    | CPUID_1_EDX_CMOV,
+            ),
+            cpuid_entry(0x8000_0000, 0x8000_0008, 0, 0, 0),
+            cpuid_entry(
+                0x8000_0001,
+                0,
+                0,
+                0,
+                CPUID_80000001_EDX_NX | CPUID_80000001_EDX_LM,
+            ),
+            // 48 bits of linear address space.
+            cpuid_entry(
+                0x8000_0008,
+                (48 << 8) | GUEST_PHYS_ADDR_BITS as u32,
+                0,
+                0,
+                0,
+            ),
+        ];
+        Ok(cpuid)
+    }
+
+    fn get_emulated_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn get_msr_index_list(&self) -> Result<Vec<u32>> {
+        Ok(MSR_INDEX_LIST.to_vec())
+    }
+}
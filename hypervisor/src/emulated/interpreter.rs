@@ -0,0 +1,2785 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A small x86 instruction interpreter.
+//!
+//! It covers the general purpose subset of the instruction set used by firmware-like guests and
+//! by the hypervisor tests: real mode, 32-bit protected mode and long mode, with or without
+//! paging. Segment limits and most privilege checks are not enforced, and the FPU and SIMD
+//! instructions are not supported.
+//!
+//! Device accesses are not performed by the interpreter. Reads abort the instruction and are
+//! reported to the caller, which re-executes the instruction once the data is available. Writes
+//! complete the instruction and are reported afterwards.
+
+use std::collections::BTreeMap;
+
+use super::MSR_EFER;
+use super::MSR_FS_BASE;
+use super::MSR_GS_BASE;
+use super::MSR_KERNEL_GS_BASE;
+use super::MSR_TSC_AUX;
+use crate::CpuIdEntry;
+use crate::DebugRegs;
+use crate::Regs;
+use crate::Segment;
+use crate::Sregs;
+use crate::MSR_IA32_TSC;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+
+const FLAG_CF: u64 = 1 << 0;
+const FLAG_PF: u64 = 1 << 2;
+const FLAG_AF: u64 = 1 << 4;
+const FLAG_ZF: u64 = 1 << 6;
+const FLAG_SF: u64 = 1 << 7;
+const FLAG_TF: u64 = 1 << 8;
+const FLAG_IF: u64 = 1 << 9;
+const FLAG_DF: u64 = 1 << 10;
+const FLAG_OF: u64 = 1 << 11;
+const FLAG_IOPL: u64 = 3 << 12;
+const FLAG_NT: u64 = 1 << 14;
+const FLAG_RF: u64 = 1 << 16;
+const FLAG_VM: u64 = 1 << 17;
+const FLAG_AC: u64 = 1 << 18;
+const FLAG_ID: u64 = 1 << 21;
+// Bit 1 of RFLAGS always reads as one.
+const FLAG_FIXED: u64 = 1 << 1;
+const FLAGS_ARITH: u64 = FLAG_CF | FLAG_PF | FLAG_AF | FLAG_ZF | FLAG_SF | FLAG_OF;
+const FLAGS_WRITABLE: u64 =
+    FLAGS_ARITH | FLAG_TF | FLAG_IF | FLAG_DF | FLAG_IOPL | FLAG_NT | FLAG_RF | FLAG_AC | FLAG_ID;
+
+// Page table entry bits.
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Exception vectors.
+const DE: u8 = 0;
+const BP: u8 = 3;
+const UD: u8 = 6;
+const NP: u8 = 11;
+const SS_FAULT: u8 = 12;
+const GP: u8 = 13;
+const PF: u8 = 14;
+
+// General purpose register indexes, as encoded in instructions.
+const RAX: usize = 0;
+const RCX: usize = 1;
+const RDX: usize = 2;
+const RBX: usize = 3;
+const RSP: usize = 4;
+const RBP: usize = 5;
+const RSI: usize = 6;
+const RDI: usize = 7;
+
+// Segment register indexes, as encoded in instructions.
+const ES: usize = 0;
+const CS: usize = 1;
+const SS: usize = 2;
+const DS: usize = 3;
+const FS: usize = 4;
+const GS: usize = 5;
+
+const PAGE_SIZE: u64 = 0x1000;
+const MAX_INSTRUCTION_LEN: u64 = 15;
+
+/// Access to guest physical memory.
+pub(super) trait GuestBus {
+    /// Reads guest memory at `gpa`. Returns false if the range is not backed by memory.
+    fn read(&mut self, gpa: u64, buf: &mut [u8]) -> bool;
+    /// Writes guest memory at `gpa`. Returns false if the range is not backed by writable memory.
+    fn write(&mut self, gpa: u64, buf: &[u8]) -> bool;
+}
+
+/// The address space of a device access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum IoSpace {
+    Pio,
+    Mmio,
+}
+
+/// A device access that must be handled by the VMM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct IoAccess {
+    pub space: IoSpace,
+    pub address: u64,
+    pub size: usize,
+    /// The data written by the guest, or `None` for a read.
+    pub data: Option<[u8; 8]>,
+}
+
+/// Why the interpreter stopped executing guest instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Exit {
+    /// The guest executed `hlt`.
+    Halt,
+    /// A device access. Reads must be completed with `Cpu::complete_read` before the next step.
+    Io(IoAccess),
+    /// An exception could not be delivered.
+    Shutdown,
+    /// The instruction is not supported by the interpreter.
+    Unsupported,
+}
+
+/// The architectural state of an emulated processor.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Cpu {
+    pub regs: Regs,
+    pub sregs: Sregs,
+    pub debugregs: DebugRegs,
+    pub xcr0: u64,
+    pub tsc_offset: u64,
+    pub cpuid: Vec<CpuIdEntry>,
+    /// MSRs without dedicated architectural state.
+    pub msrs: BTreeMap<u32, u64>,
+    /// Interrupts are blocked for one instruction after `sti` or a load of SS.
+    pub interrupt_shadow: bool,
+    // Data of the device reads done by the current instruction.
+    completed_reads: Vec<(IoSpace, u64, usize, [u8; 8])>,
+}
+
+// The outcome of an instruction that did not fault.
+enum Flow {
+    Next,
+    Halt,
+}
+
+// Reasons an instruction did not complete.
+enum Fault {
+    Exception(u8, Option<u32>),
+    // A page fault at a linear address, with its error code.
+    PageAccess(u64, u32),
+    // A device read whose data is not available yet.
+    Read(IoAccess),
+    Unsupported,
+}
+
+type Res<T> = std::result::Result<T, Fault>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Rep {
+    // REP and REPE/REPZ.
+    E,
+    // REPNE/REPNZ.
+    Ne,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(usize),
+    // A segment register index and an offset in that segment.
+    Mem(usize, u64),
+}
+
+struct ModRm {
+    reg: usize,
+    operand: Operand,
+}
+
+fn host_tsc() -> u64 {
+    // Safe because rdtsc has no side effects.
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    }
+}
+
+fn sign_bit(size: usize) -> u64 {
+    1u64 << (size * 8 - 1)
+}
+
+fn sign_extend(value: u64, size: usize) -> u64 {
+    match size {
+        1 => value as u8 as i8 as u64,
+        2 => value as u16 as i16 as u64,
+        4 => value as u32 as i32 as u64,
+        _ => value,
+    }
+}
+
+fn bytes(value: u64) -> [u8; 8] {
+    value.to_le_bytes()
+}
+
+/// Builds a segment register from a code or data segment descriptor.
+fn parse_descriptor(desc: u64, selector: u16) -> Segment {
+    let limit = (desc & 0xffff) | (((desc >> 48) & 0xf) << 16);
+    let g = ((desc >> 55) & 1) as u8;
+    Segment {
+        base: ((desc >> 16) & 0xff_ffff) | (((desc >> 56) & 0xff) << 24),
+        limit: if g != 0 {
+            ((limit << 12) | 0xfff) as u32
+        } else {
+            limit as u32
+        },
+        selector,
+        type_: ((desc >> 40) & 0xf) as u8,
+        present: ((desc >> 47) & 1) as u8,
+        dpl: ((desc >> 45) & 3) as u8,
+        db: ((desc >> 54) & 1) as u8,
+        s: ((desc >> 44) & 1) as u8,
+        l: ((desc >> 53) & 1) as u8,
+        g,
+        avl: ((desc >> 52) & 1) as u8,
+    }
+}
+
+impl Cpu {
+    /// Executes a single instruction.
+    ///
+    /// Returns `None` if the instruction completed without requiring the attention of the VMM.
+    pub fn step(&mut self, bus: &mut dyn GuestBus) -> Option<Exit> {
+        let regs = self.regs;
+        let sregs = self.sregs;
+        let shadow = self.interrupt_shadow;
+        self.interrupt_shadow = false;
+
+        let mut exec = Exec::new(self, bus);
+        let result = exec.execute();
+        let write = exec.pending_write.take();
+        let fault = match result {
+            Ok(flow) => {
+                self.completed_reads.clear();
+                return match flow {
+                    Flow::Next => write.map(Exit::Io),
+                    Flow::Halt => Some(Exit::Halt),
+                };
+            }
+            Err(fault) => fault,
+        };
+
+        // The instruction did not complete, roll back its side effects on the registers.
+        self.regs = regs;
+        self.sregs = sregs;
+        self.interrupt_shadow = shadow;
+        match fault {
+            Fault::Read(access) => Some(Exit::Io(access)),
+            Fault::Unsupported => Some(Exit::Unsupported),
+            Fault::Exception(vector, error) => {
+                self.completed_reads.clear();
+                self.raise(bus, vector, error)
+            }
+            Fault::PageAccess(address, error) => {
+                self.completed_reads.clear();
+                self.sregs.cr2 = address;
+                self.raise(bus, PF, Some(error))
+            }
+        }
+    }
+
+    /// Provides the data of a device read reported by `step`.
+    pub fn complete_read(&mut self, space: IoSpace, address: u64, data: &[u8]) {
+        let mut buf = [0u8; 8];
+        let size = data.len().min(8);
+        buf[..size].copy_from_slice(&data[..size]);
+        self.completed_reads.push((space, address, size, buf));
+    }
+
+    /// Returns the linear address of the next instruction.
+    pub fn linear_rip(&self) -> u64 {
+        if self.sregs.efer & EFER_LMA != 0 && self.sregs.cs.l != 0 {
+            self.regs.rip
+        } else {
+            self.sregs.cs.base.wrapping_add(self.regs.rip) & 0xffff_ffff
+        }
+    }
+
+    /// Returns true if an external interrupt can be delivered.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.regs.rflags & FLAG_IF != 0 && !self.interrupt_shadow
+    }
+
+    /// Delivers an external interrupt or an NMI.
+    pub fn interrupt(&mut self, bus: &mut dyn GuestBus, vector: u8) -> Option<Exit> {
+        self.raise(bus, vector, None)
+    }
+
+    fn raise(&mut self, bus: &mut dyn GuestBus, vector: u8, error: Option<u32>) -> Option<Exit> {
+        let regs = self.regs;
+        let sregs = self.sregs;
+        let mut exec = Exec::new(self, bus);
+        if exec.deliver(vector, error).is_ok() && exec.pending_write.is_none() {
+            return None;
+        }
+        // Faults while delivering an exception are not handled, treat them like a triple fault.
+        self.regs = regs;
+        self.sregs = sregs;
+        Some(Exit::Shutdown)
+    }
+
+    /// Reads an MSR.
+    pub fn read_msr(&self, index: u32) -> u64 {
+        match index {
+            MSR_IA32_TSC => host_tsc().wrapping_add(self.tsc_offset),
+            MSR_EFER => self.sregs.efer,
+            MSR_FS_BASE => self.sregs.fs.base,
+            MSR_GS_BASE => self.sregs.gs.base,
+            _ => self.msrs.get(&index).copied().unwrap_or(0),
+        }
+    }
+
+    /// Writes an MSR.
+    pub fn write_msr(&mut self, index: u32, value: u64) {
+        match index {
+            MSR_IA32_TSC => self.tsc_offset = value.wrapping_sub(host_tsc()),
+            MSR_EFER => self.sregs.efer = value,
+            MSR_FS_BASE => self.sregs.fs.base = value,
+            MSR_GS_BASE => self.sregs.gs.base = value,
+            _ => {
+                self.msrs.insert(index, value);
+            }
+        }
+    }
+
+    /// Returns the result of the `cpuid` instruction for `function` and `index`.
+    pub fn cpuid(&self, function: u32, index: u32) -> [u32; 4] {
+        // KVM_CPUID_FLAG_SIGNIFCANT_INDEX
+        const SIGNIFICANT_INDEX: u32 = 1;
+        self.cpuid
+            .iter()
+            .find(|e| {
+                e.function == function && (e.flags & SIGNIFICANT_INDEX == 0 || e.index == index)
+            })
+            .map(|e| [e.cpuid.eax, e.cpuid.ebx, e.cpuid.ecx, e.cpuid.edx])
+            .unwrap_or_default()
+    }
+
+    fn gpr(&self, index: usize) -> u64 {
+        let r = &self.regs;
+        match index {
+            0 => r.rax,
+            1 => r.rcx,
+            2 => r.rdx,
+            3 => r.rbx,
+            4 => r.rsp,
+            5 => r.rbp,
+            6 => r.rsi,
+            7 => r.rdi,
+            8 => r.r8,
+            9 => r.r9,
+            10 => r.r10,
+            11 => r.r11,
+            12 => r.r12,
+            13 => r.r13,
+            14 => r.r14,
+            _ => r.r15,
+        }
+    }
+
+    fn gpr_mut(&mut self, index: usize) -> &mut u64 {
+        let r = &mut self.regs;
+        match index {
+            0 => &mut r.rax,
+            1 => &mut r.rcx,
+            2 => &mut r.rdx,
+            3 => &mut r.rbx,
+            4 => &mut r.rsp,
+            5 => &mut r.rbp,
+            6 => &mut r.rsi,
+            7 => &mut r.rdi,
+            8 => &mut r.r8,
+            9 => &mut r.r9,
+            10 => &mut r.r10,
+            11 => &mut r.r11,
+            12 => &mut r.r12,
+            13 => &mut r.r13,
+            14 => &mut r.r14,
+            _ => &mut r.r15,
+        }
+    }
+
+    fn segment(&self, index: usize) -> &Segment {
+        let s = &self.sregs;
+        match index {
+            ES => &s.es,
+            CS => &s.cs,
+            SS => &s.ss,
+            DS => &s.ds,
+            FS => &s.fs,
+            _ => &s.gs,
+        }
+    }
+
+    fn segment_mut(&mut self, index: usize) -> &mut Segment {
+        let s = &mut self.sregs;
+        match index {
+            ES => &mut s.es,
+            CS => &mut s.cs,
+            SS => &mut s.ss,
+            DS => &mut s.ds,
+            FS => &mut s.fs,
+            _ => &mut s.gs,
+        }
+    }
+}
+
+// The state of the instruction being executed.
+struct Exec<'a> {
+    cpu: &'a mut Cpu,
+    bus: &'a mut dyn GuestBus,
+    // The device write done by the instruction, at most one is supported.
+    pending_write: Option<IoAccess>,
+    start_rip: u64,
+    seg_override: Option<usize>,
+    rep: Option<Rep>,
+    rex: u8,
+    opsize: usize,
+    addrsize: usize,
+}
+
+impl<'a> Exec<'a> {
+    fn new(cpu: &'a mut Cpu, bus: &'a mut dyn GuestBus) -> Exec<'a> {
+        Exec {
+            start_rip: cpu.regs.rip,
+            cpu,
+            bus,
+            pending_write: None,
+            seg_override: None,
+            rep: None,
+            rex: 0,
+            opsize: 4,
+            addrsize: 4,
+        }
+    }
+
+    fn protected(&self) -> bool {
+        self.cpu.sregs.cr0 & CR0_PE != 0 && self.cpu.regs.rflags & FLAG_VM == 0
+    }
+
+    fn long_mode(&self) -> bool {
+        self.cpu.sregs.efer & EFER_LMA != 0
+    }
+
+    // True when executing 64-bit code, as opposed to compatibility mode.
+    fn code64(&self) -> bool {
+        self.long_mode() && self.cpu.sregs.cs.l != 0
+    }
+
+    fn cpl(&self) -> u8 {
+        if self.protected() {
+            self.cpu.sregs.cs.dpl
+        } else {
+            0
+        }
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    // Operand size of stack operations and near branches.
+    fn stack_opsize(&self) -> usize {
+        if self.code64() {
+            if self.opsize == 2 {
+                2
+            } else {
+                8
+            }
+        } else {
+            self.opsize
+        }
+    }
+
+    // Address size of the stack pointer.
+    fn stack_size(&self) -> usize {
+        if self.code64() {
+            8
+        } else if self.cpu.sregs.ss.db != 0 {
+            4
+        } else {
+            2
+        }
+    }
+
+    fn flag(&self, flag: u64) -> bool {
+        self.cpu.regs.rflags & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u64, value: bool) {
+        if value {
+            self.cpu.regs.rflags |= flag;
+        } else {
+            self.cpu.regs.rflags &= !flag;
+        }
+    }
+
+    // Replaces the writable flags in the low `size` bytes of RFLAGS.
+    fn load_flags(&mut self, value: u64, size: usize) {
+        let writable = FLAGS_WRITABLE & mask(size);
+        self.cpu.regs.rflags = (self.cpu.regs.rflags & !writable) | (value & writable) | FLAG_FIXED;
+    }
+
+    // Reads a general purpose register, using the legacy AH..BH encoding of byte registers.
+    fn reg_read(&self, index: usize, size: usize) -> u64 {
+        if size == 1 && self.rex == 0 && (4..8).contains(&index) {
+            (self.cpu.gpr(index - 4) >> 8) & 0xff
+        } else {
+            self.cpu.gpr(index) & mask(size)
+        }
+    }
+
+    fn reg_write(&mut self, index: usize, size: usize, value: u64) {
+        if size == 1 && self.rex == 0 && (4..8).contains(&index) {
+            let r = self.cpu.gpr_mut(index - 4);
+            *r = (*r & !0xff00) | ((value & 0xff) << 8);
+        } else {
+            self.gpr_write(index, size, value);
+        }
+    }
+
+    fn gpr_write(&mut self, index: usize, size: usize, value: u64) {
+        let r = self.cpu.gpr_mut(index);
+        *r = match size {
+            1 => (*r & !0xff) | (value & 0xff),
+            2 => (*r & !0xffff) | (value & 0xffff),
+            // 32-bit writes zero the upper half.
+            4 => value & 0xffff_ffff,
+            _ => value,
+        };
+    }
+
+    fn fetch_u8(&mut self) -> Res<u8> {
+        let rip = self.cpu.regs.rip;
+        if rip.wrapping_sub(self.start_rip) >= MAX_INSTRUCTION_LEN {
+            return Err(Fault::Exception(GP, Some(0)));
+        }
+        let linear = if self.code64() {
+            rip
+        } else {
+            self.cpu.sregs.cs.base.wrapping_add(rip) & 0xffff_ffff
+        };
+        let gpa = self.translate(linear, Access::Execute)?;
+        let mut byte = [0u8];
+        if !self.bus.read(gpa, &mut byte) {
+            // Executing from device memory is not supported.
+            return Err(Fault::Unsupported);
+        }
+        self.cpu.regs.rip = if self.code64() {
+            rip.wrapping_add(1)
+        } else {
+            rip.wrapping_add(1) & 0xffff_ffff
+        };
+        Ok(byte[0])
+    }
+
+    fn peek_u8(&mut self) -> Res<u8> {
+        let rip = self.cpu.regs.rip;
+        let byte = self.fetch_u8()?;
+        self.cpu.regs.rip = rip;
+        Ok(byte)
+    }
+
+    fn fetch(&mut self, size: usize) -> Res<u64> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.fetch_u8()? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    // Fetches an immediate of `size` bytes, sign extended and truncated to `opsize` bytes.
+    fn fetch_simm(&mut self, size: usize, opsize: usize) -> Res<u64> {
+        Ok(sign_extend(self.fetch(size)?, size) & mask(opsize))
+    }
+
+    // Size of the immediate of instructions taking an `opsize` immediate, which is never more
+    // than 32 bits.
+    fn imm_size(&self, opsize: usize) -> usize {
+        opsize.min(4)
+    }
+
+    // Decodes a ModRM byte and its addressing bytes. `imm_size` is the size of the immediate
+    // following them, needed for RIP-relative addressing.
+    fn modrm(&mut self, imm_size: usize) -> Res<ModRm> {
+        let b = self.fetch_u8()?;
+        let md = b >> 6;
+        let reg = ((b >> 3) & 7) as usize | if self.rex & 4 != 0 { 8 } else { 0 };
+        let rm = (b & 7) as usize;
+        if md == 3 {
+            let rm = rm | if self.rex & 1 != 0 { 8 } else { 0 };
+            return Ok(ModRm {
+                reg,
+                operand: Operand::Reg(rm),
+            });
+        }
+
+        let (seg, offset) = if self.addrsize == 2 {
+            let r = |cpu: &Cpu, i: usize| cpu.gpr(i) & 0xffff;
+            let (base, seg) = match rm {
+                0 => (r(self.cpu, RBX) + r(self.cpu, RSI), DS),
+                1 => (r(self.cpu, RBX) + r(self.cpu, RDI), DS),
+                2 => (r(self.cpu, RBP) + r(self.cpu, RSI), SS),
+                3 => (r(self.cpu, RBP) + r(self.cpu, RDI), SS),
+                4 => (r(self.cpu, RSI), DS),
+                5 => (r(self.cpu, RDI), DS),
+                6 if md == 0 => (self.fetch(2)?, DS),
+                6 => (r(self.cpu, RBP), SS),
+                _ => (r(self.cpu, RBX), DS),
+            };
+            let disp = match md {
+                1 => self.fetch_simm(1, 8)?,
+                2 => self.fetch(2)?,
+                _ => 0,
+            };
+            (seg, base.wrapping_add(disp) & 0xffff)
+        } else {
+            let mut seg = DS;
+            let mut ea = 0u64;
+            let mut rip_relative = false;
+            if rm == 4 {
+                let sib = self.fetch_u8()?;
+                let scale = sib >> 6;
+                let index = ((sib >> 3) & 7) as usize | if self.rex & 2 != 0 { 8 } else { 0 };
+                let base = (sib & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                if index != RSP {
+                    ea = self.cpu.gpr(index) << scale;
+                }
+                if sib & 7 == 5 && md == 0 {
+                    ea = ea.wrapping_add(self.fetch_simm(4, 8)?);
+                } else {
+                    ea = ea.wrapping_add(self.cpu.gpr(base));
+                    if sib & 7 == 4 || sib & 7 == 5 {
+                        seg = SS;
+                    }
+                }
+            } else if rm == 5 && md == 0 {
+                ea = self.fetch_simm(4, 8)?;
+                rip_relative = self.code64();
+            } else {
+                ea = self.cpu.gpr(rm | if self.rex & 1 != 0 { 8 } else { 0 });
+                if rm == 5 {
+                    seg = SS;
+                }
+            }
+            let disp = match md {
+                1 => self.fetch_simm(1, 8)?,
+                2 => self.fetch_simm(4, 8)?,
+                _ => 0,
+            };
+            ea = ea.wrapping_add(disp);
+            if rip_relative {
+                ea = ea
+                    .wrapping_add(self.cpu.regs.rip)
+                    .wrapping_add(imm_size as u64);
+            }
+            (seg, ea & mask(self.addrsize))
+        };
+        Ok(ModRm {
+            reg,
+            operand: Operand::Mem(self.seg_override.unwrap_or(seg), offset),
+        })
+    }
+
+    fn read_op(&mut self, operand: Operand, size: usize) -> Res<u64> {
+        match operand {
+            Operand::Reg(index) => Ok(self.reg_read(index, size)),
+            Operand::Mem(seg, offset) => self.read_mem(seg, offset, size),
+        }
+    }
+
+    fn write_op(&mut self, operand: Operand, size: usize, value: u64) -> Res<()> {
+        match operand {
+            Operand::Reg(index) => {
+                self.reg_write(index, size, value);
+                Ok(())
+            }
+            Operand::Mem(seg, offset) => self.write_mem(seg, offset, size, value),
+        }
+    }
+
+    fn linear(&self, seg: usize, offset: u64) -> u64 {
+        if self.code64() {
+            match seg {
+                FS | GS => self.cpu.segment(seg).base.wrapping_add(offset),
+                _ => offset,
+            }
+        } else {
+            self.cpu.segment(seg).base.wrapping_add(offset) & 0xffff_ffff
+        }
+    }
+
+    fn read_mem(&mut self, seg: usize, offset: u64, size: usize) -> Res<u64> {
+        self.read_linear(self.linear(seg, offset), size)
+    }
+
+    fn write_mem(&mut self, seg: usize, offset: u64, size: usize, value: u64) -> Res<()> {
+        self.write_linear(self.linear(seg, offset), size, value)
+    }
+
+    fn read_linear(&mut self, linear: u64, size: usize) -> Res<u64> {
+        let mut buf = [0u8; 8];
+        let mut done = 0;
+        while done < size {
+            let addr = linear.wrapping_add(done as u64);
+            let chunk = (size - done).min((PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize);
+            let gpa = self.translate(addr, Access::Read)?;
+            if !self.bus.read(gpa, &mut buf[done..done + chunk]) {
+                if chunk != size {
+                    // Device accesses crossing a page boundary are not supported.
+                    return Err(Fault::Unsupported);
+                }
+                buf = self.device_read(IoSpace::Mmio, gpa, size)?;
+            }
+            done += chunk;
+        }
+        Ok(u64::from_le_bytes(buf) & mask(size))
+    }
+
+    fn write_linear(&mut self, linear: u64, size: usize, value: u64) -> Res<()> {
+        let buf = bytes(value);
+        // Translate all the pages first so that a fault doesn't leave a partial write.
+        let mut chunks = [(0u64, 0usize, 0usize); 2];
+        let mut count = 0;
+        let mut done = 0;
+        while done < size {
+            let addr = linear.wrapping_add(done as u64);
+            let chunk = (size - done).min((PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize);
+            chunks[count] = (self.translate(addr, Access::Write)?, done, chunk);
+            count += 1;
+            done += chunk;
+        }
+        for &(gpa, start, len) in &chunks[..count] {
+            if !self.bus.write(gpa, &buf[start..start + len]) {
+                if len != size {
+                    return Err(Fault::Unsupported);
+                }
+                self.device_write(IoSpace::Mmio, gpa, size, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn device_read(&mut self, space: IoSpace, address: u64, size: usize) -> Res<[u8; 8]> {
+        self.cpu
+            .completed_reads
+            .iter()
+            .find(|r| r.0 == space && r.1 == address && r.2 == size)
+            .map(|r| r.3)
+            .ok_or(Fault::Read(IoAccess {
+                space,
+                address,
+                size,
+                data: None,
+            }))
+    }
+
+    fn device_write(&mut self, space: IoSpace, address: u64, size: usize, value: u64) -> Res<()> {
+        if self.pending_write.is_some() {
+            return Err(Fault::Unsupported);
+        }
+        self.pending_write = Some(IoAccess {
+            space,
+            address,
+            size,
+            data: Some(bytes(value & mask(size))),
+        });
+        Ok(())
+    }
+
+    fn read_phys(&mut self, gpa: u64, size: usize) -> Res<u64> {
+        let mut buf = [0u8; 8];
+        if !self.bus.read(gpa, &mut buf[..size]) {
+            // Page tables in device memory are not supported.
+            return Err(Fault::Unsupported);
+        }
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write_phys(&mut self, gpa: u64, size: usize, value: u64) -> Res<()> {
+        if !self.bus.write(gpa, &bytes(value)[..size]) {
+            return Err(Fault::Unsupported);
+        }
+        Ok(())
+    }
+
+    // Translates a linear address to a guest physical address, walking the page tables.
+    fn translate(&mut self, linear: u64, access: Access) -> Res<u64> {
+        let sregs = &self.cpu.sregs;
+        if sregs.cr0 & CR0_PG == 0 {
+            return Ok(linear);
+        }
+        let (cr0, cr3, cr4) = (sregs.cr0, sregs.cr3, sregs.cr4);
+        let nxe = sregs.efer & EFER_NXE != 0;
+        let user = self.cpl() == 3;
+        let page_fault = |present: bool| {
+            let mut error = 0;
+            if present {
+                error |= 1;
+            }
+            if access == Access::Write {
+                error |= 1 << 1;
+            }
+            if user {
+                error |= 1 << 2;
+            }
+            if access == Access::Execute && nxe {
+                error |= 1 << 4;
+            }
+            Fault::PageAccess(linear, error)
+        };
+
+        let (mut table, mut level, bits, entry_size) = if self.long_mode() {
+            let levels = if cr4 & CR4_LA57 != 0 { 5 } else { 4 };
+            (cr3 & PTE_ADDR_MASK, levels, 9, 8)
+        } else if cr4 & CR4_PAE != 0 {
+            let pdpte = self.read_phys((cr3 & 0xffff_ffe0) + ((linear >> 30) & 3) * 8, 8)?;
+            if pdpte & PTE_PRESENT == 0 {
+                return Err(page_fault(false));
+            }
+            (pdpte & PTE_ADDR_MASK, 2, 9, 8)
+        } else {
+            (cr3 & 0xffff_f000, 2, 10, 4)
+        };
+
+        let mut writable = true;
+        let mut user_page = true;
+        let mut no_execute = false;
+        loop {
+            let shift = 12 + bits * (level - 1);
+            let index = (linear >> shift) & ((1 << bits) - 1);
+            let entry_gpa = table + index * entry_size as u64;
+            let mut entry = self.read_phys(entry_gpa, entry_size)?;
+            if entry & PTE_PRESENT == 0 {
+                return Err(page_fault(false));
+            }
+            writable &= entry & PTE_WRITABLE != 0;
+            user_page &= entry & PTE_USER != 0;
+            no_execute |= entry_size == 8 && nxe && entry & PTE_NX != 0;
+            let large =
+                level > 1 && entry & PTE_LARGE != 0 && (entry_size == 8 || cr4 & CR4_PSE != 0);
+            if level == 1 || large {
+                let denied = (user && !user_page)
+                    || (access == Access::Write && !writable && (user || cr0 & CR0_WP != 0))
+                    || (access == Access::Execute && no_execute);
+                if denied {
+                    return Err(page_fault(true));
+                }
+                let mut updated = entry | PTE_ACCESSED;
+                if access == Access::Write {
+                    updated |= PTE_DIRTY;
+                }
+                if updated != entry {
+                    self.write_phys(entry_gpa, entry_size, updated)?;
+                }
+                let page_mask = (1u64 << shift) - 1;
+                let frame = if entry_size == 4 {
+                    entry & 0xffff_f000 & !page_mask
+                } else {
+                    entry & PTE_ADDR_MASK & !page_mask
+                };
+                return Ok(frame | (linear & page_mask));
+            }
+            if entry & PTE_ACCESSED == 0 {
+                entry |= PTE_ACCESSED;
+                self.write_phys(entry_gpa, entry_size, entry)?;
+            }
+            table = if entry_size == 8 {
+                entry & PTE_ADDR_MASK
+            } else {
+                entry & 0xffff_f000
+            };
+            level -= 1;
+        }
+    }
+
+    fn push(&mut self, value: u64, size: usize) -> Res<()> {
+        let stack_size = self.stack_size();
+        let rsp = self.cpu.regs.rsp.wrapping_sub(size as u64) & mask(stack_size);
+        self.write_mem(SS, rsp, size, value)?;
+        self.gpr_write(RSP, stack_size, rsp);
+        Ok(())
+    }
+
+    fn pop(&mut self, size: usize) -> Res<u64> {
+        let stack_size = self.stack_size();
+        let rsp = self.cpu.regs.rsp & mask(stack_size);
+        let value = self.read_mem(SS, rsp, size)?;
+        self.gpr_write(RSP, stack_size, rsp.wrapping_add(size as u64));
+        Ok(value)
+    }
+
+    // Jumps to `target` in the current code segment.
+    fn jump(&mut self, target: u64) {
+        self.cpu.regs.rip = target & mask(self.stack_opsize());
+    }
+
+    fn jump_relative(&mut self, displacement: u64) {
+        let target = self.cpu.regs.rip.wrapping_add(displacement);
+        self.jump(target);
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let of = self.flag(FLAG_OF);
+        let cf = self.flag(FLAG_CF);
+        let zf = self.flag(FLAG_ZF);
+        let sf = self.flag(FLAG_SF);
+        let pf = self.flag(FLAG_PF);
+        let result = match cc >> 1 {
+            0 => of,
+            1 => cf,
+            2 => zf,
+            3 => cf || zf,
+            4 => sf,
+            5 => pf,
+            6 => sf != of,
+            _ => zf || sf != of,
+        };
+        result != (cc & 1 != 0)
+    }
+
+    fn set_result_flags(&mut self, result: u64, size: usize, cf: bool, of: bool, af: bool) {
+        let result = result & mask(size);
+        let mut flags = self.cpu.regs.rflags & !FLAGS_ARITH;
+        if cf {
+            flags |= FLAG_CF;
+        }
+        if of {
+            flags |= FLAG_OF;
+        }
+        if af {
+            flags |= FLAG_AF;
+        }
+        if result == 0 {
+            flags |= FLAG_ZF;
+        }
+        if result & sign_bit(size) != 0 {
+            flags |= FLAG_SF;
+        }
+        if (result as u8).count_ones() & 1 == 0 {
+            flags |= FLAG_PF;
+        }
+        self.cpu.regs.rflags = flags;
+    }
+
+    // Computes one of the eight basic arithmetic operations, in their opcode order, and sets the
+    // flags. `a` and `b` must be truncated to `size`.
+    fn alu(&mut self, op: usize, size: usize, a: u64, b: u64) -> u64 {
+        let m = mask(size);
+        let sign = sign_bit(size);
+        let carry = self.flag(FLAG_CF) as u64;
+        let (result, cf, of) = match op {
+            // add, adc
+            0 | 2 => {
+                let c = if op == 2 { carry } else { 0 };
+                let full = a as u128 + b as u128 + c as u128;
+                let r = full as u64 & m;
+                (r, full > m as u128, (a ^ r) & (b ^ r) & sign != 0)
+            }
+            // sbb, sub, cmp
+            3 | 5 | 7 => {
+                let c = if op == 3 { carry } else { 0 };
+                let r = a.wrapping_sub(b).wrapping_sub(c) & m;
+                (
+                    r,
+                    b as u128 + c as u128 > a as u128,
+                    (a ^ b) & (a ^ r) & sign != 0,
+                )
+            }
+            1 => (a | b, false, false),
+            4 => (a & b, false, false),
+            _ => (a ^ b, false, false),
+        };
+        let af = matches!(op, 0 | 2 | 3 | 5 | 7) && (a ^ b ^ result) & 0x10 != 0;
+        self.set_result_flags(result, size, cf, of, af);
+        result
+    }
+
+    // inc and dec, which preserve CF.
+    fn inc_dec(&mut self, size: usize, value: u64, dec: bool) -> u64 {
+        let cf = self.flag(FLAG_CF);
+        let result = self.alu(if dec { 5 } else { 0 }, size, value, 1);
+        self.set_flag(FLAG_CF, cf);
+        result
+    }
+
+    // The group 2 rotates and shifts.
+    fn shift(&mut self, op: usize, size: usize, value: u64, count: u64) -> u64 {
+        let bits = size as u64 * 8;
+        let count = count & if size == 8 { 0x3f } else { 0x1f };
+        if count == 0 {
+            return value;
+        }
+        let m = mask(size);
+        let sign = sign_bit(size);
+        let msb = |v: u64| v & sign != 0;
+        let (result, cf, of) = match op {
+            // rol
+            0 => {
+                let n = count % bits;
+                let r = ((value << n) | (value >> ((bits - n) % bits))) & m;
+                (r, r & 1 != 0, msb(r) != (r & 1 != 0))
+            }
+            // ror
+            1 => {
+                let n = count % bits;
+                let r = ((value >> n) | (value << ((bits - n) % bits))) & m;
+                (r, msb(r), msb(r) != msb(r << 1))
+            }
+            // rcl, rcr
+            2 | 3 => {
+                let mut r = value;
+                let mut cf = self.flag(FLAG_CF);
+                for _ in 0..count % (bits + 1) {
+                    if op == 2 {
+                        let out = msb(r);
+                        r = ((r << 1) | cf as u64) & m;
+                        cf = out;
+                    } else {
+                        let out = r & 1 != 0;
+                        r = (r >> 1) | if cf { sign } else { 0 };
+                        cf = out;
+                    }
+                }
+                let of = if op == 2 {
+                    msb(r) != cf
+                } else {
+                    msb(r) != msb(r << 1)
+                };
+                (r, cf, of)
+            }
+            // shl, sal
+            4 | 6 => {
+                let r = if count < 64 { (value << count) & m } else { 0 };
+                let cf = count <= bits && (value >> (bits - count)) & 1 != 0;
+                (r, cf, msb(r) != cf)
+            }
+            // shr
+            5 => {
+                let r = if count < 64 { value >> count } else { 0 };
+                (r, (value >> (count - 1)) & 1 != 0, msb(value))
+            }
+            // sar
+            _ => {
+                let v = sign_extend(value, size) as i64;
+                let r = (v >> count.min(63)) as u64 & m;
+                (r, (v >> (count - 1).min(63)) & 1 != 0, false)
+            }
+        };
+        if op < 4 {
+            // Rotates only change CF and OF.
+            self.set_flag(FLAG_CF, cf);
+            self.set_flag(FLAG_OF, of);
+        } else {
+            self.set_result_flags(result, size, cf, of, false);
+        }
+        result
+    }
+
+    // Signed multiplication truncated to `size`, setting CF and OF on overflow.
+    fn imul(&mut self, size: usize, a: u64, b: u64) -> u64 {
+        let full = sign_extend(a, size) as i64 as i128 * sign_extend(b, size) as i64 as i128;
+        let result = full as u64 & mask(size);
+        let overflow = sign_extend(result, size) as i64 as i128 != full;
+        self.set_flag(FLAG_CF, overflow);
+        self.set_flag(FLAG_OF, overflow);
+        result
+    }
+
+    // Returns the accumulator and its extension register for multiplications and divisions.
+    fn wide_acc(&self, size: usize) -> u128 {
+        let a = self.cpu.gpr(RAX);
+        match size {
+            1 => (a & 0xffff) as u128,
+            _ => {
+                let bits = size * 8;
+                ((self.cpu.gpr(RDX) & mask(size)) as u128) << bits | (a & mask(size)) as u128
+            }
+        }
+    }
+
+    fn set_wide_acc(&mut self, size: usize, low: u64, high: u64) {
+        if size == 1 {
+            self.gpr_write(RAX, 2, (low & 0xff) | ((high & 0xff) << 8));
+        } else {
+            self.gpr_write(RAX, size, low);
+            self.gpr_write(RDX, size, high);
+        }
+    }
+
+    // The group 3 instructions.
+    fn group3(&mut self, size: usize, m: &ModRm) -> Res<()> {
+        let op = m.reg & 7;
+        let value = self.read_op(m.operand, size)?;
+        let bits = size * 8;
+        match op {
+            0 | 1 => {
+                let imm = self.fetch_simm(self.imm_size(size), size)?;
+                self.alu(4, size, value, imm);
+            }
+            2 => self.write_op(m.operand, size, !value & mask(size))?,
+            3 => {
+                let result = self.alu(5, size, 0, value);
+                self.write_op(m.operand, size, result)?;
+            }
+            4 => {
+                let full = (self.cpu.gpr(RAX) & mask(size)) as u128 * value as u128;
+                let high = (full >> bits) as u64;
+                self.set_wide_acc(size, full as u64, high);
+                self.set_flag(FLAG_CF, high != 0);
+                self.set_flag(FLAG_OF, high != 0);
+            }
+            5 => {
+                let a = sign_extend(self.cpu.gpr(RAX) & mask(size), size) as i64 as i128;
+                let full = a * sign_extend(value, size) as i64 as i128;
+                let low = full as u64 & mask(size);
+                self.set_wide_acc(size, low, (full >> bits) as u64);
+                let overflow = sign_extend(low, size) as i64 as i128 != full;
+                self.set_flag(FLAG_CF, overflow);
+                self.set_flag(FLAG_OF, overflow);
+            }
+            6 => {
+                if value == 0 {
+                    return Err(Fault::Exception(DE, None));
+                }
+                let dividend = self.wide_acc(size);
+                let quotient = dividend / value as u128;
+                if quotient > mask(size) as u128 {
+                    return Err(Fault::Exception(DE, None));
+                }
+                let remainder = (dividend % value as u128) as u64;
+                self.set_wide_acc(size, quotient as u64, remainder);
+            }
+            _ => {
+                let wide = self.wide_acc(size);
+                // Sign extend the double width dividend.
+                let shift = 128 - 2 * bits as u32;
+                let dividend = ((wide << shift) as i128) >> shift;
+                let divisor = sign_extend(value, size) as i64 as i128;
+                // Both a zero divisor and i128::MIN / -1 with 64-bit operands are a #DE.
+                let (Some(quotient), Some(remainder)) =
+                    (dividend.checked_div(divisor), dividend.checked_rem(divisor))
+                else {
+                    return Err(Fault::Exception(DE, None));
+                };
+                let min = -(1i128 << (bits - 1));
+                let max = (1i128 << (bits - 1)) - 1;
+                if quotient < min || quotient > max {
+                    return Err(Fault::Exception(DE, None));
+                }
+                self.set_wide_acc(size, quotient as u64, remainder as u64);
+            }
+        }
+        Ok(())
+    }
+
+    // Reads a segment descriptor from the GDT or LDT.
+    fn read_descriptor(&mut self, selector: u16) -> Res<u64> {
+        let (base, limit) = if selector & 4 != 0 {
+            (self.cpu.sregs.ldt.base, self.cpu.sregs.ldt.limit as u64)
+        } else {
+            (self.cpu.sregs.gdt.base, self.cpu.sregs.gdt.limit as u64)
+        };
+        let offset = (selector & !7) as u64;
+        if offset + 7 > limit {
+            return Err(Fault::Exception(GP, Some((selector & !3) as u32)));
+        }
+        self.read_linear(base.wrapping_add(offset), 8)
+    }
+
+    fn load_segment(&mut self, seg: usize, selector: u16) -> Res<()> {
+        if !self.protected() {
+            let s = self.cpu.segment_mut(seg);
+            s.selector = selector;
+            s.base = (selector as u64) << 4;
+            return Ok(());
+        }
+        if selector & !3 == 0 {
+            if seg == CS || (seg == SS && !self.code64()) {
+                return Err(Fault::Exception(GP, Some(0)));
+            }
+            let s = self.cpu.segment_mut(seg);
+            s.selector = selector;
+            s.present = 0;
+            return Ok(());
+        }
+        let segment = parse_descriptor(self.read_descriptor(selector)?, selector);
+        if segment.present == 0 {
+            let vector = if seg == SS { SS_FAULT } else { NP };
+            return Err(Fault::Exception(vector, Some((selector & !3) as u32)));
+        }
+        *self.cpu.segment_mut(seg) = segment;
+        Ok(())
+    }
+
+    // Loads LDTR or TR, whose descriptors are 16 bytes long in long mode.
+    fn load_system_segment(&mut self, selector: u16, tr: bool) -> Res<()> {
+        let mut segment = if selector & !3 == 0 {
+            if tr {
+                return Err(Fault::Exception(GP, Some(0)));
+            }
+            Segment {
+                selector,
+                ..Default::default()
+            }
+        } else {
+            let mut segment = parse_descriptor(self.read_descriptor(selector)?, selector);
+            if self.long_mode() {
+                let gdt = self.cpu.sregs.gdt.base;
+                let high = self.read_linear(gdt.wrapping_add((selector & !7) as u64 + 8), 4)?;
+                segment.base |= high << 32;
+            }
+            if segment.present == 0 {
+                return Err(Fault::Exception(NP, Some((selector & !3) as u32)));
+            }
+            segment
+        };
+        if tr {
+            // Mark the TSS as busy.
+            segment.type_ |= 2;
+            self.cpu.sregs.tr = segment;
+        } else {
+            self.cpu.sregs.ldt = segment;
+        }
+        Ok(())
+    }
+
+    // Delivers an interrupt or exception through the IVT or IDT. RIP must be the return address.
+    fn deliver(&mut self, vector: u8, error: Option<u32>) -> Res<()> {
+        let idt = self.cpu.sregs.idt;
+        let rflags = self.cpu.regs.rflags;
+        let rip = self.cpu.regs.rip;
+        let cs = self.cpu.sregs.cs.selector as u64;
+        let vector_error = Some(vector as u32 * 8 + 2);
+
+        if !self.protected() {
+            let offset = vector as u64 * 4;
+            if offset + 3 > idt.limit as u64 {
+                return Err(Fault::Exception(GP, vector_error));
+            }
+            let entry = self.read_linear(idt.base.wrapping_add(offset), 4)?;
+            self.push(rflags, 2)?;
+            self.push(cs, 2)?;
+            self.push(rip, 2)?;
+            self.cpu.regs.rflags &= !(FLAG_IF | FLAG_TF | FLAG_AC);
+            self.load_segment(CS, (entry >> 16) as u16)?;
+            self.cpu.regs.rip = entry & 0xffff;
+            return Ok(());
+        }
+
+        let long_mode = self.long_mode();
+        let entry_size = if long_mode { 16 } else { 8 };
+        let offset = vector as u64 * entry_size;
+        if offset + entry_size - 1 > idt.limit as u64 {
+            return Err(Fault::Exception(GP, vector_error));
+        }
+        let low = self.read_linear(idt.base.wrapping_add(offset), 8)?;
+        if (low >> 47) & 1 == 0 {
+            return Err(Fault::Exception(NP, vector_error));
+        }
+        let gate_type = (low >> 40) & 0xf;
+        if gate_type != 0xe && gate_type != 0xf {
+            // Task gates and 16-bit gates are not supported.
+            return Err(Fault::Unsupported);
+        }
+        let mut target = (low & 0xffff) | ((low >> 48) << 16);
+        if long_mode {
+            target |= self.read_linear(idt.base.wrapping_add(offset + 8), 4)? << 32;
+        }
+        let selector = (low >> 16) as u16;
+        let mut code = parse_descriptor(self.read_descriptor(selector)?, selector);
+        let cpl = self.cpl();
+        let new_cpl = code.dpl.min(cpl);
+        code.selector = (selector & !3) | new_cpl as u16;
+        let ss = self.cpu.sregs.ss.selector as u64;
+        let rsp = self.cpu.regs.rsp;
+        let tss = self.cpu.sregs.tr.base;
+
+        if long_mode {
+            let ist = (low >> 32) & 7;
+            let mut new_rsp = rsp;
+            if ist != 0 {
+                new_rsp = self.read_linear(tss.wrapping_add(0x24 + (ist - 1) * 8), 8)?;
+            } else if new_cpl < cpl {
+                new_rsp = self.read_linear(tss.wrapping_add(4 + new_cpl as u64 * 8), 8)?;
+            }
+            if new_cpl < cpl {
+                self.cpu.sregs.ss = Segment {
+                    selector: new_cpl as u16,
+                    dpl: new_cpl,
+                    ..self.cpu.sregs.ss
+                };
+            }
+            self.cpu.regs.rsp = new_rsp & !0xf;
+            self.cpu.sregs.cs = code;
+            self.push(ss, 8)?;
+            self.push(rsp, 8)?;
+            self.push(rflags, 8)?;
+            self.push(cs, 8)?;
+            self.push(rip, 8)?;
+            if let Some(error) = error {
+                self.push(error as u64, 8)?;
+            }
+        } else {
+            if new_cpl < cpl {
+                let esp = self.read_linear(tss.wrapping_add(4 + new_cpl as u64 * 8), 4)?;
+                let new_ss = self.read_linear(tss.wrapping_add(8 + new_cpl as u64 * 8), 2)?;
+                self.load_segment(SS, new_ss as u16)?;
+                self.gpr_write(RSP, 8, esp);
+                self.push(ss, 4)?;
+                self.push(rsp, 4)?;
+            }
+            self.cpu.sregs.cs = code;
+            self.push(rflags, 4)?;
+            self.push(cs, 4)?;
+            self.push(rip, 4)?;
+            if let Some(error) = error {
+                self.push(error as u64, 4)?;
+            }
+        }
+        self.cpu.regs.rflags &= !(FLAG_TF | FLAG_NT | FLAG_RF | FLAG_VM);
+        if gate_type == 0xe {
+            self.cpu.regs.rflags &= !FLAG_IF;
+        }
+        self.cpu.regs.rip = target;
+        Ok(())
+    }
+
+    fn iret(&mut self) -> Res<()> {
+        let size = self.opsize;
+        if !self.protected() {
+            let ip = self.pop(size)?;
+            let cs = self.pop(size)?;
+            let flags = self.pop(size)?;
+            self.load_segment(CS, cs as u16)?;
+            self.cpu.regs.rip = ip & mask(size);
+            self.load_flags(flags, size);
+            return Ok(());
+        }
+        let ip = self.pop(size)?;
+        let cs = self.pop(size)? as u16;
+        let flags = self.pop(size)?;
+        let code64 = self.code64();
+        let outer = code64 || (cs & 3) as u8 > self.cpl();
+        let stack = if outer {
+            let sp = self.pop(size)?;
+            let ss = self.pop(size)? as u16;
+            Some((sp, ss))
+        } else {
+            None
+        };
+        self.load_segment(CS, cs)?;
+        if let Some((sp, ss)) = stack {
+            self.load_segment(SS, ss)?;
+            self.gpr_write(RSP, if code64 { 8 } else { size }, sp);
+        }
+        self.cpu.regs.rip = ip & mask(size);
+        self.load_flags(flags, size);
+        Ok(())
+    }
+
+    fn far_jump(&mut self, selector: u16, offset: u64) -> Res<()> {
+        self.load_segment(CS, selector)?;
+        self.cpu.regs.rip = offset;
+        Ok(())
+    }
+
+    fn write_cr(&mut self, cr: usize, value: u64) -> Res<()> {
+        let sregs = &mut self.cpu.sregs;
+        match cr {
+            0 => {
+                let value = value | (1 << 4);
+                if value & CR0_PG != 0 && sregs.cr0 & CR0_PG == 0 && sregs.efer & EFER_LME != 0 {
+                    sregs.efer |= EFER_LMA;
+                } else if value & CR0_PG == 0 {
+                    sregs.efer &= !EFER_LMA;
+                }
+                sregs.cr0 = value;
+            }
+            2 => sregs.cr2 = value,
+            3 => sregs.cr3 = value,
+            4 => sregs.cr4 = value,
+            8 => sregs.cr8 = value & 0xf,
+            _ => return Err(Fault::Exception(UD, None)),
+        }
+        Ok(())
+    }
+
+    fn read_cr(&self, cr: usize) -> Res<u64> {
+        let sregs = &self.cpu.sregs;
+        Ok(match cr {
+            0 => sregs.cr0,
+            2 => sregs.cr2,
+            3 => sregs.cr3,
+            4 => sregs.cr4,
+            8 => sregs.cr8,
+            _ => return Err(Fault::Exception(UD, None)),
+        })
+    }
+
+    fn dr_mut(&mut self, dr: usize) -> Res<&mut u64> {
+        let debugregs = &mut self.cpu.debugregs;
+        Ok(match dr {
+            0..=3 => &mut debugregs.db[dr],
+            4 | 6 => &mut debugregs.dr6,
+            5 | 7 => &mut debugregs.dr7,
+            _ => return Err(Fault::Exception(UD, None)),
+        })
+    }
+
+    // Advances a string operation index register by one element.
+    fn advance_index(&mut self, index: usize, size: usize) {
+        let value = self.cpu.gpr(index);
+        let next = if self.flag(FLAG_DF) {
+            value.wrapping_sub(size as u64)
+        } else {
+            value.wrapping_add(size as u64)
+        };
+        self.gpr_write(index, self.addrsize, next);
+    }
+
+    // Executes one iteration of a string instruction. Repeated instructions are restarted until
+    // their count is exhausted so that interrupts and device accesses can happen in between.
+    fn string_op(&mut self, op: u8) -> Res<()> {
+        let size = match op {
+            0x6d | 0x6f => self.opsize.min(4),
+            _ if op & 1 == 0 => 1,
+            _ => self.opsize,
+        };
+        let amask = mask(self.addrsize);
+        if self.rep.is_some() && self.cpu.gpr(RCX) & amask == 0 {
+            return Ok(());
+        }
+        let src = self.seg_override.unwrap_or(DS);
+        let rsi = self.cpu.gpr(RSI) & amask;
+        let rdi = self.cpu.gpr(RDI) & amask;
+        let port = self.cpu.gpr(RDX) & 0xffff;
+        let mut compare = false;
+        match op {
+            // ins
+            0x6c | 0x6d => {
+                let value = u64::from_le_bytes(self.device_read(IoSpace::Pio, port, size)?);
+                self.write_mem(ES, rdi, size, value)?;
+                self.advance_index(RDI, size);
+            }
+            // outs
+            0x6e | 0x6f => {
+                let value = self.read_mem(src, rsi, size)?;
+                self.device_write(IoSpace::Pio, port, size, value)?;
+                self.advance_index(RSI, size);
+            }
+            // movs
+            0xa4 | 0xa5 => {
+                let value = self.read_mem(src, rsi, size)?;
+                self.write_mem(ES, rdi, size, value)?;
+                self.advance_index(RSI, size);
+                self.advance_index(RDI, size);
+            }
+            // cmps
+            0xa6 | 0xa7 => {
+                let a = self.read_mem(src, rsi, size)?;
+                let b = self.read_mem(ES, rdi, size)?;
+                self.alu(7, size, a, b);
+                self.advance_index(RSI, size);
+                self.advance_index(RDI, size);
+                compare = true;
+            }
+            // stos
+            0xaa | 0xab => {
+                let value = self.reg_read(RAX, size);
+                self.write_mem(ES, rdi, size, value)?;
+                self.advance_index(RDI, size);
+            }
+            // lods
+            0xac | 0xad => {
+                let value = self.read_mem(src, rsi, size)?;
+                self.reg_write(RAX, size, value);
+                self.advance_index(RSI, size);
+            }
+            // scas
+            _ => {
+                let a = self.reg_read(RAX, size);
+                let b = self.read_mem(ES, rdi, size)?;
+                self.alu(7, size, a, b);
+                self.advance_index(RDI, size);
+                compare = true;
+            }
+        }
+        if let Some(rep) = self.rep {
+            let count = (self.cpu.gpr(RCX) & amask).wrapping_sub(1);
+            self.gpr_write(RCX, self.addrsize, count);
+            let zf = self.flag(FLAG_ZF);
+            let done = count & amask == 0
+                || (compare && ((rep == Rep::E && !zf) || (rep == Rep::Ne && zf)));
+            if !done {
+                self.cpu.regs.rip = self.start_rip;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Res<Flow> {
+        let code64 = self.code64();
+        let default32 = code64 || self.cpu.sregs.cs.db != 0;
+        let mut opsize_override = false;
+        let mut addrsize_override = false;
+        let op = loop {
+            let b = self.fetch_u8()?;
+            match b {
+                0x26 | 0x2e | 0x36 | 0x3e => self.seg_override = Some(((b >> 3) & 3) as usize),
+                0x64 => self.seg_override = Some(FS),
+                0x65 => self.seg_override = Some(GS),
+                0x66 => opsize_override = true,
+                0x67 => addrsize_override = true,
+                // lock
+                0xf0 => {}
+                0xf2 => self.rep = Some(Rep::Ne),
+                0xf3 => self.rep = Some(Rep::E),
+                0x40..=0x4f if code64 => {
+                    self.rex = b;
+                    continue;
+                }
+                _ => break b,
+            }
+            // A REX prefix is ignored unless it immediately precedes the opcode.
+            self.rex = 0;
+        };
+        self.opsize = if self.rex_w() {
+            8
+        } else if default32 != opsize_override {
+            4
+        } else {
+            2
+        };
+        self.addrsize = if code64 {
+            if addrsize_override {
+                4
+            } else {
+                8
+            }
+        } else if (self.cpu.sregs.cs.db != 0) != addrsize_override {
+            4
+        } else {
+            2
+        };
+        let size = self.opsize;
+        let rex_b = if self.rex & 1 != 0 { 8 } else { 0 };
+
+        match op {
+            // add, or, adc, sbb, and, sub, xor, cmp
+            0x00..=0x3f if op & 7 < 6 => {
+                let alu_op = (op >> 3) as usize;
+                let size = if op & 1 == 0 { 1 } else { size };
+                let (dst, src) = match op & 7 {
+                    0 | 1 => {
+                        let m = self.modrm(0)?;
+                        let src = self.reg_read(m.reg, size);
+                        (m.operand, src)
+                    }
+                    2 | 3 => {
+                        let m = self.modrm(0)?;
+                        let src = self.read_op(m.operand, size)?;
+                        (Operand::Reg(m.reg), src)
+                    }
+                    _ => {
+                        let imm = self.fetch_simm(self.imm_size(size), size)?;
+                        (Operand::Reg(RAX), imm)
+                    }
+                };
+                let a = self.read_op(dst, size)?;
+                let result = self.alu(alu_op, size, a, src);
+                if alu_op != 7 {
+                    self.write_op(dst, size, result)?;
+                }
+            }
+            // push es, cs, ss, ds
+            0x06 | 0x0e | 0x16 | 0x1e if !code64 => {
+                let selector = self.cpu.segment((op >> 3) as usize).selector;
+                self.push(selector as u64, size)?;
+            }
+            // pop es, ss, ds
+            0x07 | 0x17 | 0x1f if !code64 => {
+                let selector = self.pop(size)?;
+                let seg = (op >> 3) as usize;
+                self.load_segment(seg, selector as u16)?;
+                if seg == SS {
+                    self.cpu.interrupt_shadow = true;
+                }
+            }
+            0x0f => return self.execute_0f(),
+            // inc, dec
+            0x40..=0x4f => {
+                let index = (op & 7) as usize;
+                let value = self.reg_read(index, size);
+                let result = self.inc_dec(size, value, op >= 0x48);
+                self.gpr_write(index, size, result);
+            }
+            // push
+            0x50..=0x57 => {
+                let value = self.cpu.gpr((op & 7) as usize | rex_b);
+                self.push(value, self.stack_opsize())?;
+            }
+            // pop
+            0x58..=0x5f => {
+                let size = self.stack_opsize();
+                let value = self.pop(size)?;
+                self.gpr_write((op & 7) as usize | rex_b, size, value);
+            }
+            // movsxd
+            0x63 if code64 => {
+                let m = self.modrm(0)?;
+                let value = sign_extend(self.read_op(m.operand, 4)?, 4);
+                self.gpr_write(m.reg, size, value);
+            }
+            // push imm
+            0x68 | 0x6a => {
+                let imm_size = if op == 0x6a { 1 } else { self.imm_size(size) };
+                let size = self.stack_opsize();
+                let value = self.fetch_simm(imm_size, size)?;
+                self.push(value, size)?;
+            }
+            // imul r, r/m, imm
+            0x69 | 0x6b => {
+                let imm_size = if op == 0x6b { 1 } else { self.imm_size(size) };
+                let m = self.modrm(imm_size)?;
+                let imm = self.fetch_simm(imm_size, size)?;
+                let value = self.read_op(m.operand, size)?;
+                let result = self.imul(size, value, imm);
+                self.gpr_write(m.reg, size, result);
+            }
+            // ins, outs
+            0x6c..=0x6f => self.string_op(op)?,
+            // jcc rel8
+            0x70..=0x7f => {
+                let displacement = self.fetch_simm(1, 8)?;
+                if self.condition(op & 0xf) {
+                    self.jump_relative(displacement);
+                }
+            }
+            // group 1
+            0x80..=0x83 => {
+                if op == 0x82 && code64 {
+                    return Err(Fault::Exception(UD, None));
+                }
+                let size = if op & 1 == 0 { 1 } else { size };
+                let imm_size = if op == 0x81 { self.imm_size(size) } else { 1 };
+                let m = self.modrm(imm_size)?;
+                let imm = self.fetch_simm(imm_size, size)?;
+                let a = self.read_op(m.operand, size)?;
+                let alu_op = m.reg & 7;
+                let result = self.alu(alu_op, size, a, imm);
+                if alu_op != 7 {
+                    self.write_op(m.operand, size, result)?;
+                }
+            }
+            // test
+            0x84 | 0x85 => {
+                let size = if op == 0x84 { 1 } else { size };
+                let m = self.modrm(0)?;
+                let a = self.read_op(m.operand, size)?;
+                let b = self.reg_read(m.reg, size);
+                self.alu(4, size, a, b);
+            }
+            // xchg
+            0x86 | 0x87 => {
+                let size = if op == 0x86 { 1 } else { size };
+                let m = self.modrm(0)?;
+                let a = self.read_op(m.operand, size)?;
+                let b = self.reg_read(m.reg, size);
+                self.write_op(m.operand, size, b)?;
+                self.reg_write(m.reg, size, a);
+            }
+            // mov r/m, r
+            0x88 | 0x89 => {
+                let size = if op == 0x88 { 1 } else { size };
+                let m = self.modrm(0)?;
+                let value = self.reg_read(m.reg, size);
+                self.write_op(m.operand, size, value)?;
+            }
+            // mov r, r/m
+            0x8a | 0x8b => {
+                let size = if op == 0x8a { 1 } else { size };
+                let m = self.modrm(0)?;
+                let value = self.read_op(m.operand, size)?;
+                self.reg_write(m.reg, size, value);
+            }
+            // mov r/m, sreg
+            0x8c => {
+                let m = self.modrm(0)?;
+                if m.reg & 7 > GS {
+                    return Err(Fault::Exception(UD, None));
+                }
+                let selector = self.cpu.segment(m.reg & 7).selector as u64;
+                match m.operand {
+                    Operand::Reg(index) => self.gpr_write(index, size, selector),
+                    operand => self.write_op(operand, 2, selector)?,
+                }
+            }
+            // lea
+            0x8d => {
+                let m = self.modrm(0)?;
+                match m.operand {
+                    Operand::Mem(_, offset) => self.gpr_write(m.reg, size, offset),
+                    Operand::Reg(_) => return Err(Fault::Exception(UD, None)),
+                }
+            }
+            // mov sreg, r/m
+            0x8e => {
+                let m = self.modrm(0)?;
+                let seg = m.reg & 7;
+                if seg == CS || seg > GS {
+                    return Err(Fault::Exception(UD, None));
+                }
+                let selector = self.read_op(m.operand, 2)?;
+                self.load_segment(seg, selector as u16)?;
+                if seg == SS {
+                    self.cpu.interrupt_shadow = true;
+                }
+            }
+            // pop r/m
+            0x8f => {
+                let size = self.stack_opsize();
+                let m = self.modrm(0)?;
+                let value = self.pop(size)?;
+                if let Operand::Mem(seg, offset) = m.operand {
+                    // The address is computed with the incremented stack pointer.
+                    self.write_mem(seg, offset, size, value)?;
+                } else {
+                    self.write_op(m.operand, size, value)?;
+                }
+            }
+            // nop, pause
+            0x90 if rex_b == 0 => {}
+            // xchg rAX, r
+            0x90..=0x97 => {
+                let index = (op & 7) as usize | rex_b;
+                let a = self.reg_read(RAX, size);
+                let b = self.reg_read(index, size);
+                self.gpr_write(RAX, size, b);
+                self.gpr_write(index, size, a);
+            }
+            // cbw, cwde, cdqe
+            0x98 => {
+                let half = size / 2;
+                let value = sign_extend(self.reg_read(RAX, half), half);
+                self.gpr_write(RAX, size, value);
+            }
+            // cwd, cdq, cqo
+            0x99 => {
+                let negative = self.reg_read(RAX, size) & sign_bit(size) != 0;
+                self.gpr_write(RDX, size, if negative { u64::MAX } else { 0 });
+            }
+            // far call
+            0x9a if !code64 => {
+                let offset = self.fetch(size)?;
+                let selector = self.fetch(2)?;
+                let cs = self.cpu.sregs.cs.selector as u64;
+                let rip = self.cpu.regs.rip;
+                self.push(cs, size)?;
+                self.push(rip, size)?;
+                self.far_jump(selector as u16, offset)?;
+            }
+            // fwait
+            0x9b => {}
+            // pushf
+            0x9c => {
+                let flags = self.cpu.regs.rflags & !(FLAG_RF | FLAG_VM);
+                self.push(flags, self.stack_opsize())?;
+            }
+            // popf
+            0x9d => {
+                let size = self.stack_opsize();
+                let flags = self.pop(size)?;
+                self.load_flags(flags, size);
+            }
+            // sahf
+            0x9e => {
+                let ah = (self.cpu.regs.rax >> 8) & 0xff;
+                let writable = FLAG_CF | FLAG_PF | FLAG_AF | FLAG_ZF | FLAG_SF;
+                self.cpu.regs.rflags = (self.cpu.regs.rflags & !writable) | (ah & writable);
+            }
+            // lahf
+            0x9f => {
+                let flags = self.cpu.regs.rflags & 0xff;
+                self.reg_write(4, 1, flags);
+            }
+            // mov with a direct offset
+            0xa0..=0xa3 => {
+                let size = if op & 1 == 0 { 1 } else { size };
+                let offset = self.fetch(self.addrsize)?;
+                let seg = self.seg_override.unwrap_or(DS);
+                if op < 0xa2 {
+                    let value = self.read_mem(seg, offset, size)?;
+                    self.reg_write(RAX, size, value);
+                } else {
+                    let value = self.reg_read(RAX, size);
+                    self.write_mem(seg, offset, size, value)?;
+                }
+            }
+            // movs, cmps, stos, lods, scas
+            0xa4..=0xa7 | 0xaa..=0xaf => self.string_op(op)?,
+            // test rAX, imm
+            0xa8 | 0xa9 => {
+                let size = if op == 0xa8 { 1 } else { size };
+                let imm = self.fetch_simm(self.imm_size(size), size)?;
+                let a = self.reg_read(RAX, size);
+                self.alu(4, size, a, imm);
+            }
+            // mov r8, imm8
+            0xb0..=0xb7 => {
+                let imm = self.fetch(1)?;
+                self.reg_write((op & 7) as usize | rex_b, 1, imm);
+            }
+            // mov r, imm
+            0xb8..=0xbf => {
+                let imm = self.fetch(size)?;
+                self.gpr_write((op & 7) as usize | rex_b, size, imm);
+            }
+            // group 2
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if op & 1 == 0 { 1 } else { size };
+                let imm_size = if op < 0xd0 { 1 } else { 0 };
+                let m = self.modrm(imm_size)?;
+                let count = match op {
+                    0xc0 | 0xc1 => self.fetch(1)?,
+                    0xd0 | 0xd1 => 1,
+                    _ => self.cpu.regs.rcx & 0xff,
+                };
+                let value = self.read_op(m.operand, size)?;
+                let result = self.shift(m.reg & 7, size, value, count);
+                self.write_op(m.operand, size, result)?;
+            }
+            // ret
+            0xc2 | 0xc3 => {
+                let release = if op == 0xc2 { self.fetch(2)? } else { 0 };
+                let target = self.pop(self.stack_opsize())?;
+                let stack_size = self.stack_size();
+                let rsp = self.cpu.regs.rsp.wrapping_add(release);
+                self.gpr_write(RSP, stack_size, rsp);
+                self.jump(target);
+            }
+            // mov r/m, imm
+            0xc6 | 0xc7 => {
+                let size = if op == 0xc6 { 1 } else { size };
+                let imm_size = self.imm_size(size);
+                let m = self.modrm(imm_size)?;
+                let imm = self.fetch_simm(imm_size, size)?;
+                self.write_op(m.operand, size, imm)?;
+            }
+            // leave
+            0xc9 => {
+                let stack_size = self.stack_size();
+                let rbp = self.cpu.regs.rbp;
+                self.gpr_write(RSP, stack_size, rbp);
+                let size = self.stack_opsize();
+                let value = self.pop(size)?;
+                self.gpr_write(RBP, size, value);
+            }
+            // far ret
+            0xca | 0xcb => {
+                let release = if op == 0xca { self.fetch(2)? } else { 0 };
+                let offset = self.pop(size)?;
+                let selector = self.pop(size)?;
+                let stack_size = self.stack_size();
+                let rsp = self.cpu.regs.rsp.wrapping_add(release);
+                self.gpr_write(RSP, stack_size, rsp);
+                self.far_jump(selector as u16, offset & mask(size))?;
+            }
+            // int3
+            0xcc => self.deliver(BP, None)?,
+            // int imm8
+            0xcd => {
+                let vector = self.fetch(1)?;
+                self.deliver(vector as u8, None)?;
+            }
+            // iret
+            0xcf => self.iret()?,
+            // loopne, loope, loop, jcxz
+            0xe0..=0xe3 => {
+                let displacement = self.fetch_simm(1, 8)?;
+                let amask = mask(self.addrsize);
+                let take = if op == 0xe3 {
+                    self.cpu.gpr(RCX) & amask == 0
+                } else {
+                    let count = (self.cpu.gpr(RCX) & amask).wrapping_sub(1);
+                    self.gpr_write(RCX, self.addrsize, count);
+                    let zf = self.flag(FLAG_ZF);
+                    count & amask != 0
+                        && match op {
+                            0xe0 => !zf,
+                            0xe1 => zf,
+                            _ => true,
+                        }
+                };
+                if take {
+                    self.jump_relative(displacement);
+                }
+            }
+            // in, out
+            0xe4..=0xe7 | 0xec..=0xef => {
+                let size = if op & 1 == 0 { 1 } else { size.min(4) };
+                let port = if op < 0xe8 {
+                    self.fetch(1)?
+                } else {
+                    self.cpu.gpr(RDX) & 0xffff
+                };
+                if op & 2 == 0 {
+                    let data = self.device_read(IoSpace::Pio, port, size)?;
+                    self.gpr_write(RAX, size, u64::from_le_bytes(data));
+                } else {
+                    let value = self.reg_read(RAX, size);
+                    self.device_write(IoSpace::Pio, port, size, value)?;
+                }
+            }
+            // call rel
+            0xe8 => {
+                let displacement = self.fetch_simm(self.imm_size(size), 8)?;
+                let rip = self.cpu.regs.rip;
+                self.push(rip, self.stack_opsize())?;
+                self.jump_relative(displacement);
+            }
+            // jmp rel
+            0xe9 | 0xeb => {
+                let imm_size = if op == 0xeb { 1 } else { self.imm_size(size) };
+                let displacement = self.fetch_simm(imm_size, 8)?;
+                self.jump_relative(displacement);
+            }
+            // far jmp
+            0xea if !code64 => {
+                let offset = self.fetch(size)?;
+                let selector = self.fetch(2)?;
+                self.far_jump(selector as u16, offset)?;
+            }
+            0xf4 => return Ok(Flow::Halt),
+            // cmc
+            0xf5 => {
+                let cf = self.flag(FLAG_CF);
+                self.set_flag(FLAG_CF, !cf);
+            }
+            // group 3
+            0xf6 | 0xf7 => {
+                let size = if op == 0xf6 { 1 } else { size };
+                let reg = (self.peek_u8()? >> 3) & 7;
+                let imm_size = if reg < 2 { self.imm_size(size) } else { 0 };
+                let m = self.modrm(imm_size)?;
+                self.group3(size, &m)?;
+            }
+            0xf8 => self.set_flag(FLAG_CF, false),
+            0xf9 => self.set_flag(FLAG_CF, true),
+            0xfa => self.set_flag(FLAG_IF, false),
+            0xfb => {
+                if !self.flag(FLAG_IF) {
+                    self.cpu.interrupt_shadow = true;
+                }
+                self.set_flag(FLAG_IF, true);
+            }
+            0xfc => self.set_flag(FLAG_DF, false),
+            0xfd => self.set_flag(FLAG_DF, true),
+            // group 4 and 5
+            0xfe | 0xff => {
+                let m = self.modrm(0)?;
+                let size = if op == 0xfe { 1 } else { size };
+                match m.reg & 7 {
+                    0 | 1 => {
+                        let value = self.read_op(m.operand, size)?;
+                        let result = self.inc_dec(size, value, m.reg & 7 == 1);
+                        self.write_op(m.operand, size, result)?;
+                    }
+                    // call near
+                    2 if op == 0xff => {
+                        let target = self.read_op(m.operand, self.stack_opsize())?;
+                        let rip = self.cpu.regs.rip;
+                        self.push(rip, self.stack_opsize())?;
+                        self.jump(target);
+                    }
+                    // call far
+                    3 if op == 0xff => {
+                        let Operand::Mem(seg, offset) = m.operand else {
+                            return Err(Fault::Exception(UD, None));
+                        };
+                        let target = self.read_mem(seg, offset, size)?;
+                        let selector = self.read_mem(seg, offset.wrapping_add(size as u64), 2)?;
+                        let cs = self.cpu.sregs.cs.selector as u64;
+                        let rip = self.cpu.regs.rip;
+                        self.push(cs, size)?;
+                        self.push(rip, size)?;
+                        self.far_jump(selector as u16, target)?;
+                    }
+                    // jmp near
+                    4 if op == 0xff => {
+                        let target = self.read_op(m.operand, self.stack_opsize())?;
+                        self.jump(target);
+                    }
+                    // jmp far
+                    5 if op == 0xff => {
+                        let Operand::Mem(seg, offset) = m.operand else {
+                            return Err(Fault::Exception(UD, None));
+                        };
+                        let target = self.read_mem(seg, offset, size)?;
+                        let selector = self.read_mem(seg, offset.wrapping_add(size as u64), 2)?;
+                        self.far_jump(selector as u16, target)?;
+                    }
+                    // push
+                    6 if op == 0xff => {
+                        let size = self.stack_opsize();
+                        let value = self.read_op(m.operand, size)?;
+                        self.push(value, size)?;
+                    }
+                    _ => return Err(Fault::Exception(UD, None)),
+                }
+            }
+            _ => return Err(Fault::Unsupported),
+        }
+        Ok(Flow::Next)
+    }
+
+    // Executes the two byte opcodes.
+    fn execute_0f(&mut self) -> Res<Flow> {
+        let op = self.fetch_u8()?;
+        let size = self.opsize;
+        let code64 = self.code64();
+        match op {
+            // group 6
+            0x00 => {
+                let m = self.modrm(0)?;
+                match m.reg & 7 {
+                    0 => {
+                        let selector = self.cpu.sregs.ldt.selector as u64;
+                        self.write_op(m.operand, 2, selector)?;
+                    }
+                    1 => {
+                        let selector = self.cpu.sregs.tr.selector as u64;
+                        self.write_op(m.operand, 2, selector)?;
+                    }
+                    2 | 3 => {
+                        let selector = self.read_op(m.operand, 2)? as u16;
+                        self.load_system_segment(selector, m.reg & 7 == 3)?;
+                    }
+                    _ => return Err(Fault::Unsupported),
+                }
+            }
+            // group 7
+            0x01 => {
+                let modrm = self.peek_u8()?;
+                match modrm {
+                    // xgetbv
+                    0xd0 => {
+                        self.fetch_u8()?;
+                        if self.cpu.regs.rcx as u32 != 0 {
+                            return Err(Fault::Exception(GP, Some(0)));
+                        }
+                        let xcr0 = self.cpu.xcr0;
+                        self.gpr_write(RAX, 4, xcr0);
+                        self.gpr_write(RDX, 4, xcr0 >> 32);
+                    }
+                    // xsetbv
+                    0xd1 => {
+                        self.fetch_u8()?;
+                        if self.cpu.regs.rcx as u32 != 0 {
+                            return Err(Fault::Exception(GP, Some(0)));
+                        }
+                        self.cpu.xcr0 =
+                            (self.cpu.regs.rax & 0xffff_ffff) | (self.cpu.regs.rdx << 32);
+                    }
+                    // swapgs
+                    0xf8 if code64 => {
+                        self.fetch_u8()?;
+                        let kernel_gs = self.cpu.read_msr(MSR_KERNEL_GS_BASE);
+                        let gs = self.cpu.sregs.gs.base;
+                        self.cpu.write_msr(MSR_KERNEL_GS_BASE, gs);
+                        self.cpu.sregs.gs.base = kernel_gs;
+                    }
+                    // rdtscp
+                    0xf9 => {
+                        self.fetch_u8()?;
+                        let tsc = self.cpu.read_msr(MSR_IA32_TSC);
+                        let aux = self.cpu.read_msr(MSR_TSC_AUX);
+                        self.gpr_write(RAX, 4, tsc);
+                        self.gpr_write(RDX, 4, tsc >> 32);
+                        self.gpr_write(RCX, 4, aux);
+                    }
+                    _ if modrm >> 6 == 3 && (modrm >> 3) & 7 != 4 && (modrm >> 3) & 7 != 6 => {
+                        return Err(Fault::Unsupported)
+                    }
+                    _ => {
+                        let m = self.modrm(0)?;
+                        // Descriptor table bases are 24 bits with a 16-bit operand size.
+                        let base_size = if code64 { 8 } else { 4 };
+                        let base_mask = if code64 || size != 2 {
+                            u64::MAX
+                        } else {
+                            0xff_ffff
+                        };
+                        match (m.reg & 7, m.operand) {
+                            (index @ (0 | 1), Operand::Mem(seg, offset)) => {
+                                let table = if index == 0 {
+                                    self.cpu.sregs.gdt
+                                } else {
+                                    self.cpu.sregs.idt
+                                };
+                                self.write_mem(seg, offset, 2, table.limit as u64)?;
+                                self.write_mem(
+                                    seg,
+                                    offset.wrapping_add(2),
+                                    base_size,
+                                    table.base & base_mask,
+                                )?;
+                            }
+                            (index @ (2 | 3), Operand::Mem(seg, offset)) => {
+                                let limit = self.read_mem(seg, offset, 2)? as u16;
+                                let base = self.read_mem(seg, offset.wrapping_add(2), base_size)?
+                                    & base_mask;
+                                let table = if index == 2 {
+                                    &mut self.cpu.sregs.gdt
+                                } else {
+                                    &mut self.cpu.sregs.idt
+                                };
+                                table.limit = limit;
+                                table.base = base;
+                            }
+                            // smsw
+                            (4, operand) => {
+                                let cr0 = self.cpu.sregs.cr0;
+                                let size = if let Operand::Reg(_) = operand {
+                                    size
+                                } else {
+                                    2
+                                };
+                                self.write_op(operand, size, cr0)?;
+                            }
+                            // lmsw, which can set but not clear PE
+                            (6, operand) => {
+                                let value = self.read_op(operand, 2)?;
+                                let cr0 = self.cpu.sregs.cr0;
+                                self.write_cr(0, (cr0 & !0xf) | (value & 0xf) | (cr0 & CR0_PE))?;
+                            }
+                            // invlpg, there is no TLB to flush
+                            (7, Operand::Mem(..)) => {}
+                            _ => return Err(Fault::Exception(UD, None)),
+                        }
+                    }
+                }
+            }
+            // clts
+            0x06 => self.cpu.sregs.cr0 &= !CR0_TS,
+            // invd, wbinvd
+            0x08 | 0x09 => {}
+            // ud2
+            0x0b => return Err(Fault::Exception(UD, None)),
+            // prefetch and hint nops, including endbr
+            0x0d | 0x18..=0x1f => {
+                self.modrm(0)?;
+            }
+            // mov r, cr / mov cr, r / mov r, dr / mov dr, r
+            0x20..=0x23 => {
+                let m = self.modrm(0)?;
+                let Operand::Reg(index) = m.operand else {
+                    return Err(Fault::Exception(UD, None));
+                };
+                let size = if code64 { 8 } else { 4 };
+                match op {
+                    0x20 => {
+                        let value = self.read_cr(m.reg)?;
+                        self.gpr_write(index, size, value);
+                    }
+                    0x21 => {
+                        let value = *self.dr_mut(m.reg)?;
+                        self.gpr_write(index, size, value);
+                    }
+                    0x22 => {
+                        let value = self.cpu.gpr(index) & mask(size);
+                        self.write_cr(m.reg, value)?;
+                    }
+                    _ => {
+                        let value = self.cpu.gpr(index) & mask(size);
+                        *self.dr_mut(m.reg)? = value;
+                    }
+                }
+            }
+            // wrmsr
+            0x30 => {
+                let index = self.cpu.regs.rcx as u32;
+                let value = (self.cpu.regs.rax & 0xffff_ffff) | (self.cpu.regs.rdx << 32);
+                if index == MSR_EFER {
+                    // LMA is read-only, it follows CR0.PG.
+                    let lma = self.cpu.sregs.efer & EFER_LMA;
+                    self.cpu.sregs.efer = (value & !EFER_LMA) | lma;
+                } else {
+                    self.cpu.write_msr(index, value);
+                }
+            }
+            // rdtsc, rdmsr
+            0x31 | 0x32 => {
+                let index = if op == 0x31 {
+                    MSR_IA32_TSC
+                } else {
+                    self.cpu.regs.rcx as u32
+                };
+                let value = self.cpu.read_msr(index);
+                self.gpr_write(RAX, 4, value);
+                self.gpr_write(RDX, 4, value >> 32);
+            }
+            // cmovcc
+            0x40..=0x4f => {
+                let m = self.modrm(0)?;
+                let value = self.read_op(m.operand, size)?;
+                if self.condition(op & 0xf) {
+                    self.gpr_write(m.reg, size, value);
+                } else if size == 4 {
+                    let current = self.cpu.gpr(m.reg);
+                    self.gpr_write(m.reg, 4, current);
+                }
+            }
+            // jcc rel
+            0x80..=0x8f => {
+                let displacement = self.fetch_simm(self.imm_size(size), 8)?;
+                if self.condition(op & 0xf) {
+                    self.jump_relative(displacement);
+                }
+            }
+            // setcc
+            0x90..=0x9f => {
+                let m = self.modrm(0)?;
+                let value = self.condition(op & 0xf) as u64;
+                self.write_op(m.operand, 1, value)?;
+            }
+            // push fs, push gs
+            0xa0 | 0xa8 => {
+                let seg = if op == 0xa0 { FS } else { GS };
+                let selector = self.cpu.segment(seg).selector as u64;
+                self.push(selector, self.stack_opsize())?;
+            }
+            // pop fs, pop gs
+            0xa1 | 0xa9 => {
+                let seg = if op == 0xa1 { FS } else { GS };
+                let selector = self.pop(self.stack_opsize())?;
+                self.load_segment(seg, selector as u16)?;
+            }
+            // cpuid
+            0xa2 => {
+                let [eax, ebx, ecx, edx] = self
+                    .cpu
+                    .cpuid(self.cpu.regs.rax as u32, self.cpu.regs.rcx as u32);
+                self.gpr_write(RAX, 4, eax as u64);
+                self.gpr_write(RBX, 4, ebx as u64);
+                self.gpr_write(RCX, 4, ecx as u64);
+                self.gpr_write(RDX, 4, edx as u64);
+            }
+            // bt, bts, btr, btc with a register bit offset
+            0xa3 | 0xab | 0xb3 | 0xbb => {
+                let m = self.modrm(0)?;
+                let offset = self.reg_read(m.reg, size);
+                let operand = match m.operand {
+                    // The bit offset can address bits outside of the memory operand.
+                    Operand::Mem(seg, address) => {
+                        let words =
+                            (sign_extend(offset, size) as i64) >> (size * 8).trailing_zeros();
+                        let address = address.wrapping_add((words * size as i64) as u64);
+                        Operand::Mem(seg, address & mask(self.addrsize))
+                    }
+                    reg => reg,
+                };
+                self.bit_test((op >> 3) & 3, size, operand, offset)?;
+            }
+            // shld, shrd
+            0xa4 | 0xa5 | 0xac | 0xad => {
+                let imm_size = if op & 1 == 0 { 1 } else { 0 };
+                let m = self.modrm(imm_size)?;
+                let count = if op & 1 == 0 {
+                    self.fetch(1)?
+                } else {
+                    self.cpu.regs.rcx
+                } & if size == 8 { 0x3f } else { 0x1f };
+                let dst = self.read_op(m.operand, size)?;
+                if count != 0 {
+                    let src = self.reg_read(m.reg, size);
+                    let bits = size as u32 * 8;
+                    let (result, cf) = if op < 0xa8 {
+                        let wide = ((dst as u128) << bits) | src as u128;
+                        (
+                            (wide << count >> bits) as u64,
+                            (wide >> (2 * bits - count as u32)) & 1,
+                        )
+                    } else {
+                        let wide = ((src as u128) << bits) | dst as u128;
+                        ((wide >> count) as u64, (wide >> (count - 1)) & 1)
+                    };
+                    let result = result & mask(size);
+                    let of = (result ^ dst) & sign_bit(size) != 0;
+                    self.set_result_flags(result, size, cf != 0, of, false);
+                    self.write_op(m.operand, size, result)?;
+                }
+            }
+            // imul r, r/m
+            0xaf => {
+                let m = self.modrm(0)?;
+                let a = self.reg_read(m.reg, size);
+                let b = self.read_op(m.operand, size)?;
+                let result = self.imul(size, a, b);
+                self.gpr_write(m.reg, size, result);
+            }
+            // fences
+            0xae => {
+                let modrm = self.fetch_u8()?;
+                if !matches!(modrm, 0xe8..=0xff) {
+                    return Err(Fault::Unsupported);
+                }
+            }
+            // cmpxchg
+            0xb0 | 0xb1 => {
+                let size = if op == 0xb0 { 1 } else { size };
+                let m = self.modrm(0)?;
+                let dst = self.read_op(m.operand, size)?;
+                let acc = self.reg_read(RAX, size);
+                self.alu(7, size, acc, dst);
+                if acc == dst {
+                    let src = self.reg_read(m.reg, size);
+                    self.write_op(m.operand, size, src)?;
+                } else {
+                    self.reg_write(RAX, size, dst);
+                }
+            }
+            // movzx, movsx
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let src_size = if op & 1 == 0 { 1 } else { 2 };
+                let m = self.modrm(0)?;
+                let mut value = self.read_op(m.operand, src_size)?;
+                if op >= 0xbe {
+                    value = sign_extend(value, src_size);
+                }
+                self.gpr_write(m.reg, size, value & mask(size));
+            }
+            // bt, bts, btr, btc with an immediate bit offset
+            0xba => {
+                let m = self.modrm(1)?;
+                let offset = self.fetch(1)?;
+                if m.reg & 7 < 4 {
+                    return Err(Fault::Exception(UD, None));
+                }
+                self.bit_test((m.reg & 3) as u8, size, m.operand, offset)?;
+            }
+            // bsf, bsr
+            0xbc | 0xbd => {
+                let m = self.modrm(0)?;
+                let value = self.read_op(m.operand, size)?;
+                self.set_flag(FLAG_ZF, value == 0);
+                if value != 0 {
+                    let index = if op == 0xbc {
+                        value.trailing_zeros()
+                    } else {
+                        63 - value.leading_zeros()
+                    };
+                    self.gpr_write(m.reg, size, index as u64);
+                }
+            }
+            // xadd
+            0xc0 | 0xc1 => {
+                let size = if op == 0xc0 { 1 } else { size };
+                let m = self.modrm(0)?;
+                let dst = self.read_op(m.operand, size)?;
+                let src = self.reg_read(m.reg, size);
+                let sum = self.alu(0, size, dst, src);
+                self.write_op(m.operand, size, sum)?;
+                self.reg_write(m.reg, size, dst);
+            }
+            // bswap
+            0xc8..=0xcf => {
+                let index = (op & 7) as usize | if self.rex & 1 != 0 { 8 } else { 0 };
+                let value = self.cpu.gpr(index);
+                let swapped = if size == 8 {
+                    value.swap_bytes()
+                } else {
+                    (value as u32).swap_bytes() as u64
+                };
+                self.gpr_write(index, size.max(4), swapped);
+            }
+            _ => return Err(Fault::Unsupported),
+        }
+        Ok(Flow::Next)
+    }
+
+    // bt (0), bts (1), btr (2) and btc (3).
+    fn bit_test(&mut self, op: u8, size: usize, operand: Operand, offset: u64) -> Res<()> {
+        let bit = 1u64 << (offset & (size as u64 * 8 - 1));
+        let value = self.read_op(operand, size)?;
+        self.set_flag(FLAG_CF, value & bit != 0);
+        let result = match op {
+            1 => value | bit,
+            2 => value & !bit,
+            3 => value ^ bit,
+            _ => return Ok(()),
+        };
+        self.write_op(operand, size, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEM_SIZE: usize = 0x40_0000;
+
+    // Memory at the bottom of the address space, devices above it.
+    struct TestBus {
+        mem: Vec<u8>,
+    }
+
+    impl TestBus {
+        fn new() -> TestBus {
+            TestBus {
+                mem: vec![0; MEM_SIZE],
+            }
+        }
+
+        fn load(&mut self, gpa: u64, data: &[u8]) {
+            self.mem[gpa as usize..gpa as usize + data.len()].copy_from_slice(data);
+        }
+
+        fn read_u64(&self, gpa: u64) -> u64 {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&self.mem[gpa as usize..gpa as usize + 8]);
+            u64::from_le_bytes(buf)
+        }
+    }
+
+    impl GuestBus for TestBus {
+        fn read(&mut self, gpa: u64, buf: &mut [u8]) -> bool {
+            let start = gpa as usize;
+            match self.mem.get(start..start + buf.len()) {
+                Some(data) => {
+                    buf.copy_from_slice(data);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn write(&mut self, gpa: u64, buf: &[u8]) -> bool {
+            let start = gpa as usize;
+            match self.mem.get_mut(start..start + buf.len()) {
+                Some(data) => {
+                    data.copy_from_slice(buf);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    fn real_mode_cpu(rip: u64) -> Cpu {
+        let mut cpu = Cpu::default();
+        cpu.regs.rip = rip;
+        cpu.regs.rflags = FLAG_FIXED;
+        cpu.sregs.cs.base = 0;
+        cpu.sregs.cs.selector = 0;
+        cpu.sregs.idt.limit = 0x3ff;
+        cpu.regs.rsp = 0x8000;
+        cpu
+    }
+
+    // Runs until an exit, completing device reads with `read_value`.
+    fn run(cpu: &mut Cpu, bus: &mut TestBus, read_value: u64) -> Exit {
+        for _ in 0..100_000 {
+            match cpu.step(bus) {
+                None => {}
+                Some(Exit::Io(IoAccess {
+                    space,
+                    address,
+                    size,
+                    data: None,
+                })) => cpu.complete_read(space, address, &read_value.to_le_bytes()[..size]),
+                Some(exit) => return exit,
+            }
+        }
+        panic!("guest did not exit");
+    }
+
+    #[test]
+    fn real_mode_arithmetic_loop() {
+        let mut bus = TestBus::new();
+        // Sums 1 to 10 in ax, then halts.
+        bus.load(
+            0x1000,
+            &[
+                0x31, 0xc0, // xor ax, ax
+                0xb9, 0x0a, 0x00, // mov cx, 10
+                0x01, 0xc8, // add ax, cx
+                0xe2, 0xfc, // loop -4
+                0xf4, // hlt
+            ],
+        );
+        let mut cpu = real_mode_cpu(0x1000);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(cpu.regs.rax, 55);
+        assert_eq!(cpu.regs.rcx, 0);
+        assert_eq!(cpu.regs.rip, 0x100a);
+    }
+
+    #[test]
+    fn mmio_and_pio() {
+        let mut bus = TestBus::new();
+        bus.load(
+            0x1000,
+            &[
+                0x88, 0x03, // mov [bp+di], al
+                0xe4, 0x20, // in al, 0x20
+                0x8a, 0x00, // mov al, [bx+si]
+                0xe6, 0x21, // out 0x21, al
+                0xf4, // hlt
+            ],
+        );
+        let mut cpu = real_mode_cpu(0x1000);
+        cpu.regs.rax = 0x33;
+        cpu.regs.rbp = 0x8000;
+        cpu.sregs.ss.base = 0x7f_0000;
+        cpu.sregs.ds.base = 0x50_0000;
+        cpu.regs.rbx = 0x10;
+
+        let write = |address, space, value: u8| {
+            let mut data = [0u8; 8];
+            data[0] = value;
+            Some(Exit::Io(IoAccess {
+                space,
+                address,
+                size: 1,
+                data: Some(data),
+            }))
+        };
+        let read = |address, space| {
+            Some(Exit::Io(IoAccess {
+                space,
+                address,
+                size: 1,
+                data: None,
+            }))
+        };
+        assert_eq!(cpu.step(&mut bus), write(0x7f_8000, IoSpace::Mmio, 0x33));
+        assert_eq!(cpu.regs.rip, 0x1002);
+        assert_eq!(cpu.step(&mut bus), read(0x20, IoSpace::Pio));
+        assert_eq!(cpu.regs.rip, 0x1002);
+        cpu.complete_read(IoSpace::Pio, 0x20, &[0x44]);
+        assert_eq!(cpu.step(&mut bus), None);
+        assert_eq!(cpu.regs.rax, 0x44);
+        assert_eq!(cpu.step(&mut bus), read(0x50_0010, IoSpace::Mmio));
+        cpu.complete_read(IoSpace::Mmio, 0x50_0010, &[0x55]);
+        assert_eq!(cpu.step(&mut bus), None);
+        assert_eq!(cpu.step(&mut bus), write(0x21, IoSpace::Pio, 0x55));
+        assert_eq!(cpu.step(&mut bus), Some(Exit::Halt));
+    }
+
+    #[test]
+    fn real_mode_interrupts() {
+        let mut bus = TestBus::new();
+        // The #DE and int 0x10 handlers increment bx, the #DE handler skips the 2-byte div.
+        bus.load(0, &0x0000_2000u32.to_le_bytes());
+        bus.load(0x40, &0x0000_2010u32.to_le_bytes());
+        bus.load(
+            0x2000,
+            &[
+                0x43, // inc bx
+                0x89, 0xe5, // mov bp, sp
+                0x83, 0x46, 0x00, 0x02, // add word [bp], 2
+                0xcf, // iret
+            ],
+        );
+        bus.load(0x2010, &[0x43, 0xcf]);
+        bus.load(
+            0x1000,
+            &[
+                0xfb, // sti
+                0xcd, 0x10, // int 0x10
+                0x31, 0xc9, // xor cx, cx
+                0xf7, 0xf1, // div cx
+                0xf4, // hlt
+                0xf4, // hlt
+            ],
+        );
+        let mut cpu = real_mode_cpu(0x1000);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(cpu.regs.rbx, 2);
+        assert_eq!(cpu.regs.rsp, 0x8000);
+        assert!(cpu.interrupts_enabled());
+
+        // External interrupts return after the hlt.
+        assert_eq!(cpu.interrupt(&mut bus, 0x10), None);
+        assert_eq!(cpu.regs.rip, 0x2010);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(cpu.regs.rbx, 3);
+        assert_eq!(cpu.regs.rip, 0x1009);
+    }
+
+    #[test]
+    fn rep_string_operations() {
+        let mut bus = TestBus::new();
+        bus.load(0x3000, b"hello");
+        bus.load(
+            0x1000,
+            &[
+                0xbe, 0x00, 0x30, // mov si, 0x3000
+                0xbf, 0x00, 0x40, // mov di, 0x4000
+                0xb9, 0x05, 0x00, // mov cx, 5
+                0xf3, 0xa4, // rep movsb
+                0xbe, 0x00, 0x30, // mov si, 0x3000
+                0xbf, 0x00, 0x40, // mov di, 0x4000
+                0xb9, 0x05, 0x00, // mov cx, 5
+                0xf3, 0xa6, // repe cmpsb
+                0xf4, // hlt
+            ],
+        );
+        let mut cpu = real_mode_cpu(0x1000);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(&bus.mem[0x4000..0x4005], b"hello");
+        assert_eq!(cpu.regs.rcx, 0);
+        assert!(cpu.regs.rflags & FLAG_ZF != 0);
+    }
+
+    // Sets up identity mapped 2M pages for the first 1G and 64-bit segments.
+    fn long_mode_cpu(bus: &mut TestBus, rip: u64) -> Cpu {
+        let pml4 = 0x10_0000;
+        bus.load(pml4, &(0x10_1000u64 | 3).to_le_bytes());
+        bus.load(0x10_1000, &(0x10_2000u64 | 3).to_le_bytes());
+        for i in 0..512u64 {
+            bus.load(0x10_2000 + i * 8, &((i << 21) | 0x83).to_le_bytes());
+        }
+        let mut cpu = Cpu::default();
+        cpu.sregs.cr0 = CR0_PE | CR0_PG;
+        cpu.sregs.cr3 = pml4;
+        cpu.sregs.cr4 = CR4_PAE;
+        cpu.sregs.efer = EFER_LME | EFER_LMA;
+        cpu.sregs.cs.l = 1;
+        cpu.regs.rip = rip;
+        cpu.regs.rflags = FLAG_FIXED;
+        cpu.regs.rsp = 0x20_0000;
+        cpu
+    }
+
+    #[test]
+    fn long_mode_calls_and_stores() {
+        let mut bus = TestBus::new();
+        bus.load(
+            0x1000,
+            &[
+                0x48, 0xb8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // mov rax, imm64
+                0xe8, 0x0a, 0x00, 0x00, 0x00, // call +10
+                0x48, 0x89, 0x05, 0xf0, 0x0f, 0x00, 0x00, // mov [rip+0xff0], rax
+                0xf4, // hlt
+                0x90, 0x90, 0x90, // padding
+                0x48, 0xf7, 0xd0, // not rax
+                0x41, 0x50, // push r8
+                0x41, 0x58, // pop r8
+                0xc3, // ret
+            ],
+        );
+        let mut cpu = long_mode_cpu(&mut bus, 0x1000);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(cpu.regs.rax, !0x1122_3344_5566_7788);
+        assert_eq!(bus.read_u64(0x2006), !0x1122_3344_5566_7788);
+        assert_eq!(cpu.regs.rsp, 0x20_0000);
+        // Accessed and dirty bits are set in the 2M page mapping the store.
+        assert_eq!(bus.read_u64(0x10_2000) & 0x60, 0x60);
+    }
+
+    #[test]
+    fn long_mode_page_fault() {
+        let mut bus = TestBus::new();
+        let mut cpu = long_mode_cpu(&mut bus, 0x1000);
+        // Unmap the second 2M page.
+        bus.load(0x10_2008, &0u64.to_le_bytes());
+        // IDT entry 14 pointing to a handler at 0x3000 in the same code segment.
+        let idt = 0x5000;
+        let gate = 0x3000u64 | (0x8 << 16) | (0x8e00 << 32);
+        bus.load(idt + 14 * 16, &gate.to_le_bytes());
+        bus.load(0x5800, &0x0020_9a00_0000_0000u64.to_le_bytes());
+        cpu.sregs.idt = crate::DescriptorTable {
+            base: idt,
+            limit: 0xfff,
+        };
+        cpu.sregs.gdt = crate::DescriptorTable {
+            base: 0x57f8,
+            limit: 0xf,
+        };
+        cpu.sregs.cs.selector = 0x8;
+        bus.load(
+            0x1000,
+            &[
+                0x48, 0xc7, 0xc0, 0x00, 0x00, 0x30, 0x00, // mov rax, 0x300000
+                0x8b, 0x18, // mov ebx, [rax]
+            ],
+        );
+        bus.load(0x3000, &[0xf4]);
+        assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+        assert_eq!(cpu.sregs.cr2, 0x30_0000);
+        assert_eq!(cpu.regs.rip, 0x3001);
+        // Error code, RIP of the faulting instruction, CS, RFLAGS, RSP and SS.
+        assert_eq!(cpu.regs.rsp, 0x20_0000 - 6 * 8);
+        assert_eq!(bus.read_u64(0x20_0000 - 6 * 8), 0);
+        assert_eq!(bus.read_u64(0x20_0000 - 5 * 8), 0x1007);
+    }
+
+    #[test]
+    fn long_mode_signed_divide_overflow() {
+        // The most negative dividend divided by -1, for each operand size.
+        let cases: [(&[u8], u64, u64); 4] = [
+            (&[0xf6, 0xf9], 0x8000, 0),                      // idiv cl
+            (&[0x66, 0xf7, 0xf9], 0, 0x8000),                // idiv cx
+            (&[0xf7, 0xf9], 0, 0x8000_0000),                 // idiv ecx
+            (&[0x48, 0xf7, 0xf9], 0, 0x8000_0000_0000_0000), // idiv rcx
+        ];
+        for (code, rax, rdx) in cases {
+            let mut bus = TestBus::new();
+            let mut cpu = long_mode_cpu(&mut bus, 0x1000);
+            // IDT entry 0 pointing to a handler at 0x3000 in the same code segment.
+            let idt = 0x5000;
+            let gate = 0x3000u64 | (0x8 << 16) | (0x8e00 << 32);
+            bus.load(idt, &gate.to_le_bytes());
+            bus.load(0x5800, &0x0020_9a00_0000_0000u64.to_le_bytes());
+            cpu.sregs.idt = crate::DescriptorTable {
+                base: idt,
+                limit: 0xfff,
+            };
+            cpu.sregs.gdt = crate::DescriptorTable {
+                base: 0x57f8,
+                limit: 0xf,
+            };
+            cpu.sregs.cs.selector = 0x8;
+            cpu.regs.rax = rax;
+            cpu.regs.rdx = rdx;
+            cpu.regs.rcx = u64::MAX;
+            bus.load(0x1000, code);
+            bus.load(0x3000, &[0xf4]);
+            assert_eq!(run(&mut cpu, &mut bus, 0), Exit::Halt);
+            assert_eq!(cpu.regs.rip, 0x3001);
+            // #DE is a fault, the saved RIP is the one of the idiv.
+            assert_eq!(bus.read_u64(0x20_0000 - 5 * 8), 0x1000);
+            assert_eq!(cpu.regs.rax, rax);
+            assert_eq!(cpu.regs.rdx, rdx);
+        }
+    }
+
+    #[test]
+    fn unsupported_instruction() {
+        let mut bus = TestBus::new();
+        // fld1
+        bus.load(0x1000, &[0xd9, 0xe8]);
+        let mut cpu = real_mode_cpu(0x1000);
+        assert_eq!(cpu.step(&mut bus), Some(Exit::Unsupported));
+        assert_eq!(cpu.regs.rip, 0x1000);
+    }
+}
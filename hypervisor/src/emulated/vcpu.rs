@@ -0,0 +1,494 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use base::error;
+use base::Error;
+use base::Result;
+use libc::EINVAL;
+use libc::EIO;
+use libc::ENOTSUP;
+use serde::Deserialize;
+use serde::Serialize;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::interpreter::Cpu;
+use super::interpreter::Exit;
+use super::interpreter::IoAccess;
+use super::interpreter::IoSpace;
+use super::vm::signal_ioevent;
+use super::vm::IoEvents;
+use super::vm::MemRegions;
+use super::vm::MemoryBus;
+use super::MSR_INDEX_LIST;
+use crate::CpuId;
+use crate::CpuIdEntry;
+use crate::DebugRegs;
+use crate::Fpu;
+use crate::HwWatchpoint;
+use crate::HypervHypercall;
+use crate::IoOperation;
+use crate::IoParams;
+use crate::Register;
+use crate::Regs;
+use crate::Sregs;
+use crate::Vcpu;
+use crate::VcpuExit;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::VcpuSignalHandle;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::VcpuSignalHandleInner;
+use crate::VcpuX86_64;
+use crate::Xsave;
+
+/// Vector of non-maskable interrupts.
+const NMI_VECTOR: u8 = 2;
+
+/// Events waiting to be delivered to the guest, saved by `get_interrupt_state`.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct InterruptState {
+    pending_interrupt: Option<u8>,
+    pending_nmi: bool,
+    interrupt_shadow: bool,
+}
+
+struct VcpuState {
+    cpu: Cpu,
+    fpu: Fpu,
+    xsave: Xsave,
+    pending_interrupt: Option<u8>,
+    pending_nmi: bool,
+    interrupt_window_requested: bool,
+    /// The device access reported by the last exit, until it is handled.
+    pending_io: Option<IoAccess>,
+    breakpoints: Vec<u64>,
+    singlestep: bool,
+    /// Set after a breakpoint exit so that the instruction at the breakpoint can be executed.
+    resume_from_breakpoint: bool,
+}
+
+/// A vCPU whose instructions are executed by an interpreter in the calling thread.
+pub struct EmulatedVcpu {
+    id: usize,
+    state: Arc<Mutex<VcpuState>>,
+    immediate_exit: Arc<AtomicBool>,
+    guest_mem: GuestMemory,
+    mem_regions: MemRegions,
+    ioevents: IoEvents,
+}
+
+impl EmulatedVcpu {
+    pub(super) fn new(
+        id: usize,
+        guest_mem: GuestMemory,
+        mem_regions: MemRegions,
+        ioevents: IoEvents,
+    ) -> EmulatedVcpu {
+        // The default registers are the architectural reset state.
+        let mut cpu = Cpu::default();
+        cpu.xcr0 = 1;
+        EmulatedVcpu {
+            id,
+            state: Arc::new(Mutex::new(VcpuState {
+                cpu,
+                fpu: Fpu::default(),
+                xsave: Xsave::new(0),
+                pending_interrupt: None,
+                pending_nmi: false,
+                interrupt_window_requested: false,
+                pending_io: None,
+                breakpoints: Vec::new(),
+                singlestep: false,
+                resume_from_breakpoint: false,
+            })),
+            immediate_exit: Arc::new(AtomicBool::new(false)),
+            guest_mem,
+            mem_regions,
+            ioevents,
+        }
+    }
+
+    /// Runs `f` with the interpreter state and access to guest memory.
+    fn with_bus<T>(&self, cpu: &mut Cpu, f: impl FnOnce(&mut Cpu, &mut MemoryBus) -> T) -> T {
+        let mut regions = self.mem_regions.lock();
+        let mut bus = MemoryBus {
+            guest_mem: &self.guest_mem,
+            regions: &mut regions,
+        };
+        f(cpu, &mut bus)
+    }
+
+    fn handle_access(
+        &self,
+        space: IoSpace,
+        handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        let access = match state.pending_io {
+            Some(access) if access.space == space => access,
+            _ => return Err(Error::new(EINVAL)),
+        };
+        state.pending_io = None;
+        let operation = match access.data {
+            Some(data) => IoOperation::Write { data },
+            None => IoOperation::Read,
+        };
+        let result = handle_fn(IoParams {
+            address: access.address,
+            size: access.size,
+            operation,
+        });
+        if access.data.is_none() {
+            // The instruction is executed again with the data on the next run.
+            let data = result.unwrap_or_default();
+            state
+                .cpu
+                .complete_read(space, access.address, &data[..access.size]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+struct EmulatedVcpuSignalHandle {
+    immediate_exit: Arc<AtomicBool>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl VcpuSignalHandleInner for EmulatedVcpuSignalHandle {
+    fn signal_immediate_exit(&self) {
+        // Atomic stores are async signal safe.
+        self.immediate_exit.store(true, Ordering::Release);
+    }
+}
+
+impl Vcpu for EmulatedVcpu {
+    /// Makes a shallow clone of this `Vcpu`.
+    fn try_clone(&self) -> Result<Self> {
+        Ok(EmulatedVcpu {
+            id: self.id,
+            state: self.state.clone(),
+            immediate_exit: self.immediate_exit.clone(),
+            guest_mem: self.guest_mem.clone(),
+            mem_regions: self.mem_regions.clone(),
+            ioevents: self.ioevents.clone(),
+        })
+    }
+
+    fn as_vcpu(&self) -> &dyn Vcpu {
+        self
+    }
+
+    fn run(&mut self) -> Result<VcpuExit> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        loop {
+            if self.immediate_exit.load(Ordering::Acquire) {
+                return Ok(VcpuExit::Intr);
+            }
+
+            if state.pending_nmi {
+                state.pending_nmi = false;
+                if let Some(exit) =
+                    self.with_bus(&mut state.cpu, |cpu, bus| cpu.interrupt(bus, NMI_VECTOR))
+                {
+                    return Ok(exit_reason(exit, state));
+                }
+            }
+            if state.cpu.interrupts_enabled() {
+                if let Some(vector) = state.pending_interrupt.take() {
+                    if let Some(exit) =
+                        self.with_bus(&mut state.cpu, |cpu, bus| cpu.interrupt(bus, vector))
+                    {
+                        return Ok(exit_reason(exit, state));
+                    }
+                } else if state.interrupt_window_requested {
+                    return Ok(VcpuExit::IrqWindowOpen);
+                }
+            }
+
+            if !state.resume_from_breakpoint && state.breakpoints.contains(&state.cpu.linear_rip())
+            {
+                state.resume_from_breakpoint = true;
+                return Ok(VcpuExit::Debug);
+            }
+            state.resume_from_breakpoint = false;
+
+            let exit = self.with_bus(&mut state.cpu, |cpu, bus| cpu.step(bus));
+            if let Some(Exit::Io(IoAccess {
+                space,
+                address,
+                size,
+                data: Some(data),
+            })) = exit
+            {
+                if signal_ioevent(&self.ioevents, space, address, size, data) {
+                    continue;
+                }
+            }
+            if let Some(exit) = exit {
+                return Ok(exit_reason(exit, state));
+            }
+            if state.singlestep {
+                return Ok(VcpuExit::Debug);
+            }
+        }
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_immediate_exit(&self, exit: bool) {
+        self.immediate_exit.store(exit, Ordering::Release);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn signal_handle(&self) -> VcpuSignalHandle {
+        VcpuSignalHandle {
+            inner: Box::new(EmulatedVcpuSignalHandle {
+                immediate_exit: self.immediate_exit.clone(),
+            }),
+        }
+    }
+
+    fn handle_mmio(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_access(IoSpace::Mmio, handle_fn)
+    }
+
+    fn handle_io(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        self.handle_access(IoSpace::Pio, handle_fn)
+    }
+
+    fn handle_hyperv_hypercall(&self, _func: &mut dyn FnMut(HypervHypercall) -> u64) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    /// MSR accesses are handled by the interpreter, there are no MSR exits.
+    fn handle_rdmsr(&self, _data: u64) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn handle_wrmsr(&self) {}
+
+    fn on_suspend(&self) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn enable_raw_capability(&self, _cap: u32, _args: &[u64; 4]) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+}
+
+// Converts an interpreter exit to the exit reported to the VMM.
+fn exit_reason(exit: Exit, state: &mut VcpuState) -> VcpuExit {
+    match exit {
+        Exit::Halt => VcpuExit::Hlt,
+        Exit::Io(access) => {
+            state.pending_io = Some(access);
+            match access.space {
+                IoSpace::Pio => VcpuExit::Io,
+                IoSpace::Mmio => VcpuExit::Mmio,
+            }
+        }
+        Exit::Shutdown => VcpuExit::Shutdown,
+        Exit::Unsupported => {
+            error!("unsupported instruction at {:#x}", state.cpu.linear_rip());
+            VcpuExit::InternalError
+        }
+    }
+}
+
+impl VcpuX86_64 for EmulatedVcpu {
+    fn set_interrupt_window_requested(&self, requested: bool) {
+        self.state.lock().interrupt_window_requested = requested;
+    }
+
+    fn ready_for_interrupt(&self) -> bool {
+        let state = self.state.lock();
+        state.cpu.interrupts_enabled() && state.pending_interrupt.is_none()
+    }
+
+    fn interrupt(&self, irq: u32) -> Result<()> {
+        let vector = u8::try_from(irq).map_err(|_| Error::new(EINVAL))?;
+        self.state.lock().pending_interrupt = Some(vector);
+        Ok(())
+    }
+
+    fn inject_nmi(&self) -> Result<()> {
+        self.state.lock().pending_nmi = true;
+        Ok(())
+    }
+
+    fn get_regs(&self) -> Result<Regs> {
+        Ok(self.state.lock().cpu.regs)
+    }
+
+    fn set_regs(&self, regs: &Regs) -> Result<()> {
+        self.state.lock().cpu.regs = *regs;
+        Ok(())
+    }
+
+    fn get_sregs(&self) -> Result<Sregs> {
+        Ok(self.state.lock().cpu.sregs)
+    }
+
+    fn set_sregs(&self, sregs: &Sregs) -> Result<()> {
+        self.state.lock().cpu.sregs = *sregs;
+        Ok(())
+    }
+
+    /// The FPU state is stored but not used, the interpreter has no floating point support.
+    fn get_fpu(&self) -> Result<Fpu> {
+        Ok(self.state.lock().fpu)
+    }
+
+    fn set_fpu(&self, fpu: &Fpu) -> Result<()> {
+        self.state.lock().fpu = *fpu;
+        Ok(())
+    }
+
+    fn get_debugregs(&self) -> Result<DebugRegs> {
+        Ok(self.state.lock().cpu.debugregs)
+    }
+
+    fn set_debugregs(&self, debugregs: &DebugRegs) -> Result<()> {
+        self.state.lock().cpu.debugregs = *debugregs;
+        Ok(())
+    }
+
+    fn get_xcrs(&self) -> Result<Vec<Register>> {
+        Ok(vec![Register {
+            id: 0,
+            value: self.state.lock().cpu.xcr0,
+        }])
+    }
+
+    fn set_xcrs(&self, xcrs: &[Register]) -> Result<()> {
+        let mut state = self.state.lock();
+        for xcr in xcrs {
+            if xcr.id != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            state.cpu.xcr0 = xcr.value;
+        }
+        Ok(())
+    }
+
+    fn get_xsave(&self) -> Result<Xsave> {
+        Ok(self.state.lock().xsave.clone())
+    }
+
+    fn set_xsave(&self, xsave: &Xsave) -> Result<()> {
+        self.state.lock().xsave = xsave.clone();
+        Ok(())
+    }
+
+    fn get_interrupt_state(&self) -> Result<serde_json::Value> {
+        let state = self.state.lock();
+        serde_json::to_value(InterruptState {
+            pending_interrupt: state.pending_interrupt,
+            pending_nmi: state.pending_nmi,
+            interrupt_shadow: state.cpu.interrupt_shadow,
+        })
+        .map_err(|e| {
+            error!("failed to serialize interrupt state: {:?}", e);
+            Error::new(EIO)
+        })
+    }
+
+    fn set_interrupt_state(&self, data: serde_json::Value) -> Result<()> {
+        let interrupt_state: InterruptState = serde_json::from_value(data).map_err(|e| {
+            error!("failed to deserialize interrupt state: {:?}", e);
+            Error::new(EIO)
+        })?;
+        let mut state = self.state.lock();
+        state.pending_interrupt = interrupt_state.pending_interrupt;
+        state.pending_nmi = interrupt_state.pending_nmi;
+        state.cpu.interrupt_shadow = interrupt_state.interrupt_shadow;
+        Ok(())
+    }
+
+    fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()> {
+        let state = self.state.lock();
+        for msr in msrs.iter_mut() {
+            msr.value = state.cpu.read_msr(msr.id);
+        }
+        Ok(())
+    }
+
+    fn get_all_msrs(&self) -> Result<Vec<Register>> {
+        let state = self.state.lock();
+        let mut indexes = MSR_INDEX_LIST.to_vec();
+        indexes.extend(
+            state
+                .cpu
+                .msrs
+                .keys()
+                .filter(|i| !MSR_INDEX_LIST.contains(i)),
+        );
+        Ok(indexes
+            .into_iter()
+            .map(|id| Register {
+                id,
+                value: state.cpu.read_msr(id),
+            })
+            .collect())
+    }
+
+    fn set_msrs(&self, msrs: &[Register]) -> Result<()> {
+        let mut state = self.state.lock();
+        for msr in msrs {
+            state.cpu.write_msr(msr.id, msr.value);
+        }
+        Ok(())
+    }
+
+    fn set_cpuid(&self, cpuid: &CpuId) -> Result<()> {
+        self.state.lock().cpu.cpuid = cpuid.cpu_id_entries.clone();
+        Ok(())
+    }
+
+    fn get_hyperv_cpuid(&self) -> Result<CpuId> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
+        if !watchpoints.is_empty() {
+            return Err(Error::new(ENOTSUP));
+        }
+        let mut state = self.state.lock();
+        state.breakpoints = addrs.iter().map(|addr| addr.offset()).collect();
+        state.singlestep = enable_singlestep;
+        Ok(())
+    }
+
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    /// CPUID is executed by the interpreter from the table given to `set_cpuid`.
+    fn handle_cpuid(&mut self, _entry: &CpuIdEntry) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn get_tsc_offset(&self) -> Result<u64> {
+        Ok(self.state.lock().cpu.tsc_offset)
+    }
+
+    fn set_tsc_offset(&self, offset: u64) -> Result<()> {
+        self.state.lock().cpu.tsc_offset = offset;
+        Ok(())
+    }
+}
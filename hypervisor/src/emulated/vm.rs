@@ -0,0 +1,541 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
+use std::ptr::copy_nonoverlapping;
+use std::sync::Arc;
+
+use base::error;
+use base::pagesize;
+use base::AsRawDescriptor;
+use base::Error;
+use base::Event;
+use base::MappedRegion;
+use base::MmapError;
+use base::Protection;
+use base::Result;
+use base::SafeDescriptor;
+use libc::EEXIST;
+use libc::EFAULT;
+use libc::EINVAL;
+use libc::EIO;
+use libc::ENODEV;
+use libc::ENOENT;
+use libc::ENOSPC;
+use libc::ENXIO;
+use libc::EOVERFLOW;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::interpreter::GuestBus;
+use super::interpreter::IoSpace;
+use super::Emulated;
+use super::EmulatedVcpu;
+use super::GUEST_PHYS_ADDR_BITS;
+use crate::BalloonEvent;
+use crate::ClockState;
+use crate::Datamatch;
+use crate::DeviceKind;
+use crate::Hypervisor;
+use crate::HypervisorX86_64;
+use crate::IoEventAddress;
+use crate::MemSlot;
+use crate::VcpuX86_64;
+use crate::Vm;
+use crate::VmCap;
+use crate::VmX86_64;
+
+/// A memory region added with `add_memory_region`.
+pub(super) struct UserMemoryRegion {
+    guest_addr: GuestAddress,
+    mem: Box<dyn MappedRegion>,
+    read_only: bool,
+    /// One bit per page written since the last `get_dirty_log`, if dirty logging is enabled.
+    dirty_bitmap: Option<Vec<u8>>,
+}
+
+impl UserMemoryRegion {
+    // Returns the offset of `[gpa, gpa + len)` in the region, if it is contained in it.
+    fn offset_of(&self, gpa: u64, len: usize) -> Option<usize> {
+        let offset = gpa.checked_sub(self.guest_addr.offset())? as usize;
+        if offset.checked_add(len)? <= self.mem.size() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+}
+
+pub(super) type MemRegions = Arc<Mutex<BTreeMap<MemSlot, UserMemoryRegion>>>;
+
+pub(super) struct IoEvent {
+    addr: IoEventAddress,
+    datamatch: Datamatch,
+    evt: Event,
+}
+
+pub(super) type IoEvents = Arc<Mutex<Vec<IoEvent>>>;
+
+/// Signals the ioevent matching a guest write, if any. Returns true if one was signaled.
+pub(super) fn signal_ioevent(
+    ioevents: &IoEvents,
+    space: IoSpace,
+    address: u64,
+    size: usize,
+    data: [u8; 8],
+) -> bool {
+    let addr = match space {
+        IoSpace::Pio => IoEventAddress::Pio(address),
+        IoSpace::Mmio => IoEventAddress::Mmio(address),
+    };
+    let value = u64::from_le_bytes(data);
+    let matches = |datamatch: Datamatch| match datamatch {
+        Datamatch::AnyLength => true,
+        Datamatch::U8(v) => size == 1 && v.map_or(true, |v| v as u64 == value),
+        Datamatch::U16(v) => size == 2 && v.map_or(true, |v| v as u64 == value),
+        Datamatch::U32(v) => size == 4 && v.map_or(true, |v| v as u64 == value),
+        Datamatch::U64(v) => size == 8 && v.map_or(true, |v| v == value),
+    };
+    let ioevents = ioevents.lock();
+    match ioevents
+        .iter()
+        .find(|e| e.addr == addr && matches(e.datamatch))
+    {
+        Some(ioevent) => {
+            if let Err(e) = ioevent.evt.signal() {
+                error!("failed to signal ioevent at {:?}: {}", addr, e);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Access to the guest physical memory of an `EmulatedVm`, for the interpreter.
+pub(super) struct MemoryBus<'a> {
+    pub guest_mem: &'a GuestMemory,
+    pub regions: &'a mut BTreeMap<MemSlot, UserMemoryRegion>,
+}
+
+impl GuestBus for MemoryBus<'_> {
+    fn read(&mut self, gpa: u64, buf: &mut [u8]) -> bool {
+        if self
+            .guest_mem
+            .read_exact_at_addr(buf, GuestAddress(gpa))
+            .is_ok()
+        {
+            return true;
+        }
+        for region in self.regions.values() {
+            if let Some(offset) = region.offset_of(gpa, buf.len()) {
+                // Safe because the range was checked to be in the mapping, which lives as long
+                // as the region.
+                unsafe {
+                    copy_nonoverlapping(
+                        region.mem.as_ptr().add(offset),
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                    )
+                };
+                return true;
+            }
+        }
+        false
+    }
+
+    fn write(&mut self, gpa: u64, buf: &[u8]) -> bool {
+        if self
+            .guest_mem
+            .write_all_at_addr(buf, GuestAddress(gpa))
+            .is_ok()
+        {
+            return true;
+        }
+        for region in self.regions.values_mut() {
+            if let Some(offset) = region.offset_of(gpa, buf.len()) {
+                if region.read_only {
+                    return false;
+                }
+                // Safe because the range was checked to be in the mapping, which lives as long
+                // as the region.
+                unsafe {
+                    copy_nonoverlapping(buf.as_ptr(), region.mem.as_ptr().add(offset), buf.len())
+                };
+                if let Some(bitmap) = &mut region.dirty_bitmap {
+                    let page_size = pagesize();
+                    for page in offset / page_size..=(offset + buf.len() - 1) / page_size {
+                        bitmap[page / 8] |= 1 << (page % 8);
+                    }
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn dirty_log_bitmap_size(size: usize) -> usize {
+    let page_size = pagesize();
+    (((size + page_size - 1) / page_size) + 7) / 8
+}
+
+/// A VM whose vCPUs are emulated by an instruction interpreter.
+pub struct EmulatedVm {
+    emulated: Emulated,
+    guest_mem: GuestMemory,
+    mem_regions: MemRegions,
+    /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
+    mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    ioevents: IoEvents,
+}
+
+impl EmulatedVm {
+    /// Constructs a new `EmulatedVm` using the given guest memory.
+    pub fn new(emulated: &Emulated, guest_mem: GuestMemory) -> Result<EmulatedVm> {
+        Ok(EmulatedVm {
+            emulated: emulated.try_clone()?,
+            guest_mem,
+            mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
+            mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            ioevents: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    fn handle_inflate(&mut self, guest_address: GuestAddress, size: u64) -> Result<()> {
+        match self.guest_mem.remove_range(guest_address, size) {
+            Ok(_) => Ok(()),
+            Err(vm_memory::Error::MemoryAccess(_, MmapError::SystemCallFailed(e))) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+}
+
+impl Vm for EmulatedVm {
+    /// Makes a shallow clone of this `Vm`.
+    fn try_clone(&self) -> Result<Self> {
+        Ok(EmulatedVm {
+            emulated: self.emulated.try_clone()?,
+            guest_mem: self.guest_mem.clone(),
+            mem_regions: self.mem_regions.clone(),
+            mem_slot_gaps: self.mem_slot_gaps.clone(),
+            ioevents: self.ioevents.clone(),
+        })
+    }
+
+    fn check_capability(&self, c: VmCap) -> bool {
+        match c {
+            VmCap::DirtyLog => true,
+            VmCap::DirtyLogRing => false,
+            VmCap::PvClock => false,
+            VmCap::Protected => false,
+            VmCap::EarlyInitCpuid => false,
+            VmCap::BusLockDetect => false,
+        }
+    }
+
+    fn get_guest_phys_addr_bits(&self) -> u8 {
+        GUEST_PHYS_ADDR_BITS
+    }
+
+    fn get_memory(&self) -> &GuestMemory {
+        &self.guest_mem
+    }
+
+    fn add_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        read_only: bool,
+        log_dirty_pages: bool,
+    ) -> Result<MemSlot> {
+        let size = mem.size() as u64;
+        let end_addr = guest_addr.checked_add(size).ok_or(Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        let mut regions = self.mem_regions.lock();
+        let overlaps = regions.values().any(|region| {
+            guest_addr < region.guest_addr.unchecked_add(region.mem.size() as u64)
+                && region.guest_addr < end_addr
+        });
+        if overlaps {
+            return Err(Error::new(ENOSPC));
+        }
+        let slot = match self.mem_slot_gaps.lock().pop() {
+            Some(gap) => gap.0,
+            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+        };
+        let dirty_bitmap = if log_dirty_pages {
+            Some(vec![0; dirty_log_bitmap_size(mem.size())])
+        } else {
+            None
+        };
+        regions.insert(
+            slot,
+            UserMemoryRegion {
+                guest_addr,
+                mem,
+                read_only,
+                dirty_bitmap,
+            },
+        );
+        Ok(slot)
+    }
+
+    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let region = regions.get_mut(&slot).ok_or(Error::new(ENOENT))?;
+
+        region.mem.msync(offset, size).map_err(|err| match err {
+            MmapError::InvalidAddress => Error::new(EFAULT),
+            MmapError::NotPageAligned => Error::new(EINVAL),
+            MmapError::SystemCallFailed(e) => e,
+            _ => Error::new(EIO),
+        })
+    }
+
+    fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
+        let mut regions = self.mem_regions.lock();
+        let region = regions.remove(&slot).ok_or(Error::new(ENOENT))?;
+        self.mem_slot_gaps.lock().push(Reverse(slot));
+        Ok(region.mem)
+    }
+
+    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
+        // There are no in-kernel devices.
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let region = regions.get_mut(&slot).ok_or(Error::new(ENOENT))?;
+        // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
+        if dirty_log_bitmap_size(region.mem.size()) > dirty_log.len() {
+            return Err(Error::new(EINVAL));
+        }
+        let bitmap = region.dirty_bitmap.as_mut().ok_or(Error::new(ENOENT))?;
+        dirty_log[..bitmap.len()].copy_from_slice(bitmap);
+        bitmap.fill(0);
+        Ok(())
+    }
+
+    fn register_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut ioevents = self.ioevents.lock();
+        if ioevents
+            .iter()
+            .any(|e| e.addr == addr && e.datamatch == datamatch)
+        {
+            return Err(Error::new(EEXIST));
+        }
+        ioevents.push(IoEvent {
+            addr,
+            datamatch,
+            evt: evt.try_clone()?,
+        });
+        Ok(())
+    }
+
+    fn unregister_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut ioevents = self.ioevents.lock();
+        let index = ioevents
+            .iter()
+            .position(|e| e.addr == addr && e.datamatch == datamatch && &e.evt == evt)
+            .ok_or(Error::new(ENOENT))?;
+        ioevents.remove(index);
+        Ok(())
+    }
+
+    /// Ioevents are signaled by the vCPUs when the guest writes to their address, so this is a
+    /// no-op.
+    fn handle_io_events(&self, _addr: IoEventAddress, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_pvclock(&self) -> Result<ClockState> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn set_pvclock(&self, _state: &ClockState) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn add_fd_mapping(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        size: usize,
+        fd: &dyn AsRawDescriptor,
+        fd_offset: u64,
+        prot: Protection,
+    ) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let region = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
+
+        match region.mem.add_fd_mapping(offset, size, fd, fd_offset, prot) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
+        let mut regions = self.mem_regions.lock();
+        let region = regions.get_mut(&slot).ok_or(Error::new(EINVAL))?;
+
+        match region.mem.remove_mapping(offset, size) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn handle_balloon_event(&mut self, event: BalloonEvent) -> Result<()> {
+        match event {
+            BalloonEvent::Inflate(m) => self.handle_inflate(m.guest_address, m.size),
+            // Deflated pages are provided again on the next access.
+            BalloonEvent::Deflate(_) => Ok(()),
+            BalloonEvent::BalloonTargetReached(_) => Ok(()),
+        }
+    }
+}
+
+impl VmX86_64 for EmulatedVm {
+    fn get_hypervisor(&self) -> &dyn HypervisorX86_64 {
+        &self.emulated
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>> {
+        Ok(Box::new(EmulatedVcpu::new(
+            id,
+            self.guest_mem.clone(),
+            self.mem_regions.clone(),
+            self.ioevents.clone(),
+        )))
+    }
+
+    /// The TSS region is only needed by KVM on hosts without unrestricted guest support.
+    fn set_tss_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+
+    /// The identity map region is only needed by KVM on hosts without unrestricted guest support.
+    fn set_identity_map_addr(&self, _addr: GuestAddress) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::MemoryMappingBuilder;
+
+    use super::*;
+
+    fn new_vm() -> EmulatedVm {
+        let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        EmulatedVm::new(&Emulated::new(), guest_mem).unwrap()
+    }
+
+    fn mapping(size: usize) -> Box<dyn MappedRegion> {
+        Box::new(MemoryMappingBuilder::new(size).build().unwrap())
+    }
+
+    #[test]
+    fn add_memory_region_slots() {
+        let mut vm = new_vm();
+        let slot = vm
+            .add_memory_region(GuestAddress(0x10000), mapping(0x1000), false, false)
+            .unwrap();
+        assert_eq!(slot, 1);
+        // Overlaps guest memory, then the first region.
+        assert!(vm
+            .add_memory_region(GuestAddress(0), mapping(0x1000), false, false)
+            .is_err());
+        assert!(vm
+            .add_memory_region(GuestAddress(0xf000), mapping(0x2000), false, false)
+            .is_err());
+        vm.remove_memory_region(slot).unwrap();
+        assert_eq!(
+            vm.add_memory_region(GuestAddress(0xf000), mapping(0x2000), false, false)
+                .unwrap(),
+            slot
+        );
+    }
+
+    #[test]
+    fn memory_bus_read_only_and_dirty_log() {
+        let mut vm = new_vm();
+        let ro = vm
+            .add_memory_region(GuestAddress(0x10000), mapping(0x1000), true, false)
+            .unwrap();
+        let rw = vm
+            .add_memory_region(GuestAddress(0x20000), mapping(0x2000), false, true)
+            .unwrap();
+        {
+            let mut regions = vm.mem_regions.lock();
+            let mut bus = MemoryBus {
+                guest_mem: &vm.guest_mem,
+                regions: &mut regions,
+            };
+            assert!(bus.write(0x800, &[1, 2]));
+            assert!(!bus.write(0x10000, &[1]));
+            assert!(bus.write(0x21ffe, &[3, 4]));
+            let mut buf = [0u8; 2];
+            assert!(bus.read(0x21ffe, &mut buf));
+            assert_eq!(buf, [3, 4]);
+            // Crosses the end of the region.
+            assert!(!bus.read(0x21fff, &mut buf));
+        }
+        let mut dirty_log = [0xffu8];
+        vm.get_dirty_log(rw, &mut dirty_log).unwrap();
+        assert_eq!(dirty_log[0], 0b10);
+        vm.get_dirty_log(rw, &mut dirty_log).unwrap();
+        assert_eq!(dirty_log[0], 0);
+        assert!(vm.get_dirty_log(ro, &mut dirty_log).is_err());
+    }
+
+    #[test]
+    fn ioevent_datamatch() {
+        let mut vm = new_vm();
+        let evt = Event::new().unwrap();
+        vm.register_ioevent(&evt, IoEventAddress::Pio(0x10), Datamatch::U8(Some(0x42)))
+            .unwrap();
+        assert!(!signal_ioevent(
+            &vm.ioevents,
+            IoSpace::Pio,
+            0x10,
+            1,
+            [0x41, 0, 0, 0, 0, 0, 0, 0]
+        ));
+        assert!(signal_ioevent(
+            &vm.ioevents,
+            IoSpace::Pio,
+            0x10,
+            1,
+            [0x42, 0, 0, 0, 0, 0, 0, 0]
+        ));
+        evt.wait().unwrap();
+        vm.unregister_ioevent(&evt, IoEventAddress::Pio(0x10), Datamatch::U8(Some(0x42)))
+            .unwrap();
+        assert!(!signal_ioevent(
+            &vm.ioevents,
+            IoSpace::Pio,
+            0x10,
+            1,
+            [0x42, 0, 0, 0, 0, 0, 0, 0]
+        ));
+    }
+}
@@ -882,6 +882,7 @@ impl Vm for GeniezoneVm {
         }
         match c {
             VmCap::DirtyLog => true,
+            VmCap::DirtyLogRing => false,
             VmCap::PvClock => false,
             VmCap::Protected => self.check_raw_capability(GeniezoneCap::ArmProtectedVm),
             VmCap::EarlyInitCpuid => false,
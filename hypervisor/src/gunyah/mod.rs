@@ -414,6 +414,7 @@ impl Vm for GunyahVm {
     fn check_capability(&self, c: VmCap) -> bool {
         match c {
             VmCap::DirtyLog => false,
+            VmCap::DirtyLogRing => false,
             // Strictly speaking, Gunyah supports pvclock, but Gunyah takes care
             // of it and crosvm doesn't need to do anything for it
             VmCap::PvClock => false,
@@ -34,6 +34,7 @@ use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::Fpu;
+use crate::HwWatchpoint;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -565,7 +566,17 @@ impl VcpuX86_64 for HaxmVcpu {
         Err(Error::new(libc::ENXIO))
     }
 
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
@@ -19,12 +19,19 @@ use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
 use std::convert::TryFrom;
 use std::ffi::CString;
+use std::mem::size_of;
 use std::os::raw::c_ulong;
 use std::os::raw::c_void;
 use std::os::unix::prelude::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::ptr::addr_of;
+use std::ptr::addr_of_mut;
 use std::ptr::copy_nonoverlapping;
+use std::ptr::read_volatile;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use base::errno_result;
@@ -50,12 +57,15 @@ use base::SafeDescriptor;
 use data_model::vec_with_array_field;
 use kvm_sys::*;
 use libc::open64;
+use libc::EBUSY;
 use libc::EFAULT;
 use libc::EINVAL;
 use libc::EIO;
 use libc::ENOENT;
 use libc::ENOSPC;
 use libc::ENOSYS;
+use libc::ENOTSUP;
+use libc::ENXIO;
 use libc::EOVERFLOW;
 use libc::O_CLOEXEC;
 use libc::O_RDWR;
@@ -219,6 +229,10 @@ pub struct KvmVm {
     mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// The size in bytes of the dirty ring of each vcpu, once enabled with `enable_dirty_ring`
+    dirty_ring_size: Arc<Mutex<Option<usize>>>,
+    /// Whether a vcpu was created, after which the dirty ring can't be enabled anymore
+    vcpu_created: Arc<AtomicBool>,
 }
 
 impl KvmVm {
@@ -259,6 +273,8 @@ impl KvmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            dirty_ring_size: Arc::new(Mutex::new(None)),
+            vcpu_created: Arc::new(AtomicBool::new(false)),
         };
         vm.init_arch(&cfg)?;
         Ok(vm)
@@ -266,6 +282,9 @@ impl KvmVm {
 
     pub fn create_kvm_vcpu(&self, id: usize) -> Result<KvmVcpu> {
         let run_mmap_size = self.kvm.get_vcpu_mmap_size()?;
+        // Held until the vcpu is created so that `enable_dirty_ring` can't race with it.
+        let dirty_ring_size = self.dirty_ring_size.lock();
+        self.vcpu_created.store(true, Ordering::Relaxed);
 
         // Safe because we know that our file is a VM fd and we verify the return result.
         let fd = unsafe { ioctl_with_val(self, KVM_CREATE_VCPU(), c_ulong::try_from(id).unwrap()) };
@@ -286,6 +305,11 @@ impl KvmVm {
             .build()
             .map_err(|_| Error::new(ENOSPC))?;
 
+        let dirty_ring = match *dirty_ring_size {
+            Some(size) => Some(Arc::new(KvmDirtyRing::new(&vcpu, size)?)),
+            None => None,
+        };
+
         let cap_kvmclock_ctrl = self.check_raw_capability(KvmCap::KvmclockCtrl);
 
         Ok(KvmVcpu {
@@ -295,6 +319,7 @@ impl KvmVm {
             id,
             cap_kvmclock_ctrl,
             run_mmap: Arc::new(run_mmap),
+            dirty_ring,
         })
     }
 
@@ -471,6 +496,8 @@ impl KvmVm {
                     false
                 }
             }
+            // These return the maximum ring size in bytes.
+            KvmCap::DirtyLogRing | KvmCap::DirtyLogRingAcqRel => ret > 0,
             _ => ret == 1,
         }
     }
@@ -527,6 +554,8 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            dirty_ring_size: self.dirty_ring_size.clone(),
+            vcpu_created: self.vcpu_created.clone(),
         })
     }
 
@@ -536,6 +565,13 @@ impl Vm for KvmVm {
         }
         match c {
             VmCap::DirtyLog => true,
+            // The offset of the ring in the vcpu mapping is only known for x86_64, the headers of
+            // the other architectures define it as 0.
+            VmCap::DirtyLogRing => {
+                cfg!(target_arch = "x86_64")
+                    && (self.check_raw_capability(KvmCap::DirtyLogRingAcqRel)
+                        || self.check_raw_capability(KvmCap::DirtyLogRing))
+            }
             VmCap::PvClock => false,
             VmCap::Protected => self.check_raw_capability(KvmCap::ArmProtectedVm),
             VmCap::EarlyInitCpuid => false,
@@ -691,6 +727,37 @@ impl Vm for KvmVm {
         }
     }
 
+    fn enable_dirty_ring(&self, ring_size: usize) -> Result<()> {
+        if !self.check_capability(VmCap::DirtyLogRing) {
+            return Err(Error::new(ENOTSUP));
+        }
+        // Prefer the variant with acquire/release ordering on the ring entries.
+        let cap = if self.check_raw_capability(KvmCap::DirtyLogRingAcqRel) {
+            KvmCap::DirtyLogRingAcqRel
+        } else {
+            KvmCap::DirtyLogRing
+        };
+        let mut dirty_ring_size = self.dirty_ring_size.lock();
+        // The rings are mapped when the vcpus are created.
+        if self.vcpu_created.load(Ordering::Relaxed) {
+            return Err(Error::new(EBUSY));
+        }
+        // Safe because the only argument is the ring size, which isn't interpreted as a pointer.
+        unsafe { self.enable_raw_capability(cap, 0, &[ring_size as u64, 0, 0, 0]) }?;
+        *dirty_ring_size = Some(ring_size);
+        Ok(())
+    }
+
+    fn reset_dirty_rings(&self) -> Result<()> {
+        // Safe because we know that our file is a VM fd and we verify the return result.
+        let ret = unsafe { ioctl(self, KVM_RESET_DIRTY_RINGS()) };
+        if ret >= 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     fn register_ioevent(
         &mut self,
         evt: &Event,
@@ -782,6 +849,58 @@ impl VcpuSignalHandleInner for KvmVcpuSignalHandle {
     }
 }
 
+/// The ring of pages written to by a KVM Vcpu, shared with the kernel.
+struct KvmDirtyRing {
+    mmap: MemoryMapping,
+    /// Index of the next entry to harvest, which keeps increasing past the end of the ring.
+    next: Mutex<u32>,
+}
+
+impl KvmDirtyRing {
+    fn new(vcpu: &SafeDescriptor, size: usize) -> Result<KvmDirtyRing> {
+        let mmap = MemoryMappingBuilder::new(size)
+            .from_descriptor(vcpu)
+            .offset(KVM_DIRTY_LOG_PAGE_OFFSET as u64 * pagesize() as u64)
+            .build()
+            .map_err(|e| match e {
+                MmapError::SystemCallFailed(e) => e,
+                _ => Error::new(EINVAL),
+            })?;
+        Ok(KvmDirtyRing {
+            mmap,
+            next: Mutex::new(0),
+        })
+    }
+
+    fn harvest(&self, handle_fn: &mut dyn FnMut(MemSlot, u64)) -> usize {
+        let entries = self.mmap.size() / size_of::<kvm_dirty_gfn>();
+        let mut next = self.next.lock();
+        let mut count = 0;
+        loop {
+            let index = *next as usize % entries;
+            // Safe because `index` is within the ring, whose size the kernel validated when it was
+            // enabled, and the mapping is page aligned. The kernel keeps writing to the entries
+            // that aren't dirty, so they are only accessed through raw pointers and atomics.
+            unsafe {
+                let gfn = (self.mmap.as_ptr() as *mut kvm_dirty_gfn).add(index);
+                let flags = &*(addr_of_mut!((*gfn).flags) as *const AtomicU32);
+                if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                    break;
+                }
+                // The upper 16 bits of the slot are the address space, which is always 0 as the
+                // x86 SMM address space isn't used.
+                let slot = read_volatile(addr_of!((*gfn).slot)) & 0xffff;
+                let offset = read_volatile(addr_of!((*gfn).offset));
+                handle_fn(slot, offset);
+                flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            }
+            *next = next.wrapping_add(1);
+            count += 1;
+        }
+        count
+    }
+}
+
 /// A wrapper around using a KVM Vcpu.
 pub struct KvmVcpu {
     kvm: Kvm,
@@ -790,6 +909,7 @@ pub struct KvmVcpu {
     id: usize,
     cap_kvmclock_ctrl: bool,
     run_mmap: Arc<MemoryMapping>,
+    dirty_ring: Option<Arc<KvmDirtyRing>>,
 }
 
 impl Vcpu for KvmVcpu {
@@ -804,6 +924,7 @@ impl Vcpu for KvmVcpu {
             cap_kvmclock_ctrl: self.cap_kvmclock_ctrl,
             id: self.id,
             run_mmap: self.run_mmap.clone(),
+            dirty_ring: self.dirty_ring.clone(),
         })
     }
 
@@ -958,6 +1079,7 @@ impl Vcpu for KvmVcpu {
                 Ok(VcpuExit::WrMsr { index, data })
             }
             KVM_EXIT_X86_BUS_LOCK => Ok(VcpuExit::BusLock),
+            KVM_EXIT_DIRTY_RING_FULL => Ok(VcpuExit::DirtyRingFull),
             #[cfg(target_arch = "riscv64")]
             KVM_EXIT_RISCV_SBI => {
                 // Safe because we trust the kernel to correctly fill in the union
@@ -1132,6 +1254,13 @@ impl Vcpu for KvmVcpu {
         let msr = unsafe { &mut run.__bindgen_anon_1.msr };
         msr.error = 0;
     }
+
+    fn harvest_dirty_ring(&self, handle_fn: &mut dyn FnMut(MemSlot, u64)) -> Result<usize> {
+        match &self.dirty_ring {
+            Some(dirty_ring) => Ok(dirty_ring.harvest(handle_fn)),
+            None => Err(Error::new(ENXIO)),
+        }
+    }
 }
 
 impl KvmVcpu {
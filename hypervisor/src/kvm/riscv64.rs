@@ -8,6 +8,7 @@ use base::ioctl_with_ref;
 use base::Error;
 use base::Result;
 use kvm_sys::*;
+use libc::ENOTSUP;
 use libc::ENXIO;
 
 use super::Config;
@@ -135,6 +136,29 @@ impl VcpuRiscv64 for KvmVcpu {
             errno_result()
         }
     }
+
+    #[cfg(feature = "gdb")]
+    fn set_guest_debug(&self, enable_singlestep: bool) -> Result<()> {
+        // KVM ignores KVM_GUESTDBG_SINGLESTEP on riscv64, so the vcpu would run freely.
+        if enable_singlestep {
+            return Err(Error::new(ENOTSUP));
+        }
+
+        // Guest debug makes KVM stop delegating breakpoint exceptions to the guest, so `ebreak`
+        // instructions inserted by the debugger exit with KVM_EXIT_DEBUG.
+        let dbg = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE,
+            ..Default::default()
+        };
+
+        // Safe because the kernel won't read past the end of the kvm_guest_debug struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
 }
 
 // Returns the id used for call to `KVM_[GET|SET]_ONE_REG`.
@@ -146,6 +170,10 @@ fn vcpu_reg_id(reg: VcpuRegister) -> u64 {
     match reg {
         VcpuRegister::Config(r) => id_from_reg(KVM_REG_RISCV_CONFIG, r as u64),
         VcpuRegister::Core(r) => id_from_reg(KVM_REG_RISCV_CORE, r as u64),
+        VcpuRegister::Csr(r) => id_from_reg(
+            KVM_REG_RISCV_CSR,
+            KVM_REG_RISCV_CSR_GENERAL as u64 | r as u64,
+        ),
         VcpuRegister::Timer(r) => id_from_reg(KVM_REG_RISCV_TIMER, r as u64),
     }
 }
@@ -169,6 +197,7 @@ pub(super) fn chip_to_kvm_chip(chip: IrqSourceChip) -> u32 {
 mod tests {
     use super::*;
     use crate::CoreRegister;
+    use crate::CsrRegister;
 
     #[test]
     fn reg_id() {
@@ -305,4 +334,24 @@ mod tests {
             0x8030_0000_0200_0020
         );
     }
+
+    #[test]
+    fn csr_reg_id() {
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Sstatus)),
+            0x8030_0000_0300_0000
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Sepc)),
+            0x8030_0000_0300_0004
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Satp)),
+            0x8030_0000_0300_0008
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Scounteren)),
+            0x8030_0000_0300_0009
+        );
+    }
 }
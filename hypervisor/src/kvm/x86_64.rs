@@ -40,6 +40,8 @@ use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::DeviceKind;
 use crate::Fpu;
+use crate::HwWatchpoint;
+use crate::HwWatchpointKind;
 use crate::HypervisorX86_64;
 use crate::IoapicRedirectionTableEntry;
 use crate::IoapicState;
@@ -932,14 +934,19 @@ impl VcpuX86_64 for KvmVcpu {
         get_cpuid_with_initial_capacity(self, KVM_GET_SUPPORTED_HV_CPUID(), KVM_MAX_ENTRIES)
     }
 
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
         use kvm_sys::*;
         let mut dbg: kvm_guest_debug = Default::default();
 
-        if addrs.len() > 4 {
+        if addrs.len() + watchpoints.len() > 4 {
             error!(
-                "Support 4 breakpoints at most but {} addresses are passed",
-                addrs.len()
+                "Support 4 breakpoints and watchpoints at most but {} addresses are passed",
+                addrs.len() + watchpoints.len()
             );
             return Err(base::Error::new(libc::EINVAL));
         }
@@ -960,6 +967,30 @@ impl VcpuX86_64 for KvmVcpu {
             dbg.arch.debugreg[7] |= 2 << (i * 2);
         }
 
+        for (i, watchpoint) in watchpoints.iter().enumerate() {
+            // Watchpoints use the debug registers left over by the breakpoints.
+            let i = addrs.len() + i;
+            // LEN field: the watched range must be 1, 2, 4 or 8 bytes long and naturally aligned.
+            let len = match watchpoint.len {
+                1 => 0b00,
+                2 => 0b01,
+                4 => 0b11,
+                8 => 0b10,
+                _ => return Err(base::Error::new(libc::EINVAL)),
+            };
+            if watchpoint.addr.0 % watchpoint.len != 0 {
+                return Err(base::Error::new(libc::EINVAL));
+            }
+            // R/W field: x86 can't break on data reads only, so reads are watched like accesses.
+            let rw = match watchpoint.kind {
+                HwWatchpointKind::Write => 0b01,
+                HwWatchpointKind::Read | HwWatchpointKind::ReadWrite => 0b11,
+            };
+            dbg.arch.debugreg[i] = watchpoint.addr.0;
+            // Set global breakpoint enable flag, and the R/W and LEN fields of the register.
+            dbg.arch.debugreg[7] |= (2 << (i * 2)) | ((len << 2 | rw) << (16 + i * 4));
+        }
+
         let ret = unsafe {
             // Here we trust the kernel not to read past the end of the kvm_guest_debug struct.
             ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg)
@@ -971,6 +1002,29 @@ impl VcpuX86_64 for KvmVcpu {
         }
     }
 
+    #[allow(clippy::cast_ptr_alignment)]
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>> {
+        // Safe because we know we mapped enough memory to hold the kvm_run struct because the
+        // kernel told us how large it was. The pointer is page aligned so casting to a different
+        // type is well defined, hence the clippy allow attribute.
+        let run = unsafe { &*(self.run_mmap.as_ptr() as *const kvm_run) };
+        if run.exit_reason != KVM_EXIT_DEBUG {
+            return Err(base::Error::new(libc::EINVAL));
+        }
+        // Safe because the exit_reason (which comes from the kernel) told us which union field to
+        // use.
+        let debug = unsafe { run.__bindgen_anon_1.debug.arch };
+
+        // The B0-B3 bits of DR6 tell which debug register was hit, and the R/W fields of DR7 tell
+        // whether it is a watchpoint, which are set after all the breakpoints.
+        let is_watchpoint =
+            |i: usize| debug.dr7 & (2 << (i * 2)) != 0 && (debug.dr7 >> (16 + i * 4)) & 0b11 != 0;
+        let Some(hit) = (0..4).find(|&i| debug.dr6 & (1 << i) != 0 && is_watchpoint(i)) else {
+            return Ok(None);
+        };
+        Ok(Some((0..hit).filter(|&i| is_watchpoint(i)).count()))
+    }
+
     /// KVM does not support the VcpuExit::Cpuid exit type.
     fn handle_cpuid(&mut self, _entry: &CpuIdEntry) -> Result<()> {
         Err(Error::new(ENXIO))
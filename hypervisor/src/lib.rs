@@ -6,6 +6,8 @@
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 pub mod aarch64;
 pub mod caps;
+#[cfg(target_arch = "x86_64")]
+pub mod emulated;
 
 #[cfg(all(
     unix,
@@ -147,6 +149,22 @@ pub trait Vm: Send {
     /// be 2 bytes or greater.
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()>;
 
+    /// Switches dirty page tracking from the `get_dirty_log` bitmap to per-vcpu rings holding
+    /// `ring_size` bytes of entries each. Only works on VMs that support `VmCap::DirtyLogRing`.
+    ///
+    /// This must be called before any vcpu is created, and fails with `EBUSY` otherwise. Once
+    /// enabled, the pages written to in slots added with `log_dirty_pages` are collected with
+    /// `Vcpu::harvest_dirty_ring`, and `get_dirty_log` can no longer be used.
+    fn enable_dirty_ring(&self, _ring_size: usize) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Re-enables dirty tracking of the pages harvested from the dirty rings of all vcpus since the
+    /// last call, allowing their ring entries to be reused.
+    fn reset_dirty_rings(&self) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// The `datamatch` parameter can be used to limit signaling `evt` to only the cases where the
@@ -343,6 +361,16 @@ pub trait Vcpu: downcast_rs::DowncastSync {
     /// capabilities. The caller must ensure that any pointers passed in the `args` array are
     /// allocated as the kernel expects, and that mutable pointers are owned.
     unsafe fn enable_raw_capability(&self, cap: u32, args: &[u64; 4]) -> Result<()>;
+
+    /// Collects the pages written to by this Vcpu since the last harvest, calling `handle_fn` with
+    /// the slot and the page index within the slot of each, and returns the number of pages.
+    ///
+    /// Only works once `Vm::enable_dirty_ring` was called. Harvested entries aren't reused until
+    /// `Vm::reset_dirty_rings` is called, which must happen before running the Vcpu again after
+    /// `Vcpu::run` returns `VcpuExit::DirtyRingFull`.
+    fn harvest_dirty_ring(&self, _handle_fn: &mut dyn FnMut(MemSlot, u64)) -> Result<usize> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
 }
 
 downcast_rs::impl_downcast!(sync Vcpu);
@@ -364,6 +392,22 @@ pub enum Datamatch {
     U64(Option<u64>),
 }
 
+/// Type of guest memory access that triggers a hardware watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HwWatchpointKind {
+    Write,
+    Read,
+    ReadWrite,
+}
+
+/// A hardware watchpoint on the `len` bytes of guest memory starting at `addr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HwWatchpoint {
+    pub addr: GuestAddress,
+    pub len: u64,
+    pub kind: HwWatchpointKind,
+}
+
 /// A reason why a VCPU exited. One of these returns every time `Vcpu::run` is called.
 #[derive(Debug, Clone, Copy)]
 pub enum VcpuExit {
@@ -434,6 +478,8 @@ pub enum VcpuExit {
     ApicInitSipiTrap,
     /// vcpu stoppted due to bus lock
     BusLock,
+    /// The dirty ring of the vcpu is full and must be harvested and reset before running it again.
+    DirtyRingFull,
     /// Riscv supervisor call.
     Sbi {
         extension_id: u64,
@@ -33,6 +33,12 @@ pub trait VcpuRiscv64: Vcpu {
     /// Gets the value of a register on this VCPU.
     fn get_one_reg(&self, reg_id: VcpuRegister) -> Result<u64>;
 
+    #[cfg(feature = "gdb")]
+    /// Configures the vcpu for handling guest debug events, making guest `ebreak` instructions exit
+    /// to the VMM. Single-stepping isn't supported by KVM, so `enable_singlestep` fails with
+    /// `ENOTSUP`.
+    fn set_guest_debug(&self, enable_singlestep: bool) -> Result<()>;
+
     /// Snapshot VCPU
     fn snapshot(&self) -> anyhow::Result<VcpuSnapshot> {
         Err(anyhow!("not yet implemented"))
@@ -133,11 +139,28 @@ pub enum CoreRegister {
     Mode = 0x20, // Privilege mode (1 = S-mode or 0 = U-mode)
 }
 
+/// Supervisor CSRs exposed by kvm.
+#[repr(u64)]
+#[derive(Copy, Clone)]
+pub enum CsrRegister {
+    Sstatus = 0x00,    // Supervisor status
+    Sie = 0x01,        // Supervisor interrupt enable
+    Stvec = 0x02,      // Supervisor trap vector base address
+    Sscratch = 0x03,   // Supervisor scratch register
+    Sepc = 0x04,       // Supervisor exception program counter
+    Scause = 0x05,     // Supervisor trap cause
+    Stval = 0x06,      // Supervisor trap value
+    Sip = 0x07,        // Supervisor interrupt pending
+    Satp = 0x08,       // Supervisor address translation and protection
+    Scounteren = 0x09, // Supervisor counter enable
+}
+
 /// Registers exposed through `KVM_[GET|SET]_ONE_REG` API.
 #[derive(Copy, Clone)]
 pub enum VcpuRegister {
     Config(ConfigRegister),
     Core(CoreRegister),
+    Csr(CsrRegister),
     Timer(TimerRegister),
 }
 
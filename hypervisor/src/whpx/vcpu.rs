@@ -26,6 +26,7 @@ use crate::CpuId;
 use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::Fpu;
+use crate::HwWatchpoint;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -1302,7 +1303,17 @@ impl VcpuX86_64 for WhpxVcpu {
     }
 
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
@@ -508,6 +508,7 @@ impl Vm for WhpxVm {
                     );
                     false
                 }),
+            VmCap::DirtyLogRing => false,
             // there is a pvclock like thing already done w/ hyperv, but we can't get the state.
             VmCap::PvClock => false,
             VmCap::Protected => false,
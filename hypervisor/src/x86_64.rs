@@ -19,6 +19,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use vm_memory::GuestAddress;
 
+use crate::HwWatchpoint;
 use crate::Hypervisor;
 use crate::IrqRoute;
 use crate::IrqSource;
@@ -129,7 +130,19 @@ pub trait VcpuX86_64: Vcpu {
     fn get_hyperv_cpuid(&self) -> Result<CpuId>;
 
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    ///
+    /// `addrs` are the addresses of the hardware breakpoints, which share the debug registers
+    /// with `watchpoints`.
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()>;
+
+    /// Gets the index in the `watchpoints` last passed to `set_guest_debug` of the watchpoint that
+    /// caused the last `VcpuExit::Debug`, or `None` if the exit wasn't caused by a watchpoint.
+    fn get_debug_exit_watchpoint(&self) -> Result<Option<usize>>;
 
     /// This function should be called after `Vcpu::run` returns `VcpuExit::Cpuid`, and `entry`
     /// should represent the result of emulating the CPUID instruction. The `handle_cpuid` function
@@ -24,6 +24,100 @@ fn test_kvm_dirty_log() {
     });
 }
 
+#[test]
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn test_kvm_dirty_ring() {
+    use hypervisor::kvm::*;
+
+    /*
+    0000  881C mov [si],bl
+    0002  F4   hlt
+    */
+    let code = [0x88, 0x1c, 0xf4];
+    let mem_size = 0x10000;
+    let load_addr = GuestAddress(0x1000);
+    let guest_mem = GuestMemory::new(&[(GuestAddress(0x20000), 0x1000)]).unwrap();
+    let mem = SharedMemory::new("test", mem_size).expect("failed to create shared memory");
+    let mmap = MemoryMappingBuilder::new(mem_size as usize)
+        .from_shared_memory(&mem)
+        .build()
+        .expect("failed to create memory mapping");
+    mmap.write_slice(&code[..], load_addr.offset() as usize)
+        .expect("Writing code to memory failed.");
+
+    let kvm = Kvm::new().expect("failed to create kvm");
+    let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+    if !vm.check_capability(VmCap::DirtyLogRing) {
+        return;
+    }
+    vm.enable_dirty_ring(0x10000)
+        .expect("failed to enable dirty ring");
+    let slot = vm
+        .add_memory_region(
+            GuestAddress(0),
+            Box::new(
+                MemoryMappingBuilder::new(mem_size as usize)
+                    .from_shared_memory(&mem)
+                    .build()
+                    .expect("failed to create memory mapping"),
+            ),
+            false,
+            true,
+        )
+        .expect("failed to register memory");
+
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    assert_eq!(
+        vm.enable_dirty_ring(0x10000)
+            .expect_err("enabled dirty ring after creating a vcpu")
+            .errno(),
+        libc::EBUSY
+    );
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    vcpu_sregs.cs.base = 0;
+    vcpu_sregs.cs.selector = 0;
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+    let vcpu_regs = Regs {
+        rip: load_addr.offset(),
+        rflags: 2,
+        // Write 0x12 to the beginning of the 9th page.
+        rsi: 0x8000,
+        rbx: 0x12,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+
+    loop {
+        match vcpu.run().expect("run failed") {
+            // Continue on external interrupt or signal
+            VcpuExit::Intr => continue,
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    let mut dirty_pages = Vec::new();
+    vcpu.harvest_dirty_ring(&mut |slot, offset| dirty_pages.push((slot, offset)))
+        .expect("failed to harvest dirty ring");
+    assert_eq!(dirty_pages, [(slot, 8)]);
+    vm.reset_dirty_rings().expect("failed to reset dirty rings");
+    assert_eq!(
+        vcpu.harvest_dirty_ring(&mut |_, _| {})
+            .expect("failed to harvest dirty ring"),
+        0
+    );
+}
+
+#[test]
+fn test_emulated_dirty_log() {
+    use hypervisor::emulated::*;
+    test_dirty_log(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_dirty_log_not_supported() {
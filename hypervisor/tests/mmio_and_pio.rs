@@ -24,6 +24,16 @@ fn test_kvm_mmio_and_pio() {
     });
 }
 
+#[test]
+fn test_emulated_mmio_and_pio() {
+    use hypervisor::emulated::*;
+    test_mmio_and_pio(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_mmio_and_pio() {
@@ -26,6 +26,16 @@ fn test_kvm_read_only_memory() {
     });
 }
 
+#[test]
+fn test_emulated_read_only_memory() {
+    use hypervisor::emulated::*;
+    test_read_only_memory(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 // TODO(b/163163457): HAXM has a bug where the mmio write for read only memory
 //  does not happen to the correct address.
 // #[test]
@@ -22,6 +22,16 @@ fn test_kvm_real_run_addr() {
     });
 }
 
+#[test]
+fn test_emulated_real_run_addr() {
+    use hypervisor::emulated::*;
+    test_real_run_addr(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_real_run_addr() {
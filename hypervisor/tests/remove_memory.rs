@@ -23,6 +23,16 @@ fn test_kvm_remove_memory() {
     });
 }
 
+#[test]
+fn test_emulated_remove_memory() {
+    use hypervisor::emulated::*;
+    test_remove_memory(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_remove_memory() {
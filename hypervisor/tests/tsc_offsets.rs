@@ -38,6 +38,16 @@ fn test_kvm_tsc_offsets() {
     });
 }
 
+#[test]
+fn test_emulated_tsc_offsets() {
+    use hypervisor::emulated::*;
+    test_tsc_offsets(|guest_mem| {
+        let emulated = Emulated::new();
+        let vm = EmulatedVm::new(&emulated, guest_mem).expect("failed to create vm");
+        (emulated, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_tsc_offsets() {
@@ -20,12 +20,14 @@ bindgen_generate \
     --allowlist-type='Elf32_Ehdr' \
     --allowlist-type='Elf32_Phdr' \
     --allowlist-type='Elf64_Ehdr' \
+    --allowlist-type='Elf64_Nhdr' \
     --allowlist-type='Elf64_Phdr' \
     --allowlist-var='.+' \
     --with-derive-custom "elf32_hdr=FromZeroes,FromBytes,AsBytes" \
     --with-derive-custom "elf64_hdr=FromZeroes,FromBytes,AsBytes" \
     --with-derive-custom "elf32_phdr=FromZeroes,FromBytes,AsBytes" \
     --with-derive-custom "elf64_phdr=FromZeroes,FromBytes,AsBytes" \
+    --with-derive-custom "elf64_note=FromZeroes,FromBytes,AsBytes" \
     "${BINDGEN_LINUX}/include/uapi/linux/elf.h" \
     -- \
     -isystem "${BINDGEN_LINUX}/include" \
@@ -0,0 +1,338 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decompression of compressed kernel images, such as the `Image.gz` built for arm64 and riscv64,
+//! and of EFI zboot images (`vmlinuz.efi`) wrapping a compressed kernel.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::mem;
+
+use base::SafeDescriptor;
+use base::SharedMemory;
+use data_model::Le32;
+use flate2::read::GzDecoder;
+use lz4_flex::frame::FrameDecoder;
+use ruzstd::StreamingDecoder;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use crate::Error;
+use crate::Result;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+// The legacy LZ4 format produced by `lz4 -l`, which Linux uses for `Image.lz4`.
+const LZ4_LEGACY_MAGIC: [u8; 4] = [0x02, 0x21, 0x4c, 0x18];
+// Each block of the legacy LZ4 format decompresses to at most 8 MiB.
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 << 20;
+
+const ZBOOT_MZ_MAGIC: [u8; 2] = *b"MZ";
+const ZBOOT_IMAGE_TYPE: [u8; 4] = *b"zimg";
+
+/// Header of an EFI zboot image, which is a PE/COFF EFI application decompressing the kernel
+/// appended to it.
+/// <https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/drivers/firmware/efi/libstub/zboot-header.S>
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
+#[allow(unused)]
+#[repr(C)]
+struct ZbootHeader {
+    mz_magic: [u8; 2],
+    res0: [u8; 2],
+    image_type: [u8; 4],
+    payload_offset: Le32,
+    payload_size: Le32,
+    res1: [u8; 8],
+    compression_type: [u8; 8],
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Lz4Frame,
+    Lz4Legacy,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects the compression format of `data` from its magic number.
+    fn detect(data: &[u8]) -> Option<Compression> {
+        if data.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if data.starts_with(&LZ4_FRAME_MAGIC) {
+            Some(Compression::Lz4Frame)
+        } else if data.starts_with(&LZ4_LEGACY_MAGIC) {
+            Some(Compression::Lz4Legacy)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decompresses `kernel_image` if it is compressed with gzip, LZ4 or zstd, or if it is an EFI zboot
+/// image containing a kernel compressed with one of those.
+///
+/// Returns an anonymous file containing the decompressed kernel, or `None` if `kernel_image` isn't
+/// compressed and should be loaded as is. Decompression fails if the kernel grows beyond
+/// `max_size` bytes, which is usually the size of guest memory.
+pub fn decompress_kernel<F>(kernel_image: &mut F, max_size: u64) -> Result<Option<File>>
+where
+    F: Read + Seek,
+{
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::SeekKernelStart)?;
+    let mut header = Vec::new();
+    kernel_image
+        .by_ref()
+        .take(mem::size_of::<ZbootHeader>() as u64)
+        .read_to_end(&mut header)
+        .map_err(|_| Error::ReadHeader)?;
+
+    let (compression, payload_offset, payload_size) = match ZbootHeader::read_from_prefix(&header) {
+        Some(zboot) if zboot.mz_magic == ZBOOT_MZ_MAGIC && zboot.image_type == ZBOOT_IMAGE_TYPE => {
+            let payload_offset = u64::from(u32::from(zboot.payload_offset));
+            kernel_image
+                .seek(SeekFrom::Start(payload_offset))
+                .map_err(|_| Error::InvalidZbootHeader)?;
+            let mut magic = [0u8; 4];
+            kernel_image
+                .read_exact(&mut magic)
+                .map_err(|_| Error::InvalidZbootHeader)?;
+            // The payload may use a compression format that isn't supported here, such as xz.
+            let compression =
+                Compression::detect(&magic).ok_or(Error::UnsupportedKernelCompression)?;
+            (
+                compression,
+                payload_offset,
+                Some(u64::from(u32::from(zboot.payload_size))),
+            )
+        }
+        _ => match Compression::detect(&header) {
+            Some(compression) => (compression, 0, None),
+            None => return Ok(None),
+        },
+    };
+
+    kernel_image
+        .seek(SeekFrom::Start(payload_offset))
+        .map_err(|_| Error::SeekKernelStart)?;
+    let mut compressed = Vec::new();
+    kernel_image
+        .by_ref()
+        .take(payload_size.unwrap_or(u64::MAX))
+        .read_to_end(&mut compressed)
+        .map_err(|_| Error::ReadKernelImage)?;
+
+    let shm =
+        SharedMemory::new("decompressed_kernel", 0).map_err(|_| Error::CreateDecompressedKernel)?;
+    let mut output = BoundedWriter {
+        inner: File::from(SafeDescriptor::from(shm)),
+        remaining: max_size,
+        overflowed: false,
+    };
+    if decompress(compression, &compressed, &mut output).is_err() {
+        return Err(if output.overflowed {
+            Error::DecompressedKernelTooLarge
+        } else {
+            Error::DecompressKernel
+        });
+    }
+    let mut decompressed = output.inner;
+    decompressed
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::SeekKernelStart)?;
+    Ok(Some(decompressed))
+}
+
+/// A writer that fails once more than `remaining` bytes are written to it, so that a small
+/// compressed image can't fill up the host memory.
+struct BoundedWriter<W> {
+    inner: W,
+    remaining: u64,
+    overflowed: bool,
+}
+
+impl<W: Write> Write for BoundedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            self.overflowed = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "decompressed kernel is too large",
+            ));
+        }
+        let len = self.inner.write(buf)?;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn decompress<W: Write>(compression: Compression, data: &[u8], output: &mut W) -> io::Result<()> {
+    match compression {
+        Compression::Gzip => {
+            io::copy(&mut GzDecoder::new(data), output)?;
+        }
+        Compression::Lz4Frame => {
+            io::copy(&mut FrameDecoder::new(data), output)?;
+        }
+        Compression::Lz4Legacy => decompress_lz4_legacy(data, output)?,
+        Compression::Zstd => {
+            let mut decoder = StreamingDecoder::new(data)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            io::copy(&mut decoder, output)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses data in the legacy LZ4 format: a magic number followed by blocks that are each
+/// prefixed by their compressed size.
+fn decompress_lz4_legacy<W: Write>(mut data: &[u8], output: &mut W) -> io::Result<()> {
+    fn invalid_data(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    let mut block = vec![0u8; LZ4_LEGACY_BLOCK_SIZE];
+    while data.len() >= 4 {
+        let (size, rest) = data.split_at(4);
+        let size = u32::from_le_bytes(size.try_into().unwrap());
+        data = rest;
+        if size.to_le_bytes() == LZ4_LEGACY_MAGIC {
+            // Streams may be concatenated.
+            continue;
+        }
+        if data.is_empty() {
+            // Linux appends the decompressed size after the last block.
+            break;
+        }
+        let compressed = data
+            .get(..size as usize)
+            .ok_or_else(|| invalid_data("truncated LZ4 block"))?;
+        let len = lz4_flex::block::decompress_into(compressed, &mut block)
+            .map_err(|e| invalid_data(&e.to_string()))?;
+        output.write_all(&block[..len])?;
+        data = &data[size as usize..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    use flate2::write::GzEncoder;
+    use lz4_flex::frame::FrameEncoder;
+
+    const ELF64: &[u8] = include_bytes!("test_elf64.bin");
+    const MAX_SIZE: u64 = 1 << 20;
+
+    fn decompress_bytes(image: &[u8]) -> Option<Vec<u8>> {
+        let mut decompressed = decompress_kernel(&mut Cursor::new(image), MAX_SIZE).unwrap()?;
+        let mut data = Vec::new();
+        decompressed.read_to_end(&mut data).unwrap();
+        Some(data)
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(decompress_bytes(ELF64), None);
+        assert_eq!(decompress_bytes(&[]), None);
+    }
+
+    #[test]
+    fn gzip() {
+        let data = decompress_bytes(include_bytes!("test_elf64.bin.gz")).unwrap();
+        assert_eq!(data, ELF64);
+    }
+
+    #[test]
+    fn lz4_frame() {
+        let mut encoder = FrameEncoder::new(Vec::new());
+        encoder.write_all(ELF64).unwrap();
+        let data = decompress_bytes(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(data, ELF64);
+    }
+
+    #[test]
+    fn lz4_legacy() {
+        let data = decompress_bytes(include_bytes!("test_elf64.bin.lz4")).unwrap();
+        assert_eq!(data, ELF64);
+    }
+
+    #[test]
+    fn zstd() {
+        let data = decompress_bytes(include_bytes!("test_elf64.bin.zst")).unwrap();
+        assert_eq!(data, ELF64);
+    }
+
+    fn make_zboot(payload: &[u8], compression_type: &[u8; 8]) -> Vec<u8> {
+        const PAYLOAD_OFFSET: usize = 0x100;
+        let header = ZbootHeader {
+            mz_magic: ZBOOT_MZ_MAGIC,
+            res0: [0; 2],
+            image_type: ZBOOT_IMAGE_TYPE,
+            payload_offset: Le32::from(PAYLOAD_OFFSET as u32),
+            payload_size: Le32::from(payload.len() as u32),
+            res1: [0; 8],
+            compression_type: *compression_type,
+        };
+        let mut image = header.as_bytes().to_vec();
+        image.resize(PAYLOAD_OFFSET, 0);
+        image.extend_from_slice(payload);
+        // The EFI stub's own sections follow the payload.
+        image.extend_from_slice(&[0xcc; 0x40]);
+        image
+    }
+
+    #[test]
+    fn zboot() {
+        let image = make_zboot(include_bytes!("test_elf64.bin.zst"), b"zstd22\0\0");
+        assert_eq!(decompress_bytes(&image).unwrap(), ELF64);
+
+        let image = make_zboot(include_bytes!("test_elf64.bin.gz"), b"gzip\0\0\0\0");
+        assert_eq!(decompress_bytes(&image).unwrap(), ELF64);
+
+        let image = make_zboot(&[0xfd, b'7', b'z', b'X', b'Z', 0], b"xzkern\0\0");
+        assert_eq!(
+            decompress_kernel(&mut Cursor::new(image), MAX_SIZE).unwrap_err(),
+            Error::UnsupportedKernelCompression
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let image = include_bytes!("test_elf64.bin.gz");
+        assert_eq!(
+            decompress_kernel(&mut Cursor::new(&image[..image.len() / 2]), MAX_SIZE).unwrap_err(),
+            Error::DecompressKernel
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        // 16 MiB of zeros compress to a few KiB.
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&vec![0u8; 16 << 20]).unwrap();
+        let image = encoder.finish().unwrap();
+        assert_eq!(
+            decompress_kernel(&mut Cursor::new(image), MAX_SIZE).unwrap_err(),
+            Error::DecompressedKernelTooLarge
+        );
+    }
+}
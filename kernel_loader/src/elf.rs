@@ -351,3 +351,11 @@ pub struct elf64_phdr {
     pub p_align: Elf64_Xword,
 }
 pub type Elf64_Phdr = elf64_phdr;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+pub struct elf64_note {
+    pub n_namesz: Elf64_Word,
+    pub n_descsz: Elf64_Word,
+    pub n_type: Elf64_Word,
+}
+pub type Elf64_Nhdr = elf64_note;
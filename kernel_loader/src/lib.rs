@@ -28,8 +28,12 @@ use zerocopy::FromBytes;
 mod elf;
 
 mod arm64;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod decompress;
 
 pub use arm64::load_arm64_kernel;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use decompress::decompress_kernel;
 
 #[sorted]
 #[derive(Error, Debug, PartialEq, Eq)]
@@ -40,6 +44,12 @@ pub enum Error {
     CommandLineCopy,
     #[error("command line overflowed guest memory")]
     CommandLineOverflow,
+    #[error("unable to create a file for the decompressed kernel")]
+    CreateDecompressedKernel,
+    #[error("decompressed kernel image is larger than guest memory")]
+    DecompressedKernelTooLarge,
+    #[error("unable to decompress kernel image")]
+    DecompressKernel,
     #[error("invalid elf class")]
     InvalidElfClass,
     #[error("invalid elf version")]
@@ -60,6 +70,10 @@ pub enum Error {
     InvalidProgramHeaderOffset,
     #[error("invalid program header size")]
     InvalidProgramHeaderSize,
+    #[error("invalid PVH entry point note")]
+    InvalidPvhNote,
+    #[error("invalid EFI zboot header")]
+    InvalidZbootHeader,
     #[error("no loadable program headers found")]
     NoLoadableProgramHeaders,
     #[error("program header address out of allowed address range")]
@@ -68,14 +82,20 @@ pub enum Error {
     ReadHeader,
     #[error("unable to read kernel image")]
     ReadKernelImage,
+    #[error("unable to read ELF notes")]
+    ReadNotes,
     #[error("unable to read program header")]
     ReadProgramHeader,
     #[error("unable to seek to kernel end")]
     SeekKernelEnd,
     #[error("unable to seek to kernel start")]
     SeekKernelStart,
+    #[error("unable to seek to ELF notes")]
+    SeekNotes,
     #[error("unable to seek to program header")]
     SeekProgramHeader,
+    #[error("unsupported kernel compression format")]
+    UnsupportedKernelCompression,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -94,6 +114,13 @@ pub struct LoadedKernel {
     pub entry: GuestAddress,
 }
 
+/// Name of the ELF notes defined by Xen.
+#[cfg(target_arch = "x86_64")]
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+/// Type of the Xen ELF note containing the 32-bit physical address of the PVH entry point.
+#[cfg(target_arch = "x86_64")]
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
 /// Loads a kernel from a 32-bit ELF image into memory.
 ///
 /// The ELF file will be loaded at the physical address specified by the `p_paddr` fields of its
@@ -120,6 +147,7 @@ where
         kernel_image,
         phys_offset,
         Some(elf::ELFCLASS32),
+        None,
     )
 }
 
@@ -149,6 +177,7 @@ where
         kernel_image,
         phys_offset,
         Some(elf::ELFCLASS64),
+        None,
     )
 }
 
@@ -172,7 +201,57 @@ pub fn load_elf<F>(
 where
     F: Read + Seek + AsRawDescriptor,
 {
-    load_elf_for_class(guest_mem, kernel_start, kernel_image, phys_offset, None)
+    load_elf_for_class(
+        guest_mem,
+        kernel_start,
+        kernel_image,
+        phys_offset,
+        None,
+        None,
+    )
+}
+
+/// Loads a kernel from a 32-bit or 64-bit ELF image into memory, to be started through the Xen PVH
+/// entry point returned by [`find_pvh_entry`].
+///
+/// The entry point of the returned kernel is `pvh_entry` rather than `e_entry`, which PVH kernels
+/// such as FreeBSD link at a virtual address.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory region the kernel is written to.
+/// * `kernel_start` - The minimum guest address to allow when loading program headers.
+/// * `kernel_image` - Input vmlinux image.
+/// * `pvh_entry` - The PVH entry point of the image.
+#[cfg(target_arch = "x86_64")]
+pub fn load_elf_pvh<F>(
+    guest_mem: &GuestMemory,
+    kernel_start: GuestAddress,
+    kernel_image: &mut F,
+    pvh_entry: GuestAddress,
+) -> Result<LoadedKernel>
+where
+    F: Read + Seek + AsRawDescriptor,
+{
+    load_elf_for_class(
+        guest_mem,
+        kernel_start,
+        kernel_image,
+        0,
+        None,
+        Some(pvh_entry.offset()),
+    )
+}
+
+/// Returns the 32-bit entry point of the Xen PVH boot ABI of a 32-bit or 64-bit ELF image, if it
+/// advertises one with a `XEN_ELFNOTE_PHYS32_ENTRY` note.
+#[cfg(target_arch = "x86_64")]
+pub fn find_pvh_entry<F>(kernel_image: &mut F) -> Result<Option<GuestAddress>>
+where
+    F: Read + Seek + AsRawDescriptor,
+{
+    let elf = read_elf(kernel_image, None)?;
+    Ok(read_pvh_entry(kernel_image, &elf.program_headers)?.map(GuestAddress))
 }
 
 fn load_elf_for_class<F>(
@@ -181,6 +260,7 @@ fn load_elf_for_class<F>(
     kernel_image: &mut F,
     phys_offset: u64,
     ei_class: Option<u32>,
+    entry: Option<u64>,
 ) -> Result<LoadedKernel>
 where
     F: Read + Seek + AsRawDescriptor,
@@ -236,9 +316,8 @@ where
 
     // The entry point address must fall within one of the loaded sections.
     // We approximate this by checking whether it within the bounds of the first and last sections.
-    let entry = elf
-        .file_header
-        .e_entry
+    let entry = entry
+        .unwrap_or(elf.file_header.e_entry)
         .checked_add(phys_offset)
         .ok_or(Error::InvalidEntryPoint)?;
     if !address_range.contains(entry) {
@@ -252,6 +331,74 @@ where
     })
 }
 
+/// Returns the physical address found in the `XEN_ELFNOTE_PHYS32_ENTRY` note of the `PT_NOTE`
+/// segments described by `program_headers`, if any.
+#[cfg(target_arch = "x86_64")]
+fn read_pvh_entry<F>(
+    kernel_image: &mut F,
+    program_headers: &[elf::Elf64_Phdr],
+) -> Result<Option<u64>>
+where
+    F: Read + Seek,
+{
+    for phdr in program_headers {
+        if phdr.p_type != elf::PT_NOTE {
+            continue;
+        }
+
+        kernel_image
+            .seek(SeekFrom::Start(phdr.p_offset))
+            .map_err(|_| Error::SeekNotes)?;
+        // Read through `take` rather than into a buffer of `p_filesz` bytes, so that a bogus size
+        // is bounded by the size of the image.
+        let mut notes = Vec::new();
+        kernel_image
+            .by_ref()
+            .take(phdr.p_filesz)
+            .read_to_end(&mut notes)
+            .map_err(|_| Error::ReadNotes)?;
+        if notes.len() as u64 != phdr.p_filesz {
+            return Err(Error::ReadNotes);
+        }
+
+        if let Some(entry) = parse_pvh_note(&notes)? {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks for the `XEN_ELFNOTE_PHYS32_ENTRY` note in the contents of a `PT_NOTE` segment.
+#[cfg(target_arch = "x86_64")]
+fn parse_pvh_note(notes: &[u8]) -> Result<Option<u64>> {
+    // The name and descriptor of each note are padded to 4 bytes, for both ELF32 and ELF64.
+    fn align4(size: u32) -> usize {
+        (size as usize + 3) & !3
+    }
+
+    let mut offset = 0;
+    while let Some(nhdr) = notes
+        .get(offset..)
+        .and_then(elf::Elf64_Nhdr::read_from_prefix)
+    {
+        let name_start = offset + mem::size_of::<elf::Elf64_Nhdr>();
+        let desc_start = name_start + align4(nhdr.n_namesz);
+        offset = desc_start + align4(nhdr.n_descsz);
+
+        let name = notes.get(name_start..name_start + nhdr.n_namesz as usize);
+        if name != Some(XEN_ELFNOTE_NAME) || nhdr.n_type != XEN_ELFNOTE_PHYS32_ENTRY {
+            continue;
+        }
+        // The entry point is 32-bit, but 64-bit kernels may store it in a 64-bit descriptor.
+        let entry = notes
+            .get(desc_start..desc_start + nhdr.n_descsz as usize)
+            .and_then(|desc| desc.get(..4))
+            .ok_or(Error::InvalidPvhNote)?;
+        return Ok(Some(u32::from_le_bytes(entry.try_into().unwrap()).into()));
+    }
+    Ok(None)
+}
+
 /// Writes the command line string to the given memory slice.
 ///
 /// # Arguments
@@ -511,6 +658,87 @@ mod test {
         assert_eq!(kernel.entry, GuestAddress(0x20_000e));
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn load_pvh() {
+        let gm = create_guest_mem();
+        let kernel_addr = GuestAddress(0x0);
+        let mut image = make_elf64_bin();
+        let kernel = load_elf_pvh(&gm, kernel_addr, &mut image, GuestAddress(0x20_0020))
+            .expect("failed to load ELF");
+        assert_eq!(kernel.address_range.end, 0x20_0035);
+        assert_eq!(kernel.entry, GuestAddress(0x20_0020));
+        assert_eq!(
+            load_elf_pvh(&gm, kernel_addr, &mut image, GuestAddress(0x1000)),
+            Err(Error::InvalidEntryPoint)
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn no_pvh_entry() {
+        let mut image = make_elf64_bin();
+        assert_eq!(find_pvh_entry(&mut image), Ok(None));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn make_note(name: &[u8], n_type: u32, desc: &[u8]) -> Vec<u8> {
+        let mut note = Vec::new();
+        note.extend((name.len() as u32).to_le_bytes());
+        note.extend((desc.len() as u32).to_le_bytes());
+        note.extend(n_type.to_le_bytes());
+        note.extend(name);
+        note.resize((note.len() + 3) & !3, 0);
+        note.extend(desc);
+        note.resize((note.len() + 3) & !3, 0);
+        note
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn pvh_entry_note() {
+        let mut notes = make_note(b"GNU\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0xff; 20]);
+        notes.extend(make_note(b"Xen\0", 1, b"linux\0"));
+        assert_eq!(parse_pvh_note(&notes), Ok(None));
+
+        // 64-bit kernels store the entry point in a 64-bit descriptor.
+        notes.extend(make_note(
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &0x100_0200u64.to_le_bytes(),
+        ));
+        assert_eq!(parse_pvh_note(&notes), Ok(Some(0x100_0200)));
+
+        let note = make_note(
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &0x20_0000u32.to_le_bytes(),
+        );
+        assert_eq!(parse_pvh_note(&note), Ok(Some(0x20_0000)));
+
+        let note = make_note(b"Xen\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0x10, 0x20]);
+        assert_eq!(parse_pvh_note(&note), Err(Error::InvalidPvhNote));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn pvh_note_size_past_end() {
+        let notes = make_note(
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &0x20_0000u32.to_le_bytes(),
+        );
+        let phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_NOTE,
+            p_filesz: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            read_pvh_entry(&mut std::io::Cursor::new(notes), &[phdr]),
+            Err(Error::ReadNotes)
+        );
+    }
+
     #[test]
     fn bad_magic() {
         let gm = create_guest_mem();
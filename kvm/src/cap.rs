@@ -123,6 +123,8 @@ pub enum Cap {
     ArmPmuV3 = KVM_CAP_ARM_PMU_V3,
     ArmProtectedVm = KVM_CAP_ARM_PROTECTED_VM,
     ArmMte = KVM_CAP_ARM_MTE,
+    DirtyLogRing = KVM_CAP_DIRTY_LOG_RING,
+    DirtyLogRingAcqRel = KVM_CAP_DIRTY_LOG_RING_ACQ_REL,
     #[cfg(target_arch = "x86_64")]
     BusLockDetect = KVM_CAP_X86_BUS_LOCK_EXIT,
 }
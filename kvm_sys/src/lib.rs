@@ -22,6 +22,10 @@ pub const KVM_MSR_FILTER_RANGE_MAX_BITS: usize = 0x2000;
 #[cfg(target_arch = "x86_64")]
 pub const KVM_MSR_FILTER_RANGE_MAX_BYTES: usize = KVM_MSR_FILTER_RANGE_MAX_BITS / 8;
 
+// Flags of `struct kvm_dirty_gfn`, which are defined with `_BITUL` and missed by bindgen.
+pub const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+pub const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
 #[cfg(target_arch = "x86_64")]
 pub mod x86 {
     // generated with bindgen /usr/include/linux/kvm.h --no-unstable-rust --constified-enum '*' --with-derive-default
@@ -172,6 +176,7 @@ ioctl_iowr_nr!(KVM_GET_REG_LIST, KVMIO, 0xb0, kvm_reg_list);
 ioctl_io_nr!(KVM_SMI, KVMIO, 0xb7);
 #[cfg(target_arch = "x86_64")]
 ioctl_iow_nr!(KVM_X86_SET_MSR_FILTER, KVMIO, 0xc6, kvm_msr_filter);
+ioctl_io_nr!(KVM_RESET_DIRTY_RINGS, KVMIO, 0xc7);
 
 // Along with the common ioctls, we reexport the ioctls of the current
 // platform.
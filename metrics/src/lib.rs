@@ -17,6 +17,7 @@ mod local_stats;
 mod metrics_cleanup;
 mod metrics_requests;
 mod noop;
+pub mod openmetrics;
 pub mod sys;
 pub mod protos {
     include!(concat!(env!("OUT_DIR"), "/metrics_protos/generated.rs"));
@@ -0,0 +1,283 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encoding of metrics in the OpenMetrics text format, and a minimal HTTP responder so they can be
+//! scraped by Prometheus compatible monitoring.
+//!
+//! See https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Requests larger than this are rejected. Scrapers only send a request line and a few headers.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// The type of a metric family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    /// A value that only goes up, such as a number of events.
+    Counter,
+    /// A value that can go up and down, such as a memory size.
+    Gauge,
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetricType::Counter => write!(f, "counter"),
+            MetricType::Gauge => write!(f, "gauge"),
+        }
+    }
+}
+
+/// A value of a metric family, identified by its labels.
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// A set of metrics sharing a name, a type and a help text, e.g. the number of VM exits per vCPU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricFamily {
+    name: String,
+    metric_type: MetricType,
+    help: String,
+    samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    /// Creates a metric family without any value.
+    ///
+    /// `name` must not have the `_total` suffix of counters, it is added when encoding.
+    pub fn new(name: &str, metric_type: MetricType, help: &str) -> MetricFamily {
+        MetricFamily {
+            name: name.to_string(),
+            metric_type,
+            help: help.to_string(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds the value of the metric identified by `labels`.
+    pub fn add_sample(&mut self, labels: &[(&str, &str)], value: f64) {
+        self.samples.push(Sample {
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            value,
+        });
+    }
+
+    /// Adds a sample and returns `self`, for metrics with a single value.
+    pub fn with_sample(mut self, labels: &[(&str, &str)], value: f64) -> MetricFamily {
+        self.add_sample(labels, value);
+        self
+    }
+
+    fn encode(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "# TYPE {} {}", self.name, self.metric_type)?;
+        writeln!(out, "# HELP {} {}", self.name, escape(&self.help, false))?;
+        let suffix = match self.metric_type {
+            MetricType::Counter => "_total",
+            MetricType::Gauge => "",
+        };
+        for sample in &self.samples {
+            write!(out, "{}{}", self.name, suffix)?;
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+                    .collect();
+                write!(out, "{{{}}}", labels.join(","))?;
+            }
+            writeln!(out, " {}", sample.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes the backslashes and line feeds of a help text, and the double quotes of a label value.
+fn escape(s: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the exposition of `families` in the OpenMetrics text format.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        // Writing to a String can't fail.
+        let _ = family.encode(&mut out);
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Answers a single HTTP request received on `stream`.
+///
+/// A `GET /metrics` request is answered with the exposition of the metric families returned by
+/// `collect`. The connection is closed after the response.
+pub fn serve_request<S, F>(mut stream: S, collect: F) -> io::Result<()>
+where
+    S: Read + Write,
+    F: FnOnce() -> Vec<MetricFamily>,
+{
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    // Read until the end of the headers. Scrapers don't send a body with GET requests.
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return write_response(&mut stream, "413 Payload Too Large", "text/plain", "");
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            write_response(&mut stream, "200 OK", CONTENT_TYPE, &encode(&collect()))
+        }
+        (Some("GET"), _) => write_response(&mut stream, "404 Not Found", "text/plain", ""),
+        _ => write_response(&mut stream, "405 Method Not Allowed", "text/plain", ""),
+    }
+}
+
+fn write_response<S: Write>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_families() {
+        let mut exits = MetricFamily::new("crosvm_vcpu_exits", MetricType::Counter, "VM exits.");
+        exits.add_sample(&[("vcpu", "0"), ("reason", "Io")], 12.0);
+        exits.add_sample(&[("vcpu", "1"), ("reason", "Mmio")], 3.0);
+        let balloon = MetricFamily::new(
+            "crosvm_balloon_actual_bytes",
+            MetricType::Gauge,
+            "Size of the balloon.",
+        )
+        .with_sample(&[], 4096.0);
+
+        assert_eq!(
+            encode(&[exits, balloon]),
+            "# TYPE crosvm_vcpu_exits counter\n\
+             # HELP crosvm_vcpu_exits VM exits.\n\
+             crosvm_vcpu_exits_total{vcpu=\"0\",reason=\"Io\"} 12\n\
+             crosvm_vcpu_exits_total{vcpu=\"1\",reason=\"Mmio\"} 3\n\
+             # TYPE crosvm_balloon_actual_bytes gauge\n\
+             # HELP crosvm_balloon_actual_bytes Size of the balloon.\n\
+             crosvm_balloon_actual_bytes 4096\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn encode_escapes_label_values() {
+        let family = MetricFamily::new("m", MetricType::Gauge, "a\\b\nc \"d\"")
+            .with_sample(&[("device", "a\"b\\c\nd")], 0.5);
+
+        assert_eq!(
+            encode(&[family]),
+            "# TYPE m gauge\n\
+             # HELP m a\\\\b\\nc \"d\"\n\
+             m{device=\"a\\\"b\\\\c\\nd\"} 0.5\n\
+             # EOF\n"
+        );
+    }
+
+    // A stream reading from a request and writing to a response buffer.
+    struct TestStream<'a> {
+        request: &'a [u8],
+        response: Vec<u8>,
+    }
+
+    impl Read for TestStream<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for TestStream<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serve(request: &str) -> String {
+        let mut stream = TestStream {
+            request: request.as_bytes(),
+            response: Vec::new(),
+        };
+        serve_request(&mut stream, || {
+            vec![MetricFamily::new("up", MetricType::Gauge, "Up.").with_sample(&[], 1.0)]
+        })
+        .unwrap();
+        String::from_utf8(stream.response).unwrap()
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let body = "# TYPE up gauge\n# HELP up Up.\nup 1\n# EOF\n";
+        assert_eq!(
+            serve("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                CONTENT_TYPE,
+                body.len(),
+                body
+            )
+        );
+    }
+
+    #[test]
+    fn serve_unknown_path() {
+        assert!(serve("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(serve("POST /metrics HTTP/1.1\r\n\r\n")
+            .starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -33,9 +33,15 @@ use devices::PciRootCommand;
 #[cfg(feature = "gdb")]
 use gdbstub::arch::Arch;
 #[cfg(feature = "gdb")]
+use gdbstub_arch::riscv::reg::id::RiscvRegId;
+#[cfg(feature = "gdb")]
 use gdbstub_arch::riscv::Riscv64 as GdbArch;
 use hypervisor::CoreRegister;
 use hypervisor::CpuConfigRiscv64;
+#[cfg(feature = "gdb")]
+use hypervisor::CsrRegister;
+#[cfg(feature = "gdb")]
+use hypervisor::HwWatchpoint;
 use hypervisor::Hypervisor;
 use hypervisor::ProtectionType;
 use hypervisor::TimerRegister;
@@ -112,6 +118,8 @@ pub enum Error {
     CreateSocket(io::Error),
     #[error("failed to create VCPU: {0}")]
     CreateVcpu(base::Error),
+    #[error("failed to decompress kernel: {0}")]
+    DecompressKernel(kernel_loader::Error),
     #[error("vm created wrong kind of vcpu")]
     DowncastVcpu,
     #[error("failed to finalize devices: {0}")]
@@ -128,10 +136,18 @@ pub enum Error {
     InitrdLoadFailure(arch::LoadImageError),
     #[error("kernel could not be loaded: {0}")]
     KernelLoadFailure(arch::LoadImageError),
+    #[error("error translating address: Page not present")]
+    PageNotPresent,
     #[error("protected vms not supported on riscv(yet)")]
     ProtectedVmUnsupported,
     #[error("ramoops address is different from high_mmio_base: {0} vs {1}")]
     RamoopsAddress(u64, u64),
+    #[error("error reading guest memory: {0}")]
+    ReadGuestMemory(vm_memory::GuestMemoryError),
+    #[error("error reading CPU register: {0}")]
+    ReadReg(base::Error),
+    #[error("error reading CPU registers: {0}")]
+    ReadRegs(base::Error),
     #[error("failed to register irq fd: {0}")]
     RegisterIrqfd(base::Error),
     #[error("error registering PCI bus: {0}")]
@@ -140,14 +156,24 @@ pub enum Error {
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("failed to set device attr: {0}")]
     SetDeviceAttr(base::Error),
+    #[error("failed to set a hardware breakpoint: {0}")]
+    SetHwBreakpoint(base::Error),
     #[error("failed to set register: {0}")]
     SetReg(base::Error),
     #[error("Timebase frequency too large")]
     TimebaseTooLarge,
+    #[error("failed to translate virtual address")]
+    TranslatingVirtAddr,
     #[error("this function isn't supported")]
     Unsupported,
     #[error("failed to initialize VCPU: {0}")]
     VcpuInit(base::Error),
+    #[error("error writing guest memory: {0}")]
+    WriteGuestMemory(vm_memory::GuestMemoryError),
+    #[error("error writing CPU register: {0}")]
+    WriteReg(base::Error),
+    #[error("error writing CPU registers: {0}")]
+    WriteRegs(base::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -294,6 +320,10 @@ impl arch::LinuxArch for Riscv64 {
                 return Err(Error::ImageTypeUnsupported);
             }
             VmImage::Kernel(ref mut kernel_image) => {
+                let mut decompressed =
+                    kernel_loader::decompress_kernel(kernel_image, mem.memory_size())
+                        .map_err(Error::DecompressKernel)?;
+                let kernel_image = decompressed.as_mut().unwrap_or(kernel_image);
                 let kernel_size =
                     arch::load_image(&mem, kernel_image, get_kernel_addr(), u64::max_value())
                         .map_err(Error::KernelLoadFailure)?;
@@ -459,49 +489,255 @@ impl<T: VcpuRiscv64> arch::GdbOps<T> for Riscv64 {
     type Error = Error;
 
     fn read_memory(
-        _vcpu: &T,
-        _guest_mem: &GuestMemory,
-        _vaddr: GuestAddress,
-        _len: usize,
+        vcpu: &T,
+        guest_mem: &GuestMemory,
+        vaddr: GuestAddress,
+        len: usize,
     ) -> Result<Vec<u8>> {
-        unimplemented!();
+        let satp = vcpu
+            .get_one_reg(VcpuRegister::Csr(CsrRegister::Satp))
+            .map_err(Error::ReadReg)?;
+        let mut buf = vec![0; len];
+        let mut total_read = 0u64;
+        // Handle reads across page boundaries.
+        while total_read < len as u64 {
+            let (paddr, psize) = phys_addr(guest_mem, vaddr.0 + total_read, satp)?;
+            let read_len = std::cmp::min(len as u64 - total_read, psize - (paddr & (psize - 1)));
+            guest_mem
+                .read_exact_at_addr(
+                    &mut buf[total_read as usize..(total_read + read_len) as usize],
+                    GuestAddress(paddr),
+                )
+                .map_err(Error::ReadGuestMemory)?;
+            total_read += read_len;
+        }
+        Ok(buf)
     }
 
     fn write_memory(
-        _vcpu: &T,
-        _guest_mem: &GuestMemory,
-        _vaddr: GuestAddress,
-        _buf: &[u8],
+        vcpu: &T,
+        guest_mem: &GuestMemory,
+        vaddr: GuestAddress,
+        buf: &[u8],
     ) -> Result<()> {
-        unimplemented!();
+        let satp = vcpu
+            .get_one_reg(VcpuRegister::Csr(CsrRegister::Satp))
+            .map_err(Error::ReadReg)?;
+        let mut total_written = 0u64;
+        // Handle writes across page boundaries.
+        while total_written < buf.len() as u64 {
+            let (paddr, psize) = phys_addr(guest_mem, vaddr.0 + total_written, satp)?;
+            let write_len = std::cmp::min(
+                buf.len() as u64 - total_written,
+                psize - (paddr & (psize - 1)),
+            );
+            guest_mem
+                .write_all_at_addr(
+                    &buf[total_written as usize..(total_written + write_len) as usize],
+                    GuestAddress(paddr),
+                )
+                .map_err(Error::WriteGuestMemory)?;
+            total_written += write_len;
+        }
+        Ok(())
     }
 
-    fn read_registers(_vcpu: &T) -> Result<<GdbArch as Arch>::Registers> {
-        unimplemented!();
+    fn read_registers(vcpu: &T) -> Result<<GdbArch as Arch>::Registers> {
+        let mut regs: <GdbArch as Arch>::Registers = Default::default();
+
+        // x0 is hardwired to zero and isn't exposed by KVM.
+        for (x, reg) in regs.x.iter_mut().skip(1).zip(GDB_GPRS) {
+            *x = vcpu
+                .get_one_reg(VcpuRegister::Core(reg))
+                .map_err(Error::ReadRegs)?;
+        }
+        regs.pc = vcpu
+            .get_one_reg(VcpuRegister::Core(CoreRegister::Pc))
+            .map_err(Error::ReadRegs)?;
+
+        Ok(regs)
     }
 
-    fn write_registers(_vcpu: &T, _regs: &<GdbArch as Arch>::Registers) -> Result<()> {
-        unimplemented!();
+    fn write_registers(vcpu: &T, regs: &<GdbArch as Arch>::Registers) -> Result<()> {
+        for (x, reg) in regs.x.iter().skip(1).zip(GDB_GPRS) {
+            vcpu.set_one_reg(VcpuRegister::Core(reg), *x)
+                .map_err(Error::WriteRegs)?;
+        }
+        vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::Pc), regs.pc)
+            .map_err(Error::WriteRegs)
     }
 
-    fn read_register(_vcpu: &T, _reg_id: <GdbArch as Arch>::RegId) -> Result<Vec<u8>> {
-        unimplemented!();
+    fn read_register(vcpu: &T, reg_id: <GdbArch as Arch>::RegId) -> Result<Vec<u8>> {
+        // x0 is hardwired to zero.
+        let val = match reg_id {
+            RiscvRegId::Gpr(0) => 0,
+            _ => vcpu
+                .get_one_reg(gdb_reg(reg_id).ok_or(Error::Unsupported)?)
+                .map_err(Error::ReadReg)?,
+        };
+        Ok(val.to_le_bytes().to_vec())
     }
 
-    fn write_register(_vcpu: &T, _reg_id: <GdbArch as Arch>::RegId, _data: &[u8]) -> Result<()> {
-        unimplemented!();
+    fn write_register(vcpu: &T, reg_id: <GdbArch as Arch>::RegId, data: &[u8]) -> Result<()> {
+        let val = data
+            .try_into()
+            .map(u64::from_le_bytes)
+            .map_err(|_| Error::WriteReg(base::Error::new(libc::EINVAL)))?;
+        match reg_id {
+            // Writes to x0 are ignored.
+            RiscvRegId::Gpr(0) => Ok(()),
+            _ => vcpu
+                .set_one_reg(gdb_reg(reg_id).ok_or(Error::Unsupported)?, val)
+                .map_err(Error::WriteReg),
+        }
     }
 
     fn enable_singlestep(_vcpu: &T) -> Result<()> {
-        unimplemented!();
+        // KVM can't single-step riscv64 vcpus, GDB steps with breakpoints instead.
+        Err(Error::Unsupported)
     }
 
     fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
-        unimplemented!();
+        // KVM doesn't expose the debug triggers to the VMM, so only software breakpoints are
+        // available.
+        Ok(0)
     }
 
-    fn set_hw_breakpoints(_vcpu: &T, _breakpoints: &[GuestAddress]) -> Result<()> {
-        unimplemented!();
+    fn set_hw_breakpoints(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+    ) -> Result<()> {
+        if !breakpoints.is_empty() || !watchpoints.is_empty() {
+            return Err(Error::SetHwBreakpoint(base::Error::new(libc::ENOTSUP)));
+        }
+        // Keep trapping the software breakpoints while disabling single-step.
+        const SINGLE_STEP: bool = false;
+        vcpu.set_guest_debug(SINGLE_STEP)
+            .map_err(Error::SetHwBreakpoint)
+    }
+
+    fn get_debug_exit_watchpoint(_vcpu: &T) -> Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// Translates the guest virtual address `vaddr` using the page tables pointed to by `satp`.
+/// Returns the guest physical address and the size of the page containing it.
+#[cfg(feature = "gdb")]
+fn phys_addr(mem: &GuestMemory, vaddr: u64, satp: u64) -> Result<(u64, u64)> {
+    const SATP_MODE_SHIFT: u64 = 60;
+    const SATP_MODE_BARE: u64 = 0;
+    const SATP_MODE_SV39: u64 = 8;
+    const SATP_MODE_SV48: u64 = 9;
+    const SATP_MODE_SV57: u64 = 10;
+    const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+    // bits 10 through 53 are the physical page number in a PTE.
+    const PTE_PPN_SHIFT: u64 = 10;
+    const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+    const PTE_VALID: u64 = 1 << 0;
+    const PTE_READ: u64 = 1 << 1;
+    const PTE_EXECUTE: u64 = 1 << 3;
+
+    const PAGE_SHIFT: u64 = 12;
+    const PAGE_SIZE_4K: u64 = 1 << PAGE_SHIFT;
+    const VPN_BITS: u64 = 9;
+
+    let levels = match satp >> SATP_MODE_SHIFT {
+        SATP_MODE_BARE => return Ok((vaddr, PAGE_SIZE_4K)),
+        SATP_MODE_SV39 => 3,
+        SATP_MODE_SV48 => 4,
+        SATP_MODE_SV57 => 5,
+        _ => return Err(Error::TranslatingVirtAddr),
+    };
+
+    let mut table_addr = (satp & SATP_PPN_MASK) << PAGE_SHIFT;
+    for level in (0..levels).rev() {
+        let shift = PAGE_SHIFT + level * VPN_BITS;
+        let vpn = (vaddr >> shift) & ((1 << VPN_BITS) - 1);
+        let pte: u64 = mem
+            .read_obj_from_addr(GuestAddress(table_addr + vpn * 8))
+            .map_err(|_| Error::TranslatingVirtAddr)?;
+        if pte & PTE_VALID == 0 {
+            return Err(Error::PageNotPresent);
+        }
+        let ppn_addr = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) << PAGE_SHIFT;
+        // A PTE is a leaf if it's readable or executable, mapping a superpage above level 0.
+        if pte & (PTE_READ | PTE_EXECUTE) != 0 {
+            let page_size = 1 << shift;
+            return Ok((
+                ppn_addr & !(page_size - 1) | vaddr & (page_size - 1),
+                page_size,
+            ));
+        }
+        table_addr = ppn_addr;
+    }
+    Err(Error::PageNotPresent)
+}
+
+/// Core registers backing the GDB general purpose registers x1 to x31.
+#[cfg(feature = "gdb")]
+const GDB_GPRS: [CoreRegister; 31] = [
+    CoreRegister::Ra,
+    CoreRegister::Sp,
+    CoreRegister::Gp,
+    CoreRegister::Tp,
+    CoreRegister::T0,
+    CoreRegister::T1,
+    CoreRegister::T2,
+    CoreRegister::S0,
+    CoreRegister::S1,
+    CoreRegister::A0,
+    CoreRegister::A1,
+    CoreRegister::A2,
+    CoreRegister::A3,
+    CoreRegister::A4,
+    CoreRegister::A5,
+    CoreRegister::A6,
+    CoreRegister::A7,
+    CoreRegister::S2,
+    CoreRegister::S3,
+    CoreRegister::S4,
+    CoreRegister::S5,
+    CoreRegister::S6,
+    CoreRegister::S7,
+    CoreRegister::S8,
+    CoreRegister::S9,
+    CoreRegister::S10,
+    CoreRegister::S11,
+    CoreRegister::T3,
+    CoreRegister::T4,
+    CoreRegister::T5,
+    CoreRegister::T6,
+];
+
+/// Returns the KVM register backing the GDB register `reg_id`, or `None` if it isn't accessible.
+#[cfg(feature = "gdb")]
+fn gdb_reg(reg_id: <GdbArch as Arch>::RegId) -> Option<VcpuRegister> {
+    match reg_id {
+        RiscvRegId::Gpr(n) => GDB_GPRS
+            .get(usize::from(n).checked_sub(1)?)
+            .map(|&reg| VcpuRegister::Core(reg)),
+        RiscvRegId::Pc => Some(VcpuRegister::Core(CoreRegister::Pc)),
+        RiscvRegId::Priv => Some(VcpuRegister::Core(CoreRegister::Mode)),
+        RiscvRegId::Csr(csr) => {
+            let csr = match csr {
+                0x100 => CsrRegister::Sstatus,
+                0x104 => CsrRegister::Sie,
+                0x105 => CsrRegister::Stvec,
+                0x106 => CsrRegister::Scounteren,
+                0x140 => CsrRegister::Sscratch,
+                0x141 => CsrRegister::Sepc,
+                0x142 => CsrRegister::Scause,
+                0x143 => CsrRegister::Stval,
+                0x144 => CsrRegister::Sip,
+                0x180 => CsrRegister::Satp,
+                _ => return None,
+            };
+            Some(VcpuRegister::Csr(csr))
+        }
+        // TODO: Expose the floating point registers through KVM_REG_RISCV_FP_D.
+        _ => None,
     }
 }
 
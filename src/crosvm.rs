@@ -15,4 +15,6 @@ mod gpu_config;
 pub mod plugin;
 #[cfg(all(target_arch = "x86_64", unix))]
 pub mod ratelimit;
+#[cfg(feature = "stats")]
+pub(crate) mod stats;
 pub mod sys;
@@ -13,6 +13,8 @@ cfg_if::cfg_if! {
 }
 
 use std::collections::BTreeMap;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::SocketAddr;
 #[cfg(feature = "config-file")]
 use std::path::Path;
 use std::path::PathBuf;
@@ -62,6 +64,10 @@ use serde::Deserializer;
 use serde::Serialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+#[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+use vm_control::balloon_policy::BalloonPolicyConfig;
+use vm_control::input::InputEvent;
+use vm_control::EmulatedUsbDevice;
 
 #[cfg(feature = "gpu")]
 use super::gpu_config::fixup_gpu_display_options;
@@ -86,6 +92,7 @@ use crate::crosvm::config::BatteryConfig;
 #[cfg(feature = "plugin")]
 use crate::crosvm::config::BindMount;
 use crate::crosvm::config::CpuOptions;
+use crate::crosvm::config::CustomInputOption;
 use crate::crosvm::config::Executable;
 use crate::crosvm::config::FileBackedMappingParameters;
 #[cfg(feature = "plugin")]
@@ -93,6 +100,8 @@ use crate::crosvm::config::GidMap;
 use crate::crosvm::config::HypervisorKind;
 use crate::crosvm::config::IrqChipKind;
 use crate::crosvm::config::MemOptions;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use crate::crosvm::config::MetricsExporterOption;
 use crate::crosvm::config::TouchDeviceOption;
 use crate::crosvm::config::VhostUserFsOption;
 use crate::crosvm::config::VhostUserOption;
@@ -123,6 +132,8 @@ pub enum CrossPlatformCommands {
     #[cfg(feature = "balloon")]
     Balloon(BalloonCommand),
     #[cfg(feature = "balloon")]
+    BalloonPolicy(BalloonPolicyCommand),
+    #[cfg(feature = "balloon")]
     BalloonStats(BalloonStatsCommand),
     #[cfg(feature = "balloon")]
     BalloonWs(BalloonWsCommand),
@@ -136,11 +147,15 @@ pub enum CrossPlatformCommands {
     CreateQcow2(CreateQcow2Command),
     Device(DeviceCommand),
     Disk(DiskCommand),
+    DumpGuest(DumpGuestCommand),
     #[cfg(feature = "gpu")]
     Gpu(GpuCommand),
+    Input(InputCommand),
     MakeRT(MakeRTCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
+    #[cfg(feature = "stats")]
+    Stats(StatsCommand),
     Stop(StopCommand),
     Suspend(SuspendCommand),
     Swap(SwapCommand),
@@ -153,6 +168,7 @@ pub enum CrossPlatformCommands {
     #[cfg(feature = "pci-hotplug")]
     VirtioNet(VirtioNetCommand),
     Snapshot(SnapshotCommand),
+    Snd(SndCommand),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -174,6 +190,49 @@ pub struct BalloonCommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+/// Prints the state and last decision of the automatic balloon policy
+pub struct BalloonPolicyStatusCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "enable")]
+/// Resume automatic balloon resizing
+pub struct BalloonPolicyEnableCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "disable")]
+/// Pause automatic balloon resizing
+pub struct BalloonPolicyDisableCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+/// Automatic balloon policy commands
+#[derive(FromArgs)]
+#[argh(subcommand, name = "balloon_policy")]
+pub struct BalloonPolicyCommand {
+    #[argh(subcommand)]
+    pub nested: BalloonPolicySubcommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum BalloonPolicySubcommands {
+    Status(BalloonPolicyStatusCommand),
+    Enable(BalloonPolicyEnableCommand),
+    Disable(BalloonPolicyDisableCommand),
+}
+
 #[derive(argh::FromArgs)]
 #[argh(subcommand, name = "balloon_stats")]
 /// Prints virtio balloon statistics for a `VM_SOCKET`
@@ -271,6 +330,18 @@ pub struct DiskCommand {
     pub command: DiskSubcommand,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dump-guest")]
+/// Writes guest memory and vCPU registers of the crosvm instance to an ELF core file
+pub struct DumpGuestCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(positional, arg_name = "FILE")]
+    /// path of the core file to write
+    pub dump_path: PathBuf,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "make_rt")]
 /// Enables real-time vcpu priority for crosvm instances started with `--delay-rt`
@@ -292,6 +363,22 @@ pub struct ResumeCommand {
     pub full: bool,
 }
 
+#[cfg(feature = "stats")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stats")]
+/// Prints the VM exit and bus access statistics of the crosvm instance as JSON
+pub struct StatsCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    /// start gathering statistics instead of printing them
+    #[argh(switch)]
+    pub enable: bool,
+    /// stop gathering statistics instead of printing them
+    #[argh(switch)]
+    pub disable: bool,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "stop")]
 /// Stops crosvm instances via their control sockets
@@ -361,6 +448,146 @@ pub struct SwapStatusCommand {
     pub socket_path: String,
 }
 
+fn parse_jack_state(value: &str) -> Result<bool, String> {
+    match value {
+        "connect" => Ok(true),
+        "disconnect" => Ok(false),
+        _ => Err(format!(
+            "invalid jack state {}, expected connect or disconnect",
+            value
+        )),
+    }
+}
+
+#[derive(FromArgs)]
+/// plug or unplug a jack of a virtio-snd device
+#[argh(subcommand, name = "jack")]
+pub struct SndJackSubcommand {
+    #[argh(positional, arg_name = "SND_INDEX")]
+    /// index of the virtio-snd device
+    pub snd_index: usize,
+    #[argh(positional, arg_name = "JACK_ID")]
+    /// index of the jack in the device
+    pub jack_id: u32,
+    #[argh(positional, arg_name = "STATE", from_str_fn(parse_jack_state))]
+    /// connect or disconnect
+    pub connected: bool,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SndSubcommand {
+    Jack(SndJackSubcommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "snd")]
+/// Manage virtio-snd devices
+pub struct SndCommand {
+    #[argh(subcommand)]
+    pub command: SndSubcommand,
+}
+
+fn parse_input_event(value: &str) -> Result<InputEvent, String> {
+    let invalid = || format!("invalid event {}, expected TYPE:CODE:VALUE", value);
+    let mut fields = value.split(':');
+    let mut next_field = || fields.next().ok_or_else(invalid);
+    let type_ = next_field()?.parse().map_err(|_| invalid())?;
+    let code = next_field()?.parse().map_err(|_| invalid())?;
+    let value = next_field()?.parse().map_err(|_| invalid())?;
+    if fields.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(InputEvent { type_, code, value })
+}
+
+#[derive(FromArgs)]
+/// send events to an input device
+#[argh(subcommand, name = "inject")]
+pub struct InputInjectSubcommand {
+    #[argh(
+        option,
+        long = "event",
+        arg_name = "TYPE:CODE:VALUE",
+        from_str_fn(parse_input_event)
+    )]
+    /// event to send, in decimal, e.g. 1:30:1 to press KEY_A. A
+    /// SYN_REPORT event is sent after the events if the last one
+    /// is not already one
+    pub events: Vec<InputEvent>,
+    #[argh(positional, arg_name = "NAME")]
+    /// name of the input device: the option that created it
+    /// followed by its index, e.g. keyboard0 or multi-touch1
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// send the events of an evemu event script to an input device
+/// at the times they were recorded
+#[argh(subcommand, name = "replay")]
+pub struct InputReplaySubcommand {
+    #[argh(positional, arg_name = "NAME")]
+    /// name of the input device
+    pub name: String,
+    #[argh(positional, arg_name = "SCRIPT")]
+    /// path to the event script
+    pub script_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// record the events an input device sends to the guest to an
+/// event script that `crosvm input replay` can play back
+#[argh(subcommand, name = "record")]
+pub struct InputRecordSubcommand {
+    #[argh(positional, arg_name = "NAME")]
+    /// name of the input device
+    pub name: String,
+    #[argh(positional, arg_name = "SCRIPT")]
+    /// path to the event script to create
+    pub script_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// stop recording the events of an input device
+#[argh(subcommand, name = "stop-recording")]
+pub struct InputStopRecordingSubcommand {
+    #[argh(positional, arg_name = "NAME")]
+    /// name of the input device
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum InputSubcommand {
+    Inject(InputInjectSubcommand),
+    Replay(InputReplaySubcommand),
+    Record(InputRecordSubcommand),
+    StopRecording(InputStopRecordingSubcommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "input")]
+/// Inject, replay and record events of virtio-input devices
+pub struct InputCommand {
+    #[argh(subcommand)]
+    pub command: InputSubcommand,
+}
+
 /// Vmm-swap commands
 #[derive(FromArgs)]
 #[argh(subcommand, name = "swap")]
@@ -552,6 +779,10 @@ pub enum GpuSubCommand {
     AddDisplays(GpuAddDisplaysCommand),
     ListDisplays(GpuListDisplaysCommand),
     RemoveDisplays(GpuRemoveDisplaysCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Screenshot(GpuScreenshotCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    Capture(GpuCaptureCommand),
 }
 
 #[cfg(feature = "gpu")]
@@ -591,6 +822,48 @@ pub struct GpuRemoveDisplaysCommand {
     pub socket_path: String,
 }
 
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Save the image shown on a display as a PNG file.
+#[argh(subcommand, name = "screenshot")]
+pub struct GpuScreenshotCommand {
+    #[argh(positional, arg_name = "DISPLAY_ID")]
+    /// display id
+    pub display_id: u32,
+    #[argh(positional, arg_name = "FILE")]
+    /// path of the PNG file to write
+    pub output: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Capture frames of a display to a directory or a pipe.
+#[argh(subcommand, name = "capture")]
+pub struct GpuCaptureCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(option)]
+    /// number of frames to capture (default: until OUTPUT is closed)
+    pub frames: Option<u32>,
+    #[argh(option, default = "100")]
+    /// minimum delay between two frames in milliseconds (default: 100)
+    pub interval_ms: u64,
+    #[argh(switch)]
+    /// write rows of XRGB8888 pixels instead of PNG images
+    pub raw: bool,
+    #[argh(positional, arg_name = "OUTPUT")]
+    /// directory to write numbered frames to, or file or named pipe to write the frames to back
+    /// to back
+    pub output: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum UsbSubCommand {
@@ -615,6 +888,16 @@ pub struct UsbAttachCommand {
     #[argh(positional, arg_name = "VM_SOCKET")]
     /// VM Socket path
     pub socket_path: String,
+    #[argh(option, arg_name = "DEVICE")]
+    /// attach a device emulated by crosvm instead of a host device, one of mass-storage,
+    /// mass-storage-ro, keyboard or tablet. dev_path is then the disk image or the socket
+    /// providing input events.
+    pub emulated: Option<EmulatedUsbDevice>,
+    #[argh(option, arg_name = "BUSID")]
+    /// import device BUSID from a USB/IP server instead of attaching a host device. dev_path is
+    /// then the address of the server, as host[:port] with IPv6 addresses in brackets when a port
+    /// is given, e.g. [::1]:3240.
+    pub usbip: Option<String>,
 }
 
 #[derive(FromArgs)]
@@ -925,6 +1208,31 @@ pub struct RunCommand {
     /// enable page reporting in balloon.
     pub balloon_page_reporting: Option<bool>,
 
+    #[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+    #[argh(option)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// enable the automatic balloon policy, which resizes the
+    /// balloon based on guest stats and host memory pressure.
+    /// Comma separated key=value pairs:
+    /// Possible key values:
+    ///     interval-ms=NUM - time between two evaluations of
+    ///        the policy (default: 5000).
+    ///     guest-reserve-mib=NUM - memory the guest always
+    ///        keeps available (default: 256).
+    ///     step-mib=NUM - largest resize performed at once
+    ///        (default: 128).
+    ///     min-adjust-mib=NUM - smallest resize performed
+    ///        (default: 16).
+    ///     max-balloon-mib=NUM - largest balloon size
+    ///        (default: unbounded).
+    ///     pressure-high=NUM - host memory PSI (some avg10, in
+    ///        percent) above which the balloon is inflated
+    ///        (default: 10).
+    ///     pressure-low=NUM - host memory PSI below which the
+    ///        balloon is deflated (default: 1).
+    pub balloon_policy: Option<BalloonPolicyConfig>,
+
     #[argh(option)]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1111,6 +1419,19 @@ pub struct RunCommand {
     /// the crash handler ipc pipe name.
     pub crash_pipe_name: Option<String>,
 
+    #[argh(option, arg_name = "path=PATH,config-path=PATH")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = append)]
+    /// add an input device whose capabilities are read from a
+    /// JSON descriptor file, e.g. a gamepad or a pen tablet.
+    /// Possible key values:
+    ///     path=PATH - path to a socket from where to read input
+    ///         events and write status updates to.
+    ///     config-path=PATH - path to the JSON file describing the
+    ///         name, serial name, device ids, properties, supported
+    ///         events and axes of the device.
+    pub custom_input: Vec<CustomInputOption>,
+
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1169,6 +1490,14 @@ pub struct RunCommand {
     /// dump generated device tree as a DTB file
     pub dump_device_tree_blob: Option<PathBuf>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, long = "dump-guest-on-crash", arg_name = "FILE")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// write an ELF core dump of guest memory and vCPU registers to
+    /// FILE when the guest panics (pvpanic) or reports a crash
+    pub dump_guest_on_crash: Option<PathBuf>,
+
     #[argh(
         option,
         arg_name = "CPU=DYN_PWR[,CPU=DYN_PWR[,...]]",
@@ -1200,7 +1529,6 @@ pub struct RunCommand {
     /// path to an event device node. The device will be grabbed (unusable from the host) and made available to the guest with the same configuration it shows on the host
     pub evdev: Vec<PathBuf>,
 
-    #[cfg(windows)]
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1474,6 +1802,17 @@ pub struct RunCommand {
     ///     size=NUM - amount of guest memory in MiB. (default: 256)
     pub mem: Option<MemOptions>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "[path=PATH|port=PORT]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// serve VM metrics in the OpenMetrics text format, for
+    /// Prometheus compatible monitoring.
+    /// Possible key values:
+    ///     path=PATH - path of a unix socket to listen on.
+    ///     port=PORT - TCP port to listen on, on 127.0.0.1.
+    pub metrics_exporter: Option<MetricsExporterOption>,
+
     #[argh(option, from_str_fn(parse_mmio_address_range))]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1777,6 +2116,14 @@ pub struct RunCommand {
     /// enable virtio-pvclock.
     pub pvclock: Option<bool>,
 
+    #[cfg(target_arch = "x86_64")]
+    #[argh(switch)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// start an ELF kernel through the Xen PVH entry point it
+    /// advertises, instead of its 64-bit entry point
+    pub pvh: Option<bool>,
+
     #[argh(option, long = "restore", arg_name = "PATH")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1926,7 +2273,7 @@ pub struct RunCommand {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     #[argh(
         option,
-        arg_name = "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:dax=BOOL,posix_acl=BOOL]"
+        arg_name = "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:dax=BOOL,posix_acl=BOOL,lower=PATH,uid_map=MAP,gid_map=MAP,num_queues=N,num_threads=N]"
     )]
     // TODO(b/218223240) add Deserialize implementation for SharedDir so it can be supported by the
     // config file.
@@ -1978,6 +2325,24 @@ pub struct RunCommand {
     ///        supports POSIX ACLs.  This should only be enabled
     ///        when the underlying file system supports POSIX ACLs.
     ///        The default value for this option is "true".
+    ///     lower=PATH - Read-only directory to overlay below
+    ///        the shared directory. Files from PATH are visible
+    ///        in the guest and copied into the shared directory
+    ///        when they are modified. Cannot be combined with
+    ///        ascii_casefold. (default: none)
+    ///     uid_map=MAP - uid map applied by the device itself,
+    ///        in the format "guest host count[,guest host count]".
+    ///        The value must be quoted if it contains more than
+    ///        one range. Unlike uidmap, this doesn't require a
+    ///        user namespace. Only supported for type=fs.
+    ///        (default: identity)
+    ///     gid_map=MAP - gid map in the same format as uid_map.
+    ///        (default: identity)
+    ///     num_queues=N - Number of request queues advertised to
+    ///        the VM. Older guest kernels don't support more
+    ///        than one. (default: 1)
+    ///     num_threads=N - Number of threads executing the
+    ///        requests of each request queue. (default: 1)
     ///     uid=UID - uid of the device process in the user
     ///        namespace created by minijail. (default: 0)
     ///     gid=GID - gid of the device process in the user
@@ -2286,7 +2651,7 @@ pub struct RunCommand {
     #[serde(default)]
     #[merge(strategy = append)]
     /// (EXPERIMENTAL) enable virtio-video decoder device
-    /// Possible backend values: libvda, ffmpeg, vaapi
+    /// Possible backend values: libvda, ffmpeg, vaapi, swcodec
     pub video_decoder: Vec<VideoDeviceConfig>,
 
     #[cfg(feature = "video-encoder")]
@@ -2294,7 +2659,7 @@ pub struct RunCommand {
     #[serde(default)]
     #[merge(strategy = append)]
     /// (EXPERIMENTAL) enable virtio-video encoder device
-    /// Possible backend values: libvda
+    /// Possible backend values: libvda, ffmpeg, swcodec
     pub video_encoder: Vec<VideoDeviceConfig>,
 
     #[cfg(target_arch = "aarch64")]
@@ -2317,8 +2682,9 @@ pub struct RunCommand {
     /// Possible key values:
     ///     capture=(false,true) - Disable/enable audio capture.
     ///         Default is false.
-    ///     backend=(null,file,[cras]) - Which backend to use for
-    ///         virtio-snd.
+    ///     backend=(null,file,[cras],[alsa],[pulse]) - Which
+    ///         backend to use for virtio-snd. pulse also works with
+    ///         PipeWire through pipewire-pulse.
     ///     client_type=(crosvm,arcvm,borealis) - Set specific
     ///         client type for cras backend. Default is crosvm.
     ///     socket_type=(legacy,unified) Set specific socket type
@@ -2327,6 +2693,19 @@ pub struct RunCommand {
     ///         for file backend.
     ///     playback_size=INT - Set size of the output streams
     ///         from file backend.
+    ///     playback_format=(raw,wav) - Write output streams of
+    ///         file backend as raw samples to stream-N.out, or as
+    ///         WAV files to stream-N.wav. Default is raw.
+    ///     capture_path=STR - Set directory of the stream-N.wav or
+    ///         stream-N.raw files read by input streams of file
+    ///         backend. Input streams without a file capture
+    ///         silence.
+    ///     capture_loop=(false,true) - Restart input streams of
+    ///         file backend at the end of their file instead of
+    ///         capturing silence. Default is false.
+    ///     timestamp_path=STR - Set directory where file backend
+    ///         logs the time of each buffer of stream N to
+    ///         stream-N.log.
     ///     num_output_devices=INT - Set number of output PCM
     ///         devices.
     ///     num_input_devices=INT - Set number of input PCM devices.
@@ -2334,9 +2713,34 @@ pub struct RunCommand {
     ///         streams per device.
     ///     num_input_streams=INT - Set number of input PCM streams
     ///         per device.
+    ///     output_device_config=[[device=STR,chmaps=[LAYOUT,...],
+    ///         jack=STATE],...] - Configure each output PCM device.
+    ///         device sets the host device for the alsa and pulse
+    ///         backends, e.g. an ALSA PCM name like "hw:0,0" or a
+    ///         PulseAudio sink. chmaps lists the channel layouts
+    ///         (mono,stereo,quad,surround51,surround71) offered to
+    ///         the guest. Default is [stereo,quad,surround51].
+    ///         jack=(connected,disconnected) adds a headphone jack
+    ///         in that initial state, which `crosvm snd jack` plugs
+    ///         and unplugs while the VM runs. Jacks are not
+    ///         supported by the vhost-user device.
+    ///     input_device_config=[[device=STR,chmaps=[LAYOUT,...],
+    ///         jack=STATE],...] - Same as output_device_config for
+    ///         input PCM devices, with a microphone jack. Default
+    ///         chmaps is [stereo].
     pub virtio_snd: Vec<SndParameters>,
 
-    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE]")]
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "ADDRESS:PORT")]
+    #[serde(default)]
+    #[merge(strategy = overwrite_option)]
+    /// serve the GPU display to VNC clients connecting to
+    /// ADDRESS:PORT (e.g. 127.0.0.1:5900), instead of showing it
+    /// in a Wayland or X11 window. Clients are not authenticated,
+    /// so ADDRESS must be a loopback address
+    pub vnc_display: Option<SocketAddr>,
+
+    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE][,uds-path=PATH]")]
     #[serde(default)]
     #[merge(strategy = overwrite_option)]
     /// add a vsock device. Since a guest can only have one CID,
@@ -2344,6 +2748,10 @@ pub struct RunCommand {
     ///     cid=CID - CID to use for the device.
     ///     device=VHOST_DEVICE - path to the vhost-vsock device to
     ///         use (Linux only). Defaults to /dev/vhost-vsock.
+    ///     uds-path=PATH - bridge the guest to unix sockets
+    ///         instead of using vhost-vsock (Linux only). Guest
+    ///         connections to port P go to PATH_P, and host
+    ///         programs reach the guest through PATH.
     pub vsock: Option<VsockConfig>,
 
     #[cfg(feature = "vtpm")]
@@ -2510,6 +2918,11 @@ impl TryFrom<RunCommand> for super::config::Config {
         let mem = cmd.mem.unwrap_or_default();
         cfg.memory = mem.size;
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            cfg.metrics_exporter = cmd.metrics_exporter;
+        }
+
         #[cfg(target_arch = "aarch64")]
         {
             if cmd.mte.unwrap_or_default()
@@ -2672,7 +3085,6 @@ impl TryFrom<RunCommand> for super::config::Config {
                 cfg.crash_pipe_name = cmd.crash_pipe_name;
             }
             cfg.product_name = cmd.product_name;
-            cfg.exit_stats = cmd.exit_stats.unwrap_or_default();
             cfg.host_guid = cmd.host_guid;
             cfg.kernel_log_file = cmd.kernel_log_file;
             cfg.log_file = cmd.log_file;
@@ -2715,6 +3127,16 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         #[cfg(any(target_os = "android", target_os = "linux"))]
         {
+            if let Some(address) = cmd.vnc_display {
+                // The VNC server doesn't authenticate its clients.
+                if !address.ip().is_loopback() {
+                    return Err(format!(
+                        "--vnc-display must listen on a loopback address, got {}",
+                        address
+                    ));
+                }
+            }
+            cfg.vnc_display = cmd.vnc_display;
             cfg.x_display = cmd.x_display;
         }
 
@@ -2831,6 +3253,7 @@ impl TryFrom<RunCommand> for super::config::Config {
         cfg.virtio_switches = cmd.switches;
         cfg.virtio_rotary = cmd.rotary;
         cfg.virtio_input_evdevs = cmd.evdev;
+        cfg.virtio_custom_input = cmd.custom_input;
 
         cfg.irq_chip = cmd.irqchip;
 
@@ -2872,6 +3295,10 @@ impl TryFrom<RunCommand> for super::config::Config {
         cfg.rng = !cmd.no_rng.unwrap_or_default();
         cfg.balloon = !cmd.no_balloon.unwrap_or_default();
         cfg.balloon_page_reporting = cmd.balloon_page_reporting.unwrap_or_default();
+        #[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+        {
+            cfg.balloon_policy = cmd.balloon_policy;
+        }
         cfg.balloon_ws_num_bins = cmd.balloon_ws_num_bins.unwrap_or(4);
         cfg.balloon_ws_reporting = cmd.balloon_ws_reporting.unwrap_or_default()
         // TODO(b/288432539): remove once concierge is migrated
@@ -3116,6 +3543,7 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.break_linux_pci_config_io = cmd.break_linux_pci_config_io.unwrap_or_default();
             cfg.enable_hwp = cmd.enable_hwp.unwrap_or_default();
             cfg.force_s2idle = cmd.s2idle.unwrap_or_default();
+            cfg.pvh = cmd.pvh.unwrap_or_default();
             cfg.pcie_ecam = cmd.pcie_ecam;
             cfg.pci_low_start = cmd.pci_start;
             cfg.no_i8042 = cmd.no_i8042.unwrap_or_default();
@@ -3154,6 +3582,11 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.dump_device_tree_blob = cmd.dump_device_tree_blob;
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            cfg.dump_guest_on_crash = cmd.dump_guest_on_crash;
+        }
+
         cfg.itmt = cmd.itmt.unwrap_or_default();
 
         #[cfg(target_arch = "x86_64")]
@@ -3163,6 +3596,8 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.stub_pci_devices = cmd.stub_pci_device;
 
+        cfg.exit_stats = cmd.exit_stats.unwrap_or_default();
+
         cfg.file_backed_mappings = cmd.file_backed_mapping;
 
         cfg.init_memory = cmd.init_mem;
@@ -7,6 +7,7 @@ use std::arch::x86_64::__cpuid;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::__cpuid_count;
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -50,6 +51,8 @@ use resources::AddressRange;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
+#[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+use vm_control::balloon_policy::BalloonPolicyConfig;
 use vm_control::BatteryType;
 #[cfg(target_arch = "x86_64")]
 use x86_64::check_host_hybrid_support;
@@ -400,6 +403,27 @@ impl FromStr for TouchDeviceOption {
     }
 }
 
+/// Where the OpenMetrics exporter listens for scrapes. Exactly one of `path` and `port` is set.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MetricsExporterOption {
+    /// Path of a unix socket to listen on.
+    pub path: Option<PathBuf>,
+    /// TCP port to listen on, on the loopback interface only.
+    pub port: Option<u16>,
+}
+
+/// Input device whose capabilities are read from a descriptor file.
+#[derive(Debug, Serialize, Deserialize, FromKeyValues, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CustomInputOption {
+    /// Socket from where to read the input events.
+    pub path: PathBuf,
+    /// JSON file describing the device.
+    pub config_path: PathBuf,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromKeyValues)]
 #[serde(deny_unknown_fields)]
 pub struct FileBackedMappingParameters {
@@ -751,6 +775,8 @@ pub struct Config {
     pub balloon_bias: i64,
     pub balloon_control: Option<PathBuf>,
     pub balloon_page_reporting: bool,
+    #[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+    pub balloon_policy: Option<BalloonPolicyConfig>,
     pub balloon_ws_num_bins: u8,
     pub balloon_ws_reporting: bool,
     pub battery_config: Option<BatteryConfig>,
@@ -779,11 +805,12 @@ pub struct Config {
     pub display_window_keyboard: bool,
     pub display_window_mouse: bool,
     pub dump_device_tree_blob: Option<PathBuf>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub dump_guest_on_crash: Option<PathBuf>,
     pub dynamic_power_coefficient: BTreeMap<usize, u32>,
     pub enable_fw_cfg: bool,
     pub enable_hwp: bool,
     pub executable_path: Option<Executable>,
-    #[cfg(windows)]
     pub exit_stats: bool,
     pub file_backed_mappings: Vec<FileBackedMappingParameters>,
     pub force_calibrated_tsc_leaf: bool,
@@ -823,6 +850,8 @@ pub struct Config {
     pub logs_directory: Option<String>,
     pub memory: Option<u64>,
     pub memory_file: Option<PathBuf>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub metrics_exporter: Option<MetricsExporterOption>,
     pub mmio_address_ranges: Vec<AddressRange>,
     #[cfg(target_arch = "aarch64")]
     pub mte: bool,
@@ -861,6 +890,8 @@ pub struct Config {
     pub pstore: Option<Pstore>,
     #[cfg(windows)]
     pub pvclock: bool,
+    #[cfg(target_arch = "x86_64")]
+    pub pvh: bool,
     /// Must be `Some` iff `protection_type == ProtectionType::UnprotectedWithFirmware`.
     pub pvm_fw: Option<PathBuf>,
     pub restore_path: Option<PathBuf>,
@@ -923,6 +954,7 @@ pub struct Config {
     #[cfg(feature = "video-encoder")]
     pub video_enc: Vec<VideoDeviceConfig>,
     pub virt_cpufreq: bool,
+    pub virtio_custom_input: Vec<CustomInputOption>,
     pub virtio_input_evdevs: Vec<PathBuf>,
     pub virtio_keyboard: Vec<PathBuf>,
     pub virtio_mice: Vec<PathBuf>,
@@ -934,6 +966,7 @@ pub struct Config {
     pub virtio_snds: Vec<SndParameters>,
     pub virtio_switches: Vec<PathBuf>,
     pub virtio_trackpad: Vec<TouchDeviceOption>,
+    pub vnc_display: Option<SocketAddr>,
     pub vsock: Option<VsockConfig>,
     #[cfg(feature = "vtpm")]
     pub vtpm_proxy: bool,
@@ -953,6 +986,8 @@ impl Default for Config {
             balloon_bias: 0,
             balloon_control: None,
             balloon_page_reporting: false,
+            #[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+            balloon_policy: None,
             balloon_ws_num_bins: VIRTIO_BALLOON_WS_DEFAULT_NUM_BINS,
             balloon_ws_reporting: false,
             battery_config: None,
@@ -981,11 +1016,12 @@ impl Default for Config {
             display_window_keyboard: false,
             display_window_mouse: false,
             dump_device_tree_blob: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            dump_guest_on_crash: None,
             dynamic_power_coefficient: BTreeMap::new(),
             enable_fw_cfg: false,
             enable_hwp: false,
             executable_path: None,
-            #[cfg(windows)]
             exit_stats: false,
             file_backed_mappings: Vec::new(),
             force_calibrated_tsc_leaf: false,
@@ -1033,6 +1069,8 @@ impl Default for Config {
             logs_directory: None,
             memory: None,
             memory_file: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            metrics_exporter: None,
             mmio_address_ranges: Vec::new(),
             #[cfg(target_arch = "aarch64")]
             mte: false,
@@ -1067,6 +1105,8 @@ impl Default for Config {
             pstore: None,
             #[cfg(windows)]
             pvclock: false,
+            #[cfg(target_arch = "x86_64")]
+            pvh: false,
             pvm_fw: None,
             restore_path: None,
             rng: true,
@@ -1127,6 +1167,7 @@ impl Default for Config {
             #[cfg(feature = "video-encoder")]
             video_enc: Vec::new(),
             virt_cpufreq: false,
+            virtio_custom_input: Vec::new(),
             virtio_input_evdevs: Vec::new(),
             virtio_keyboard: Vec::new(),
             virtio_mice: Vec::new(),
@@ -1137,6 +1178,7 @@ impl Default for Config {
             virtio_snds: Vec::new(),
             virtio_switches: Vec::new(),
             virtio_trackpad: Vec::new(),
+            vnc_display: None,
             #[cfg(feature = "vtpm")]
             vtpm_proxy: false,
             wayland_socket_paths: BTreeMap::new(),
@@ -1158,9 +1200,12 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
     {
         crate::crosvm::gpu_config::validate_gpu_config(cfg)?;
     }
-    #[cfg(feature = "gdb")]
-    if cfg.gdb.is_some() && cfg.vcpu_count.unwrap_or(1) != 1 {
-        return Err("`gdb` requires the number of vCPU to be 1".to_string());
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if let Some(exporter) = &cfg.metrics_exporter {
+        if exporter.path.is_some() == exporter.port.is_some() {
+            return Err("`metrics-exporter` requires exactly one of `path` or `port`".to_string());
+        }
     }
     if cfg.host_cpu_topology {
         if cfg.no_smt {
@@ -1277,6 +1322,14 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         return Err("'balloon_page_reporting' requires enabled balloon".to_string());
     }
 
+    #[cfg(all(feature = "balloon", any(target_os = "android", target_os = "linux")))]
+    if let Some(policy) = &cfg.balloon_policy {
+        if !cfg.balloon {
+            return Err("'balloon-policy' requires enabled balloon".to_string());
+        }
+        policy.validate()?;
+    }
+
     #[cfg(any(target_os = "android", target_os = "linux"))]
     if cfg.lock_guest_memory && cfg.jail_config.is_none() {
         return Err("'lock-guest-memory' and 'disable-sandbox' are mutually exclusive".to_string());
@@ -1693,6 +1746,31 @@ mod tests {
         from_key_values::<BatteryConfig>("type=xxx").expect_err("parse should have failed");
     }
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parse_vnc_display() {
+        let cfg = TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--vnc-display", "127.0.0.1:5900", "/dev/null"],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cfg.vnc_display, Some("127.0.0.1:5900".parse().unwrap()));
+
+        // The server doesn't authenticate clients, so it must not be reachable from other hosts.
+        TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--vnc-display", "0.0.0.0:5900", "/dev/null"],
+            )
+            .unwrap(),
+        )
+        .map(|_| ())
+        .expect_err("non-loopback address must be rejected");
+    }
+
     #[test]
     fn parse_irqchip_kernel() {
         let cfg = TryInto::<Config>::try_into(
@@ -1858,6 +1936,61 @@ mod tests {
         );
     }
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parse_metrics_exporter() {
+        let cfg = TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--metrics-exporter", "port=9100", "/dev/null"],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.metrics_exporter,
+            Some(MetricsExporterOption {
+                path: None,
+                port: Some(9100),
+            })
+        );
+
+        let cfg = TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &[
+                    "--metrics-exporter",
+                    "path=/run/crosvm.metrics",
+                    "/dev/null",
+                ],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.metrics_exporter,
+            Some(MetricsExporterOption {
+                path: Some("/run/crosvm.metrics".into()),
+                port: None,
+            })
+        );
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parse_metrics_exporter_invalid() {
+        for arg in ["path=/run/crosvm.metrics,port=9100", ""] {
+            assert!(TryInto::<Config>::try_into(
+                crate::crosvm::cmdline::RunCommand::from_args(
+                    &[],
+                    &["--metrics-exporter", arg, "/dev/null"],
+                )
+                .unwrap(),
+            )
+            .is_err());
+        }
+    }
+
     #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
     #[test]
     fn parse_video() {
@@ -1883,6 +2016,12 @@ mod tests {
             let params: VideoDeviceConfig = from_key_values("vaapi").unwrap();
             assert_eq!(params.backend, VideoBackendType::Vaapi);
         }
+
+        #[cfg(feature = "swcodec")]
+        {
+            let params: VideoDeviceConfig = from_key_values("swcodec").unwrap();
+            assert_eq!(params.backend, VideoBackendType::Swcodec);
+        }
     }
 
     #[test]
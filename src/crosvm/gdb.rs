@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::VecDeque;
 use std::net::TcpListener;
 use std::sync::mpsc;
 use std::time::Duration;
@@ -17,27 +18,33 @@ use base::Tube;
 use base::TubeError;
 use gdbstub::arch::Arch;
 use gdbstub::common::Signal;
+use gdbstub::common::Tid;
 use gdbstub::conn::Connection;
 use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::run_blocking;
 use gdbstub::stub::run_blocking::BlockingEventLoop;
-use gdbstub::stub::SingleThreadStopReason;
+use gdbstub::stub::MultiThreadStopReason;
+use gdbstub::target::ext::base::multithread::MultiThreadBase;
+use gdbstub::target::ext::base::multithread::MultiThreadResume;
+use gdbstub::target::ext::base::multithread::MultiThreadResumeOps;
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStep;
+#[cfg(not(target_arch = "riscv64"))]
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStepOps;
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64"))]
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccessOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadBase;
-use gdbstub::target::ext::base::singlethread::SingleThreadResume;
-use gdbstub::target::ext::base::singlethread::SingleThreadResumeOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStep;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStepOps;
 use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::Breakpoints;
 use gdbstub::target::ext::breakpoints::BreakpointsOps;
 use gdbstub::target::ext::breakpoints::HwBreakpoint;
 use gdbstub::target::ext::breakpoints::HwBreakpointOps;
+use gdbstub::target::ext::breakpoints::HwWatchpoint;
+use gdbstub::target::ext::breakpoints::HwWatchpointOps;
+use gdbstub::target::ext::breakpoints::WatchKind;
 use gdbstub::target::Target;
 use gdbstub::target::TargetError::NonFatal;
 use gdbstub::target::TargetResult;
+use hypervisor::HwWatchpointKind;
 use remain::sorted;
 #[cfg(target_arch = "riscv64")]
 use riscv64::Riscv64 as CrosvmArch;
@@ -113,14 +120,40 @@ enum Error {
 }
 type GdbResult<T> = std::result::Result<T, Error>;
 
+// Each vCPU is exposed to GDB as a thread, whose ID is the vCPU index plus one since GDB thread
+// IDs can't be zero.
+fn cpu_to_tid(cpu: usize) -> Tid {
+    Tid::new(cpu + 1).expect("vCPU thread ID overflow")
+}
+
+fn tid_to_cpu(tid: Tid) -> usize {
+    tid.get() - 1
+}
+
+// How GDB asked to resume a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResumeAction {
+    Continue,
+    Step,
+}
+
 pub struct GdbStub {
     vm_tube: Mutex<Tube>,
     vcpu_com: Vec<mpsc::Sender<VcpuControl>>,
     from_vcpu: mpsc::Receiver<VcpuDebugStatusMessage>,
-
-    single_step: bool,
+    // Breakpoint and watchpoint hits received while waiting for the response of another request.
+    // They are reported to GDB before resuming the vCPUs.
+    pending_stops: VecDeque<VcpuDebugStatusMessage>,
+
+    // Resume actions set by GDB for the next resume, indexed by vCPU. The step action is kept
+    // until the vCPU stops to report the completion of the step.
+    resume_actions: Vec<Option<ResumeAction>>,
+    // Whether the hardware breakpoints and watchpoints of the vCPU must be applied again before
+    // resuming it, e.g. because single-stepping replaced them.
+    hw_breakpoints_stale: Vec<bool>,
     max_hw_breakpoints: Option<usize>,
     hw_breakpoints: Vec<GuestAddress>,
+    hw_watchpoints: Vec<hypervisor::HwWatchpoint>,
 }
 
 impl GdbStub {
@@ -129,23 +162,39 @@ impl GdbStub {
         vcpu_com: Vec<mpsc::Sender<VcpuControl>>,
         from_vcpu: mpsc::Receiver<VcpuDebugStatusMessage>,
     ) -> Self {
+        let vcpu_count = vcpu_com.len();
         GdbStub {
             vm_tube: Mutex::new(vm_tube),
             vcpu_com,
             from_vcpu,
-            single_step: false,
+            pending_stops: VecDeque::new(),
+            resume_actions: vec![None; vcpu_count],
+            // Apply the debug state on the first resume so that every vCPU traps to the debugger.
+            hw_breakpoints_stale: vec![true; vcpu_count],
             max_hw_breakpoints: None,
             hw_breakpoints: Default::default(),
+            hw_watchpoints: Default::default(),
         }
     }
 
-    fn vcpu_request(&self, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
-        // We use the only one vCPU when GDB is enabled.
-        self.vcpu_com[0].send(request).map_err(Error::VcpuRequest)?;
+    fn vcpu_request(&mut self, cpu: usize, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
+        self.vcpu_com[cpu]
+            .send(request)
+            .map_err(Error::VcpuRequest)?;
 
-        match self.from_vcpu.recv_timeout(Duration::from_millis(500)) {
-            Ok(msg) => Ok(msg.msg),
-            Err(e) => Err(Error::VcpuResponse(e)),
+        loop {
+            let msg = self
+                .from_vcpu
+                .recv_timeout(Duration::from_millis(500))
+                .map_err(Error::VcpuResponse)?;
+            match msg.msg {
+                // Another vCPU hit a breakpoint before all the vCPUs were stopped.
+                VcpuDebugStatus::HitBreakPoint | VcpuDebugStatus::HitWatchPoint(_) => {
+                    self.pending_stops.push_back(msg)
+                }
+                status if msg.cpu == cpu => return Ok(status),
+                status => error!("Unexpected response from vCPU {}: {:?}", msg.cpu, status),
+            }
         }
     }
 
@@ -159,8 +208,8 @@ impl GdbStub {
         }
     }
 
-    fn max_hw_breakpoints_request(&self) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
+    fn max_hw_breakpoints_request(&mut self) -> TargetResult<usize, Self> {
+        match self.vcpu_request(0, VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
             Ok(VcpuDebugStatus::HwBreakPointCount(n)) => Ok(n),
             Ok(s) => {
                 error!("Unexpected vCPU response for GetHwBreakPointCount: {:?}", s);
@@ -172,6 +221,73 @@ impl GdbStub {
             }
         }
     }
+
+    /// Sets the current hardware breakpoints and watchpoints on the vCPU `cpu`.
+    /// Returns `false` if the operation could not be completed.
+    fn set_vcpu_hw_breakpoints(&mut self, cpu: usize) -> bool {
+        let request =
+            VcpuDebug::SetHwBreakPoint(self.hw_breakpoints.clone(), self.hw_watchpoints.clone());
+        match self.vcpu_request(cpu, VcpuControl::Debug(request)) {
+            Ok(VcpuDebugStatus::CommandComplete) => {
+                self.hw_breakpoints_stale[cpu] = false;
+                true
+            }
+            Ok(s) => {
+                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
+                false
+            }
+            Err(e) => {
+                error!("Failed to request SetHwBreakPoint: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Sets the current hardware breakpoints and watchpoints on all the vCPUs.
+    /// Returns `false` if the operation could not be completed.
+    fn set_hw_breakpoints(&mut self) -> bool {
+        // Try every vCPU even after a failure so they all end up in the same state once the
+        // breakpoints are rolled back.
+        (0..self.vcpu_com.len()).fold(true, |ok, cpu| self.set_vcpu_hw_breakpoints(cpu) && ok)
+    }
+
+    /// Converts a breakpoint or watchpoint hit of a vCPU to the stop reason reported to GDB.
+    fn stop_reason(
+        &mut self,
+        msg: VcpuDebugStatusMessage,
+    ) -> Option<MultiThreadStopReason<<GdbArch as Arch>::Usize>> {
+        let tid = cpu_to_tid(msg.cpu);
+        let action = self.resume_actions[msg.cpu].take();
+        match msg.msg {
+            // `DoneStep` doesn't say which vCPU stepped, so report a trap of its thread instead.
+            VcpuDebugStatus::HitBreakPoint if action == Some(ResumeAction::Step) => {
+                Some(MultiThreadStopReason::SignalWithThread {
+                    tid,
+                    signal: Signal::SIGTRAP,
+                })
+            }
+            VcpuDebugStatus::HitBreakPoint => Some(MultiThreadStopReason::HwBreak(tid)),
+            VcpuDebugStatus::HitWatchPoint(index) => match self.hw_watchpoints.get(index) {
+                Some(watchpoint) => Some(MultiThreadStopReason::Watch {
+                    tid,
+                    kind: match watchpoint.kind {
+                        HwWatchpointKind::Write => WatchKind::Write,
+                        HwWatchpointKind::Read => WatchKind::Read,
+                        HwWatchpointKind::ReadWrite => WatchKind::ReadWrite,
+                    },
+                    addr: watchpoint.addr.0,
+                }),
+                None => {
+                    error!("vCPU {} hit unknown watchpoint {}", msg.cpu, index);
+                    Some(MultiThreadStopReason::HwBreak(tid))
+                }
+            },
+            status => {
+                error!("Unexpected VcpuDebugStatus: {:?}", status);
+                None
+            }
+        }
+    }
 }
 
 impl Target for GdbStub {
@@ -179,10 +295,10 @@ impl Target for GdbStub {
     type Error = &'static str;
 
     fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+        BaseOps::MultiThread(self)
     }
 
-    // TODO(keiichiw): sw_breakpoint, hw_watchpoint, extended_mode, monitor_cmd, section_offsets
+    // TODO(keiichiw): sw_breakpoint, extended_mode, monitor_cmd, section_offsets
     fn support_breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
         Some(self)
     }
@@ -200,12 +316,13 @@ impl Target for GdbStub {
     }
 }
 
-impl SingleThreadBase for GdbStub {
+impl MultiThreadBase for GdbStub {
     fn read_registers(
         &mut self,
         regs: &mut <Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadRegs)) {
+        match self.vcpu_request(tid_to_cpu(tid), VcpuControl::Debug(VcpuDebug::ReadRegs)) {
             Ok(VcpuDebugStatus::RegValues(r)) => {
                 *regs = r;
                 Ok(())
@@ -224,10 +341,12 @@ impl SingleThreadBase for GdbStub {
     fn write_registers(
         &mut self,
         regs: &<Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(
-            regs.clone(),
-        )))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(regs.clone()))),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteRegs: {:?}", s);
@@ -244,11 +363,12 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &mut [u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadMem(
-            GuestAddress(start_addr),
-            data.len(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(start_addr), data.len())),
+        ) {
             Ok(VcpuDebugStatus::MemoryRegion(r)) => {
                 for (dst, v) in data.iter_mut().zip(r.iter()) {
                     *dst = *v;
@@ -270,11 +390,15 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &[u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteMem(
-            GuestAddress(start_addr),
-            data.to_owned(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteMem(
+                GuestAddress(start_addr),
+                data.to_owned(),
+            )),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteMem: {:?}", s);
@@ -287,21 +411,57 @@ impl SingleThreadBase for GdbStub {
         }
     }
 
+    fn list_active_threads(
+        &mut self,
+        thread_is_active: &mut dyn FnMut(Tid),
+    ) -> Result<(), Self::Error> {
+        for cpu in 0..self.vcpu_com.len() {
+            thread_is_active(cpu_to_tid(cpu));
+        }
+        Ok(())
+    }
+
     #[inline(always)]
-    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<Self>> {
+    fn support_resume(&mut self) -> Option<MultiThreadResumeOps<Self>> {
         Some(self)
     }
 
-    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64"))]
     #[inline(always)]
-    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<(), Self>> {
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<Tid, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadResume for GdbStub {
-    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        // TODO: Handle any incoming signal.
+impl MultiThreadResume for GdbStub {
+    fn resume(&mut self) -> Result<(), Self::Error> {
+        // Report the breakpoints hit while the vCPUs were being stopped before running again.
+        if !self.pending_stops.is_empty() {
+            self.resume_actions.iter_mut().for_each(|a| *a = None);
+            return Ok(());
+        }
+
+        // All the vCPUs are resumed, and the ones with a step action only run one instruction.
+        // vCPUs without an action are continued, as gdbstub doesn't report the default action.
+        for cpu in 0..self.vcpu_com.len() {
+            if self.resume_actions[cpu] == Some(ResumeAction::Step) {
+                match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::EnableSinglestep)) {
+                    Ok(VcpuDebugStatus::CommandComplete) => {
+                        self.hw_breakpoints_stale[cpu] = true;
+                    }
+                    Ok(s) => {
+                        error!("Unexpected vCPU response for EnableSinglestep: {:?}", s);
+                        return Err("Unexpected vCPU response for EnableSinglestep");
+                    }
+                    Err(e) => {
+                        error!("Failed to request EnableSinglestep: {}", e);
+                        return Err("Failed to request EnableSinglestep");
+                    }
+                }
+            } else if self.hw_breakpoints_stale[cpu] && !self.set_vcpu_hw_breakpoints(cpu) {
+                return Err("Failed to restore the hardware breakpoints");
+            }
+        }
 
         self.vm_request(VmRequest::ResumeVcpus).map_err(|e| {
             error!("Failed to resume the target: {}", e);
@@ -309,35 +469,39 @@ impl SingleThreadResume for GdbStub {
         })
     }
 
+    fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+        self.resume_actions.iter_mut().for_each(|a| *a = None);
+        Ok(())
+    }
+
+    fn set_resume_action_continue(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        // TODO: Handle any incoming signal.
+
+        self.resume_actions[tid_to_cpu(tid)] = Some(ResumeAction::Continue);
+        Ok(())
+    }
+
+    // KVM can't single-step riscv64 vCPUs, so GDB steps them with software breakpoints.
+    #[cfg(not(target_arch = "riscv64"))]
     #[inline(always)]
-    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+    fn support_single_step(&mut self) -> Option<MultiThreadSingleStepOps<'_, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadSingleStep for GdbStub {
-    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+impl MultiThreadSingleStep for GdbStub {
+    fn set_resume_action_step(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
         // TODO: Handle any incoming signal.
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::EnableSinglestep)) {
-            Ok(VcpuDebugStatus::CommandComplete) => {
-                self.single_step = true;
-            }
-            Ok(s) => {
-                error!("Unexpected vCPU response for EnableSinglestep: {:?}", s);
-                return Err("Unexpected vCPU response for EnableSinglestep");
-            }
-            Err(e) => {
-                error!("Failed to request EnableSinglestep: {}", e);
-                return Err("Failed to request EnableSinglestep");
-            }
-        };
-
-        self.vm_request(VmRequest::ResumeVcpus).map_err(|e| {
-            error!("Failed to resume the target: {}", e);
-            "Failed to resume the target"
-        })?;
-
+        self.resume_actions[tid_to_cpu(tid)] = Some(ResumeAction::Step);
         Ok(())
     }
 }
@@ -346,6 +510,10 @@ impl Breakpoints for GdbStub {
     fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
         Some(self)
     }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+        Some(self)
+    }
 }
 
 impl HwBreakpoint for GdbStub {
@@ -356,30 +524,25 @@ impl HwBreakpoint for GdbStub {
         addr: <Self::Arch as Arch>::Usize,
         _kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        let max_count = *(match &mut self.max_hw_breakpoints {
-            None => self
-                .max_hw_breakpoints
-                .insert(self.max_hw_breakpoints_request()?),
+        let max_count = match self.max_hw_breakpoints {
             Some(c) => c,
-        });
+            None => {
+                let count = self.max_hw_breakpoints_request()?;
+                *self.max_hw_breakpoints.insert(count)
+            }
+        };
         if self.hw_breakpoints.len() >= max_count {
             error!("Not allowed to set more than {} HW breakpoints", max_count);
             return Err(NonFatal);
         }
         self.hw_breakpoints.push(GuestAddress(addr));
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
-            }
+        if self.set_hw_breakpoints() {
+            Ok(true)
+        } else {
+            self.hw_breakpoints.pop();
+            self.set_hw_breakpoints();
+            Ok(false)
         }
     }
 
@@ -392,30 +555,73 @@ impl HwBreakpoint for GdbStub {
     ) -> TargetResult<bool, Self> {
         self.hw_breakpoints.retain(|&b| b.0 != addr);
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
+        Ok(self.set_hw_breakpoints())
+    }
+}
+
+impl HwWatchpoint for GdbStub {
+    /// Add a new hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        self.hw_watchpoints.push(hypervisor::HwWatchpoint {
+            addr: GuestAddress(addr),
+            len,
+            kind: match kind {
+                WatchKind::Write => HwWatchpointKind::Write,
+                WatchKind::Read => HwWatchpointKind::Read,
+                WatchKind::ReadWrite => HwWatchpointKind::ReadWrite,
+            },
+        });
+
+        // The watchpoint may not fit in the debug registers, in which case GDB falls back to
+        // software watchpoints.
+        if self.set_hw_breakpoints() {
+            Ok(true)
+        } else {
+            self.hw_watchpoints.pop();
+            self.set_hw_breakpoints();
+            Ok(false)
+        }
+    }
+
+    /// Remove an existing hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        _kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .hw_watchpoints
+            .iter()
+            .position(|w| w.addr.0 == addr && w.len == len)
+        {
+            Some(index) => {
+                self.hw_watchpoints.remove(index);
+                Ok(self.set_hw_breakpoints())
             }
+            None => Ok(false),
         }
     }
 }
 
-impl SingleRegisterAccess<()> for GdbStub {
+impl SingleRegisterAccess<Tid> for GdbStub {
     fn read_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         buf: &mut [u8],
     ) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadReg(reg_id))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadReg(reg_id)),
+        ) {
             Ok(VcpuDebugStatus::RegValue(r)) => {
                 if buf.len() != r.len() {
                     error!(
@@ -443,14 +649,14 @@ impl SingleRegisterAccess<()> for GdbStub {
 
     fn write_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         val: &[u8],
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteReg(
-            reg_id,
-            val.to_owned(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteReg(reg_id, val.to_owned())),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteReg: {:?}", s);
@@ -469,7 +675,7 @@ struct GdbStubEventLoop;
 impl BlockingEventLoop for GdbStubEventLoop {
     type Target = GdbStub;
     type Connection = Box<dyn ConnectionExt<Error = std::io::Error>>;
-    type StopReason = SingleThreadStopReason<<GdbArch as Arch>::Usize>;
+    type StopReason = MultiThreadStopReason<<GdbArch as Arch>::Usize>;
 
     fn wait_for_stop_reason(
         target: &mut Self::Target,
@@ -482,27 +688,25 @@ impl BlockingEventLoop for GdbStubEventLoop {
         >,
     > {
         loop {
+            // The vCPUs are still stopped if a breakpoint hit is pending.
+            if let Some(msg) = target.pending_stops.pop_front() {
+                if let Some(reason) = target.stop_reason(msg) {
+                    return Ok(run_blocking::Event::TargetStopped(reason));
+                }
+                continue;
+            }
+
             // TODO(keiichiw): handle error?
             if let Ok(msg) = target
                 .from_vcpu
                 .recv_timeout(std::time::Duration::from_millis(100))
             {
-                match msg.msg {
-                    VcpuDebugStatus::HitBreakPoint => {
-                        if target.single_step {
-                            target.single_step = false;
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::DoneStep,
-                            ));
-                        } else {
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::HwBreak(()),
-                            ));
-                        }
-                    }
-                    status => {
-                        error!("Unexpected VcpuDebugStatus: {:?}", status);
-                    }
+                // Only the vCPU that hit the breakpoint stopped, so stop the other ones too.
+                if let Err(e) = target.vm_request(VmRequest::SuspendVcpus) {
+                    error!("Failed to suspend the target: {}", e);
+                }
+                if let Some(reason) = target.stop_reason(msg) {
+                    return Ok(run_blocking::Event::TargetStopped(reason));
                 }
             }
 
@@ -529,21 +733,28 @@ impl BlockingEventLoop for GdbStubEventLoop {
             "Failed to suspend the target"
         })?;
 
-        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+        Ok(Some(MultiThreadStopReason::Signal(Signal::SIGINT)))
     }
 }
 
-/// Notify the GDB thread that a VCPU has stopped because of a breakpoint.
-pub fn vcpu_exit_debug(
+/// Notify the GDB thread that a VCPU has stopped because of a breakpoint or a watchpoint.
+pub fn vcpu_exit_debug<V>(
     cpu: usize,
+    vcpu: &V,
     to_gdb_tube: Option<&mpsc::Sender<VcpuDebugStatusMessage>>,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<()>
+where
+    V: VcpuArch + 'static,
+{
     if let Some(ch) = to_gdb_tube.as_ref() {
-        ch.send(VcpuDebugStatusMessage {
-            cpu,
-            msg: VcpuDebugStatus::HitBreakPoint,
-        })
-        .context("failed to send breakpoint status to gdb thread")?;
+        let msg = match <CrosvmArch as arch::GdbOps<V>>::get_debug_exit_watchpoint(vcpu)
+            .context("failed to get the watchpoint of a debug exit")?
+        {
+            Some(index) => VcpuDebugStatus::HitWatchPoint(index),
+            None => VcpuDebugStatus::HitBreakPoint,
+        };
+        ch.send(VcpuDebugStatusMessage { cpu, msg })
+            .context("failed to send breakpoint status to gdb thread")?;
     }
     Ok(())
 }
@@ -600,8 +811,8 @@ where
             <CrosvmArch as arch::GdbOps<V>>::get_max_hw_breakpoints(vcpu as &V)
                 .context("failed to get max number of HW breakpoints")?,
         ),
-        VcpuDebug::SetHwBreakPoint(addrs) => {
-            <CrosvmArch as arch::GdbOps<V>>::set_hw_breakpoints(vcpu as &V, &addrs)
+        VcpuDebug::SetHwBreakPoint(addrs, watchpoints) => {
+            <CrosvmArch as arch::GdbOps<V>>::set_hw_breakpoints(vcpu as &V, &addrs, &watchpoints)
                 .context("failed to handle a gdb SetHwBreakPoint command")?;
             VcpuDebugStatus::CommandComplete
         }
@@ -614,3 +825,79 @@ where
         })
         .context("failed to send a debug status to GDB thread")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // Answers the debug requests of a vCPU until the GDB stub is dropped. Returns whether
+    // single-stepping was enabled.
+    fn fake_vcpu(
+        cpu: usize,
+        requests: mpsc::Receiver<VcpuControl>,
+        replies: mpsc::Sender<VcpuDebugStatusMessage>,
+    ) -> thread::JoinHandle<bool> {
+        thread::spawn(move || {
+            let mut single_step = false;
+            for request in requests {
+                match request {
+                    VcpuControl::Debug(VcpuDebug::EnableSinglestep) => single_step = true,
+                    VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(..)) => {}
+                    _ => panic!("unexpected request for vCPU {}", cpu),
+                }
+                let msg = VcpuDebugStatus::CommandComplete;
+                replies.send(VcpuDebugStatusMessage { cpu, msg }).unwrap();
+            }
+            single_step
+        })
+    }
+
+    #[test]
+    fn resume_step_and_unspecified() {
+        let (vm_tube, vm_host) = Tube::pair().unwrap();
+        let vm = thread::spawn(move || {
+            let request = vm_host.recv::<VmRequest>().unwrap();
+            vm_host.send(&VmResponse::Ok).unwrap();
+            matches!(request, VmRequest::ResumeVcpus)
+        });
+
+        let (replies, from_vcpu) = mpsc::channel();
+        let mut vcpu_com = Vec::new();
+        let mut vcpus = Vec::new();
+        for cpu in 0..3 {
+            let (sender, requests) = mpsc::channel();
+            vcpu_com.push(sender);
+            vcpus.push(fake_vcpu(cpu, requests, replies.clone()));
+        }
+        let mut gdb = GdbStub::new(vm_tube, vcpu_com, from_vcpu);
+
+        // Like `vCont;s:2;c`: the default continue action isn't reported to the target.
+        gdb.clear_resume_actions().unwrap();
+        gdb.set_resume_action_step(cpu_to_tid(1), None).unwrap();
+        gdb.resume().unwrap();
+        assert!(vm.join().unwrap(), "all the vCPUs must be resumed");
+
+        // The stop of the stepped vCPU is reported for its thread.
+        let stop = |cpu| VcpuDebugStatusMessage {
+            cpu,
+            msg: VcpuDebugStatus::HitBreakPoint,
+        };
+        assert_eq!(
+            gdb.stop_reason(stop(1)),
+            Some(MultiThreadStopReason::SignalWithThread {
+                tid: cpu_to_tid(1),
+                signal: Signal::SIGTRAP,
+            })
+        );
+        assert_eq!(
+            gdb.stop_reason(stop(0)),
+            Some(MultiThreadStopReason::HwBreak(cpu_to_tid(0)))
+        );
+
+        drop(gdb);
+        let single_step: Vec<bool> = vcpus.into_iter().map(|v| v.join().unwrap()).collect();
+        assert_eq!(single_step, [false, true, false]);
+    }
+}
@@ -10,11 +10,15 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
+use devices::Bus;
 use devices::BusStatistics;
 use hypervisor::VcpuExit;
 use sync::Mutex;
 
+#[cfg(windows)]
 const ERROR_RETRY_I32: i32 = winapi::shared::winerror::ERROR_RETRY as i32;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const ERROR_RETRY_I32: i32 = libc::EAGAIN;
 
 /// Statistics about the number and duration of VM exits.
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -75,9 +79,10 @@ impl VmExitStatistics {
     }
 
     /// Merge several VmExitStatistics into one.
-    pub fn merged(stats: &[VmExitStatistics]) -> VmExitStatistics {
+    pub fn merged(stats: &[Arc<Mutex<VmExitStatistics>>]) -> VmExitStatistics {
         let mut merged = VmExitStatistics::new();
         for other in stats.iter() {
+            let other = other.lock();
             for exit_index in 0..(MAX_EXIT_INT + 1) {
                 // We overflow because we don't want any disruptions to emulator running due to
                 // statistics
@@ -187,7 +192,9 @@ fn exit_index_to_str(exit: usize) -> String {
 pub struct StatisticsCollector {
     pub pio_bus_stats: Vec<Arc<Mutex<BusStatistics>>>,
     pub mmio_bus_stats: Vec<Arc<Mutex<BusStatistics>>>,
-    pub vm_exit_stats: Vec<VmExitStatistics>,
+    pub vm_exit_stats: Vec<Arc<Mutex<VmExitStatistics>>>,
+    /// Whether statistics are gathered by the vcpus added with `add_vcpu`.
+    enabled: bool,
 }
 
 impl StatisticsCollector {
@@ -195,6 +202,43 @@ impl StatisticsCollector {
         StatisticsCollector::default()
     }
 
+    /// Registers the statistics of a new vcpu using `io_bus` and `mmio_bus`.
+    ///
+    /// The buses are given their own statistics so that the accesses of each vcpu are recorded
+    /// separately. Returns the VM exit statistics the vcpu should update.
+    pub fn add_vcpu(
+        &mut self,
+        io_bus: &mut Bus,
+        mmio_bus: &mut Bus,
+    ) -> Arc<Mutex<VmExitStatistics>> {
+        for (bus, bus_stats) in [
+            (io_bus, &mut self.pio_bus_stats),
+            (mmio_bus, &mut self.mmio_bus_stats),
+        ] {
+            let mut stats = bus.stats.lock().empty_clone();
+            stats.set_enabled(self.enabled);
+            bus.stats = Arc::new(Mutex::new(stats));
+            bus_stats.push(bus.stats.clone());
+        }
+
+        let mut exit_stats = VmExitStatistics::new();
+        exit_stats.set_enabled(self.enabled);
+        let exit_stats = Arc::new(Mutex::new(exit_stats));
+        self.vm_exit_stats.push(exit_stats.clone());
+        exit_stats
+    }
+
+    /// Enable or disable statistics gathering on all the vcpus.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        for stats in self.pio_bus_stats.iter().chain(self.mmio_bus_stats.iter()) {
+            stats.lock().set_enabled(enabled);
+        }
+        for stats in self.vm_exit_stats.iter() {
+            stats.lock().set_enabled(enabled);
+        }
+    }
+
     /// Return a merged version of the pio bus statistics, mmio bus statistics, and the vm exit
     /// statistics for all vcpus.
     fn merged(&self) -> (BusStatistics, BusStatistics, VmExitStatistics) {
@@ -216,7 +260,7 @@ impl StatisticsCollector {
             vcpus_vec.push(serde_json::json!({
                 "io": self.pio_bus_stats[i].lock().json(),
                 "mmio": self.mmio_bus_stats[i].lock().json(),
-                "exits": self.vm_exit_stats[i].json(),
+                "exits": self.vm_exit_stats[i].lock().json(),
             }));
         }
 
@@ -4,6 +4,8 @@
 
 #[cfg(target_os = "android")]
 mod android;
+#[cfg(feature = "balloon")]
+mod balloon_policy;
 pub mod cmdline;
 pub mod config;
 mod device_helpers;
@@ -11,6 +13,7 @@ mod device_helpers;
 pub(crate) mod gpu;
 #[cfg(feature = "pci-hotplug")]
 pub(crate) mod jail_warden;
+mod metrics_exporter;
 #[cfg(feature = "pci-hotplug")]
 pub(crate) mod pci_hotplug_helpers;
 #[cfg(feature = "pci-hotplug")]
@@ -39,6 +42,7 @@ use std::ops::RangeInclusive;
 use std::os::unix::prelude::OpenOptionsExt;
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process;
 #[cfg(feature = "registered_events")]
 use std::rc::Rc;
@@ -63,6 +67,10 @@ use arch::VmArch;
 use arch::VmComponents;
 use arch::VmImage;
 use argh::FromArgs;
+#[cfg(feature = "balloon")]
+use balloon_policy::BalloonPolicyRunner;
+#[cfg(feature = "balloon")]
+use balloon_policy::BALLOON_POLICY_KEY;
 use base::ReadNotifier;
 #[cfg(feature = "balloon")]
 use base::UnixSeqpacket;
@@ -156,6 +164,7 @@ use jail_warden::JailWardenImpl;
 #[cfg(feature = "pci-hotplug")]
 use jail_warden::PermissiveJailWarden;
 use libc;
+use metrics_exporter::start_metrics_exporter;
 use minijail::Minijail;
 #[cfg(feature = "pci-hotplug")]
 use pci_hotplug_manager::PciHotPlugManager;
@@ -190,6 +199,8 @@ use crate::crosvm::gdb::gdb_thread;
 use crate::crosvm::gdb::GdbStub;
 #[cfg(all(target_arch = "x86_64", unix))]
 use crate::crosvm::ratelimit::Ratelimit;
+#[cfg(feature = "stats")]
+use crate::crosvm::stats::StatisticsCollector;
 use crate::crosvm::sys::cmdline::DevicesCommand;
 use crate::crosvm::sys::config::SharedDir;
 use crate::crosvm::sys::config::SharedDirKind;
@@ -212,6 +223,8 @@ fn create_virtio_devices(
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    #[cfg_attr(not(feature = "audio"), allow(unused_variables))] snd_device_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut BTreeMap<String, Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
     #[cfg(feature = "registered_events")] registered_evt_q: &SendTube,
@@ -397,12 +410,21 @@ fn create_virtio_devices(
         }
     }
 
+    // Returns the device end of the control tube of the input device `name`, whose host end is
+    // kept in `input_host_tubes`.
+    let mut input_control_tube = |name: String| -> Result<Tube> {
+        let (host_tube, device_tube) = Tube::pair().context("failed to create tube")?;
+        input_host_tubes.insert(name, host_tube);
+        Ok(device_tube)
+    };
+
     for (idx, single_touch_spec) in cfg.virtio_single_touch.iter().enumerate() {
         devs.push(create_single_touch_device(
             cfg.protection_type,
             &cfg.jail_config,
             single_touch_spec,
             idx as u32,
+            input_control_tube(format!("single-touch{}", idx))?,
         )?);
     }
 
@@ -412,6 +434,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             multi_touch_spec,
             idx as u32,
+            input_control_tube(format!("multi-touch{}", idx))?,
         )?);
     }
 
@@ -421,6 +444,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             trackpad_spec,
             idx as u32,
+            input_control_tube(format!("trackpad{}", idx))?,
         )?);
     }
 
@@ -430,6 +454,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             mouse_socket,
             idx as u32,
+            input_control_tube(format!("mouse{}", idx))?,
         )?);
     }
 
@@ -439,6 +464,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             keyboard_socket,
             idx as u32,
+            input_control_tube(format!("keyboard{}", idx))?,
         )?);
     }
 
@@ -448,6 +474,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             switches_socket,
             idx as u32,
+            input_control_tube(format!("switches{}", idx))?,
         )?);
     }
 
@@ -457,14 +484,26 @@ fn create_virtio_devices(
             &cfg.jail_config,
             rotary_socket,
             idx as u32,
+            input_control_tube(format!("rotary{}", idx))?,
         )?);
     }
 
-    for dev_path in &cfg.virtio_input_evdevs {
+    for (idx, custom_input) in cfg.virtio_custom_input.iter().enumerate() {
+        devs.push(create_custom_input_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            custom_input,
+            idx as u32,
+            input_control_tube(format!("custom-input{}", idx))?,
+        )?);
+    }
+
+    for (idx, dev_path) in cfg.virtio_input_evdevs.iter().enumerate() {
         devs.push(create_vinput_device(
             cfg.protection_type,
             &cfg.jail_config,
             dev_path,
+            input_control_tube(format!("evdev{}", idx))?,
         )?);
     }
 
@@ -520,6 +559,7 @@ fn create_virtio_devices(
                 cfg.protection_type,
                 &cfg.jail_config,
                 virtio_snd.clone(),
+                snd_device_tubes.remove(0),
             )?);
         }
     }
@@ -663,6 +703,8 @@ fn create_devices(
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    snd_device_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut BTreeMap<String, Tube>,
     #[cfg(feature = "usb")] usb_provider: DeviceProvider,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
@@ -791,6 +833,8 @@ fn create_devices(
         disk_device_tubes,
         pmem_device_tubes,
         fs_device_tubes,
+        snd_device_tubes,
+        input_host_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_tube,
         #[cfg(feature = "gpu")]
@@ -1177,6 +1221,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         itmt: cfg.itmt,
         #[cfg(target_arch = "x86_64")]
         force_s2idle: cfg.force_s2idle,
+        #[cfg(target_arch = "x86_64")]
+        pvh: cfg.pvh,
         pvm_fw: pvm_fw_image,
         #[cfg(target_arch = "x86_64")]
         pcie_ecam: cfg.pcie_ecam,
@@ -1613,6 +1659,18 @@ where
         components.gdb = Some((port, gdb_control_tube));
     }
 
+    // Removes the socket file of the metrics exporter when the VM exits.
+    let _metrics_exporter_socket = match &cfg.metrics_exporter {
+        Some(option) => {
+            // Each scrape queries the control loop like the `crosvm` control commands do.
+            let (exporter_host_tube, exporter_tube) =
+                Tube::pair().context("failed to create tube")?;
+            control_tubes.push(TaggedControlTube::Vm(exporter_host_tube));
+            start_metrics_exporter(option, exporter_tube, cfg.disks.len())?
+        }
+        None => None,
+    };
+
     #[cfg(feature = "balloon")]
     let (balloon_host_tube, balloon_device_tube) = if cfg.balloon {
         if let Some(ref path) = cfg.balloon_control {
@@ -1647,6 +1705,23 @@ where
         disk_device_tubes.push(disk_device_tube);
     }
 
+    // Input devices get their control socket when they are created, named after the option
+    // creating them.
+    let mut input_host_tubes = BTreeMap::new();
+
+    // Create one control socket per sound device.
+    let mut snd_device_tubes = Vec::new();
+    let mut snd_host_tubes = Vec::new();
+    #[cfg(feature = "audio")]
+    let num_snd_devices = cfg.virtio_snds.len();
+    #[cfg(not(feature = "audio"))]
+    let num_snd_devices = 0;
+    for _ in 0..num_snd_devices {
+        let (snd_host_tube, snd_device_tube) = Tube::pair().context("failed to create tube")?;
+        snd_host_tubes.push(snd_host_tube);
+        snd_device_tubes.push(snd_device_tube);
+    }
+
     let mut pmem_device_tubes = Vec::new();
     let pmem_count = cfg.pmem_devices.len();
     for _ in 0..pmem_count {
@@ -1767,6 +1842,8 @@ where
         &mut disk_device_tubes,
         &mut pmem_device_tubes,
         &mut fs_device_tubes,
+        &mut snd_device_tubes,
+        &mut input_host_tubes,
         #[cfg(feature = "usb")]
         usb_provider,
         #[cfg(feature = "gpu")]
@@ -1994,6 +2071,8 @@ where
         #[cfg(feature = "balloon")]
         balloon_host_tube,
         &disk_host_tubes,
+        &snd_host_tubes,
+        &input_host_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
         #[cfg(feature = "usb")]
@@ -2538,6 +2617,44 @@ fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
     }
 }
 
+/// Writes a guest memory dump after the guest panicked or one of its vCPUs reported a crash.
+///
+/// A vCPU reporting a crash stays suspended until it is resumed, so all the vCPUs are suspended
+/// during the dump and resumed afterwards to let the guest (or the crashed vCPU) carry on.
+fn dump_guest_on_crash(
+    dump_path: &Path,
+    mem: &GuestMemory,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_num: usize,
+) {
+    info!("dumping guest to {}", dump_path.display());
+    kick_vcpus(VcpuControl::RunState(VmRunMode::Suspending));
+    if let Err(e) = arch::vmcore::dump_guest(dump_path, mem, &kick_vcpus, vcpu_num) {
+        error!("failed to dump guest: {:#}", e);
+    }
+    kick_vcpus(VcpuControl::RunState(VmRunMode::Running));
+}
+
+/// Starts writing a guest memory dump requested from a control tube in a separate thread, adding
+/// the event signaled at the end of the dump to `wait_ctx`.
+fn start_guest_dump<T: EventToken>(
+    dump_path: PathBuf,
+    mem: &GuestMemory,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_num: usize,
+    wait_ctx: &WaitContext<T>,
+    token: T,
+) -> Result<arch::vmcore::GuestDumpThread> {
+    info!("dumping guest to {}", dump_path.display());
+    let dump = arch::vmcore::GuestDumpThread::start(dump_path, mem, &kick_vcpus, vcpu_num)?;
+    if let Err(e) = wait_ctx.add(dump.done_event(), token) {
+        // Wait for the dump here rather than leaving the vCPUs suspended.
+        dump.finish(kick_vcpus)?;
+        return Err(e).context("failed to add guest dump event to wait context");
+    }
+    Ok(dump)
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu>,
     sys_allocator: SystemAllocator,
@@ -2548,6 +2665,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
+    snd_host_tubes: &[Tube],
+    input_host_tubes: &BTreeMap<String, Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
     vm_evt_rdtube: RecvTube,
@@ -2578,6 +2697,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         RegisteredEvent,
         #[cfg(feature = "balloon")]
         BalloonTube,
+        #[cfg(feature = "balloon")]
+        BalloonPolicy,
+        GuestDump,
     }
 
     #[cfg(feature = "registered_events")]
@@ -2699,6 +2821,18 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         .transpose()
         .context("failed to create balloon tube")?;
 
+    #[cfg(feature = "balloon")]
+    let mut balloon_policy = match (&cfg.balloon_policy, &balloon_tube) {
+        (Some(config), Some(_)) => {
+            let runner = BalloonPolicyRunner::new(config.clone(), cfg.balloon_ws_reporting)?;
+            wait_ctx
+                .add(runner.timer(), Token::BalloonPolicy)
+                .context("failed to add descriptor to wait context")?;
+            Some(runner)
+        }
+        _ => None,
+    };
+
     if cfg.jail_config.is_some() {
         // Before starting VCPUs, in case we started with some capabilities, drop them all.
         drop_capabilities().context("failed to drop process capabilities")?;
@@ -2824,6 +2958,11 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     // Architecture-specific code must supply a vcpu_init element for each VCPU.
     assert_eq!(vcpus.len(), linux.vcpu_init.len());
 
+    #[cfg(feature = "stats")]
+    let mut stats = StatisticsCollector::new();
+    #[cfg(feature = "stats")]
+    stats.set_enabled(cfg.exit_stats);
+
     for ((cpu_id, vcpu), vcpu_init) in vcpus.into_iter().enumerate().zip(linux.vcpu_init.drain(..))
     {
         let (to_vcpu_channel, from_main_channel) = mpsc::channel();
@@ -2858,6 +2997,13 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         #[cfg(target_arch = "riscv64")]
         let cpu_config = Some(CpuConfigRiscv64::new(vcpu_init.fdt_address));
 
+        #[allow(unused_mut)]
+        let mut io_bus = (*linux.io_bus).clone();
+        #[allow(unused_mut)]
+        let mut mmio_bus = (*linux.mmio_bus).clone();
+        #[cfg(feature = "stats")]
+        let exit_stats = stats.add_vcpu(&mut io_bus, &mut mmio_bus);
+
         let handle = vcpu::run_vcpu(
             cpu_id,
             vcpu_ids[cpu_id],
@@ -2873,8 +3019,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             vcpu_affinity,
             linux.delay_rt,
             vcpu_thread_barrier.clone(),
-            (*linux.io_bus).clone(),
-            (*linux.mmio_bus).clone(),
+            io_bus,
+            mmio_bus,
             vm_evt_wrtube
                 .try_clone()
                 .context("failed to clone vm event tube")?,
@@ -2894,6 +3040,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             #[cfg(all(target_arch = "x86_64", unix))]
             bus_lock_ratelimit_ctrl,
             run_mode,
+            cfg.dump_guest_on_crash.is_some(),
+            #[cfg(feature = "stats")]
+            exit_stats,
         )?;
         vcpu_handles.push((handle, to_vcpu_channel));
     }
@@ -2994,6 +3143,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     #[cfg(feature = "registered_events")]
     let mut registered_evt_tubes: HashMap<RegisteredEvent, HashSet<AddressedProtoTube>> =
         HashMap::new();
+    // Guest dump requested from a control tube, with the id of the tube.
+    let mut guest_dump: Option<(usize, arch::vmcore::GuestDumpThread)> = None;
 
     'wait: loop {
         let events = {
@@ -3056,6 +3207,22 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                             VmEventType::Panic(panic_code) => {
                                 pvpanic_code = PvPanicCode::from_u8(panic_code);
                                 info!("Guest reported panic [Code: {}]", pvpanic_code);
+                                if pvpanic_code == PvPanicCode::Panicked {
+                                    if let Some(dump_path) = &cfg.dump_guest_on_crash {
+                                        dump_guest_on_crash(
+                                            dump_path,
+                                            linux.vm.get_memory(),
+                                            |msg| {
+                                                vcpu::kick_all_vcpus(
+                                                    &vcpu_handles,
+                                                    linux.irq_chip.as_irq_chip(),
+                                                    msg,
+                                                )
+                                            },
+                                            vcpu_handles.len(),
+                                        );
+                                    }
+                                }
                                 break_to_wait = false;
                             }
                             VmEventType::WatchdogReset => {
@@ -3280,10 +3447,71 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 VmResponse::Err(base::Error::new(libc::ENOTSUP))
                                             }
                                         }
+                                        VmRequest::DumpGuest { dump_path } => {
+                                            if !dump_path.is_absolute() {
+                                                VmResponse::ErrString(format!(
+                                                    "guest dump path {} is not absolute",
+                                                    dump_path.display()
+                                                ))
+                                            } else if guest_dump.is_some() {
+                                                VmResponse::ErrString(
+                                                    "a guest dump is already in progress"
+                                                        .to_owned(),
+                                                )
+                                            } else {
+                                                match start_guest_dump(
+                                                    dump_path,
+                                                    linux.vm.get_memory(),
+                                                    |msg| {
+                                                        vcpu::kick_all_vcpus(
+                                                            &vcpu_handles,
+                                                            linux.irq_chip.as_irq_chip(),
+                                                            msg,
+                                                        )
+                                                    },
+                                                    vcpu_handles.len(),
+                                                    &wait_ctx,
+                                                    Token::GuestDump,
+                                                ) {
+                                                    Ok(dump) => {
+                                                        // The response is sent once the dump has
+                                                        // been written.
+                                                        guest_dump = Some((id, dump));
+                                                        continue;
+                                                    }
+                                                    Err(e) => {
+                                                        error!("failed to dump guest: {:#}", e);
+                                                        VmResponse::ErrString(format!("{:#}", e))
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        #[cfg(feature = "stats")]
+                                        VmRequest::Stats(cmd) => match cmd {
+                                            StatsCommand::Enable => {
+                                                stats.set_enabled(true);
+                                                VmResponse::Ok
+                                            }
+                                            StatsCommand::Disable => {
+                                                stats.set_enabled(false);
+                                                VmResponse::Ok
+                                            }
+                                            StatsCommand::Get => VmResponse::Stats(stats.json()),
+                                        },
+                                        #[cfg(feature = "balloon")]
+                                        VmRequest::BalloonPolicyCommand(cmd) => {
+                                            if let Some(policy) = balloon_policy.as_mut() {
+                                                policy.handle_command(cmd)
+                                            } else {
+                                                VmResponse::Err(base::Error::new(libc::ENOTSUP))
+                                            }
+                                        }
                                         _ => {
                                             let response = request.execute(
                                                 &mut run_mode_opt,
                                                 disk_host_tubes,
+                                                snd_host_tubes,
+                                                input_host_tubes,
                                                 &mut linux.pm,
                                                 #[cfg(feature = "gpu")]
                                                 Some(&gpu_control_tube),
@@ -3461,7 +3689,19 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                     match balloon_tube.as_mut().expect("missing balloon tube").recv() {
                         Ok(resp) => {
                             for (resp, idx) in resp {
-                                if let Some(TaggedControlTube::Vm(tube)) = control_tubes.get(&idx) {
+                                if idx == BALLOON_POLICY_KEY {
+                                    if let Some(adjust) = balloon_policy
+                                        .as_mut()
+                                        .and_then(|policy| policy.on_response(resp))
+                                    {
+                                        balloon_tube
+                                            .as_mut()
+                                            .expect("missing balloon tube")
+                                            .send_cmd(adjust, None);
+                                    }
+                                } else if let Some(TaggedControlTube::Vm(tube)) =
+                                    control_tubes.get(&idx)
+                                {
                                     if let Err(e) = tube.send(&resp) {
                                         error!("failed to send VmResponse: {}", e);
                                     }
@@ -3475,6 +3715,38 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                         }
                     }
                 }
+                Token::GuestDump => {
+                    let (id, dump) = guest_dump.take().expect("missing guest dump");
+                    if let Err(e) = wait_ctx.delete(dump.done_event()) {
+                        warn!("failed to remove guest dump event from wait context: {}", e);
+                    }
+                    let response = match dump.finish(|msg| {
+                        vcpu::kick_all_vcpus(&vcpu_handles, linux.irq_chip.as_irq_chip(), msg)
+                    }) {
+                        Ok(()) => VmResponse::Ok,
+                        Err(e) => {
+                            error!("failed to dump guest: {:#}", e);
+                            VmResponse::ErrString(format!("{:#}", e))
+                        }
+                    };
+                    if let Some(TaggedControlTube::Vm(tube)) = control_tubes.get(&id) {
+                        if let Err(e) = tube.send(&response) {
+                            error!("failed to send VmResponse: {}", e);
+                        }
+                    }
+                }
+                #[cfg(feature = "balloon")]
+                Token::BalloonPolicy => {
+                    let policy = balloon_policy.as_mut().expect("missing balloon policy");
+                    let tube = balloon_tube.as_mut().expect("missing balloon tube");
+                    for cmd in policy.on_timer() {
+                        if let Some((resp, _)) = tube.send_cmd(cmd, Some(BALLOON_POLICY_KEY)) {
+                            if let Some(adjust) = policy.on_response(resp) {
+                                tube.send_cmd(adjust, None);
+                            }
+                        }
+                    }
+                }
             }
         }
 
@@ -3574,6 +3846,12 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         Err(_) => panic!("internal error: io_bus had more than one reference at shutdown"),
     }
 
+    #[cfg(feature = "stats")]
+    if cfg.exit_stats {
+        println!("Statistics Collected:\n{}", stats);
+        println!("Statistics JSON:\n{}", stats.json());
+    }
+
     // Explicitly drop the VM structure here to allow the devices to clean up before the
     // control sockets are closed when this function exits.
     mem::drop(linux);
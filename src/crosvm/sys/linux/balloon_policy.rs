@@ -0,0 +1,170 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Drives the automatic balloon policy from the main control loop.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use base::warn;
+use base::Timer;
+use base::TimerTrait;
+use vm_control::balloon_policy::BalloonPolicy;
+use vm_control::balloon_policy::BalloonPolicyCommand;
+use vm_control::balloon_policy::BalloonPolicyConfig;
+use vm_control::balloon_policy::BalloonPolicyInput;
+use vm_control::balloon_policy::MemoryPressure;
+use vm_control::BalloonControlCommand;
+use vm_control::VmResponse;
+
+/// Key used to route balloon tube responses back to the policy instead of a control tube.
+pub const BALLOON_POLICY_KEY: usize = usize::MAX;
+
+/// Time after which the responses of an evaluation are considered lost, so that the next timer
+/// expiration starts a new evaluation.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Evaluation waiting for the responses of the balloon device.
+struct PendingEvaluation {
+    input: BalloonPolicyInput,
+    /// Number of responses still expected.
+    remaining: usize,
+    started: Instant,
+}
+
+/// Periodically gathers the policy inputs over the balloon tube and turns the policy decisions
+/// into balloon adjustments.
+pub struct BalloonPolicyRunner {
+    policy: BalloonPolicy,
+    timer: Timer,
+    ws_reporting: bool,
+    pending: Option<PendingEvaluation>,
+    psi_warned: bool,
+}
+
+impl BalloonPolicyRunner {
+    pub fn new(config: BalloonPolicyConfig, ws_reporting: bool) -> Result<Self> {
+        let mut timer = Timer::new().context("failed to create balloon policy timer")?;
+        let interval = config.interval();
+        timer
+            .reset(interval, Some(interval))
+            .context("failed to arm balloon policy timer")?;
+        Ok(BalloonPolicyRunner {
+            policy: BalloonPolicy::new(config),
+            timer,
+            ws_reporting,
+            pending: None,
+            psi_warned: false,
+        })
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    /// Handles a timer expiration. Returns the commands to send on the balloon tube with
+    /// `BALLOON_POLICY_KEY` to collect the inputs of the next evaluation.
+    pub fn on_timer(&mut self) -> Vec<BalloonControlCommand> {
+        if let Err(e) = self.timer.mark_waited() {
+            warn!("failed to mark balloon policy timer waited: {}", e);
+        }
+        // Skip this round if disabled or if the guest has not answered the previous one yet.
+        if !self.policy.enabled() {
+            return Vec::new();
+        }
+        if let Some(pending) = &self.pending {
+            if pending.started.elapsed() < RESPONSE_TIMEOUT {
+                return Vec::new();
+            }
+            warn!("balloon policy: no response from the balloon device, retrying");
+            self.pending = None;
+        }
+
+        let host_pressure = match MemoryPressure::read_host() {
+            Ok(pressure) => Some(pressure),
+            Err(e) => {
+                if !self.psi_warned {
+                    warn!(
+                        "balloon policy running without host memory pressure: {:#}",
+                        e
+                    );
+                    self.psi_warned = true;
+                }
+                None
+            }
+        };
+
+        let mut commands = vec![BalloonControlCommand::Stats];
+        if self.ws_reporting {
+            commands.push(BalloonControlCommand::WorkingSet);
+        }
+        self.pending = Some(PendingEvaluation {
+            input: BalloonPolicyInput {
+                host_pressure,
+                ..Default::default()
+            },
+            remaining: commands.len(),
+            started: Instant::now(),
+        });
+        commands
+    }
+
+    /// Handles a response to one of the commands returned by `on_timer`. Returns the balloon
+    /// adjustment to perform once all the inputs have been collected.
+    pub fn on_response(&mut self, response: VmResponse) -> Option<BalloonControlCommand> {
+        let mut pending = self.pending.take()?;
+        let input = &mut pending.input;
+        match response {
+            VmResponse::BalloonStats {
+                stats,
+                balloon_actual,
+            } => {
+                input.stats = stats;
+                input.balloon_actual = balloon_actual;
+            }
+            VmResponse::BalloonWS { ws, balloon_actual } => {
+                input.ws = Some(ws);
+                input.balloon_actual = balloon_actual;
+            }
+            r => {
+                warn!("balloon policy: unexpected balloon response: {}", r);
+                return None;
+            }
+        }
+        if pending.remaining > 1 {
+            pending.remaining -= 1;
+            self.pending = Some(pending);
+            return None;
+        }
+
+        // A disable request may have arrived while the inputs were collected.
+        if !self.policy.enabled() {
+            return None;
+        }
+        let decision = self.policy.evaluate(&pending.input);
+        decision
+            .target
+            .map(|num_bytes| BalloonControlCommand::Adjust {
+                num_bytes,
+                wait_for_success: false,
+            })
+    }
+
+    /// Handles a `BalloonPolicyCommand` received on a control tube.
+    pub fn handle_command(&mut self, command: BalloonPolicyCommand) -> VmResponse {
+        match command {
+            BalloonPolicyCommand::Status => VmResponse::BalloonPolicyStatus(self.policy.status()),
+            BalloonPolicyCommand::Enable => {
+                self.policy.set_enabled(true);
+                VmResponse::Ok
+            }
+            BalloonPolicyCommand::Disable => {
+                self.policy.set_enabled(false);
+                VmResponse::Ok
+            }
+        }
+    }
+}
@@ -101,7 +101,10 @@ pub struct DevicesCommand {
     ///        See help from `crosvm run` command.
     pub block: Vec<VhostUserParams<DiskOption>>,
 
-    #[argh(option, arg_name = "vhost=PATH,cid=CID[,device=VHOST_DEVICE]")]
+    #[argh(
+        option,
+        arg_name = "vhost=PATH,cid=CID[,device=VHOST_DEVICE][,uds-path=PATH]"
+    )]
     /// start a vsock device.
     /// Possible key values:
     ///     vhost=PATH - Path to a vhost-user endpoint to listen to.
@@ -109,6 +112,10 @@ pub struct DevicesCommand {
     ///     cid=CID - CID to use for the device.
     ///     device=VHOST_DEVICE - path to the vhost-vsock device to
     ///         use (Linux only). Defaults to /dev/vhost-vsock.
+    ///     uds-path=PATH - bridge the guest to unix sockets
+    ///         instead of using vhost-vsock (Linux only). Guest
+    ///         connections to port P go to PATH_P, and host
+    ///         programs reach the guest through PATH.
     pub vsock: Vec<VhostUserParams<VsockConfig>>,
 
     #[cfg(feature = "net")]
@@ -129,6 +129,14 @@ impl FromStr for SharedDir {
         //   and directory contents should be considered valid (default: 5)
         // * cache=CACHE - one of "never", "always", or "auto" (default: auto)
         // * writeback=BOOL - indicates whether writeback caching should be enabled (default: false)
+        // * lower=PATH - a read-only directory overlaid below the shared directory (default: none)
+        // * uid_map=MAP, gid_map=MAP - uid/gid maps applied by the device itself, in the format
+        //   "guest host count[,guest host count]". Quote the value if it has multiple ranges.
+        //   Unlike uidmap/gidmap, these don't require a user namespace. Only supported for
+        //   type=fs. (default: identity)
+        // * num_queues=N - the number of request queues advertised to the VM (default: 1)
+        // * num_threads=N - the number of threads executing the requests of each request queue
+        //   (default: 1)
         // * uid=UID - uid of the device process in the user namespace created by minijail.
         //   (default: 0)
         // * gid=GID - gid of the device process in the user namespace created by minijail.
@@ -194,6 +202,14 @@ impl FromStr for SharedDir {
                     .map_err(|e| anyhow!("failed to parse fs config '{:?}': {e}", type_opts))?;
             }
             SharedDirKind::P9 => {
+                // The 9p server passes the ids of the VM straight to the host when it creates
+                // files or changes their owners, so it cannot honor an id map.
+                if let Some(opt) = type_opts
+                    .iter()
+                    .find(|opt| opt.starts_with("uid_map=") || opt.starts_with("gid_map="))
+                {
+                    bail!("`{opt}` is only supported for type=fs");
+                }
                 shared_dir.p9_cfg = type_opts
                     .join(":")
                     .parse()
@@ -216,6 +232,7 @@ mod tests {
     use super::*;
     use crate::crosvm::config::from_key_values;
     use crate::crosvm::config::BindMount;
+    use crate::crosvm::config::CustomInputOption;
     use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_HEIGHT;
     use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_WIDTH;
 
@@ -468,6 +485,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn virtio_custom_input() {
+        let mut config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--custom-input",
+                "path=/dev/gamepad-test,config-path=/etc/gamepad.json",
+                "/dev/null",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        assert_eq!(
+            config.virtio_custom_input.pop().unwrap(),
+            CustomInputOption {
+                path: PathBuf::from("/dev/gamepad-test"),
+                config_path: PathBuf::from("/etc/gamepad.json"),
+            }
+        );
+    }
+
     #[test]
     fn vfio_pci_path() {
         let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
@@ -817,6 +857,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_shared_dir_lower() {
+        let shared_dir: SharedDir = "/:lower:type=fs:lower=/".parse().unwrap();
+        assert_eq!(shared_dir.fs_cfg.lower, Some(PathBuf::from("/")));
+
+        let shared_dir: SharedDir = "/:upper:type=fs".parse().unwrap();
+        assert_eq!(shared_dir.fs_cfg.lower, None);
+    }
+
+    #[test]
+    fn parse_shared_dir_id_map() {
+        let shared_dir: SharedDir =
+            "/:fs:type=fs:uid_map=\"0 1000 1,1 100000 10\":gid_map=0 1000 1"
+                .parse()
+                .unwrap();
+        assert_eq!(shared_dir.fs_cfg.uid_map.to_host(0), Some(1000));
+        assert_eq!(shared_dir.fs_cfg.uid_map.to_host(5), Some(100004));
+        assert_eq!(shared_dir.fs_cfg.gid_map.to_host(0), Some(1000));
+
+        assert!("/:fs:type=fs:uid_map=0 1000".parse::<SharedDir>().is_err());
+        assert!("/:p9:type=p9:gid_map=0 1000 1"
+            .parse::<SharedDir>()
+            .is_err());
+    }
+
     #[test]
     fn parse_cache_policy() {
         // The default policy is `auto`.
@@ -82,6 +82,7 @@ use sync::Mutex;
 use vm_control::api::VmMemoryClient;
 use vm_memory::GuestAddress;
 
+use crate::crosvm::config::CustomInputOption;
 use crate::crosvm::config::TouchDeviceOption;
 use crate::crosvm::config::VhostUserFsOption;
 use crate::crosvm::config::VhostUserOption;
@@ -443,11 +444,13 @@ pub fn create_virtio_snd_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     snd_params: SndParameters,
+    control_tube: Tube,
 ) -> DeviceResult {
     let backend = snd_params.backend;
     let dev = virtio::snd::common_backend::VirtioSnd::new(
         virtio::base_features(protection_type),
         snd_params,
+        Some(control_tube),
     )
     .context("failed to create cras sound device")?;
 
@@ -455,16 +458,28 @@ pub fn create_virtio_snd_device(
 
     let policy = match backend {
         Backend::NULL | Backend::FILE => "snd_null_device",
+        #[cfg(feature = "audio_alsa")]
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::ALSA) => "snd_alsa_device",
         #[cfg(feature = "audio_cras")]
         Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) => "snd_cras_device",
-        #[cfg(not(feature = "audio_cras"))]
+        #[cfg(feature = "audio_pulse")]
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSE) => "snd_pulse_device",
+        #[cfg(not(any(
+            feature = "audio_alsa",
+            feature = "audio_cras",
+            feature = "audio_pulse"
+        )))]
         _ => unreachable!(),
     };
 
     let jail = if let Some(jail_config) = jail_config {
         let mut config = SandboxConfig::new(jail_config, policy);
-        #[cfg(feature = "audio_cras")]
-        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) {
+        #[cfg(any(
+            feature = "audio_alsa",
+            feature = "audio_cras",
+            feature = "audio_pulse"
+        ))]
+        if matches!(backend, Backend::Sys(_)) {
             config.bind_mounts = true;
         }
         // TODO(b/267574679): running as current_user may not be required for snd device.
@@ -477,6 +492,31 @@ pub fn create_virtio_snd_device(
             let run_cras_path = Path::new("/run/cras");
             jail.mount_bind(run_cras_path, run_cras_path, true)?;
         }
+        #[cfg(feature = "audio_alsa")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::ALSA) {
+            let dev_snd_path = Path::new("/dev/snd");
+            jail.mount_bind(dev_snd_path, dev_snd_path, true)?;
+            // Configuration and plugins of alsa-lib.
+            jail_mount_bind_if_exists(
+                &mut jail,
+                &[
+                    "/etc/alsa",
+                    "/etc/asound.conf",
+                    "/usr/share/alsa",
+                    "/usr/lib64",
+                    "/usr/lib",
+                ],
+            )?;
+        }
+        #[cfg(feature = "audio_pulse")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSE) {
+            // The server socket of PulseAudio, or of pipewire-pulse.
+            if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+                let pulse_path = Path::new(&runtime_dir).join("pulse");
+                jail_mount_bind_if_exists(&mut jail, &[pulse_path])?;
+            }
+            jail_mount_bind_if_exists(&mut jail, &["/etc/pulse"])?;
+        }
         Some(jail)
     } else {
         None
@@ -519,6 +559,7 @@ pub fn create_single_touch_device(
     jail_config: &Option<JailConfig>,
     single_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = single_touch_spec
         .get_path()
@@ -527,7 +568,7 @@ pub fn create_single_touch_device(
 
     let (width, height) = single_touch_spec.get_size();
     let name = single_touch_spec.get_name();
-    let dev = virtio::input::new_single_touch(
+    let mut dev = virtio::input::new_single_touch(
         idx,
         socket,
         width,
@@ -536,6 +577,8 @@ pub fn create_single_touch_device(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
+
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
         jail: simple_jail(jail_config, "input_device")?,
@@ -547,6 +590,7 @@ pub fn create_multi_touch_device(
     jail_config: &Option<JailConfig>,
     multi_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = multi_touch_spec
         .get_path()
@@ -555,7 +599,7 @@ pub fn create_multi_touch_device(
 
     let (width, height) = multi_touch_spec.get_size();
     let name = multi_touch_spec.get_name();
-    let dev = virtio::input::new_multi_touch(
+    let mut dev = virtio::input::new_multi_touch(
         idx,
         socket,
         width,
@@ -564,6 +608,7 @@ pub fn create_multi_touch_device(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -576,6 +621,7 @@ pub fn create_trackpad_device(
     jail_config: &Option<JailConfig>,
     trackpad_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = trackpad_spec
         .get_path()
@@ -584,7 +630,7 @@ pub fn create_trackpad_device(
 
     let (width, height) = trackpad_spec.get_size();
     let name = trackpad_spec.get_name();
-    let dev = virtio::input::new_trackpad(
+    let mut dev = virtio::input::new_trackpad(
         idx,
         socket,
         width,
@@ -593,6 +639,7 @@ pub fn create_trackpad_device(
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -605,13 +652,15 @@ pub fn create_mouse_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     mouse_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = mouse_socket
         .into_unix_stream()
         .context("failed configuring virtio mouse")?;
 
-    let dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -624,13 +673,15 @@ pub fn create_keyboard_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     keyboard_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = keyboard_socket
         .into_unix_stream()
         .context("failed configuring virtio keyboard")?;
 
-    let dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -643,13 +694,15 @@ pub fn create_switches_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     switches_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = switches_socket
         .into_unix_stream()
         .context("failed configuring virtio switches")?;
 
-    let dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -662,13 +715,48 @@ pub fn create_rotary_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     rotary_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = rotary_socket
         .into_unix_stream()
         .context("failed configuring virtio rotary")?;
 
-    let dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "input_device")?,
+    })
+}
+
+pub fn create_custom_input_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    custom_input: &CustomInputOption,
+    idx: u32,
+    control_tube: Tube,
+) -> DeviceResult {
+    let socket = custom_input
+        .path
+        .as_path()
+        .into_unix_stream()
+        .context("failed configuring custom virtio input device")?;
+
+    let mut dev = virtio::input::new_custom(
+        idx,
+        socket,
+        &custom_input.config_path,
+        virtio::base_features(protection_type),
+    )
+    .with_context(|| {
+        format!(
+            "failed to set up input device from {}",
+            custom_input.config_path.display()
+        )
+    })?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -680,6 +768,7 @@ pub fn create_vinput_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     dev_path: &Path,
+    control_tube: Tube,
 ) -> DeviceResult {
     let dev_file = OpenOptions::new()
         .read(true)
@@ -687,8 +776,9 @@ pub fn create_vinput_device(
         .open(dev_path)
         .with_context(|| format!("failed to open vinput device {}", dev_path.display()))?;
 
-    let dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
+    let mut dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
         .context("failed to set up input device")?;
+    dev.set_control_tube(control_tube);
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -960,6 +1050,8 @@ pub fn create_video_device(
             VideoBackendType::Vaapi => true,
             #[cfg(feature = "ffmpeg")]
             VideoBackendType::Ffmpeg => false,
+            #[cfg(feature = "swcodec")]
+            VideoBackendType::Swcodec => false,
         };
 
         if need_drm_device {
@@ -1048,6 +1140,12 @@ impl VirtioDeviceBuilder for &VsockConfig {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let features = virtio::base_features(protection_type);
 
+        if let Some(uds_path) = &self.uds_path {
+            let dev = virtio::vsock::UnixVsock::new(self.cid, uds_path, features)
+                .context("failed to set up userspace virtual socket device")?;
+            return Ok(Box::new(dev));
+        }
+
         let dev = virtio::vhost::Vsock::new(features, self)
             .context("failed to set up virtual socket device")?;
 
@@ -1058,12 +1156,52 @@ impl VirtioDeviceBuilder for &VsockConfig {
         self,
         keep_rds: &mut Vec<RawDescriptor>,
     ) -> anyhow::Result<Box<dyn VhostUserDevice>> {
+        if let Some(uds_path) = &self.uds_path {
+            let features = virtio::base_features(ProtectionType::Unprotected);
+            let vsock_device = virtio::vsock::UnixVsock::new(self.cid, uds_path, features)?;
+
+            keep_rds.extend(vsock_device.keep_rds());
+
+            return Ok(Box::new(vsock_device));
+        }
+
         let vsock_device = VhostUserVsockDevice::new(self.cid, &self.vhost_device)?;
 
         keep_rds.push(vsock_device.as_raw_descriptor());
 
         Ok(Box::new(vsock_device))
     }
+
+    fn create_jail(
+        &self,
+        jail_config: &Option<JailConfig>,
+        virtio_transport: VirtioDeviceType,
+    ) -> anyhow::Result<Option<Minijail>> {
+        let uds_path = match &self.uds_path {
+            Some(uds_path) => uds_path,
+            None => {
+                return simple_jail(
+                    jail_config,
+                    &virtio_transport.seccomp_policy_file(Self::NAME),
+                )
+            }
+        };
+
+        let jail_config = match jail_config {
+            Some(jail_config) => jail_config,
+            None => return Ok(None),
+        };
+        let policy = virtio_transport.seccomp_policy_file("vsock");
+        let mut config = SandboxConfig::new(jail_config, &policy);
+        config.bind_mounts = true;
+        let mut jail =
+            create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+        // The device connects to the sockets of host programs next to its own socket.
+        if let Some(socket_dir) = uds_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            jail.mount_bind(socket_dir, socket_dir, true)?;
+        }
+        Ok(Some(jail))
+    }
 }
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -1114,9 +1252,9 @@ pub fn create_fs_device(
     };
 
     let features = virtio::base_features(protection_type);
-    // TODO(chirantan): Use more than one worker once the kernel driver has been fixed to not panic
-    // when num_queues > 1.
-    let dev = virtio::fs::Fs::new(features, tag, 1, fs_cfg, device_tube)
+    // Older guest kernels panic when more than one request queue is advertised, so this is opt-in.
+    let num_queues = fs_cfg.num_queues;
+    let dev = virtio::fs::Fs::new(features, tag, num_queues, fs_cfg, device_tube)
         .context("failed to create fs device")?;
 
     Ok(VirtioDeviceStub {
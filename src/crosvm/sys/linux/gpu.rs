@@ -8,7 +8,9 @@ static_assertions::assert_cfg!(feature = "gpu");
 
 use std::collections::HashMap;
 use std::env;
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use base::platform::move_proc_to_cgroup;
 use jail::*;
@@ -112,6 +114,14 @@ pub fn create_gpu_device(
         );
     }
 
+    if let Some(address) = cfg.vnc_display {
+        // Bind now, as the sandboxed device has no access to the host network.
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("failed to listen for VNC clients on {}", address))?;
+        info!("serving the display to VNC clients on {}", address);
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(Arc::new(listener)));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
@@ -0,0 +1,635 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serves the metrics of a running VM in the OpenMetrics text format, on a unix socket or a TCP
+//! port of the loopback interface.
+//!
+//! Each scrape sends control requests to the main loop over a `Tube`, the same way `crosvm
+//! balloon_stats` or `crosvm swap status` would, and converts the responses into metric families.
+//!
+//! Network devices have no control channel to the main loop, so there are no network metrics. The
+//! queue notifications of every virtio device are part of the bus accesses exported with the
+//! `stats` feature.
+
+use std::cell::Cell;
+use std::fs::remove_file;
+use std::fs::symlink_metadata;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::TcpListener;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use base::warn;
+use base::Tube;
+use base::UnlinkUnixListener;
+use metrics::openmetrics::serve_request;
+use metrics::openmetrics::MetricFamily;
+use metrics::openmetrics::MetricType;
+use swap::SwapStatus;
+#[cfg(feature = "balloon")]
+use vm_control::BalloonControlCommand;
+#[cfg(feature = "balloon")]
+use vm_control::BalloonStats;
+use vm_control::DiskControlCommand;
+use vm_control::DiskStats;
+#[cfg(feature = "stats")]
+use vm_control::StatsCommand;
+use vm_control::SwapCommand;
+use vm_control::VmRequest;
+use vm_control::VmResponse;
+
+use crate::crosvm::config::MetricsExporterOption;
+
+// How long a scraper may take to send its request or to read the page.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for the response to each control request, in particular balloon stats which
+// depend on the guest.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// Binds the socket described by `option` and spawns a thread answering scrapes on it.
+///
+/// `vm_tube` must be connected to the control loop of the VM, which answers the `VmRequest`s sent
+/// for each scrape. `num_disks` is the number of disks whose stats are exported.
+///
+/// For a unix socket, returns a listener that removes the socket file when dropped, which the
+/// caller keeps for the lifetime of the VM.
+pub fn start_metrics_exporter(
+    option: &MetricsExporterOption,
+    vm_tube: Tube,
+    num_disks: usize,
+) -> Result<Option<UnlinkUnixListener>> {
+    let (listener, unlink) = match (&option.path, option.port) {
+        (Some(path), None) => {
+            let listener = bind_unix(path).with_context(|| {
+                format!("failed to bind metrics exporter socket {}", path.display())
+            })?;
+            let unlink = UnlinkUnixListener(
+                listener
+                    .try_clone()
+                    .context("failed to clone metrics exporter socket")?,
+            );
+            (Listener::Unix(listener), Some(unlink))
+        }
+        (None, Some(port)) => (
+            Listener::Tcp(
+                TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                    .with_context(|| format!("failed to bind metrics exporter port {}", port))?,
+            ),
+            None,
+        ),
+        _ => anyhow::bail!("metrics exporter requires exactly one of path or port"),
+    };
+    vm_tube
+        .set_recv_timeout(Some(RESPONSE_TIMEOUT))
+        .context("failed to set metrics exporter tube timeout")?;
+
+    thread::Builder::new()
+        .name("metrics_exporter".to_owned())
+        .spawn(move || exporter_thread(listener, VmClient::new(vm_tube), num_disks))
+        .context("failed to spawn metrics exporter thread")?;
+    Ok(unlink)
+}
+
+/// Binds a unix socket at `path`, replacing the socket file left behind by a previous run.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => remove_file(path)?,
+        Ok(_) => return Err(io::Error::from_raw_os_error(libc::EEXIST)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+fn exporter_thread(listener: Listener, vm_client: VmClient, num_disks: usize) {
+    let scrape = || collect(&vm_client, num_disks);
+    loop {
+        // Scrapes are served one at a time, so a client that stalls must not block the others.
+        let result = match &listener {
+            Listener::Unix(l) => l.accept().and_then(|(stream, _)| {
+                stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                serve_request(stream, scrape)
+            }),
+            Listener::Tcp(l) => l.accept().and_then(|(stream, _)| {
+                stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+                serve_request(stream, scrape)
+            }),
+        };
+        if let Err(e) = result {
+            warn!("failed to serve metrics: {}", e);
+        }
+    }
+}
+
+/// Sends control requests to the main loop and matches their responses, which carry no tag.
+///
+/// The control loop answers requests in order, except balloon stats which are answered whenever
+/// the guest reports them. The responses of requests that timed out are counted so that they are
+/// discarded when they arrive late, instead of being taken for the response of a later request.
+struct VmClient {
+    tube: Tube,
+    // Late responses still expected, other than balloon stats.
+    stale_responses: Cell<usize>,
+    // Late balloon stats still expected.
+    stale_balloon_stats: Cell<usize>,
+}
+
+impl VmClient {
+    fn new(tube: Tube) -> Self {
+        VmClient {
+            tube,
+            stale_responses: Cell::new(0),
+            stale_balloon_stats: Cell::new(0),
+        }
+    }
+
+    /// Sends `request` to the control loop and returns its response, or `None` if it failed or
+    /// timed out.
+    fn request(&self, request: VmRequest) -> Option<VmResponse> {
+        if let Err(e) = self.tube.send(&request) {
+            warn!("failed to send {:?} for metrics: {}", request, e);
+            return None;
+        }
+        let is_balloon_stats = is_balloon_stats_request(&request);
+        loop {
+            let response = match self.tube.recv() {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(
+                        "failed to receive response to {:?} for metrics: {}",
+                        request, e
+                    );
+                    let stale = self.stale_count(is_balloon_stats);
+                    stale.set(stale.get() + 1);
+                    return None;
+                }
+            };
+            let response_is_balloon_stats = is_balloon_stats_response(&response);
+            let stale = self.stale_count(response_is_balloon_stats);
+            if stale.get() > 0 {
+                // The late response to a request of an earlier scrape that timed out.
+                stale.set(stale.get() - 1);
+            } else if response_is_balloon_stats == is_balloon_stats {
+                return Some(response);
+            } else {
+                warn!("unexpected {} while waiting for {:?}", response, request);
+            }
+        }
+    }
+
+    fn stale_count(&self, balloon_stats: bool) -> &Cell<usize> {
+        if balloon_stats {
+            &self.stale_balloon_stats
+        } else {
+            &self.stale_responses
+        }
+    }
+}
+
+#[cfg(feature = "balloon")]
+fn is_balloon_stats_request(request: &VmRequest) -> bool {
+    matches!(
+        request,
+        VmRequest::BalloonCommand(BalloonControlCommand::Stats {})
+    )
+}
+
+#[cfg(not(feature = "balloon"))]
+fn is_balloon_stats_request(_request: &VmRequest) -> bool {
+    false
+}
+
+#[cfg(feature = "balloon")]
+fn is_balloon_stats_response(response: &VmResponse) -> bool {
+    matches!(response, VmResponse::BalloonStats { .. })
+}
+
+#[cfg(not(feature = "balloon"))]
+fn is_balloon_stats_response(_response: &VmResponse) -> bool {
+    false
+}
+
+/// Collects the metrics of the VM. Metrics of features that are disabled, or of requests that
+/// fail or time out, are omitted.
+fn collect(vm_client: &VmClient, num_disks: usize) -> Vec<MetricFamily> {
+    let mut families = Vec::new();
+
+    #[cfg(feature = "balloon")]
+    if let Some(VmResponse::BalloonStats {
+        stats,
+        balloon_actual,
+    }) = vm_client.request(VmRequest::BalloonCommand(BalloonControlCommand::Stats {}))
+    {
+        families.extend(balloon_families(&stats, balloon_actual));
+    }
+
+    if let Some(VmResponse::SwapStatus(status)) =
+        vm_client.request(VmRequest::Swap(SwapCommand::Status))
+    {
+        families.extend(swap_families(&status));
+    }
+
+    let disk_stats: Vec<(usize, DiskStats)> = (0..num_disks)
+        .filter_map(|disk_index| {
+            match vm_client.request(VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::Stats,
+            }) {
+                Some(VmResponse::DiskStats(stats)) => Some((disk_index, stats)),
+                _ => None,
+            }
+        })
+        .collect();
+    if !disk_stats.is_empty() {
+        families.extend(disk_families(&disk_stats));
+    }
+
+    #[cfg(feature = "stats")]
+    if let Some(VmResponse::Stats(stats)) = vm_client.request(VmRequest::Stats(StatsCommand::Get)) {
+        families.extend(stats_families(&stats));
+    }
+
+    families
+}
+
+/// Converts the request counters of the disks, labelled with their index in the `--block` options.
+fn disk_families(disks: &[(usize, DiskStats)]) -> Vec<MetricFamily> {
+    let mut requests = MetricFamily::new(
+        "crosvm_disk_requests",
+        MetricType::Counter,
+        "Requests completed by virtio-block devices, by disk and operation.",
+    );
+    let mut bytes = MetricFamily::new(
+        "crosvm_disk_bytes",
+        MetricType::Counter,
+        "Bytes transferred by virtio-block devices, by disk and operation.",
+    );
+    let mut errors = MetricFamily::new(
+        "crosvm_disk_errors",
+        MetricType::Counter,
+        "Requests of virtio-block devices that failed, by disk.",
+    );
+    for (index, stats) in disks {
+        let disk = index.to_string();
+        for (operation, count) in [
+            ("read", stats.reads),
+            ("write", stats.writes),
+            ("flush", stats.flushes),
+            ("discard", stats.discards),
+        ] {
+            requests.add_sample(
+                &[("disk", disk.as_str()), ("operation", operation)],
+                count as f64,
+            );
+        }
+        for (operation, count) in [("read", stats.read_bytes), ("write", stats.write_bytes)] {
+            bytes.add_sample(
+                &[("disk", disk.as_str()), ("operation", operation)],
+                count as f64,
+            );
+        }
+        errors.add_sample(&[("disk", disk.as_str())], stats.errors as f64);
+    }
+    vec![requests, bytes, errors]
+}
+
+#[cfg(feature = "balloon")]
+fn balloon_families(stats: &BalloonStats, balloon_actual: u64) -> Vec<MetricFamily> {
+    let mut families = vec![MetricFamily::new(
+        "crosvm_balloon_actual_bytes",
+        MetricType::Gauge,
+        "Size of the balloon.",
+    )
+    .with_sample(&[], balloon_actual as f64)];
+
+    // The guest only reports the statistics it supports.
+    let counters = [
+        (
+            "swap_in_pages",
+            stats.swap_in,
+            "Pages swapped in by the guest.",
+        ),
+        (
+            "swap_out_pages",
+            stats.swap_out,
+            "Pages swapped out by the guest.",
+        ),
+        (
+            "major_faults",
+            stats.major_faults,
+            "Major page faults in the guest.",
+        ),
+        (
+            "minor_faults",
+            stats.minor_faults,
+            "Minor page faults in the guest.",
+        ),
+        (
+            "hugetlb_allocations",
+            stats.hugetlb_allocations,
+            "Huge page allocations in the guest.",
+        ),
+        (
+            "hugetlb_failures",
+            stats.hugetlb_failures,
+            "Failed huge page allocations in the guest.",
+        ),
+    ];
+    let gauges = [
+        (
+            "free_memory_bytes",
+            stats.free_memory,
+            "Free memory of the guest.",
+        ),
+        (
+            "total_memory_bytes",
+            stats.total_memory,
+            "Total memory of the guest.",
+        ),
+        (
+            "available_memory_bytes",
+            stats.available_memory,
+            "Available memory of the guest.",
+        ),
+        (
+            "disk_caches_bytes",
+            stats.disk_caches,
+            "Disk caches of the guest.",
+        ),
+        (
+            "shared_memory_bytes",
+            stats.shared_memory,
+            "Shared memory of the guest.",
+        ),
+        (
+            "unevictable_memory_bytes",
+            stats.unevictable_memory,
+            "Unevictable memory of the guest.",
+        ),
+    ];
+    for (metric_type, values) in [(MetricType::Counter, counters), (MetricType::Gauge, gauges)] {
+        for (name, value, help) in values {
+            if let Some(value) = value {
+                families.push(
+                    MetricFamily::new(&format!("crosvm_balloon_guest_{}", name), metric_type, help)
+                        .with_sample(&[], value as f64),
+                );
+            }
+        }
+    }
+    families
+}
+
+fn swap_families(status: &SwapStatus) -> Vec<MetricFamily> {
+    let metrics = &status.metrics;
+    let mut pages = MetricFamily::new(
+        "crosvm_swap_pages",
+        MetricType::Gauge,
+        "Pages of guest memory handled by vmm-swap, by location.",
+    );
+    for (location, value) in [
+        ("resident", metrics.resident_pages),
+        ("staging", metrics.staging_pages),
+        ("swap_file", metrics.swap_pages),
+    ] {
+        pages.add_sample(&[("location", location)], value as f64);
+    }
+
+    let mut faults = MetricFamily::new(
+        "crosvm_swap_page_faults",
+        MetricType::Counter,
+        "Page faults handled by vmm-swap, by how the page was filled.",
+    );
+    for (source, value) in [
+        ("file", metrics.copied_from_file_pages),
+        ("staging", metrics.copied_from_staging_pages),
+        ("zero", metrics.zeroed_pages),
+        ("redundant", metrics.redundant_pages),
+    ] {
+        faults.add_sample(&[("source", source)], value as f64);
+    }
+
+    let state = format!("{:?}", status.state);
+    vec![
+        MetricFamily::new(
+            "crosvm_swap_state",
+            MetricType::Gauge,
+            "Current vmm-swap state, 1 for the active state.",
+        )
+        .with_sample(&[("state", state.as_str())], 1.0),
+        pages,
+        faults,
+        MetricFamily::new(
+            "crosvm_swap_state_transition_pages",
+            MetricType::Gauge,
+            "Pages moved by the last vmm-swap state transition.",
+        )
+        .with_sample(&[], status.state_transition.pages as f64),
+        MetricFamily::new(
+            "crosvm_swap_state_transition_seconds",
+            MetricType::Gauge,
+            "Duration of the last vmm-swap state transition.",
+        )
+        .with_sample(&[], status.state_transition.time_ms as f64 / 1000.0),
+    ]
+}
+
+/// Converts a duration of the statistics JSON into seconds.
+#[cfg(feature = "stats")]
+fn duration_secs(duration: &serde_json::Value) -> f64 {
+    duration["seconds"].as_f64().unwrap_or_default()
+        + duration["subsecond_nanos"].as_f64().unwrap_or_default() / 1e9
+}
+
+/// Converts the VM exit and bus access statistics, as returned by `StatisticsCollector::json`.
+#[cfg(feature = "stats")]
+fn stats_families(stats: &serde_json::Value) -> Vec<MetricFamily> {
+    let mut exits = MetricFamily::new(
+        "crosvm_vcpu_exits",
+        MetricType::Counter,
+        "VM exits, by vCPU and exit type.",
+    );
+    let mut exit_durations = MetricFamily::new(
+        "crosvm_vcpu_exit_seconds",
+        MetricType::Counter,
+        "Time spent handling VM exits, by vCPU and exit type.",
+    );
+    for (vcpu, vcpu_stats) in stats["vcpus"].as_array().into_iter().flatten().enumerate() {
+        let vcpu = vcpu.to_string();
+        for exit in vcpu_stats["exits"].as_array().into_iter().flatten() {
+            let count = exit["count"].as_f64().unwrap_or_default();
+            // Skip the exit types that never happened to keep the page small.
+            if count == 0.0 {
+                continue;
+            }
+            let labels = [
+                ("vcpu", vcpu.as_str()),
+                ("exit_type", exit["exit_type"].as_str().unwrap_or_default()),
+            ];
+            exits.add_sample(&labels, count);
+            exit_durations.add_sample(&labels, duration_secs(&exit["duration"]));
+        }
+    }
+
+    let mut accesses = MetricFamily::new(
+        "crosvm_bus_accesses",
+        MetricType::Counter,
+        "Device accesses through the io and mmio buses.",
+    );
+    let mut access_durations = MetricFamily::new(
+        "crosvm_bus_access_seconds",
+        MetricType::Counter,
+        "Time spent handling device accesses through the io and mmio buses.",
+    );
+    for bus in ["io", "mmio"] {
+        for device in stats["merged"][bus].as_array().into_iter().flatten() {
+            let name = device["info"]["name"].as_str().unwrap_or_default();
+            let id = device["info"]["id"].to_string();
+            for (operation, count, duration) in [
+                ("read", "reads", "read_duration"),
+                ("write", "writes", "write_duration"),
+            ] {
+                let labels = [
+                    ("bus", bus),
+                    ("device", name),
+                    ("id", id.as_str()),
+                    ("operation", operation),
+                ];
+                accesses.add_sample(&labels, device["stats"][count].as_f64().unwrap_or_default());
+                access_durations.add_sample(&labels, duration_secs(&device["stats"][duration]));
+            }
+        }
+    }
+
+    vec![exits, exit_durations, accesses, access_durations]
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::openmetrics::encode;
+    use swap::SwapMetrics;
+    use swap::SwapState;
+    use swap::SwapStateTransition;
+
+    use super::*;
+
+    #[test]
+    fn swap_metrics() {
+        let status = SwapStatus {
+            state: SwapState::Active,
+            metrics: SwapMetrics {
+                resident_pages: 1,
+                copied_from_file_pages: 2,
+                copied_from_staging_pages: 3,
+                zeroed_pages: 4,
+                redundant_pages: 5,
+                staging_pages: 6,
+                swap_pages: 7,
+            },
+            state_transition: SwapStateTransition {
+                pages: 8,
+                time_ms: 1500,
+            },
+        };
+        let page = encode(&swap_families(&status));
+
+        assert!(page.contains("crosvm_swap_state{state=\"Active\"} 1\n"));
+        assert!(page.contains("crosvm_swap_pages{location=\"swap_file\"} 7\n"));
+        assert!(page.contains("crosvm_swap_page_faults_total{source=\"zero\"} 4\n"));
+        assert!(page.contains("crosvm_swap_state_transition_seconds 1.5\n"));
+    }
+
+    #[test]
+    fn late_response_discarded() {
+        let (client_tube, server_tube) = Tube::pair().unwrap();
+        client_tube
+            .set_recv_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let vm_client = VmClient::new(client_tube);
+
+        // The first request times out, and its response arrives during the second one.
+        assert!(vm_client
+            .request(VmRequest::Swap(SwapCommand::Status))
+            .is_none());
+        server_tube.recv::<VmRequest>().unwrap();
+        server_tube.send(&VmResponse::Ok).unwrap();
+        server_tube
+            .send(&VmResponse::ErrString("second".to_owned()))
+            .unwrap();
+
+        let response = vm_client.request(VmRequest::Swap(SwapCommand::Status));
+        assert!(matches!(response, Some(VmResponse::ErrString(s)) if s == "second"));
+        server_tube.recv::<VmRequest>().unwrap();
+    }
+
+    #[test]
+    fn disk_metrics() {
+        let stats = DiskStats {
+            reads: 1,
+            read_bytes: 512,
+            writes: 2,
+            write_bytes: 4096,
+            flushes: 3,
+            discards: 4,
+            errors: 5,
+        };
+        let page = encode(&disk_families(&[(1, stats)]));
+
+        assert!(page.contains("crosvm_disk_requests_total{disk=\"1\",operation=\"flush\"} 3\n"));
+        assert!(page.contains("crosvm_disk_bytes_total{disk=\"1\",operation=\"write\"} 4096\n"));
+        assert!(page.contains("crosvm_disk_errors_total{disk=\"1\"} 5\n"));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_metrics() {
+        let stats = serde_json::json!({
+            "merged": {
+                "io": [{
+                    "info": {"name": "serial", "id": 3, "base": 0x3f8, "len": 8},
+                    "stats": {
+                        "reads": 10,
+                        "read_duration": {"seconds": 0, "subsecond_nanos": 500_000_000},
+                        "writes": 0,
+                        "write_duration": {"seconds": 0, "subsecond_nanos": 0},
+                    },
+                }],
+                "mmio": [],
+                "exits": [],
+            },
+            "vcpus": [{
+                "io": [],
+                "mmio": [],
+                "exits": [
+                    {"exit_type": "Io", "count": 12, "duration": {"seconds": 2, "subsecond_nanos": 0}},
+                    {"exit_type": "Mmio", "count": 0, "duration": {"seconds": 0, "subsecond_nanos": 0}},
+                ],
+            }],
+        });
+        let page = encode(&stats_families(&stats));
+
+        assert!(page.contains("crosvm_vcpu_exits_total{vcpu=\"0\",exit_type=\"Io\"} 12\n"));
+        assert!(page.contains("crosvm_vcpu_exit_seconds_total{vcpu=\"0\",exit_type=\"Io\"} 2\n"));
+        assert!(!page.contains("exit_type=\"Mmio\""));
+        assert!(page.contains(
+            "crosvm_bus_accesses_total{bus=\"io\",device=\"serial\",id=\"3\",operation=\"read\"} 10\n"
+        ));
+        assert!(page.contains(
+            "crosvm_bus_access_seconds_total{bus=\"io\",device=\"serial\",id=\"3\",operation=\"read\"} 0.5\n"
+        ));
+    }
+}
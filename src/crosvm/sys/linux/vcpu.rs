@@ -29,6 +29,7 @@ use base::signal::BlockedSignal;
 use base::*;
 use devices::Bus;
 use devices::IrqChip;
+use devices::PvPanicCode;
 use devices::VcpuRunState;
 use hypervisor::IoOperation;
 use hypervisor::IoParams;
@@ -37,7 +38,7 @@ use hypervisor::VcpuSignalHandle;
 use libc::c_int;
 #[cfg(target_arch = "riscv64")]
 use riscv64::Riscv64 as Arch;
-#[cfg(all(target_arch = "x86_64", unix))]
+#[cfg(any(feature = "stats", all(target_arch = "x86_64", unix)))]
 use sync::Mutex;
 use vm_control::*;
 #[cfg(feature = "gdb")]
@@ -48,6 +49,8 @@ use x86_64::X8664arch as Arch;
 use super::ExitState;
 #[cfg(all(target_arch = "x86_64", unix))]
 use crate::crosvm::ratelimit::Ratelimit;
+#[cfg(feature = "stats")]
+use crate::crosvm::stats::VmExitStatistics;
 
 fn bus_io_handler(bus: &Bus) -> impl FnMut(IoParams) -> Option<[u8; 8]> + '_ {
     |IoParams {
@@ -222,14 +225,19 @@ fn vcpu_loop<V>(
     io_bus: Bus,
     mmio_bus: Bus,
     from_main_tube: mpsc::Receiver<VcpuControl>,
+    vm_evt_wrtube: &SendTube,
     #[cfg(feature = "gdb")] to_gdb_tube: Option<mpsc::Sender<VcpuDebugStatusMessage>>,
     #[cfg(feature = "gdb")] guest_mem: GuestMemory,
     #[cfg(all(target_arch = "x86_64", unix))] bus_lock_ratelimit_ctrl: Arc<Mutex<Ratelimit>>,
+    dump_on_crash: bool,
+    #[cfg(feature = "stats")] exit_stats: Arc<Mutex<VmExitStatistics>>,
 ) -> ExitState
 where
     V: VcpuArch,
 {
     let mut interrupted_by_signal = false;
+    // Set when the guest crashed and the vCPU is kept around until the guest memory dump is done.
+    let mut crashed = false;
 
     loop {
         // Start by checking for messages to process and the run state of the CPU.
@@ -268,6 +276,9 @@ where
                 for msg in messages {
                     match msg {
                         VcpuControl::RunState(new_mode) => {
+                            if crashed && new_mode != VmRunMode::Suspending {
+                                return ExitState::Stop;
+                            }
                             run_mode = new_mode;
                             match run_mode {
                                 VmRunMode::Running => {}
@@ -329,6 +340,12 @@ where
                                 error!("Failed to send restore response: {}", e);
                             }
                         }
+                        VcpuControl::CoreDumpRegisters(response_chan) => {
+                            let resp = arch::vmcore::vcpu_prstatus(&vcpu, cpu_id);
+                            if let Err(e) = response_chan.send((cpu_id, resp)) {
+                                error!("Failed to send core dump registers: {}", e);
+                            }
+                        }
                     }
                 }
                 if run_mode == VmRunMode::Running {
@@ -355,7 +372,12 @@ where
         }
 
         if !interrupted_by_signal {
-            match vcpu.run() {
+            let exit = vcpu.run();
+
+            #[cfg(feature = "stats")]
+            let start = exit_stats.lock().start_stat();
+
+            match exit {
                 Ok(VcpuExit::Io) => {
                     if let Err(e) = vcpu.handle_io(&mut bus_io_handler(&io_bus)) {
                         error!("failed to handle io: {}", e)
@@ -393,12 +415,23 @@ where
                 }
                 Ok(VcpuExit::SystemEventCrash) => {
                     info!("system crash event on vcpu {}", cpu_id);
-                    return ExitState::Stop;
+                    if !dump_on_crash {
+                        return ExitState::Stop;
+                    }
+                    // Report the crash like a pvpanic so the guest gets dumped, and park the vCPU
+                    // so its registers can be included. It exits once it is resumed.
+                    let panic_event = VmEventType::Panic(PvPanicCode::Panicked as u8);
+                    if let Err(e) = vm_evt_wrtube.send::<VmEventType>(&panic_event) {
+                        error!("failed to send crash event on vcpu {}: {}", cpu_id, e);
+                        return ExitState::Stop;
+                    }
+                    crashed = true;
+                    run_mode = VmRunMode::Suspending;
                 }
                 Ok(VcpuExit::Debug) => {
                     #[cfg(feature = "gdb")]
                     if let Err(e) =
-                        crate::crosvm::gdb::vcpu_exit_debug(cpu_id, to_gdb_tube.as_ref())
+                        crate::crosvm::gdb::vcpu_exit_debug(cpu_id, &vcpu, to_gdb_tube.as_ref())
                     {
                         error!("Failed to handle VcpuExit::Debug: {:#}", e);
                         return ExitState::Crash;
@@ -442,6 +475,9 @@ where
                     }
                 },
             }
+
+            #[cfg(feature = "stats")]
+            exit_stats.lock().end_stat(&exit, start);
         }
 
         if interrupted_by_signal {
@@ -477,6 +513,8 @@ pub fn run_vcpu<V>(
     vcpu_cgroup_tasks_file: Option<File>,
     #[cfg(all(target_arch = "x86_64", unix))] bus_lock_ratelimit_ctrl: Arc<Mutex<Ratelimit>>,
     run_mode: VmRunMode,
+    dump_on_crash: bool,
+    #[cfg(feature = "stats")] exit_stats: Arc<Mutex<VmExitStatistics>>,
 ) -> Result<JoinHandle<()>>
 where
     V: VcpuArch + 'static,
@@ -538,12 +576,16 @@ where
                     io_bus,
                     mmio_bus,
                     from_main_tube,
+                    &vm_evt_wrtube,
                     #[cfg(feature = "gdb")]
                     to_gdb_tube,
                     #[cfg(feature = "gdb")]
                     guest_mem,
                     #[cfg(all(target_arch = "x86_64", unix))]
                     bus_lock_ratelimit_ctrl,
+                    dump_on_crash,
+                    #[cfg(feature = "stats")]
+                    exit_stats,
                 );
 
                 // We don't want any more VCPU signals from now until the thread exits.
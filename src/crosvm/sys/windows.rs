@@ -6,8 +6,6 @@ pub mod cmdline;
 pub mod config;
 
 pub(crate) mod broker;
-#[cfg(feature = "stats")]
-pub(crate) mod stats;
 
 #[cfg(feature = "crash-report")]
 pub(crate) use broker::setup_emulator_crash_reporting;
@@ -7,8 +7,10 @@
 //! ## Feature flags
 #![cfg_attr(feature = "document-features", doc = document_features::document_features!())]
 
+use std::fs::File;
 #[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use std::fs::OpenOptions;
+use std::io::BufReader;
 use std::path::Path;
 
 use anyhow::anyhow;
@@ -51,12 +53,20 @@ use crosvm::cmdline::CrossPlatformCommands;
 use crosvm::cmdline::CrossPlatformDevicesCommands;
 #[cfg(windows)]
 use sys::windows::setup_metrics_reporting;
+#[cfg(feature = "balloon")]
+use vm_control::balloon_policy::BalloonPolicyCommand;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_capture;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_add;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_remove;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_screenshot;
+use vm_control::client::do_input_record;
+use vm_control::client::do_input_replay;
 use vm_control::client::do_modify_battery;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_add;
@@ -64,25 +74,34 @@ use vm_control::client::do_net_add;
 use vm_control::client::do_net_remove;
 use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
+use vm_control::client::do_usb_attach_emulated;
+use vm_control::client::do_usb_attach_usbip;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
-#[cfg(feature = "balloon")]
+#[cfg(any(feature = "balloon", feature = "stats"))]
 use vm_control::client::handle_request;
 use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
 use vm_control::client::ModifyGpuResult;
+use vm_control::client::ModifyUsbError;
 use vm_control::client::ModifyUsbResult;
+use vm_control::input::parse_event_script;
+use vm_control::input::InputEvent;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
 use vm_control::DiskControlCommand;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::InputControlCommand;
 use vm_control::RestoreCommand;
 use vm_control::SnapshotCommand;
+use vm_control::SndControlCommand;
+#[cfg(feature = "stats")]
+use vm_control::StatsCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
-#[cfg(feature = "balloon")]
+#[cfg(any(feature = "balloon", feature = "stats"))]
 use vm_control::VmResponse;
 
 use crate::sys::error_to_exit_code;
@@ -174,6 +193,37 @@ where
     Ok(CommandStatus::from(exit_state))
 }
 
+#[cfg(feature = "stats")]
+fn stats_vms(cmd: cmdline::StatsCommand) -> std::result::Result<(), ()> {
+    let command = match (cmd.enable, cmd.disable) {
+        (true, true) => {
+            error!("--enable and --disable can't be used together");
+            return Err(());
+        }
+        (true, false) => StatsCommand::Enable,
+        (false, true) => StatsCommand::Disable,
+        (false, false) => StatsCommand::Get,
+    };
+    let request = &VmRequest::Stats(command);
+    match handle_request(request, cmd.socket_path)? {
+        VmResponse::Ok => Ok(()),
+        VmResponse::Stats(stats) => match serde_json::to_string_pretty(&stats) {
+            Ok(stats_json) => {
+                println!("{}", stats_json);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to serialize into JSON: {}", e);
+                Err(())
+            }
+        },
+        r => {
+            error!("unexpected response: {}", r);
+            Err(())
+        }
+    }
+}
+
 fn stop_vms(cmd: cmdline::StopCommand) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::Exit, cmd.socket_path)
 }
@@ -254,6 +304,35 @@ fn balloon_stats(cmd: cmdline::BalloonStatsCommand) -> std::result::Result<(), (
     }
 }
 
+#[cfg(feature = "balloon")]
+fn balloon_policy(cmd: cmdline::BalloonPolicyCommand) -> std::result::Result<(), ()> {
+    use cmdline::BalloonPolicySubcommands::*;
+    let (command, socket_path) = match cmd.nested {
+        Status(params) => (BalloonPolicyCommand::Status, params.socket_path),
+        Enable(params) => (BalloonPolicyCommand::Enable, params.socket_path),
+        Disable(params) => (BalloonPolicyCommand::Disable, params.socket_path),
+    };
+    let request = &VmRequest::BalloonPolicyCommand(command);
+    let response = handle_request(request, socket_path)?;
+    match response {
+        VmResponse::BalloonPolicyStatus(status) => {
+            match serde_json::to_string_pretty(&status) {
+                Ok(status_json) => println!("{status_json}"),
+                Err(e) => {
+                    error!("Failed to serialize into JSON: {e}");
+                    return Err(());
+                }
+            }
+            Ok(())
+        }
+        VmResponse::Ok => Ok(()),
+        r => {
+            error!("unexpected response: {r}");
+            Err(())
+        }
+    }
+}
+
 #[cfg(feature = "balloon")]
 fn balloon_ws(cmd: cmdline::BalloonWsCommand) -> std::result::Result<(), ()> {
     let command = BalloonControlCommand::WorkingSet {};
@@ -337,7 +416,6 @@ fn modify_virtio_net(cmd: cmdline::VirtioNetCommand) -> std::result::Result<(),
 
 #[cfg(feature = "composite-disk")]
 fn create_composite(cmd: cmdline::CreateCompositeCommand) -> std::result::Result<(), ()> {
-    use std::fs::File;
     use std::path::PathBuf;
 
     let composite_image_path = &cmd.path;
@@ -517,10 +595,73 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
     }
 }
 
+fn snd_cmd(cmd: cmdline::SndCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::SndSubcommand::Jack(cmd) => {
+            let request = VmRequest::SndCommand {
+                snd_index: cmd.snd_index,
+                command: SndControlCommand::SetJack {
+                    jack_id: cmd.jack_id,
+                    connected: cmd.connected,
+                },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn input_cmd(cmd: cmdline::InputCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::InputSubcommand::Inject(cmd) => {
+            let mut events = cmd.events;
+            if events.last() != Some(&InputEvent::syn_report()) {
+                events.push(InputEvent::syn_report());
+            }
+            let request = VmRequest::InputCommand {
+                name: cmd.name,
+                command: InputControlCommand::Inject { events },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::InputSubcommand::Replay(cmd) => {
+            let file = File::open(&cmd.script_path).map_err(|e| {
+                error!("failed to open {}: {}", cmd.script_path.display(), e);
+            })?;
+            let script = parse_event_script(BufReader::new(file)).map_err(|e| {
+                error!("{}", e);
+            })?;
+            do_input_replay(&cmd.name, &script, cmd.socket_path)
+        }
+        cmdline::InputSubcommand::Record(cmd) => {
+            do_input_record(&cmd.name, &cmd.script_path, cmd.socket_path)
+        }
+        cmdline::InputSubcommand::StopRecording(cmd) => {
+            let request = VmRequest::InputCommand {
+                name: cmd.name,
+                command: InputControlCommand::StopRecording,
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
 fn make_rt(cmd: cmdline::MakeRTCommand) -> std::result::Result<(), ()> {
     vms_request(&VmRequest::MakeRT, cmd.socket_path)
 }
 
+fn dump_guest(cmd: cmdline::DumpGuestCommand) -> std::result::Result<(), ()> {
+    // The dump is written by the crosvm instance, which may not run in the same directory.
+    let dump_path = match std::env::current_dir() {
+        Ok(dir) => dir.join(cmd.dump_path),
+        Err(e) => {
+            error!("Failed to get the current directory: {}", e);
+            return Err(());
+        }
+    };
+    let request = VmRequest::DumpGuest { dump_path };
+    vms_request(&request, cmd.socket_path)
+}
+
 #[cfg(feature = "gpu")]
 fn gpu_display_add(cmd: cmdline::GpuAddDisplaysCommand) -> ModifyGpuResult {
     do_gpu_display_add(cmd.socket_path, cmd.gpu_display)
@@ -536,12 +677,33 @@ fn gpu_display_remove(cmd: cmdline::GpuRemoveDisplaysCommand) -> ModifyGpuResult
     do_gpu_display_remove(cmd.socket_path, cmd.display_id)
 }
 
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_screenshot(cmd: cmdline::GpuScreenshotCommand) -> ModifyGpuResult {
+    do_gpu_screenshot(cmd.socket_path, cmd.display_id, &cmd.output)
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_capture(cmd: cmdline::GpuCaptureCommand) -> ModifyGpuResult {
+    do_gpu_capture(
+        cmd.socket_path,
+        cmd.display_id,
+        &cmd.output,
+        cmd.frames,
+        std::time::Duration::from_millis(cmd.interval_ms),
+        cmd.raw,
+    )
+}
+
 #[cfg(feature = "gpu")]
 fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::GpuSubCommand::AddDisplays(cmd) => gpu_display_add(cmd),
         cmdline::GpuSubCommand::ListDisplays(cmd) => gpu_display_list(cmd),
         cmdline::GpuSubCommand::RemoveDisplays(cmd) => gpu_display_remove(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::Screenshot(cmd) => gpu_screenshot(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::Capture(cmd) => gpu_capture(cmd),
     };
     match result {
         Ok(response) => {
@@ -558,7 +720,14 @@ fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
 fn usb_attach(cmd: UsbAttachCommand) -> ModifyUsbResult<UsbControlResult> {
     let dev_path = Path::new(&cmd.dev_path);
 
-    do_usb_attach(cmd.socket_path, dev_path)
+    match (cmd.emulated, cmd.usbip) {
+        (Some(_), Some(_)) => Err(ModifyUsbError::InvalidArguments(
+            "--emulated and --usbip are mutually exclusive",
+        )),
+        (Some(device), None) => do_usb_attach_emulated(cmd.socket_path, device, dev_path),
+        (None, Some(busid)) => do_usb_attach_usbip(cmd.socket_path, &cmd.dev_path, &busid),
+        (None, None) => do_usb_attach(cmd.socket_path, dev_path),
+    }
 }
 
 fn usb_detach(cmd: cmdline::UsbDetachCommand) -> ModifyUsbResult<UsbControlResult> {
@@ -732,6 +901,10 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                         balloon_vms(cmd).map_err(|_| anyhow!("balloon subcommand failed"))
                     }
                     #[cfg(feature = "balloon")]
+                    CrossPlatformCommands::BalloonPolicy(cmd) => {
+                        balloon_policy(cmd).map_err(|_| anyhow!("balloon_policy failed"))
+                    }
+                    #[cfg(feature = "balloon")]
                     CrossPlatformCommands::BalloonStats(cmd) => {
                         balloon_stats(cmd).map_err(|_| anyhow!("balloon_stats subcommand failed"))
                     }
@@ -758,10 +931,16 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Disk(cmd) => {
                         disk_cmd(cmd).map_err(|_| anyhow!("disk subcommand failed"))
                     }
+                    CrossPlatformCommands::DumpGuest(cmd) => {
+                        dump_guest(cmd).map_err(|_| anyhow!("dump-guest subcommand failed"))
+                    }
                     #[cfg(feature = "gpu")]
                     CrossPlatformCommands::Gpu(cmd) => {
                         modify_gpu(cmd).map_err(|_| anyhow!("gpu subcommand failed"))
                     }
+                    CrossPlatformCommands::Input(cmd) => {
+                        input_cmd(cmd).map_err(|_| anyhow!("input subcommand failed"))
+                    }
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
@@ -769,6 +948,10 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
                     CrossPlatformCommands::Run(_) => unreachable!(),
+                    #[cfg(feature = "stats")]
+                    CrossPlatformCommands::Stats(cmd) => {
+                        stats_vms(cmd).map_err(|_| anyhow!("stats subcommand failed"))
+                    }
                     CrossPlatformCommands::Stop(cmd) => {
                         stop_vms(cmd).map_err(|_| anyhow!("stop subcommand failed"))
                     }
@@ -803,6 +986,9 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Snapshot(cmd) => {
                         snapshot_vm(cmd).map_err(|_| anyhow!("snapshot subcommand failed"))
                     }
+                    CrossPlatformCommands::Snd(cmd) => {
+                        snd_cmd(cmd).map_err(|_| anyhow!("snd subcommand failed"))
+                    }
                 }
                 .map(|_| CommandStatus::SuccessOrVmStop)
             }
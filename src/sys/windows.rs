@@ -213,10 +213,10 @@ use crate::crosvm::config::Executable;
 use crate::crosvm::config::IrqChipKind;
 #[cfg(feature = "gpu")]
 use crate::crosvm::config::TouchDeviceOption;
+#[cfg(feature = "stats")]
+use crate::crosvm::stats::StatisticsCollector;
 use crate::crosvm::sys::config::HypervisorKind;
 use crate::crosvm::sys::windows::broker::BrokerTubes;
-#[cfg(feature = "stats")]
-use crate::crosvm::sys::windows::stats::StatisticsCollector;
 #[cfg(feature = "gpu")]
 pub(crate) use crate::sys::windows::product::get_gpu_product_configs;
 #[cfg(feature = "audio")]
@@ -358,7 +358,7 @@ fn create_snd_device(
     _product_args: SndBackendConfigProduct,
 ) -> DeviceResult {
     let features = virtio::base_features(cfg.protection_type);
-    let dev = VirtioSnd::new(features, parameters)
+    let dev = VirtioSnd::new(features, parameters, None)
         .exit_context(Exit::VirtioSoundDeviceNew, "failed to create snd device")?;
 
     Ok(VirtioDeviceStub {
@@ -809,6 +809,8 @@ fn handle_readable_event<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         let resp = request.execute(
             &mut run_mode_opt,
             disk_host_tubes,
+            &[],
+            &BTreeMap::new(),
             &mut guest_os.pm,
             #[cfg(feature = "gpu")]
             None,
@@ -2008,6 +2010,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         force_s2idle: cfg.force_s2idle,
         fw_cfg_parameters: cfg.fw_cfg_parameters.clone(),
         itmt: false,
+        #[cfg(target_arch = "x86_64")]
+        pvh: cfg.pvh,
         pvm_fw: None,
         #[cfg(target_arch = "x86_64")]
         pci_low_start: cfg.pci_low_start,
@@ -79,9 +79,9 @@ use x86_64::cpuid::CpuIdContext;
 use x86_64::X8664arch as Arch;
 
 #[cfg(feature = "stats")]
-use crate::crosvm::sys::windows::stats::StatisticsCollector;
+use crate::crosvm::stats::StatisticsCollector;
 #[cfg(feature = "stats")]
-use crate::crosvm::sys::windows::stats::VmExitStatistics;
+use crate::crosvm::stats::VmExitStatistics;
 use crate::sys::windows::save_vcpu_tsc_offset;
 use crate::sys::windows::ExitState;
 
@@ -915,7 +915,9 @@ where
                             let mut collector = stats.lock();
                             collector.pio_bus_stats.push(io_bus.stats);
                             collector.mmio_bus_stats.push(mmio_bus.stats);
-                            collector.vm_exit_stats.push(exit_stats);
+                            collector
+                                .vm_exit_stats
+                                .push(Arc::new(Mutex::new(exit_stats)));
                         }
                         return Ok(ExitState::Stop);
                     }
@@ -993,6 +995,12 @@ fn process_vcpu_control_messages<V>(
                     error!("Failed to send restore response: {}", e);
                 }
             }
+            VcpuControl::CoreDumpRegisters(response_chan) => {
+                let resp = arch::vmcore::vcpu_prstatus(&vcpu, vcpu.id());
+                if let Err(e) = response_chan.send((vcpu.id(), resp)) {
+                    error!("Failed to send core dump registers: {}", e);
+                }
+            }
         }
     }
 }
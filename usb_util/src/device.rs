@@ -27,6 +27,7 @@ use base::Protection;
 use base::RawDescriptor;
 use data_model::vec_with_array_field;
 use libc::EAGAIN;
+use libc::EIO;
 use libc::ENODEV;
 use libc::ENOENT;
 use libc::EPIPE;
@@ -656,6 +657,28 @@ impl Transfer {
     pub fn set_callback<C: 'static + Fn(Transfer) + Send + Sync>(&mut self, cb: C) {
         self.callback = Some(Box::new(cb));
     }
+
+    /// Get the endpoint address (number and direction bit) targeted by this transfer.
+    pub fn endpoint(&self) -> u8 {
+        self.urb().endpoint
+    }
+
+    /// Complete a transfer that is not backed by a host device, such as one handled by an
+    /// emulated device, and invoke its completion callback.
+    pub fn complete(mut self, status: TransferStatus, actual_length: usize) {
+        let errno = match status {
+            TransferStatus::Completed => 0,
+            TransferStatus::Error => EIO,
+            TransferStatus::Cancelled => ENOENT,
+            TransferStatus::NoDevice => ENODEV,
+            TransferStatus::Stalled => EPIPE,
+        };
+        self.urb_mut().status = -errno;
+        self.urb_mut().actual_length = actual_length.try_into().unwrap_or(c_int::MAX);
+        if let Some(cb) = self.callback.take() {
+            cb(self);
+        }
+    }
 }
 
 impl TransferHandle {
@@ -42,6 +42,14 @@ pub enum Error {
     ReleaseDmaBufferFailed,
     #[error("attempted to cancel already-completed transfer")]
     TransferAlreadyCompleted,
+    #[error("USB/IP bus id is too long")]
+    UsbipBusIdTooLong,
+    #[error("USB/IP server refused to export the device: status {0}")]
+    UsbipImportFailed(u32),
+    #[error("USB/IP connection failed: {0}")]
+    UsbipIo(io::Error),
+    #[error("unexpected USB/IP reply {0:#x}")]
+    UsbipUnexpectedReply(u32),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -9,6 +9,7 @@ mod descriptor;
 mod device;
 mod error;
 mod types;
+pub mod usbip;
 
 pub use self::descriptor::parse_usbfs_descriptors;
 pub use self::descriptor::ConfigDescriptorTree;
@@ -0,0 +1,375 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB/IP protocol messages, as documented in the Linux kernel's
+//! Documentation/usb/usbip_protocol.rst. All fields are big endian.
+
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+
+use data_model::Be16;
+use data_model::Be32;
+use data_model::SBe32;
+use static_assertions::const_assert;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::types::DeviceSpeed;
+
+/// TCP port usbipd listens on by default.
+pub const USBIP_DEFAULT_PORT: u16 = 3240;
+
+const USBIP_VERSION: u16 = 0x0111;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const BUSID_SIZE: usize = 32;
+
+pub const USBIP_CMD_SUBMIT: u32 = 1;
+pub const USBIP_CMD_UNLINK: u32 = 2;
+pub const USBIP_RET_SUBMIT: u32 = 3;
+pub const USBIP_RET_UNLINK: u32 = 4;
+
+pub const USBIP_DIR_OUT: u32 = 0;
+pub const USBIP_DIR_IN: u32 = 1;
+
+// Linux URB_DIR_IN transfer flag.
+const URB_DIR_IN: u32 = 0x0200;
+
+/// Header of the messages exchanged before a device is imported.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct OpHeader {
+    pub version: Be16,
+    pub code: Be16,
+    pub status: Be32,
+}
+
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+struct OpImportRequest {
+    header: OpHeader,
+    busid: [u8; BUSID_SIZE],
+}
+
+/// Description of an exported device, sent by the server in reply to an import request.
+#[allow(non_snake_case)]
+#[derive(Copy, Clone, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct UsbipUsbDevice {
+    pub path: [u8; 256],
+    pub busid: [u8; BUSID_SIZE],
+    pub busnum: Be32,
+    pub devnum: Be32,
+    pub speed: Be32,
+    pub idVendor: Be16,
+    pub idProduct: Be16,
+    pub bcdDevice: Be16,
+    pub bDeviceClass: u8,
+    pub bDeviceSubClass: u8,
+    pub bDeviceProtocol: u8,
+    pub bConfigurationValue: u8,
+    pub bNumConfigurations: u8,
+    pub bNumInterfaces: u8,
+}
+
+impl UsbipUsbDevice {
+    /// Get the identifier of the device used in the URB messages.
+    pub fn devid(&self) -> u32 {
+        (self.busnum.to_native() << 16) | self.devnum.to_native()
+    }
+
+    /// Get the speed of the device, `None` if it is unknown.
+    pub fn speed(&self) -> Option<DeviceSpeed> {
+        // Values of the Linux usb_device_speed enum.
+        match self.speed.to_native() {
+            1 => Some(DeviceSpeed::Low),
+            2 => Some(DeviceSpeed::Full),
+            3 => Some(DeviceSpeed::High),
+            5 => Some(DeviceSpeed::Super),
+            6 => Some(DeviceSpeed::SuperPlus),
+            _ => None,
+        }
+    }
+}
+
+/// Header common to all the URB messages.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct HeaderBasic {
+    pub command: Be32,
+    pub seqnum: Be32,
+    pub devid: Be32,
+    pub direction: Be32,
+    pub ep: Be32,
+}
+
+/// USBIP_CMD_SUBMIT message, followed by the transfer data for OUT transfers.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct CmdSubmit {
+    pub header: HeaderBasic,
+    pub transfer_flags: Be32,
+    pub transfer_buffer_length: SBe32,
+    pub start_frame: SBe32,
+    pub number_of_packets: SBe32,
+    pub interval: SBe32,
+    pub setup: [u8; 8],
+}
+
+impl CmdSubmit {
+    /// Create a non-isochronous transfer request. `setup` is only used by control transfers.
+    pub fn new(
+        seqnum: u32,
+        devid: u32,
+        ep: u8,
+        direction: u32,
+        transfer_buffer_length: i32,
+        setup: [u8; 8],
+    ) -> CmdSubmit {
+        CmdSubmit {
+            header: HeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: seqnum.into(),
+                devid: devid.into(),
+                direction: direction.into(),
+                ep: (ep as u32).into(),
+            },
+            transfer_flags: if direction == USBIP_DIR_IN {
+                URB_DIR_IN.into()
+            } else {
+                0u32.into()
+            },
+            transfer_buffer_length: transfer_buffer_length.into(),
+            setup,
+            ..Default::default()
+        }
+    }
+}
+
+/// USBIP_RET_SUBMIT message, followed by the transfer data for IN transfers.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct RetSubmit {
+    pub header: HeaderBasic,
+    /// Zero or a negated Linux errno.
+    pub status: SBe32,
+    pub actual_length: SBe32,
+    pub start_frame: SBe32,
+    pub number_of_packets: SBe32,
+    pub error_count: SBe32,
+    pub padding: [u8; 8],
+}
+
+/// USBIP_CMD_UNLINK message.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct CmdUnlink {
+    pub header: HeaderBasic,
+    pub unlink_seqnum: Be32,
+    pub padding: [u8; 24],
+}
+
+impl CmdUnlink {
+    /// Create a request to cancel the transfer submitted with `unlink_seqnum`.
+    pub fn new(seqnum: u32, devid: u32, unlink_seqnum: u32) -> CmdUnlink {
+        CmdUnlink {
+            header: HeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum: seqnum.into(),
+                devid: devid.into(),
+                ..Default::default()
+            },
+            unlink_seqnum: unlink_seqnum.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// USBIP_RET_UNLINK message.
+#[derive(Copy, Clone, Debug, Default, FromZeroes, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct RetUnlink {
+    pub header: HeaderBasic,
+    /// -ECONNRESET if the transfer was cancelled, zero if it had already completed.
+    pub status: SBe32,
+    pub padding: [u8; 24],
+}
+
+fn _assert_message_sizes() {
+    const_assert!(size_of::<OpHeader>() == 8);
+    const_assert!(size_of::<UsbipUsbDevice>() == 312);
+    const_assert!(size_of::<CmdSubmit>() == 48);
+    const_assert!(size_of::<RetSubmit>() == 48);
+    const_assert!(size_of::<CmdUnlink>() == 48);
+    const_assert!(size_of::<RetUnlink>() == 48);
+}
+
+/// Reply to a URB message sent to the server.
+pub enum Reply {
+    Submit(RetSubmit),
+    Unlink(RetUnlink),
+}
+
+/// Request the export of device `busid` from the server at the other end of `stream`. Once this
+/// succeeds, `stream` carries the URB messages of the device.
+pub fn import_device<S: Read + Write>(stream: &mut S, busid: &str) -> Result<UsbipUsbDevice> {
+    let mut request = OpImportRequest {
+        header: OpHeader {
+            version: USBIP_VERSION.into(),
+            code: OP_REQ_IMPORT.into(),
+            status: 0u32.into(),
+        },
+        busid: [0; BUSID_SIZE],
+    };
+    // The bus id must be nul terminated.
+    if busid.len() >= BUSID_SIZE {
+        return Err(Error::UsbipBusIdTooLong);
+    }
+    request.busid[..busid.len()].copy_from_slice(busid.as_bytes());
+    stream
+        .write_all(request.as_bytes())
+        .map_err(Error::UsbipIo)?;
+
+    let mut header = OpHeader::new_zeroed();
+    stream
+        .read_exact(header.as_bytes_mut())
+        .map_err(Error::UsbipIo)?;
+    if header.code.to_native() != OP_REP_IMPORT {
+        return Err(Error::UsbipUnexpectedReply(header.code.to_native() as u32));
+    }
+    if header.status.to_native() != 0 {
+        return Err(Error::UsbipImportFailed(header.status.to_native()));
+    }
+    let mut device = UsbipUsbDevice::new_zeroed();
+    stream
+        .read_exact(device.as_bytes_mut())
+        .map_err(Error::UsbipIo)?;
+    Ok(device)
+}
+
+/// Read the header of the next reply from the server. The data of a `RetSubmit` follows it.
+pub fn read_reply<R: Read>(reader: &mut R) -> Result<Reply> {
+    let mut buf = [0u8; size_of::<RetSubmit>()];
+    reader.read_exact(&mut buf).map_err(Error::UsbipIo)?;
+    // Both replies have the same size, so they can be read from `buf` in full.
+    let header = HeaderBasic::read_from_prefix(&buf[..]).unwrap();
+    match header.command.to_native() {
+        USBIP_RET_SUBMIT => Ok(Reply::Submit(RetSubmit::read_from(&buf[..]).unwrap())),
+        USBIP_RET_UNLINK => Ok(Reply::Unlink(RetUnlink::read_from(&buf[..]).unwrap())),
+        command => Err(Error::UsbipUnexpectedReply(command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Cursor;
+
+    use super::*;
+
+    // Stream replaying canned server replies and recording what the client sends.
+    struct FakeServer {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for FakeServer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeServer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn import() {
+        let mut device = UsbipUsbDevice::new_zeroed();
+        device.busnum = 3u32.into();
+        device.devnum = 7u32.into();
+        device.speed = 3u32.into();
+        let mut replies = OpHeader {
+            version: USBIP_VERSION.into(),
+            code: OP_REP_IMPORT.into(),
+            status: 0u32.into(),
+        }
+        .as_bytes()
+        .to_vec();
+        replies.extend_from_slice(device.as_bytes());
+        let mut server = FakeServer {
+            replies: Cursor::new(replies),
+            sent: Vec::new(),
+        };
+
+        let device = import_device(&mut server, "1-1.2").unwrap();
+        assert_eq!(device.devid(), 0x0003_0007);
+        assert!(matches!(device.speed(), Some(DeviceSpeed::High)));
+        assert_eq!(server.sent.len(), size_of::<OpImportRequest>());
+        assert_eq!(&server.sent[..4], &[0x01, 0x11, 0x80, 0x03]);
+        assert_eq!(&server.sent[8..14], b"1-1.2\0");
+    }
+
+    #[test]
+    fn import_refused() {
+        let replies = OpHeader {
+            version: USBIP_VERSION.into(),
+            code: OP_REP_IMPORT.into(),
+            status: 1u32.into(),
+        }
+        .as_bytes()
+        .to_vec();
+        let mut server = FakeServer {
+            replies: Cursor::new(replies),
+            sent: Vec::new(),
+        };
+        assert!(matches!(
+            import_device(&mut server, "1-1"),
+            Err(Error::UsbipImportFailed(1))
+        ));
+    }
+
+    #[test]
+    fn submit_encoding() {
+        let cmd = CmdSubmit::new(5, 0x10002, 1, USBIP_DIR_IN, 512, [0; 8]);
+        let bytes = cmd.as_bytes();
+        assert_eq!(&bytes[..4], &[0, 0, 0, 1]);
+        assert_eq!(&bytes[4..8], &[0, 0, 0, 5]);
+        assert_eq!(&bytes[8..12], &[0, 1, 0, 2]);
+        assert_eq!(&bytes[12..16], &[0, 0, 0, 1]);
+        // URB_DIR_IN transfer flag, then the 512 byte transfer length.
+        assert_eq!(&bytes[20..24], &[0, 0, 0x02, 0]);
+        assert_eq!(&bytes[24..28], &[0, 0, 0x02, 0]);
+    }
+
+    #[test]
+    fn reply_decoding() {
+        let ret = RetUnlink {
+            header: HeaderBasic {
+                command: USBIP_RET_UNLINK.into(),
+                seqnum: 9u32.into(),
+                ..Default::default()
+            },
+            status: (-104i32).into(),
+            ..Default::default()
+        };
+        match read_reply(&mut ret.as_bytes()).unwrap() {
+            Reply::Unlink(r) => {
+                assert_eq!(r.header.seqnum.to_native(), 9);
+                assert_eq!(r.status.to_native(), -104);
+            }
+            Reply::Submit(_) => panic!("decoded an unlink reply as a submit reply"),
+        }
+    }
+}
@@ -0,0 +1,525 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! In-process balloon sizing policy.
+//!
+//! The policy periodically samples the guest balloon statistics, the guest working set (when
+//! working set reporting is enabled) and the host memory pressure reported by the kernel PSI
+//! interface, and decides how the balloon should be resized. It only computes decisions; the
+//! caller is responsible for fetching the inputs and forwarding the resulting `Adjust` commands
+//! to the balloon device.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
+
+use crate::BalloonStats;
+use crate::BalloonWS;
+
+/// Path of the host memory pressure stall information file.
+pub const HOST_MEMORY_PRESSURE_PATH: &str = "/proc/pressure/memory";
+
+const MIB: u64 = 1 << 20;
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+fn default_guest_reserve_mib() -> u64 {
+    256
+}
+
+fn default_step_mib() -> u64 {
+    128
+}
+
+fn default_min_adjust_mib() -> u64 {
+    16
+}
+
+fn default_pressure_high() -> u32 {
+    10
+}
+
+fn default_pressure_low() -> u32 {
+    1
+}
+
+/// Parameters of the automatic balloon policy, parsed from `--balloon-policy`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BalloonPolicyConfig {
+    /// Interval between two policy evaluations, in milliseconds.
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// Amount of memory the guest should always keep available, in MiB.
+    #[serde(default = "default_guest_reserve_mib")]
+    pub guest_reserve_mib: u64,
+    /// Maximum amount the balloon is resized by in a single decision, in MiB.
+    #[serde(default = "default_step_mib")]
+    pub step_mib: u64,
+    /// Resizes smaller than this amount are not performed, in MiB.
+    #[serde(default = "default_min_adjust_mib")]
+    pub min_adjust_mib: u64,
+    /// Upper bound of the balloon size, in MiB. Unbounded if not set.
+    #[serde(default)]
+    pub max_balloon_mib: Option<u64>,
+    /// Host memory pressure (PSI `some avg10`, in percent) above which the balloon is inflated.
+    #[serde(default = "default_pressure_high")]
+    pub pressure_high: u32,
+    /// Host memory pressure (PSI `some avg10`, in percent) below which the balloon is deflated.
+    #[serde(default = "default_pressure_low")]
+    pub pressure_low: u32,
+}
+
+impl Default for BalloonPolicyConfig {
+    fn default() -> Self {
+        BalloonPolicyConfig {
+            interval_ms: default_interval_ms(),
+            guest_reserve_mib: default_guest_reserve_mib(),
+            step_mib: default_step_mib(),
+            min_adjust_mib: default_min_adjust_mib(),
+            max_balloon_mib: None,
+            pressure_high: default_pressure_high(),
+            pressure_low: default_pressure_low(),
+        }
+    }
+}
+
+impl BalloonPolicyConfig {
+    /// Checks that the parameters are consistent with each other.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.interval_ms == 0 {
+            return Err("balloon policy interval must be non-zero".to_string());
+        }
+        if self.step_mib == 0 {
+            return Err("balloon policy step must be non-zero".to_string());
+        }
+        if self.pressure_low > self.pressure_high {
+            return Err("balloon policy pressure-low must not exceed pressure-high".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+/// Memory pressure stall information, as reported by `/proc/pressure/memory`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryPressure {
+    /// Share of time in which at least some tasks were stalled on memory, in percent.
+    pub some_avg10: f64,
+    pub some_avg60: f64,
+    /// Share of time in which all non-idle tasks were stalled on memory, in percent.
+    pub full_avg10: f64,
+    pub full_avg60: f64,
+}
+
+impl MemoryPressure {
+    /// Parses the content of a PSI file such as `/proc/pressure/memory`.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut pressure = MemoryPressure::default();
+        let mut found_some = false;
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            let kind = match fields.next() {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let mut avg10 = None;
+            let mut avg60 = None;
+            for field in fields {
+                let (key, value) = field
+                    .split_once('=')
+                    .with_context(|| format!("malformed PSI field: {}", field))?;
+                match key {
+                    "avg10" => avg10 = Some(value.parse::<f64>()?),
+                    "avg60" => avg60 = Some(value.parse::<f64>()?),
+                    _ => (),
+                }
+            }
+            let avg10 = avg10.ok_or_else(|| anyhow!("missing avg10 in PSI line: {}", line))?;
+            let avg60 = avg60.ok_or_else(|| anyhow!("missing avg60 in PSI line: {}", line))?;
+            match kind {
+                "some" => {
+                    pressure.some_avg10 = avg10;
+                    pressure.some_avg60 = avg60;
+                    found_some = true;
+                }
+                "full" => {
+                    pressure.full_avg10 = avg10;
+                    pressure.full_avg60 = avg60;
+                }
+                _ => return Err(anyhow!("unknown PSI line: {}", line)),
+            }
+        }
+        if !found_some {
+            return Err(anyhow!("no `some` line in PSI data"));
+        }
+        Ok(pressure)
+    }
+
+    /// Reads and parses the PSI file at `path`.
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = fs::read_to_string(path.as_ref())
+            .with_context(|| format!("failed to read {}", path.as_ref().display()))?;
+        Self::parse(&content)
+    }
+
+    /// Reads the memory pressure of the host.
+    pub fn read_host() -> Result<Self> {
+        Self::read_from(HOST_MEMORY_PRESSURE_PATH)
+    }
+}
+
+/// Inputs of a single policy evaluation.
+#[derive(Clone, Debug, Default)]
+pub struct BalloonPolicyInput {
+    pub stats: BalloonStats,
+    /// Current size of the balloon in bytes.
+    pub balloon_actual: u64,
+    pub ws: Option<BalloonWS>,
+    pub host_pressure: Option<MemoryPressure>,
+}
+
+/// Reason behind a policy decision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BalloonPolicyReason {
+    /// The host is under memory pressure and the guest has memory to spare.
+    HostPressure,
+    /// The guest has less available memory than its reserve.
+    GuestStarved,
+    /// The host has no memory pressure, so memory is handed back to the guest.
+    HostRelaxed,
+    /// No resize is needed.
+    Steady,
+    /// The guest did not report enough statistics to make a decision.
+    MissingStats,
+}
+
+/// Outcome of a single policy evaluation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BalloonPolicyDecision {
+    pub reason: BalloonPolicyReason,
+    /// Balloon size before the decision, in bytes.
+    pub balloon_actual: u64,
+    /// Requested balloon size, in bytes. `None` if the balloon should not be resized.
+    pub target: Option<u64>,
+}
+
+/// Snapshot of the policy state, reported through the control socket.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BalloonPolicyStatus {
+    pub enabled: bool,
+    pub config: BalloonPolicyConfig,
+    pub evaluations: u64,
+    pub adjustments: u64,
+    pub host_pressure: Option<MemoryPressure>,
+    pub last_decision: Option<BalloonPolicyDecision>,
+}
+
+/// Commands controlling the balloon policy, sent on the crosvm control socket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BalloonPolicyCommand {
+    /// Returns the policy state and its last decision.
+    Status,
+    /// Resumes automatic balloon resizing.
+    Enable,
+    /// Pauses automatic balloon resizing. Explicit `Adjust` commands are not affected.
+    Disable,
+}
+
+/// Balloon sizing policy driven by guest statistics and host memory pressure.
+pub struct BalloonPolicy {
+    config: BalloonPolicyConfig,
+    enabled: bool,
+    evaluations: u64,
+    adjustments: u64,
+    host_pressure: Option<MemoryPressure>,
+    last_decision: Option<BalloonPolicyDecision>,
+}
+
+impl BalloonPolicy {
+    pub fn new(config: BalloonPolicyConfig) -> Self {
+        BalloonPolicy {
+            config,
+            enabled: true,
+            evaluations: 0,
+            adjustments: 0,
+            host_pressure: None,
+            last_decision: None,
+        }
+    }
+
+    pub fn config(&self) -> &BalloonPolicyConfig {
+        &self.config
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn status(&self) -> BalloonPolicyStatus {
+        BalloonPolicyStatus {
+            enabled: self.enabled,
+            config: self.config.clone(),
+            evaluations: self.evaluations,
+            adjustments: self.adjustments,
+            host_pressure: self.host_pressure,
+            last_decision: self.last_decision.clone(),
+        }
+    }
+
+    /// Amount of guest memory that must stay out of the balloon, in bytes.
+    ///
+    /// This is the configured reserve, raised to the size of the most recently used working set
+    /// bucket when a working set report is available.
+    fn protected_bytes(&self, ws: Option<&BalloonWS>) -> u64 {
+        let reserve = self.config.guest_reserve_mib * MIB;
+        let hot = ws
+            .and_then(|ws| ws.ws.iter().min_by_key(|bucket| bucket.age))
+            .map(|bucket| bucket.bytes.iter().sum())
+            .unwrap_or(0);
+        reserve.max(hot)
+    }
+
+    /// Evaluates the policy against `input` and records the resulting decision.
+    pub fn evaluate(&mut self, input: &BalloonPolicyInput) -> BalloonPolicyDecision {
+        self.evaluations += 1;
+        self.host_pressure = input.host_pressure;
+        let decision = self.decide(input);
+        if decision.target.is_some() {
+            self.adjustments += 1;
+        }
+        self.last_decision = Some(decision.clone());
+        decision
+    }
+
+    fn decide(&self, input: &BalloonPolicyInput) -> BalloonPolicyDecision {
+        let actual = input.balloon_actual;
+        let decision = |reason, target| BalloonPolicyDecision {
+            reason,
+            balloon_actual: actual,
+            target,
+        };
+
+        let available = match input.stats.available_memory.or_else(|| {
+            input
+                .stats
+                .free_memory
+                .map(|free| free + input.stats.disk_caches.unwrap_or(0))
+        }) {
+            Some(available) => available,
+            None => return decision(BalloonPolicyReason::MissingStats, None),
+        };
+
+        let step = self.config.step_mib * MIB;
+        let protected = self.protected_bytes(input.ws.as_ref());
+        let pressure = input.host_pressure.map(|p| p.some_avg10);
+
+        let (reason, target) = if available < protected {
+            let shortfall = (protected - available).min(step);
+            (
+                BalloonPolicyReason::GuestStarved,
+                actual.saturating_sub(shortfall),
+            )
+        } else if pressure.map_or(false, |p| p >= self.config.pressure_high as f64) {
+            let spare = (available - protected).min(step);
+            let mut target = actual + spare;
+            if let Some(max) = self.config.max_balloon_mib {
+                // Never deflate under host pressure, even if the balloon is already above the
+                // maximum.
+                target = target.min(max * MIB).max(actual);
+            }
+            (BalloonPolicyReason::HostPressure, target)
+        } else if pressure.map_or(true, |p| p <= self.config.pressure_low as f64) {
+            (
+                BalloonPolicyReason::HostRelaxed,
+                actual.saturating_sub(step),
+            )
+        } else {
+            (BalloonPolicyReason::Steady, actual)
+        };
+
+        if target == actual {
+            let reason = match reason {
+                BalloonPolicyReason::HostRelaxed => BalloonPolicyReason::Steady,
+                reason => reason,
+            };
+            return decision(reason, None);
+        }
+        // Small resizes are not worth the guest's effort, except to fully deflate the balloon.
+        if target != 0 && target.abs_diff(actual) < self.config.min_adjust_mib * MIB {
+            return decision(reason, None);
+        }
+        decision(reason, Some(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WSBucket;
+
+    fn pressure(some_avg10: f64) -> Option<MemoryPressure> {
+        Some(MemoryPressure {
+            some_avg10,
+            ..Default::default()
+        })
+    }
+
+    fn input(
+        available_mib: u64,
+        balloon_mib: u64,
+        host: Option<MemoryPressure>,
+    ) -> BalloonPolicyInput {
+        BalloonPolicyInput {
+            stats: BalloonStats {
+                available_memory: Some(available_mib * MIB),
+                ..Default::default()
+            },
+            balloon_actual: balloon_mib * MIB,
+            ws: None,
+            host_pressure: host,
+        }
+    }
+
+    #[test]
+    fn parse_psi() {
+        let content = "some avg10=1.50 avg60=0.75 avg300=0.10 total=12345\n\
+                       full avg10=0.50 avg60=0.25 avg300=0.00 total=678\n";
+        let p = MemoryPressure::parse(content).unwrap();
+        assert_eq!(p.some_avg10, 1.5);
+        assert_eq!(p.some_avg60, 0.75);
+        assert_eq!(p.full_avg10, 0.5);
+        assert_eq!(p.full_avg60, 0.25);
+    }
+
+    #[test]
+    fn parse_psi_invalid() {
+        assert!(MemoryPressure::parse("").is_err());
+        assert!(MemoryPressure::parse("some avg10=x avg60=0.0").is_err());
+        assert!(MemoryPressure::parse("some avg60=0.0").is_err());
+    }
+
+    #[test]
+    fn inflate_under_host_pressure() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let d = policy.evaluate(&input(1024, 0, pressure(20.0)));
+        assert_eq!(d.reason, BalloonPolicyReason::HostPressure);
+        assert_eq!(d.target, Some(128 * MIB));
+
+        // Only the memory above the reserve can be reclaimed.
+        let d = policy.evaluate(&input(300, 512, pressure(20.0)));
+        assert_eq!(d.target, Some((512 + 44) * MIB));
+    }
+
+    #[test]
+    fn inflate_bounded_by_max() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig {
+            max_balloon_mib: Some(64),
+            ..Default::default()
+        });
+        let d = policy.evaluate(&input(1024, 0, pressure(20.0)));
+        assert_eq!(d.target, Some(64 * MIB));
+        let d = policy.evaluate(&input(1024, 64, pressure(20.0)));
+        assert_eq!(d.target, None);
+        let d = policy.evaluate(&input(1024, 128, pressure(20.0)));
+        assert_eq!(d.reason, BalloonPolicyReason::HostPressure);
+        assert_eq!(d.target, None);
+    }
+
+    #[test]
+    fn deflate_when_guest_starved() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let d = policy.evaluate(&input(200, 1024, pressure(50.0)));
+        assert_eq!(d.reason, BalloonPolicyReason::GuestStarved);
+        assert_eq!(d.target, Some((1024 - 56) * MIB));
+    }
+
+    #[test]
+    fn deflate_when_host_relaxed() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let d = policy.evaluate(&input(1024, 100, pressure(0.0)));
+        assert_eq!(d.reason, BalloonPolicyReason::HostRelaxed);
+        assert_eq!(d.target, Some(0));
+
+        let d = policy.evaluate(&input(1024, 0, None));
+        assert_eq!(d.reason, BalloonPolicyReason::Steady);
+        assert_eq!(d.target, None);
+    }
+
+    #[test]
+    fn steady_between_thresholds() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let d = policy.evaluate(&input(1024, 512, pressure(5.0)));
+        assert_eq!(d.reason, BalloonPolicyReason::Steady);
+        assert_eq!(d.target, None);
+        let status = policy.status();
+        assert_eq!(status.evaluations, 1);
+        assert_eq!(status.adjustments, 0);
+    }
+
+    #[test]
+    fn working_set_protects_hot_memory() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let mut i = input(1024, 0, pressure(20.0));
+        i.ws = Some(BalloonWS {
+            ws: vec![
+                WSBucket {
+                    age: 10,
+                    bytes: [600 * MIB, 350 * MIB],
+                },
+                WSBucket {
+                    age: 60,
+                    bytes: [2048 * MIB, 0],
+                },
+            ],
+        });
+        let d = policy.evaluate(&i);
+        assert_eq!(d.reason, BalloonPolicyReason::HostPressure);
+        assert_eq!(d.target, Some(74 * MIB));
+    }
+
+    #[test]
+    fn missing_stats() {
+        let mut policy = BalloonPolicy::new(BalloonPolicyConfig::default());
+        let d = policy.evaluate(&BalloonPolicyInput::default());
+        assert_eq!(d.reason, BalloonPolicyReason::MissingStats);
+        assert_eq!(d.target, None);
+    }
+
+    #[test]
+    fn config_from_key_values() {
+        let cfg: BalloonPolicyConfig =
+            serde_keyvalue::from_key_values("interval-ms=1000,step-mib=64,max-balloon-mib=2048")
+                .unwrap();
+        assert_eq!(
+            cfg,
+            BalloonPolicyConfig {
+                interval_ms: 1000,
+                step_mib: 64,
+                max_balloon_mib: Some(2048),
+                ..Default::default()
+            }
+        );
+        assert!(cfg.validate().is_ok());
+        let cfg: BalloonPolicyConfig =
+            serde_keyvalue::from_key_values("pressure-low=20,pressure-high=10").unwrap();
+        assert!(cfg.validate().is_err());
+    }
+}
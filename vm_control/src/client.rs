@@ -2,9 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::fs::OpenOptions;
 use std::path::Path;
 use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
 
 #[cfg(feature = "pci-hotplug")]
 use anyhow::anyhow;
@@ -15,6 +18,7 @@ use thiserror::Error;
 
 #[cfg(feature = "gpu")]
 pub use crate::gpu::*;
+use crate::input::ScriptEvent;
 pub use crate::sys::handle_request;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub use crate::sys::handle_request_with_timeout;
@@ -30,8 +34,12 @@ enum ModifyBatError {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum ModifyUsbError {
+    #[error("failed to connect to USB/IP server {0}: {1}")]
+    FailedToConnect(String, base::Error),
     #[error("failed to open device {0}: {1}")]
     FailedToOpenDevice(PathBuf, base::Error),
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(&'static str),
     #[error("socket failed")]
     SocketFailed,
     #[error("unexpected response: {0}")]
@@ -58,6 +66,56 @@ pub fn vms_request<T: AsRef<Path> + std::fmt::Debug>(
     }
 }
 
+/// Sends the events of `script` to the input device `name`, each at its time after the start of
+/// the replay. Consecutive events with the same time are sent together.
+pub fn do_input_replay<T: AsRef<Path> + std::fmt::Debug>(
+    name: &str,
+    script: &[ScriptEvent],
+    socket_path: T,
+) -> VmsRequestResult {
+    let start = Instant::now();
+    let mut first = 0;
+    while first < script.len() {
+        let time = script[first].time;
+        let count = script[first..]
+            .iter()
+            .take_while(|event| event.time == time)
+            .count();
+        if let Some(delay) = time.checked_sub(start.elapsed()) {
+            thread::sleep(delay);
+        }
+        let request = VmRequest::InputCommand {
+            name: name.to_owned(),
+            command: InputControlCommand::Inject {
+                events: script[first..first + count]
+                    .iter()
+                    .map(|event| event.event)
+                    .collect(),
+            },
+        };
+        vms_request(&request, socket_path.as_ref())?;
+        first += count;
+    }
+    Ok(())
+}
+
+/// Starts recording the events sent to the guest by the input device `name` to a new file at
+/// `path`.
+pub fn do_input_record<T: AsRef<Path> + std::fmt::Debug>(
+    name: &str,
+    path: &Path,
+    socket_path: T,
+) -> VmsRequestResult {
+    let file = File::create(path).map_err(|e| {
+        println!("failed to create {}: {}", path.display(), e);
+    })?;
+    let request = VmRequest::InputCommand {
+        name: name.to_owned(),
+        command: InputControlCommand::StartRecording { file },
+    };
+    vms_request(&request, socket_path)
+}
+
 #[cfg(feature = "pci-hotplug")]
 /// Send a `VmRequest` for PCI hotplug that expects `VmResponse::PciResponse::AddOk(bus)`
 pub fn do_net_add<T: AsRef<Path> + std::fmt::Debug>(
@@ -120,6 +178,54 @@ pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     }
 }
 
+/// Attach a USB device emulated by crosvm. `backing_path` is the disk image of a mass storage
+/// device, or the unix socket providing the input events of a keyboard or tablet.
+pub fn do_usb_attach_emulated<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    device: EmulatedUsbDevice,
+    backing_path: &Path,
+) -> ModifyUsbResult<UsbControlResult> {
+    let file = match device {
+        EmulatedUsbDevice::MassStorage { read_only } => open_file_or_duplicate(
+            backing_path,
+            OpenOptions::new().read(true).write(!read_only),
+        ),
+        EmulatedUsbDevice::Keyboard | EmulatedUsbDevice::Tablet => {
+            crate::sys::connect_input_event_socket(backing_path)
+        }
+    }
+    .map_err(|e| ModifyUsbError::FailedToOpenDevice(backing_path.into(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachEmulatedDevice { device, file });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+/// Attach device `busid` exported by the USB/IP server at `server`, given as `host[:port]`.
+pub fn do_usb_attach_usbip<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    server: &str,
+    busid: &str,
+) -> ModifyUsbResult<UsbControlResult> {
+    let file = crate::sys::connect_usbip_server(server)
+        .map_err(|e| ModifyUsbError::FailedToConnect(server.to_string(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachUsbipDevice {
+        busid: busid.to_string(),
+        file,
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 pub fn do_usb_detach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     port: u8,
@@ -9,6 +9,7 @@ use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use gdbstub_arch::riscv::Riscv64 as GdbArch;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::HwWatchpoint;
 use vm_memory::GuestAddress;
 
 /// Messages that can be sent to a vCPU to set/get its state from the debugger.
@@ -22,7 +23,7 @@ pub enum VcpuDebug {
     WriteMem(GuestAddress, Vec<u8>),
     EnableSinglestep,
     GetHwBreakPointCount,
-    SetHwBreakPoint(Vec<GuestAddress>),
+    SetHwBreakPoint(Vec<GuestAddress>, Vec<HwWatchpoint>),
 }
 
 /// Messages that can be sent from a vCPU to update the state to the debugger.
@@ -35,6 +36,8 @@ pub enum VcpuDebugStatus {
     CommandComplete,
     HwBreakPointCount(usize),
     HitBreakPoint,
+    /// The vCPU stopped on the watchpoint at the given index of the last `SetHwBreakPoint`.
+    HitWatchPoint(usize),
 }
 
 /// Pair of a vCPU ID and messages that can be sent from the vCPU to update the state to the
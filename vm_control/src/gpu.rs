@@ -2,11 +2,37 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod png;
+
 use std::collections::BTreeMap as Map;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::File;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::fs::OpenOptions;
+use std::io;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::BufWriter;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Read;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Seek;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::io::Write;
 use std::path::Path;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::thread;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::time::Duration;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::time::Instant;
 
+use base::with_as_descriptor;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::SafeDescriptor;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::SharedMemory;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -122,9 +148,20 @@ impl Default for DisplayParameters {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GpuControlCommand {
-    AddDisplays { displays: Vec<DisplayParameters> },
+    AddDisplays {
+        displays: Vec<DisplayParameters>,
+    },
     ListDisplays,
-    RemoveDisplays { display_ids: Vec<u32> },
+    RemoveDisplays {
+        display_ids: Vec<u32>,
+    },
+    /// Reads back the image currently shown on a display and writes it to `file` as rows of
+    /// XRGB8888 pixels without padding.
+    Screenshot {
+        display_id: u32,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -137,6 +174,14 @@ pub enum GpuControlResult {
     NoSuchDisplay {
         display_id: u32,
     },
+    NoScanoutResource {
+        display_id: u32,
+    },
+    Screenshot {
+        width: u32,
+        height: u32,
+    },
+    ScreenshotFailed(String),
 }
 
 impl Display for GpuControlResult {
@@ -155,11 +200,17 @@ impl Display for GpuControlResult {
             }
             TooManyDisplays(n) => write!(f, "too_many_displays {}", n),
             NoSuchDisplay { display_id } => write!(f, "no_such_display {}", display_id),
+            NoScanoutResource { display_id } => {
+                write!(f, "no_scanout_resource {}", display_id)
+            }
+            Screenshot { width, height } => write!(f, "screenshot {}x{}", width, height),
+            ScreenshotFailed(e) => write!(f, "screenshot_failed {}", e),
         }
     }
 }
 
 pub enum ModifyGpuError {
+    ScreenshotIo(io::Error),
     SocketFailed,
     UnexpectedResponse(VmResponse),
     UnknownCommand(String),
@@ -171,6 +222,7 @@ impl fmt::Display for ModifyGpuError {
         use self::ModifyGpuError::*;
 
         match self {
+            ScreenshotIo(e) => write!(f, "failed to transfer screenshot: {}", e),
             SocketFailed => write!(f, "socket failed"),
             UnexpectedResponse(r) => write!(f, "unexpected response: {}", r),
             UnknownCommand(c) => write!(f, "unknown display command: `{}`", c),
@@ -218,3 +270,135 @@ pub fn do_gpu_display_remove<T: AsRef<Path> + std::fmt::Debug>(
         .map_err(|_| ModifyGpuError::SocketFailed)?
         .into()
 }
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+/// The image shown on a display at the time of a screenshot.
+struct ScreenshotImage {
+    width: u32,
+    height: u32,
+    /// Rows of XRGB8888 pixels without padding.
+    pixels: Vec<u8>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn read_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> std::result::Result<ScreenshotImage, ModifyGpuError> {
+    // The device writes the pixels into shared memory since they are too large for the socket.
+    let shm = SharedMemory::new("crosvm_gpu_screenshot", 0)
+        .map_err(|e| ModifyGpuError::ScreenshotIo(e.into()))?;
+    let mut file = File::from(SafeDescriptor::from(shm));
+    let request = VmRequest::GpuCommand(GpuControlCommand::Screenshot {
+        display_id,
+        file: file.try_clone().map_err(ModifyGpuError::ScreenshotIo)?,
+    });
+    let result: ModifyGpuResult = handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into();
+    let (width, height) = match result? {
+        GpuControlResult::Screenshot { width, height } => (width, height),
+        r => return Err(ModifyGpuError::GpuControl(r)),
+    };
+
+    let mut pixels = Vec::new();
+    file.seek(io::SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut pixels))
+        .map_err(ModifyGpuError::ScreenshotIo)?;
+    if pixels.len() != width as usize * height as usize * 4 {
+        return Err(ModifyGpuError::ScreenshotIo(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "incomplete screenshot",
+        )));
+    }
+    Ok(ScreenshotImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Saves the image currently shown on a display to `output` as a PNG file.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_gpu_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    output: &Path,
+) -> ModifyGpuResult {
+    let image = read_screenshot(control_socket_path, display_id)?;
+    File::create(output)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            png::write_png(&mut writer, image.width, image.height, &image.pixels)?;
+            writer.flush()
+        })
+        .map_err(ModifyGpuError::ScreenshotIo)?;
+    Ok(GpuControlResult::Screenshot {
+        width: image.width,
+        height: image.height,
+    })
+}
+
+/// Captures frames of a display, at most one every `interval`, until `frames` frames were captured
+/// or, when `frames` is `None`, until `output` is closed.
+///
+/// If `output` is a directory, every frame is written to its own numbered file. Otherwise, the
+/// frames are written back to back to `output`, which is typically a named pipe. Frames are PNG
+/// images, or rows of XRGB8888 pixels if `raw` is set.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_gpu_capture<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    output: &Path,
+    frames: Option<u32>,
+    interval: Duration,
+    raw: bool,
+) -> ModifyGpuResult {
+    let mut stream = if output.is_dir() {
+        None
+    } else {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)
+            .map_err(ModifyGpuError::ScreenshotIo)?;
+        Some(BufWriter::new(file))
+    };
+
+    let mut last_size = (0, 0);
+    let mut frame = 0;
+    while frames.map_or(true, |frames| frame < frames) {
+        let start = Instant::now();
+        let image = read_screenshot(&control_socket_path, display_id)?;
+        let write_frame = |writer: &mut BufWriter<File>| {
+            if raw {
+                writer.write_all(&image.pixels)?;
+            } else {
+                png::write_png(writer, image.width, image.height, &image.pixels)?;
+            }
+            writer.flush()
+        };
+        let result = match stream.as_mut() {
+            Some(writer) => write_frame(writer),
+            None => {
+                let name = format!("frame-{:05}.{}", frame, if raw { "raw" } else { "png" });
+                File::create(output.join(name))
+                    .and_then(|file| write_frame(&mut BufWriter::new(file)))
+            }
+        };
+        match result {
+            // The reader of the pipe is gone, there is nobody left to capture frames for.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe && frames.is_none() => break,
+            r => r.map_err(ModifyGpuError::ScreenshotIo)?,
+        }
+        last_size = (image.width, image.height);
+        frame += 1;
+        thread::sleep(interval.saturating_sub(start.elapsed()));
+    }
+
+    Ok(GpuControlResult::Screenshot {
+        width: last_size.0,
+        height: last_size.1,
+    })
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal PNG encoder for display screenshots.
+//!
+//! The image data is compressed at the fastest level, which is what automated tests capturing
+//! many frames want.
+
+use std::io;
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "PNG chunk too large"))?;
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// Writes `pixels`, rows of `width` XRGB8888 pixels without padding, as an 8-bit RGB PNG image.
+pub fn write_png<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let row_size = width as usize * 4;
+    if pixels.len() != row_size * height as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pixel data does not match the image size",
+        ));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, color type, then the default compression, filter and interlace methods.
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut scanline = Vec::with_capacity(1 + width as usize * 3);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    if row_size > 0 {
+        for row in pixels.chunks_exact(row_size) {
+            scanline.clear();
+            scanline.push(FILTER_NONE);
+            for pixel in row.chunks_exact(4) {
+                // XRGB8888 is stored in little-endian order, with blue first.
+                scanline.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            encoder.write_all(&scanline)?;
+        }
+    }
+
+    w.write_all(&SIGNATURE)?;
+    write_chunk(w, b"IHDR", &header)?;
+    write_chunk(w, b"IDAT", &encoder.finish()?)?;
+    write_chunk(w, b"IEND", &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    // Splits a PNG file into its chunks, checking their CRCs.
+    fn chunks(mut png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        png = &png[8..];
+        let mut chunks = Vec::new();
+        while !png.is_empty() {
+            let len = u32::from_be_bytes(png[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = png[4..8].try_into().unwrap();
+            let data = png[8..8 + len].to_vec();
+            let crc = u32::from_be_bytes(png[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&png[4..8 + len]));
+            chunks.push((kind, data));
+            png = &png[12 + len..];
+        }
+        chunks
+    }
+
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        ZlibDecoder::new(stream).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn encode_image() {
+        let pixels = [
+            0x01, 0x02, 0x03, 0x00, 0x11, 0x12, 0x13, 0x00, // first row
+            0x21, 0x22, 0x23, 0x00, 0x31, 0x32, 0x33, 0x00, // second row
+        ];
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, &pixels).unwrap();
+
+        let chunks = chunks(&png);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(&chunks[0].0, b"IHDR");
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert_eq!(&chunks[1].0, b"IDAT");
+        assert_eq!(
+            inflate(&chunks[1].1),
+            [0, 0x03, 0x02, 0x01, 0x13, 0x12, 0x11, 0, 0x23, 0x22, 0x21, 0x33, 0x32, 0x31]
+        );
+        assert_eq!(chunks[2], (*b"IEND", Vec::new()));
+    }
+
+    #[test]
+    fn large_image() {
+        let pixels = vec![0x80; 200 * 100 * 4];
+        let mut png = Vec::new();
+        write_png(&mut png, 200, 100, &pixels).unwrap();
+        let scanlines = inflate(&chunks(&png)[1].1);
+        assert_eq!(scanlines.len(), (1 + 200 * 3) * 100);
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let mut png = Vec::new();
+        assert!(write_png(&mut png, 2, 2, &[0; 12]).is_err());
+    }
+}
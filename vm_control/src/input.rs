@@ -0,0 +1,191 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Input events injected into virtio-input devices, and the event script format used to record
+//! and replay them.
+//!
+//! Scripts use the event lines of the evemu format, so that recordings made with `evemu-record`
+//! on a host device can be replayed in the guest. Each event is one line:
+//!
+//! ```text
+//! E: <seconds>.<microseconds> <type> <code> <value>
+//! ```
+//!
+//! where the type and code are hexadecimal and the value is decimal, e.g.
+//! `E: 0.016000 0001 001e 0001` presses KEY_A 16ms after the start of the script. Other lines,
+//! such as comments starting with `#` and the device description lines of evemu, are ignored.
+
+use std::fmt;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::time::Duration;
+
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+/// An input event, with the same fields as a Linux `input_event` without its timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    /// Returns the SYN_REPORT event that ends each packet of events.
+    pub fn syn_report() -> InputEvent {
+        InputEvent {
+            type_: 0,
+            code: 0,
+            value: 0,
+        }
+    }
+}
+
+/// An event of a script, sent `time` after the start of the script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptEvent {
+    pub time: Duration,
+    pub event: InputEvent,
+}
+
+impl Display for ScriptEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "E: {}.{:06} {:04x} {:04x} {:04}",
+            self.time.as_secs(),
+            self.time.subsec_micros(),
+            self.event.type_,
+            self.event.code,
+            self.event.value
+        )
+    }
+}
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("invalid event on line {0}: {1}")]
+    InvalidEvent(usize, String),
+    #[error("failed to read event script: {0}")]
+    Read(std::io::Error),
+}
+
+fn parse_event_line(line: &str) -> Option<ScriptEvent> {
+    let mut fields = line.split_whitespace();
+    let (secs, micros) = fields.next()?.split_once('.')?;
+    if micros.len() != 6 {
+        return None;
+    }
+    let time =
+        Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(micros.parse().ok()?);
+    let type_ = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let code = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let value = fields.next()?.parse().ok()?;
+    // Anything after the event is a comment.
+    match fields.next() {
+        Some(comment) if !comment.starts_with('#') => return None,
+        _ => (),
+    }
+    Some(ScriptEvent {
+        time,
+        event: InputEvent { type_, code, value },
+    })
+}
+
+/// Reads the events of a script, in the order they must be sent.
+pub fn parse_event_script<R: BufRead>(reader: R) -> Result<Vec<ScriptEvent>, ScriptError> {
+    let mut events: Vec<ScriptEvent> = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(ScriptError::Read)?;
+        let event_line = match line.trim().strip_prefix("E:") {
+            Some(event_line) => event_line,
+            None => continue,
+        };
+        let event = parse_event_line(event_line)
+            .ok_or_else(|| ScriptError::InvalidEvent(index + 1, line.clone()))?;
+        if events.last().map_or(false, |last| last.time > event.time) {
+            return Err(ScriptError::InvalidEvent(index + 1, line));
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let script = "# EVEMU 1.3\n\
+                      N: Crosvm Virtio Keyboard 0\n\
+                      E: 0.000000 0001 001e 0001\t# EV_KEY / KEY_A 1\n\
+                      E: 0.000000 0000 0000 0000\n\
+                      \n\
+                      E: 1.016000 0003 0000 -010\n";
+        let events = parse_event_script(script.as_bytes()).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ScriptEvent {
+                    time: Duration::ZERO,
+                    event: InputEvent {
+                        type_: 1,
+                        code: 30,
+                        value: 1
+                    }
+                },
+                ScriptEvent {
+                    time: Duration::ZERO,
+                    event: InputEvent {
+                        type_: 0,
+                        code: 0,
+                        value: 0
+                    }
+                },
+                ScriptEvent {
+                    time: Duration::from_millis(1016),
+                    event: InputEvent {
+                        type_: 3,
+                        code: 0,
+                        value: -10
+                    }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let event = ScriptEvent {
+            time: Duration::from_micros(2_000_042),
+            event: InputEvent {
+                type_: 3,
+                code: 0x35,
+                value: 1280,
+            },
+        };
+        let line = event.to_string();
+        assert_eq!(line, "E: 2.000042 0003 0035 1280\n");
+        assert_eq!(parse_event_script(line.as_bytes()).unwrap(), vec![event]);
+    }
+
+    #[test]
+    fn invalid_script() {
+        assert!(matches!(
+            parse_event_script("E: 0.5 0001 001e 0001\n".as_bytes()),
+            Err(ScriptError::InvalidEvent(1, _))
+        ));
+        assert!(matches!(
+            parse_event_script(
+                "E: 1.000000 0001 001e 0001\nE: 0.000000 0001 001e 0000\n".as_bytes()
+            ),
+            Err(ScriptError::InvalidEvent(2, _))
+        ));
+    }
+}
@@ -15,6 +15,7 @@ pub mod api;
 pub mod gdb;
 #[cfg(feature = "gpu")]
 pub mod gpu;
+pub mod input;
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use base::MemoryMappingBuilderUnix;
@@ -23,6 +24,8 @@ use base::MemoryMappingBuilderWindows;
 use hypervisor::BalloonEvent;
 use hypervisor::MemRegion;
 
+#[cfg(feature = "balloon")]
+pub mod balloon_policy;
 #[cfg(feature = "balloon")]
 mod balloon_tube;
 pub mod client;
@@ -101,6 +104,10 @@ pub use vm_control_product::GpuSendToService;
 pub use vm_control_product::ServiceSendToGpu;
 use vm_memory::GuestAddress;
 
+#[cfg(feature = "balloon")]
+use crate::balloon_policy::BalloonPolicyCommand;
+#[cfg(feature = "balloon")]
+use crate::balloon_policy::BalloonPolicyStatus;
 #[cfg(feature = "balloon")]
 pub use crate::balloon_tube::*;
 #[cfg(feature = "gdb")]
@@ -113,6 +120,7 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+use crate::input::InputEvent;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -125,6 +133,9 @@ pub enum VcpuControl {
     GetStates(mpsc::Sender<VmRunMode>),
     Snapshot(mpsc::Sender<anyhow::Result<VcpuSnapshot>>),
     Restore(mpsc::Sender<anyhow::Result<()>>, Box<VcpuSnapshot>),
+    // Request the registers of the vCPU for a guest memory dump, as the descriptor of an ELF
+    // `NT_PRSTATUS` note. The vCPU index and the result are sent back over the included channel.
+    CoreDumpRegisters(mpsc::Sender<(usize, anyhow::Result<Vec<u8>>)>),
 }
 
 /// Mode of execution for the VM.
@@ -185,6 +196,8 @@ pub const USB_CONTROL_MAX_PORTS: usize = 16;
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// Get the counters of the requests completed by the disk.
+    Stats,
 }
 
 impl Display for DiskControlCommand {
@@ -193,6 +206,7 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            Stats => write!(f, "disk_stats"),
         }
     }
 }
@@ -201,6 +215,76 @@ impl Display for DiskControlCommand {
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    Stats(DiskStats),
+}
+
+/// Counters of the requests a virtio-block device completed since it was activated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub write_bytes: u64,
+    pub flushes: u64,
+    /// Discard and write zeroes requests.
+    pub discards: u64,
+    /// Requests that failed, which are not included in the other counters.
+    pub errors: u64,
+}
+
+/// Sound control commands, sent to a virtio-snd device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SndControlCommand {
+    /// Plugs (`connected` is true) or unplugs the jack `jack_id` of the device.
+    SetJack { jack_id: u32, connected: bool },
+}
+
+impl Display for SndControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SndControlCommand::*;
+
+        match self {
+            SetJack { jack_id, connected } => write!(f, "snd_set_jack {} {}", jack_id, connected),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SndControlResult {
+    Ok,
+    Err(SysError),
+}
+
+/// Input control commands, sent to a virtio-input device.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputControlCommand {
+    /// Sends `events` to the guest after the events already received from the device's source.
+    Inject { events: Vec<InputEvent> },
+    /// Writes the events the device sends to the guest to `file`, as an event script.
+    StartRecording {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Stops writing the events sent to the guest.
+    StopRecording,
+}
+
+impl Display for InputControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InputControlCommand::*;
+
+        match self {
+            Inject { events } => write!(f, "input_inject {} events", events.len()),
+            StartRecording { .. } => write!(f, "input_start_recording"),
+            StopRecording => write!(f, "input_stop_recording"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum InputControlResult {
+    Ok,
+    Err(SysError),
 }
 
 /// Net control commands for adding and removing tap devices.
@@ -211,12 +295,54 @@ pub enum NetControlCommand {
     RemoveTap(u8),
 }
 
+/// USB devices that crosvm can emulate itself instead of passing through a host device.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmulatedUsbDevice {
+    /// Bulk-only mass storage device backed by a disk image.
+    MassStorage { read_only: bool },
+    /// HID boot keyboard driven by virtio-input events read from a socket.
+    Keyboard,
+    /// HID absolute pointing device driven by virtio-input events read from a socket.
+    Tablet,
+}
+
+impl FromStr for EmulatedUsbDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s {
+            "mass-storage" => Ok(EmulatedUsbDevice::MassStorage { read_only: false }),
+            "mass-storage-ro" => Ok(EmulatedUsbDevice::MassStorage { read_only: true }),
+            "keyboard" => Ok(EmulatedUsbDevice::Keyboard),
+            "tablet" => Ok(EmulatedUsbDevice::Tablet),
+            _ => Err(format!(
+                "invalid emulated usb device `{}`: expected mass-storage, mass-storage-ro, \
+                 keyboard or tablet",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum UsbControlCommand {
     AttachDevice {
         #[serde(with = "with_as_descriptor")]
         file: File,
     },
+    /// Attach a device emulated by crosvm. `file` is the disk image of a mass storage device or
+    /// the input event socket of a HID device.
+    AttachEmulatedDevice {
+        device: EmulatedUsbDevice,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Attach device `busid` exported by the USB/IP server connected to `file`.
+    AttachUsbipDevice {
+        busid: String,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
     DetachDevice {
         port: u8,
     },
@@ -1215,6 +1341,18 @@ pub enum SwapCommand {
     Status,
 }
 
+/// Commands for the VM exit and bus access statistics of the vCPUs.
+#[cfg(feature = "stats")]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StatsCommand {
+    /// Start gathering statistics.
+    Enable,
+    /// Stop gathering statistics. The statistics gathered so far are kept.
+    Disable,
+    /// Get the statistics gathered so far.
+    Get,
+}
+
 ///
 /// A request to the main process to perform some operation on the VM.
 ///
@@ -1244,12 +1382,27 @@ pub enum VmRequest {
     /// Command for balloon driver.
     #[cfg(feature = "balloon")]
     BalloonCommand(BalloonControlCommand),
+    /// Command for the automatic balloon policy.
+    #[cfg(feature = "balloon")]
+    BalloonPolicyCommand(BalloonPolicyCommand),
     /// Send a command to a disk chosen by `disk_index`.
     /// `disk_index` is a 0-based count of `--disk`, `--rwdisk`, and `-r` command-line options.
     DiskCommand {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Send a command to a sound device chosen by `snd_index`.
+    /// `snd_index` is a 0-based count of `--virtio-snd` command-line options.
+    SndCommand {
+        snd_index: usize,
+        command: SndControlCommand,
+    },
+    /// Send a command to an input device chosen by its `name`, the command-line option that
+    /// created it followed by its 0-based count, e.g. "keyboard0" or "multi-touch1".
+    InputCommand {
+        name: String,
+        command: InputControlCommand,
+    },
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     #[cfg(feature = "gpu")]
@@ -1269,6 +1422,12 @@ pub enum VmRequest {
     Snapshot(SnapshotCommand),
     /// Command to Restore devices
     Restore(RestoreCommand),
+    /// Write a dump of guest memory and vCPU registers in the ELF core file format to
+    /// `dump_path`.
+    DumpGuest { dump_path: PathBuf },
+    /// Command for the VM exit and bus access statistics.
+    #[cfg(feature = "stats")]
+    Stats(StatsCommand),
     /// Register for event notification
     #[cfg(feature = "registered_events")]
     RegisterListener {
@@ -1375,6 +1534,7 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     match disk_host_tube.recv() {
         Ok(DiskControlResult::Ok) => VmResponse::Ok,
         Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(DiskControlResult::Stats(stats)) => VmResponse::DiskStats(stats),
         Err(e) => {
             error!("disk socket recv failed: {}", e);
             VmResponse::Err(SysError::new(EINVAL))
@@ -1382,6 +1542,42 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+pub fn handle_snd_command(command: &SndControlCommand, snd_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the sound device via its control socket.
+    if let Err(e) = snd_host_tube.send(command) {
+        error!("snd socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    // Wait for the sound control command to be processed
+    match snd_host_tube.recv() {
+        Ok(SndControlResult::Ok) => VmResponse::Ok,
+        Ok(SndControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("snd socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_input_command(command: &InputControlCommand, input_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the input device via its control socket.
+    if let Err(e) = input_host_tube.send(command) {
+        error!("input socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    // Wait for the input control command to be processed
+    match input_host_tube.recv() {
+        Ok(InputControlResult::Ok) => VmResponse::Ok,
+        Ok(InputControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("input socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1475,6 +1671,15 @@ impl<'a> VcpuSuspendGuard<'a> {
             kick_vcpus,
         })
     }
+
+    /// Keeps the vCPUs suspended past the scope of the guard, returning the run mode the caller
+    /// must restore them to with [VcpuControl::RunState] (nothing to do for
+    /// [VmRunMode::Suspending]).
+    pub fn into_saved_run_mode(self) -> VmRunMode {
+        let saved_run_mode = self.saved_run_mode;
+        std::mem::forget(self);
+        saved_run_mode
+    }
 }
 
 impl Drop for VcpuSuspendGuard<'_> {
@@ -1552,6 +1757,8 @@ impl VmRequest {
         &self,
         run_mode: &mut Option<VmRunMode>,
         disk_host_tubes: &[Tube],
+        snd_host_tubes: &[Tube],
+        input_host_tubes: &BTreeMap<String, Tube>,
         pm: &mut Option<Arc<Mutex<dyn PmResource + Send>>>,
         #[cfg(feature = "gpu")] gpu_control_tube: Option<&Tube>,
         usb_control_tube: Option<&Tube>,
@@ -1809,6 +2016,20 @@ impl VmRequest {
             }
             #[cfg(feature = "balloon")]
             VmRequest::BalloonCommand(_) => unreachable!("Should be handled with BalloonTube"),
+            #[cfg(feature = "balloon")]
+            VmRequest::BalloonPolicyCommand(_) => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
+            VmRequest::DumpGuest { .. } => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
+            #[cfg(feature = "stats")]
+            VmRequest::Stats(_) => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
             VmRequest::DiskCommand {
                 disk_index,
                 ref command,
@@ -1816,6 +2037,20 @@ impl VmRequest {
                 Some(tube) => handle_disk_command(command, tube),
                 None => VmResponse::Err(SysError::new(ENODEV)),
             },
+            VmRequest::SndCommand {
+                snd_index,
+                ref command,
+            } => match &snd_host_tubes.get(snd_index) {
+                Some(tube) => handle_snd_command(command, tube),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
+            VmRequest::InputCommand {
+                ref name,
+                ref command,
+            } => match input_host_tubes.get(name) {
+                Some(tube) => handle_input_command(command, tube),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
             #[cfg(feature = "gpu")]
             VmRequest::GpuCommand(ref cmd) => match gpu_control_tube {
                 Some(gpu_control) => {
@@ -2132,6 +2367,9 @@ pub enum VmResponse {
     /// Results of balloon WS-R command
     #[cfg(feature = "balloon")]
     BalloonWS { ws: BalloonWS, balloon_actual: u64 },
+    /// State and last decision of the automatic balloon policy.
+    #[cfg(feature = "balloon")]
+    BalloonPolicyStatus(BalloonPolicyStatus),
     /// Results of PCI hot plug
     #[cfg(feature = "pci-hotplug")]
     PciHotPlugResponse { bus: u8 },
@@ -2146,6 +2384,11 @@ pub enum VmResponse {
     SwapStatus(SwapStatus),
     /// Gets the state of Devices (sleep/wake)
     DevicesState(DevicesState),
+    /// Results of disk stats command.
+    DiskStats(DiskStats),
+    /// VM exit and bus access statistics of the vCPUs, as JSON.
+    #[cfg(feature = "stats")]
+    Stats(serde_json::Value),
 }
 
 impl Display for VmResponse {
@@ -2184,6 +2427,15 @@ impl Display for VmResponse {
                     balloon_actual,
                 )
             }
+            #[cfg(feature = "balloon")]
+            VmResponse::BalloonPolicyStatus(status) => {
+                write!(
+                    f,
+                    "balloon policy: {}",
+                    serde_json::to_string_pretty(&status)
+                        .unwrap_or_else(|_| "invalid_response".to_string()),
+                )
+            }
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
             #[cfg(feature = "pci-hotplug")]
             PciHotPlugResponse { bus } => write!(f, "pci hotplug bus {:?}", bus),
@@ -2199,6 +2451,14 @@ impl Display for VmResponse {
                 )
             }
             DevicesState(status) => write!(f, "devices status: {:?}", status),
+            DiskStats(stats) => write!(f, "disk stats: {:?}", stats),
+            #[cfg(feature = "stats")]
+            Stats(stats) => write!(
+                f,
+                "{}",
+                serde_json::to_string_pretty(&stats)
+                    .unwrap_or_else(|_| "invalid_response".to_string()),
+            ),
         }
     }
 }
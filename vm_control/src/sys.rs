@@ -20,6 +20,8 @@ cfg_if::cfg_if! {
     }
 }
 
+pub use platform::connect_input_event_socket;
+pub use platform::connect_usbip_server;
 pub use platform::handle_request;
 pub use platform::prepare_shared_memory_region;
 pub use platform::should_prepare_memory_region;
@@ -5,6 +5,12 @@
 #[cfg(feature = "gpu")]
 pub(crate) mod gpu;
 
+use std::fs::File;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::time::Duration;
 
@@ -33,6 +39,46 @@ use crate::client::HandleRequestResult;
 use crate::VmRequest;
 use crate::VmResponse;
 
+/// Connects to a unix socket providing virtio-input events and returns it as a file so that it
+/// can be sent over a control socket.
+pub fn connect_input_event_socket(path: &Path) -> base::Result<File> {
+    let stream = UnixStream::connect(path)?;
+    Ok(File::from(OwnedFd::from(stream)))
+}
+
+// Port usbipd listens on by default.
+const USBIP_DEFAULT_PORT: u16 = 3240;
+
+/// Splits `address`, given as `host[:port]`, into a host and a port. IPv6 addresses must be in
+/// brackets when followed by a port, as in `[::1]:3240`.
+fn parse_usbip_address(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = match address.parse::<SocketAddr>() {
+        Ok(addr) => (address.rsplit_once(':')?.0, addr.port()),
+        // The colons of an IPv6 address aren't a port separator.
+        Err(_) if address.parse::<Ipv6Addr>().is_ok() => (address, USBIP_DEFAULT_PORT),
+        Err(_) if address.starts_with('[') && address.ends_with(']') => {
+            (address, USBIP_DEFAULT_PORT)
+        }
+        Err(_) => match address.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (address, USBIP_DEFAULT_PORT),
+        },
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port))
+}
+
+/// Connects to the USB/IP server at `address`, given as `host[:port]`, and returns the connection
+/// as a file so that it can be sent over a control socket.
+pub fn connect_usbip_server(address: &str) -> base::Result<File> {
+    let (host, port) = parse_usbip_address(address).ok_or_else(|| SysError::new(EINVAL))?;
+    let stream = TcpStream::connect((host, port))?;
+    Ok(File::from(OwnedFd::from(stream)))
+}
+
 pub fn handle_request<T: AsRef<Path> + std::fmt::Debug>(
     request: &VmRequest,
     socket_path: T,
@@ -238,3 +284,23 @@ impl FsMappingRequest {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usbip_address() {
+        assert_eq!(parse_usbip_address("server"), Some(("server", 3240)));
+        assert_eq!(parse_usbip_address("server:1234"), Some(("server", 1234)));
+        assert_eq!(parse_usbip_address("10.0.0.1"), Some(("10.0.0.1", 3240)));
+        assert_eq!(
+            parse_usbip_address("10.0.0.1:1234"),
+            Some(("10.0.0.1", 1234))
+        );
+        assert_eq!(parse_usbip_address("::1"), Some(("::1", 3240)));
+        assert_eq!(parse_usbip_address("[::1]"), Some(("::1", 3240)));
+        assert_eq!(parse_usbip_address("[::1]:1234"), Some(("::1", 1234)));
+        assert_eq!(parse_usbip_address("server:port"), None);
+    }
+}
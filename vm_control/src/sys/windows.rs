@@ -5,6 +5,7 @@
 #[cfg(feature = "gpu")]
 pub(crate) mod gpu;
 
+use std::fs::File;
 use std::io::Result;
 use std::mem::size_of;
 use std::path::Path;
@@ -27,6 +28,20 @@ use crate::VmRequest;
 
 pub const SERVICE_MESSAGE_HEADER_SIZE: usize = size_of::<u32>();
 
+/// Input event sockets are not supported on Windows.
+pub fn connect_input_event_socket(_path: &Path) -> base::Result<File> {
+    Err(Error::from(std::io::Error::from(
+        std::io::ErrorKind::Unsupported,
+    )))
+}
+
+/// USB/IP is not supported on Windows.
+pub fn connect_usbip_server(_address: &str) -> base::Result<File> {
+    Err(Error::from(std::io::Error::from(
+        std::io::ErrorKind::Unsupported,
+    )))
+}
+
 pub fn handle_request<T: AsRef<Path> + std::fmt::Debug>(
     request: &VmRequest,
     socket_path: T,
@@ -27,6 +27,9 @@ mod msr_index;
 #[allow(clippy::all)]
 mod mpspec;
 
+#[allow(non_camel_case_types)]
+mod pvh;
+
 pub mod acpi;
 mod bzimage;
 pub mod cpuid;
@@ -111,6 +114,8 @@ use hypervisor::x86_64::Regs;
 #[cfg(feature = "gdb")]
 use hypervisor::x86_64::Sregs;
 use hypervisor::CpuConfigX86_64;
+#[cfg(feature = "gdb")]
+use hypervisor::HwWatchpoint;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorX86_64;
 use hypervisor::ProtectionType;
@@ -212,6 +217,8 @@ pub enum Error {
     EnableSinglestep(base::Error),
     #[error("failed to enable split irqchip: {0}")]
     EnableSplitIrqchip(base::Error),
+    #[error("failed to get the watchpoint of a debug exit: {0}")]
+    GetDebugExitWatchpoint(base::Error),
     #[error("failed to get serial cmdline: {0}")]
     GetSerialCmdline(GetSerialCmdlineError),
     #[error("failed to insert device onto bus: {0}")]
@@ -232,8 +239,12 @@ pub enum Error {
     LoadKernel(kernel_loader::Error),
     #[error("error loading pflash: {0}")]
     LoadPflash(io::Error),
+    #[error("the kernel is not an ELF image with a PVH entry point")]
+    NoPvhEntry,
     #[error("error translating address: Page not present")]
     PageNotPresent,
+    #[error("an android fstab can't be passed to a kernel booted with PVH")]
+    PvhAndroidFstab,
     #[error("error reading guest memory {0}")]
     ReadingGuestMemory(vm_memory::GuestMemoryError),
     #[error("single register read not supported on x86_64")]
@@ -270,6 +281,8 @@ pub enum Error {
     SetupPageTables(regs::Error),
     #[error("failed to set up pflash: {0}")]
     SetupPflash(anyhow::Error),
+    #[error("failed to set up PVH start info: {0}")]
+    SetupPvh(pvh::Error),
     #[error("failed to set up registers: {0}")]
     SetupRegs(regs::Error),
     #[error("failed to set up SMBIOS: {0}")]
@@ -321,11 +334,23 @@ pub struct SetupData {
     pub type_: SetupDataType,
 }
 
+#[derive(Copy, Clone)]
 enum E820Type {
     Ram = 0x01,
     Reserved = 0x2,
 }
 
+/// How the bootstrap VCPU enters a loaded kernel.
+enum KernelEntry {
+    /// Linux/x86 64-bit boot protocol, in long mode with `RSI` pointing to `params`.
+    Linux64 {
+        params: Box<boot_params>,
+        entry: GuestAddress,
+    },
+    /// Xen PVH boot ABI, in 32-bit protected mode with `EBX` pointing to a `hvm_start_info`.
+    Pvh(GuestAddress),
+}
+
 const MB: u64 = 1 << 20;
 const GB: u64 = 1 << 30;
 
@@ -336,6 +361,9 @@ const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
 const HIGH_MMIO_MAX_END: u64 = (1u64 << 46) - 1;
 pub const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 pub const ZERO_PAGE_OFFSET: u64 = 0x7000;
+// The PVH start info is used instead of the zero page, and placed below it.
+const PVH_INFO_START: u64 = 0x6000;
+const PVH_INFO_END: u64 = ZERO_PAGE_OFFSET;
 const TSS_ADDR: u64 = 0xfffb_d000;
 
 pub const KERNEL_START_OFFSET: u64 = 0x20_0000;
@@ -440,7 +468,6 @@ fn configure_system(
     initrd: Option<(GuestAddress, usize)>,
     mut params: boot_params,
 ) -> Result<()> {
-    const EBDA_START: u64 = 0x0009_fc00;
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
@@ -461,14 +488,34 @@ fn configure_system(
         params.hdr.ramdisk_size = initrd_size as u32;
     }
 
-    add_e820_entry(
-        &mut params,
+    for (range, mem_type) in memory_map(guest_mem, kernel_addr) {
+        add_e820_entry(&mut params, range, mem_type)?;
+    }
+
+    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
+    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
+        return Err(Error::ZeroPagePastRamEnd);
+    }
+
+    guest_mem
+        .write_obj_at_addr(params, zero_page_addr)
+        .map_err(|_| Error::ZeroPageSetup)?;
+
+    Ok(())
+}
+
+/// Returns the guest physical memory map reported to the kernel, through e820 or the PVH start
+/// info.
+fn memory_map(guest_mem: &GuestMemory, kernel_addr: GuestAddress) -> Vec<(AddressRange, E820Type)> {
+    const EBDA_START: u64 = 0x0009_fc00;
+
+    let mut memory_map = vec![(
         AddressRange {
             start: START_OF_RAM_32BITS,
             end: EBDA_START - 1,
         },
         E820Type::Ram,
-    )?;
+    )];
 
     // GuestMemory::end_addr() returns the first address past the end, so subtract 1 to get the
     // inclusive end.
@@ -481,30 +528,20 @@ fn configure_system(
         start: FIRST_ADDR_PAST_32BITS,
         end: guest_mem_end,
     };
-    add_e820_entry(&mut params, ram_below_4g, E820Type::Ram)?;
+    memory_map.push((ram_below_4g, E820Type::Ram));
     if !ram_above_4g.is_empty() {
-        add_e820_entry(&mut params, ram_above_4g, E820Type::Ram)?
+        memory_map.push((ram_above_4g, E820Type::Ram));
     }
 
     let pcie_cfg_mmio_range = read_pcie_cfg_mmio();
-    add_e820_entry(&mut params, pcie_cfg_mmio_range, E820Type::Reserved)?;
+    memory_map.push((pcie_cfg_mmio_range, E820Type::Reserved));
 
-    add_e820_entry(
-        &mut params,
+    memory_map.push((
         X8664arch::get_pcie_vcfg_mmio_range(guest_mem, &pcie_cfg_mmio_range),
         E820Type::Reserved,
-    )?;
+    ));
 
-    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
-    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
-        return Err(Error::ZeroPagePastRamEnd);
-    }
-
-    guest_mem
-        .write_obj_at_addr(params, zero_page_addr)
-        .map_err(|_| Error::ZeroPageSetup)?;
-
-    Ok(())
+    memory_map
 }
 
 /// Write setup_data entries in guest memory and link them together with the `next` field.
@@ -932,7 +969,7 @@ impl arch::LinuxArch for X8664arch {
         };
 
         // TODO (tjeznach) Write RSDP to bootconfig before writing to memory
-        acpi::create_acpi_tables(
+        let rsdp_addr = acpi::create_acpi_tables(
             &mem,
             vcpu_count as u8,
             sci_irq,
@@ -981,32 +1018,63 @@ impl arch::LinuxArch for X8664arch {
                 // The default values for `Regs` and `Sregs` already set up the reset vector.
             }
             VmImage::Kernel(ref mut kernel_image) => {
-                let (params, kernel_end, kernel_entry) = Self::load_kernel(&mem, kernel_image)?;
-
-                Self::setup_system_memory(
-                    &mem,
-                    &CString::new(cmdline).unwrap(),
-                    components.initrd_image,
-                    components.android_fstab,
-                    kernel_end,
-                    params,
-                    dump_device_tree_blob,
-                )?;
-
-                // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
-                // <https://www.kernel.org/doc/html/latest/x86/boot.html>
-                vcpu_init[0].regs.rip = kernel_entry.offset();
-                vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
-                vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
-
-                msrs = regs::long_mode_msrs();
-                msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
-
-                // Set up long mode and enable paging.
-                regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::ConfigureSegments)?;
-                regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::SetupPageTables)?;
+                let (kernel_entry, kernel_end) =
+                    Self::load_kernel(&mem, kernel_image, components.pvh)?;
+
+                match kernel_entry {
+                    KernelEntry::Linux64 { params, entry } => {
+                        Self::setup_system_memory(
+                            &mem,
+                            &CString::new(cmdline).unwrap(),
+                            components.initrd_image,
+                            components.android_fstab,
+                            kernel_end,
+                            *params,
+                            dump_device_tree_blob,
+                        )?;
+
+                        // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
+                        // <https://www.kernel.org/doc/html/latest/x86/boot.html>
+                        vcpu_init[0].regs.rip = entry.offset();
+                        vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
+                        vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
+
+                        msrs = regs::long_mode_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        // Set up long mode and enable paging.
+                        regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                        regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::SetupPageTables)?;
+                    }
+                    KernelEntry::Pvh(entry) => {
+                        // The android fstab is passed in a setup_data entry, which has no PVH
+                        // equivalent.
+                        if components.android_fstab.is_some() {
+                            return Err(Error::PvhAndroidFstab);
+                        }
+                        Self::setup_pvh_system_memory(
+                            &mem,
+                            &CString::new(cmdline).unwrap(),
+                            components.initrd_image,
+                            kernel_end,
+                            Some(rsdp_addr),
+                        )?;
+
+                        // Configure the bootstrap VCPU for the Xen PVH boot ABI.
+                        // <https://xenbits.xen.org/docs/unstable/misc/pvh.html>
+                        vcpu_init[0].regs.rip = entry.offset();
+                        vcpu_init[0].regs.rbx = PVH_INFO_START;
+
+                        msrs = regs::default_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        // Set up 32-bit protected mode without paging.
+                        regs::configure_pvh_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                    }
+                }
             }
         }
 
@@ -1306,7 +1374,7 @@ impl<T: VcpuX86_64> arch::GdbOps<T> for X8664arch {
     }
 
     fn enable_singlestep(vcpu: &T) -> Result<()> {
-        vcpu.set_guest_debug(&[], true /* enable_singlestep */)
+        vcpu.set_guest_debug(&[], &[], true /* enable_singlestep */)
             .map_err(Error::EnableSinglestep)
     }
 
@@ -1314,10 +1382,19 @@ impl<T: VcpuX86_64> arch::GdbOps<T> for X8664arch {
         Ok(4usize)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
-        vcpu.set_guest_debug(breakpoints, false /* enable_singlestep */)
+    fn set_hw_breakpoints(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+    ) -> Result<()> {
+        vcpu.set_guest_debug(breakpoints, watchpoints, false /* enable_singlestep */)
             .map_err(Error::SetHwBreakpoint)
     }
+
+    fn get_debug_exit_watchpoint(vcpu: &T) -> Result<Option<usize>> {
+        vcpu.get_debug_exit_watchpoint()
+            .map_err(Error::GetDebugExitWatchpoint)
+    }
 }
 
 #[cfg(feature = "gdb")]
@@ -1562,35 +1639,54 @@ impl X8664arch {
     ///
     /// * `mem` - The memory to be used by the guest.
     /// * `kernel_image` - the File object for the specified kernel.
+    /// * `pvh` - start an ELF kernel through the PVH entry point it advertises.
     ///
     /// # Returns
     ///
-    /// On success, returns the Linux x86_64 boot protocol parameters, the first address past the
-    /// end of the kernel, and the entry point (initial `RIP` value).
+    /// On success, returns how to enter the kernel and the first address past the end of the
+    /// kernel.
     fn load_kernel(
         mem: &GuestMemory,
         kernel_image: &mut File,
-    ) -> Result<(boot_params, u64, GuestAddress)> {
+        pvh: bool,
+    ) -> Result<(KernelEntry, u64)> {
         let kernel_start = GuestAddress(KERNEL_START_OFFSET);
+        if pvh {
+            // 32-bit and 64-bit ELF kernels can be started through their PVH entry point.
+            let pvh_entry = match kernel_loader::find_pvh_entry(kernel_image) {
+                Ok(Some(pvh_entry)) => pvh_entry,
+                Ok(None) | Err(kernel_loader::Error::InvalidMagicNumber) => {
+                    return Err(Error::NoPvhEntry)
+                }
+                Err(e) => return Err(Error::LoadKernel(e)),
+            };
+            let loaded_kernel =
+                kernel_loader::load_elf_pvh(mem, kernel_start, kernel_image, pvh_entry)
+                    .map_err(Error::LoadKernel)?;
+            return Ok((
+                KernelEntry::Pvh(loaded_kernel.entry),
+                loaded_kernel.address_range.end,
+            ));
+        }
+
         match kernel_loader::load_elf64(mem, kernel_start, kernel_image, 0) {
             Ok(loaded_kernel) => {
                 // ELF kernels don't contain a `boot_params` structure, so synthesize a default one.
-                let boot_params = Default::default();
-                Ok((
-                    boot_params,
-                    loaded_kernel.address_range.end,
-                    loaded_kernel.entry,
-                ))
+                let kernel_entry = KernelEntry::Linux64 {
+                    params: Box::default(),
+                    entry: loaded_kernel.entry,
+                };
+                Ok((kernel_entry, loaded_kernel.address_range.end))
             }
             Err(kernel_loader::Error::InvalidMagicNumber) => {
                 // The image failed to parse as ELF, so try to load it as a bzImage.
-                let (boot_params, bzimage_end) =
-                    bzimage::load_bzimage(mem, kernel_start, kernel_image)
-                        .map_err(Error::LoadBzImage)?;
-                let bzimage_entry = mem
+                let (params, bzimage_end) = bzimage::load_bzimage(mem, kernel_start, kernel_image)
+                    .map_err(Error::LoadBzImage)?;
+                let entry = mem
                     .checked_offset(kernel_start, KERNEL_64BIT_ENTRY_OFFSET)
                     .ok_or(Error::KernelOffsetPastEnd)?;
-                Ok((boot_params, bzimage_end, bzimage_entry))
+                let params = Box::new(params);
+                Ok((KernelEntry::Linux64 { params, entry }, bzimage_end))
             }
             Err(e) => Err(Error::LoadKernel(e)),
         }
@@ -1632,28 +1728,12 @@ impl X8664arch {
         )?;
 
         let initrd = match initrd_file {
-            Some(mut initrd_file) => {
-                let mut initrd_addr_max = u64::from(params.hdr.initrd_addr_max);
-                // Default initrd_addr_max for old kernels (see Documentation/x86/boot.txt).
-                if initrd_addr_max == 0 {
-                    initrd_addr_max = 0x37FFFFFF;
-                }
-
-                let mem_max = mem.end_addr().offset() - 1;
-                if initrd_addr_max > mem_max {
-                    initrd_addr_max = mem_max;
-                }
-
-                let (initrd_start, initrd_size) = arch::load_image_high(
-                    mem,
-                    &mut initrd_file,
-                    GuestAddress(kernel_end),
-                    GuestAddress(initrd_addr_max),
-                    base::pagesize() as u64,
-                )
-                .map_err(Error::LoadInitrd)?;
-                Some((initrd_start, initrd_size))
-            }
+            Some(mut initrd_file) => Some(Self::load_initrd(
+                mem,
+                &mut initrd_file,
+                kernel_end,
+                u64::from(params.hdr.initrd_addr_max),
+            )?),
             None => None,
         };
 
@@ -1669,6 +1749,73 @@ impl X8664arch {
         Ok(())
     }
 
+    /// Configures the system memory space for a kernel started through its PVH entry point. Should
+    /// be called once per vm before starting vcpu threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory to be used by the guest.
+    /// * `cmdline` - the kernel commandline
+    /// * `initrd_file` - an initial ramdisk image
+    /// * `kernel_end` - the first address past the end of the kernel
+    /// * `rsdp_addr` - the address of the ACPI RSDP
+    pub fn setup_pvh_system_memory(
+        mem: &GuestMemory,
+        cmdline: &CStr,
+        initrd_file: Option<File>,
+        kernel_end: u64,
+        rsdp_addr: Option<GuestAddress>,
+    ) -> Result<()> {
+        kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)
+            .map_err(Error::LoadCmdline)?;
+
+        let initrd = match initrd_file {
+            Some(mut initrd_file) => Some(Self::load_initrd(mem, &mut initrd_file, kernel_end, 0)?),
+            None => None,
+        };
+
+        pvh::write_start_info(
+            mem,
+            GuestAddress(PVH_INFO_START),
+            GuestAddress(PVH_INFO_END),
+            GuestAddress(CMDLINE_OFFSET),
+            initrd,
+            rsdp_addr,
+            &memory_map(mem, GuestAddress(KERNEL_START_OFFSET)),
+        )
+        .map_err(Error::SetupPvh)
+    }
+
+    /// Loads the initial ramdisk as high as possible below `initrd_addr_max`, or below the default
+    /// limit of the Linux boot protocol if it is 0.
+    ///
+    /// Returns the guest address and size of the ramdisk.
+    fn load_initrd(
+        mem: &GuestMemory,
+        initrd_file: &mut File,
+        kernel_end: u64,
+        mut initrd_addr_max: u64,
+    ) -> Result<(GuestAddress, usize)> {
+        // Default initrd_addr_max for old kernels (see Documentation/x86/boot.txt).
+        if initrd_addr_max == 0 {
+            initrd_addr_max = 0x37FFFFFF;
+        }
+
+        let mem_max = mem.end_addr().offset() - 1;
+        if initrd_addr_max > mem_max {
+            initrd_addr_max = mem_max;
+        }
+
+        arch::load_image_high(
+            mem,
+            initrd_file,
+            GuestAddress(kernel_end),
+            GuestAddress(initrd_addr_max),
+            base::pagesize() as u64,
+        )
+        .map_err(Error::LoadInitrd)
+    }
+
     fn get_pcie_vcfg_mmio_range(mem: &GuestMemory, pcie_cfg_mmio: &AddressRange) -> AddressRange {
         // Put PCIe VCFG region at a 2MB boundary after physical memory or 4gb, whichever is greater.
         let ram_end_round_2mb = (mem.end_addr().offset() + 2 * MB - 1) / (2 * MB) * (2 * MB);
@@ -0,0 +1,223 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Xen PVH boot ABI, which starts a kernel in 32-bit protected mode with `EBX` pointing to an
+//! `hvm_start_info` structure describing the command line, modules and memory map.
+//!
+//! <https://xenbits.xen.org/docs/unstable/misc/pvh.html>
+
+use std::mem;
+
+use remain::sorted;
+use resources::AddressRange;
+use thiserror::Error;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use vm_memory::GuestMemoryError;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+use crate::E820Type;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid memory map entry {0:?}")]
+    InvalidMemoryMapEntry(AddressRange),
+    #[error("the PVH start info does not fit below {0}")]
+    StartInfoTooLarge(GuestAddress),
+    #[error("failed to write the PVH start info: {0}")]
+    WriteStartInfo(GuestMemoryError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+// Version 1 adds the memory map to version 0.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, FromZeroes, FromBytes, AsBytes)]
+pub struct hvm_start_info {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, FromZeroes, FromBytes, AsBytes)]
+pub struct hvm_modlist_entry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, FromZeroes, FromBytes, AsBytes)]
+pub struct hvm_memmap_table_entry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+/// Writes an `hvm_start_info` structure at `start`, followed by the module list and memory map it
+/// points to, making sure they end before `end`.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `start` - The guest address of the `hvm_start_info` structure, to be passed in `EBX`.
+/// * `end` - The first guest address past the area reserved for the start info.
+/// * `cmdline_addr` - The guest address of the null-terminated kernel command line.
+/// * `initrd` - The guest address and size of the initial ramdisk, passed as the first module.
+/// * `rsdp_addr` - The guest address of the ACPI RSDP, if any.
+/// * `memory_map` - The guest physical memory map.
+pub(crate) fn write_start_info(
+    guest_mem: &GuestMemory,
+    start: GuestAddress,
+    end: GuestAddress,
+    cmdline_addr: GuestAddress,
+    initrd: Option<(GuestAddress, usize)>,
+    rsdp_addr: Option<GuestAddress>,
+    memory_map: &[(AddressRange, E820Type)],
+) -> Result<()> {
+    let modlist: Vec<hvm_modlist_entry> = initrd
+        .into_iter()
+        .map(|(addr, size)| hvm_modlist_entry {
+            paddr: addr.offset(),
+            size: size as u64,
+            ..Default::default()
+        })
+        .collect();
+    let memmap = memory_map
+        .iter()
+        .map(|&(range, mem_type)| {
+            Ok(hvm_memmap_table_entry {
+                addr: range.start,
+                size: range.len().ok_or(Error::InvalidMemoryMapEntry(range))?,
+                type_: mem_type as u32,
+                reserved: 0,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let modlist_addr = start
+        .checked_add(mem::size_of::<hvm_start_info>() as u64)
+        .ok_or(Error::StartInfoTooLarge(end))?;
+    let memmap_addr = modlist_addr
+        .checked_add(modlist.as_bytes().len() as u64)
+        .ok_or(Error::StartInfoTooLarge(end))?;
+    let memmap_end = memmap_addr
+        .checked_add(memmap.as_bytes().len() as u64)
+        .ok_or(Error::StartInfoTooLarge(end))?;
+    if memmap_end > end {
+        return Err(Error::StartInfoTooLarge(end));
+    }
+
+    let start_info = hvm_start_info {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        nr_modules: modlist.len() as u32,
+        modlist_paddr: if modlist.is_empty() {
+            0
+        } else {
+            modlist_addr.offset()
+        },
+        cmdline_paddr: cmdline_addr.offset(),
+        rsdp_paddr: rsdp_addr.map_or(0, |addr| addr.offset()),
+        memmap_paddr: memmap_addr.offset(),
+        memmap_entries: memmap.len() as u32,
+        ..Default::default()
+    };
+
+    guest_mem
+        .write_obj_at_addr(start_info, start)
+        .map_err(Error::WriteStartInfo)?;
+    guest_mem
+        .write_all_at_addr(modlist.as_bytes(), modlist_addr)
+        .map_err(Error::WriteStartInfo)?;
+    guest_mem
+        .write_all_at_addr(memmap.as_bytes(), memmap_addr)
+        .map_err(Error::WriteStartInfo)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_info_layout() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let memory_map = [
+            (
+                AddressRange::from_start_and_size(0, 0x9fc00).unwrap(),
+                E820Type::Ram,
+            ),
+            (
+                AddressRange::from_start_and_size(0x20_0000, 0x100_0000).unwrap(),
+                E820Type::Ram,
+            ),
+            (
+                AddressRange::from_start_and_size(0xe000_0000, 0x1000_0000).unwrap(),
+                E820Type::Reserved,
+            ),
+        ];
+        write_start_info(
+            &gm,
+            GuestAddress(0x6000),
+            GuestAddress(0x7000),
+            GuestAddress(0x2_0000),
+            Some((GuestAddress(0x80_0000), 0x1234)),
+            Some(GuestAddress(0xe_0000)),
+            &memory_map,
+        )
+        .unwrap();
+
+        let start_info: hvm_start_info = gm.read_obj_from_addr(GuestAddress(0x6000)).unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.version, 1);
+        assert_eq!(start_info.cmdline_paddr, 0x2_0000);
+        assert_eq!(start_info.rsdp_paddr, 0xe_0000);
+        assert_eq!(start_info.nr_modules, 1);
+        assert_eq!(start_info.memmap_entries, 3);
+
+        let module: hvm_modlist_entry = gm
+            .read_obj_from_addr(GuestAddress(start_info.modlist_paddr))
+            .unwrap();
+        assert_eq!(module.paddr, 0x80_0000);
+        assert_eq!(module.size, 0x1234);
+
+        let entry: hvm_memmap_table_entry = gm
+            .read_obj_from_addr(GuestAddress(
+                start_info.memmap_paddr + 2 * mem::size_of::<hvm_memmap_table_entry>() as u64,
+            ))
+            .unwrap();
+        assert_eq!(entry.addr, 0xe000_0000);
+        assert_eq!(entry.size, 0x1000_0000);
+        assert_eq!(entry.type_, E820Type::Reserved as u32);
+
+        assert!(write_start_info(
+            &gm,
+            GuestAddress(0x6000),
+            GuestAddress(0x6040),
+            GuestAddress(0x2_0000),
+            None,
+            None,
+            &memory_map,
+        )
+        .is_err());
+    }
+}